@@ -0,0 +1,48 @@
+//! Interpreter vs. closure backend on large neuron groups
+//!
+//! Run with `cargo bench -p oldies-brian`.
+
+use oldies_core::Rng;
+use oldies_brian::{AdExNeuron, Backend, COBANeuron, IzhikevichNeuron, NeuronEquations, NeuronGroup};
+use std::time::Instant;
+
+const N: usize = 100_000;
+const STEPS: usize = 100;
+const DT: f64 = 0.1;
+
+/// Mean wall time per step (ms)
+fn time_per_step(equations: &NeuronEquations, backend: Backend) -> f64 {
+    let mut group = NeuronGroup::new("G", N, equations.clone());
+    group.backend = backend;
+    let mut rng = Rng::new(1);
+    // First step compiles
+    group.update(0.0, DT, &mut rng).expect("update");
+
+    let start = Instant::now();
+    for k in 1..=STEPS {
+        group.update(k as f64 * DT, DT, &mut rng).expect("update");
+    }
+    start.elapsed().as_secs_f64() * 1e3 / STEPS as f64
+}
+
+fn main() {
+    let models = [
+        ("AdEx", AdExNeuron::default().to_equations()),
+        ("Izhikevich", IzhikevichNeuron::regular_spiking().to_equations()),
+        ("COBA", COBANeuron::default().to_equations()),
+    ];
+
+    println!("{} neurons, {} steps", N, STEPS);
+    println!("{:<12} {:>14} {:>14} {:>9}", "model", "interp ms/step", "closure ms/step", "speedup");
+    for (name, equations) in &models {
+        let interpreted = time_per_step(equations, Backend::Interpreter);
+        let closures = time_per_step(equations, Backend::Closures);
+        println!(
+            "{:<12} {:>14.3} {:>14.3} {:>8.2}x",
+            name,
+            interpreted,
+            closures,
+            interpreted / closures
+        );
+    }
+}
@@ -0,0 +1,1035 @@
+//! Expression parser and compiled evaluator
+//!
+//! Brian model strings (`"(v_rest - v + R*I) / tau"`, `"v > v_thresh"`,
+//! `"w += b"`) are parsed into an AST and compiled into a small stack
+//! bytecode. Names are resolved once, at compile time, against a
+//! [`SymbolTable`] so evaluation only indexes into a slice of values.
+//!
+//! Numeric values are expressed in the simulator's base system (ms, mV, nA,
+//! uS, nF, MOhm), the same convention used by the built-in models. Unit names
+//! appearing in expressions (`mV`, `ms`, `pA`, ...) evaluate to their scale
+//! factor in that system, so `0.1*mV` is `0.1` and `10*Hz` is `0.01` (per ms).
+
+use oldies_core::Rng;
+use crate::{BrianError, Result, TimedArray, Unit};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// ============================================================================
+// AST
+// ============================================================================
+
+/// Binary operators
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+/// Parsed expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Name(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    /// Names referenced by this expression (excluding function names)
+    pub fn names(&self) -> Vec<String> {
+        let mut out = vec![];
+        self.collect_names(&mut out);
+        out
+    }
+
+    /// Replace names by their definitions
+    pub fn substitute(&self, defs: &HashMap<String, Expr>) -> Expr {
+        match self {
+            Expr::Number(_) => self.clone(),
+            Expr::Name(n) => defs.get(n).cloned().unwrap_or_else(|| self.clone()),
+            Expr::Neg(e) => Expr::Neg(Box::new(e.substitute(defs))),
+            Expr::Not(e) => Expr::Not(Box::new(e.substitute(defs))),
+            Expr::Binary(op, a, b) => {
+                Expr::Binary(*op, Box::new(a.substitute(defs)), Box::new(b.substitute(defs)))
+            }
+            Expr::Call(name, args) => {
+                Expr::Call(name.clone(), args.iter().map(|a| a.substitute(defs)).collect())
+            }
+        }
+    }
+
+    /// Whether the expression draws random numbers
+    pub fn is_stochastic(&self) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Name(_) => false,
+            Expr::Neg(e) | Expr::Not(e) => e.is_stochastic(),
+            Expr::Binary(_, a, b) => a.is_stochastic() || b.is_stochastic(),
+            Expr::Call(name, args) => {
+                matches!(name.as_str(), "rand" | "randn") || args.iter().any(Expr::is_stochastic)
+            }
+        }
+    }
+
+    fn collect_names(&self, out: &mut Vec<String>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Name(n) => {
+                if !out.contains(n) {
+                    out.push(n.clone());
+                }
+            }
+            Expr::Neg(e) | Expr::Not(e) => e.collect_names(out),
+            Expr::Binary(_, a, b) => {
+                a.collect_names(out);
+                b.collect_names(out);
+            }
+            Expr::Call(_, args) => {
+                for a in args {
+                    a.collect_names(out);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(x) => write!(f, "{:?}", x),
+            Expr::Name(n) => write!(f, "{}", n),
+            Expr::Neg(e) => write!(f, "(-{})", e),
+            Expr::Not(e) => write!(f, "(not {})", e),
+            Expr::Binary(op, a, b) => {
+                let sym = match op {
+                    BinOp::Add => "+",
+                    BinOp::Sub => "-",
+                    BinOp::Mul => "*",
+                    BinOp::Div => "/",
+                    BinOp::Mod => "%",
+                    BinOp::Pow => "**",
+                    BinOp::Lt => "<",
+                    BinOp::Le => "<=",
+                    BinOp::Gt => ">",
+                    BinOp::Ge => ">=",
+                    BinOp::Eq => "==",
+                    BinOp::Ne => "!=",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                };
+                write!(f, "({} {} {})", a, sym, b)
+            }
+            Expr::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, a) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", a)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Assignment operator of a statement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignOp {
+    Set,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Parsed statement: `var op expr`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub target: String,
+    pub op: AssignOp,
+    pub expr: Expr,
+}
+
+// ============================================================================
+// LEXER
+// ============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    const OPS: [&str; 22] = [
+        "**", "<=", ">=", "==", "!=", "+=", "-=", "*=", "/=", "+", "-", "*", "/", "%", "^", "<",
+        ">", "=", "!", "~", "&", "|",
+    ];
+
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = text.parse::<f64>().map_err(|_| {
+                BrianError::ParseError(format!("Invalid number '{}' in '{}'", text, src))
+            })?;
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match OPS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    i += op.len();
+                }
+                None => {
+                    return Err(BrianError::ParseError(format!(
+                        "Unexpected character '{}' in '{}'",
+                        c, src
+                    )))
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// ============================================================================
+// PARSER
+// ============================================================================
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    src: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(src)?,
+            pos: 0,
+            src,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn error(&self, msg: &str) -> BrianError {
+        BrianError::ParseError(format!("{} in '{}'", msg, self.src))
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(id)) if id == kw) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.peek() {
+            None => Ok(()),
+            Some(tok) => Err(self.error(&format!("Unexpected token {:?}", tok))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.eat_keyword("or") || self.eat_op("|") {
+            let rhs = self.parse_and()?;
+            lhs = Expr::Binary(BinOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_not()?;
+        while self.eat_keyword("and") || self.eat_op("&") {
+            let rhs = self.parse_not()?;
+            lhs = Expr::Binary(BinOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("not") || self.eat_op("!") || self.eat_op("~") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_additive()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("<")) => BinOp::Lt,
+                Some(Token::Op("<=")) => BinOp::Le,
+                Some(Token::Op(">")) => BinOp::Gt,
+                Some(Token::Op(">=")) => BinOp::Ge,
+                Some(Token::Op("==")) => BinOp::Eq,
+                Some(Token::Op("!=")) => BinOp::Ne,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_additive()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("+")) => BinOp::Add,
+                Some(Token::Op("-")) => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_multiplicative()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("*")) => BinOp::Mul,
+                Some(Token::Op("/")) => BinOp::Div,
+                Some(Token::Op("%")) => BinOp::Mod,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.eat_op("-") {
+            return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+        }
+        if self.eat_op("+") {
+            return self.parse_unary();
+        }
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> Result<Expr> {
+        let base = self.parse_atom()?;
+        if self.eat_op("**") || self.eat_op("^") {
+            // Right associative, and binds tighter than a unary minus on its left
+            let exponent = self.parse_unary()?;
+            return Ok(Expr::Binary(BinOp::Pow, Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(x)) => Ok(Expr::Number(x)),
+            Some(Token::Ident(name)) => {
+                if matches!(self.peek(), Some(Token::LParen)) {
+                    self.pos += 1;
+                    let mut args = vec![];
+                    if !matches!(self.peek(), Some(Token::RParen)) {
+                        loop {
+                            args.push(self.parse_or()?);
+                            if matches!(self.peek(), Some(Token::Comma)) {
+                                self.pos += 1;
+                            } else {
+                                break;
+                            }
+                        }
+                    }
+                    match self.next() {
+                        Some(Token::RParen) => Ok(Expr::Call(name, args)),
+                        _ => Err(self.error("Expected ')' after arguments")),
+                    }
+                } else {
+                    Ok(Expr::Name(name))
+                }
+            }
+            Some(Token::LParen) => {
+                let inner = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(inner),
+                    _ => Err(self.error("Expected ')'")),
+                }
+            }
+            Some(tok) => Err(self.error(&format!("Unexpected token {:?}", tok))),
+            None => Err(self.error("Unexpected end of expression")),
+        }
+    }
+}
+
+/// Parse an expression string
+pub fn parse_expression(src: &str) -> Result<Expr> {
+    let mut parser = Parser::new(src)?;
+    let expr = parser.parse_or()?;
+    parser.finish()?;
+    Ok(expr)
+}
+
+/// Parse a single statement (`v = v_reset`, `w += b`)
+pub fn parse_statement(src: &str) -> Result<Statement> {
+    let mut parser = Parser::new(src)?;
+
+    let target = match parser.next() {
+        Some(Token::Ident(name)) => name,
+        _ => return Err(parser.error("Expected variable name")),
+    };
+
+    let op = match parser.next() {
+        Some(Token::Op("=")) => AssignOp::Set,
+        Some(Token::Op("+=")) => AssignOp::Add,
+        Some(Token::Op("-=")) => AssignOp::Sub,
+        Some(Token::Op("*=")) => AssignOp::Mul,
+        Some(Token::Op("/=")) => AssignOp::Div,
+        _ => return Err(parser.error("Expected assignment operator")),
+    };
+
+    let expr = parser.parse_or()?;
+    parser.finish()?;
+
+    Ok(Statement { target, op, expr })
+}
+
+/// Parse a block of statements separated by newlines or `;`
+pub fn parse_statements(src: &str) -> Result<Vec<Statement>> {
+    src.split(['\n', ';'])
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(parse_statement)
+        .collect()
+}
+
+// ============================================================================
+// SYMBOLS
+// ============================================================================
+
+/// Maps names to value slots used by compiled programs
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+    /// Tabulated functions callable as `name(t)` or `name(t, i)`
+    functions: HashMap<String, Arc<TimedArray>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a symbol, returning its slot (existing slot if already present)
+    pub fn add(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.index.get(name) {
+            return slot;
+        }
+        let slot = self.names.len();
+        self.names.push(name.to_string());
+        self.index.insert(name.to_string(), slot);
+        slot
+    }
+
+    /// Make a [`TimedArray`] callable under its name
+    pub fn add_timed_array(&mut self, array: &TimedArray) {
+        self.functions.insert(array.name.clone(), Arc::new(array.clone()));
+    }
+
+    /// Make `name` refer to an existing slot
+    pub fn alias(&mut self, name: &str, slot: usize) {
+        self.index.insert(name.to_string(), slot);
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<usize> {
+        self.index.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+/// Unit names usable inside expressions
+pub fn unit_from_name(name: &str) -> Option<Unit> {
+    let unit = match name {
+        "second" | "s" => Unit::Second,
+        "ms" | "msecond" => Unit::Millisecond,
+        "us" | "usecond" => Unit::Microsecond,
+        "volt" | "V" => Unit::Volt,
+        "mV" | "mvolt" => Unit::Millivolt,
+        "amp" | "A" => Unit::Ampere,
+        "nA" | "namp" => Unit::Nanoampere,
+        "pA" | "pamp" => Unit::Picoampere,
+        "siemens" | "S" => Unit::Siemens,
+        "nS" | "nsiemens" => Unit::Nanosiemens,
+        "uS" | "usiemens" => Unit::Microsiemens,
+        "farad" | "F" => Unit::Farad,
+        "pF" | "pfarad" => Unit::Picofarad,
+        "ohm" => Unit::Ohm,
+        "Mohm" | "MOhm" => Unit::Megaohm,
+        "Gohm" | "GOhm" => Unit::Gigaohm,
+        "Hz" | "hertz" => Unit::Hertz,
+        "1" | "dimensionless" => Unit::Dimensionless,
+        _ => return None,
+    };
+    Some(unit)
+}
+
+pub(crate) fn named_constant(name: &str) -> Option<f64> {
+    match name {
+        "pi" => Some(std::f64::consts::PI),
+        "e" => Some(std::f64::consts::E),
+        "inf" => Some(f64::INFINITY),
+        "True" | "true" => Some(1.0),
+        "False" | "false" => Some(0.0),
+        _ => unit_from_name(name).map(|u| u.to_internal_factor()),
+    }
+}
+
+// ============================================================================
+// BYTECODE
+// ============================================================================
+
+/// Built-in functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Exp,
+    Log,
+    Log10,
+    Sqrt,
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Abs,
+    Floor,
+    Ceil,
+    Int,
+    Sign,
+    Clip,
+    Min,
+    Max,
+    Rand,
+    Randn,
+}
+
+impl Builtin {
+    pub(crate) fn from_name(name: &str) -> Option<(Self, usize)> {
+        let f = match name {
+            "exp" => (Builtin::Exp, 1),
+            "log" => (Builtin::Log, 1),
+            "log10" => (Builtin::Log10, 1),
+            "sqrt" => (Builtin::Sqrt, 1),
+            "sin" => (Builtin::Sin, 1),
+            "cos" => (Builtin::Cos, 1),
+            "tan" => (Builtin::Tan, 1),
+            "sinh" => (Builtin::Sinh, 1),
+            "cosh" => (Builtin::Cosh, 1),
+            "tanh" => (Builtin::Tanh, 1),
+            "abs" => (Builtin::Abs, 1),
+            "floor" => (Builtin::Floor, 1),
+            "ceil" => (Builtin::Ceil, 1),
+            "int" => (Builtin::Int, 1),
+            "sign" => (Builtin::Sign, 1),
+            "clip" => (Builtin::Clip, 3),
+            "min" | "minimum" => (Builtin::Min, 2),
+            "max" | "maximum" => (Builtin::Max, 2),
+            "rand" => (Builtin::Rand, 0),
+            "randn" => (Builtin::Randn, 0),
+            _ => return None,
+        };
+        Some(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    Const(f64),
+    Load(usize),
+    Neg,
+    Not,
+    Bin(BinOp),
+    Call(Builtin),
+    /// Look up table `.0` of the program with `.1` arguments (time, index)
+    Lookup(usize, usize),
+}
+
+/// Compiled expression (stack bytecode)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    code: Vec<Op>,
+    stack_size: usize,
+    tables: Vec<Arc<TimedArray>>,
+    /// Native closures generated by [`Program::compile_kernel`]
+    #[cfg(feature = "closures")]
+    kernel: Option<crate::codegen::Kernel>,
+}
+
+impl Program {
+    /// Compile an expression against a symbol table
+    pub fn compile(expr: &Expr, symbols: &SymbolTable) -> Result<Self> {
+        let mut code = vec![];
+        let mut tables = vec![];
+        emit(expr, symbols, &mut code, &mut tables)?;
+
+        // Track the maximum stack depth so evaluation never reallocates
+        let mut depth: usize = 0;
+        let mut stack_size = 0;
+        for op in &code {
+            match op {
+                Op::Const(_) | Op::Load(_) => depth += 1,
+                Op::Neg | Op::Not => {}
+                Op::Bin(_) => depth -= 1,
+                Op::Call(f) => {
+                    depth = depth + 1 - f.arity();
+                }
+                Op::Lookup(_, arity) => depth = depth + 1 - arity,
+            }
+            stack_size = stack_size.max(depth);
+        }
+
+        Ok(Self {
+            code,
+            stack_size,
+            tables,
+            #[cfg(feature = "closures")]
+            kernel: None,
+        })
+    }
+
+    /// Slots read by the program
+    pub fn slots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.code.iter().filter_map(|op| match op {
+            Op::Load(slot) => Some(*slot),
+            _ => None,
+        })
+    }
+
+    #[cfg(feature = "closures")]
+    pub(crate) fn ops(&self) -> &[Op] {
+        &self.code
+    }
+
+    #[cfg(feature = "closures")]
+    pub(crate) fn table(&self, k: usize) -> &Arc<TimedArray> {
+        &self.tables[k]
+    }
+
+    /// Generate native closures; later evaluations run them instead of
+    /// interpreting the bytecode
+    #[cfg(feature = "closures")]
+    pub fn compile_kernel(&mut self) {
+        if self.kernel.is_none() {
+            self.kernel = Some(crate::codegen::compile(self));
+        }
+    }
+
+    /// Parse and compile in one go
+    pub fn parse(src: &str, symbols: &SymbolTable) -> Result<Self> {
+        Self::compile(&parse_expression(src)?, symbols)
+    }
+
+    /// Evaluate against slot values
+    pub fn eval(&self, values: &[f64], rng: &mut Rng) -> f64 {
+        #[cfg(feature = "closures")]
+        if let Some(kernel) = &self.kernel {
+            return kernel.eval(values, rng);
+        }
+        let mut stack: Vec<f64> = Vec::with_capacity(self.stack_size);
+        for op in &self.code {
+            match *op {
+                Op::Const(x) => stack.push(x),
+                Op::Load(slot) => stack.push(values[slot]),
+                Op::Neg => {
+                    let x = stack.pop().unwrap_or(0.0);
+                    stack.push(-x);
+                }
+                Op::Not => {
+                    let x = stack.pop().unwrap_or(0.0);
+                    stack.push(bool_to_f64(x == 0.0));
+                }
+                Op::Bin(op) => {
+                    let b = stack.pop().unwrap_or(0.0);
+                    let a = stack.pop().unwrap_or(0.0);
+                    stack.push(apply_binary(op, a, b));
+                }
+                Op::Call(f) => {
+                    let value = match f {
+                        Builtin::Rand => rng.uniform(),
+                        Builtin::Randn => rng.normal(),
+                        Builtin::Clip => {
+                            let hi = stack.pop().unwrap_or(0.0);
+                            let lo = stack.pop().unwrap_or(0.0);
+                            let x = stack.pop().unwrap_or(0.0);
+                            x.max(lo).min(hi)
+                        }
+                        Builtin::Min | Builtin::Max => {
+                            let b = stack.pop().unwrap_or(0.0);
+                            let a = stack.pop().unwrap_or(0.0);
+                            if f == Builtin::Min { a.min(b) } else { a.max(b) }
+                        }
+                        _ => {
+                            let x = stack.pop().unwrap_or(0.0);
+                            apply_unary(f, x)
+                        }
+                    };
+                    stack.push(value);
+                }
+                Op::Lookup(table, arity) => {
+                    let column = if arity == 2 { stack.pop().unwrap_or(0.0) } else { 0.0 };
+                    let t = stack.pop().unwrap_or(0.0);
+                    stack.push(self.tables[table].lookup(t, column as usize));
+                }
+            }
+        }
+        stack.pop().unwrap_or(0.0)
+    }
+
+    /// Evaluate as a boolean condition
+    pub fn eval_bool(&self, values: &[f64], rng: &mut Rng) -> bool {
+        self.eval(values, rng) != 0.0
+    }
+
+    /// Whether the program only consists of a constant
+    pub fn as_constant(&self) -> Option<f64> {
+        match self.code.as_slice() {
+            [Op::Const(x)] => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+impl Builtin {
+    fn arity(self) -> usize {
+        match self {
+            Builtin::Rand | Builtin::Randn => 0,
+            Builtin::Clip => 3,
+            Builtin::Min | Builtin::Max => 2,
+            _ => 1,
+        }
+    }
+}
+
+fn emit(expr: &Expr, symbols: &SymbolTable, code: &mut Vec<Op>, tables: &mut Vec<Arc<TimedArray>>) -> Result<()> {
+    match expr {
+        Expr::Number(x) => code.push(Op::Const(*x)),
+        Expr::Name(name) => {
+            if let Some(slot) = symbols.resolve(name) {
+                code.push(Op::Load(slot));
+            } else if let Some(value) = named_constant(name) {
+                code.push(Op::Const(value));
+            } else {
+                return Err(BrianError::EquationError(format!(
+                    "Unknown identifier '{}'",
+                    name
+                )));
+            }
+        }
+        Expr::Neg(e) => {
+            emit(e, symbols, code, tables)?;
+            fold_unary(code, Op::Neg);
+        }
+        Expr::Not(e) => {
+            emit(e, symbols, code, tables)?;
+            fold_unary(code, Op::Not);
+        }
+        Expr::Binary(op, a, b) => {
+            emit(a, symbols, code, tables)?;
+            emit(b, symbols, code, tables)?;
+            // Constant folding keeps unit factors (`5*mV`) free at runtime
+            if let [.., Op::Const(x), Op::Const(y)] = code.as_slice() {
+                let folded = apply_binary(*op, *x, *y);
+                code.truncate(code.len() - 2);
+                code.push(Op::Const(folded));
+            } else {
+                code.push(Op::Bin(*op));
+            }
+        }
+        Expr::Call(name, args) if symbols.functions.contains_key(name) => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(BrianError::EquationError(format!(
+                    "Timed array '{}' takes (t) or (t, i), got {} argument(s)",
+                    name,
+                    args.len()
+                )));
+            }
+            for arg in args {
+                emit(arg, symbols, code, tables)?;
+            }
+            tables.push(symbols.functions[name].clone());
+            code.push(Op::Lookup(tables.len() - 1, args.len()));
+        }
+        Expr::Call(name, args) => {
+            let (builtin, arity) = Builtin::from_name(name).ok_or_else(|| {
+                BrianError::EquationError(format!("Unknown function '{}'", name))
+            })?;
+            if args.len() != arity {
+                return Err(BrianError::EquationError(format!(
+                    "Function '{}' takes {} argument(s), got {}",
+                    name,
+                    arity,
+                    args.len()
+                )));
+            }
+            for arg in args {
+                emit(arg, symbols, code, tables)?;
+            }
+            code.push(Op::Call(builtin));
+        }
+    }
+    Ok(())
+}
+
+fn fold_unary(code: &mut Vec<Op>, op: Op) {
+    if let Some(Op::Const(x)) = code.last().copied() {
+        code.pop();
+        code.push(Op::Const(match op {
+            Op::Neg => -x,
+            _ => bool_to_f64(x == 0.0),
+        }));
+    } else {
+        code.push(op);
+    }
+}
+
+pub(crate) fn bool_to_f64(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
+
+pub(crate) fn apply_binary(op: BinOp, a: f64, b: f64) -> f64 {
+    match op {
+        BinOp::Add => a + b,
+        BinOp::Sub => a - b,
+        BinOp::Mul => a * b,
+        BinOp::Div => a / b,
+        BinOp::Mod => a.rem_euclid(b),
+        BinOp::Pow => {
+            if b == 2.0 { a * a } else { a.powf(b) }
+        }
+        BinOp::Lt => bool_to_f64(a < b),
+        BinOp::Le => bool_to_f64(a <= b),
+        BinOp::Gt => bool_to_f64(a > b),
+        BinOp::Ge => bool_to_f64(a >= b),
+        BinOp::Eq => bool_to_f64(a == b),
+        BinOp::Ne => bool_to_f64(a != b),
+        BinOp::And => bool_to_f64(a != 0.0 && b != 0.0),
+        BinOp::Or => bool_to_f64(a != 0.0 || b != 0.0),
+    }
+}
+
+pub(crate) fn apply_unary(f: Builtin, x: f64) -> f64 {
+    match f {
+        Builtin::Exp => x.exp(),
+        Builtin::Log => x.ln(),
+        Builtin::Log10 => x.log10(),
+        Builtin::Sqrt => x.sqrt(),
+        Builtin::Sin => x.sin(),
+        Builtin::Cos => x.cos(),
+        Builtin::Tan => x.tan(),
+        Builtin::Sinh => x.sinh(),
+        Builtin::Cosh => x.cosh(),
+        Builtin::Tanh => x.tanh(),
+        Builtin::Abs => x.abs(),
+        Builtin::Floor => x.floor(),
+        Builtin::Ceil => x.ceil(),
+        Builtin::Int => x.trunc(),
+        Builtin::Sign => {
+            if x > 0.0 { 1.0 } else if x < 0.0 { -1.0 } else { 0.0 }
+        }
+        _ => x,
+    }
+}
+
+/// Compiled statement: `slot op= program`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledStatement {
+    pub target: usize,
+    pub op: AssignOp,
+    pub program: Program,
+}
+
+impl CompiledStatement {
+    pub fn compile(stmt: &Statement, symbols: &SymbolTable) -> Result<Self> {
+        let target = symbols.resolve(&stmt.target).ok_or_else(|| {
+            BrianError::EquationError(format!("Cannot assign to unknown variable '{}'", stmt.target))
+        })?;
+        Ok(Self {
+            target,
+            op: stmt.op,
+            program: Program::compile(&stmt.expr, symbols)?,
+        })
+    }
+
+    /// Execute the statement, updating `values` in place
+    pub fn execute(&self, values: &mut [f64], rng: &mut Rng) {
+        let rhs = self.program.eval(values, rng);
+        let slot = &mut values[self.target];
+        match self.op {
+            AssignOp::Set => *slot = rhs,
+            AssignOp::Add => *slot += rhs,
+            AssignOp::Sub => *slot -= rhs,
+            AssignOp::Mul => *slot *= rhs,
+            AssignOp::Div => *slot /= rhs,
+        }
+    }
+}
+
+/// Compile a block of statements
+pub fn compile_statements(src: &str, symbols: &SymbolTable) -> Result<Vec<CompiledStatement>> {
+    parse_statements(src)?
+        .iter()
+        .map(|stmt| CompiledStatement::compile(stmt, symbols))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str, symbols: &SymbolTable, values: &[f64]) -> f64 {
+        let mut rng = Rng::new(1);
+        Program::parse(src, symbols).unwrap().eval(values, &mut rng)
+    }
+
+    #[test]
+    fn test_precedence() {
+        let symbols = SymbolTable::new();
+        assert_eq!(eval("1 + 2 * 3", &symbols, &[]), 7.0);
+        assert_eq!(eval("-2**2", &symbols, &[]), -4.0);
+        assert_eq!(eval("2**3**2", &symbols, &[]), 512.0);
+        assert_eq!(eval("(1 + 2) * 3", &symbols, &[]), 9.0);
+        assert_eq!(eval("1 < 2 and not 3 < 2", &symbols, &[]), 1.0);
+        assert_eq!(eval("1.5e-3 * 2", &symbols, &[]), 3e-3);
+    }
+
+    #[test]
+    fn test_variables_and_functions() {
+        let mut symbols = SymbolTable::new();
+        symbols.add("v");
+        symbols.add("tau");
+        let values = [-70.0, 10.0];
+        assert_eq!(eval("(-65 - v) / tau", &symbols, &values), 0.5);
+        assert!((eval("exp(v / tau)", &symbols, &values) - (-7.0f64).exp()).abs() < 1e-12);
+        assert_eq!(eval("clip(v, -60, 0)", &symbols, &values), -60.0);
+    }
+
+    #[test]
+    fn test_units_fold_to_constants() {
+        let symbols = SymbolTable::new();
+        let program = Program::parse("-50*mV + 2*volt", &symbols).unwrap();
+        assert_eq!(program.as_constant(), Some(1950.0));
+    }
+
+    #[test]
+    fn test_unknown_identifier() {
+        let symbols = SymbolTable::new();
+        assert!(matches!(
+            Program::parse("v + 1", &symbols),
+            Err(BrianError::EquationError(_))
+        ));
+        assert!(matches!(parse_expression("1 +"), Err(BrianError::ParseError(_))));
+    }
+
+    #[test]
+    fn test_statements() {
+        let mut symbols = SymbolTable::new();
+        symbols.add("v");
+        symbols.add("w");
+        let stmts = compile_statements("v = -65; w += 2*v", &symbols).unwrap();
+        let mut values = [0.0, 1.0];
+        let mut rng = Rng::new(1);
+        for stmt in &stmts {
+            stmt.execute(&mut values, &mut rng);
+        }
+        assert_eq!(values, [-65.0, -129.0]);
+    }
+}
@@ -11,9 +11,31 @@
 //! - Network topology and connectivity
 //! - Spike monitors and state monitors
 
+#[cfg(feature = "closures")]
+pub mod codegen;
+pub mod expr;
+pub mod linear;
+pub mod pathway;
+pub mod rate;
+pub mod script;
+pub mod spatial;
+pub mod spikequeue;
+pub mod standalone;
+pub mod swc;
+pub mod units;
+
+use expr::{CompiledStatement, Expr, Program, SymbolTable};
+use linear::{LinearSystem, Propagator};
+use pathway::{Pathway, PathwayState, Scope};
 use ndarray::{Array1, Array2};
+use oldies_core::Rng;
+use rate::{RateConnection, RateGroup};
+use spatial::SpatialNeuron;
+use spikequeue::SpikeQueue;
+use units::Dimension;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -70,6 +92,9 @@ pub enum Unit {
 
     // Dimensionless
     Dimensionless,
+
+    /// Unscaled SI unit of arbitrary dimension (e.g. volt/second)
+    Compound(Dimension),
 }
 
 impl Unit {
@@ -94,6 +119,32 @@ impl Unit {
             Unit::Gigaohm => 1e9,
             Unit::Hertz => 1.0,
             Unit::Dimensionless => 1.0,
+            Unit::Compound(_) => 1.0,
+        }
+    }
+
+    /// Scale factor to the simulator's base system (ms, mV, nA, uS, nF, MOhm)
+    pub fn to_internal_factor(&self) -> f64 {
+        match self {
+            Unit::Second => 1e3,
+            Unit::Millisecond => 1.0,
+            Unit::Microsecond => 1e-3,
+            Unit::Volt => 1e3,
+            Unit::Millivolt => 1.0,
+            Unit::Ampere => 1e9,
+            Unit::Nanoampere => 1.0,
+            Unit::Picoampere => 1e-3,
+            Unit::Siemens => 1e6,
+            Unit::Nanosiemens => 1e-3,
+            Unit::Microsiemens => 1.0,
+            Unit::Farad => 1e9,
+            Unit::Picofarad => 1e-3,
+            Unit::Ohm => 1e-6,
+            Unit::Megaohm => 1.0,
+            Unit::Gigaohm => 1e3,
+            Unit::Hertz => 1e-3,
+            Unit::Dimensionless => 1.0,
+            Unit::Compound(dim) => dim.internal_factor(),
         }
     }
 }
@@ -114,6 +165,11 @@ impl Quantity {
     pub fn to_si(&self) -> f64 {
         self.value * self.unit.to_si_factor()
     }
+
+    /// Convert to the simulator's base system (ms, mV, nA, ...)
+    pub fn to_internal(&self) -> f64 {
+        self.value * self.unit.to_internal_factor()
+    }
 }
 
 // ============================================================================
@@ -128,6 +184,9 @@ pub struct DifferentialEquation {
     pub unit: Unit,
     /// Method: euler, rk2, rk4, exponential_euler
     pub method: IntegrationMethod,
+    /// Frozen while the neuron is refractory: `(unless refractory)`
+    #[serde(default)]
+    pub unless_refractory: bool,
 }
 
 /// Algebraic equation: v = expr (computed each timestep)
@@ -138,6 +197,15 @@ pub struct AlgebraicEquation {
     pub unit: Unit,
 }
 
+/// Per-neuron parameter declaration: `v_th : volt (constant)`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParameterDeclaration {
+    pub variable: String,
+    pub unit: Unit,
+    /// Reset and `run_regularly` code may not change it
+    pub constant: bool,
+}
+
 /// Threshold condition for spike generation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThresholdCondition {
@@ -169,11 +237,23 @@ pub enum IntegrationMethod {
     ExactSolution,  // For analytically solvable equations
 }
 
+/// How compiled expressions are evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Backend {
+    /// Bytecode interpreter
+    #[default]
+    Interpreter,
+    /// Native closures generated at compile time (`closures` feature)
+    Closures,
+}
+
 /// Complete neuron equations
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NeuronEquations {
     pub differential: Vec<DifferentialEquation>,
     pub algebraic: Vec<AlgebraicEquation>,
+    /// Variables with one value per neuron and no equation
+    pub per_neuron: Vec<ParameterDeclaration>,
     pub threshold: Option<ThresholdCondition>,
     pub reset: Option<ResetEquations>,
     pub refractory: Option<RefractorySpec>,
@@ -215,19 +295,21 @@ impl LIFNeuron {
                 DifferentialEquation {
                     variable: "v".into(),
                     expression: format!(
-                        "(({} - v) + {} * I) / {}",
+                        "(({} * mV - v) + {} * Mohm * I) / ({} * ms)",
                         self.v_rest, self.r_m, self.tau_m
                     ),
                     unit: Unit::Millivolt,
                     method: IntegrationMethod::ExponentialEuler,
+                    unless_refractory: true,
                 },
             ],
             algebraic: vec![],
+            per_neuron: vec![],
             threshold: Some(ThresholdCondition {
-                condition: format!("v > {}", self.v_thresh),
+                condition: format!("v > {} * mV", self.v_thresh),
             }),
             reset: Some(ResetEquations {
-                equations: vec![format!("v = {}", self.v_reset)],
+                equations: vec![format!("v = {} * mV", self.v_reset)],
             }),
             refractory: Some(RefractorySpec::Duration(
                 Quantity::new(self.tau_ref, Unit::Millisecond)
@@ -276,31 +358,34 @@ impl AdExNeuron {
                 DifferentialEquation {
                     variable: "v".into(),
                     expression: format!(
-                        "(-{} * (v - {}) + {} * {} * exp((v - {}) / {}) - w + I) / {}",
+                        "(-{} * nS * (v - {} * mV) + {} * nS * {} * mV * exp((v - {} * mV) / ({} * mV)) - w + I) / ({} * pF)",
                         self.g_l, self.e_l, self.g_l, self.delta_t,
                         self.v_t, self.delta_t, self.c_m
                     ),
                     unit: Unit::Millivolt,
                     method: IntegrationMethod::Euler,
+                    unless_refractory: true,
                 },
                 DifferentialEquation {
                     variable: "w".into(),
                     expression: format!(
-                        "({} * (v - {}) - w) / {}",
+                        "({} * nS * (v - {} * mV) - w) / ({} * ms)",
                         self.a, self.e_l, self.tau_w
                     ),
                     unit: Unit::Picoampere,
                     method: IntegrationMethod::Euler,
+                    unless_refractory: false,
                 },
             ],
             algebraic: vec![],
+            per_neuron: vec![],
             threshold: Some(ThresholdCondition {
-                condition: format!("v > {}", self.v_peak),
+                condition: format!("v > {} * mV", self.v_peak),
             }),
             reset: Some(ResetEquations {
                 equations: vec![
-                    format!("v = {}", self.v_reset),
-                    format!("w += {}", self.b),
+                    format!("v = {} * mV", self.v_reset),
+                    format!("w += {} * pA", self.b),
                 ],
             }),
             refractory: None,
@@ -347,20 +432,24 @@ impl IzhikevichNeuron {
     pub fn to_equations(&self) -> NeuronEquations {
         NeuronEquations {
             differential: vec![
+                // Dimensionless form: v in mV, time in ms
                 DifferentialEquation {
                     variable: "v".into(),
-                    expression: "0.04 * v * v + 5.0 * v + 140.0 - u + I".into(),
-                    unit: Unit::Millivolt,
+                    expression: "(0.04 * v * v + 5.0 * v + 140.0 - u + I) / ms".into(),
+                    unit: Unit::Dimensionless,
                     method: IntegrationMethod::Euler,
+                    unless_refractory: false,
                 },
                 DifferentialEquation {
                     variable: "u".into(),
-                    expression: format!("{} * ({} * v - u)", self.a, self.b),
+                    expression: format!("{} * ({} * v - u) / ms", self.a, self.b),
                     unit: Unit::Dimensionless,
                     method: IntegrationMethod::Euler,
+                    unless_refractory: false,
                 },
             ],
             algebraic: vec![],
+            per_neuron: vec![],
             threshold: Some(ThresholdCondition {
                 condition: "v >= 30.0".into(),
             }),
@@ -376,6 +465,85 @@ impl IzhikevichNeuron {
     }
 }
 
+/// Conductance-based LIF neuron (Vogels & Abbott 2005, Brian's COBA example)
+///
+/// Synaptic conductances `ge`, `gi` are expressed relative to the leak
+/// conductance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct COBANeuron {
+    pub tau_m: f64,      // Membrane time constant (ms)
+    pub e_l: f64,        // Leak reversal (mV)
+    pub e_exc: f64,      // Excitatory reversal (mV)
+    pub e_inh: f64,      // Inhibitory reversal (mV)
+    pub tau_e: f64,      // Excitatory conductance decay (ms)
+    pub tau_i: f64,      // Inhibitory conductance decay (ms)
+    pub v_reset: f64,    // Reset potential (mV)
+    pub v_thresh: f64,   // Spike threshold (mV)
+    pub tau_ref: f64,    // Refractory period (ms)
+}
+
+impl Default for COBANeuron {
+    fn default() -> Self {
+        Self {
+            tau_m: 20.0,
+            e_l: -49.0,  // Above threshold: the benchmark is self-sustained
+            e_exc: 0.0,
+            e_inh: -80.0,
+            tau_e: 5.0,
+            tau_i: 10.0,
+            v_reset: -60.0,
+            v_thresh: -50.0,
+            tau_ref: 5.0,
+        }
+    }
+}
+
+impl COBANeuron {
+    pub fn to_equations(&self) -> NeuronEquations {
+        // Conditionally linear: exponential Euler is stable at large dt
+        NeuronEquations {
+            differential: vec![
+                DifferentialEquation {
+                    variable: "v".into(),
+                    expression: format!(
+                        "(ge * ({} * mV - v) + gi * ({} * mV - v) - (v - {} * mV)) / ({} * ms)",
+                        self.e_exc, self.e_inh, self.e_l, self.tau_m
+                    ),
+                    unit: Unit::Millivolt,
+                    method: IntegrationMethod::ExponentialEuler,
+                    unless_refractory: true,
+                },
+                DifferentialEquation {
+                    variable: "ge".into(),
+                    expression: format!("-ge / ({} * ms)", self.tau_e),
+                    unit: Unit::Dimensionless,
+                    method: IntegrationMethod::ExponentialEuler,
+                    unless_refractory: false,
+                },
+                DifferentialEquation {
+                    variable: "gi".into(),
+                    expression: format!("-gi / ({} * ms)", self.tau_i),
+                    unit: Unit::Dimensionless,
+                    method: IntegrationMethod::ExponentialEuler,
+                    unless_refractory: false,
+                },
+            ],
+            algebraic: vec![],
+            per_neuron: vec![],
+            threshold: Some(ThresholdCondition {
+                condition: format!("v > {} * mV", self.v_thresh),
+            }),
+            reset: Some(ResetEquations {
+                equations: vec![format!("v = {} * mV", self.v_reset)],
+            }),
+            refractory: Some(RefractorySpec::Duration(
+                Quantity::new(self.tau_ref, Unit::Millisecond)
+            )),
+            parameters: HashMap::new(),
+        }
+    }
+}
+
 // ============================================================================
 // SYNAPSE MODELS
 // ============================================================================
@@ -422,6 +590,87 @@ pub enum SynapseModel {
     },
 }
 
+/// Reversal potential of NMDA receptors (mV)
+pub const E_NMDA: f64 = 0.0;
+
+/// Variable incremented directly by instantaneous synapses
+pub const MEMBRANE_SYMBOL: &str = "v";
+
+impl SynapseModel {
+    /// Default weight of new connections
+    pub fn weight(&self) -> f64 {
+        match *self {
+            SynapseModel::Delta { weight }
+            | SynapseModel::Exponential { weight, .. }
+            | SynapseModel::Alpha { weight, .. }
+            | SynapseModel::DualExponential { weight, .. }
+            | SynapseModel::NMDA { weight, .. }
+            | SynapseModel::STP { weight, .. } => weight,
+        }
+    }
+
+    /// Whether spikes jump the target's membrane potential instead of
+    /// driving a postsynaptic current
+    pub fn is_instantaneous(&self) -> bool {
+        matches!(self, SynapseModel::Delta { .. } | SynapseModel::STP { .. })
+    }
+
+    /// Amount added to the (g, h) state of the target for a spike of weight `w`
+    fn increment(&self, w: f64) -> (f64, f64) {
+        match *self {
+            SynapseModel::Exponential { .. } => (w, 0.0),
+            SynapseModel::Alpha { .. } => (0.0, w),
+            SynapseModel::DualExponential { tau_rise, tau_decay, .. }
+            | SynapseModel::NMDA { tau_rise, tau_decay, .. } => {
+                let w = w * dual_exponential_norm(tau_rise, tau_decay);
+                (w, w)
+            }
+            SynapseModel::Delta { .. } | SynapseModel::STP { .. } => (0.0, 0.0),
+        }
+    }
+
+    /// Exact decay of the (g, h) state over one step
+    fn decay(&self, g: &mut f64, h: &mut f64, dt: f64) {
+        match *self {
+            SynapseModel::Exponential { tau, .. } => *g *= (-dt / tau).exp(),
+            SynapseModel::Alpha { tau, .. } => {
+                // g' = (e*h - g)/tau, h' = -h/tau
+                let decay = (-dt / tau).exp();
+                *g = (*g + std::f64::consts::E * *h * dt / tau) * decay;
+                *h *= decay;
+            }
+            SynapseModel::DualExponential { tau_rise, tau_decay, .. }
+            | SynapseModel::NMDA { tau_rise, tau_decay, .. } => {
+                *g *= (-dt / tau_decay).exp();
+                *h *= (-dt / tau_rise).exp();
+            }
+            SynapseModel::Delta { .. } | SynapseModel::STP { .. } => {}
+        }
+    }
+
+    /// Postsynaptic current (nA) for state (g, h) at membrane potential `v`
+    fn current(&self, g: f64, h: f64, v: f64) -> f64 {
+        match *self {
+            SynapseModel::Exponential { .. } | SynapseModel::Alpha { .. } => g,
+            SynapseModel::DualExponential { .. } => g - h,
+            SynapseModel::NMDA { mg_concentration, .. } => {
+                let block = 1.0 / (1.0 + mg_concentration / 3.57 * (-0.062 * v).exp());
+                (g - h) * block * (E_NMDA - v)
+            }
+            SynapseModel::Delta { .. } | SynapseModel::STP { .. } => 0.0,
+        }
+    }
+}
+
+/// Factor making the peak of exp(-t/tau_decay) - exp(-t/tau_rise) equal to one
+fn dual_exponential_norm(tau_rise: f64, tau_decay: f64) -> f64 {
+    if (tau_decay - tau_rise).abs() < 1e-12 {
+        return 1.0;
+    }
+    let t_peak = tau_rise * tau_decay / (tau_decay - tau_rise) * (tau_decay / tau_rise).ln();
+    1.0 / ((-t_peak / tau_decay).exp() - (-t_peak / tau_rise).exp())
+}
+
 /// Spike-Timing-Dependent Plasticity
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct STDPRule {
@@ -446,6 +695,24 @@ impl Default for STDPRule {
     }
 }
 
+impl STDPRule {
+    /// Weight change of an isolated spike pair separated by
+    /// `delta_t = t_post - t_pre` (ms)
+    pub fn window(&self, delta_t: f64) -> f64 {
+        if delta_t > 0.0 {
+            self.a_plus * (-delta_t / self.tau_pre).exp()
+        } else if delta_t < 0.0 {
+            -self.a_minus * (delta_t / self.tau_post).exp()
+        } else {
+            0.0
+        }
+    }
+
+    pub fn clip(&self, w: f64) -> f64 {
+        w.clamp(self.w_min, self.w_max)
+    }
+}
+
 // ============================================================================
 // NEURON GROUP
 // ============================================================================
@@ -457,12 +724,478 @@ pub struct NeuronGroup {
     pub n: usize,
     pub equations: NeuronEquations,
     pub method: IntegrationMethod,
+    /// Expression evaluation backend
+    pub backend: Backend,
     /// State variables for all neurons
     pub state: HashMap<String, Array1<f64>>,
+    /// Input current for each neuron (the `I` symbol in equations)
+    pub input: Array1<f64>,
+    /// Synaptic current for each neuron, added to `input` and recomputed
+    /// by the network every step
+    pub synaptic_input: Array1<f64>,
     /// Last spike time for each neuron (-inf if never spiked)
     pub last_spike: Array1<f64>,
     /// Is neuron currently in refractory period?
     pub refractory_until: Array1<f64>,
+    /// Timed arrays callable from the equations
+    pub timed_arrays: HashMap<String, TimedArray>,
+    /// Statement blocks run at fixed intervals
+    pub regular_operations: Vec<RegularOperation>,
+    /// Own clock period in ms (a multiple of the network's `dt`); `None`
+    /// updates every step
+    #[serde(default)]
+    pub dt: Option<f64>,
+    /// User-defined events checked alongside the threshold
+    #[serde(default)]
+    pub events: Vec<CustomEvent>,
+    /// Neurons where each custom event occurred during the last update,
+    /// with their state variables (in `CompiledEquations::state_vars`
+    /// order) as they were when the condition was checked
+    #[serde(skip)]
+    pub events_fired: HashMap<String, Vec<(usize, Vec<f64>)>>,
+    /// Equations compiled against the group's symbols (built lazily)
+    #[serde(skip)]
+    compiled: Option<CompiledEquations>,
+}
+
+/// Statements run on a group every `dt` ms, before integration (Brian's
+/// `run_regularly`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegularOperation {
+    pub code: String,
+    pub dt: f64,
+    /// Neurons to run on, or `None` for all
+    pub neurons: Option<Vec<usize>>,
+}
+
+impl RegularOperation {
+    /// Whether the operation is due at time `t` of a simulation with time step `dt`
+    fn is_due(&self, t: f64, dt: f64) -> bool {
+        let every = ((self.dt / dt).round() as u64).max(1);
+        ((t / dt).round() as u64).is_multiple_of(every)
+    }
+}
+
+/// Event other than the spike, with its own condition and statements
+/// (Brian's `events={'name': condition}` and `run_on_event`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomEvent {
+    pub name: String,
+    pub condition: String,
+    /// Statements run on the neurons where the event occurred, after the
+    /// spike reset
+    pub code: Vec<String>,
+}
+
+/// Symbol assigned to the group's input current
+pub const INPUT_SYMBOL: &str = "I";
+
+/// Boolean that is false while a neuron is refractory
+pub const NOT_REFRACTORY_SYMBOL: &str = "not_refractory";
+
+/// Group equations compiled to bytecode
+#[derive(Debug, Clone)]
+pub struct CompiledEquations {
+    pub symbols: SymbolTable,
+    /// State variables stored in `NeuronGroup::state`, in slot order
+    pub state_vars: Vec<String>,
+    /// (slot, right-hand side) of each differential equation
+    pub derivatives: Vec<(usize, Program)>,
+    /// (slot, expression) of each algebraic equation, in definition order
+    pub algebraic: Vec<(usize, Program)>,
+    /// `dx/dt = A + B*x` split of each derivative, if conditionally linear
+    pub exponential_euler: Vec<Option<(Program, Program)>>,
+    /// The whole system, if linear with constant coefficients
+    pub linear_system: Option<LinearSystem>,
+    /// Slot of the input current, if the equations don't define `I` themselves
+    pub input_slot: Option<usize>,
+    pub t_slot: usize,
+    pub dt_slot: usize,
+    pub index_slot: usize,
+    pub not_refractory_slot: usize,
+    /// Slots of the variables flagged `(unless refractory)`
+    pub frozen_slots: Vec<usize>,
+    /// Slot values shared by all neurons (parameters, `N`)
+    pub constants: Vec<f64>,
+    pub threshold: Option<Program>,
+    pub reset: Vec<CompiledStatement>,
+    /// Compiled custom event conditions and statements, in the group's order
+    pub events: Vec<(Program, Vec<CompiledStatement>)>,
+    /// Compiled `RegularOperation` code, in the group's order
+    pub regular: Vec<Vec<CompiledStatement>>,
+    /// Slots of per-neuron parameters flagged `constant`
+    pub constant_slots: Vec<usize>,
+    /// Fixed refractory period (ms)
+    pub refractory_period: Option<f64>,
+    /// Neurons stay refractory while this condition holds
+    pub refractory_condition: Option<Program>,
+}
+
+/// Reject statements that assign to a constant parameter
+fn check_writable(code: &[CompiledStatement], constant_slots: &[usize], symbols: &SymbolTable) -> Result<()> {
+    match code.iter().find(|stmt| constant_slots.contains(&stmt.target)) {
+        Some(stmt) => Err(BrianError::EquationError(format!(
+            "Cannot assign to constant parameter '{}'",
+            symbols.names()[stmt.target]
+        ))),
+        None => Ok(()),
+    }
+}
+
+impl CompiledEquations {
+    pub fn compile(equations: &NeuronEquations, n: usize, timed_arrays: &HashMap<String, TimedArray>) -> Result<Self> {
+        equations.check_units()?;
+
+        let mut symbols = SymbolTable::new();
+        for array in timed_arrays.values() {
+            symbols.add_timed_array(array);
+        }
+        let mut state_vars = vec![];
+
+        for eq in &equations.differential {
+            symbols.add(&eq.variable);
+            state_vars.push(eq.variable.clone());
+        }
+        for eq in &equations.algebraic {
+            symbols.add(&eq.variable);
+            state_vars.push(eq.variable.clone());
+        }
+        let mut constant_slots = vec![];
+        for decl in &equations.per_neuron {
+            let slot = symbols.add(&decl.variable);
+            state_vars.push(decl.variable.clone());
+            if decl.constant {
+                constant_slots.push(slot);
+            }
+        }
+
+        let mut parameters: Vec<(&String, &Quantity)> = equations.parameters.iter().collect();
+        parameters.sort_by(|a, b| a.0.cmp(b.0));
+        let mut param_slots = vec![];
+        for (name, quantity) in parameters {
+            if symbols.resolve(name).is_none() {
+                param_slots.push((symbols.add(name), quantity.to_internal()));
+            }
+        }
+
+        let input_slot = if symbols.resolve(INPUT_SYMBOL).is_none() {
+            Some(symbols.add(INPUT_SYMBOL))
+        } else {
+            None
+        };
+        let t_slot = symbols.add("t");
+        let dt_slot = symbols.add("dt");
+        let index_slot = symbols.add("i");
+        let n_slot = symbols.add("N");
+        let not_refractory_slot = symbols.add(NOT_REFRACTORY_SYMBOL);
+
+        let mut constants = vec![0.0; symbols.len()];
+        for (slot, value) in param_slots {
+            constants[slot] = value;
+        }
+        constants[n_slot] = n as f64;
+        constants[not_refractory_slot] = 1.0;
+
+        // As in Brian, a frozen variable's derivative is multiplied by
+        // `not_refractory`, so it is constant in every stage of the step
+        let frozen = |eq: &DifferentialEquation| if eq.unless_refractory {
+            format!("{} * ({})", NOT_REFRACTORY_SYMBOL, eq.expression)
+        } else {
+            eq.expression.clone()
+        };
+        let frozen_slots = equations.differential.iter()
+            .filter(|eq| eq.unless_refractory)
+            .filter_map(|eq| symbols.resolve(&eq.variable))
+            .collect();
+
+        let derivatives = equations.differential.iter()
+            .map(|eq| {
+                let slot = symbols.resolve(&eq.variable).unwrap_or_default();
+                Program::parse(&frozen(eq), &symbols).map(|p| (slot, p))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let algebraic = equations.algebraic.iter()
+            .map(|eq| {
+                let slot = symbols.resolve(&eq.variable).unwrap_or_default();
+                Program::parse(&eq.expression, &symbols).map(|p| (slot, p))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Linear analysis works on derivatives with algebraic variables
+        // substituted by their definitions
+        let mut definitions: HashMap<String, Expr> = HashMap::new();
+        for eq in &equations.algebraic {
+            let def = expr::parse_expression(&eq.expression)?.substitute(&definitions);
+            definitions.insert(eq.variable.clone(), def);
+        }
+        let rhs = equations.differential.iter()
+            .map(|eq| Ok(expr::parse_expression(&eq.expression)?.substitute(&definitions)))
+            .collect::<Result<Vec<_>>>()?;
+        let vars: Vec<&str> = equations.differential.iter().map(|eq| eq.variable.as_str()).collect();
+
+        let exponential_euler = equations.differential.iter().zip(&vars)
+            .map(|(eq, var)| {
+                let e = expr::parse_expression(&frozen(eq))?.substitute(&definitions);
+                match linear::affine(&e, &[var]) {
+                    Some(split) => Ok(Some((
+                        Program::compile(&split.offset, &symbols)?,
+                        Program::compile(&split.coeffs[0], &symbols)?,
+                    ))),
+                    None => Ok(None),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut dynamic = vars.clone();
+        dynamic.extend([INPUT_SYMBOL, "t", "dt", "i", NOT_REFRACTORY_SYMBOL]);
+        dynamic.extend(equations.algebraic.iter().map(|eq| eq.variable.as_str()));
+        let mut matrix = vec![];
+        let mut offsets = vec![];
+        for e in &rhs {
+            let Some(split) = linear::affine(e, &vars) else { break };
+            if split.coeffs.iter().any(|c| c.is_stochastic() || c.names().iter().any(|n| dynamic.contains(&n.as_str()))) {
+                break;
+            }
+            for c in &split.coeffs {
+                matrix.push(Program::compile(c, &symbols)?.eval(&constants, &mut Rng::default()));
+            }
+            offsets.push(Program::compile(&split.offset, &symbols)?);
+        }
+        let linear_system = (offsets.len() == rhs.len()).then(|| LinearSystem::new(matrix, offsets));
+
+        let threshold = equations.threshold.as_ref()
+            .map(|th| Program::parse(&th.condition, &symbols))
+            .transpose()?;
+
+        let reset = match &equations.reset {
+            Some(reset) => reset.equations.iter()
+                .map(|line| expr::compile_statements(line, &symbols))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect(),
+            None => vec![],
+        };
+        check_writable(&reset, &constant_slots, &symbols)?;
+
+        let (refractory_period, refractory_condition) = match &equations.refractory {
+            Some(RefractorySpec::Duration(q)) => (Some(q.to_internal()), None),
+            Some(RefractorySpec::Condition(cond)) => (None, Some(Program::parse(cond, &symbols)?)),
+            None => (None, None),
+        };
+
+        Ok(Self {
+            symbols,
+            state_vars,
+            derivatives,
+            algebraic,
+            exponential_euler,
+            linear_system,
+            input_slot,
+            t_slot,
+            dt_slot,
+            index_slot,
+            not_refractory_slot,
+            frozen_slots,
+            constants,
+            threshold,
+            reset,
+            events: vec![],
+            regular: vec![],
+            constant_slots,
+            refractory_period,
+            refractory_condition,
+        })
+    }
+
+    /// Generate native closures for every program
+    #[cfg(feature = "closures")]
+    pub fn compile_kernels(&mut self) -> Result<()> {
+        let (conditions, event_code): (Vec<_>, Vec<_>) = self.events.iter_mut().map(|(c, s)| (c, s)).unzip();
+        let statements = self.reset.iter_mut()
+            .chain(self.regular.iter_mut().flatten())
+            .chain(event_code.into_iter().flatten());
+        let programs = self.derivatives.iter_mut().map(|(_, p)| p)
+            .chain(self.algebraic.iter_mut().map(|(_, p)| p))
+            .chain(self.exponential_euler.iter_mut().flatten().flat_map(|(a, b)| [a, b]))
+            .chain(self.linear_system.iter_mut().flat_map(|s| s.offsets.iter_mut()))
+            .chain(self.threshold.iter_mut())
+            .chain(conditions)
+            .chain(self.refractory_condition.iter_mut())
+            .chain(statements.map(|stmt| &mut stmt.program));
+        for program in programs {
+            program.compile_kernel();
+        }
+        Ok(())
+    }
+
+    /// Generate native closures for every program
+    #[cfg(not(feature = "closures"))]
+    pub fn compile_kernels(&mut self) -> Result<()> {
+        Err(BrianError::EquationError(
+            "The closure backend requires the 'closures' feature".into(),
+        ))
+    }
+
+    /// Check that `method` applies to these equations and allocate the
+    /// buffers for stepping with it
+    fn prepare(&self, method: IntegrationMethod, dt: f64) -> Result<StepScratch> {
+        let mut scratch = StepScratch::new(self.derivatives.len());
+        match method {
+            IntegrationMethod::ExponentialEuler => {
+                if let Some(k) = self.exponential_euler.iter().position(Option::is_none) {
+                    return Err(BrianError::EquationError(format!(
+                        "Equation for '{}' is not conditionally linear, cannot use exponential Euler",
+                        self.state_vars[k]
+                    )));
+                }
+            }
+            IntegrationMethod::ExactSolution => match &self.linear_system {
+                Some(system) => scratch.propagator = Some(system.propagator(dt)),
+                None => {
+                    return Err(BrianError::EquationError(
+                        "Equations are not linear with constant coefficients, cannot solve exactly".into(),
+                    ));
+                }
+            },
+            _ => {}
+        }
+        Ok(scratch)
+    }
+
+    /// Evaluate algebraic equations in place
+    fn update_algebraic(&self, values: &mut [f64], rng: &mut Rng) {
+        for (slot, program) in &self.algebraic {
+            values[*slot] = program.eval(values, rng);
+        }
+    }
+
+    /// Evaluate all derivatives into `out`
+    fn eval_derivatives(&self, values: &mut [f64], out: &mut [f64], rng: &mut Rng) {
+        self.update_algebraic(values, rng);
+        for (k, (_, program)) in self.derivatives.iter().enumerate() {
+            out[k] = program.eval(values, rng);
+        }
+    }
+
+    /// Set the differential variables to `y0 + h * k`
+    fn set_state(&self, values: &mut [f64], y0: &[f64], h: f64, k: &[f64]) {
+        for (j, (slot, _)) in self.derivatives.iter().enumerate() {
+            values[*slot] = y0[j] + h * k[j];
+        }
+    }
+
+    /// Advance the differential variables of one neuron by `dt`
+    fn step_neuron(
+        &self,
+        method: IntegrationMethod,
+        values: &mut [f64],
+        dt: f64,
+        rng: &mut Rng,
+        scratch: &mut StepScratch,
+    ) {
+        let t0 = values[self.t_slot];
+        let StepScratch { y0, k1, k2, k3, k4, propagator } = scratch;
+        for (j, (slot, _)) in self.derivatives.iter().enumerate() {
+            y0[j] = values[*slot];
+        }
+
+        match method {
+            IntegrationMethod::RungeKutta2 => {
+                // Midpoint method
+                self.eval_derivatives(values, k1, rng);
+                self.set_state(values, y0, 0.5 * dt, k1);
+                values[self.t_slot] = t0 + 0.5 * dt;
+                self.eval_derivatives(values, k2, rng);
+                self.set_state(values, y0, dt, k2);
+            }
+            IntegrationMethod::Heun => {
+                self.eval_derivatives(values, k1, rng);
+                self.set_state(values, y0, dt, k1);
+                values[self.t_slot] = t0 + dt;
+                self.eval_derivatives(values, k2, rng);
+                for j in 0..k1.len() {
+                    k1[j] = 0.5 * (k1[j] + k2[j]);
+                }
+                self.set_state(values, y0, dt, k1);
+            }
+            IntegrationMethod::RungeKutta4 => {
+                self.eval_derivatives(values, k1, rng);
+                self.set_state(values, y0, 0.5 * dt, k1);
+                values[self.t_slot] = t0 + 0.5 * dt;
+                self.eval_derivatives(values, k2, rng);
+                self.set_state(values, y0, 0.5 * dt, k2);
+                self.eval_derivatives(values, k3, rng);
+                self.set_state(values, y0, dt, k3);
+                values[self.t_slot] = t0 + dt;
+                self.eval_derivatives(values, k4, rng);
+                for j in 0..k1.len() {
+                    k1[j] = (k1[j] + 2.0 * k2[j] + 2.0 * k3[j] + k4[j]) / 6.0;
+                }
+                self.set_state(values, y0, dt, k1);
+            }
+            IntegrationMethod::ExponentialEuler => {
+                // A and B are evaluated at the start of the step for all
+                // variables before any of them is updated
+                for (k, split) in self.exponential_euler.iter().enumerate() {
+                    let (a, b) = split.as_ref().expect("checked by NeuronGroup::update");
+                    k1[k] = a.eval(values, rng);
+                    k2[k] = b.eval(values, rng);
+                }
+                for (j, (slot, _)) in self.derivatives.iter().enumerate() {
+                    let (a, b) = (k1[j], k2[j]);
+                    let rate = a + b * y0[j];
+                    values[*slot] = if b == 0.0 {
+                        y0[j] + rate * dt
+                    } else {
+                        y0[j] + rate * (b * dt).exp_m1() / b
+                    };
+                }
+            }
+            IntegrationMethod::ExactSolution => {
+                let system = self.linear_system.as_ref().expect("checked by NeuronGroup::update");
+                let propagator = propagator.as_ref().expect("built by NeuronGroup::update");
+                system.eval_offsets(values, k1, rng);
+                propagator.apply(y0, k1, k2);
+                for (j, (slot, _)) in self.derivatives.iter().enumerate() {
+                    values[*slot] = k2[j];
+                }
+            }
+            // The stochastic scheme currently falls back to forward Euler
+            IntegrationMethod::Euler | IntegrationMethod::Milstein => {
+                self.eval_derivatives(values, k1, rng);
+                self.set_state(values, y0, dt, k1);
+            }
+        }
+
+        values[self.t_slot] = t0;
+        self.update_algebraic(values, rng);
+    }
+}
+
+/// Per-neuron work buffers for the Runge-Kutta stages
+struct StepScratch {
+    y0: Vec<f64>,
+    k1: Vec<f64>,
+    k2: Vec<f64>,
+    k3: Vec<f64>,
+    k4: Vec<f64>,
+    /// Exact-solution propagator for the current `dt`
+    propagator: Option<Propagator>,
+}
+
+impl StepScratch {
+    fn new(n: usize) -> Self {
+        Self {
+            y0: vec![0.0; n],
+            k1: vec![0.0; n],
+            k2: vec![0.0; n],
+            k3: vec![0.0; n],
+            k4: vec![0.0; n],
+            propagator: None,
+        }
+    }
 }
 
 impl NeuronGroup {
@@ -473,16 +1206,123 @@ impl NeuronGroup {
         for eq in &equations.differential {
             state.insert(eq.variable.clone(), Array1::zeros(n));
         }
+        for eq in &equations.algebraic {
+            state.insert(eq.variable.clone(), Array1::zeros(n));
+        }
+        for decl in &equations.per_neuron {
+            state.insert(decl.variable.clone(), Array1::zeros(n));
+        }
+
+        let method = equations.differential.first()
+            .map(|eq| eq.method)
+            .unwrap_or(IntegrationMethod::Euler);
 
         Self {
             name: name.to_string(),
             n,
             equations,
-            method: IntegrationMethod::Euler,
+            method,
+            backend: Backend::default(),
             state,
+            input: Array1::zeros(n),
+            synaptic_input: Array1::zeros(n),
             last_spike: Array1::from_elem(n, f64::NEG_INFINITY),
             refractory_until: Array1::from_elem(n, f64::NEG_INFINITY),
+            timed_arrays: HashMap::new(),
+            regular_operations: vec![],
+            dt: None,
+            events: vec![],
+            events_fired: HashMap::new(),
+            compiled: None,
+        }
+    }
+
+    /// Run `code` on every neuron each `dt` ms, at the start of the step
+    pub fn run_regularly(&mut self, code: &str, dt: f64) {
+        self.add_regular_operation(code, dt, None);
+    }
+
+    /// Run `code` each `dt` ms on the given neurons only
+    pub fn run_regularly_on(&mut self, code: &str, dt: f64, neurons: Vec<usize>) {
+        self.add_regular_operation(code, dt, Some(neurons));
+    }
+
+    fn add_regular_operation(&mut self, code: &str, dt: f64, neurons: Option<Vec<usize>>) {
+        self.regular_operations.push(RegularOperation {
+            code: code.to_string(),
+            dt,
+            neurons,
+        });
+        self.compiled = None;
+    }
+
+    /// Add an event occurring whenever `condition` holds, e.g. a plateau
+    /// `"v > -40*mV"`. Unlike spikes, events ignore refractoriness.
+    pub fn add_event(&mut self, name: &str, condition: &str) -> Result<()> {
+        if name == "spike" || self.events.iter().any(|e| e.name == name) {
+            return Err(BrianError::EquationError(format!(
+                "Group '{}' already has an event named '{}'", self.name, name
+            )));
+        }
+        self.events.push(CustomEvent {
+            name: name.to_string(),
+            condition: condition.to_string(),
+            code: vec![],
+        });
+        self.compiled = None;
+        Ok(())
+    }
+
+    /// Run `code` on every neuron where the event `name` occurs
+    pub fn run_on_event(&mut self, name: &str, code: &str) -> Result<()> {
+        let event = self.events.iter_mut().find(|e| e.name == name).ok_or_else(|| {
+            BrianError::EquationError(format!("Group '{}' has no event named '{}'", self.name, name))
+        })?;
+        event.code.push(code.to_string());
+        self.compiled = None;
+        Ok(())
+    }
+
+    /// Run statements once for every neuron, e.g. to initialize
+    /// heterogeneous parameters: `"v_th = -50*mV + 5*mV*rand()"`.
+    ///
+    /// The statements see the group's variables and parameters, `i` and `N`;
+    /// constant parameters may be assigned here.
+    pub fn assign(&mut self, code: &str, rng: &mut Rng) -> Result<()> {
+        let dimensions = self.equations.dimension_table();
+        for stmt in expr::parse_statements(code)? {
+            units::check_statement(&stmt, &dimensions)?;
+        }
+        self.compile()?;
+        let compiled = self.compiled.as_ref().unwrap();
+        let statements = expr::compile_statements(code, &compiled.symbols)?;
+
+        let mut columns: Vec<Array1<f64>> = compiled.state_vars.iter()
+            .map(|name| self.state.remove(name).unwrap_or_else(|| Array1::zeros(self.n)))
+            .collect();
+        let mut values = compiled.constants.clone();
+        for i in 0..self.n {
+            for (slot, column) in columns.iter().enumerate() {
+                values[slot] = column[i];
+            }
+            values[compiled.index_slot] = i as f64;
+            for stmt in &statements {
+                stmt.execute(&mut values, rng);
+            }
+            for (slot, column) in columns.iter_mut().enumerate() {
+                column[i] = values[slot];
+            }
+        }
+        for (name, column) in compiled.state_vars.iter().zip(columns) {
+            self.state.insert(name.clone(), column);
         }
+        Ok(())
+    }
+
+    /// Make a timed array callable from the group's equations
+    pub fn add_timed_array(&mut self, array: TimedArray) {
+        self.timed_arrays.insert(array.name.clone(), array);
+        self.compiled = None;
     }
 
     pub fn set_initial(&mut self, variable: &str, values: Array1<f64>) -> Result<()> {
@@ -500,66 +1340,524 @@ impl NeuronGroup {
             ))
         }
     }
-}
 
-// ============================================================================
-// SYNAPSES
-// ============================================================================
+    /// Compile the group's equations (done automatically before the first step)
+    pub fn compile(&mut self) -> Result<&CompiledEquations> {
+        if self.compiled.is_none() {
+            let mut compiled = CompiledEquations::compile(&self.equations, self.n, &self.timed_arrays)?;
+            let dimensions = self.equations.dimension_table();
+            for op in &self.regular_operations {
+                for stmt in expr::parse_statements(&op.code)? {
+                    units::check_statement(&stmt, &dimensions)?;
+                }
+                let code = expr::compile_statements(&op.code, &compiled.symbols)?;
+                check_writable(&code, &compiled.constant_slots, &compiled.symbols)?;
+                compiled.regular.push(code);
+            }
+            for event in &self.events {
+                units::infer(&expr::parse_expression(&event.condition)?, &dimensions)?;
+                let mut code = vec![];
+                for line in &event.code {
+                    for stmt in expr::parse_statements(line)? {
+                        units::check_statement(&stmt, &dimensions)?;
+                    }
+                    code.extend(expr::compile_statements(line, &compiled.symbols)?);
+                }
+                check_writable(&code, &compiled.constant_slots, &compiled.symbols)?;
+                compiled.events.push((Program::parse(&event.condition, &compiled.symbols)?, code));
+            }
+            if self.backend == Backend::Closures {
+                compiled.compile_kernels()?;
+            }
+            self.compiled = Some(compiled);
+        }
+        Ok(self.compiled.as_ref().unwrap())
+    }
 
-/// Synapse connections between neuron groups
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Synapses {
-    pub name: String,
-    pub source: String,      // Source NeuronGroup name
-    pub target: String,      // Target NeuronGroup name
-    pub model: SynapseModel,
-    pub plasticity: Option<STDPRule>,
-    /// Sparse connectivity: (source_idx, target_idx)
-    pub connections: Vec<(usize, usize)>,
-    /// Weights (same length as connections)
-    pub weights: Vec<f64>,
-    /// Delays in ms (same length as connections)
-    pub delays: Vec<f64>,
-}
+    /// Discard compiled equations (after editing `equations`)
+    pub fn invalidate(&mut self) {
+        self.compiled = None;
+    }
 
-impl Synapses {
-    pub fn new(name: &str, source: &str, target: &str, model: SynapseModel) -> Self {
-        Self {
-            name: name.to_string(),
-            source: source.to_string(),
-            target: target.to_string(),
-            model,
-            plasticity: None,
-            connections: vec![],
-            weights: vec![],
-            delays: vec![],
+    /// Change the number of elements, zero-initializing new ones
+    pub fn resize(&mut self, n: usize) {
+        if n == self.n {
+            return;
         }
+        let resize = |a: &Array1<f64>, fill: f64| -> Array1<f64> {
+            (0..n).map(|k| if k < a.len() { a[k] } else { fill }).collect()
+        };
+        for column in self.state.values_mut() {
+            *column = resize(column, 0.0);
+        }
+        self.input = resize(&self.input, 0.0);
+        self.synaptic_input = resize(&self.synaptic_input, 0.0);
+        self.last_spike = resize(&self.last_spike, f64::NEG_INFINITY);
+        self.refractory_until = resize(&self.refractory_until, f64::NEG_INFINITY);
+        self.n = n;
+        self.compiled = None;
     }
 
-    /// Connect all-to-all
-    pub fn connect_all_to_all(&mut self, n_source: usize, n_target: usize, weight: f64, delay: f64) {
-        for i in 0..n_source {
-            for j in 0..n_target {
-                self.connections.push((i, j));
-                self.weights.push(weight);
-                self.delays.push(delay);
-            }
-        }
+    /// Whether neuron `i` is refractory at time `t`
+    pub fn is_refractory(&self, i: usize, t: f64) -> bool {
+        t < self.refractory_until[i]
     }
 
-    /// Connect with probability p
-    pub fn connect_random(&mut self, n_source: usize, n_target: usize, p: f64, weight: f64, delay: f64) {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
+    /// Run one full update cycle at time `t`: integrate, check the
+    /// threshold, apply resets and start refractory periods.
+    ///
+    /// Returns the indices of the neurons that spiked.
+    pub fn update(&mut self, t: f64, dt: f64, rng: &mut Rng) -> Result<Vec<usize>> {
+        self.compile()?;
+        let compiled = self.compiled.take().unwrap();
+
+        let mut scratch = match compiled.prepare(self.method, dt) {
+            Ok(scratch) => scratch,
+            Err(e) => {
+                self.compiled = Some(compiled);
+                return Err(e);
+            }
+        };
+
+        let mut columns: Vec<Array1<f64>> = compiled.state_vars.iter()
+            .map(|name| self.state.remove(name).unwrap_or_else(|| Array1::zeros(self.n)))
+            .collect();
+
+        let mut values = compiled.constants.clone();
+        values[compiled.dt_slot] = dt;
+        let mut spikes = vec![];
+        let mut held = vec![];
+        let mut occurred = vec![false; compiled.events.len()];
+        self.events_fired = self.events.iter().map(|e| (e.name.clone(), vec![])).collect();
+
+        // Regular operations due this step, with the neurons they apply to
+        let regular: Vec<(&Vec<CompiledStatement>, Option<Vec<bool>>)> = self.regular_operations.iter()
+            .zip(&compiled.regular)
+            .filter(|(op, _)| op.is_due(t, dt))
+            .map(|(op, code)| {
+                let mask = op.neurons.as_ref().map(|neurons| {
+                    let mut mask = vec![false; self.n];
+                    for &i in neurons.iter().filter(|&&i| i < self.n) {
+                        mask[i] = true;
+                    }
+                    mask
+                });
+                (code, mask)
+            })
+            .collect();
+
+        for i in 0..self.n {
+            for (slot, column) in columns.iter().enumerate() {
+                values[slot] = column[i];
+            }
+            if let Some(slot) = compiled.input_slot {
+                values[slot] = self.input[i] + self.synaptic_input[i];
+            }
+            values[compiled.index_slot] = i as f64;
+            values[compiled.t_slot] = t;
+
+            for (code, mask) in &regular {
+                if mask.as_ref().is_none_or(|m| m[i]) {
+                    for stmt in code.iter() {
+                        stmt.execute(&mut values, rng);
+                    }
+                }
+            }
+
+            let mut refractory = self.is_refractory(i, t);
+
+            // A condition-based refractory period ends once the condition fails
+            if refractory {
+                if let Some(cond) = &compiled.refractory_condition {
+                    if !cond.eval_bool(&values, rng) {
+                        self.refractory_until[i] = t;
+                        refractory = false;
+                    }
+                }
+            }
+
+            // Variables flagged `(unless refractory)` are held; the exact
+            // solver integrates the whole system, so restore them afterwards
+            values[compiled.not_refractory_slot] = if refractory { 0.0 } else { 1.0 };
+            held.clear();
+            if refractory {
+                held.extend(compiled.frozen_slots.iter().map(|&slot| values[slot]));
+            }
+            compiled.step_neuron(self.method, &mut values, dt, rng, &mut scratch);
+            if !held.is_empty() {
+                for (&slot, &value) in compiled.frozen_slots.iter().zip(&held) {
+                    values[slot] = value;
+                }
+                compiled.update_algebraic(&mut values, rng);
+            }
+
+            // All conditions see the integrated values, before any reset
+            for ((condition, _), occurred) in compiled.events.iter().zip(&mut occurred) {
+                *occurred = condition.eval_bool(&values, rng);
+            }
+            for (event, _) in self.events.iter().zip(&occurred).filter(|(_, &o)| o) {
+                let snapshot = values[..compiled.state_vars.len()].to_vec();
+                self.events_fired.get_mut(&event.name).unwrap().push((i, snapshot));
+            }
+
+            if !refractory {
+                let spiked = match &compiled.threshold {
+                    Some(threshold) => threshold.eval_bool(&values, rng),
+                    None => false,
+                };
+
+                if spiked {
+                    spikes.push(i);
+                    for stmt in &compiled.reset {
+                        stmt.execute(&mut values, rng);
+                    }
+                    self.last_spike[i] = t;
+                    if let Some(period) = compiled.refractory_period {
+                        self.refractory_until[i] = t + period;
+                    } else if compiled.refractory_condition.is_some() {
+                        self.refractory_until[i] = f64::INFINITY;
+                    }
+                }
+            }
+
+            for ((_, code), _) in compiled.events.iter().zip(&occurred).filter(|(_, &o)| o) {
+                for stmt in code {
+                    stmt.execute(&mut values, rng);
+                }
+            }
+
+            for (slot, column) in columns.iter_mut().enumerate() {
+                column[i] = values[slot];
+            }
+        }
+
+        for (name, column) in compiled.state_vars.iter().zip(columns) {
+            self.state.insert(name.clone(), column);
+        }
+        self.compiled = Some(compiled);
+
+        Ok(spikes)
+    }
+}
+
+// ============================================================================
+// SYNAPSES
+// ============================================================================
+
+/// Synapse connections between neuron groups
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Synapses {
+    pub name: String,
+    pub source: String,      // Source NeuronGroup name
+    pub target: String,      // Target NeuronGroup name
+    pub model: SynapseModel,
+    pub plasticity: Option<STDPRule>,
+    /// Sparse connectivity: (source_idx, target_idx)
+    pub connections: Vec<(usize, usize)>,
+    /// Weights (same length as connections)
+    pub weights: Vec<f64>,
+    /// Delays in ms (same length as connections)
+    pub delays: Vec<f64>,
+    /// Dynamic synaptic state
+    pub state: SynapticState,
+    /// Spikes in flight (built on the first step)
+    #[serde(skip)]
+    queue: Option<SpikeQueue>,
+    /// Incoming synapses of each target neuron (built with the queue)
+    #[serde(skip)]
+    post_index: Vec<Vec<usize>>,
+    /// Per-synapse variables and their equations, integrated as a group
+    /// with one element per connection
+    pub dynamics: Option<NeuronGroup>,
+    /// Statements run when a presynaptic spike arrives; when present they
+    /// replace the delivery of the built-in `model`
+    pub on_pre: Vec<String>,
+    /// Statements run when a postsynaptic neuron spikes
+    pub on_post: Vec<String>,
+    /// Compiled `on_pre` / `on_post` (built with the queue)
+    #[serde(skip)]
+    pathways: Option<(Pathway, Pathway)>,
+    /// Constants visible to `connect` expressions and pathways
+    pub parameters: HashMap<String, Quantity>,
+    /// Own clock period in ms; `None` updates every step
+    #[serde(default)]
+    pub dt: Option<f64>,
+    /// Source and target spikes seen since the last tick of a slower clock
+    #[serde(skip)]
+    pending: HashMap<String, Vec<usize>>,
+}
 
+/// Dynamic state of a synapse population
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SynapticState {
+    /// Postsynaptic conductance/current per target neuron
+    pub g: Vec<f64>,
+    /// Rise variable per target neuron (alpha and dual-exponential synapses)
+    pub h: Vec<f64>,
+    /// Tsodyks-Markram utilization per connection
+    pub u: Vec<f64>,
+    /// Tsodyks-Markram available resources per connection
+    pub x: Vec<f64>,
+    /// Arrival time of the last spike per connection (ms)
+    pub last_arrival: Vec<f64>,
+    /// STDP presynaptic trace per connection
+    pub apre: Vec<f64>,
+    /// STDP postsynaptic trace per connection
+    pub apost: Vec<f64>,
+    /// Time the STDP traces were last brought up to date (ms)
+    pub last_trace_update: Vec<f64>,
+}
+
+impl Synapses {
+    pub fn new(name: &str, source: &str, target: &str, model: SynapseModel) -> Self {
+        Self {
+            name: name.to_string(),
+            source: source.to_string(),
+            target: target.to_string(),
+            model,
+            plasticity: None,
+            connections: vec![],
+            weights: vec![],
+            delays: vec![],
+            state: SynapticState::default(),
+            queue: None,
+            post_index: vec![],
+            dynamics: None,
+            on_pre: vec![],
+            on_post: vec![],
+            pathways: None,
+            parameters: HashMap::new(),
+            dt: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Give the synapses their own state variables (`dg/dt = -g/tau : 1`)
+    pub fn set_equations(&mut self, equations: NeuronEquations) {
+        let name = format!("{}_synapses", self.name);
+        self.dynamics = Some(NeuronGroup::new(&name, self.connections.len(), equations));
+        self.pathways = None;
+    }
+
+    /// Discard compiled pathways (after editing `on_pre`/`on_post`)
+    pub fn invalidate(&mut self) {
+        self.pathways = None;
+    }
+
+    /// Compile `on_pre`/`on_post` against the synaptic, source and target variables
+    fn compile_pathways(&self, target: &NeuronGroup, source: Option<&NeuronGroup>, dt: f64) -> Result<(Pathway, Pathway)> {
+        let mut parameters = self.parameters.clone();
+        if let Some(dynamics) = &self.dynamics {
+            parameters.extend(dynamics.equations.parameters.clone());
+        }
+        let pre = if self.source == self.target { Some(target) } else { source };
+        let scope = Scope {
+            synaptic: self.dynamics.as_ref()
+                .map(|d| d.state.keys().map(String::as_str).collect())
+                .unwrap_or_default(),
+            pre: pre.map(|g| g.state.keys().map(String::as_str).collect()).unwrap_or_default(),
+            post: target.state.keys().map(String::as_str).collect(),
+            parameters: &parameters,
+        };
+        Ok((
+            Pathway::compile(&self.on_pre, &scope, dt)?,
+            Pathway::compile(&self.on_post, &scope, dt)?,
+        ))
+    }
+
+    /// Size the synaptic state and build the spike queue and pathways if the
+    /// connectivity or time step changed
+    fn prepare(&mut self, target: &NeuronGroup, source: Option<&NeuronGroup>, dt: f64) -> Result<()> {
+        let n_target = target.n;
+        if self.weights.len() != self.connections.len() {
+            return Err(BrianError::SimulationError(format!(
+                "Synapses '{}': expected {} weights, got {}",
+                self.name,
+                self.connections.len(),
+                self.weights.len()
+            )));
+        }
+        if !self.queue.as_ref().is_some_and(|q| q.matches(self.connections.len(), dt)) {
+            self.queue = Some(SpikeQueue::new(&self.connections, &self.delays, dt)?);
+            self.post_index = vec![vec![]; n_target];
+            for (syn, &(_, j)) in self.connections.iter().enumerate() {
+                if j < n_target {
+                    self.post_index[j].push(syn);
+                }
+            }
+            self.pathways = None;
+        }
+        if let Some(dynamics) = &mut self.dynamics {
+            dynamics.resize(self.connections.len());
+        }
+        if self.pathways.is_none() {
+            self.pathways = Some(self.compile_pathways(target, source, dt)?);
+        }
+
+        let state = &mut self.state;
+        state.g.resize(n_target, 0.0);
+        state.h.resize(n_target, 0.0);
+        let n = self.connections.len();
+        state.u.resize(n, 0.0);
+        state.x.resize(n, 1.0);
+        state.last_arrival.resize(n, f64::NEG_INFINITY);
+        if self.plasticity.is_some() {
+            state.apre.resize(n, 0.0);
+            state.apost.resize(n, 0.0);
+            state.last_trace_update.resize(n, 0.0);
+        }
+        Ok(())
+    }
+
+    /// Number of presynaptic spikes still travelling along the synapses
+    pub fn pending_spikes(&self) -> usize {
+        self.queue.as_ref().map_or(0, SpikeQueue::pending)
+    }
+
+    /// Add the postsynaptic current of each target neuron to `target.synaptic_input`
+    pub fn add_currents(&self, target: &mut NeuronGroup) {
+        if self.model.is_instantaneous() {
+            return;
+        }
+        let v = target.state.get(MEMBRANE_SYMBOL);
+        for (j, (&g, &h)) in self.state.g.iter().zip(&self.state.h).enumerate().take(target.n) {
+            let v_j = v.map_or(0.0, |v| v[j]);
+            target.synaptic_input[j] += self.model.current(g, h, v_j);
+        }
+    }
+
+    /// Advance the synapses by one step: integrate the synaptic equations,
+    /// decay the postsynaptic state, queue this step's source spikes, deliver
+    /// the ones that are due at `t`, then run plasticity and `on_post` for
+    /// the target spikes
+    pub fn propagate(
+        &mut self,
+        spikes: &HashMap<String, Vec<usize>>,
+        t: f64,
+        dt: f64,
+        target: &mut NeuronGroup,
+        source: Option<&NeuronGroup>,
+        rng: &mut Rng,
+    ) -> Result<()> {
+        self.prepare(target, source, dt)?;
+        let fired = spikes.get(&self.source).map_or(&[][..], Vec::as_slice);
+        let post_fired = spikes.get(&self.target).map_or(&[][..], Vec::as_slice);
+
+        if let Some(dynamics) = &mut self.dynamics {
+            dynamics.update(t, dt, rng)?;
+        }
+
+        let state = &mut self.state;
+        for (g, h) in state.g.iter_mut().zip(state.h.iter_mut()) {
+            self.model.decay(g, h, dt);
+        }
+
+        let queue = self.queue.as_mut().expect("queue built by prepare");
+        queue.push(fired);
+        let due = queue.pop();
+
+        let (on_pre, on_post) = self.pathways.take().expect("pathways built by prepare");
+        let pre_state = if self.source == self.target { None } else { source.map(|g| &g.state) };
+
+        if !due.is_empty() {
+            if on_pre.is_empty() {
+                self.deliver(&due, t, target)?;
+            } else {
+                let mut state = PathwayState {
+                    synaptic: self.dynamics.as_mut().map(|d| &mut d.state),
+                    weights: &mut self.weights,
+                    pre: pre_state,
+                    post: &mut target.state,
+                };
+                for &syn in &due {
+                    on_pre.run(syn, self.connections[syn], t, &mut state, rng);
+                }
+            }
+        }
+
+        if let Some(rule) = &self.plasticity {
+            // Pre: potentiate the presynaptic trace, depress by the postsynaptic one
+            for &syn in &due {
+                self.state.update_traces(syn, t, rule);
+                self.state.apre[syn] += rule.a_plus;
+                self.weights[syn] = rule.clip(self.weights[syn] - self.state.apost[syn]);
+            }
+            // Post: the mirror image
+            for &j in post_fired {
+                for &syn in self.post_index.get(j).map_or(&[][..], Vec::as_slice) {
+                    self.state.update_traces(syn, t, rule);
+                    self.state.apost[syn] += rule.a_minus;
+                    self.weights[syn] = rule.clip(self.weights[syn] + self.state.apre[syn]);
+                }
+            }
+        }
+
+        if !on_post.is_empty() {
+            let mut state = PathwayState {
+                synaptic: self.dynamics.as_mut().map(|d| &mut d.state),
+                weights: &mut self.weights,
+                pre: pre_state,
+                post: &mut target.state,
+            };
+            for &j in post_fired {
+                for &syn in self.post_index.get(j).map_or(&[][..], Vec::as_slice) {
+                    on_post.run(syn, self.connections[syn], t, &mut state, rng);
+                }
+            }
+        }
+
+        self.pathways = Some((on_pre, on_post));
+        Ok(())
+    }
+
+    /// Apply the spikes arriving at `t` along synapses `due` to the target
+    fn deliver(&mut self, due: &[usize], t: f64, target: &mut NeuronGroup) -> Result<()> {
+        if self.model.is_instantaneous() {
+            let v = target.state.get_mut(MEMBRANE_SYMBOL).ok_or_else(|| {
+                BrianError::SimulationError(format!(
+                    "Synapses '{}': target '{}' has no variable '{}'",
+                    self.name, target.name, MEMBRANE_SYMBOL
+                ))
+            })?;
+            for &syn in due {
+                let (_, j) = self.connections[syn];
+                let mut w = self.weights[syn];
+                if let SynapseModel::STP { u_se, tau_rec, tau_fac, .. } = self.model {
+                    w *= self.state.release(syn, t, u_se, tau_rec, tau_fac);
+                }
+                if j < target.n {
+                    v[j] += w;
+                }
+            }
+        } else {
+            for &syn in due {
+                let (_, j) = self.connections[syn];
+                let (dg, dh) = self.model.increment(self.weights[syn]);
+                if j < target.n {
+                    self.state.g[j] += dg;
+                    self.state.h[j] += dh;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect all-to-all
+    pub fn connect_all_to_all(&mut self, n_source: usize, n_target: usize, weight: f64, delay: f64) {
         for i in 0..n_source {
             for j in 0..n_target {
-                let mut hasher = DefaultHasher::new();
-                (i, j).hash(&mut hasher);
-                let hash = hasher.finish();
-                let r = (hash as f64) / (u64::MAX as f64);
+                self.connections.push((i, j));
+                self.weights.push(weight);
+                self.delays.push(delay);
+            }
+        }
+    }
 
-                if r < p {
+    /// Connect with probability p
+    pub fn connect_random(&mut self, n_source: usize, n_target: usize, p: f64, weight: f64, delay: f64, rng: &mut Rng) {
+        for i in 0..n_source {
+            for j in 0..n_target {
+                if rng.uniform() < p {
                     self.connections.push((i, j));
                     self.weights.push(weight);
                     self.delays.push(delay);
@@ -568,6 +1866,80 @@ impl Synapses {
         }
     }
 
+    /// Connect by expressions over the pre/post indices `i`, `j` and the
+    /// `_pre`/`_post` variables of the groups (Brian's `Synapses.connect`).
+    ///
+    /// Each pair satisfying `condition` is connected with probability `p`,
+    /// `n` times. Empty strings default to `True`, `1` and `1`; `p` and `n`
+    /// may be written as `"p = ..."`/`"n = ..."`. New connections get the
+    /// model's weight and no delay.
+    pub fn connect(&mut self, condition: &str, p: &str, n: &str, ends: &Endpoints, rng: &mut Rng) -> Result<()> {
+        let mut symbols = SymbolTable::new();
+        let i_slot = symbols.add("i");
+        let j_slot = symbols.add("j");
+        let n_pre_slot = symbols.add("N_pre");
+        let n_post_slot = symbols.add("N_post");
+        let pre_vars = side_vars(ends.pre, "pre", &mut symbols);
+        let post_vars = side_vars(ends.post, "post", &mut symbols);
+
+        let mut values = vec![];
+        let mut parameters: Vec<(&String, &Quantity)> = self.parameters.iter().collect();
+        parameters.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, quantity) in parameters {
+            if symbols.resolve(name).is_none() {
+                values.push((symbols.add(name), quantity.to_internal()));
+            }
+        }
+
+        let compile = |src: &str, name: &str, default: &str| -> Result<Program> {
+            let src = match src.split_once('=') {
+                Some((lhs, rhs)) if lhs.trim() == name && !rhs.starts_with('=') => rhs,
+                _ => src,
+            };
+            let src = if src.trim().is_empty() { default } else { src };
+            Program::parse(src, &symbols)
+        };
+        let condition = compile(condition, "condition", "True")?;
+        let p = compile(p, "p", "1")?;
+        let n = compile(n, "n", "1")?;
+
+        let mut slots = vec![0.0; symbols.len()];
+        for (slot, value) in values {
+            slots[slot] = value;
+        }
+        slots[n_pre_slot] = ends.n_pre as f64;
+        slots[n_post_slot] = ends.n_post as f64;
+
+        let weight = self.model.weight();
+        for i in 0..ends.n_pre {
+            slots[i_slot] = i as f64;
+            for &(slot, name) in &pre_vars {
+                slots[slot] = ends.pre.map_or(0.0, |s| s[name][i]);
+            }
+            for j in 0..ends.n_post {
+                slots[j_slot] = j as f64;
+                for &(slot, name) in &post_vars {
+                    slots[slot] = ends.post.map_or(0.0, |s| s[name][j]);
+                }
+
+                if !condition.eval_bool(&slots, rng) {
+                    continue;
+                }
+                let probability = p.eval(&slots, rng);
+                if probability < 1.0 && rng.uniform() >= probability {
+                    continue;
+                }
+                let count = n.eval(&slots, rng).round().max(0.0) as usize;
+                for _ in 0..count {
+                    self.connections.push((i, j));
+                    self.weights.push(weight);
+                    self.delays.push(0.0);
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// One-to-one mapping
     pub fn connect_one_to_one(&mut self, n: usize, weight: f64, delay: f64) {
         for i in 0..n {
@@ -578,6 +1950,53 @@ impl Synapses {
     }
 }
 
+impl SynapticState {
+    /// Tsodyks-Markram release for a spike arriving at `t`; returns the
+    /// fraction u*x of resources used
+    fn release(&mut self, syn: usize, t: f64, u_se: f64, tau_rec: f64, tau_fac: f64) -> f64 {
+        let elapsed = t - self.last_arrival[syn];
+        self.last_arrival[syn] = t;
+
+        // Between spikes u relaxes to 0 and x recovers to 1
+        self.u[syn] = if tau_fac > 0.0 { self.u[syn] * (-elapsed / tau_fac).exp() } else { 0.0 };
+        self.x[syn] = 1.0 + (self.x[syn] - 1.0) * (-elapsed / tau_rec).exp();
+
+        self.u[syn] += u_se * (1.0 - self.u[syn]);
+        let r = self.u[syn] * self.x[syn];
+        self.x[syn] -= r;
+        r
+    }
+
+    /// Decay the STDP traces of a connection up to time `t`
+    fn update_traces(&mut self, syn: usize, t: f64, rule: &STDPRule) {
+        let elapsed = t - self.last_trace_update[syn];
+        self.apre[syn] *= (-elapsed / rule.tau_pre).exp();
+        self.apost[syn] *= (-elapsed / rule.tau_post).exp();
+        self.last_trace_update[syn] = t;
+    }
+}
+
+/// Add the `_pre`/`_post` variables of one side of a connection to `symbols`
+fn side_vars<'a>(
+    state: Option<&'a HashMap<String, Array1<f64>>>,
+    suffix: &str,
+    symbols: &mut SymbolTable,
+) -> Vec<(usize, &'a str)> {
+    let mut names: Vec<&str> = state.iter().flat_map(|s| s.keys()).map(String::as_str).collect();
+    names.sort_unstable();
+    names.into_iter()
+        .map(|name| (symbols.add(&format!("{}_{}", name, suffix)), name))
+        .collect()
+}
+
+/// Sizes and variables of the groups on either side of a [`Synapses`]
+pub struct Endpoints<'a> {
+    pub n_pre: usize,
+    pub n_post: usize,
+    pub pre: Option<&'a HashMap<String, Array1<f64>>>,
+    pub post: Option<&'a HashMap<String, Array1<f64>>>,
+}
+
 // ============================================================================
 // INPUT DEVICES
 // ============================================================================
@@ -588,6 +2007,9 @@ pub struct PoissonGroup {
     pub name: String,
     pub n: usize,
     pub rates: Array1<f64>,  // Hz
+    /// Own clock period in ms; `None` draws every step
+    #[serde(default)]
+    pub dt: Option<f64>,
 }
 
 impl PoissonGroup {
@@ -596,6 +2018,7 @@ impl PoissonGroup {
             name: name.to_string(),
             n,
             rates: Array1::from_elem(n, rate),
+            dt: None,
         }
     }
 
@@ -605,8 +2028,49 @@ impl PoissonGroup {
             name: name.to_string(),
             n,
             rates,
+            dt: None,
+        }
+    }
+}
+
+/// Summed drive from `n` independent Poisson sources, added directly to a
+/// variable of every target neuron (Brian's `PoissonInput`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoissonInput {
+    pub target: String,
+    pub variable: String,
+    /// Number of sources per target neuron
+    pub n: usize,
+    pub rate: f64,  // Hz
+    /// Increment per input spike, in the variable's units
+    pub weight: f64,
+}
+
+impl PoissonInput {
+    pub fn new(target: &str, variable: &str, n: usize, rate: f64, weight: f64) -> Self {
+        Self {
+            target: target.to_string(),
+            variable: variable.to_string(),
+            n,
+            rate,
+            weight,
         }
     }
+
+    /// Add one step's worth of input spikes to the target
+    pub fn apply(&self, target: &mut NeuronGroup, dt: f64, rng: &mut Rng) -> Result<()> {
+        let column = target.state.get_mut(&self.variable).ok_or_else(|| {
+            BrianError::SimulationError(format!(
+                "PoissonInput: unknown variable '{}' in group '{}'",
+                self.variable, self.target
+            ))
+        })?;
+        let p = self.rate * dt / 1000.0;
+        for x in column.iter_mut() {
+            *x += self.weight * rng.binomial(self.n, p) as f64;
+        }
+        Ok(())
+    }
 }
 
 /// Spike generator from predetermined spike times
@@ -616,6 +2080,10 @@ pub struct SpikeGeneratorGroup {
     pub n: usize,
     /// Spike times: (neuron_idx, time_ms)
     pub spike_times: Vec<(usize, f64)>,
+    /// Own clock period in ms; spikes are emitted on the first tick at or
+    /// after their time. `None` ticks every step
+    #[serde(default)]
+    pub dt: Option<f64>,
 }
 
 impl SpikeGeneratorGroup {
@@ -624,6 +2092,7 @@ impl SpikeGeneratorGroup {
             name: name.to_string(),
             n,
             spike_times: vec![],
+            dt: None,
         }
     }
 
@@ -637,11 +2106,74 @@ impl SpikeGeneratorGroup {
 }
 
 /// Timed array for time-varying input
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Callable in equations as `name(t)` (first column) or `name(t, i)`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TimedArray {
     pub name: String,
     pub times: Array1<f64>,   // ms
     pub values: Array2<f64>,  // (time_points, neurons)
+    pub interpolation: Interpolation,
+}
+
+/// How a [`TimedArray`] is evaluated between samples
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    /// Hold each sample until the next one (Brian's behavior)
+    Step,
+    Linear,
+}
+
+impl TimedArray {
+    pub fn new(name: &str, times: Array1<f64>, values: Array2<f64>) -> Result<Self> {
+        if times.len() != values.nrows() || times.is_empty() {
+            return Err(BrianError::SimulationError(format!(
+                "Timed array '{}': {} times for {} rows",
+                name,
+                times.len(),
+                values.nrows()
+            )));
+        }
+        if (1..times.len()).any(|k| times[k] <= times[k - 1]) {
+            return Err(BrianError::SimulationError(format!(
+                "Timed array '{}': times must be increasing",
+                name
+            )));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            times,
+            values,
+            interpolation: Interpolation::Step,
+        })
+    }
+
+    pub fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    /// Value of `column` at time `t`; samples are held before the first and
+    /// after the last time point. Out-of-range columns give NaN.
+    pub fn lookup(&self, t: f64, column: usize) -> f64 {
+        if column >= self.values.ncols() {
+            return f64::NAN;
+        }
+        let n = self.times.len();
+        // Last sample at or before t
+        let k = match (0..n).rev().find(|&k| self.times[k] <= t) {
+            Some(k) => k,
+            None => return self.values[[0, column]],
+        };
+        match self.interpolation {
+            Interpolation::Linear if k + 1 < n => {
+                let (t0, t1) = (self.times[k], self.times[k + 1]);
+                let (v0, v1) = (self.values[[k, column]], self.values[[k + 1, column]]);
+                v0 + (v1 - v0) * (t - t0) / (t1 - t0)
+            }
+            _ => self.values[[k, column]],
+        }
+    }
 }
 
 // ============================================================================
@@ -693,13 +2225,73 @@ impl SpikeMonitor {
     }
 }
 
+/// Record the occurrences of a custom event, optionally with the values
+/// of some variables when the event's condition was met
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventMonitor {
+    pub source: String,
+    pub event: String,
+    pub variables: Vec<String>,
+    /// Recorded events: (neuron_idx, time_ms)
+    pub events: Vec<(usize, f64)>,
+    /// Values of each variable, one per recorded event
+    pub values: HashMap<String, Vec<f64>>,
+}
+
+impl EventMonitor {
+    pub fn new(source: &str, event: &str, variables: &[&str]) -> Self {
+        Self {
+            source: source.to_string(),
+            event: event.to_string(),
+            variables: variables.iter().map(|s| s.to_string()).collect(),
+            events: vec![],
+            values: variables.iter().map(|v| (v.to_string(), vec![])).collect(),
+        }
+    }
+
+    /// Record the events `group` emitted during its last update
+    pub fn record(&mut self, group: &NeuronGroup, time: f64) -> Result<()> {
+        let Some(fired) = group.events_fired.get(&self.event) else {
+            return Ok(());
+        };
+        let Some(compiled) = group.compiled.as_ref() else {
+            return Ok(());
+        };
+        let slots = self.variables.iter()
+            .map(|var| {
+                compiled.state_vars.iter().position(|v| v == var).ok_or_else(|| {
+                    BrianError::SimulationError(format!(
+                        "EventMonitor: unknown variable '{}' in group '{}'", var, self.source
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (idx, snapshot) in fired {
+            self.events.push((*idx, time));
+            for (var, &slot) in self.variables.iter().zip(&slots) {
+                self.values.get_mut(var).unwrap().push(snapshot[slot]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Event times of each neuron
+    pub fn event_trains(&self) -> HashMap<usize, Vec<f64>> {
+        let mut trains: HashMap<usize, Vec<f64>> = HashMap::new();
+        for &(idx, time) in &self.events {
+            trains.entry(idx).or_default().push(time);
+        }
+        trains
+    }
+}
+
 /// Record state variable over time
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StateMonitor {
     pub source: String,
     pub variables: Vec<String>,
     pub record_indices: Vec<usize>,  // Which neurons to record
-    pub dt: f64,                     // Recording timestep (ms)
+    pub dt: f64,                     // Recording clock (ms), a multiple of the network's dt
     /// Recorded values: variable -> (times, values[neuron][time])
     pub data: HashMap<String, (Vec<f64>, Vec<Vec<f64>>)>,
 }
@@ -722,7 +2314,8 @@ impl StateMonitor {
 
     pub fn record(&mut self, variable: &str, time: f64, values: &Array1<f64>) {
         if let Some((times, data)) = self.data.get_mut(variable) {
-            if times.is_empty() || time >= times.last().unwrap() + self.dt {
+            // Tolerate the rounding error accumulated by the network clock
+            if times.is_empty() || time + 1e-6 * self.dt >= times.last().unwrap() + self.dt {
                 times.push(time);
                 for (i, &idx) in self.record_indices.iter().enumerate() {
                     if idx < values.len() {
@@ -741,43 +2334,274 @@ pub struct PopulationRateMonitor {
     pub bin_size: f64,  // ms
     pub times: Vec<f64>,
     pub rates: Vec<f64>,  // Hz
+    /// Population size
+    pub n: usize,
+    /// Start of the bin being accumulated (ms)
+    bin_start: Option<f64>,
+    /// Spikes in the bin being accumulated
+    bin_spikes: usize,
+}
+
+/// Smoothing window for [`PopulationRateMonitor::smooth_rate`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SmoothingWindow {
+    /// Moving average over `width` ms
+    Flat { width: f64 },
+    /// Gaussian with standard deviation `width` ms, truncated at two widths
+    Gaussian { width: f64 },
+}
+
+impl PopulationRateMonitor {
+    /// Monitor the rate of a population of `n` neurons in bins of `bin_size` ms
+    /// (use the network's `dt` for Brian's per-step rate)
+    pub fn new(source: &str, n: usize, bin_size: f64) -> Self {
+        Self {
+            source: source.to_string(),
+            bin_size,
+            times: vec![],
+            rates: vec![],
+            n,
+            bin_start: None,
+            bin_spikes: 0,
+        }
+    }
+
+    /// Accumulate the spikes of the step `[t, t + dt)`, closing the bin
+    /// once it is complete
+    pub fn record(&mut self, t: f64, dt: f64, n_spikes: usize) {
+        let start = *self.bin_start.get_or_insert(t);
+        self.bin_spikes += n_spikes;
+
+        // Tolerate rounding in the accumulated time
+        if t + dt >= start + self.bin_size - 1e-6 * dt {
+            let width = t + dt - start;
+            let rate = if self.n == 0 {
+                0.0
+            } else {
+                self.bin_spikes as f64 / (self.n as f64 * width / 1000.0)
+            };
+            self.times.push(start);
+            self.rates.push(rate);
+            self.bin_start = None;
+            self.bin_spikes = 0;
+        }
+    }
+
+    /// Rate trace convolved with a normalized window (same length as `rates`)
+    pub fn smooth_rate(&self, window: SmoothingWindow) -> Vec<f64> {
+        let kernel: Vec<f64> = match window {
+            SmoothingWindow::Flat { width } => {
+                let half = ((width / self.bin_size).round() as usize).max(1) / 2;
+                vec![1.0; 2 * half + 1]
+            }
+            SmoothingWindow::Gaussian { width } => {
+                let sigma = width / self.bin_size;
+                let half = (2.0 * sigma).round() as i64;
+                (-half..=half).map(|k| (-(k * k) as f64 / (2.0 * sigma * sigma)).exp()).collect()
+            }
+        };
+        let total: f64 = kernel.iter().sum();
+        let half = (kernel.len() / 2) as i64;
+
+        (0..self.rates.len() as i64)
+            .map(|k| {
+                kernel.iter().enumerate()
+                    .filter_map(|(m, w)| {
+                        let idx = k + m as i64 - half;
+                        self.rates.get(usize::try_from(idx).ok()?).map(|r| r * w)
+                    })
+                    .sum::<f64>() / total
+            })
+            .collect()
+    }
 }
 
 // ============================================================================
 // NETWORK
 // ============================================================================
 
-/// Complete Brian network
+type Callback = dyn FnMut(&mut Network) + Send;
+
+/// User callback run every few steps (Brian's `@network_operation`)
+#[derive(Clone)]
+pub struct NetworkOperation {
+    callback: Arc<Mutex<Callback>>,
+    /// Period in ms; `None` runs every step
+    pub dt: Option<f64>,
+}
+
+impl std::fmt::Debug for NetworkOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NetworkOperation").field("dt", &self.dt).finish_non_exhaustive()
+    }
+}
+
+impl NetworkOperation {
+    /// Whether the operation is due at step `step` of a network with time step `dt`
+    fn is_due(&self, step: u64, dt: f64) -> bool {
+        let every = self.dt.map_or(1, |period| ((period / dt).round() as u64).max(1));
+        step.is_multiple_of(every)
+    }
+}
+
+/// Network steps per tick of the clock `period` owned by `owner` (`None`
+/// ticks every step). Clocks must be whole multiples of the network's `dt`
+/// so that every object updates at the same instants as the base clock.
+fn clock_steps(period: Option<f64>, dt: f64, owner: &str) -> Result<u64> {
+    let Some(period) = period else { return Ok(1) };
+    let ratio = period / dt;
+    let every = ratio.round();
+    if every < 1.0 || (ratio - every).abs() > 1e-9 * every {
+        return Err(BrianError::SimulationError(format!(
+            "Clock of '{}' ({} ms) is not a multiple of the network dt ({} ms)",
+            owner, period, dt
+        )));
+    }
+    Ok(every as u64)
+}
+
+/// Complete Brian network. Objects are kept in order of their names, so
+/// that they draw from the random number generator in the same order in
+/// every run.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Network {
-    pub neuron_groups: HashMap<String, NeuronGroup>,
-    pub synapses: HashMap<String, Synapses>,
-    pub poisson_groups: HashMap<String, PoissonGroup>,
-    pub spike_generators: HashMap<String, SpikeGeneratorGroup>,
-    pub spike_monitors: HashMap<String, SpikeMonitor>,
-    pub state_monitors: HashMap<String, StateMonitor>,
+    pub neuron_groups: BTreeMap<String, NeuronGroup>,
+    /// Multicompartmental neurons; each compartment is a spike source
+    pub spatial_neurons: BTreeMap<String, SpatialNeuron>,
+    /// Populations with a continuous rate output
+    #[serde(default)]
+    pub rate_groups: BTreeMap<String, RateGroup>,
+    /// Weighted couplings from rate groups, recomputed every step
+    #[serde(default)]
+    pub rate_connections: BTreeMap<String, RateConnection>,
+    pub synapses: BTreeMap<String, Synapses>,
+    pub poisson_groups: BTreeMap<String, PoissonGroup>,
+    pub poisson_inputs: Vec<PoissonInput>,
+    pub spike_generators: BTreeMap<String, SpikeGeneratorGroup>,
+    pub spike_monitors: BTreeMap<String, SpikeMonitor>,
+    /// Custom event monitors, keyed by `<source>_<event>`
+    #[serde(default)]
+    pub event_monitors: BTreeMap<String, EventMonitor>,
+    pub state_monitors: BTreeMap<String, StateMonitor>,
+    pub rate_monitors: BTreeMap<String, PopulationRateMonitor>,
+    /// Timed arrays shared by all groups
+    pub timed_arrays: BTreeMap<String, TimedArray>,
     pub dt: f64,  // Timestep in ms
     pub t: f64,   // Current time in ms
+    /// Random number generator used by `rand()`/`randn()` in equations
+    pub rng: Rng,
+    /// Spikes emitted by each group during the last step
+    #[serde(skip)]
+    pub spikes: HashMap<String, Vec<usize>>,
+    /// Callbacks run at the start of a step, in the order they were added
+    #[serde(skip)]
+    pub operations: Vec<NetworkOperation>,
+    /// Snapshots taken by `store`
+    #[serde(skip)]
+    snapshots: HashMap<String, Network>,
 }
 
 impl Network {
     pub fn new(dt: f64) -> Self {
         Self {
-            neuron_groups: HashMap::new(),
-            synapses: HashMap::new(),
-            poisson_groups: HashMap::new(),
-            spike_generators: HashMap::new(),
-            spike_monitors: HashMap::new(),
-            state_monitors: HashMap::new(),
+            neuron_groups: BTreeMap::new(),
+            spatial_neurons: BTreeMap::new(),
+            rate_groups: BTreeMap::new(),
+            rate_connections: BTreeMap::new(),
+            synapses: BTreeMap::new(),
+            poisson_groups: BTreeMap::new(),
+            poisson_inputs: vec![],
+            spike_generators: BTreeMap::new(),
+            spike_monitors: BTreeMap::new(),
+            event_monitors: BTreeMap::new(),
+            state_monitors: BTreeMap::new(),
+            rate_monitors: BTreeMap::new(),
+            timed_arrays: BTreeMap::new(),
             dt,
             t: 0.0,
+            rng: Rng::default(),
+            spikes: HashMap::new(),
+            operations: vec![],
+            snapshots: HashMap::new(),
         }
     }
 
-    pub fn add_neuron_group(&mut self, group: NeuronGroup) {
+    /// Snapshot the state of every object, the monitors and the clock
+    pub fn store(&mut self, name: &str) {
+        let snapshots = std::mem::take(&mut self.snapshots);
+        let operations = std::mem::take(&mut self.operations);
+        let snapshot = self.clone();
+        self.snapshots = snapshots;
+        self.operations = operations;
+        self.snapshots.insert(name.to_string(), snapshot);
+    }
+
+    /// Return to a stored snapshot. The random number generator keeps its
+    /// current state so repeated trials see fresh noise, as in Brian.
+    pub fn restore(&mut self, name: &str) -> Result<()> {
+        let rng = self.rng.clone();
+        self.restore_with_random_state(name)?;
+        self.rng = rng;
+        Ok(())
+    }
+
+    /// Return to a stored snapshot, including the random number generator
+    pub fn restore_with_random_state(&mut self, name: &str) -> Result<()> {
+        let snapshot = self.snapshots.get(name)
+            .ok_or_else(|| BrianError::SimulationError(format!("No stored state named '{}'", name)))?
+            .clone();
+        let snapshots = std::mem::take(&mut self.snapshots);
+        let operations = std::mem::take(&mut self.operations);
+        *self = snapshot;
+        self.snapshots = snapshots;
+        self.operations = operations;
+        Ok(())
+    }
+
+    /// Seed the network's random number generator
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn add_neuron_group(&mut self, mut group: NeuronGroup) {
+        for array in self.timed_arrays.values() {
+            group.add_timed_array(array.clone());
+        }
         self.neuron_groups.insert(group.name.clone(), group);
     }
 
+    pub fn add_spatial_neuron(&mut self, mut neuron: SpatialNeuron) {
+        for array in self.timed_arrays.values() {
+            neuron.add_timed_array(array.clone());
+        }
+        self.spatial_neurons.insert(neuron.name.clone(), neuron);
+    }
+
+    pub fn add_rate_group(&mut self, mut group: RateGroup) {
+        for array in self.timed_arrays.values() {
+            group.group.add_timed_array(array.clone());
+        }
+        self.rate_groups.insert(group.name().to_string(), group);
+    }
+
+    pub fn add_rate_connection(&mut self, connection: RateConnection) {
+        self.rate_connections.insert(connection.name.clone(), connection);
+    }
+
+    /// Make a timed array callable from the equations of every group
+    pub fn add_timed_array(&mut self, array: TimedArray) {
+        for group in self.neuron_groups.values_mut() {
+            group.add_timed_array(array.clone());
+        }
+        for group in self.rate_groups.values_mut() {
+            group.group.add_timed_array(array.clone());
+        }
+        for neuron in self.spatial_neurons.values_mut() {
+            neuron.add_timed_array(array.clone());
+        }
+        self.timed_arrays.insert(array.name.clone(), array);
+    }
+
     pub fn add_synapses(&mut self, synapses: Synapses) {
         self.synapses.insert(synapses.name.clone(), synapses);
     }
@@ -786,10 +2610,31 @@ impl Network {
         self.poisson_groups.insert(group.name.clone(), group);
     }
 
+    /// Inputs are kept sorted by target, variable and parameters, like the
+    /// named objects, so that their draws do not depend on the order added
+    pub fn add_poisson_input(&mut self, input: PoissonInput) {
+        let order = |a: &PoissonInput, b: &PoissonInput| {
+            (&a.target, &a.variable, a.n)
+                .cmp(&(&b.target, &b.variable, b.n))
+                .then(a.rate.total_cmp(&b.rate))
+                .then(a.weight.total_cmp(&b.weight))
+        };
+        let at = self.poisson_inputs.partition_point(|i| order(i, &input).is_le());
+        self.poisson_inputs.insert(at, input);
+    }
+
     pub fn add_spike_monitor(&mut self, monitor: SpikeMonitor) {
         self.spike_monitors.insert(monitor.source.clone(), monitor);
     }
 
+    pub fn add_event_monitor(&mut self, monitor: EventMonitor) {
+        self.event_monitors.insert(format!("{}_{}", monitor.source, monitor.event), monitor);
+    }
+
+    pub fn add_rate_monitor(&mut self, monitor: PopulationRateMonitor) {
+        self.rate_monitors.insert(monitor.source.clone(), monitor);
+    }
+
     pub fn add_state_monitor(&mut self, monitor: StateMonitor) {
         self.state_monitors.insert(
             format!("{}_state", monitor.source),
@@ -797,6 +2642,18 @@ impl Network {
         );
     }
 
+    /// Run `callback` with mutable access to the network every `dt` ms
+    /// (every step if `None`), before the step's monitors and updates
+    pub fn add_operation<F>(&mut self, callback: F, dt: Option<f64>)
+    where
+        F: FnMut(&mut Network) + Send + 'static,
+    {
+        self.operations.push(NetworkOperation {
+            callback: Arc::new(Mutex::new(callback)),
+            dt,
+        });
+    }
+
     /// Run simulation for given duration
     pub fn run(&mut self, duration: f64) -> Result<()> {
         let n_steps = (duration / self.dt).ceil() as usize;
@@ -808,30 +2665,280 @@ impl Network {
         Ok(())
     }
 
-    /// Single simulation step
+    /// Single simulation step. Each kind of object is updated in order of
+    /// the names, whatever its clock, so the random numbers drawn go to the
+    /// same objects in every run with the same seed.
     fn step(&mut self) -> Result<()> {
-        // Update time
-        self.t += self.dt;
+        // Operations are taken out so they can borrow the whole network
+        let step = (self.t / self.dt).round() as u64;
+        let operations = std::mem::take(&mut self.operations);
+        for op in &operations {
+            if op.is_due(step, self.dt) {
+                let mut callback = op.callback.lock().unwrap_or_else(|e| e.into_inner());
+                (*callback)(self);
+            }
+        }
+        let added = std::mem::replace(&mut self.operations, operations);
+        self.operations.extend(added);
+
+        let t = self.t;
+        let dt = self.dt;
+
+        // State monitors record the values at the start of their tick
+        for (name, monitor) in self.state_monitors.iter_mut() {
+            if !step.is_multiple_of(clock_steps(Some(monitor.dt), dt, name)?) {
+                continue;
+            }
+            let group = self.neuron_groups.get(&monitor.source)
+                .or_else(|| self.rate_groups.get(&monitor.source).map(|r| &r.group));
+            if let Some(group) = group {
+                for var in monitor.variables.clone() {
+                    if let Some(values) = group.state.get(&var) {
+                        monitor.record(&var, t, values);
+                    }
+                }
+            }
+        }
+
+        // Postsynaptic currents at the start of the step
+        for group in self.neuron_groups.values_mut() {
+            group.synaptic_input.fill(0.0);
+        }
+        for syn in self.synapses.values() {
+            if let Some(target) = self.neuron_groups.get_mut(&syn.target) {
+                syn.add_currents(target);
+            }
+        }
+
+        self.couple_rates()?;
+
+        // Objects on slower clocks emit nothing between their ticks
+        let mut spikes: HashMap<String, Vec<usize>> = HashMap::new();
+
+        for (name, group) in self.neuron_groups.iter_mut() {
+            let fired = if step.is_multiple_of(clock_steps(group.dt, dt, name)?) {
+                group.update(t, group.dt.unwrap_or(dt), &mut self.rng)?
+            } else {
+                group.events_fired.clear();
+                vec![]
+            };
+            spikes.insert(name.clone(), fired);
+        }
+        for (name, neuron) in self.spatial_neurons.iter_mut() {
+            let fired = if step.is_multiple_of(clock_steps(neuron.dt, dt, name)?) {
+                neuron.update(t, neuron.dt.unwrap_or(dt), &mut self.rng)?
+            } else {
+                vec![]
+            };
+            spikes.insert(name.clone(), fired);
+        }
+
+        for (name, group) in self.rate_groups.iter_mut() {
+            if step.is_multiple_of(clock_steps(group.group.dt, dt, name)?) {
+                group.update(t, group.group.dt.unwrap_or(dt), &mut self.rng)?;
+            }
+        }
+
+        for (name, group) in &self.poisson_groups {
+            let mut fired = vec![];
+            if step.is_multiple_of(clock_steps(group.dt, dt, name)?) {
+                let p = group.dt.unwrap_or(dt) / 1000.0;
+                fired.extend((0..group.n).filter(|&i| self.rng.uniform() < group.rates[i] * p));
+            }
+            spikes.insert(name.clone(), fired);
+        }
+
+        for (name, group) in &self.spike_generators {
+            let mut fired = vec![];
+            if step.is_multiple_of(clock_steps(group.dt, dt, name)?) {
+                let period = group.dt.unwrap_or(dt);
+                fired.extend(group.spike_times.iter()
+                    .filter(|&&(_, time)| time >= t && time < t + period)
+                    .map(|&(i, _)| i));
+            }
+            spikes.insert(name.clone(), fired);
+        }
+
+        // Queue this step's spikes and deliver the ones that are due
+        for syn in self.synapses.values_mut() {
+            let every = clock_steps(syn.dt, dt, &syn.name)?;
+            // Synapses on a slower clock see every spike since their last tick
+            let pending = if every > 1 {
+                let mut names = vec![syn.source.clone()];
+                if syn.target != syn.source {
+                    names.push(syn.target.clone());
+                }
+                for name in names {
+                    let fired = spikes.get(&name).cloned().unwrap_or_default();
+                    syn.pending.entry(name).or_default().extend(fired);
+                }
+                if !step.is_multiple_of(every) {
+                    continue;
+                }
+                Some(std::mem::take(&mut syn.pending))
+            } else {
+                None
+            };
+            // The target is taken out so the source can be borrowed alongside it
+            let mut target = self.neuron_groups.remove(&syn.target).ok_or_else(|| {
+                BrianError::SimulationError(format!(
+                    "Synapses '{}': unknown target group '{}'",
+                    syn.name, syn.target
+                ))
+            })?;
+            let source = self.neuron_groups.get(&syn.source);
+            let result = syn.propagate(
+                pending.as_ref().unwrap_or(&spikes),
+                t,
+                syn.dt.unwrap_or(dt),
+                &mut target,
+                source,
+                &mut self.rng,
+            );
+            self.neuron_groups.insert(syn.target.clone(), target);
+            result?;
+        }
+
+        // Background input arrives alongside synaptic input
+        for input in &self.poisson_inputs {
+            let target = self.neuron_groups.get_mut(&input.target).ok_or_else(|| {
+                BrianError::SimulationError(format!("PoissonInput: unknown target group '{}'", input.target))
+            })?;
+            input.apply(target, dt, &mut self.rng)?;
+        }
+
+        for (source, fired) in &spikes {
+            if let Some(monitor) = self.spike_monitors.get_mut(source) {
+                for &i in fired {
+                    monitor.record_spike(i, t);
+                }
+            }
+        }
+
+        for monitor in self.event_monitors.values_mut() {
+            if let Some(group) = self.neuron_groups.get(&monitor.source) {
+                monitor.record(group, t)?;
+            }
+        }
 
-        // For now, basic Euler integration (placeholder for full implementation)
-        for (_name, group) in &mut self.neuron_groups {
-            // Simple integration of state variables would go here
-            // This is a skeleton - full implementation would parse and evaluate equations
-            let _n = group.n;
+        for monitor in self.rate_monitors.values_mut() {
+            let n_spikes = spikes.get(&monitor.source).map_or(0, Vec::len);
+            monitor.record(t, dt, n_spikes);
         }
 
+        self.spikes = spikes;
+
+        // Update time
+        self.t += dt;
+
+        Ok(())
+    }
+
+    /// Set the target variables of the rate connections to the weighted
+    /// sums of the current rates
+    fn couple_rates(&mut self) -> Result<()> {
+        let mut inputs: HashMap<(String, String), Array1<f64>> = HashMap::new();
+        for conn in self.rate_connections.values() {
+            let rates = self.rate_groups.get(&conn.source).ok_or_else(|| {
+                BrianError::SimulationError(format!(
+                    "RateConnection '{}': unknown source rate group '{}'",
+                    conn.name, conn.source
+                ))
+            })?.rates()?;
+            let n = self.rate_groups.get(&conn.target).map(RateGroup::n)
+                .or_else(|| self.neuron_groups.get(&conn.target).map(|g| g.n))
+                .ok_or_else(|| BrianError::SimulationError(format!(
+                    "RateConnection '{}': unknown target group '{}'",
+                    conn.name, conn.target
+                )))?;
+            let input = inputs.entry((conn.target.clone(), conn.target_var.clone()))
+                .or_insert_with(|| Array1::zeros(n));
+            conn.accumulate(rates, input)?;
+        }
+        for ((target, var), input) in inputs {
+            let group = match self.rate_groups.get_mut(&target) {
+                Some(rates) => &mut rates.group,
+                None => self.neuron_groups.get_mut(&target).unwrap(),
+            };
+            let column = group.state.get_mut(&var).ok_or_else(|| {
+                BrianError::SimulationError(format!(
+                    "RateConnection: unknown variable '{}' in group '{}'", var, target
+                ))
+            })?;
+            column.assign(&input);
+        }
         Ok(())
     }
+
+    pub fn add_spike_generator(&mut self, group: SpikeGeneratorGroup) {
+        self.spike_generators.insert(group.name.clone(), group);
+    }
+
+    /// Size of any spike source or neuron group
+    pub fn group_size(&self, name: &str) -> Option<usize> {
+        self.neuron_groups.get(name).map(|g| g.n)
+            .or_else(|| self.spatial_neurons.get(name).map(SpatialNeuron::n))
+            .or_else(|| self.poisson_groups.get(name).map(|g| g.n))
+            .or_else(|| self.spike_generators.get(name).map(|g| g.n))
+    }
+
+    /// Connect the synapses `name` by expressions (see [`Synapses::connect`]).
+    /// Random connections draw from the network's generator, so they repeat
+    /// with the seed even after stochastic groups have been run.
+    pub fn connect(&mut self, name: &str, condition: &str, p: &str, n: &str) -> Result<()> {
+        let (source, target) = match self.synapses.get(name) {
+            Some(syn) => (syn.source.clone(), syn.target.clone()),
+            None => return Err(BrianError::SimulationError(format!("Unknown synapses: {}", name))),
+        };
+        let size = |group: &str| {
+            self.group_size(group)
+                .ok_or_else(|| BrianError::SimulationError(format!("Unknown group: {}", group)))
+        };
+        let ends = Endpoints {
+            n_pre: size(&source)?,
+            n_post: size(&target)?,
+            pre: self.neuron_groups.get(&source).map(|g| &g.state),
+            post: self.neuron_groups.get(&target).map(|g| &g.state),
+        };
+        let syn = self.synapses.get_mut(name).expect("checked above");
+        syn.connect(condition, p, n, &ends, &mut self.rng)
+    }
 }
 
 // ============================================================================
 // BRIAN SCRIPT PARSER (simplified)
 // ============================================================================
 
+/// Split `expr : unit (flags)` into the expression and its unit
+fn split_unit(rest: &str) -> Result<(&str, Unit)> {
+    match rest.rsplit_once(':') {
+        Some((expr, unit)) => {
+            let unit = unit.split('(').next().unwrap_or("");
+            Ok((expr.trim(), Unit::parse(unit)?))
+        }
+        None => Ok((rest.trim(), Unit::Dimensionless)),
+    }
+}
+
+/// Flags after the unit: `: volt (constant, shared)` gives `["constant", "shared"]`
+fn split_flags(rest: &str) -> Vec<String> {
+    rest.rsplit_once(':')
+        .and_then(|(_, unit)| unit.split_once('('))
+        .map(|(_, flags)| {
+            flags.trim_end().trim_end_matches(')')
+                .split(',')
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// Parse Brian-style equations
 pub fn parse_equations(text: &str) -> Result<NeuronEquations> {
     let mut differential = vec![];
     let mut algebraic = vec![];
+    let mut per_neuron = vec![];
 
     for line in text.lines() {
         let line = line.trim();
@@ -841,47 +2948,52 @@ pub fn parse_equations(text: &str) -> Result<NeuronEquations> {
 
         // Differential equation: dv/dt = expr : unit
         if line.starts_with('d') && line.contains("/dt") {
-            let parts: Vec<&str> = line.split('=').collect();
-            if parts.len() >= 2 {
-                let var_part = parts[0].trim();
+            if let Some((var_part, rest)) = line.split_once('=') {
                 let var = var_part
+                    .trim()
                     .trim_start_matches('d')
                     .split("/dt")
                     .next()
                     .unwrap_or("")
                     .trim();
-
-                let expr_parts: Vec<&str> = parts[1].split(':').collect();
-                let expr = expr_parts[0].trim();
+                let (expr, unit) = split_unit(rest)?;
 
                 differential.push(DifferentialEquation {
                     variable: var.to_string(),
                     expression: expr.to_string(),
-                    unit: Unit::Dimensionless,
+                    unit,
                     method: IntegrationMethod::Euler,
+                    unless_refractory: split_flags(rest).iter().any(|f| f == "unless refractory"),
                 });
             }
         }
         // Algebraic equation: v = expr : unit
         else if line.contains('=') && !line.contains("/dt") {
-            let parts: Vec<&str> = line.split('=').collect();
-            if parts.len() >= 2 {
-                let var = parts[0].trim();
-                let expr_parts: Vec<&str> = parts[1].split(':').collect();
-                let expr = expr_parts[0].trim();
+            if let Some((var, rest)) = line.split_once('=') {
+                let (expr, unit) = split_unit(rest)?;
 
                 algebraic.push(AlgebraicEquation {
-                    variable: var.to_string(),
+                    variable: var.trim().to_string(),
                     expression: expr.to_string(),
-                    unit: Unit::Dimensionless,
+                    unit,
                 });
             }
         }
+        // Parameter: v_th : volt (constant)
+        else if let Some((var, _)) = line.split_once(':') {
+            let (_, unit) = split_unit(line)?;
+            per_neuron.push(ParameterDeclaration {
+                variable: var.trim().to_string(),
+                unit,
+                constant: split_flags(line).iter().any(|f| f == "constant"),
+            });
+        }
     }
 
     Ok(NeuronEquations {
         differential,
         algebraic,
+        per_neuron,
         threshold: None,
         reset: None,
         refractory: None,
@@ -925,22 +3037,22 @@ pub fn brunel_network(
 
     // E -> E
     let mut ee = Synapses::new("EE", "E", "E", SynapseModel::Delta { weight: w_exc });
-    ee.connect_random(n_exc, n_exc, p_conn, w_exc, 1.5);
+    ee.connect_random(n_exc, n_exc, p_conn, w_exc, 1.5, &mut network.rng);
     network.add_synapses(ee);
 
     // E -> I
     let mut ei = Synapses::new("EI", "E", "I", SynapseModel::Delta { weight: w_exc });
-    ei.connect_random(n_exc, n_inh, p_conn, w_exc, 1.5);
+    ei.connect_random(n_exc, n_inh, p_conn, w_exc, 1.5, &mut network.rng);
     network.add_synapses(ei);
 
     // I -> E
     let mut ie = Synapses::new("IE", "I", "E", SynapseModel::Delta { weight: w_inh });
-    ie.connect_random(n_inh, n_exc, p_conn, w_inh, 1.5);
+    ie.connect_random(n_inh, n_exc, p_conn, w_inh, 1.5, &mut network.rng);
     network.add_synapses(ie);
 
     // I -> I
     let mut ii = Synapses::new("II", "I", "I", SynapseModel::Delta { weight: w_inh });
-    ii.connect_random(n_inh, n_inh, p_conn, w_inh, 1.5);
+    ii.connect_random(n_inh, n_inh, p_conn, w_inh, 1.5, &mut network.rng);
     network.add_synapses(ii);
 
     // External Poisson input
@@ -966,9 +3078,45 @@ pub fn cuba_network(n: usize, dt: f64) -> Network {
 }
 
 /// COBA (Conductance-based) LIF network
-pub fn coba_network(_n: usize, dt: f64) -> Network {
-    // Simplified implementation
-    Network::new(dt)
+///
+/// Excitatory (80%) and inhibitory (20%) populations connected with
+/// probability 0.02; spikes increment the target's `ge`/`gi`.
+pub fn coba_network(n: usize, dt: f64) -> Network {
+    let n_exc = (0.8 * n as f64) as usize;
+    let n_inh = n - n_exc;
+
+    let mut network = Network::new(dt);
+    let coba = COBANeuron::default();
+
+    for (name, size) in [("E", n_exc), ("I", n_inh)] {
+        let mut group = NeuronGroup::new(name, size, coba.to_equations());
+        let v0 = Array1::from_shape_fn(size, |_| {
+            coba.v_reset + network.rng.uniform() * (coba.v_thresh - coba.v_reset)
+        });
+        group.set_initial("v", v0).ok();
+        network.add_neuron_group(group);
+        network.add_spike_monitor(SpikeMonitor::new(name, size));
+    }
+
+    // Weights relative to the leak conductance
+    let w_exc = 0.6;
+    let w_inh = 6.7;
+    let p_conn = 0.02;
+
+    for (source, n_source, weight, on_pre) in [
+        ("E", n_exc, w_exc, "ge += w"),
+        ("I", n_inh, w_inh, "gi += w"),
+    ] {
+        for (target, n_target) in [("E", n_exc), ("I", n_inh)] {
+            let name = format!("{}{}", source, target);
+            let mut syn = Synapses::new(&name, source, target, SynapseModel::Delta { weight });
+            syn.connect_random(n_source, n_target, p_conn, weight, 0.0, &mut network.rng);
+            syn.on_pre = vec![on_pre.into()];
+            network.add_synapses(syn);
+        }
+    }
+
+    network
 }
 
 // ============================================================================
@@ -1061,6 +3209,573 @@ mod tests {
         assert_eq!(net.neuron_groups["I"].n, 20);
     }
 
+    #[test]
+    fn test_equations_integrate() {
+        let mut eqs = parse_equations("dv/dt = (v_rest - v + I) / tau : volt").unwrap();
+        eqs.parameters.insert("v_rest".into(), Quantity::new(-70.0, Unit::Millivolt));
+        eqs.parameters.insert("tau".into(), Quantity::new(0.01, Unit::Second));
+
+        let mut group = NeuronGroup::new("G", 2, eqs);
+        group.set_initial("v", Array1::from_vec(vec![-70.0, -50.0])).unwrap();
+        group.input[0] = 10.0;
+
+        let mut net = Network::new(0.1);
+        net.add_neuron_group(group);
+        net.run(100.0).unwrap();
+
+        // Relaxes to v_rest + I with tau = 10 ms
+        let v = &net.neuron_groups["G"].state["v"];
+        assert!((v[0] - -60.0).abs() < 0.01);
+        assert!((v[1] - -70.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_lif_firing_and_refractory() {
+        let lif = LIFNeuron::default();
+        let mut group = NeuronGroup::new("G", 1, lif.to_equations());
+        group.method = IntegrationMethod::RungeKutta4;
+        group.set_initial("v", Array1::from_elem(1, lif.v_reset)).unwrap();
+        group.input.fill(2.0);
+
+        let mut net = Network::new(0.01);
+        net.add_neuron_group(group);
+        net.add_spike_monitor(SpikeMonitor::new("G", 1));
+        net.add_state_monitor(StateMonitor::new("G", &["v"], &[0], 0.01));
+        net.run(100.0).unwrap();
+
+        // ISI = tau * ln((v_inf - v_reset) / (v_inf - v_thresh)) + tau_ref
+        let expected = 10.0 * (20.0f64 / 5.0).ln() + 2.0;
+        let trains = net.spike_monitors["G"].spike_trains();
+        let train = &trains[&0];
+        assert!(train.len() >= 5);
+        for isi in train.windows(2).map(|w| w[1] - w[0]) {
+            assert!((isi - expected).abs() < 0.05, "isi = {}", isi);
+        }
+
+        // Clamped at reset during the refractory period
+        let (times, data) = &net.state_monitors["G_state"].data["v"];
+        let t_spike = train[0];
+        for (&time, &v) in times.iter().zip(data[0].iter()) {
+            if time > t_spike + 0.005 && time < t_spike + 1.995 {
+                assert_eq!(v, lif.v_reset);
+            }
+        }
+    }
+
+    #[test]
+    fn test_synaptic_delays() {
+        let mut gen = SpikeGeneratorGroup::new("P", 1);
+        gen.add_spikes(&[0], &[1.0]);
+
+        let jump = NeuronGroup::new("G", 2, parse_equations("dv/dt = 0 / ms : 1").unwrap());
+        let current = NeuronGroup::new("H", 1, parse_equations("dv/dt = I / ms : 1").unwrap());
+
+        let mut delta = Synapses::new("PG", "P", "G", SynapseModel::Delta { weight: 1.0 });
+        delta.connections = vec![(0, 0), (0, 1)];
+        delta.weights = vec![0.5, 2.0];
+        delta.delays = vec![2.0, 0.0];
+        let mut exp = Synapses::new("PH", "P", "H", SynapseModel::Exponential { weight: 1.0, tau: 5.0 });
+        exp.connect_one_to_one(1, 1.0, 0.0);
+
+        let mut net = Network::new(0.1);
+        net.add_spike_generator(gen);
+        net.add_neuron_group(jump);
+        net.add_neuron_group(current);
+        net.add_synapses(delta);
+        net.add_synapses(exp);
+
+        net.run(2.0).unwrap();
+        let v = &net.neuron_groups["G"].state["v"];
+        assert_eq!((v[0], v[1]), (0.0, 2.0));
+        assert_eq!(net.synapses["PG"].pending_spikes(), 1);
+
+        net.run(1.5).unwrap();
+        let v = &net.neuron_groups["G"].state["v"];
+        assert_eq!((v[0], v[1]), (0.5, 2.0));
+        assert_eq!(net.synapses["PG"].pending_spikes(), 0);
+
+        // The integrated exponential current approaches weight * tau
+        net.run(100.0).unwrap();
+        let v = net.neuron_groups["H"].state["v"][0];
+        assert!((v - 5.0).abs() < 0.1, "v = {}", v);
+    }
+
+    #[test]
+    fn test_exponential_euler_large_dt() {
+        // tau = 10 ms, dt = 25 ms: forward Euler diverges, exponential Euler
+        // is exact for constant input
+        let lif = LIFNeuron::default();
+        let v_inf = lif.v_rest + lif.r_m * 1.0;
+        for method in [IntegrationMethod::ExponentialEuler, IntegrationMethod::ExactSolution] {
+            let mut eqs = lif.to_equations();
+            eqs.threshold = None;
+            let mut group = NeuronGroup::new("G", 1, eqs);
+            group.method = method;
+            group.set_initial("v", Array1::from_elem(1, lif.v_rest)).unwrap();
+            group.input.fill(1.0);
+            let mut rng = Rng::default();
+            let mut t = 0.0;
+            for _ in 0..4 {
+                group.update(t, 25.0, &mut rng).unwrap();
+                t += 25.0;
+            }
+            let expected = v_inf + (lif.v_rest - v_inf) * (-t / lif.tau_m).exp();
+            assert!((group.state["v"][0] - expected).abs() < 1e-9, "{:?}", method);
+        }
+
+        let mut eqs = lif.to_equations();
+        eqs.threshold = None;
+        let mut group = NeuronGroup::new("G", 1, eqs);
+        group.method = IntegrationMethod::Euler;
+        group.set_initial("v", Array1::from_elem(1, -60.0)).unwrap();
+        let mut rng = Rng::default();
+        for k in 0..20 {
+            group.update(k as f64 * 25.0, 25.0, &mut rng).unwrap();
+        }
+        assert!(group.state["v"][0].abs() > 1e3);
+    }
+
+    #[test]
+    fn test_exact_coupled_system() {
+        // Current-based synapse driving a membrane: linear, constant coefficients
+        let mut eqs = parse_equations(
+            "dv/dt = (g - v) / tau_m : volt\ndg/dt = -g / tau_s : volt",
+        ).unwrap();
+        eqs.parameters.insert("tau_m".into(), Quantity::new(10.0, Unit::Millisecond));
+        eqs.parameters.insert("tau_s".into(), Quantity::new(5.0, Unit::Millisecond));
+        let mut group = NeuronGroup::new("G", 1, eqs);
+        group.method = IntegrationMethod::ExactSolution;
+        group.set_initial("g", Array1::from_elem(1, 1.0)).unwrap();
+
+        let mut rng = Rng::default();
+        for k in 0..3 {
+            group.update(k as f64 * 4.0, 4.0, &mut rng).unwrap();
+        }
+        let t: f64 = 12.0;
+        let expected = 5.0 / (10.0 - 5.0) * ((-t / 10.0).exp() - (-t / 5.0).exp());
+        assert!((group.state["v"][0] - expected).abs() < 1e-9);
+
+        // COBA is only conditionally linear
+        let mut coba = NeuronGroup::new("C", 1, COBANeuron::default().to_equations());
+        coba.method = IntegrationMethod::ExactSolution;
+        assert!(matches!(coba.update(0.0, 0.1, &mut rng), Err(BrianError::EquationError(_))));
+        coba.method = IntegrationMethod::ExponentialEuler;
+        assert!(coba.update(0.0, 0.1, &mut rng).is_ok());
+
+        let mut izh = NeuronGroup::new("Z", 1, IzhikevichNeuron::regular_spiking().to_equations());
+        izh.method = IntegrationMethod::ExponentialEuler;
+        assert!(matches!(izh.update(0.0, 0.1, &mut rng), Err(BrianError::EquationError(_))));
+    }
+
+    #[test]
+    fn test_synapse_equations_and_pathways() {
+        let mut gen = SpikeGeneratorGroup::new("P", 2);
+        gen.add_spikes(&[0, 0, 1], &[1.05, 2.05, 2.05]);
+
+        let mut eqs = parse_equations("dv/dt = 0 / ms : 1").unwrap();
+        eqs.threshold = Some(ThresholdCondition { condition: "v > 2.5".into() });
+        eqs.reset = Some(ResetEquations { equations: vec!["v = 0".into()] });
+
+        let mut syn = Synapses::new("S", "P", "G", SynapseModel::Delta { weight: 1.0 });
+        syn.connect_all_to_all(2, 1, 1.0, 0.0);
+        let mut syn_eqs = parse_equations("dx/dt = -x / tau : 1").unwrap();
+        syn_eqs.parameters.insert("tau".into(), Quantity::new(10.0, Unit::Millisecond));
+        syn.set_equations(syn_eqs);
+        syn.on_pre = vec!["v_post += w; x += 1".into()];
+        syn.on_post = vec!["w = 0.5 * w".into()];
+
+        let mut net = Network::new(0.1);
+        net.add_spike_generator(gen);
+        net.add_neuron_group(NeuronGroup::new("G", 1, eqs));
+        net.add_synapses(syn);
+        net.add_spike_monitor(SpikeMonitor::new("G", 1));
+
+        net.run(1.5).unwrap();
+        assert!((net.neuron_groups["G"].state["v"][0] - 1.0).abs() < 1e-12);
+
+        // Two arrivals push v to 3, the neuron fires on the next step and
+        // on_post halves both weights
+        net.run(1.5).unwrap();
+        assert_eq!(net.spike_monitors["G"].spike_trains()[&0].len(), 1);
+        assert_eq!(net.neuron_groups["G"].state["v"][0], 0.0);
+        assert_eq!(net.synapses["S"].weights, vec![0.5, 0.5]);
+
+        let x = &net.synapses["S"].dynamics.as_ref().unwrap().state["x"];
+        assert!(x[0] > x[1] && x[1] > 0.0 && x[1] < 1.0);
+    }
+
+    #[test]
+    fn test_pathway_read_only_variables() {
+        let mut net = Network::new(0.1);
+        net.add_neuron_group(NeuronGroup::new("G", 2, parse_equations("dv/dt = 0 / ms : 1").unwrap()));
+        let mut syn = Synapses::new("S", "G", "G", SynapseModel::Delta { weight: 1.0 });
+        syn.connect_one_to_one(2, 1.0, 0.0);
+        syn.on_pre = vec!["v_pre = 0".into()];
+        net.add_synapses(syn);
+        assert!(matches!(net.run(0.1), Err(BrianError::EquationError(_))));
+    }
+
+    #[test]
+    fn test_coba_network_runs() {
+        let mut net = coba_network(200, 0.1);
+        net.run(20.0).unwrap();
+        assert_eq!(net.synapses.len(), 4);
+        let spikes: usize = net.spike_monitors.values().map(|m| m.spike_trains().len()).sum();
+        assert!(spikes > 0);
+    }
+
+    #[test]
+    fn test_string_connect() {
+        let mut net = Network::new(0.1);
+        net.seed(42);
+        let mut group = NeuronGroup::new("G", 50, parse_equations("dx/dt = 0 / ms : 1").unwrap());
+        group.set_initial("x", (0..50).map(|k| k as f64).collect()).unwrap();
+        net.add_neuron_group(group);
+
+        net.add_synapses(Synapses::new("all", "G", "G", SynapseModel::Delta { weight: 0.5 }));
+        net.connect("all", "i != j", "", "").unwrap();
+        let all = &net.synapses["all"];
+        assert_eq!(all.connections.len(), 50 * 49);
+        assert!(all.connections.iter().all(|&(i, j)| i != j));
+        assert!(all.weights.iter().all(|&w| w == 0.5));
+
+        net.add_synapses(Synapses::new("multi", "G", "G", SynapseModel::Delta { weight: 1.0 }));
+        net.connect("multi", "j == (i + 1) % N_post", "", "n = 2").unwrap();
+        assert_eq!(net.synapses["multi"].connections.len(), 100);
+
+        // Distance-dependent probability over a state variable
+        let mut gauss = Synapses::new("gauss", "G", "G", SynapseModel::Delta { weight: 1.0 });
+        gauss.parameters.insert("sigma".into(), Quantity::new(3.0, Unit::Dimensionless));
+        net.add_synapses(gauss);
+        net.connect("gauss", "", "p = exp(-(x_pre - x_post)**2 / (2 * sigma**2))", "").unwrap();
+        let gauss = &net.synapses["gauss"];
+        assert!(gauss.connections.iter().all(|&(i, j)| i.abs_diff(j) < 20));
+        let near = gauss.connections.iter().filter(|&&(i, j)| i.abs_diff(j) <= 1).count();
+        let far = gauss.connections.iter().filter(|&&(i, j)| i.abs_diff(j) >= 6).count();
+        assert!(near > far);
+
+        // Same seed, same connectivity
+        let mut a = Synapses::new("a", "P", "Q", SynapseModel::Delta { weight: 1.0 });
+        let mut b = a.clone();
+        let ends = Endpoints { n_pre: 30, n_post: 30, pre: None, post: None };
+        a.connect("", "0.2", "", &ends, &mut Rng::new(7)).unwrap();
+        b.connect("", "0.2", "", &ends, &mut Rng::new(7)).unwrap();
+        assert_eq!(a.connections, b.connections);
+        assert!((a.connections.len() as f64 - 180.0).abs() < 60.0);
+
+        assert!(net.connect("all", "y_pre > 0", "", "").is_err());
+    }
+
+    #[test]
+    fn test_population_rate_monitor() {
+        let mut net = Network::new(0.1);
+        net.add_poisson_group(PoissonGroup::new("P", 1000, 20.0));
+        net.add_rate_monitor(PopulationRateMonitor::new("P", 1000, 5.0));
+        net.run(1000.0).unwrap();
+
+        let monitor = &net.rate_monitors["P"];
+        assert_eq!(monitor.rates.len(), 200);
+        assert!((monitor.times[1] - 5.0).abs() < 1e-6);
+        let mean: f64 = monitor.rates.iter().sum::<f64>() / monitor.rates.len() as f64;
+        assert!((mean - 20.0).abs() < 1.0, "mean = {}", mean);
+
+        // Smoothing keeps the mean and reduces fluctuations away from the edges
+        let variance = |r: &[f64]| r.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / r.len() as f64;
+        for window in [SmoothingWindow::Flat { width: 25.0 }, SmoothingWindow::Gaussian { width: 10.0 }] {
+            let smooth = monitor.smooth_rate(window);
+            assert_eq!(smooth.len(), monitor.rates.len());
+            let inner = &smooth[10..190];
+            assert!(variance(inner) < variance(&monitor.rates[10..190]) / 2.0);
+        }
+
+        // A flat window over a constant trace is the identity away from the edges
+        let mut constant = PopulationRateMonitor::new("C", 10, 1.0);
+        for k in 0..20 {
+            constant.record(k as f64, 1.0, 1);
+        }
+        let smooth = constant.smooth_rate(SmoothingWindow::Flat { width: 3.0 });
+        assert!(smooth[1..19].iter().all(|&r| (r - 100.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_timed_array_stimulus() {
+        // Two neurons, stimulus switching at t = 10 ms
+        let times = Array1::from_vec(vec![0.0, 10.0]);
+        let values = Array2::from_shape_vec((2, 2), vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        let stimulus = TimedArray::new("stimulus", times, values.clone()).unwrap();
+        assert_eq!(stimulus.lookup(5.0, 1), 2.0);
+        assert_eq!(stimulus.lookup(-1.0, 0), 1.0);
+        assert_eq!(stimulus.lookup(50.0, 0), 3.0);
+        assert!(stimulus.lookup(5.0, 2).is_nan());
+        let linear = stimulus.clone().with_interpolation(Interpolation::Linear);
+        assert_eq!(linear.lookup(2.5, 0), 1.5);
+        assert!(TimedArray::new("bad", Array1::from_vec(vec![1.0, 0.0]), values.clone()).is_err());
+
+        // dx/dt = stimulus(t, i) integrates the step function (switch placed
+        // between steps)
+        let stimulus = TimedArray::new("stimulus", Array1::from_vec(vec![0.0, 10.05]), values).unwrap();
+        let eqs = parse_equations("dx/dt = J / ms : 1\nJ = stimulus(t, i) : 1").unwrap();
+        let mut net = Network::new(0.1);
+        net.add_timed_array(stimulus);
+        net.add_neuron_group(NeuronGroup::new("G", 2, eqs));
+        net.run(20.0).unwrap();
+        let x = &net.neuron_groups["G"].state["x"];
+        assert!((x[0] - (10.1 * 1.0 + 9.9 * 3.0)).abs() < 1e-6, "x = {}", x[0]);
+        assert!((x[1] - (10.1 * 2.0 + 9.9 * 4.0)).abs() < 1e-6);
+
+        let mut group = NeuronGroup::new("H", 1, parse_equations("dx/dt = missing(t) / ms : 1").unwrap());
+        assert!(group.update(0.0, 0.1, &mut Rng::default()).is_err());
+    }
+
+    #[test]
+    fn test_network_operation() {
+        // Kick x up by 1 every 2 ms; it otherwise stays constant
+        let eqs = parse_equations("dx/dt = 0 / ms : 1").unwrap();
+        let mut net = Network::new(0.1);
+        net.add_neuron_group(NeuronGroup::new("G", 3, eqs));
+        let calls = Arc::new(Mutex::new(vec![]));
+        let log = calls.clone();
+        net.add_operation(move |net: &mut Network| {
+            log.lock().unwrap().push(net.t);
+            if let Some(x) = net.neuron_groups.get_mut("G").and_then(|g| g.state.get_mut("x")) {
+                *x += 1.0;
+            }
+        }, Some(2.0));
+        net.run(10.0).unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 5);
+        assert!((calls[1] - 2.0).abs() < 1e-9);
+        assert!(net.neuron_groups["G"].state["x"].iter().all(|&x| x == 5.0));
+    }
+
+    #[test]
+    fn test_multiple_clocks() {
+        // A slow integrator, a coarse monitor and slow synapses fed by a
+        // generator on the base clock
+        let mut slow = NeuronGroup::new("G", 1, parse_equations("dx/dt = 1 / ms : 1").unwrap());
+        slow.dt = Some(1.0);
+        let mut gen = SpikeGeneratorGroup::new("P", 1);
+        gen.add_spikes(&[0, 0, 0], &[0.1, 0.25, 0.5]);
+        let mut syn = Synapses::new("S", "P", "H", SynapseModel::Delta { weight: 1.0 });
+        syn.connect_all_to_all(1, 1, 1.0, 0.0);
+        syn.on_pre = vec!["v_post += w".into()];
+        syn.dt = Some(1.0);
+
+        let mut net = Network::new(0.1);
+        net.add_neuron_group(slow);
+        net.add_neuron_group(NeuronGroup::new("H", 1, parse_equations("dv/dt = 0 / ms : 1").unwrap()));
+        net.add_spike_generator(gen);
+        net.add_synapses(syn);
+        net.add_state_monitor(StateMonitor::new("G", &["x"], &[0], 2.0));
+
+        net.run(0.9).unwrap();
+        assert_eq!(net.neuron_groups["G"].state["x"][0], 1.0);
+        assert_eq!(net.neuron_groups["H"].state["v"][0], 0.0);
+
+        // The synapses' next tick delivers all three buffered spikes
+        net.run(9.1).unwrap();
+        assert!((net.neuron_groups["G"].state["x"][0] - 10.0).abs() < 1e-9);
+        assert_eq!(net.neuron_groups["H"].state["v"][0], 3.0);
+        let (times, values) = &net.state_monitors["G_state"].data["x"];
+        assert_eq!(times.len(), 5);
+        assert!((values[0][4] - 8.0).abs() < 1e-9);
+
+        net.neuron_groups.get_mut("H").unwrap().dt = Some(0.25);
+        assert!(net.run(1.0).is_err());
+    }
+
+    #[test]
+    fn test_run_regularly() {
+        let eqs = parse_equations("dv/dt = 0 * mV / ms : volt").unwrap();
+        let mut group = NeuronGroup::new("G", 3, eqs);
+        group.run_regularly("v += 0.5 * mV", 1.0);
+        group.run_regularly_on("v -= 2 * mV", 5.0, vec![2]);
+        let mut rng = Rng::default();
+        for k in 0..100 {
+            group.update(k as f64 * 0.1, 0.1, &mut rng).unwrap();
+        }
+        // Ran at t = 0, 1, ..., 9 ms and t = 0, 5 ms
+        let v = &group.state["v"];
+        assert!((v[0] - 5.0).abs() < 1e-9);
+        assert!((v[1] - 5.0).abs() < 1e-9);
+        assert!((v[2] - 1.0).abs() < 1e-9);
+
+        group.run_regularly("v += 1 * second", 1.0);
+        assert!(group.update(10.0, 0.1, &mut rng).is_err());
+    }
+
+    #[cfg(feature = "closures")]
+    #[test]
+    fn test_closure_backend_matches_interpreter() {
+        let run = |backend: Backend| {
+            let mut group = NeuronGroup::new("G", 10, AdExNeuron::default().to_equations());
+            group.backend = backend;
+            group.input = Array1::from_shape_fn(10, |i| 0.1 * i as f64);
+            group.set_initial("v", Array1::from_elem(10, -70.0)).unwrap();
+            let mut rng = Rng::new(7);
+            let mut spikes = vec![];
+            for k in 0..2000 {
+                spikes.extend(group.update(k as f64 * 0.1, 0.1, &mut rng).unwrap());
+            }
+            (group.state["v"].to_vec(), spikes)
+        };
+        let (v, spikes) = run(Backend::Closures);
+        assert!(!spikes.is_empty());
+        assert_eq!((v, spikes), run(Backend::Interpreter));
+    }
+
+    #[test]
+    fn test_store_restore() {
+        let mut net = coba_network(200, 0.1);
+        net.seed(3);
+        net.add_spike_monitor(SpikeMonitor::new("E", 160));
+        net.store("initial");
+
+        let trial = |net: &mut Network| {
+            net.run(20.0).unwrap();
+            (net.neuron_groups["E"].state["v"].to_vec(), net.spike_monitors["E"].spikes.len())
+        };
+        let first = trial(&mut net);
+        assert!(first.1 > 0);
+
+        // Same initial condition and noise: identical trial
+        net.restore_with_random_state("initial").unwrap();
+        assert_eq!(net.t, 0.0);
+        assert!(net.spike_monitors["E"].spikes.is_empty());
+        assert_eq!(trial(&mut net), first);
+
+        // Fresh noise from the same starting point
+        net.restore("initial").unwrap();
+        assert_eq!(net.t, 0.0);
+        assert!(net.restore("missing").is_err());
+    }
+
+    #[test]
+    fn test_heterogeneous_parameters() {
+        let mut eqs = parse_equations("
+            dv/dt = (v_rest - v) / tau : volt
+            v_rest : volt (constant)
+            v_th : volt (constant)
+            tau : second
+        ").unwrap();
+        assert_eq!(eqs.per_neuron.len(), 3);
+        assert!(eqs.per_neuron[0].constant && !eqs.per_neuron[2].constant);
+        eqs.threshold = Some(ThresholdCondition { condition: "v > v_th".into() });
+        eqs.reset = Some(ResetEquations { equations: vec!["v = v_rest".into()] });
+
+        let mut group = NeuronGroup::new("G", 50, eqs.clone());
+        let mut rng = Rng::new(2);
+        group.assign("v_rest = -70*mV + 10*mV*i/N\nv_th = -50*mV + 5*mV*rand()\ntau = 10*ms", &mut rng).unwrap();
+        let v_th = group.state["v_th"].to_vec();
+        assert!(v_th.iter().all(|v| (-50.0..-45.0).contains(v)));
+        assert!(v_th.iter().any(|&v| v != v_th[0]));
+
+        for k in 0..1000 {
+            group.update(k as f64 * 0.1, 0.1, &mut rng).unwrap();
+        }
+        for i in 0..50 {
+            let expected = -70.0 + 10.0 * i as f64 / 50.0;
+            assert!((group.state["v"][i] - expected).abs() < 1e-3);
+        }
+        assert!(group.assign("v_rest = 1*second", &mut rng).is_err());
+
+        // Model code cannot change constants
+        eqs.reset = Some(ResetEquations { equations: vec!["v_th += 1*mV".into()] });
+        assert!(NeuronGroup::new("H", 1, eqs).compile().is_err());
+        group.run_regularly("v_rest = -60*mV", 1.0);
+        assert!(group.update(100.0, 0.1, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_unless_refractory() {
+        let mut eqs = parse_equations("
+            dv/dt = (20*mV - v) / (5*ms) : volt (unless refractory)
+            dx/dt = (20*mV - x) / (5*ms) : volt
+            active = not_refractory : 1
+        ").unwrap();
+        assert!(eqs.differential[0].unless_refractory && !eqs.differential[1].unless_refractory);
+        eqs.threshold = Some(ThresholdCondition { condition: "v > 10*mV".into() });
+        eqs.reset = Some(ResetEquations { equations: vec!["v = 0*mV\nx = 0*mV".into()] });
+        eqs.refractory = Some(RefractorySpec::Duration(Quantity::new(2.0, Unit::Millisecond)));
+
+        let mut group = NeuronGroup::new("G", 1, eqs);
+        let mut rng = Rng::new(1);
+        let mut k = 0;
+        while group.update(k as f64 * 0.1, 0.1, &mut rng).unwrap().is_empty() {
+            k += 1;
+        }
+
+        // v is held at its reset value while x keeps integrating
+        for _ in 0..10 {
+            k += 1;
+            group.update(k as f64 * 0.1, 0.1, &mut rng).unwrap();
+            assert_eq!(group.state["v"][0], 0.0);
+            assert_eq!(group.state["active"][0], 0.0);
+        }
+        assert!(group.state["x"][0] > 1.0);
+
+        k += 10;
+        group.update(k as f64 * 0.1, 0.1, &mut rng).unwrap();
+        assert!(group.state["v"][0] > 0.0);
+        assert_eq!(group.state["active"][0], 1.0);
+    }
+
+    #[test]
+    fn test_custom_events() {
+        let mut eqs = parse_equations("
+            dv/dt = (20*mV - v) / (5*ms) : volt
+            n_up : 1
+        ").unwrap();
+        eqs.threshold = Some(ThresholdCondition { condition: "v > 15*mV".into() });
+        eqs.reset = Some(ResetEquations { equations: vec!["v = 0*mV".into()] });
+        let mut group = NeuronGroup::new("G", 2, eqs);
+        group.add_event("up", "v > 10*mV").unwrap();
+        group.run_on_event("up", "n_up += 1").unwrap();
+        assert!(group.add_event("spike", "v > 0*mV").is_err());
+        assert!(group.run_on_event("down", "n_up = 0").is_err());
+
+        let mut net = Network::new(0.1);
+        net.add_neuron_group(group);
+        net.add_spike_monitor(SpikeMonitor::new("G", 2));
+        net.add_event_monitor(EventMonitor::new("G", "up", &["v"]));
+        net.run(20.0).unwrap();
+
+        // Values are taken before the spike reset
+        let monitor = &net.event_monitors["G_up"];
+        let v = &monitor.values["v"];
+        assert_eq!(v.len(), monitor.events.len());
+        assert!(v.iter().all(|&v| v > 10.0));
+        assert!(v.iter().any(|&v| v > 15.0));
+        assert_eq!(net.spike_monitors["G"].counts, vec![2, 2]);
+        let n_up = net.neuron_groups["G"].state["n_up"][0];
+        assert_eq!(monitor.event_trains()[&0].len() as f64, n_up);
+
+        net.neuron_groups.get_mut("G").unwrap().run_on_event("up", "n_up += 1*mV").unwrap();
+        assert!(net.run(0.1).is_err());
+    }
+
+    #[test]
+    fn test_poisson_input() {
+        // Shot noise mean: N * rate * w * tau = 1000 * 10 Hz * 0.1 mV * 10 ms
+        let eqs = parse_equations("dv/dt = -v / (10*ms) : volt").unwrap();
+        let mut net = Network::new(0.1);
+        net.seed(5);
+        net.add_neuron_group(NeuronGroup::new("G", 200, eqs));
+        net.add_poisson_input(PoissonInput::new("G", "v", 1000, 10.0, 0.1));
+        net.run(100.0).unwrap();
+        let mean = net.neuron_groups["G"].state["v"].mean().unwrap();
+        assert!((mean - 10.0).abs() < 0.5, "mean = {}", mean);
+
+        // Sparse regime uses exact inversion
+        let mut rng = Rng::new(1);
+        let total: usize = (0..20000).map(|_| rng.binomial(50, 0.01)).sum();
+        assert!((total as f64 / 20000.0 - 0.5).abs() < 0.02);
+
+        net.add_poisson_input(PoissonInput::new("G", "w", 10, 1.0, 1.0));
+        assert!(net.run(0.1).is_err());
+    }
+
     #[test]
     fn test_stdp_rule() {
         let stdp = STDPRule::default();
@@ -1068,4 +3783,182 @@ mod tests {
         assert!(stdp.a_minus > stdp.a_plus);  // Slight LTD dominance
         assert_eq!(stdp.tau_pre, stdp.tau_post);
     }
+
+    #[test]
+    fn test_stdp_window() {
+        // One isolated pre/post pair per channel; the post neuron is driven
+        // to spike one step after its drive spike
+        let dt = 0.1;
+        let intervals = [-40.0, -20.0, -10.0, -5.0, -1.0, 1.0, 5.0, 10.0, 20.0, 40.0];
+        let n = intervals.len();
+        let t_pre = 50.05;
+
+        let mut pre = SpikeGeneratorGroup::new("Pre", n);
+        let mut drive = SpikeGeneratorGroup::new("Drive", n);
+        for (k, &delta) in intervals.iter().enumerate() {
+            pre.add_spikes(&[k], &[t_pre]);
+            drive.add_spikes(&[k], &[t_pre + delta - dt]);
+        }
+
+        let mut eqs = parse_equations("dv/dt = 0 / ms : 1").unwrap();
+        eqs.threshold = Some(ThresholdCondition { condition: "v > 0.5".into() });
+        eqs.reset = Some(ResetEquations { equations: vec!["v = 0".into()] });
+
+        let rule = STDPRule::default();
+        let mut plastic = Synapses::new("S", "Pre", "Post", SynapseModel::Exponential { weight: 0.5, tau: 5.0 });
+        plastic.plasticity = Some(rule.clone());
+        plastic.connect_one_to_one(n, 0.5, 0.0);
+        let mut driver = Synapses::new("D", "Drive", "Post", SynapseModel::Delta { weight: 1.0 });
+        driver.connect_one_to_one(n, 1.0, 0.0);
+
+        let mut net = Network::new(dt);
+        net.add_spike_generator(pre);
+        net.add_spike_generator(drive);
+        net.add_neuron_group(NeuronGroup::new("Post", n, eqs));
+        net.add_synapses(plastic);
+        net.add_synapses(driver);
+        net.run(150.0).unwrap();
+
+        for (k, &delta) in intervals.iter().enumerate() {
+            let dw = net.synapses["S"].weights[k] - 0.5;
+            let expected = if delta > 0.0 {
+                rule.a_plus * (-delta / rule.tau_pre).exp()
+            } else {
+                -rule.a_minus * (delta / rule.tau_post).exp()
+            };
+            assert!((dw - expected).abs() < 1e-9, "dt = {}: {} vs {}", delta, dw, expected);
+            assert!((dw - rule.window(delta)).abs() < 1e-9);
+        }
+
+        // Repeated potentiation saturates at w_max
+        let mut syn = Synapses::new("S", "A", "B", SynapseModel::Exponential { weight: 0.99, tau: 5.0 });
+        syn.plasticity = Some(rule.clone());
+        syn.connect_one_to_one(1, 0.99, 0.0);
+        let mut target = NeuronGroup::new("B", 1, parse_equations("dv/dt = 0 / ms : 1").unwrap());
+        let mut rng = Rng::default();
+        let pre_spike = HashMap::from([("A".to_string(), vec![0])]);
+        let post_spike = HashMap::from([("B".to_string(), vec![0])]);
+        for step in 0..10 {
+            let t = step as f64 * 10.0;
+            syn.propagate(&pre_spike, t, dt, &mut target, None, &mut rng).unwrap();
+            syn.propagate(&post_spike, t + 1.0, dt, &mut target, None, &mut rng).unwrap();
+        }
+        assert_eq!(syn.weights[0], rule.w_max);
+    }
+
+    #[test]
+    fn test_seed_reproducible() {
+        // The same seeded groups added in either order spike alike
+        let run = |names: &[&str]| {
+            let mut net = Network::new(0.1);
+            net.seed(42);
+            for name in names {
+                net.add_poisson_group(PoissonGroup::new(name, 20, 50.0));
+                net.add_spike_monitor(SpikeMonitor::new(name, 20));
+            }
+            net.run(100.0).unwrap();
+            net.spike_monitors.iter().map(|(name, m)| (name.clone(), m.spikes.clone())).collect::<Vec<_>>()
+        };
+        let first = run(&["A", "B", "C", "D"]);
+        assert!(first.iter().all(|(_, spikes)| !spikes.is_empty()));
+        assert_eq!(run(&["D", "C", "B", "A"]), first);
+        assert_eq!(run(&["B", "D", "A", "C"]), first);
+    }
+
+    #[test]
+    fn test_seed_reproducible_clocks() {
+        // Random updates of groups, Poisson sources and synapses on
+        // different clocks repeat with the seed, whatever the order added
+        let run = |reversed: bool| {
+            let mut groups = vec![];
+            for (name, dt) in [("G", None), ("H", Some(0.5))] {
+                let mut group = NeuronGroup::new(name, 5, parse_equations("dv/dt = 0 / ms : 1").unwrap());
+                group.dt = dt;
+                group.run_regularly("v += rand()", 1.0);
+                groups.push(group);
+            }
+            let mut sources = vec![];
+            for (name, dt) in [("P", None), ("Q", Some(0.2))] {
+                let mut source = PoissonGroup::new(name, 5, 100.0);
+                source.dt = dt;
+                sources.push(source);
+            }
+            let mut synapses = vec![];
+            for (name, source, target) in [("S", "P", "G"), ("T", "Q", "H")] {
+                let mut syn = Synapses::new(name, source, target, SynapseModel::Delta { weight: 1.0 });
+                syn.connect_all_to_all(5, 5, 1.0, 0.0);
+                syn.on_pre = vec!["v_post += rand()".into()];
+                synapses.push(syn);
+            }
+            if reversed {
+                groups.reverse();
+                sources.reverse();
+                synapses.reverse();
+            }
+            let mut net = Network::new(0.1);
+            net.seed(11);
+            groups.into_iter().for_each(|g| net.add_neuron_group(g));
+            sources.into_iter().for_each(|p| net.add_poisson_group(p));
+            synapses.into_iter().for_each(|s| net.add_synapses(s));
+            net.run(20.0).unwrap();
+            (net.neuron_groups["G"].state["v"].to_vec(), net.neuron_groups["H"].state["v"].to_vec())
+        };
+        let first = run(false);
+        assert!(first.0.iter().chain(&first.1).all(|&v| v > 0.0));
+        assert_eq!(run(true), first);
+    }
+
+    #[test]
+    fn test_poisson_input_reproducible() {
+        // Inputs onto two groups, beside a Poisson group, repeat with the
+        // seed whatever order they are added in
+        let run = |reversed: bool| {
+            let mut inputs = vec![
+                PoissonInput::new("G", "v", 100, 10.0, 0.1),
+                PoissonInput::new("H", "v", 50, 20.0, 0.2),
+                PoissonInput::new("G", "v", 10, 5.0, 1.0),
+            ];
+            if reversed {
+                inputs.reverse();
+            }
+            let mut net = Network::new(0.1);
+            net.seed(3);
+            net.add_poisson_group(PoissonGroup::new("P", 10, 50.0));
+            for name in ["G", "H"] {
+                net.add_neuron_group(NeuronGroup::new(name, 10, parse_equations("dv/dt = -v / (10*ms) : volt").unwrap()));
+            }
+            inputs.into_iter().for_each(|i| net.add_poisson_input(i));
+            net.add_state_monitor(StateMonitor::new("G", &["v"], &[0, 5], 0.1));
+            net.add_state_monitor(StateMonitor::new("H", &["v"], &[0, 5], 0.1));
+            net.run(50.0).unwrap();
+            net.state_monitors.values().map(|m| m.data["v"].1.clone()).collect::<Vec<_>>()
+        };
+        let first = run(false);
+        assert!(first.iter().all(|trace| trace.iter().flatten().any(|&v| v > 0.0)));
+        assert_eq!(run(true), first);
+    }
+
+    #[test]
+    fn test_connect_reproducible() {
+        // Random connectivity made after a stochastic run repeats with the
+        // seed whatever order the objects were added in
+        let run = |names: &[&str]| {
+            let mut net = Network::new(0.1);
+            net.seed(9);
+            for name in names {
+                net.add_poisson_group(PoissonGroup::new(name, 20, 30.0));
+                let target = format!("{}_target", name);
+                net.add_neuron_group(NeuronGroup::new(&target, 20, parse_equations("dv/dt = -v / (10*ms) : volt").unwrap()));
+                net.add_synapses(Synapses::new(&format!("{}_syn", name), name, &target, SynapseModel::Delta { weight: 1.0 }));
+            }
+            net.run(10.0).unwrap();
+            for name in ["A_syn", "B_syn"] {
+                net.connect(name, "i != j", "0.2", "1").unwrap();
+            }
+            net.synapses.values().map(|syn| syn.connections.clone()).collect::<Vec<_>>()
+        };
+        let first = run(&["A", "B"]);
+        assert!(first.iter().all(|c| !c.is_empty()));
+        assert_eq!(run(&["B", "A"]), first);
+    }
 }
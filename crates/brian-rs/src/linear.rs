@@ -0,0 +1,300 @@
+//! Linear analysis of differential equations
+//!
+//! Exponential Euler needs each equation to be *conditionally linear*,
+//! `dx/dt = A + B*x` with `A` and `B` free of `x` (they may depend on other
+//! variables). Exact integration needs the whole system to be linear with
+//! constant coefficients, `dX/dt = M*X + b(t)`; its per-step propagator is
+//! obtained from a matrix exponential.
+
+use crate::expr::{BinOp, Expr, Program};
+use oldies_core::Rng;
+
+/// Affine decomposition of an expression: `offset + sum(coeffs[k] * vars[k])`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Affine {
+    pub offset: Expr,
+    pub coeffs: Vec<Expr>,
+}
+
+/// Decompose `expr` as an affine function of `vars`, or `None` if it is not
+/// linear in them
+pub fn affine(expr: &Expr, vars: &[&str]) -> Option<Affine> {
+    if !depends_on(expr, vars) {
+        return Some(Affine {
+            offset: expr.clone(),
+            coeffs: vec![zero(); vars.len()],
+        });
+    }
+
+    match expr {
+        Expr::Name(name) => {
+            let mut coeffs = vec![zero(); vars.len()];
+            let k = vars.iter().position(|v| v == name)?;
+            coeffs[k] = Expr::Number(1.0);
+            Some(Affine { offset: zero(), coeffs })
+        }
+        Expr::Neg(e) => {
+            let a = affine(e, vars)?;
+            Some(a.map(neg))
+        }
+        Expr::Binary(BinOp::Add, a, b) | Expr::Binary(BinOp::Sub, a, b) => {
+            let subtract = matches!(expr, Expr::Binary(BinOp::Sub, ..));
+            let la = affine(a, vars)?;
+            let lb = affine(b, vars)?;
+            let combine = |x: Expr, y: Expr| if subtract { sub(x, y) } else { add(x, y) };
+            Some(Affine {
+                offset: combine(la.offset, lb.offset),
+                coeffs: la.coeffs.into_iter().zip(lb.coeffs).map(|(x, y)| combine(x, y)).collect(),
+            })
+        }
+        Expr::Binary(BinOp::Mul, a, b) => {
+            if !depends_on(a, vars) {
+                Some(affine(b, vars)?.map(|e| mul((**a).clone(), e)))
+            } else if !depends_on(b, vars) {
+                Some(affine(a, vars)?.map(|e| mul(e, (**b).clone())))
+            } else {
+                None
+            }
+        }
+        Expr::Binary(BinOp::Div, a, b) if !depends_on(b, vars) => {
+            Some(affine(a, vars)?.map(|e| div(e, (**b).clone())))
+        }
+        // Functions, powers and logic of the variables are nonlinear
+        _ => None,
+    }
+}
+
+impl Affine {
+    fn map(self, f: impl Fn(Expr) -> Expr) -> Affine {
+        Affine {
+            offset: f(self.offset),
+            coeffs: self.coeffs.into_iter().map(f).collect(),
+        }
+    }
+}
+
+fn depends_on(expr: &Expr, vars: &[&str]) -> bool {
+    expr.names().iter().any(|n| vars.contains(&n.as_str()))
+}
+
+fn zero() -> Expr {
+    Expr::Number(0.0)
+}
+
+fn is_number(e: &Expr, x: f64) -> bool {
+    matches!(e, Expr::Number(v) if *v == x)
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    if is_number(&a, 0.0) {
+        b
+    } else if is_number(&b, 0.0) {
+        a
+    } else {
+        Expr::Binary(BinOp::Add, Box::new(a), Box::new(b))
+    }
+}
+
+fn sub(a: Expr, b: Expr) -> Expr {
+    if is_number(&b, 0.0) {
+        a
+    } else if is_number(&a, 0.0) {
+        neg(b)
+    } else {
+        Expr::Binary(BinOp::Sub, Box::new(a), Box::new(b))
+    }
+}
+
+fn mul(a: Expr, b: Expr) -> Expr {
+    if is_number(&a, 0.0) || is_number(&b, 0.0) {
+        zero()
+    } else if is_number(&a, 1.0) {
+        b
+    } else if is_number(&b, 1.0) {
+        a
+    } else {
+        Expr::Binary(BinOp::Mul, Box::new(a), Box::new(b))
+    }
+}
+
+fn div(a: Expr, b: Expr) -> Expr {
+    if is_number(&a, 0.0) {
+        zero()
+    } else {
+        Expr::Binary(BinOp::Div, Box::new(a), Box::new(b))
+    }
+}
+
+fn neg(a: Expr) -> Expr {
+    match a {
+        Expr::Number(x) => Expr::Number(-x),
+        Expr::Neg(inner) => *inner,
+        other => Expr::Neg(Box::new(other)),
+    }
+}
+
+// ============================================================================
+// EXACT INTEGRATION
+// ============================================================================
+
+/// Linear system `dX/dt = M*X + b` with constant `M`
+#[derive(Debug, Clone)]
+pub struct LinearSystem {
+    n: usize,
+    /// Row-major coefficient matrix
+    matrix: Vec<f64>,
+    /// Inhomogeneous term of each equation (may depend on time and input)
+    pub offsets: Vec<Program>,
+}
+
+/// One-step solution operator: `X(t+dt) = transition*X(t) + input*b`
+#[derive(Debug, Clone)]
+pub struct Propagator {
+    n: usize,
+    transition: Vec<f64>,
+    input: Vec<f64>,
+}
+
+impl LinearSystem {
+    /// Build from constant coefficients (row-major) and compiled offsets
+    pub fn new(matrix: Vec<f64>, offsets: Vec<Program>) -> Self {
+        let n = offsets.len();
+        assert_eq!(matrix.len(), n * n);
+        Self { n, matrix, offsets }
+    }
+
+    /// Propagator over a step of `dt`, holding `b` constant over the step
+    pub fn propagator(&self, dt: f64) -> Propagator {
+        // exp([[M, 1], [0, 0]] * dt) = [[exp(M dt), int_0^dt exp(M s) ds], [0, 1]]
+        let n = self.n;
+        let m = 2 * n;
+        let mut augmented = vec![0.0; m * m];
+        for r in 0..n {
+            for c in 0..n {
+                augmented[r * m + c] = self.matrix[r * n + c] * dt;
+            }
+            augmented[r * m + n + r] = dt;
+        }
+        let exp = expm(&augmented, m);
+
+        let mut transition = vec![0.0; n * n];
+        let mut input = vec![0.0; n * n];
+        for r in 0..n {
+            for c in 0..n {
+                transition[r * n + c] = exp[r * m + c];
+                input[r * n + c] = exp[r * m + n + c];
+            }
+        }
+        Propagator { n, transition, input }
+    }
+
+    /// Evaluate the offsets into `out`
+    pub fn eval_offsets(&self, values: &[f64], out: &mut [f64], rng: &mut Rng) {
+        for (o, program) in out.iter_mut().zip(&self.offsets) {
+            *o = program.eval(values, rng);
+        }
+    }
+}
+
+impl Propagator {
+    /// Advance `y` given the offsets `b`
+    pub fn apply(&self, y: &[f64], b: &[f64], out: &mut [f64]) {
+        let n = self.n;
+        for (r, o) in out.iter_mut().enumerate().take(n) {
+            let mut acc = 0.0;
+            for c in 0..n {
+                acc += self.transition[r * n + c] * y[c] + self.input[r * n + c] * b[c];
+            }
+            *o = acc;
+        }
+    }
+}
+
+/// Matrix exponential of a row-major `n`x`n` matrix (scaling and squaring
+/// with a truncated Taylor series)
+pub fn expm(a: &[f64], n: usize) -> Vec<f64> {
+    let norm = (0..n)
+        .map(|r| a[r * n..(r + 1) * n].iter().map(|x| x.abs()).sum::<f64>())
+        .fold(0.0, f64::max);
+    let squarings = if norm > 0.5 { (norm / 0.5).log2().ceil() as u32 } else { 0 };
+    let scale = 0.5f64.powi(squarings as i32);
+    let scaled: Vec<f64> = a.iter().map(|x| x * scale).collect();
+
+    let mut result = identity(n);
+    let mut term = identity(n);
+    for k in 1..=18 {
+        term = matmul(&term, &scaled, n);
+        for x in term.iter_mut() {
+            *x /= k as f64;
+        }
+        for (r, t) in result.iter_mut().zip(&term) {
+            *r += t;
+        }
+    }
+
+    for _ in 0..squarings {
+        result = matmul(&result, &result, n);
+    }
+    result
+}
+
+fn identity(n: usize) -> Vec<f64> {
+    let mut out = vec![0.0; n * n];
+    for k in 0..n {
+        out[k * n + k] = 1.0;
+    }
+    out
+}
+
+fn matmul(a: &[f64], b: &[f64], n: usize) -> Vec<f64> {
+    let mut out = vec![0.0; n * n];
+    for r in 0..n {
+        for k in 0..n {
+            let x = a[r * n + k];
+            if x != 0.0 {
+                for c in 0..n {
+                    out[r * n + c] += x * b[k * n + c];
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::parse_expression;
+
+    #[test]
+    fn test_affine_decomposition() {
+        let e = parse_expression("(ge * (E_e - v) + (E_l - v)) / tau").unwrap();
+        let a = affine(&e, &["v"]).unwrap();
+        assert_eq!(a.offset.to_string(), "(((ge * E_e) + E_l) / tau)");
+        assert_eq!(a.coeffs[0].to_string(), "(((ge * -1.0) + -1.0) / tau)");
+
+        // Conditionally linear in v only
+        assert!(affine(&e, &["v", "ge"]).is_none());
+        assert!(affine(&parse_expression("exp(v) - w").unwrap(), &["v"]).is_none());
+        assert!(affine(&parse_expression("exp(v) - w").unwrap(), &["w"]).is_some());
+    }
+
+    #[test]
+    fn test_expm() {
+        // Rotation generator
+        let t = 2.0;
+        let e = expm(&[0.0, t, -t, 0.0], 2);
+        let expected = [t.cos(), t.sin(), -t.sin(), t.cos()];
+        for (x, y) in e.iter().zip(expected) {
+            assert!((x - y).abs() < 1e-12);
+        }
+
+        // Decay with constant input: x' = -x/tau + b
+        let tau = 10.0;
+        let p = LinearSystem { n: 1, matrix: vec![-1.0 / tau], offsets: vec![] }.propagator(5.0);
+        let mut out = [0.0];
+        p.apply(&[1.0], &[2.0], &mut out);
+        let decay = (-5.0 / tau).exp();
+        assert!((out[0] - (decay + 2.0 * tau * (1.0 - decay))).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,188 @@
+//! Synaptic pathways: `on_pre` and `on_post` statements
+//!
+//! Statements are run once per synapse when a spike arrives (`on_pre`) or
+//! when the postsynaptic neuron fires (`on_post`). They see:
+//!
+//! - the synapse's own variables, including the weight `w`
+//! - presynaptic variables with a `_pre` suffix (read-only)
+//! - postsynaptic variables with a `_post` suffix, or unsuffixed
+//! - the synapse parameters, `t`, `dt`, and the indices `i` (pre) and `j` (post)
+
+use crate::expr::{self, CompiledStatement, SymbolTable};
+use oldies_core::Rng;
+use crate::{BrianError, Quantity, Result};
+use ndarray::Array1;
+use std::collections::HashMap;
+
+/// Name of the synaptic weight in pathway statements
+pub const WEIGHT_SYMBOL: &str = "w";
+
+/// Where the value of a slot comes from
+#[derive(Debug, Clone, PartialEq)]
+enum Binding {
+    Synaptic(String),
+    Weight,
+    Pre(String),
+    Post(String),
+    Time,
+    PreIndex,
+    PostIndex,
+}
+
+/// Names visible to pathway statements
+pub struct Scope<'a> {
+    /// Per-synapse state variables
+    pub synaptic: Vec<&'a str>,
+    /// Variables of the source group
+    pub pre: Vec<&'a str>,
+    /// Variables of the target group
+    pub post: Vec<&'a str>,
+    pub parameters: &'a HashMap<String, Quantity>,
+}
+
+/// Compiled statement list
+#[derive(Debug, Clone)]
+pub struct Pathway {
+    code: Vec<CompiledStatement>,
+    /// Initial slot values (parameters, `dt`)
+    constants: Vec<f64>,
+    /// Slots loaded before each run
+    inputs: Vec<(usize, Binding)>,
+    /// Slots written back after each run
+    outputs: Vec<(usize, Binding)>,
+}
+
+/// State a pathway reads and writes for one synapse
+pub struct PathwayState<'a> {
+    pub synaptic: Option<&'a mut HashMap<String, Array1<f64>>>,
+    pub weights: &'a mut [f64],
+    /// Source group state, or `None` to read `_pre` variables from the target
+    /// (recurrent synapses)
+    pub pre: Option<&'a HashMap<String, Array1<f64>>>,
+    pub post: &'a mut HashMap<String, Array1<f64>>,
+}
+
+impl Pathway {
+    /// Compile a list of statement blocks
+    pub fn compile(code: &[String], scope: &Scope, dt: f64) -> Result<Self> {
+        let mut symbols = SymbolTable::new();
+        let mut bindings = vec![];
+
+        for name in &scope.synaptic {
+            bindings.push((symbols.add(name), Binding::Synaptic(name.to_string())));
+        }
+        if symbols.resolve(WEIGHT_SYMBOL).is_none() {
+            bindings.push((symbols.add(WEIGHT_SYMBOL), Binding::Weight));
+        }
+        for name in &scope.post {
+            let slot = symbols.add(&format!("{}_post", name));
+            bindings.push((slot, Binding::Post(name.to_string())));
+            if symbols.resolve(name).is_none() {
+                symbols.alias(name, slot);
+            }
+        }
+        for name in &scope.pre {
+            bindings.push((symbols.add(&format!("{}_pre", name)), Binding::Pre(name.to_string())));
+        }
+
+        let mut parameters = vec![];
+        for (name, quantity) in scope.parameters {
+            if symbols.resolve(name).is_none() {
+                parameters.push((symbols.add(name), quantity.to_internal()));
+            }
+        }
+        bindings.push((symbols.add("t"), Binding::Time));
+        let dt_slot = symbols.add("dt");
+        bindings.push((symbols.add("i"), Binding::PreIndex));
+        bindings.push((symbols.add("j"), Binding::PostIndex));
+
+        let mut constants = vec![0.0; symbols.len()];
+        for (slot, value) in parameters {
+            constants[slot] = value;
+        }
+        constants[dt_slot] = dt;
+
+        let code: Vec<CompiledStatement> = code.iter()
+            .map(|block| expr::compile_statements(block, &symbols))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let mut outputs = vec![];
+        for stmt in &code {
+            let binding = bindings.iter()
+                .find(|(slot, _)| *slot == stmt.target)
+                .map(|(_, b)| b.clone());
+            match binding {
+                Some(b @ (Binding::Synaptic(_) | Binding::Weight | Binding::Post(_))) => {
+                    if !outputs.iter().any(|(slot, _)| *slot == stmt.target) {
+                        outputs.push((stmt.target, b));
+                    }
+                }
+                _ => {
+                    return Err(BrianError::EquationError(format!(
+                        "Cannot assign to read-only variable '{}'",
+                        symbols.names()[stmt.target]
+                    )));
+                }
+            }
+        }
+
+        // Only load what the statements can observe
+        let used: Vec<usize> = code.iter()
+            .flat_map(|stmt| std::iter::once(stmt.target).chain(stmt.program.slots()))
+            .collect();
+        let inputs = bindings.into_iter()
+            .filter(|(slot, _)| used.contains(slot))
+            .collect();
+
+        Ok(Self { code, constants, inputs, outputs })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Run the statements for synapse `syn` connecting `i` to `j`
+    pub fn run(&self, syn: usize, (i, j): (usize, usize), t: f64, state: &mut PathwayState, rng: &mut Rng) {
+        let mut values = self.constants.clone();
+        for (slot, binding) in &self.inputs {
+            values[*slot] = match binding {
+                Binding::Synaptic(name) => state.synaptic.as_ref()
+                    .and_then(|s| s.get(name))
+                    .map_or(0.0, |column| column[syn]),
+                Binding::Weight => state.weights[syn],
+                Binding::Pre(name) => state.pre.unwrap_or(state.post)
+                    .get(name)
+                    .map_or(0.0, |column| column[i]),
+                Binding::Post(name) => state.post.get(name).map_or(0.0, |column| column[j]),
+                Binding::Time => t,
+                Binding::PreIndex => i as f64,
+                Binding::PostIndex => j as f64,
+            };
+        }
+
+        for stmt in &self.code {
+            stmt.execute(&mut values, rng);
+        }
+
+        for (slot, binding) in &self.outputs {
+            let value = values[*slot];
+            match binding {
+                Binding::Synaptic(name) => {
+                    if let Some(column) = state.synaptic.as_mut().and_then(|s| s.get_mut(name)) {
+                        column[syn] = value;
+                    }
+                }
+                Binding::Weight => state.weights[syn] = value,
+                Binding::Post(name) => {
+                    if let Some(column) = state.post.get_mut(name) {
+                        column[j] = value;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
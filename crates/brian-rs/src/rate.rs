@@ -0,0 +1,201 @@
+//! Rate-based populations
+//!
+//! A [`RateGroup`] integrates equations like a [`NeuronGroup`], but its
+//! output is a continuous variable (a firing rate or activity) instead of
+//! spikes. [`RateConnection`]s couple populations Wilson-Cowan style: every
+//! step, before integration, the target variable of each connection is set
+//! to the weighted sum of the presynaptic rates, like a Brian synaptic
+//! variable flagged `(summed)`:
+//!
+//! ```text
+//! tau * dr/dt = -r + F(I_rec + I_ext)
+//! I_rec[j] = sum(w[i, j] * r_pre[i])
+//! ```
+//!
+//! Connections may target the rate groups and the spiking groups of the
+//! same network, so mean-field and spiking models can drive each other's
+//! inputs. Several connections writing the same variable are added up.
+
+use oldies_core::Rng;
+use crate::{BrianError, NeuronEquations, NeuronGroup, Result};
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+
+/// Population whose output is the continuous variable `rate`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateGroup {
+    /// Equations and state, integrated without threshold or reset
+    pub group: NeuronGroup,
+    /// Variable read by outgoing connections
+    pub rate: String,
+}
+
+impl RateGroup {
+    pub fn new(name: &str, n: usize, equations: NeuronEquations, rate: &str) -> Self {
+        Self {
+            group: NeuronGroup::new(name, n, equations),
+            rate: rate.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.group.name
+    }
+
+    pub fn n(&self) -> usize {
+        self.group.n
+    }
+
+    /// Current output of each unit
+    pub fn rates(&self) -> Result<&Array1<f64>> {
+        self.group.state.get(&self.rate).ok_or_else(|| {
+            BrianError::SimulationError(format!(
+                "RateGroup '{}': unknown rate variable '{}'",
+                self.group.name, self.rate
+            ))
+        })
+    }
+
+    /// Integrate the equations over one step
+    pub fn update(&mut self, t: f64, dt: f64, rng: &mut Rng) -> Result<()> {
+        if self.group.equations.threshold.is_some() {
+            return Err(BrianError::SimulationError(format!(
+                "RateGroup '{}' cannot have a threshold",
+                self.group.name
+            )));
+        }
+        self.group.update(t, dt, rng)?;
+        Ok(())
+    }
+}
+
+/// Weighted coupling from the rates of a [`RateGroup`] into a variable of
+/// another group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateConnection {
+    pub name: String,
+    /// Source rate group
+    pub source: String,
+    /// Target rate or neuron group
+    pub target: String,
+    /// Per-neuron variable of the target overwritten with the summed input
+    pub target_var: String,
+    /// Sparse connectivity: (source_idx, target_idx)
+    pub connections: Vec<(usize, usize)>,
+    /// Weights (same length as connections), in target units per rate unit
+    pub weights: Vec<f64>,
+}
+
+impl RateConnection {
+    pub fn new(name: &str, source: &str, target: &str, target_var: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            source: source.to_string(),
+            target: target.to_string(),
+            target_var: target_var.to_string(),
+            connections: vec![],
+            weights: vec![],
+        }
+    }
+
+    /// Connect all source units to all target units
+    pub fn connect_all_to_all(&mut self, n_source: usize, n_target: usize, weight: f64) {
+        for i in 0..n_source {
+            for j in 0..n_target {
+                self.connections.push((i, j));
+                self.weights.push(weight);
+            }
+        }
+    }
+
+    /// Connect unit i to unit i
+    pub fn connect_one_to_one(&mut self, n: usize, weight: f64) {
+        for i in 0..n {
+            self.connections.push((i, i));
+            self.weights.push(weight);
+        }
+    }
+
+    /// Add the weighted source rates to `input`
+    pub fn accumulate(&self, rates: &Array1<f64>, input: &mut Array1<f64>) -> Result<()> {
+        for (&(i, j), &w) in self.connections.iter().zip(&self.weights) {
+            if i >= rates.len() || j >= input.len() {
+                return Err(BrianError::SimulationError(format!(
+                    "RateConnection '{}': connection ({}, {}) out of range",
+                    self.name, i, j
+                )));
+            }
+            input[j] += w * rates[i];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_equations, Network, StateMonitor};
+
+    /// Wilson-Cowan excitatory and inhibitory populations
+    fn wilson_cowan(net: &mut Network, w_ee: f64, w_ie: f64) {
+        let eqs = |tau: &str| {
+            parse_equations(&format!(
+                "dr/dt = (-r + 1 / (1 + exp(-(I_rec + I_ext - 4)))) / ({}*ms) : 1\n\
+                 I_rec : 1\n\
+                 I_ext : 1",
+                tau
+            ))
+            .unwrap()
+        };
+        net.add_rate_group(RateGroup::new("E", 1, eqs("10"), "r"));
+        net.add_rate_group(RateGroup::new("I", 1, eqs("20"), "r"));
+        for (name, source, target, w) in [
+            ("EE", "E", "E", w_ee),
+            ("EI", "E", "I", 10.0),
+            ("IE", "I", "E", -w_ie),
+        ] {
+            let mut conn = RateConnection::new(name, source, target, "I_rec");
+            conn.connect_all_to_all(1, 1, w);
+            net.add_rate_connection(conn);
+        }
+    }
+
+    #[test]
+    fn test_wilson_cowan_fixed_point() {
+        let mut net = Network::new(0.1);
+        wilson_cowan(&mut net, 12.0, 10.0);
+        net.add_state_monitor(StateMonitor::new("E", &["r"], &[0], 1.0));
+        net.run(500.0).unwrap();
+
+        // At rest r = F(sum w * r + I_ext) for both populations
+        let f = |x: f64| 1.0 / (1.0 + (-(x - 4.0)).exp());
+        let e = net.rate_groups["E"].rates().unwrap()[0];
+        let i = net.rate_groups["I"].rates().unwrap()[0];
+        assert!((e - f(12.0 * e - 10.0 * i)).abs() < 1e-6, "e = {}", e);
+        assert!((i - f(10.0 * e)).abs() < 1e-6, "i = {}", i);
+        assert_eq!(net.state_monitors["E_state"].data["r"].0.len(), 500);
+    }
+
+    #[test]
+    fn test_rates_drive_spiking_group() {
+        let mut net = Network::new(0.1);
+        wilson_cowan(&mut net, 12.0, 10.0);
+        net.rate_groups.get_mut("E").unwrap().group.state.get_mut("I_ext").unwrap().fill(2.0);
+
+        let mut eqs = parse_equations("dv/dt = (drive - v) / (10*ms) : 1\ndrive : 1").unwrap();
+        eqs.threshold = Some(crate::ThresholdCondition { condition: "v > 1".into() });
+        eqs.reset = Some(crate::ResetEquations { equations: vec!["v = 0".into()] });
+        net.add_neuron_group(NeuronGroup::new("G", 1, eqs));
+        let mut conn = RateConnection::new("EG", "E", "G", "drive");
+        conn.connect_all_to_all(1, 1, 5.0);
+        net.add_rate_connection(conn);
+        net.add_spike_monitor(crate::SpikeMonitor::new("G", 1));
+        net.run(200.0).unwrap();
+        assert!(net.spike_monitors["G"].counts[0] > 0);
+
+        let mut conn = RateConnection::new("GE", "G", "E", "I_rec");
+        conn.connect_all_to_all(1, 1, 1.0);
+        net.add_rate_connection(conn);
+        assert!(net.run(0.1).is_err());
+    }
+}
@@ -0,0 +1,952 @@
+//! Importer for Brian2 Python scripts
+//!
+//! Handles the declarative subset most published models are written in:
+//!
+//! - constants (`tau = 10*ms`) and equation strings (`eqs = '''...'''`)
+//! - `NeuronGroup`, `PoissonGroup`, `SpikeGeneratorGroup` and `Synapses`, each
+//!   optionally on its own clock (`dt=...`)
+//! - `S.connect(...)` with `condition`, `p`, `n`, `j='i'` or index lists
+//! - attribute assignments (`G.v = -70*mV`, `G.v = 'rand()*10*mV'`, `S.w = ...`,
+//!   `S.delay = ...`, `defaultclock.dt = ...`)
+//! - `PoissonInput`, `SpikeMonitor`, `StateMonitor`, `PopulationRateMonitor`
+//! - `run(...)`, `seed(...)`
+//!
+//! Imports, `start_scope()` and plotting calls are skipped. Anything else
+//! (loops, function definitions, indexing) is reported as an error rather
+//! than silently ignored. Constants are resolved when the script ends, so
+//! like in Brian they may be defined after the groups that use them.
+
+use crate::expr::{self, Program, SymbolTable};
+use crate::units::{self, Dim, DimensionTable};
+use crate::{
+    parse_equations, BrianError, IntegrationMethod, Network, NeuronGroup, PoissonGroup,
+    PoissonInput, PopulationRateMonitor, Quantity, RefractorySpec, ResetEquations, Result, SpikeGeneratorGroup,
+    SpikeMonitor, StateMonitor, SynapseModel, Synapses, ThresholdCondition, Unit,
+};
+use ndarray::Array1;
+use std::collections::HashMap;
+
+/// Calls that only produce output and are skipped
+const IGNORED_CALLS: &[&str] = &[
+    "start_scope", "print", "plot", "show", "figure", "subplot", "xlabel", "ylabel", "title",
+    "legend", "xlim", "ylim", "axis", "hist", "savefig", "tight_layout",
+];
+
+/// Python object a script variable refers to
+#[derive(Debug, Clone)]
+pub enum ScriptObject {
+    Constant(Quantity),
+    Text(String),
+    NeuronGroup(String),
+    PoissonGroup(String),
+    SpikeGeneratorGroup(String),
+    Synapses(String),
+    /// Spike monitor of the named source
+    SpikeMonitor(String),
+    /// State monitor of the named source
+    StateMonitor(String),
+    /// Population rate monitor of the named source
+    RateMonitor(String),
+    /// Poisson input to the named group
+    PoissonInput(String),
+}
+
+/// Network built from a script, with the runs it requested
+#[derive(Debug)]
+pub struct Script {
+    pub network: Network,
+    /// Script variables
+    pub objects: HashMap<String, ScriptObject>,
+    /// Durations passed to `run` (ms)
+    pub runs: Vec<f64>,
+}
+
+impl Script {
+    /// Run the network for each requested duration in turn
+    pub fn run(&mut self) -> Result<()> {
+        for &duration in &self.runs {
+            self.network.run(duration)?;
+        }
+        Ok(())
+    }
+}
+
+/// Translate a Brian2 script into a network
+pub fn import_script(src: &str) -> Result<Script> {
+    let mut importer = Importer {
+        network: Network::new(0.1),
+        objects: HashMap::new(),
+        runs: vec![],
+        delays: HashMap::new(),
+    };
+    for (line, statement) in logical_lines(src)? {
+        importer.statement(&statement)
+            .map_err(|e| BrianError::ParseError(format!("line {}: {}", line, e)))?;
+    }
+    importer.finish()
+}
+
+struct Importer {
+    network: Network,
+    objects: HashMap<String, ScriptObject>,
+    runs: Vec<f64>,
+    /// `delay` given to the `Synapses` constructor, applied to new connections
+    delays: HashMap<String, f64>,
+}
+
+impl Importer {
+    fn statement(&mut self, statement: &str) -> Result<()> {
+        let first = statement.split_whitespace().next().unwrap_or("");
+        if matches!(first, "from" | "import") || statement.starts_with("prefs.") {
+            return Ok(());
+        }
+
+        if let Some((lhs, rhs)) = split_assignment(statement) {
+            return match lhs.split_once('.') {
+                Some((object, attribute)) => self.set_attribute(object, attribute, rhs),
+                None if is_identifier(lhs) => self.assign(lhs, rhs),
+                None => Err(unsupported(statement)),
+            };
+        }
+
+        let (callee, args) = parse_call(statement).ok_or_else(|| unsupported(statement))?;
+        let (positional, keywords) = parse_args(args)?;
+        let arg = |k: usize, key: &str| keywords.get(key).or(positional.get(k)).map(String::as_str);
+        match callee {
+            "run" => {
+                let duration = self.number(arg(0, "duration").ok_or_else(|| missing("duration"))?)?;
+                self.runs.push(duration);
+                Ok(())
+            }
+            "seed" => {
+                let seed = self.number(arg(0, "seed").ok_or_else(|| missing("seed"))?)?;
+                self.network.seed(seed as u64);
+                Ok(())
+            }
+            name if IGNORED_CALLS.contains(&name) => Ok(()),
+            name => match name.split_once('.') {
+                Some((object, "connect")) => self.connect(object, &positional, &keywords),
+                _ => Err(unsupported(statement)),
+            },
+        }
+    }
+
+    /// `name = value`
+    fn assign(&mut self, name: &str, rhs: &str) -> Result<()> {
+        let object = if let Some(text) = self.text(rhs) {
+            ScriptObject::Text(text)
+        } else if let Some((callee, args)) = parse_call(rhs) {
+            let (positional, keywords) = parse_args(args)?;
+            self.construct(name, callee, &positional, &keywords)?
+        } else {
+            ScriptObject::Constant(self.quantity(rhs)?)
+        };
+        self.objects.insert(name.to_string(), object);
+        Ok(())
+    }
+
+    /// Create the object for `name = callee(args)`
+    fn construct(
+        &mut self,
+        name: &str,
+        callee: &str,
+        positional: &[String],
+        keywords: &HashMap<String, String>,
+    ) -> Result<ScriptObject> {
+        let arg = |k: usize, key: &str| keywords.get(key).or(positional.get(k)).map(String::as_str);
+        let name = match arg(usize::MAX, "name") {
+            Some(text) => self.text(text).ok_or_else(|| invalid("name", text))?,
+            None => name.to_string(),
+        };
+        // Objects given `dt=` run on their own clock
+        let clock = match keywords.get("dt") {
+            Some(dt) => Some(self.number(dt)?),
+            None => None,
+        };
+
+        match callee {
+            "NeuronGroup" => {
+                let n = self.count(arg(0, "N").ok_or_else(|| missing("N"))?)?;
+                let model = arg(1, "model").ok_or_else(|| missing("model"))?;
+                let mut equations = parse_equations(&self.text(model).ok_or_else(|| invalid("model", model))?)?;
+                if let Some(threshold) = arg(usize::MAX, "threshold") {
+                    let condition = self.text(threshold).ok_or_else(|| invalid("threshold", threshold))?;
+                    equations.threshold = Some(ThresholdCondition { condition });
+                }
+                if let Some(reset) = arg(usize::MAX, "reset") {
+                    let code = self.text(reset).ok_or_else(|| invalid("reset", reset))?;
+                    equations.reset = Some(ResetEquations { equations: vec![code] });
+                }
+                if let Some(refractory) = arg(usize::MAX, "refractory") {
+                    equations.refractory = Some(match self.text(refractory) {
+                        Some(condition) => RefractorySpec::Condition(condition),
+                        None => RefractorySpec::Duration(self.quantity(refractory)?),
+                    });
+                }
+                let mut group = NeuronGroup::new(&name, n, equations);
+                if let Some(method) = arg(usize::MAX, "method") {
+                    let method = integration_method(&self.text(method).ok_or_else(|| invalid("method", method))?)?;
+                    group.method = method;
+                    for eq in &mut group.equations.differential {
+                        eq.method = method;
+                    }
+                }
+                group.dt = clock;
+                self.network.add_neuron_group(group);
+                Ok(ScriptObject::NeuronGroup(name))
+            }
+            "PoissonGroup" => {
+                let n = self.count(arg(0, "N").ok_or_else(|| missing("N"))?)?;
+                let rates = arg(1, "rates").ok_or_else(|| missing("rates"))?;
+                let rate = self.quantity(rates)?.to_si();
+                let mut group = PoissonGroup::new(&name, n, rate);
+                group.dt = clock;
+                self.network.add_poisson_group(group);
+                Ok(ScriptObject::PoissonGroup(name))
+            }
+            "SpikeGeneratorGroup" => {
+                let n = self.count(arg(0, "N").ok_or_else(|| missing("N"))?)?;
+                let indices = self.list(arg(1, "indices").ok_or_else(|| missing("indices"))?)?;
+                let times = self.list(arg(2, "times").ok_or_else(|| missing("times"))?)?;
+                if indices.len() != times.len() {
+                    return Err(BrianError::ParseError(format!(
+                        "{} spike indices but {} spike times",
+                        indices.len(),
+                        times.len()
+                    )));
+                }
+                let mut group = SpikeGeneratorGroup::new(&name, n);
+                let indices: Vec<usize> = indices.iter().map(|&i| i as usize).collect();
+                group.add_spikes(&indices, &times);
+                group.dt = clock;
+                self.network.add_spike_generator(group);
+                Ok(ScriptObject::SpikeGeneratorGroup(name))
+            }
+            "Synapses" => {
+                let source = self.group(arg(0, "source").ok_or_else(|| missing("source"))?)?;
+                let target = match arg(1, "target") {
+                    Some(target) if self.text(target).is_none() => self.group(target)?,
+                    _ => source.clone(),
+                };
+                // The target may be omitted, shifting the model to position 1
+                let model_pos = if positional.get(1).is_some_and(|t| self.text(t).is_some()) { 1 } else { 2 };
+
+                let mut syn = Synapses::new(&name, &source, &target, SynapseModel::Delta { weight: 0.0 });
+                if let Some(model) = arg(model_pos, "model") {
+                    let equations = parse_equations(&self.text(model).ok_or_else(|| invalid("model", model))?)?;
+                    if !equations.differential.is_empty() || !equations.algebraic.is_empty() {
+                        syn.set_equations(equations);
+                    }
+                }
+                // `pre`/`post` are the older spellings
+                for key in ["on_pre", "pre"] {
+                    if let Some(code) = keywords.get(key) {
+                        syn.on_pre.push(self.text(code).ok_or_else(|| invalid(key, code))?);
+                    }
+                }
+                for key in ["on_post", "post"] {
+                    if let Some(code) = keywords.get(key) {
+                        syn.on_post.push(self.text(code).ok_or_else(|| invalid(key, code))?);
+                    }
+                }
+                if let Some(delay) = keywords.get("delay") {
+                    let delay = self.number(delay)?;
+                    self.delays.insert(name.clone(), delay);
+                }
+                syn.dt = clock;
+                self.network.add_synapses(syn);
+                Ok(ScriptObject::Synapses(name))
+            }
+            "PoissonInput" => {
+                let target = self.group(arg(0, "target").ok_or_else(|| missing("target"))?)?;
+                let variable = arg(1, "target_var").ok_or_else(|| missing("target_var"))?;
+                let variable = self.text(variable).ok_or_else(|| invalid("target_var", variable))?;
+                let n = self.count(arg(2, "N").ok_or_else(|| missing("N"))?)?;
+                let rate = self.quantity(arg(3, "rate").ok_or_else(|| missing("rate"))?)?.to_si();
+                let weight = self.number(arg(4, "weight").ok_or_else(|| missing("weight"))?)?;
+                self.network.add_poisson_input(PoissonInput::new(&target, &variable, n, rate, weight));
+                Ok(ScriptObject::PoissonInput(target))
+            }
+            "SpikeMonitor" => {
+                let source = self.group(arg(0, "source").ok_or_else(|| missing("source"))?)?;
+                let n = self.size(&source)?;
+                self.network.add_spike_monitor(SpikeMonitor::new(&source, n));
+                Ok(ScriptObject::SpikeMonitor(source))
+            }
+            "StateMonitor" => {
+                let source = self.group(arg(0, "source").ok_or_else(|| missing("source"))?)?;
+                let variables = arg(1, "variables").ok_or_else(|| missing("variables"))?;
+                let variables: Vec<String> = match self.text(variables) {
+                    Some(var) => vec![var],
+                    None => list_items(variables)
+                        .ok_or_else(|| invalid("variables", variables))?
+                        .iter()
+                        .map(|v| self.text(v).ok_or_else(|| invalid("variables", v)))
+                        .collect::<Result<_>>()?,
+                };
+                let n = self.size(&source)?;
+                let indices: Vec<usize> = match arg(2, "record") {
+                    None | Some("True") => (0..n).collect(),
+                    Some("False") => vec![],
+                    Some(record) if list_items(record).is_some() => {
+                        self.list(record)?.iter().map(|&i| i as usize).collect()
+                    }
+                    Some(record) => vec![self.count(record)?],
+                };
+                let dt = clock.unwrap_or(self.network.dt);
+                let variables: Vec<&str> = variables.iter().map(String::as_str).collect();
+                self.network.add_state_monitor(StateMonitor::new(&source, &variables, &indices, dt));
+                Ok(ScriptObject::StateMonitor(source))
+            }
+            "PopulationRateMonitor" => {
+                let source = self.group(arg(0, "source").ok_or_else(|| missing("source"))?)?;
+                let n = self.size(&source)?;
+                let bin = self.network.dt;
+                self.network.add_rate_monitor(PopulationRateMonitor::new(&source, n, bin));
+                Ok(ScriptObject::RateMonitor(source))
+            }
+            "Equations" => {
+                let text = arg(0, "eqs").ok_or_else(|| missing("eqs"))?;
+                Ok(ScriptObject::Text(self.text(text).ok_or_else(|| invalid("eqs", text))?))
+            }
+            other => Err(BrianError::ParseError(format!("Unsupported constructor: {}", other))),
+        }
+    }
+
+    /// `S.connect(...)`
+    fn connect(&mut self, object: &str, positional: &[String], keywords: &HashMap<String, String>) -> Result<()> {
+        let name = match self.objects.get(object) {
+            Some(ScriptObject::Synapses(name)) => name.clone(),
+            _ => return Err(BrianError::ParseError(format!("'{}' is not a Synapses object", object))),
+        };
+        let arg = |k: usize, key: &str| keywords.get(key).or(positional.get(k)).map(String::as_str);
+        let start = self.network.synapses[&name].connections.len();
+
+        if let (Some(i), Some(j)) = (keywords.get("i"), keywords.get("j")) {
+            if list_items(i).is_some() || list_items(j).is_some() {
+                // Explicit pairs
+                let i = self.list(i)?;
+                let j = self.list(j)?;
+                if i.len() != j.len() {
+                    return Err(BrianError::ParseError("Index lists i and j differ in length".into()));
+                }
+                let syn = self.network.synapses.get_mut(&name).expect("registered above");
+                let weight = syn.model.weight();
+                for (&a, &b) in i.iter().zip(&j) {
+                    syn.connections.push((a as usize, b as usize));
+                    syn.weights.push(weight);
+                    syn.delays.push(0.0);
+                }
+            } else {
+                let condition = format!("i == {} and j == {}", self.number(i)?, self.number(j)?);
+                self.network.connect(&name, &condition, "", "")?;
+            }
+        } else {
+            let mut condition = match arg(0, "condition") {
+                Some(cond) => match self.text(cond) {
+                    Some(text) => text,
+                    None => cond.to_string(),
+                },
+                None => String::new(),
+            };
+            // `j='i'` style one-to-one mapping
+            if let Some(j) = keywords.get("j") {
+                let target = self.text(j).ok_or_else(|| invalid("j", j))?;
+                let mapping = format!("j == ({})", target);
+                condition = if condition.is_empty() { mapping } else { format!("({}) and {}", condition, mapping) };
+            }
+            let p = arg(usize::MAX, "p").map(|p| self.text(p).unwrap_or_else(|| p.to_string())).unwrap_or_default();
+            let n = arg(usize::MAX, "n").map(|n| self.text(n).unwrap_or_else(|| n.to_string())).unwrap_or_default();
+            self.network.connect(&name, &condition, &p, &n)?;
+        }
+
+        if let Some(&delay) = self.delays.get(&name) {
+            let syn = self.network.synapses.get_mut(&name).expect("registered above");
+            for d in &mut syn.delays[start..] {
+                *d = delay;
+            }
+        }
+        Ok(())
+    }
+
+    /// `object.attribute = value`
+    fn set_attribute(&mut self, object: &str, attribute: &str, rhs: &str) -> Result<()> {
+        if object == "defaultclock" && attribute == "dt" {
+            self.network.dt = self.number(rhs)?;
+            return Ok(());
+        }
+
+        match self.objects.get(object).cloned() {
+            Some(ScriptObject::NeuronGroup(name)) => {
+                let group = &self.network.neuron_groups[&name];
+                let n = group.n;
+                let mut columns: Vec<(String, Vec<f64>)> = group.state.iter()
+                    .map(|(var, column)| (var.clone(), column.to_vec()))
+                    .collect();
+                columns.push(("i".into(), (0..n).map(|i| i as f64).collect()));
+                columns.push(("N".into(), vec![n as f64; n]));
+                let values = Array1::from_vec(self.values(rhs, n, &columns)?);
+
+                let group = self.network.neuron_groups.get_mut(&name).expect("registered above");
+                if attribute == crate::INPUT_SYMBOL && !group.state.contains_key(attribute) {
+                    group.input = values;
+                    Ok(())
+                } else {
+                    group.set_initial(attribute, values)
+                }
+            }
+            Some(ScriptObject::PoissonGroup(name)) if attribute == "rates" => {
+                let n = self.network.poisson_groups[&name].n;
+                let columns = vec![("i".into(), (0..n).map(|i| i as f64).collect())];
+                // Rates are evaluated in the internal 1/ms
+                let rates = self.values(rhs, n, &columns)?;
+                self.network.poisson_groups.get_mut(&name).expect("registered above").rates =
+                    rates.iter().map(|r| r * 1e3).collect();
+                Ok(())
+            }
+            Some(ScriptObject::Synapses(name)) => {
+                let syn = &self.network.synapses[&name];
+                let n = syn.connections.len();
+                let (n_pre, n_post) = (self.size(&syn.source)?, self.size(&syn.target)?);
+                let mut columns: Vec<(String, Vec<f64>)> = vec![
+                    ("i".into(), syn.connections.iter().map(|&(i, _)| i as f64).collect()),
+                    ("j".into(), syn.connections.iter().map(|&(_, j)| j as f64).collect()),
+                    ("N_pre".into(), vec![n_pre as f64; n]),
+                    ("N_post".into(), vec![n_post as f64; n]),
+                    ("N".into(), vec![n as f64; n]),
+                ];
+                if let Some(dynamics) = &syn.dynamics {
+                    for (var, column) in &dynamics.state {
+                        let mut values = column.to_vec();
+                        values.resize(n, 0.0);
+                        columns.push((var.clone(), values));
+                    }
+                }
+                let values = self.values(rhs, n, &columns)?;
+
+                let syn = self.network.synapses.get_mut(&name).expect("registered above");
+                match attribute {
+                    crate::pathway::WEIGHT_SYMBOL => syn.weights = values,
+                    "delay" => syn.delays = values,
+                    var => match &mut syn.dynamics {
+                        Some(dynamics) if dynamics.state.contains_key(var) => {
+                            dynamics.resize(n);
+                            dynamics.set_initial(var, Array1::from_vec(values))?;
+                        }
+                        _ => return Err(BrianError::ParseError(format!("Unknown synaptic variable: {}", var))),
+                    },
+                }
+                Ok(())
+            }
+            _ => Err(BrianError::ParseError(format!("Cannot set {}.{}", object, attribute))),
+        }
+    }
+
+    /// Make every constant visible to the equations and pathways
+    fn finish(mut self) -> Result<Script> {
+        let constants: HashMap<String, Quantity> = self.objects.iter()
+            .filter_map(|(name, object)| match object {
+                ScriptObject::Constant(q) => Some((name.clone(), *q)),
+                _ => None,
+            })
+            .collect();
+        for group in self.network.neuron_groups.values_mut() {
+            for (name, q) in &constants {
+                group.equations.parameters.entry(name.clone()).or_insert(*q);
+            }
+            group.invalidate();
+        }
+        for syn in self.network.synapses.values_mut() {
+            for (name, q) in &constants {
+                syn.parameters.entry(name.clone()).or_insert(*q);
+            }
+            if let Some(dynamics) = &mut syn.dynamics {
+                for (name, q) in &constants {
+                    dynamics.equations.parameters.entry(name.clone()).or_insert(*q);
+                }
+                dynamics.invalidate();
+            }
+            syn.invalidate();
+        }
+
+        Ok(Script {
+            network: self.network,
+            objects: self.objects,
+            runs: self.runs,
+        })
+    }
+
+    // ------------------------------------------------------------------
+    // Values
+    // ------------------------------------------------------------------
+
+    /// String literal, or a variable holding one
+    fn text(&self, src: &str) -> Option<String> {
+        if let Some(text) = string_literal(src) {
+            return Some(text);
+        }
+        if let Some(ScriptObject::Text(text)) = self.objects.get(src.trim()) {
+            return Some(text.clone());
+        }
+        match parse_call(src) {
+            Some(("Equations", args)) => self.text(args),
+            _ => None,
+        }
+    }
+
+    /// Name of the group a variable refers to
+    fn group(&self, src: &str) -> Result<String> {
+        match self.objects.get(src.trim()) {
+            Some(
+                ScriptObject::NeuronGroup(name)
+                | ScriptObject::PoissonGroup(name)
+                | ScriptObject::SpikeGeneratorGroup(name),
+            ) => Ok(name.clone()),
+            _ => Err(BrianError::ParseError(format!("'{}' is not a group", src.trim()))),
+        }
+    }
+
+    fn size(&self, group: &str) -> Result<usize> {
+        self.network.group_size(group)
+            .ok_or_else(|| BrianError::ParseError(format!("Unknown group: {}", group)))
+    }
+
+    /// Constants visible to expressions
+    fn constants(&self) -> impl Iterator<Item = (&String, &Quantity)> {
+        self.objects.iter().filter_map(|(name, object)| match object {
+            ScriptObject::Constant(q) => Some((name, q)),
+            _ => None,
+        })
+    }
+
+    /// Evaluate a constant expression with its dimension
+    fn quantity(&mut self, src: &str) -> Result<Quantity> {
+        let ast = expr::parse_expression(src)?;
+        let mut dimensions = DimensionTable::new();
+        for (name, q) in self.constants() {
+            dimensions.insert(name.clone(), Dim::Known(q.unit.dimension()));
+        }
+        let unit = match units::infer(&ast, &dimensions)? {
+            Dim::Known(dim) if !dim.is_dimensionless() => Unit::Compound(dim),
+            _ => Unit::Dimensionless,
+        };
+        let value = self.values(src, 1, &[])?[0];
+        Ok(Quantity::new(value / unit.to_internal_factor(), unit))
+    }
+
+    /// Evaluate a constant expression in internal units
+    fn number(&mut self, src: &str) -> Result<f64> {
+        Ok(self.values(src, 1, &[])?[0])
+    }
+
+    fn count(&mut self, src: &str) -> Result<usize> {
+        let x = self.number(src)?;
+        if x < 0.0 || x.fract() != 0.0 {
+            return Err(invalid("count", src));
+        }
+        Ok(x as usize)
+    }
+
+    /// Evaluate `[a, b, ...]` or `array([...])`, optionally times a unit
+    fn list(&mut self, src: &str) -> Result<Vec<f64>> {
+        let src = src.trim();
+        let (items, scale) = match list_items(src) {
+            Some(items) => (items, list_scale(src)),
+            None => return Err(invalid("list", src)),
+        };
+        let scale = match scale {
+            Some(scale) => self.number(scale)?,
+            None => 1.0,
+        };
+        items.iter().map(|item| Ok(self.number(item)? * scale)).collect()
+    }
+
+    /// Evaluate a number, a constant expression, or a string expression
+    /// for each of `n` elements with per-element `columns`
+    fn values(&mut self, src: &str, n: usize, columns: &[(String, Vec<f64>)]) -> Result<Vec<f64>> {
+        let code = self.text(src).unwrap_or_else(|| src.to_string());
+        let mut symbols = SymbolTable::new();
+        let mut values = vec![];
+        for (name, q) in self.constants() {
+            symbols.add(name);
+            values.push(q.to_internal());
+        }
+        let slots: Vec<usize> = columns.iter().map(|(name, _)| symbols.add(name)).collect();
+        values.resize(symbols.len(), 0.0);
+        let program = Program::parse(&code, &symbols)?;
+
+        Ok((0..n)
+            .map(|k| {
+                for (slot, (_, column)) in slots.iter().zip(columns) {
+                    values[*slot] = column[k];
+                }
+                program.eval(&values, &mut self.network.rng)
+            })
+            .collect())
+    }
+}
+
+fn integration_method(name: &str) -> Result<IntegrationMethod> {
+    Ok(match name {
+        "euler" => IntegrationMethod::Euler,
+        "exponential_euler" => IntegrationMethod::ExponentialEuler,
+        "rk2" => IntegrationMethod::RungeKutta2,
+        "rk4" => IntegrationMethod::RungeKutta4,
+        "heun" => IntegrationMethod::Heun,
+        "milstein" => IntegrationMethod::Milstein,
+        "exact" | "linear" => IntegrationMethod::ExactSolution,
+        other => return Err(BrianError::ParseError(format!("Unknown integration method: {}", other))),
+    })
+}
+
+fn unsupported(statement: &str) -> BrianError {
+    BrianError::ParseError(format!("Unsupported statement: {}", statement))
+}
+
+fn missing(argument: &str) -> BrianError {
+    BrianError::ParseError(format!("Missing argument: {}", argument))
+}
+
+fn invalid(argument: &str, value: &str) -> BrianError {
+    BrianError::ParseError(format!("Invalid {}: {}", argument, value))
+}
+
+// ============================================================================
+// LEXICAL HELPERS
+// ============================================================================
+
+/// Split the script into statements, joining lines inside brackets and
+/// triple-quoted strings and dropping comments
+fn logical_lines(src: &str) -> Result<Vec<(usize, String)>> {
+    let mut out = vec![];
+    let mut current = String::new();
+    let mut start = 1;
+    let mut depth = 0i32;
+    let mut quote: Option<&str> = None;
+    let mut line = 1;
+    let mut rest = src;
+
+    while let Some(c) = rest.chars().next() {
+        let len = c.len_utf8();
+        match quote {
+            Some(q) => {
+                if rest.starts_with(q) {
+                    current.push_str(q);
+                    rest = &rest[q.len()..];
+                    quote = None;
+                    continue;
+                }
+                if c == '\\' && rest.len() > 1 {
+                    let escaped = rest[1..].chars().next().unwrap_or(' ');
+                    current.push(c);
+                    current.push(escaped);
+                    rest = &rest[1 + escaped.len_utf8()..];
+                    continue;
+                }
+                if c == '\n' {
+                    if q.len() == 1 {
+                        return Err(BrianError::ParseError(format!("line {}: unterminated string", line)));
+                    }
+                    line += 1;
+                }
+                current.push(c);
+            }
+            None => match c {
+                '#' => {
+                    let end = rest.find('\n').unwrap_or(rest.len());
+                    rest = &rest[end..];
+                    continue;
+                }
+                '\'' | '"' => {
+                    let q = ["'''", "\"\"\"", "'", "\""].into_iter().find(|q| rest.starts_with(q)).unwrap();
+                    current.push_str(q);
+                    rest = &rest[q.len()..];
+                    quote = Some(q);
+                    continue;
+                }
+                '(' | '[' | '{' => {
+                    depth += 1;
+                    current.push(c);
+                }
+                ')' | ']' | '}' => {
+                    depth -= 1;
+                    current.push(c);
+                }
+                '\\' if rest[1..].starts_with('\n') => {
+                    rest = &rest[2..];
+                    line += 1;
+                    continue;
+                }
+                '\n' | ';' => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    if depth <= 0 {
+                        let statement = current.trim();
+                        if !statement.is_empty() {
+                            out.push((start, statement.to_string()));
+                        }
+                        current.clear();
+                        start = line;
+                    } else {
+                        current.push(' ');
+                    }
+                }
+                _ => current.push(c),
+            },
+        }
+        rest = &rest[len..];
+    }
+
+    if quote.is_some() || depth != 0 {
+        return Err(BrianError::ParseError(format!("line {}: unexpected end of script", start)));
+    }
+    let statement = current.trim();
+    if !statement.is_empty() {
+        out.push((start, statement.to_string()));
+    }
+    Ok(out)
+}
+
+/// Visit the characters of `src` outside strings with the bracket depth
+/// they sit at (brackets count as outside themselves)
+fn scan(src: &str, mut f: impl FnMut(usize, char, i32) -> bool) {
+    let mut depth = 0;
+    let mut quote: Option<&str> = None;
+    let mut k = 0;
+    while k < src.len() {
+        let rest = &src[k..];
+        let c = rest.chars().next().unwrap_or(' ');
+        match quote {
+            Some(q) if rest.starts_with(q) => {
+                k += q.len();
+                quote = None;
+                continue;
+            }
+            Some(_) if c == '\\' => {
+                k += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+                continue;
+            }
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' => {
+                    let q = ["'''", "\"\"\"", "'", "\""].into_iter().find(|q| rest.starts_with(q)).unwrap();
+                    k += q.len();
+                    quote = Some(q);
+                    continue;
+                }
+                '(' | '[' | '{' => {
+                    if !f(k, c, depth) {
+                        return;
+                    }
+                    depth += 1;
+                }
+                ')' | ']' | '}' => {
+                    depth -= 1;
+                    if !f(k, c, depth) {
+                        return;
+                    }
+                }
+                _ => {
+                    if !f(k, c, depth) {
+                        return;
+                    }
+                }
+            },
+        }
+        k += c.len_utf8();
+    }
+}
+
+/// Split `lhs = rhs` at a top-level `=` that is not part of a comparison
+fn split_assignment(statement: &str) -> Option<(&str, &str)> {
+    let bytes = statement.as_bytes();
+    let mut found = None;
+    scan(statement, |k, c, depth| {
+        if c == '=' && depth == 0 {
+            let prev = if k > 0 { bytes[k - 1] } else { b' ' };
+            let next = bytes.get(k + 1).copied().unwrap_or(b' ');
+            if !b"=!<>+-*/".contains(&prev) && next != b'=' {
+                found = Some(k);
+                return false;
+            }
+        }
+        true
+    });
+    found.map(|k| (statement[..k].trim(), statement[k + 1..].trim()))
+}
+
+/// Split on top-level commas
+fn split_top_level(src: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut last = 0;
+    scan(src, |k, c, depth| {
+        if c == ',' && depth == 0 {
+            parts.push(src[last..k].trim());
+            last = k + 1;
+        }
+        true
+    });
+    parts.push(src[last..].trim());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Split `callee(args)` where the closing parenthesis ends the text
+fn parse_call(src: &str) -> Option<(&str, &str)> {
+    let src = src.trim();
+    let open = src.find('(')?;
+    let callee = src[..open].trim();
+    if callee.is_empty() || !callee.split('.').all(is_identifier) || !src.ends_with(')') {
+        return None;
+    }
+    // The opening parenthesis must match the final one
+    let inner = &src[open..];
+    let mut matched = None;
+    scan(inner, |k, c, depth| {
+        if c == ')' && depth == 0 {
+            matched = Some(k);
+            return false;
+        }
+        true
+    });
+    (matched? == inner.len() - 1).then(|| (callee, &inner[1..inner.len() - 1]))
+}
+
+/// Split call arguments into positional and keyword (`key=value`) texts
+fn parse_args(args: &str) -> Result<(Vec<String>, HashMap<String, String>)> {
+    let mut positional = vec![];
+    let mut keywords = HashMap::new();
+    for arg in split_top_level(args) {
+        match split_assignment(arg) {
+            Some((key, value)) if is_identifier(key) => {
+                keywords.insert(key.to_string(), value.to_string());
+            }
+            Some(_) => return Err(BrianError::ParseError(format!("Invalid argument: {}", arg))),
+            None if keywords.is_empty() => positional.push(arg.to_string()),
+            None => {
+                return Err(BrianError::ParseError(format!("Positional argument after keywords: {}", arg)));
+            }
+        }
+    }
+    Ok((positional, keywords))
+}
+
+/// Contents of a Python string literal
+fn string_literal(src: &str) -> Option<String> {
+    let src = src.trim();
+    let src = src.strip_prefix('r').unwrap_or(src);
+    for q in ["'''", "\"\"\"", "'", "\""] {
+        if src.len() >= 2 * q.len() && src.starts_with(q) && src.ends_with(q) {
+            let inner = &src[q.len()..src.len() - q.len()];
+            if q.len() == 1 && inner.contains(q) {
+                return None;
+            }
+            return Some(inner.to_string());
+        }
+    }
+    None
+}
+
+/// Items of `[...]` or `array([...])`, ignoring a trailing `*unit`
+fn list_items(src: &str) -> Option<Vec<&str>> {
+    let src = src.trim();
+    let src = src.strip_prefix("array(").or_else(|| src.strip_prefix("np.array(")).unwrap_or(src);
+    let inner = src.strip_prefix('[')?;
+    let end = inner.find(']')?;
+    Some(split_top_level(&inner[..end]))
+}
+
+/// Unit factor multiplying a list (`[1, 2]*ms`)
+fn list_scale(src: &str) -> Option<&str> {
+    let end = src.rfind(']')?;
+    let rest = src[end + 1..].trim_start_matches(')').trim();
+    rest.strip_prefix('*').map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+from brian2 import *
+
+defaultclock.dt = 0.1*ms
+seed(1)
+N = 20
+tau = 10*ms
+eqs = '''
+dv/dt = (-70*mV - v) / tau + drive : volt
+drive = 3*mV/ms : volt/second
+'''
+G = NeuronGroup(N, eqs, threshold='v > v_th', reset='v = -70*mV',
+                refractory=2*ms, method='euler')
+G.v = '-70*mV + rand() * 10*mV'  # random start
+P = SpikeGeneratorGroup(2, [0, 1], [5, 10]*ms)
+S = Synapses(P, G, 'w : volt', on_pre='v_post += w', delay=1*ms)
+S.connect(j='i')
+S.w = 'i * mV'
+background = PoissonInput(G, 'v', 100, 1*Hz, weight=0.1*mV)
+spikes = SpikeMonitor(G)
+state = StateMonitor(G, 'v', record=[0, 1])
+rate = PopulationRateMonitor(G)
+v_th = -50*mV
+run(50*ms)
+plot(state.t/ms, state.v[0]/mV)
+"#;
+
+    #[test]
+    fn test_import_script() {
+        let mut script = import_script(SCRIPT).unwrap();
+        assert_eq!(script.runs, vec![50.0]);
+        match &script.objects["tau"] {
+            ScriptObject::Constant(q) => {
+                assert_eq!(q.to_internal(), 10.0);
+                assert_eq!(q.unit.dimension(), Unit::Millisecond.dimension());
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(&script.objects["S"], ScriptObject::Synapses(name) if name == "S"));
+
+        let net = &script.network;
+        let v = &net.neuron_groups["G"].state["v"];
+        assert_eq!(v.len(), 20);
+        assert!(v.iter().all(|&v| (-70.0..=-60.0).contains(&v)));
+        let syn = &net.synapses["S"];
+        assert_eq!(syn.connections, vec![(0, 0), (1, 1)]);
+        assert_eq!(syn.weights, vec![0.0, 1.0]);
+        assert_eq!(syn.delays, vec![1.0, 1.0]);
+        assert_eq!(net.poisson_inputs[0].n, 100);
+        assert!((net.poisson_inputs[0].rate - 1.0).abs() < 1e-12);
+
+        script.run().unwrap();
+        assert!((script.network.t - 50.0).abs() < 1e-6);
+        assert!(!script.network.spike_monitors["G"].spikes.is_empty());
+        assert!(!script.network.state_monitors["G_state"].data["v"].0.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_statements() {
+        let err = import_script("N = 10\nfor k in range(N):\n    pass\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(import_script("G = NeuronGroup(10, 'dv/dt = -v/ms : 1', method='magic')").is_err());
+        assert!(import_script("S = Synapses(P, P)").is_err());
+    }
+
+    #[test]
+    fn test_logical_lines() {
+        let lines = logical_lines("a = f(1,\n      2)  # comment\nb = '''x\ny'''\nc = 3; d = 4\n").unwrap();
+        let statements: Vec<&str> = lines.iter().map(|(_, s)| s.as_str()).collect();
+        assert_eq!(statements, vec!["a = f(1,       2)", "b = '''x\ny'''", "c = 3", "d = 4"]);
+        assert_eq!(lines[1].0, 3);
+        assert_eq!(parse_call("f(g(1), 'a)')"), Some(("f", "g(1), 'a)'")));
+        assert_eq!(parse_call("f(1) + g(2)"), None);
+        assert_eq!(split_assignment("S.connect(condition='i != j')"), None);
+    }
+}
@@ -0,0 +1,560 @@
+//! Multicompartmental neurons
+//!
+//! A [`SpatialNeuron`] is a tree of compartments built from a [`Morphology`].
+//! Its equations describe the transmembrane current density `Im` (amp/meter**2)
+//! as a function of the membrane potential `v`, plus any channel gating
+//! variables, exactly like Brian's `SpatialNeuron`. Gating variables are
+//! advanced with the group's integration method; the cable equation
+//!
+//! ```text
+//! Cm * area * dv/dt = Im * area + sum(g_axial * (v_neighbor - v)) + I
+//! ```
+//!
+//! is solved with backward Euler, so `Im` must be linear in `v`. The
+//! resulting tree system is eliminated in linear time (Hines' method).
+//!
+//! Lengths are in um, the simulator's base length, so `Cm` and `Im`
+//! become nF/um^2 and nA/um^2 and axial conductances come out in uS.
+
+use crate::expr::{self, Program};
+use oldies_core::Rng;
+use crate::units::Dimension;
+use crate::{
+    linear, BrianError, CompiledEquations, IntegrationMethod, NeuronEquations, Quantity, Result,
+    TimedArray, Unit,
+};
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Name of the membrane potential of a spatial neuron
+pub const POTENTIAL_SYMBOL: &str = "v";
+
+/// Name of the transmembrane current density
+pub const MEMBRANE_CURRENT_SYMBOL: &str = "Im";
+
+/// Shape of a compartment
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+    /// Isopotential sphere (soma) with no axial resistance of its own
+    Sphere,
+    /// Cylinder segment
+    Cylinder,
+}
+
+/// Single compartment (lengths in um)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Compartment {
+    pub shape: Shape,
+    pub length: f64,
+    pub diameter: f64,
+    /// Parent compartment; always has a smaller index
+    pub parent: Option<usize>,
+    /// Distal end (the center, for a sphere) in um, if the morphology was
+    /// reconstructed
+    #[serde(default)]
+    pub coordinates: Option<[f64; 3]>,
+}
+
+impl Compartment {
+    /// Membrane area (um^2)
+    pub fn area(&self) -> f64 {
+        match self.shape {
+            Shape::Sphere => std::f64::consts::PI * self.diameter * self.diameter,
+            Shape::Cylinder => std::f64::consts::PI * self.diameter * self.length,
+        }
+    }
+
+    /// Axial resistance from the center to either end, for a given
+    /// intracellular resistivity
+    fn half_resistance(&self, ri: f64) -> f64 {
+        match self.shape {
+            Shape::Sphere => 0.0,
+            Shape::Cylinder => {
+                let cross_section = std::f64::consts::PI * self.diameter * self.diameter / 4.0;
+                ri * 0.5 * self.length / cross_section
+            }
+        }
+    }
+}
+
+/// Named run of consecutive compartments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    pub name: String,
+    pub start: usize,
+    pub n: usize,
+}
+
+/// Tree of compartments
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Morphology {
+    pub compartments: Vec<Compartment>,
+    pub sections: Vec<Section>,
+}
+
+impl Morphology {
+    /// Single spherical soma of the given diameter (um)
+    pub fn soma(diameter: f64) -> Self {
+        Self {
+            compartments: vec![Compartment {
+                shape: Shape::Sphere,
+                length: diameter,
+                diameter,
+                parent: None,
+                coordinates: None,
+            }],
+            sections: vec![Section { name: "soma".into(), start: 0, n: 1 }],
+        }
+    }
+
+    /// Unbranched cable of `n` compartments
+    pub fn cylinder(length: f64, diameter: f64, n: usize) -> Result<Self> {
+        let mut morphology = Self::default();
+        morphology.push_cylinder("root", None, length, diameter, n)?;
+        Ok(morphology)
+    }
+
+    /// Attach a cylinder of `n` compartments to compartment `parent` and
+    /// return the range of the new compartments
+    pub fn attach(
+        &mut self,
+        name: &str,
+        parent: usize,
+        length: f64,
+        diameter: f64,
+        n: usize,
+    ) -> Result<Range<usize>> {
+        if parent >= self.compartments.len() {
+            return Err(BrianError::SimulationError(format!(
+                "Cannot attach '{}' to compartment {} of {}",
+                name,
+                parent,
+                self.compartments.len()
+            )));
+        }
+        self.push_cylinder(name, Some(parent), length, diameter, n)
+    }
+
+    fn push_cylinder(
+        &mut self,
+        name: &str,
+        parent: Option<usize>,
+        length: f64,
+        diameter: f64,
+        n: usize,
+    ) -> Result<Range<usize>> {
+        if n == 0 || !(length > 0.0 && diameter > 0.0) {
+            return Err(BrianError::SimulationError(format!(
+                "Invalid section '{}': length {} um, diameter {} um, {} compartments",
+                name, length, diameter, n
+            )));
+        }
+        if self.section(name).is_some() {
+            return Err(BrianError::SimulationError(format!("Duplicate section: {}", name)));
+        }
+
+        let start = self.compartments.len();
+        for k in 0..n {
+            self.compartments.push(Compartment {
+                shape: Shape::Cylinder,
+                length: length / n as f64,
+                diameter,
+                parent: if k == 0 { parent } else { Some(start + k - 1) },
+                coordinates: None,
+            });
+        }
+        self.sections.push(Section { name: name.to_string(), start, n });
+        Ok(start..start + n)
+    }
+
+    /// Compartments of a named section
+    pub fn section(&self, name: &str) -> Option<Range<usize>> {
+        self.sections.iter()
+            .find(|s| s.name == name)
+            .map(|s| s.start..s.start + s.n)
+    }
+
+    /// Section containing compartment `k`
+    pub fn section_of(&self, k: usize) -> Option<&Section> {
+        self.sections.iter().find(|s| (s.start..s.start + s.n).contains(&k))
+    }
+
+    /// Sections attached to the named section, in order
+    pub fn children(&self, name: &str) -> Vec<&Section> {
+        let Some(range) = self.section(name) else { return vec![] };
+        self.sections.iter()
+            .filter(|s| self.compartments[s.start].parent.is_some_and(|p| range.contains(&p)))
+            .collect()
+    }
+
+    /// Section the named section is attached to
+    pub fn parent(&self, name: &str) -> Option<&Section> {
+        let range = self.section(name)?;
+        self.section_of(self.compartments[range.start].parent?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.compartments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.compartments.is_empty()
+    }
+
+    /// Total membrane area (um^2)
+    pub fn area(&self) -> f64 {
+        self.compartments.iter().map(Compartment::area).sum()
+    }
+
+    /// Axial conductance (uS) between each compartment and its parent, for
+    /// a resistivity in MOhm*um
+    fn axial_conductances(&self, ri: f64) -> Result<Vec<f64>> {
+        self.compartments.iter()
+            .enumerate()
+            .map(|(k, c)| match c.parent {
+                None => Ok(0.0),
+                Some(p) => {
+                    let r = c.half_resistance(ri) + self.compartments[p].half_resistance(ri);
+                    if r > 0.0 {
+                        Ok(1.0 / r)
+                    } else {
+                        Err(BrianError::SimulationError(format!(
+                            "Compartments {} and {} have no axial resistance between them",
+                            p, k
+                        )))
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Equations compiled for a spatial neuron
+#[derive(Debug, Clone)]
+struct CompiledCable {
+    equations: CompiledEquations,
+    v_slot: usize,
+    /// `Im = a + b*v`
+    current_offset: Program,
+    current_slope: Program,
+    /// Capacitance (nF) and area (um^2) per compartment
+    capacitance: Vec<f64>,
+    area: Vec<f64>,
+    /// Axial conductance (uS) to the parent compartment
+    axial: Vec<f64>,
+}
+
+/// Multicompartmental neuron
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpatialNeuron {
+    pub name: String,
+    pub morphology: Morphology,
+    pub equations: NeuronEquations,
+    /// Integration method for the gating variables
+    pub method: IntegrationMethod,
+    /// Specific membrane capacitance
+    pub cm: Quantity,
+    /// Intracellular resistivity
+    pub ri: Quantity,
+    /// State variables per compartment, including `v`
+    pub state: HashMap<String, Array1<f64>>,
+    /// Current injected into each compartment (nA)
+    pub input: Array1<f64>,
+    /// Timed arrays callable from the equations
+    pub timed_arrays: HashMap<String, TimedArray>,
+    /// Own clock period in ms; `None` updates every step
+    #[serde(default)]
+    pub dt: Option<f64>,
+    #[serde(skip)]
+    compiled: Option<CompiledCable>,
+}
+
+impl SpatialNeuron {
+    /// Build a neuron with 1 uF/cm^2 membrane capacitance and 100 ohm*cm
+    /// intracellular resistivity
+    pub fn new(name: &str, morphology: Morphology, equations: NeuronEquations) -> Self {
+        let n = morphology.len();
+        let mut state = HashMap::new();
+        state.insert(POTENTIAL_SYMBOL.to_string(), Array1::zeros(n));
+        for eq in &equations.differential {
+            state.insert(eq.variable.clone(), Array1::zeros(n));
+        }
+        for eq in &equations.algebraic {
+            state.insert(eq.variable.clone(), Array1::zeros(n));
+        }
+        for decl in &equations.per_neuron {
+            state.insert(decl.variable.clone(), Array1::zeros(n));
+        }
+        let method = equations.differential.first()
+            .map(|eq| eq.method)
+            .unwrap_or(IntegrationMethod::Euler);
+
+        Self {
+            name: name.to_string(),
+            morphology,
+            equations,
+            method,
+            cm: Quantity::new(0.01, Unit::Compound(Dimension::CAPACITANCE.div(&Dimension::LENGTH.powi(2)))),
+            ri: Quantity::new(1.0, Unit::Compound(Dimension::RESISTANCE.mul(&Dimension::LENGTH))),
+            state,
+            input: Array1::zeros(n),
+            timed_arrays: HashMap::new(),
+            dt: None,
+            compiled: None,
+        }
+    }
+
+    /// Number of compartments
+    pub fn n(&self) -> usize {
+        self.morphology.len()
+    }
+
+    /// Make a timed array callable from the equations
+    pub fn add_timed_array(&mut self, array: TimedArray) {
+        self.timed_arrays.insert(array.name.clone(), array);
+        self.compiled = None;
+    }
+
+    pub fn set_initial(&mut self, variable: &str, values: Array1<f64>) -> Result<()> {
+        let n = self.n();
+        match self.state.get_mut(variable) {
+            Some(state) if values.len() == n => {
+                *state = values;
+                Ok(())
+            }
+            Some(_) => Err(BrianError::SimulationError(format!(
+                "Expected {} values, got {}",
+                n,
+                values.len()
+            ))),
+            None => Err(BrianError::SimulationError(format!("Unknown variable: {}", variable))),
+        }
+    }
+
+    /// Discard compiled equations (after editing the equations, morphology
+    /// or electrical properties)
+    pub fn invalidate(&mut self) {
+        self.compiled = None;
+    }
+
+    fn compile(&self) -> Result<CompiledCable> {
+        let current = self.equations.algebraic.iter()
+            .find(|eq| eq.variable == MEMBRANE_CURRENT_SYMBOL)
+            .ok_or_else(|| BrianError::EquationError(format!(
+                "Spatial neuron '{}' does not define {}",
+                self.name, MEMBRANE_CURRENT_SYMBOL
+            )))?;
+        let density = Dimension::CURRENT.div(&Dimension::LENGTH.powi(2));
+        if current.unit.dimension() != density {
+            return Err(BrianError::UnitError {
+                expected: density.to_string(),
+                got: current.unit.dimension().to_string(),
+            });
+        }
+
+        // The potential is not integrated by the equations themselves; it
+        // is declared like a parameter and loaded per compartment
+        let mut equations = self.equations.clone();
+        if equations.differential.iter().any(|eq| eq.variable == POTENTIAL_SYMBOL) {
+            return Err(BrianError::EquationError(format!(
+                "The membrane potential '{}' of a spatial neuron is defined by the cable equation",
+                POTENTIAL_SYMBOL
+            )));
+        }
+        equations.parameters.insert(POTENTIAL_SYMBOL.into(), Quantity::new(0.0, Unit::Millivolt));
+        let compiled = CompiledEquations::compile(&equations, self.n(), &self.timed_arrays)?;
+        let v_slot = compiled.symbols.resolve(POTENTIAL_SYMBOL).unwrap_or_default();
+
+        let mut definitions = HashMap::new();
+        for eq in &self.equations.algebraic {
+            if eq.variable == MEMBRANE_CURRENT_SYMBOL {
+                break;
+            }
+            let def = expr::parse_expression(&eq.expression)?.substitute(&definitions);
+            definitions.insert(eq.variable.clone(), def);
+        }
+        let im = expr::parse_expression(&current.expression)?.substitute(&definitions);
+        let split = linear::affine(&im, &[POTENTIAL_SYMBOL]).ok_or_else(|| {
+            BrianError::EquationError(format!(
+                "{} must be linear in {} for the cable equation",
+                MEMBRANE_CURRENT_SYMBOL, POTENTIAL_SYMBOL
+            ))
+        })?;
+
+        let area: Vec<f64> = self.morphology.compartments.iter().map(|c| c.area()).collect();
+        let cm = self.cm.to_internal();
+        Ok(CompiledCable {
+            v_slot,
+            current_offset: Program::compile(&split.offset, &compiled.symbols)?,
+            current_slope: Program::compile(&split.coeffs[0], &compiled.symbols)?,
+            capacitance: area.iter().map(|a| cm * a).collect(),
+            axial: self.morphology.axial_conductances(self.ri.to_internal())?,
+            area,
+            equations: compiled,
+        })
+    }
+
+    /// Advance all compartments by `dt` at time `t`.
+    ///
+    /// Returns the compartments that crossed the threshold, after applying
+    /// the reset to them.
+    pub fn update(&mut self, t: f64, dt: f64, rng: &mut Rng) -> Result<Vec<usize>> {
+        if self.compiled.is_none() {
+            self.compiled = Some(self.compile()?);
+        }
+        let cable = self.compiled.take().unwrap();
+        let result = self.step(&cable, t, dt, rng);
+        self.compiled = Some(cable);
+        result
+    }
+
+    fn step(&mut self, cable: &CompiledCable, t: f64, dt: f64, rng: &mut Rng) -> Result<Vec<usize>> {
+        let compiled = &cable.equations;
+        let mut scratch = compiled.prepare(self.method, dt)?;
+        let n = self.n();
+
+        let mut columns: Vec<Array1<f64>> = compiled.state_vars.iter()
+            .map(|name| self.state.remove(name).unwrap_or_else(|| Array1::zeros(n)))
+            .collect();
+        let mut v = self.state.remove(POTENTIAL_SYMBOL).unwrap_or_else(|| Array1::zeros(n));
+
+        let mut values = compiled.constants.clone();
+        values[compiled.dt_slot] = dt;
+        let load = |values: &mut [f64], columns: &[Array1<f64>], v: f64, i: usize| {
+            for (slot, column) in columns.iter().enumerate() {
+                values[slot] = column[i];
+            }
+            values[cable.v_slot] = v;
+            values[compiled.index_slot] = i as f64;
+            values[compiled.t_slot] = t;
+        };
+
+        // Linearize Im and advance the gating variables at the old potential
+        let mut diag = vec![0.0; n];
+        let mut rhs = vec![0.0; n];
+        for i in 0..n {
+            load(&mut values, &columns, v[i], i);
+            let a = cable.current_offset.eval(&values, rng);
+            let b = cable.current_slope.eval(&values, rng);
+            let c = cable.capacitance[i] / dt;
+            diag[i] = c - cable.area[i] * b;
+            rhs[i] = c * v[i] + cable.area[i] * a + self.input[i];
+
+            compiled.step_neuron(self.method, &mut values, dt, rng, &mut scratch);
+            for (slot, column) in columns.iter_mut().enumerate() {
+                column[i] = values[slot];
+            }
+        }
+
+        // Backward Euler on the tree: eliminate leaves into their parents,
+        // then substitute back from the roots
+        let parents: Vec<Option<usize>> = self.morphology.compartments.iter().map(|c| c.parent).collect();
+        for (i, parent) in parents.iter().enumerate() {
+            if let Some(p) = *parent {
+                diag[i] += cable.axial[i];
+                diag[p] += cable.axial[i];
+            }
+        }
+        for i in (0..n).rev() {
+            if let Some(p) = parents[i] {
+                let factor = cable.axial[i] / diag[i];
+                diag[p] -= factor * cable.axial[i];
+                rhs[p] += factor * rhs[i];
+            }
+        }
+        for i in 0..n {
+            let coupled = parents[i].map_or(0.0, |p| cable.axial[i] * v[p]);
+            v[i] = (rhs[i] + coupled) / diag[i];
+        }
+
+        // Threshold and reset at the new potential
+        let mut spikes = vec![];
+        for i in 0..n {
+            load(&mut values, &columns, v[i], i);
+            compiled.update_algebraic(&mut values, rng);
+            let spiked = compiled.threshold.as_ref().is_some_and(|th| th.eval_bool(&values, rng));
+            if spiked {
+                spikes.push(i);
+                for stmt in &compiled.reset {
+                    stmt.execute(&mut values, rng);
+                }
+            }
+            for (slot, column) in columns.iter_mut().enumerate() {
+                column[i] = values[slot];
+            }
+            v[i] = values[cable.v_slot];
+        }
+
+        for (name, column) in compiled.state_vars.iter().zip(columns) {
+            self.state.insert(name.clone(), column);
+        }
+        self.state.insert(POTENTIAL_SYMBOL.to_string(), v);
+
+        Ok(spikes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_equations;
+
+    /// Passive membrane, tau = Cm / gL = 10 ms
+    fn passive() -> NeuronEquations {
+        let mut eqs = parse_equations("Im = gL * (EL - v) : amp/meter**2").unwrap();
+        eqs.parameters.insert("gL".into(), Quantity::new(1.0, Unit::parse("siemens/meter**2").unwrap()));
+        eqs.parameters.insert("EL".into(), Quantity::new(-70.0, Unit::Millivolt));
+        eqs
+    }
+
+    #[test]
+    fn test_morphology() {
+        let mut morpho = Morphology::soma(20.0);
+        let dend = morpho.attach("dendrite", 0, 300.0, 2.0, 3).unwrap();
+        assert_eq!(dend, 1..4);
+        let branch = morpho.attach("branch", dend.end - 1, 50.0, 1.0, 1).unwrap();
+        assert_eq!(morpho.compartments[branch.start].parent, Some(3));
+        assert_eq!(morpho.section("dendrite"), Some(1..4));
+        assert!(morpho.attach("dendrite", 0, 1.0, 1.0, 1).is_err());
+        assert!(morpho.attach("x", 99, 1.0, 1.0, 1).is_err());
+
+        let pi = std::f64::consts::PI;
+        assert!((morpho.compartments[0].area() - pi * 400.0).abs() < 1e-9);
+        assert!((morpho.compartments[1].area() - pi * 200.0).abs() < 1e-9);
+        // Soma to first dendrite compartment: half of a 100 um segment
+        let g = morpho.axial_conductances(1.0).unwrap();
+        assert!((g[1] - pi / 50.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_passive_cable() {
+        // Isopotential soma relaxes with tau = 10 ms
+        let mut soma = SpatialNeuron::new("soma", Morphology::soma(10.0), passive());
+        soma.set_initial("v", Array1::from_elem(1, -60.0)).unwrap();
+        let mut rng = Rng::default();
+        for k in 0..1000 {
+            soma.update(k as f64 * 0.01, 0.01, &mut rng).unwrap();
+        }
+        let v = soma.state["v"][0];
+        assert!((v - (-70.0 + 10.0 * (-1.0f64).exp())).abs() < 0.01, "v = {}", v);
+
+        // Sealed cable one length constant long (lambda = 500 um for a
+        // 1 um fiber): steady-state attenuation is 1/cosh(1)
+        let morpho = Morphology::cylinder(500.0, 1.0, 100).unwrap();
+        let mut cable = SpatialNeuron::new("cable", morpho, passive());
+        cable.set_initial("v", Array1::from_elem(100, -70.0)).unwrap();
+        cable.input[0] = 0.01;
+        for k in 0..2000 {
+            cable.update(k as f64 * 0.1, 0.1, &mut rng).unwrap();
+        }
+        let v = &cable.state["v"];
+        let ratio = (v[99] + 70.0) / (v[0] + 70.0);
+        assert!(v[0] > -70.0);
+        assert!((ratio - 1.0 / 1.0f64.cosh()).abs() < 0.02, "ratio = {}", ratio);
+
+        let mut bad = SpatialNeuron::new("bad", Morphology::soma(10.0), parse_equations("Im = v * v : amp/meter**2").unwrap());
+        assert!(bad.update(0.0, 0.1, &mut rng).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Spike queue for synaptic delays
+//!
+//! Delays are rounded to whole time steps. Each presynaptic spike schedules
+//! its outgoing synapses into a ring buffer of per-step slots; popping the
+//! current slot yields the synapses whose spikes arrive during this step.
+
+use crate::{BrianError, Result};
+
+/// Ring buffer of synapse indices keyed on time steps
+#[derive(Debug, Clone)]
+pub struct SpikeQueue {
+    /// Synapses due at each upcoming step; `slots[offset]` is the current step
+    slots: Vec<Vec<usize>>,
+    offset: usize,
+    /// Delay of each synapse in steps
+    delays: Vec<usize>,
+    /// Outgoing synapses of each presynaptic neuron
+    by_source: Vec<Vec<usize>>,
+    dt: f64,
+}
+
+impl SpikeQueue {
+    /// Build a queue for the given connections and delays (ms)
+    pub fn new(connections: &[(usize, usize)], delays: &[f64], dt: f64) -> Result<Self> {
+        if delays.len() != connections.len() {
+            return Err(BrianError::SimulationError(format!(
+                "Expected {} delays, got {}",
+                connections.len(),
+                delays.len()
+            )));
+        }
+        if dt <= 0.0 {
+            return Err(BrianError::SimulationError(format!("Invalid time step: {}", dt)));
+        }
+
+        let mut steps = Vec::with_capacity(delays.len());
+        for &delay in delays {
+            if !delay.is_finite() || delay < 0.0 {
+                return Err(BrianError::SimulationError(format!("Invalid delay: {} ms", delay)));
+            }
+            steps.push((delay / dt).round() as usize);
+        }
+
+        let n_source = connections.iter().map(|&(i, _)| i + 1).max().unwrap_or(0);
+        let mut by_source = vec![vec![]; n_source];
+        for (syn, &(i, _)) in connections.iter().enumerate() {
+            by_source[i].push(syn);
+        }
+
+        let max_delay = steps.iter().copied().max().unwrap_or(0);
+
+        Ok(Self {
+            slots: vec![vec![]; max_delay + 1],
+            offset: 0,
+            delays: steps,
+            by_source,
+            dt,
+        })
+    }
+
+    /// Whether the queue was built for `n_synapses` synapses at this time step
+    pub fn matches(&self, n_synapses: usize, dt: f64) -> bool {
+        self.delays.len() == n_synapses && self.dt == dt
+    }
+
+    /// Schedule the outgoing synapses of the neurons that spiked this step
+    pub fn push(&mut self, sources: &[usize]) {
+        let len = self.slots.len();
+        for &i in sources {
+            if let Some(synapses) = self.by_source.get(i) {
+                for &syn in synapses {
+                    self.slots[(self.offset + self.delays[syn]) % len].push(syn);
+                }
+            }
+        }
+    }
+
+    /// Take the synapses due at the current step and advance by one step
+    pub fn pop(&mut self) -> Vec<usize> {
+        let due = std::mem::take(&mut self.slots[self.offset]);
+        self.offset = (self.offset + 1) % self.slots.len();
+        due
+    }
+
+    /// Number of spikes in flight
+    pub fn pending(&self) -> usize {
+        self.slots.iter().map(Vec::len).sum()
+    }
+
+    /// Drop all spikes in flight
+    pub fn clear(&mut self) {
+        for slot in &mut self.slots {
+            slot.clear();
+        }
+        self.offset = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivery_after_delay() {
+        let connections = [(0, 0), (0, 1), (1, 0)];
+        let mut queue = SpikeQueue::new(&connections, &[0.0, 0.3, 1.0], 0.1).unwrap();
+
+        queue.push(&[0]);
+        assert_eq!(queue.pop(), vec![0]);
+        assert_eq!(queue.pending(), 1);
+        assert!(queue.pop().is_empty());
+        assert!(queue.pop().is_empty());
+        assert_eq!(queue.pop(), vec![1]);
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn test_invalid_delays() {
+        assert!(SpikeQueue::new(&[(0, 0)], &[-1.0], 0.1).is_err());
+        assert!(SpikeQueue::new(&[(0, 0)], &[], 0.1).is_err());
+    }
+}
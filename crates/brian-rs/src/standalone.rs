@@ -0,0 +1,1138 @@
+//! Standalone mode: export a network as a Rust project
+//!
+//! Like Brian2's C++ standalone device, [`generate`] turns a constructed
+//! [`Network`] into a self-contained Cargo project with the model fixed at
+//! generation time. Equations, thresholds, resets and synaptic pathways
+//! become straight-line Rust with parameters inlined as constants, and the
+//! current state and connectivity are written to `static_arrays/` as raw
+//! little-endian data.
+//!
+//! The project has no dependencies. `cargo run --release -- results/`
+//! simulates the requested duration and writes the monitors to CSV files
+//! (`spikes_<source>.csv`, `rate_<source>.csv`,
+//! `state_<source>_<variable>.csv`). Its library target exposes the network
+//! as `Network::new()`/`step()`/`run(steps)` for embedding in other
+//! applications.
+//!
+//! The generated step follows [`Network::run`] with objects of each kind
+//! updated in name order, and draws from a copy of the network's random
+//! number generator, so a network whose randomness comes from a single
+//! object reproduces the interpreter spike for spike. Objects that need the
+//! interpreter at run time are rejected: network operations, spatial
+//! neurons, rate groups, per-object clocks, timed arrays, `run_regularly`
+//! code, custom events, exact integration, synaptic equations, STDP and the
+//! built-in conductance and STP synapse models (`on_pre` statements cover
+//! these). Spikes still in flight when the project is generated are not
+//! carried over.
+
+use crate::expr::{self, AssignOp, BinOp, Builtin, Expr, Statement};
+use crate::{
+    linear, BrianError, IntegrationMethod, Network, NeuronGroup, RefractorySpec, Result,
+    SynapseModel, Synapses, INPUT_SYMBOL, MEMBRANE_SYMBOL, NOT_REFRACTORY_SYMBOL,
+};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Append a formatted line; writing to a `String` cannot fail
+macro_rules! emit {
+    ($out:expr) => { $out.push('\n') };
+    ($out:expr, $($arg:tt)*) => { let _ = writeln!($out, $($arg)*); };
+}
+
+/// Generated Cargo project
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project {
+    pub name: String,
+    /// (path relative to the project root, contents)
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+impl Project {
+    /// Contents of a generated file
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.files.iter().find(|(p, _)| p == path).map(|(_, data)| data.as_slice())
+    }
+
+    /// Write the project to `dir`, creating it if needed
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let io = |e: std::io::Error| BrianError::SimulationError(format!("Cannot write project: {}", e));
+        for (path, data) in &self.files {
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(io)?;
+            }
+            std::fs::write(path, data).map_err(io)?;
+        }
+        Ok(())
+    }
+}
+
+/// Model names and the Rust expressions standing for them
+type Names = HashMap<String, String>;
+
+fn unsupported(what: String) -> BrianError {
+    BrianError::SimulationError(format!("Standalone mode does not support {}", what))
+}
+
+/// Rust literal for a float, exact to the last bit
+fn literal(x: f64) -> String {
+    if x.is_nan() {
+        "f64::NAN".into()
+    } else if x.is_infinite() {
+        if x > 0.0 { "f64::INFINITY".into() } else { "f64::NEG_INFINITY".into() }
+    } else if x < 0.0 {
+        format!("({:?}_f64)", x)
+    } else {
+        format!("{:?}_f64", x)
+    }
+}
+
+/// Local variable holding a model name
+fn local(name: &str) -> String {
+    format!("_{}", name)
+}
+
+/// Rust source for `e`. Operands are evaluated in the interpreter's order,
+/// so random draws line up.
+fn rust_expr(e: &Expr, names: &Names) -> Result<String> {
+    let sub = |e: &Expr| rust_expr(e, names);
+    Ok(match e {
+        Expr::Number(x) => literal(*x),
+        Expr::Name(name) => match names.get(name) {
+            Some(code) => code.clone(),
+            None => literal(expr::named_constant(name).ok_or_else(|| {
+                BrianError::EquationError(format!("Unknown identifier '{}'", name))
+            })?),
+        },
+        Expr::Neg(a) => format!("(-{})", sub(a)?),
+        Expr::Not(a) => format!("b2f({} == 0.0)", sub(a)?),
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (sub(a)?, sub(b)?);
+            match op {
+                BinOp::Add => format!("({} + {})", a, b),
+                BinOp::Sub => format!("({} - {})", a, b),
+                BinOp::Mul => format!("({} * {})", a, b),
+                BinOp::Div => format!("({} / {})", a, b),
+                BinOp::Mod => format!("{}.rem_euclid({})", a, b),
+                BinOp::Pow => format!("pow({}, {})", a, b),
+                BinOp::Lt => format!("b2f({} < {})", a, b),
+                BinOp::Le => format!("b2f({} <= {})", a, b),
+                BinOp::Gt => format!("b2f({} > {})", a, b),
+                BinOp::Ge => format!("b2f({} >= {})", a, b),
+                BinOp::Eq => format!("b2f({} == {})", a, b),
+                BinOp::Ne => format!("b2f({} != {})", a, b),
+                BinOp::And => format!("and({}, {})", a, b),
+                BinOp::Or => format!("or({}, {})", a, b),
+            }
+        }
+        Expr::Call(name, args) => {
+            let (f, arity) = Builtin::from_name(name)
+                .ok_or_else(|| unsupported(format!("function '{}'", name)))?;
+            if args.len() != arity {
+                return Err(BrianError::EquationError(format!(
+                    "Function '{}' takes {} argument(s), got {}",
+                    name, arity, args.len()
+                )));
+            }
+            let args = args.iter().map(sub).collect::<Result<Vec<_>>>()?;
+            let method = |m: &str| format!("{}.{}()", args[0], m);
+            match f {
+                Builtin::Rand => "rng.uniform()".into(),
+                Builtin::Randn => "rng.normal()".into(),
+                Builtin::Clip => format!("clip({}, {}, {})", args[0], args[1], args[2]),
+                Builtin::Min => format!("{}.min({})", args[0], args[1]),
+                Builtin::Max => format!("{}.max({})", args[0], args[1]),
+                Builtin::Sign => format!("sign({})", args[0]),
+                Builtin::Exp => method("exp"),
+                Builtin::Log => method("ln"),
+                Builtin::Log10 => method("log10"),
+                Builtin::Sqrt => method("sqrt"),
+                Builtin::Sin => method("sin"),
+                Builtin::Cos => method("cos"),
+                Builtin::Tan => method("tan"),
+                Builtin::Sinh => method("sinh"),
+                Builtin::Cosh => method("cosh"),
+                Builtin::Tanh => method("tanh"),
+                Builtin::Abs => method("abs"),
+                Builtin::Floor => method("floor"),
+                Builtin::Ceil => method("ceil"),
+                Builtin::Int => method("trunc"),
+            }
+        }
+    })
+}
+
+/// Rust statements for model code; every target must be in `writable`
+fn rust_statements(code: &[Statement], names: &Names, writable: &[String], out: &mut String, indent: &str) -> Result<()> {
+    for stmt in code {
+        if !writable.contains(&stmt.target) {
+            return Err(BrianError::EquationError(format!(
+                "Cannot assign to read-only variable '{}'",
+                stmt.target
+            )));
+        }
+        let op = match stmt.op {
+            AssignOp::Set => "=",
+            AssignOp::Add => "+=",
+            AssignOp::Sub => "-=",
+            AssignOp::Mul => "*=",
+            AssignOp::Div => "/=",
+        };
+        emit!(out, "{}{} {} {};", indent, names[&stmt.target], op, rust_expr(&stmt.expr, names)?);
+    }
+    Ok(())
+}
+
+fn parse_blocks(blocks: &[String]) -> Result<Vec<Statement>> {
+    Ok(blocks.iter()
+        .map(|block| expr::parse_statements(block))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Raw data files under `static_arrays/`
+#[derive(Default)]
+struct Arrays(Vec<(String, Vec<u8>)>);
+
+impl Arrays {
+    fn f64s(&mut self, name: &str, values: impl IntoIterator<Item = f64>) -> String {
+        let data = values.into_iter().flat_map(f64::to_le_bytes).collect();
+        self.0.push((format!("static_arrays/{}", name), data));
+        format!("load_f64(\"{}\")?", name)
+    }
+
+    fn indices(&mut self, name: &str, values: impl IntoIterator<Item = usize>) -> String {
+        let data = values.into_iter().flat_map(|i| (i as u64).to_le_bytes()).collect();
+        self.0.push((format!("static_arrays/{}", name), data));
+        format!("load_indices(\"{}\")?", name)
+    }
+}
+
+/// Generated neuron group
+#[derive(Debug, Clone)]
+struct GroupInfo {
+    field: String,
+    ty: String,
+    n: usize,
+    state: Vec<String>,
+}
+
+/// Generated code so far
+#[derive(Default)]
+struct Generator {
+    /// Type and impl definitions
+    items: String,
+    /// Fields of the generated `Network`
+    fields: String,
+    /// Their initializers in `Network::new`
+    init: String,
+    /// Start of `Network::step`: state monitors
+    record: String,
+    /// Rest of `Network::step`
+    step: String,
+    /// Body of `Network::write_results`
+    results: String,
+    arrays: Arrays,
+    /// Generated neuron groups, by name
+    groups: HashMap<String, GroupInfo>,
+    /// Local holding each spike source's spikes in `step`
+    spikes: HashMap<String, String>,
+}
+
+impl Generator {
+    fn neuron_group(&mut self, k: usize, group: &NeuronGroup) -> Result<()> {
+        let eqs = &group.equations;
+        eqs.check_units()?;
+        if !group.timed_arrays.is_empty() {
+            return Err(unsupported(format!("timed arrays (group '{}')", group.name)));
+        }
+        if !group.regular_operations.is_empty() {
+            return Err(unsupported(format!("run_regularly code (group '{}')", group.name)));
+        }
+        if !group.events.is_empty() {
+            return Err(unsupported(format!("custom events (group '{}')", group.name)));
+        }
+        if group.method == IntegrationMethod::ExactSolution {
+            return Err(unsupported(format!("exact integration (group '{}')", group.name)));
+        }
+
+        let state: Vec<String> = eqs.differential.iter().map(|eq| eq.variable.clone())
+            .chain(eqs.algebraic.iter().map(|eq| eq.variable.clone()))
+            .chain(eqs.per_neuron.iter().map(|decl| decl.variable.clone()))
+            .collect();
+
+        // Same resolution order as `CompiledEquations::compile`
+        let mut names = Names::new();
+        for name in ["t", "dt", "i", "N", NOT_REFRACTORY_SYMBOL] {
+            names.insert(name.into(), local(name));
+        }
+        let has_input = !state.iter().any(|v| v == INPUT_SYMBOL) && !eqs.parameters.contains_key(INPUT_SYMBOL);
+        if has_input {
+            names.insert(INPUT_SYMBOL.into(), local(INPUT_SYMBOL));
+        }
+        for (name, quantity) in &eqs.parameters {
+            names.insert(name.clone(), literal(quantity.to_internal()));
+        }
+        for name in &state {
+            names.insert(name.clone(), local(name));
+        }
+
+        let frozen = |eq: &crate::DifferentialEquation| -> Result<Expr> {
+            let e = expr::parse_expression(&eq.expression)?;
+            Ok(if eq.unless_refractory {
+                Expr::Binary(BinOp::Mul, Box::new(Expr::Name(NOT_REFRACTORY_SYMBOL.into())), Box::new(e))
+            } else {
+                e
+            })
+        };
+        let derivatives = eqs.differential.iter().map(frozen).collect::<Result<Vec<_>>>()?;
+        let algebraic = eqs.algebraic.iter()
+            .map(|eq| Ok((eq.variable.clone(), expr::parse_expression(&eq.expression)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let held: Vec<&str> = eqs.differential.iter()
+            .filter(|eq| eq.unless_refractory)
+            .map(|eq| eq.variable.as_str())
+            .collect();
+
+        let ty = format!("Group{}", k);
+        let field = format!("group{}", k);
+        let items = &mut self.items;
+        emit!(items, "/// Neuron group `{}`", group.name);
+        emit!(items, "pub struct {} {{", ty);
+        emit!(items, "    pub n: usize,");
+        emit!(items, "    pub input: Vec<f64>,");
+        emit!(items, "    pub refractory_until: Vec<f64>,");
+        for name in &state {
+            emit!(items, "    pub {}: Vec<f64>,", local(name));
+        }
+        emit!(items, "}}");
+        emit!(items);
+        emit!(items, "impl {} {{", ty);
+        emit!(items, "    fn update(&mut self, t: f64, rng: &mut Rng) -> Vec<usize> {{");
+        emit!(items, "        let mut spikes = vec![];");
+        emit!(items, "        for i in 0..self.n {{");
+        for name in &state {
+            emit!(items, "            let mut {} = self.{}[i];", local(name), local(name));
+        }
+        if has_input {
+            emit!(items, "            let mut {} = self.input[i];", local(INPUT_SYMBOL));
+        }
+        emit!(items, "            let _t = t;");
+        emit!(items, "            let _dt = DT;");
+        emit!(items, "            let _i = i as f64;");
+        emit!(items, "            let _N = self.n as f64;");
+        emit!(items, "            let mut refractory = t < self.refractory_until[i];");
+        if let Some(RefractorySpec::Condition(cond)) = &eqs.refractory {
+            let cond = rust_expr(&expr::parse_expression(cond)?, &names)?;
+            emit!(items, "            if refractory && {} == 0.0 {{", cond);
+            emit!(items, "                self.refractory_until[i] = t;");
+            emit!(items, "                refractory = false;");
+            emit!(items, "            }}");
+        }
+        emit!(items, "            let _not_refractory = if refractory {{ 0.0 }} else {{ 1.0 }};");
+        if !held.is_empty() {
+            let values: Vec<String> = held.iter().map(|v| local(v)).collect();
+            emit!(items, "            let held = [{}];", values.join(", "));
+        }
+
+        self.integrate(group.method, &eqs.differential, &derivatives, &algebraic, &names)?;
+        let items = &mut self.items;
+
+        if !held.is_empty() {
+            emit!(items, "            if refractory {{");
+            for (m, v) in held.iter().enumerate() {
+                emit!(items, "                {} = held[{}];", local(v), m);
+            }
+            for (var, e) in &algebraic {
+                emit!(items, "                {} = {};", local(var), rust_expr(e, &names)?);
+            }
+            emit!(items, "            }}");
+        }
+
+        if let Some(threshold) = &eqs.threshold {
+            let condition = rust_expr(&expr::parse_expression(&threshold.condition)?, &names)?;
+            emit!(items, "            if !refractory && {} != 0.0 {{", condition);
+            emit!(items, "                spikes.push(i);");
+            if let Some(reset) = &eqs.reset {
+                let mut writable = state.clone();
+                if has_input {
+                    writable.push(INPUT_SYMBOL.into());
+                }
+                rust_statements(&parse_blocks(&reset.equations)?, &names, &writable, items, "                ")?;
+            }
+            match &eqs.refractory {
+                Some(RefractorySpec::Duration(q)) => {
+                    emit!(items, "                self.refractory_until[i] = t + {};", literal(q.to_internal()));
+                }
+                Some(RefractorySpec::Condition(_)) => {
+                    emit!(items, "                self.refractory_until[i] = f64::INFINITY;");
+                }
+                None => {}
+            }
+            emit!(items, "            }}");
+        }
+
+        for name in &state {
+            emit!(items, "            self.{}[i] = {};", local(name), local(name));
+        }
+        emit!(items, "        }}");
+        emit!(items, "        spikes");
+        emit!(items, "    }}");
+        emit!(items, "}}");
+        emit!(items);
+
+        emit!(self.fields, "    pub {}: {},", field, ty);
+        let input = self.arrays.f64s(&format!("{}_input", field), group.input.iter().copied());
+        let refractory = self.arrays.f64s(&format!("{}_refractory_until", field), group.refractory_until.iter().copied());
+        emit!(self.init, "            {}: {} {{", field, ty);
+        emit!(self.init, "                n: {},", group.n);
+        emit!(self.init, "                input: {},", input);
+        emit!(self.init, "                refractory_until: {},", refractory);
+        for name in &state {
+            let values = match group.state.get(name) {
+                Some(column) => column.to_vec(),
+                None => vec![0.0; group.n],
+            };
+            let load = self.arrays.f64s(&format!("{}_{}", field, name), values);
+            emit!(self.init, "                {}: {},", local(name), load);
+        }
+        emit!(self.init, "            }},");
+
+        let spikes = format!("spikes_{}", field);
+        emit!(self.step, "        let {} = self.{}.update(t, &mut self.rng);", spikes, field);
+        self.spikes.insert(group.name.clone(), spikes);
+        self.groups.insert(group.name.clone(), GroupInfo { field, ty, n: group.n, state });
+        Ok(())
+    }
+
+    /// Advance the differential variables, then update the algebraic ones
+    fn integrate(
+        &mut self,
+        method: IntegrationMethod,
+        differential: &[crate::DifferentialEquation],
+        derivatives: &[Expr],
+        algebraic: &[(String, Expr)],
+        names: &Names,
+    ) -> Result<()> {
+        let items = &mut self.items;
+        let vars: Vec<String> = differential.iter().map(|eq| local(&eq.variable)).collect();
+        let n = vars.len();
+
+        if method == IntegrationMethod::ExponentialEuler {
+            // A and B with the algebraic variables substituted, all evaluated
+            // before any variable changes
+            let mut definitions = HashMap::new();
+            for (var, e) in algebraic {
+                definitions.insert(var.clone(), e.substitute(&definitions));
+            }
+            for (k, (eq, e)) in differential.iter().zip(derivatives).enumerate() {
+                let split = linear::affine(&e.substitute(&definitions), &[&eq.variable]).ok_or_else(|| {
+                    BrianError::EquationError(format!(
+                        "Equation for '{}' is not conditionally linear, cannot use exponential Euler",
+                        eq.variable
+                    ))
+                })?;
+                emit!(items, "            let a{} = {};", k, rust_expr(&split.offset, names)?);
+                emit!(items, "            let b{} = {};", k, rust_expr(&split.coeffs[0], names)?);
+            }
+            for (k, var) in vars.iter().enumerate() {
+                emit!(items, "            let rate = a{k} + b{k} * {var};");
+                emit!(items, "            {var} = if b{k} == 0.0 {{ {var} + rate * DT }} else {{ {var} + rate * (b{k} * DT).exp_m1() / b{k} }};");
+            }
+        } else {
+            emit!(items, "            let f = |{}_t: f64, rng: &mut Rng| -> [f64; {}] {{",
+                vars.iter().map(|v| format!("{}: f64, ", v)).collect::<String>(), n);
+            for (var, e) in algebraic {
+                emit!(items, "                let {} = {};", local(var), rust_expr(e, names)?);
+            }
+            let rhs = derivatives.iter().map(|e| rust_expr(e, names)).collect::<Result<Vec<_>>>()?;
+            emit!(items, "                [{}]", rhs.join(", "));
+            emit!(items, "            }};");
+
+            // Arguments `y0 + h*k` for each variable
+            let stage = |h: &str, k: &str| -> String {
+                (0..n).map(|j| format!("{} + {} * {}[{}], ", vars[j], h, k, j)).collect()
+            };
+            let set = |items: &mut String, h: &str, k: &str| {
+                for (j, var) in vars.iter().enumerate() {
+                    emit!(items, "            {} = {} + {} * {}[{}];", var, var, h, k, j);
+                }
+            };
+            let y0: String = vars.iter().map(|v| format!("{}, ", v)).collect();
+            match method {
+                IntegrationMethod::RungeKutta2 => {
+                    emit!(items, "            let k1 = f({}t, rng);", y0);
+                    emit!(items, "            let k2 = f({}t + 0.5 * DT, rng);", stage("(0.5 * DT)", "k1"));
+                    set(items, "DT", "k2");
+                }
+                IntegrationMethod::Heun => {
+                    emit!(items, "            let k1 = f({}t, rng);", y0);
+                    emit!(items, "            let k2 = f({}t + DT, rng);", stage("DT", "k1"));
+                    emit!(items, "            let k: [f64; {}] = std::array::from_fn(|j| 0.5 * (k1[j] + k2[j]));", n);
+                    set(items, "DT", "k");
+                }
+                IntegrationMethod::RungeKutta4 => {
+                    emit!(items, "            let k1 = f({}t, rng);", y0);
+                    emit!(items, "            let k2 = f({}t + 0.5 * DT, rng);", stage("(0.5 * DT)", "k1"));
+                    emit!(items, "            let k3 = f({}t + 0.5 * DT, rng);", stage("(0.5 * DT)", "k2"));
+                    emit!(items, "            let k4 = f({}t + DT, rng);", stage("DT", "k3"));
+                    emit!(items, "            let k: [f64; {}] = std::array::from_fn(|j| (k1[j] + 2.0 * k2[j] + 2.0 * k3[j] + k4[j]) / 6.0);", n);
+                    set(items, "DT", "k");
+                }
+                _ => {
+                    emit!(items, "            let k1 = f({}t, rng);", y0);
+                    set(items, "DT", "k1");
+                }
+            }
+        }
+
+        for (var, e) in algebraic {
+            emit!(items, "            {} = {};", local(var), rust_expr(e, names)?);
+        }
+        Ok(())
+    }
+
+    /// Spike sources without equations: Poisson groups and generators
+    fn sources(&mut self, network: &Network) {
+        let mut poisson: Vec<_> = network.poisson_groups.values().collect();
+        poisson.sort_by(|a, b| a.name.cmp(&b.name));
+        for (k, group) in poisson.into_iter().enumerate() {
+            let field = format!("poisson{}", k);
+            let rates = self.arrays.f64s(&format!("{}_rates", field), group.rates.iter().copied());
+            emit!(self.fields, "    /// Poisson group `{}` (Hz)", group.name);
+            emit!(self.fields, "    pub {}: Vec<f64>,", field);
+            emit!(self.init, "            {}: {},", field, rates);
+            let spikes = format!("spikes_{}", field);
+            emit!(self.step, "        let {}: Vec<usize> = (0..self.{}.len())", spikes, field);
+            emit!(self.step, "            .filter(|&i| self.rng.uniform() < self.{}[i] * DT / 1000.0)", field);
+            emit!(self.step, "            .collect();");
+            self.spikes.insert(group.name.clone(), spikes);
+        }
+
+        let mut generators: Vec<_> = network.spike_generators.values().collect();
+        generators.sort_by(|a, b| a.name.cmp(&b.name));
+        for (k, group) in generators.into_iter().enumerate() {
+            let field = format!("generator{}", k);
+            let indices = self.arrays.indices(&format!("{}_indices", field), group.spike_times.iter().map(|s| s.0));
+            let times = self.arrays.f64s(&format!("{}_times", field), group.spike_times.iter().map(|s| s.1));
+            emit!(self.fields, "    /// Spike generator `{}`: (neuron, time in ms)", group.name);
+            emit!(self.fields, "    pub {}: Vec<(usize, f64)>,", field);
+            emit!(self.init, "            {}: {}.into_iter().zip({}).collect(),", field, indices, times);
+            let spikes = format!("spikes_{}", field);
+            emit!(self.step, "        let {}: Vec<usize> = self.{}.iter()", spikes, field);
+            emit!(self.step, "            .filter(|&&(_, time)| time >= t && time < t + DT)");
+            emit!(self.step, "            .map(|&(i, _)| i)");
+            emit!(self.step, "            .collect();");
+            self.spikes.insert(group.name.clone(), spikes);
+        }
+    }
+
+    fn synapses(&mut self, k: usize, syn: &Synapses, dt: f64) -> Result<()> {
+        if syn.dynamics.is_some() {
+            return Err(unsupported(format!("synaptic equations (synapses '{}')", syn.name)));
+        }
+        if syn.plasticity.is_some() {
+            return Err(unsupported(format!("STDP rules (synapses '{}')", syn.name)));
+        }
+        if syn.on_pre.is_empty() && !matches!(syn.model, SynapseModel::Delta { .. }) {
+            return Err(unsupported(format!(
+                "the built-in {:?} model (synapses '{}'); use on_pre statements",
+                syn.model, syn.name
+            )));
+        }
+        if syn.weights.len() != syn.connections.len() || syn.delays.len() != syn.connections.len() {
+            return Err(BrianError::SimulationError(format!(
+                "Synapses '{}': expected {} weights and delays",
+                syn.name,
+                syn.connections.len()
+            )));
+        }
+        let unknown = |group: &str| BrianError::SimulationError(format!(
+            "Synapses '{}': unknown group '{}'", syn.name, group
+        ));
+        let target = self.groups.get(&syn.target).cloned().ok_or_else(|| unknown(&syn.target))?;
+        let fired = self.spikes.get(&syn.source).cloned().ok_or_else(|| unknown(&syn.source))?;
+        let post_fired = self.spikes[&syn.target].clone();
+        let recurrent = syn.source == syn.target;
+        let source = self.groups.get(&syn.source).filter(|_| !recurrent).cloned();
+        let post_vars = &target.state;
+        let pre_vars = match &source {
+            Some(source) => source.state.clone(),
+            None if recurrent => target.state.clone(),
+            None => vec![],
+        };
+
+        let mut delays = Vec::with_capacity(syn.delays.len());
+        for &delay in &syn.delays {
+            if !delay.is_finite() || delay < 0.0 {
+                return Err(BrianError::SimulationError(format!("Invalid delay: {} ms", delay)));
+            }
+            delays.push((delay / dt).round() as usize);
+        }
+        let max_delay = delays.iter().copied().max().unwrap_or(0);
+
+        // Same resolution order as `Pathway::compile`
+        let mut names = Names::new();
+        for (name, code) in [("t", "t"), ("dt", "DT"), ("i", "(i as f64)"), ("j", "(j as f64)")] {
+            names.insert(name.into(), code.into());
+        }
+        for (name, quantity) in &syn.parameters {
+            names.insert(name.clone(), literal(quantity.to_internal()));
+        }
+        for var in &pre_vars {
+            names.insert(format!("{}_pre", var), local(&format!("{}_pre", var)));
+        }
+        for var in post_vars {
+            let name = local(&format!("{}_post", var));
+            names.insert(var.clone(), name.clone());
+            names.insert(format!("{}_post", var), name);
+        }
+        names.insert("w".into(), local("w"));
+        let mut writable = vec!["w".to_string()];
+        writable.extend(post_vars.iter().cloned());
+        writable.extend(post_vars.iter().map(|v| format!("{}_post", v)));
+
+        let ty = format!("Synapses{}", k);
+        let field = format!("synapses{}", k);
+        let items = &mut self.items;
+        emit!(items, "/// Synapses `{}` from `{}` to `{}`", syn.name, syn.source, syn.target);
+        emit!(items, "pub struct {} {{", ty);
+        emit!(items, "    pub pre: Vec<usize>,");
+        emit!(items, "    pub post: Vec<usize>,");
+        emit!(items, "    pub _w: Vec<f64>,");
+        emit!(items, "    /// Delay of each synapse in steps");
+        emit!(items, "    pub delays: Vec<usize>,");
+        emit!(items, "    by_source: Vec<Vec<usize>>,");
+        emit!(items, "    post_index: Vec<Vec<usize>>,");
+        emit!(items, "    slots: Vec<Vec<usize>>,");
+        emit!(items, "    offset: usize,");
+        emit!(items, "}}");
+        emit!(items);
+        emit!(items, "impl {} {{", ty);
+        emit!(items, "    fn new(pre: Vec<usize>, post: Vec<usize>, w: Vec<f64>, delays: Vec<usize>, n_post: usize) -> Self {{");
+        emit!(items, "        let mut by_source = vec![vec![]; pre.iter().map(|&i| i + 1).max().unwrap_or(0)];");
+        emit!(items, "        let mut post_index = vec![vec![]; n_post];");
+        emit!(items, "        for syn in 0..pre.len() {{");
+        emit!(items, "            by_source[pre[syn]].push(syn);");
+        emit!(items, "            if post[syn] < n_post {{");
+        emit!(items, "                post_index[post[syn]].push(syn);");
+        emit!(items, "            }}");
+        emit!(items, "        }}");
+        emit!(items, "        Self {{ pre, post, _w: w, delays, by_source, post_index, slots: vec![vec![]; {}], offset: 0 }}", max_delay + 1);
+        emit!(items, "    }}");
+        emit!(items);
+        let source_arg = source.as_ref().map_or(String::new(), |s| format!(", source: &{}", s.ty));
+        emit!(items, "    fn propagate(&mut self, fired: &[usize], post_fired: &[usize], t: f64, target: &mut {}{}, rng: &mut Rng) {{",
+            target.ty, source_arg);
+        emit!(items, "        let len = self.slots.len();");
+        emit!(items, "        for &i in fired {{");
+        emit!(items, "            if let Some(synapses) = self.by_source.get(i) {{");
+        emit!(items, "                for &syn in synapses {{");
+        emit!(items, "                    self.slots[(self.offset + self.delays[syn]) % len].push(syn);");
+        emit!(items, "                }}");
+        emit!(items, "            }}");
+        emit!(items, "        }}");
+        emit!(items, "        let due = std::mem::take(&mut self.slots[self.offset]);");
+        emit!(items, "        self.offset = (self.offset + 1) % len;");
+
+        let pathway = |items: &mut String, code: &[Statement]| -> Result<()> {
+            let mut used: Vec<String> = vec![];
+            for stmt in code {
+                for name in std::iter::once(stmt.target.clone()).chain(stmt.expr.names()) {
+                    if let Some(local) = names.get(&name) {
+                        if local.starts_with('_') && !used.contains(local) {
+                            used.push(local.clone());
+                        }
+                    }
+                }
+            }
+            let pre_state = if recurrent { "target" } else { "source" };
+            let load = |local: &str| -> String {
+                if local == "_w" {
+                    "self._w[syn]".into()
+                } else if let Some(var) = local.strip_suffix("_post") {
+                    format!("target.{}[j]", var)
+                } else {
+                    format!("{}.{}[i]", pre_state, local.strip_suffix("_pre").unwrap_or(local))
+                }
+            };
+            emit!(items, "            let (i, j) = (self.pre[syn], self.post[syn]);");
+            for local in &used {
+                emit!(items, "            let mut {} = {};", local, load(local));
+            }
+            rust_statements(code, &names, &writable, items, "            ")?;
+            let mut written: Vec<&String> = vec![];
+            for stmt in code {
+                let local = &names[&stmt.target];
+                if !written.contains(&local) {
+                    emit!(items, "            {} = {};", load(local), local);
+                    written.push(local);
+                }
+            }
+            Ok(())
+        };
+
+        if syn.on_pre.is_empty() {
+            if !post_vars.iter().any(|v| v == MEMBRANE_SYMBOL) {
+                return Err(BrianError::SimulationError(format!(
+                    "Synapses '{}': target '{}' has no variable '{}'",
+                    syn.name, syn.target, MEMBRANE_SYMBOL
+                )));
+            }
+            emit!(items, "        for &syn in &due {{");
+            emit!(items, "            let j = self.post[syn];");
+            emit!(items, "            if j < target.n {{");
+            emit!(items, "                target.{}[j] += self._w[syn];", local(MEMBRANE_SYMBOL));
+            emit!(items, "            }}");
+            emit!(items, "        }}");
+        } else {
+            emit!(items, "        for &syn in &due {{");
+            pathway(items, &parse_blocks(&syn.on_pre)?)?;
+            emit!(items, "        }}");
+        }
+        if !syn.on_post.is_empty() {
+            emit!(items, "        for &j in post_fired {{");
+            emit!(items, "            for &syn in self.post_index.get(j).map_or(&[][..], Vec::as_slice) {{");
+            pathway(items, &parse_blocks(&syn.on_post)?)?;
+            emit!(items, "            }}");
+            emit!(items, "        }}");
+        }
+        emit!(items, "    }}");
+        emit!(items, "}}");
+        emit!(items);
+
+        let pre = self.arrays.indices(&format!("{}_pre", field), syn.connections.iter().map(|c| c.0));
+        let post = self.arrays.indices(&format!("{}_post", field), syn.connections.iter().map(|c| c.1));
+        let w = self.arrays.f64s(&format!("{}_w", field), syn.weights.iter().copied());
+        let delays = self.arrays.indices(&format!("{}_delays", field), delays);
+        emit!(self.fields, "    pub {}: {},", field, ty);
+        emit!(self.init, "            {}: {}::new({}, {}, {}, {}, {}),", field, ty, pre, post, w, delays, target.n);
+        let source_param = source.as_ref().map_or(String::new(), |s| format!(", &self.{}", s.field));
+        emit!(self.step, "        self.{}.propagate(&{}, &{}, t, &mut self.{}{}, &mut self.rng);",
+            field, fired, post_fired, target.field, source_param);
+        Ok(())
+    }
+}
+
+impl Generator {
+    fn monitors(&mut self, network: &Network) {
+        let mut states: Vec<_> = network.state_monitors.iter().collect();
+        states.sort_by(|a, b| a.0.cmp(b.0));
+        for (k, (_, monitor)) in states.into_iter().enumerate() {
+            let Some(group) = self.groups.get(&monitor.source) else { continue };
+            let vars: Vec<&String> = monitor.variables.iter().filter(|v| group.state.contains(v)).collect();
+            let field = format!("state_monitor{}", k);
+            let indices: Vec<String> = monitor.record_indices.iter().map(usize::to_string).collect();
+            emit!(self.fields, "    /// `{}` of `{}`", vars.iter().map(|v| v.as_str()).collect::<Vec<_>>().join(", "), monitor.source);
+            emit!(self.fields, "    pub {}: StateMonitor,", field);
+            emit!(self.init, "            {}: StateMonitor::new({}, vec![{}], {}),", field, literal(monitor.dt), indices.join(", "), vars.len());
+            emit!(self.record, "        if self.{}.is_due(t) {{", field);
+            emit!(self.record, "            self.{}.times.push(t);", field);
+            for (m, var) in vars.iter().enumerate() {
+                emit!(self.record, "            self.{}.push({}, &self.{}.{});", field, m, group.field, local(var));
+                emit!(self.results, "        self.{}.write(&dir.join(\"state_{}_{}.csv\"), {})?;", field, monitor.source, var, m);
+            }
+            emit!(self.record, "        }}");
+        }
+
+        let mut spikes: Vec<_> = network.spike_monitors.values().collect();
+        spikes.sort_by(|a, b| a.source.cmp(&b.source));
+        for (k, monitor) in spikes.into_iter().enumerate() {
+            let Some(fired) = self.spikes.get(&monitor.source) else { continue };
+            let field = format!("spike_monitor{}", k);
+            emit!(self.fields, "    /// Spikes of `{}`: (neuron, time in ms)", monitor.source);
+            emit!(self.fields, "    pub {}: Vec<(usize, f64)>,", field);
+            emit!(self.init, "            {}: vec![],", field);
+            emit!(self.step, "        self.{}.extend({}.iter().map(|&i| (i, t)));", field, fired);
+            emit!(self.results, "        write_spikes(&dir.join(\"spikes_{}.csv\"), &self.{})?;", monitor.source, field);
+        }
+
+        let mut rates: Vec<_> = network.rate_monitors.values().collect();
+        rates.sort_by(|a, b| a.source.cmp(&b.source));
+        for (k, monitor) in rates.into_iter().enumerate() {
+            let Some(fired) = self.spikes.get(&monitor.source) else { continue };
+            let field = format!("rate_monitor{}", k);
+            emit!(self.fields, "    /// Population rate of `{}`", monitor.source);
+            emit!(self.fields, "    pub {}: RateMonitor,", field);
+            emit!(self.init, "            {}: RateMonitor::new({}, {}),", field, monitor.n, literal(monitor.bin_size));
+            emit!(self.step, "        self.{}.record(t, {}.len());", field, fired);
+            emit!(self.results, "        self.{}.write(&dir.join(\"rate_{}.csv\"))?;", field, monitor.source);
+        }
+    }
+}
+
+/// The shared random number generator without its serde derives and
+/// tests, so generated projects draw the same sequences
+fn random_source() -> String {
+    let source = include_str!("../../oldies-core/src/random.rs");
+    source.split("\n#[cfg(test)]").next().unwrap_or(source)
+        .replace("use serde::{Deserialize, Serialize};\n\n", "")
+        .replace(", Serialize, Deserialize)]", ")]")
+}
+
+/// Helpers shared by every generated project
+const RUNTIME: &str = r#"fn b2f(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
+
+fn pow(a: f64, b: f64) -> f64 {
+    if b == 2.0 { a * a } else { a.powf(b) }
+}
+
+fn and(a: f64, b: f64) -> f64 {
+    b2f(a != 0.0 && b != 0.0)
+}
+
+fn or(a: f64, b: f64) -> f64 {
+    b2f(a != 0.0 || b != 0.0)
+}
+
+fn sign(x: f64) -> f64 {
+    if x > 0.0 { 1.0 } else if x < 0.0 { -1.0 } else { 0.0 }
+}
+
+fn clip(x: f64, lo: f64, hi: f64) -> f64 {
+    x.max(lo).min(hi)
+}
+
+fn read_array(name: &str) -> io::Result<Vec<[u8; 8]>> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("static_arrays").join(name);
+    let bytes = std::fs::read(path)?;
+    Ok(bytes.chunks_exact(8).map(|b| b.try_into().unwrap()).collect())
+}
+
+fn load_f64(name: &str) -> io::Result<Vec<f64>> {
+    Ok(read_array(name)?.into_iter().map(f64::from_le_bytes).collect())
+}
+
+fn load_indices(name: &str) -> io::Result<Vec<usize>> {
+    Ok(read_array(name)?.into_iter().map(|b| u64::from_le_bytes(b) as usize).collect())
+}
+
+fn write_spikes(path: &Path, spikes: &[(usize, f64)]) -> io::Result<()> {
+    let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, "i,t")?;
+    for (i, t) in spikes {
+        writeln!(file, "{},{}", i, t)?;
+    }
+    file.flush()
+}
+
+/// Recorded variables of a neuron group
+pub struct StateMonitor {
+    pub dt: f64,
+    pub indices: Vec<usize>,
+    pub times: Vec<f64>,
+    /// values[variable][index][sample]
+    pub values: Vec<Vec<Vec<f64>>>,
+}
+
+impl StateMonitor {
+    fn new(dt: f64, indices: Vec<usize>, n_vars: usize) -> Self {
+        let values = vec![vec![vec![]; indices.len()]; n_vars];
+        Self { dt, indices, times: vec![], values }
+    }
+
+    fn is_due(&self, t: f64) -> bool {
+        self.times.last().is_none_or(|&last| t + 1e-6 * self.dt >= last + self.dt)
+    }
+
+    fn push(&mut self, var: usize, values: &[f64]) {
+        for (k, &idx) in self.indices.iter().enumerate() {
+            if idx < values.len() {
+                self.values[var][k].push(values[idx]);
+            }
+        }
+    }
+
+    fn write(&self, path: &Path, var: usize) -> io::Result<()> {
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        let header: Vec<String> = self.indices.iter().map(|i| i.to_string()).collect();
+        writeln!(file, "t,{}", header.join(","))?;
+        for (s, t) in self.times.iter().enumerate() {
+            let row: Vec<String> = self.values[var].iter()
+                .map(|samples| samples.get(s).map_or(String::new(), |x| x.to_string()))
+                .collect();
+            writeln!(file, "{},{}", t, row.join(","))?;
+        }
+        file.flush()
+    }
+}
+
+/// Population rate in bins
+pub struct RateMonitor {
+    pub n: usize,
+    pub bin_size: f64,
+    pub times: Vec<f64>,
+    pub rates: Vec<f64>,
+    bin_start: Option<f64>,
+    bin_spikes: usize,
+}
+
+impl RateMonitor {
+    fn new(n: usize, bin_size: f64) -> Self {
+        Self { n, bin_size, times: vec![], rates: vec![], bin_start: None, bin_spikes: 0 }
+    }
+
+    fn record(&mut self, t: f64, n_spikes: usize) {
+        let start = *self.bin_start.get_or_insert(t);
+        self.bin_spikes += n_spikes;
+        if t + DT >= start + self.bin_size - 1e-6 * DT {
+            let width = t + DT - start;
+            let rate = if self.n == 0 { 0.0 } else { self.bin_spikes as f64 / (self.n as f64 * width / 1000.0) };
+            self.times.push(start);
+            self.rates.push(rate);
+            self.bin_start = None;
+            self.bin_spikes = 0;
+        }
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(file, "t,rate")?;
+        for (t, rate) in self.times.iter().zip(&self.rates) {
+            writeln!(file, "{},{}", t, rate)?;
+        }
+        file.flush()
+    }
+}
+"#;
+
+/// Build the project for `network`, simulating `duration` ms from its
+/// current state
+pub fn generate(network: &Network, name: &str, duration: f64) -> Result<Project> {
+    if !network.operations.is_empty() {
+        return Err(unsupported("network operations".into()));
+    }
+    if !network.spatial_neurons.is_empty() {
+        return Err(unsupported("spatial neurons".into()));
+    }
+    if !network.rate_groups.is_empty() {
+        return Err(unsupported("rate groups".into()));
+    }
+    if !network.event_monitors.is_empty() {
+        return Err(unsupported("event monitors".into()));
+    }
+    let clocks = network.neuron_groups.values().map(|g| (&g.name, g.dt))
+        .chain(network.synapses.values().map(|s| (&s.name, s.dt)))
+        .chain(network.poisson_groups.values().map(|g| (&g.name, g.dt)))
+        .chain(network.spike_generators.values().map(|g| (&g.name, g.dt)));
+    for (owner, dt) in clocks {
+        if dt.is_some() {
+            return Err(unsupported(format!("per-object clocks ('{}')", owner)));
+        }
+    }
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(BrianError::SimulationError(format!("Invalid project name: '{}'", name)));
+    }
+
+    let mut gen = Generator::default();
+    let mut groups: Vec<_> = network.neuron_groups.values().collect();
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    for (k, group) in groups.into_iter().enumerate() {
+        gen.neuron_group(k, group)?;
+    }
+    gen.sources(network);
+
+    let mut synapses: Vec<_> = network.synapses.values().collect();
+    synapses.sort_by(|a, b| a.name.cmp(&b.name));
+    for (k, syn) in synapses.into_iter().enumerate() {
+        gen.synapses(k, syn, network.dt)?;
+    }
+
+    for input in &network.poisson_inputs {
+        let target = gen.groups.get(&input.target).ok_or_else(|| {
+            BrianError::SimulationError(format!("PoissonInput: unknown target group '{}'", input.target))
+        })?;
+        if !target.state.contains(&input.variable) {
+            return Err(BrianError::SimulationError(format!(
+                "PoissonInput: unknown variable '{}' in group '{}'",
+                input.variable, input.target
+            )));
+        }
+        emit!(gen.step, "        let p = {} * DT / 1000.0;", literal(input.rate));
+        emit!(gen.step, "        for x in self.{}.{}.iter_mut() {{", target.field, local(&input.variable));
+        emit!(gen.step, "            *x += {} * self.rng.binomial({}, p) as f64;", literal(input.weight), input.n);
+        emit!(gen.step, "        }}");
+    }
+
+    gen.monitors(network);
+
+    let steps = (duration / network.dt).ceil() as u64;
+    let (state, cached) = network.rng.state();
+    let cached = cached.map_or("None".to_string(), |x| format!("Some({})", literal(x)));
+    let mut lib = String::new();
+    emit!(lib, "//! Network `{}` generated by oldies-brian in standalone mode", name);
+    emit!(lib);
+    emit!(lib, "#![allow(unused, non_snake_case, clippy::all)]");
+    emit!(lib);
+    emit!(lib, "pub mod random;");
+    emit!(lib);
+    emit!(lib, "use random::Rng;");
+    emit!(lib, "use std::io::{{self, Write}};");
+    emit!(lib, "use std::path::Path;");
+    emit!(lib);
+    emit!(lib, "/// Time step (ms)");
+    emit!(lib, "pub const DT: f64 = {};", literal(network.dt));
+    emit!(lib, "/// Steps in the requested run");
+    emit!(lib, "pub const N_STEPS: u64 = {};", steps);
+    emit!(lib);
+    lib.push_str(RUNTIME);
+    emit!(lib);
+    lib.push_str(&gen.items);
+    emit!(lib, "/// The whole network");
+    emit!(lib, "pub struct Network {{");
+    lib.push_str(&gen.fields);
+    emit!(lib, "    /// Current time (ms)");
+    emit!(lib, "    pub t: f64,");
+    emit!(lib, "    pub rng: Rng,");
+    emit!(lib, "}}");
+    emit!(lib);
+    emit!(lib, "impl Network {{");
+    emit!(lib, "    /// Load the network in its state at generation time");
+    emit!(lib, "    pub fn new() -> io::Result<Self> {{");
+    emit!(lib, "        Ok(Self {{");
+    lib.push_str(&gen.init);
+    emit!(lib, "            t: {},", literal(network.t));
+    emit!(lib, "            rng: Rng::from_state({}, {}),", state, cached);
+    emit!(lib, "        }})");
+    emit!(lib, "    }}");
+    emit!(lib);
+    emit!(lib, "    pub fn run(&mut self, steps: u64) {{");
+    emit!(lib, "        for _ in 0..steps {{");
+    emit!(lib, "            self.step();");
+    emit!(lib, "        }}");
+    emit!(lib, "    }}");
+    emit!(lib);
+    emit!(lib, "    pub fn step(&mut self) {{");
+    emit!(lib, "        let t = self.t;");
+    lib.push_str(&gen.record);
+    lib.push_str(&gen.step);
+    emit!(lib, "        self.t += DT;");
+    emit!(lib, "    }}");
+    emit!(lib);
+    emit!(lib, "    /// Write the monitors as CSV files to `dir`");
+    emit!(lib, "    pub fn write_results(&self, dir: &Path) -> io::Result<()> {{");
+    emit!(lib, "        std::fs::create_dir_all(dir)?;");
+    lib.push_str(&gen.results);
+    emit!(lib, "        Ok(())");
+    emit!(lib, "    }}");
+    emit!(lib, "}}");
+
+    let mut main = String::new();
+    emit!(main, "use std::path::PathBuf;");
+    emit!(main);
+    emit!(main, "fn main() -> std::io::Result<()> {{");
+    emit!(main, "    let dir = std::env::args().nth(1).map_or_else(|| PathBuf::from(\"results\"), PathBuf::from);");
+    emit!(main, "    let mut network = {}::Network::new()?;", name);
+    emit!(main, "    let start = std::time::Instant::now();");
+    emit!(main, "    network.run({}::N_STEPS);", name);
+    emit!(main, "    eprintln!(\"Simulated {{}} steps in {{:.3}} s\", {}::N_STEPS, start.elapsed().as_secs_f64());", name);
+    emit!(main, "    network.write_results(&dir)");
+    emit!(main, "}}");
+
+    let manifest = format!(
+        "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n\n[profile.release]\nopt-level = 3\ncodegen-units = 1\n",
+        name
+    );
+
+    let mut files = vec![
+        ("Cargo.toml".to_string(), manifest.into_bytes()),
+        ("src/lib.rs".to_string(), lib.into_bytes()),
+        ("src/main.rs".to_string(), main.into_bytes()),
+        ("src/random.rs".to_string(), random_source().into_bytes()),
+    ];
+    files.append(&mut gen.arrays.0);
+    Ok(Project { name, files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        LIFNeuron, NeuronGroup, PoissonGroup, PopulationRateMonitor, SpikeMonitor, StateMonitor,
+    };
+    use ndarray::Array1;
+
+    /// LIF neurons driven by a constant current and Poisson input, with
+    /// recurrent inhibition. Only the Poisson group draws random numbers.
+    fn network() -> Network {
+        let mut net = Network::new(0.1);
+        net.seed(3);
+        let mut group = NeuronGroup::new("G", 20, LIFNeuron::default().to_equations());
+        group.set_initial("v", Array1::linspace(-70.0, -55.0, 20)).unwrap();
+        group.input.fill(1.8);
+        net.add_neuron_group(group);
+        net.add_poisson_group(PoissonGroup::new("P", 50, 40.0));
+
+        let mut input = Synapses::new("S", "P", "G", SynapseModel::Delta { weight: 0.0 });
+        input.connect_random(50, 20, 0.3, 1.5, 1.0, &mut net.rng);
+        input.on_pre = vec!["v_post += w * (1 + 0.5 * (i % 2))".into()];
+        net.add_synapses(input);
+        let mut recurrent = Synapses::new("R", "G", "G", SynapseModel::Delta { weight: 0.0 });
+        recurrent.connect_all_to_all(20, 20, -0.3, 2.0);
+        net.add_synapses(recurrent);
+
+        net.add_spike_monitor(SpikeMonitor::new("G", 20));
+        net.add_state_monitor(StateMonitor::new("G", &["v"], &[0, 7], 1.0));
+        net.add_rate_monitor(PopulationRateMonitor::new("G", 20, 5.0));
+        net
+    }
+
+    #[test]
+    fn test_generate() {
+        let net = network();
+        let project = generate(&net, "small-net", 100.0).unwrap();
+        assert_eq!(project.name, "small_net");
+        let lib = String::from_utf8(project.file("src/lib.rs").unwrap().to_vec()).unwrap();
+        assert!(lib.contains("pub const N_STEPS: u64 = 1000;"));
+        assert!(lib.contains("pub struct Group0"));
+        assert!(lib.contains("fn propagate(&mut self, fired: &[usize], post_fired: &[usize], t: f64, target: &mut Group0, rng: &mut Rng)"));
+        assert!(lib.contains("_v_post += (_w * (1.0_f64 + (0.5_f64 * (i as f64).rem_euclid(2.0_f64))));"));
+        assert_eq!(project.file("static_arrays/group0_v").unwrap().len(), 20 * 8);
+        assert_eq!(project.file("static_arrays/synapses0_pre").unwrap().len(), 400 * 8);
+        let random = String::from_utf8(project.file("src/random.rs").unwrap().to_vec()).unwrap();
+        assert!(!random.contains("serde"));
+
+        let mut exact = network();
+        exact.neuron_groups.get_mut("G").unwrap().method = IntegrationMethod::ExactSolution;
+        assert!(generate(&exact, "net", 1.0).is_err());
+        let mut operation = network();
+        operation.add_operation(|_| {}, None);
+        assert!(generate(&operation, "net", 1.0).is_err());
+        let mut conductance = network();
+        conductance.add_synapses(Synapses::new("X", "P", "G", SynapseModel::Exponential { weight: 1.0, tau: 5.0 }));
+        assert!(generate(&conductance, "net", 1.0).is_err());
+    }
+
+    /// Builds and runs the generated project with cargo
+    #[test]
+    #[ignore]
+    fn test_standalone_matches_interpreter() {
+        let mut net = network();
+        let project = generate(&net, "standalone_test", 100.0).unwrap();
+        let dir = std::env::temp_dir().join("oldies_brian_standalone_test");
+        project.write(&dir).unwrap();
+        let status = std::process::Command::new(env!("CARGO"))
+            .args(["run", "--release", "--quiet", "--", "results"])
+            .current_dir(&dir)
+            .env_remove("CARGO_TARGET_DIR")
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        net.run(100.0).unwrap();
+        let expected: String = net.spike_monitors["G"].spikes.iter()
+            .map(|(i, t)| format!("{},{}\n", i, t))
+            .collect();
+        assert!(expected.lines().count() > 20);
+        let spikes = std::fs::read_to_string(dir.join("results/spikes_G.csv")).unwrap();
+        assert_eq!(spikes, format!("i,t\n{}", expected));
+    }
+}
@@ -0,0 +1,335 @@
+//! SWC morphology files
+//!
+//! SWC is the plain-text format of NeuroMorpho.org reconstructions, one
+//! sample point per line:
+//!
+//! ```text
+//! # id type x y z radius parent
+//! 1 1 0.0 0.0 0.0 10.0 -1
+//! 2 3 0.0 15.0 0.0 1.0 1
+//! ```
+//!
+//! Coordinates and radii are in um and the root has parent `-1`. As in
+//! Brian's `Morphology.from_swc`, unbranched runs of points of the same type
+//! become sections, and every point becomes a cylinder reaching back to its
+//! parent point. Sections are named after the point type and numbered in
+//! depth-first order (`dend[0]`, `dend[1]`, `axon[0]`), so branches can be
+//! looked up with [`Morphology::section`] and walked with
+//! [`Morphology::children`].
+//!
+//! A soma given as a single point, or in the three-point NeuroMorpho
+//! convention, becomes a sphere; a soma outline traced as a chain of points
+//! becomes a cylinder section.
+
+use crate::spatial::{Compartment, Morphology, Section, Shape};
+use crate::{BrianError, Result};
+use std::collections::HashMap;
+
+/// SWC type of soma points
+const SOMA: u32 = 1;
+
+/// Sample point, with the parent as an index into the point list
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    kind: u32,
+    position: [f64; 3],
+    radius: f64,
+    parent: Option<usize>,
+}
+
+/// Section name prefix for an SWC point type
+fn type_name(kind: u32) -> &'static str {
+    match kind {
+        0 => "undefined",
+        1 => "soma",
+        2 => "axon",
+        3 => "dend",
+        4 => "apic",
+        _ => "custom",
+    }
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a.iter().zip(&b).map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt()
+}
+
+/// Parse the sample points; parents must be listed before their children
+fn parse_points(text: &str) -> Result<Vec<Point>> {
+    let mut index: HashMap<i64, usize> = HashMap::new();
+    let mut points = vec![];
+
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |what: String| BrianError::ParseError(format!("SWC line {}: {}", lineno + 1, what));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 7 {
+            return Err(error(format!("expected 7 fields, got {}", fields.len())));
+        }
+        let number = |k: usize| {
+            fields[k].parse::<f64>().map_err(|_| error(format!("invalid number '{}'", fields[k])))
+        };
+        let integer = |k: usize| {
+            number(k).and_then(|x| if x.fract() == 0.0 {
+                Ok(x as i64)
+            } else {
+                Err(error(format!("invalid index '{}'", fields[k])))
+            })
+        };
+
+        let id = integer(0)?;
+        let kind = integer(1)?.max(0) as u32;
+        let position = [number(2)?, number(3)?, number(4)?];
+        let radius = number(5)?;
+        if !(radius > 0.0 && radius.is_finite()) {
+            return Err(error(format!("radius must be positive, got {}", radius)));
+        }
+        let parent = match integer(6)? {
+            p if p < 0 => None,
+            p => Some(*index.get(&p).ok_or_else(|| error(format!("parent {} is not defined before point {}", p, id)))?),
+        };
+        if index.insert(id, points.len()).is_some() {
+            return Err(error(format!("duplicate point {}", id)));
+        }
+        points.push(Point { kind, position, radius, parent });
+    }
+
+    match points.iter().filter(|p| p.parent.is_none()).count() {
+        1 => Ok(points),
+        n => Err(BrianError::ParseError(format!(
+            "SWC morphology must have exactly one root point, found {}",
+            n
+        ))),
+    }
+}
+
+/// Morphology under construction
+struct Builder<'a> {
+    points: &'a [Point],
+    children: Vec<Vec<usize>>,
+    morphology: Morphology,
+    /// Compartment ending at each point
+    compartment: Vec<Option<usize>>,
+    /// Sections created so far per name prefix
+    counts: HashMap<&'static str, usize>,
+}
+
+impl Builder<'_> {
+    /// Add a cylinder for each point of an unbranched run. Points that
+    /// coincide with their parent are merged into the parent's compartment.
+    fn section(&mut self, name: String, run: &[usize]) {
+        let start = self.morphology.compartments.len();
+        for &k in run {
+            let point = self.points[k];
+            let Some(q) = point.parent else { continue };
+            let parent = self.points[q];
+            let length = distance(point.position, parent.position);
+            if length == 0.0 {
+                self.compartment[k] = self.compartment[q];
+                continue;
+            }
+            // Neurites taper between their points but not into the soma
+            let diameter = if parent.kind == SOMA && point.kind != SOMA {
+                2.0 * point.radius
+            } else {
+                point.radius + parent.radius
+            };
+            self.compartment[k] = Some(self.morphology.compartments.len());
+            self.morphology.compartments.push(Compartment {
+                shape: Shape::Cylinder,
+                length,
+                diameter,
+                parent: self.compartment[q],
+                coordinates: Some(point.position),
+            });
+        }
+        let n = self.morphology.compartments.len() - start;
+        if n > 0 {
+            self.morphology.sections.push(Section { name, start, n });
+        }
+    }
+
+    /// Build the soma and return the points that start the neurites
+    fn soma(&mut self) -> Result<Vec<usize>> {
+        let root = self.points[0];
+        let mut soma = vec![0];
+        let mut k = 0;
+        while k < soma.len() {
+            soma.extend(self.children[soma[k]].iter().filter(|&&c| self.points[c].kind == SOMA));
+            k += 1;
+        }
+        let soma_children = |k: usize| self.children[k].iter().filter(|&&c| self.points[c].kind == SOMA).count();
+
+        let three_point = soma.len() == 3 && soma[1..].iter().all(|&k| self.points[k].parent == Some(0) && soma_children(k) == 0);
+        if soma.len() == 1 || three_point {
+            self.morphology.compartments.push(Compartment {
+                shape: Shape::Sphere,
+                length: 2.0 * root.radius,
+                diameter: 2.0 * root.radius,
+                parent: None,
+                coordinates: Some(root.position),
+            });
+            self.morphology.sections.push(Section { name: "soma".into(), start: 0, n: 1 });
+            for &k in &soma {
+                self.compartment[k] = Some(0);
+            }
+        } else if soma.iter().all(|&k| soma_children(k) <= 1) {
+            self.section("soma".into(), &soma[1..]);
+            if self.morphology.is_empty() {
+                return Err(BrianError::ParseError("SWC soma outline has zero length".into()));
+            }
+            self.compartment[0] = Some(0);
+        } else {
+            return Err(BrianError::ParseError(
+                "Branched SWC soma outlines are not supported".into(),
+            ));
+        }
+
+        Ok(soma.iter()
+            .flat_map(|&k| self.children[k].iter().copied())
+            .filter(|&c| self.points[c].kind != SOMA)
+            .collect())
+    }
+}
+
+impl Morphology {
+    /// Build a morphology from the contents of an SWC file
+    pub fn from_swc(text: &str) -> Result<Self> {
+        let points = parse_points(text)?;
+        let mut children = vec![vec![]; points.len()];
+        for (k, point) in points.iter().enumerate() {
+            if let Some(q) = point.parent {
+                children[q].push(k);
+            }
+        }
+
+        let mut builder = Builder {
+            points: &points,
+            children,
+            morphology: Morphology::default(),
+            compartment: vec![None; points.len()],
+            counts: HashMap::new(),
+        };
+
+        // Without a soma, the root point only marks where the first
+        // neurite starts
+        let mut stack = if points[0].kind == SOMA {
+            builder.soma()?
+        } else if builder.children[0].len() == 1 {
+            builder.children[0].clone()
+        } else {
+            return Err(BrianError::ParseError(
+                "SWC root must be a soma point or have a single child".into(),
+            ));
+        };
+
+        // Depth-first, so that every section follows its parent
+        stack.reverse();
+        while let Some(first) = stack.pop() {
+            let kind = points[first].kind;
+            let mut run = vec![first];
+            while let [next] = builder.children[run[run.len() - 1]][..] {
+                if points[next].kind != kind {
+                    break;
+                }
+                run.push(next);
+            }
+
+            let prefix = type_name(kind);
+            let name = format!("{}[{}]", prefix, builder.counts.get(prefix).unwrap_or(&0));
+            let before = builder.morphology.sections.len();
+            builder.section(name, &run);
+            if builder.morphology.sections.len() > before {
+                *builder.counts.entry(prefix).or_default() += 1;
+            }
+
+            let last = run[run.len() - 1];
+            stack.extend(builder.children[last].iter().rev());
+        }
+
+        if builder.morphology.is_empty() {
+            return Err(BrianError::ParseError("SWC morphology has no compartments".into()));
+        }
+        Ok(builder.morphology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oldies_core::Rng;
+    use crate::spatial::SpatialNeuron;
+    use crate::{parse_equations, Quantity, Unit};
+    use ndarray::Array1;
+
+    /// Three-point soma, a dendrite that forks once, and an axon
+    const CELL: &str = "
+        # NeuroMorpho.org style header
+        1 1   0.0   0.0 0.0 5.0 -1
+        2 1   0.0  -5.0 0.0 5.0  1
+        3 1   0.0   5.0 0.0 5.0  1
+        4 3   0.0  20.0 0.0 1.0  1
+        5 3   0.0  40.0 0.0 1.0  4
+        6 3 -10.0  60.0 0.0 0.5  5
+        7 3  10.0  60.0 0.0 0.5  5
+        8 3  10.0  60.0 0.0 0.5  7
+        9 3  20.0  80.0 0.0 0.5  8
+        10 2  0.0 -30.0 0.0 0.5  1
+    ";
+
+    #[test]
+    fn test_from_swc() {
+        let morpho = Morphology::from_swc(CELL).unwrap();
+        let names: Vec<&str> = morpho.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["soma", "dend[0]", "dend[1]", "dend[2]", "axon[0]"]);
+        assert_eq!(morpho.compartments[0].shape, Shape::Sphere);
+        assert_eq!(morpho.compartments[0].diameter, 10.0);
+
+        // Trunk of two points, then a fork; the duplicated point 8 is merged
+        assert_eq!(morpho.section("dend[0]"), Some(1..3));
+        assert_eq!(morpho.section("dend[2]"), Some(4..6));
+        let children: Vec<&str> = morpho.children("dend[0]").iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(children, ["dend[1]", "dend[2]"]);
+        assert_eq!(morpho.parent("dend[1]").unwrap().name, "dend[0]");
+        assert_eq!(morpho.parent("axon[0]").unwrap().name, "soma");
+
+        let first = &morpho.compartments[1];
+        assert_eq!((first.length, first.diameter, first.parent), (20.0, 2.0, Some(0)));
+        assert_eq!(morpho.compartments[5].coordinates, Some([20.0, 80.0, 0.0]));
+        assert!((morpho.compartments[3].length - 500f64.sqrt()).abs() < 1e-12);
+
+        // The reconstructed cell can be simulated directly
+        let mut eqs = parse_equations("Im = gL * (EL - v) : amp/meter**2").unwrap();
+        eqs.parameters.insert("gL".into(), Quantity::new(1.0, Unit::parse("siemens/meter**2").unwrap()));
+        eqs.parameters.insert("EL".into(), Quantity::new(-70.0, Unit::Millivolt));
+        let n = morpho.len();
+        let mut neuron = SpatialNeuron::new("cell", morpho, eqs);
+        neuron.set_initial("v", Array1::from_elem(n, -70.0)).unwrap();
+        neuron.input[0] = 0.01;
+        let mut rng = Rng::default();
+        for k in 0..100 {
+            neuron.update(k as f64 * 0.1, 0.1, &mut rng).unwrap();
+        }
+        let v = &neuron.state["v"];
+        assert!(v[0] > v[1] && v[1] > v[5] && v[5] > -70.0);
+    }
+
+    #[test]
+    fn test_swc_errors() {
+        assert!(Morphology::from_swc("1 1 0 0 0 5 -1\n2 3 0 10 0 1 3").is_err());
+        assert!(Morphology::from_swc("1 1 0 0 0 5 -1\n2 3 0 10 0 1 -1").is_err());
+        assert!(Morphology::from_swc("1 1 0 0 0 5").is_err());
+        assert!(Morphology::from_swc("1 1 0 0 0 0 -1").is_err());
+        assert!(Morphology::from_swc("").is_err());
+
+        // Soma outline traced as a chain, and a neurite without a soma
+        let chain = Morphology::from_swc("1 1 0 0 0 5 -1\n2 1 0 10 0 5 1\n3 1 0 20 0 5 2\n4 3 0 30 0 1 3").unwrap();
+        assert_eq!(chain.section("soma"), Some(0..2));
+        assert_eq!(chain.compartments[2].parent, Some(1));
+        let axon = Morphology::from_swc("1 2 0 0 0 1 -1\n2 2 0 10 0 1 1\n3 2 0 20 0 1 2").unwrap();
+        assert_eq!(axon.section("axon[0]"), Some(0..2));
+        assert_eq!(axon.compartments[0].parent, None);
+    }
+}
@@ -0,0 +1,494 @@
+//! Dimensional analysis
+//!
+//! Every [`Unit`] maps to a [`Dimension`]: integer powers of the seven SI
+//! base dimensions. Dimensions are propagated through parsed expressions so
+//! that inconsistent models (`dv/dt` not in volt/second, `v + tau`, ...) are
+//! rejected with [`BrianError::UnitError`] before they are simulated.
+//!
+//! Bare numeric literals act as dimensionless scale factors in products, but
+//! adopt the dimension of the other operand in sums and comparisons, so
+//! `v > -50*mV` and `v > -50` are both accepted.
+
+use crate::expr::{self, AssignOp, BinOp, Expr, Statement};
+use crate::{BrianError, NeuronEquations, RefractorySpec, Result, Unit, INPUT_SYMBOL, NOT_REFRACTORY_SYMBOL};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Powers of the SI base dimensions: m, kg, s, A, K, mol, cd
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Dimension(pub [i8; 7]);
+
+const BASE_SYMBOLS: [&str; 7] = ["m", "kg", "s", "A", "K", "mol", "cd"];
+
+/// SI value of the simulator's base unit for each base dimension
+/// (um, ug, ms, nA, K, mol, cd), which makes mV, uS, nF and MOhm coherent
+const INTERNAL_BASE: [f64; 7] = [1e-6, 1e-9, 1e-3, 1e-9, 1.0, 1.0, 1.0];
+
+impl Dimension {
+    pub const DIMENSIONLESS: Dimension = Dimension([0; 7]);
+    pub const LENGTH: Dimension = Dimension([1, 0, 0, 0, 0, 0, 0]);
+    pub const MASS: Dimension = Dimension([0, 1, 0, 0, 0, 0, 0]);
+    pub const TIME: Dimension = Dimension([0, 0, 1, 0, 0, 0, 0]);
+    pub const CURRENT: Dimension = Dimension([0, 0, 0, 1, 0, 0, 0]);
+    pub const TEMPERATURE: Dimension = Dimension([0, 0, 0, 0, 1, 0, 0]);
+    pub const AMOUNT: Dimension = Dimension([0, 0, 0, 0, 0, 1, 0]);
+    pub const LUMINOSITY: Dimension = Dimension([0, 0, 0, 0, 0, 0, 1]);
+    pub const VOLTAGE: Dimension = Dimension([2, 1, -3, -1, 0, 0, 0]);
+    pub const CONDUCTANCE: Dimension = Dimension([-2, -1, 3, 2, 0, 0, 0]);
+    pub const CAPACITANCE: Dimension = Dimension([-2, -1, 4, 2, 0, 0, 0]);
+    pub const RESISTANCE: Dimension = Dimension([2, 1, -3, -2, 0, 0, 0]);
+    pub const FREQUENCY: Dimension = Dimension([0, 0, -1, 0, 0, 0, 0]);
+
+    pub fn is_dimensionless(&self) -> bool {
+        *self == Self::DIMENSIONLESS
+    }
+
+    pub fn mul(&self, other: &Dimension) -> Dimension {
+        let mut out = [0; 7];
+        for (k, e) in out.iter_mut().enumerate() {
+            *e = self.0[k] + other.0[k];
+        }
+        Dimension(out)
+    }
+
+    pub fn div(&self, other: &Dimension) -> Dimension {
+        self.mul(&other.powi(-1))
+    }
+
+    pub fn powi(&self, n: i8) -> Dimension {
+        let mut out = self.0;
+        for e in out.iter_mut() {
+            *e *= n;
+        }
+        Dimension(out)
+    }
+
+    /// Square root, if all exponents are even
+    pub fn sqrt(&self) -> Option<Dimension> {
+        if self.0.iter().any(|e| e % 2 != 0) {
+            return None;
+        }
+        let mut out = self.0;
+        for e in out.iter_mut() {
+            *e /= 2;
+        }
+        Some(Dimension(out))
+    }
+
+    /// Factor converting an SI value of this dimension to the simulator's
+    /// base system
+    pub fn internal_factor(&self) -> f64 {
+        self.0.iter()
+            .zip(INTERNAL_BASE.iter())
+            .map(|(&e, &base)| base.powi(-(e as i32)))
+            .product()
+    }
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let named = [
+            (Dimension::VOLTAGE, "volt"),
+            (Dimension::CONDUCTANCE, "siemens"),
+            (Dimension::CAPACITANCE, "farad"),
+            (Dimension::RESISTANCE, "ohm"),
+            (Dimension::CURRENT, "amp"),
+            (Dimension::TIME, "second"),
+            (Dimension::FREQUENCY, "hertz"),
+            (Dimension::VOLTAGE.div(&Dimension::TIME), "volt/second"),
+            (Dimension::CURRENT.div(&Dimension::TIME), "amp/second"),
+        ];
+        if self.is_dimensionless() {
+            return write!(f, "1");
+        }
+        if let Some((_, name)) = named.iter().find(|(d, _)| d == self) {
+            return write!(f, "{}", name);
+        }
+
+        let parts: Vec<String> = self.0.iter()
+            .zip(BASE_SYMBOLS.iter())
+            .filter(|(&e, _)| e != 0)
+            .map(|(&e, sym)| if e == 1 { sym.to_string() } else { format!("{}^{}", sym, e) })
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl Unit {
+    /// Physical dimension of the unit
+    pub fn dimension(&self) -> Dimension {
+        match self {
+            Unit::Second | Unit::Millisecond | Unit::Microsecond => Dimension::TIME,
+            Unit::Volt | Unit::Millivolt => Dimension::VOLTAGE,
+            Unit::Ampere | Unit::Nanoampere | Unit::Picoampere => Dimension::CURRENT,
+            Unit::Siemens | Unit::Nanosiemens | Unit::Microsiemens => Dimension::CONDUCTANCE,
+            Unit::Farad | Unit::Picofarad => Dimension::CAPACITANCE,
+            Unit::Ohm | Unit::Megaohm | Unit::Gigaohm => Dimension::RESISTANCE,
+            Unit::Hertz => Dimension::FREQUENCY,
+            Unit::Dimensionless => Dimension::DIMENSIONLESS,
+            Unit::Compound(dim) => *dim,
+        }
+    }
+
+    /// Parse a unit specification from an equation (`volt`, `1`, `volt/second`)
+    ///
+    /// Like Brian, equation units must be unscaled SI units.
+    pub fn parse(spec: &str) -> Result<Unit> {
+        let spec = spec.trim();
+        if spec.is_empty() || spec == "1" {
+            return Ok(Unit::Dimensionless);
+        }
+        if let Some(unit) = expr::unit_from_name(spec) {
+            return Ok(unit);
+        }
+
+        let ast = expr::parse_expression(spec)?;
+        let (dim, factor) = unit_expression(&ast)?;
+        if (factor - 1.0).abs() > 1e-12 {
+            return Err(BrianError::ParseError(format!(
+                "Equation units must be base SI units, got '{}'",
+                spec
+            )));
+        }
+        Ok(Unit::Compound(dim))
+    }
+}
+
+/// Evaluate a unit expression (`amp/meter**2`) to (dimension, SI factor)
+fn unit_expression(ast: &Expr) -> Result<(Dimension, f64)> {
+    let invalid = || BrianError::ParseError(format!("Invalid unit expression '{}'", ast));
+    match ast {
+        Expr::Number(x) => Ok((Dimension::DIMENSIONLESS, *x)),
+        Expr::Name(name) => match name.as_str() {
+            "meter" | "metre" | "m" => Ok((Dimension::LENGTH, 1.0)),
+            "kilogram" | "kg" => Ok((Dimension::MASS, 1.0)),
+            "kelvin" | "K" => Ok((Dimension::TEMPERATURE, 1.0)),
+            "mole" | "mol" => Ok((Dimension::AMOUNT, 1.0)),
+            "molar" | "mM" => Ok((Dimension::AMOUNT.div(&Dimension::LENGTH.powi(3)), 1e3)),
+            "candela" | "cd" => Ok((Dimension::LUMINOSITY, 1.0)),
+            _ => expr::unit_from_name(name)
+                .map(|u| (u.dimension(), u.to_si_factor()))
+                .ok_or_else(invalid),
+        },
+        Expr::Binary(BinOp::Mul, a, b) => {
+            let (da, fa) = unit_expression(a)?;
+            let (db, fb) = unit_expression(b)?;
+            Ok((da.mul(&db), fa * fb))
+        }
+        Expr::Binary(BinOp::Div, a, b) => {
+            let (da, fa) = unit_expression(a)?;
+            let (db, fb) = unit_expression(b)?;
+            Ok((da.div(&db), fa / fb))
+        }
+        Expr::Binary(BinOp::Pow, a, b) => {
+            let (da, fa) = unit_expression(a)?;
+            match b.as_ref() {
+                Expr::Number(n) if n.fract() == 0.0 => Ok((da.powi(*n as i8), fa.powi(*n as i32))),
+                Expr::Neg(inner) => match inner.as_ref() {
+                    Expr::Number(n) if n.fract() == 0.0 => {
+                        Ok((da.powi(-(*n as i8)), fa.powi(-(*n as i32))))
+                    }
+                    _ => Err(invalid()),
+                },
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// ============================================================================
+// DIMENSION INFERENCE
+// ============================================================================
+
+/// Inferred dimension of a (sub)expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dim {
+    Known(Dimension),
+    /// Bare numeric literal
+    Literal,
+    /// No information (e.g. an undeclared input)
+    Any,
+}
+
+impl Dim {
+    fn dimensionless() -> Self {
+        Dim::Known(Dimension::DIMENSIONLESS)
+    }
+
+    /// Whether this is compatible with `expected`
+    pub fn matches(&self, expected: &Dimension) -> bool {
+        match self {
+            Dim::Known(d) => d == expected,
+            Dim::Literal | Dim::Any => true,
+        }
+    }
+}
+
+fn mismatch(expected: &Dimension, got: &Dimension) -> BrianError {
+    BrianError::UnitError {
+        expected: expected.to_string(),
+        got: got.to_string(),
+    }
+}
+
+/// Dimension of each symbol visible to an expression
+pub type DimensionTable = HashMap<String, Dim>;
+
+/// Infer the dimension of an expression
+pub fn infer(ast: &Expr, symbols: &DimensionTable) -> Result<Dim> {
+    match ast {
+        Expr::Number(_) => Ok(Dim::Literal),
+        Expr::Name(name) => {
+            if let Some(dim) = symbols.get(name) {
+                return Ok(*dim);
+            }
+            if let Some(unit) = expr::unit_from_name(name) {
+                return Ok(Dim::Known(unit.dimension()));
+            }
+            Ok(Dim::Literal)
+        }
+        Expr::Neg(e) => infer(e, symbols),
+        Expr::Not(e) => {
+            infer(e, symbols)?;
+            Ok(Dim::dimensionless())
+        }
+        Expr::Binary(op, a, b) => {
+            let da = infer(a, symbols)?;
+            let db = infer(b, symbols)?;
+            match op {
+                BinOp::Add | BinOp::Sub | BinOp::Mod => unify(da, db),
+                BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::Eq | BinOp::Ne => {
+                    unify(da, db)?;
+                    Ok(Dim::dimensionless())
+                }
+                BinOp::And | BinOp::Or => Ok(Dim::dimensionless()),
+                BinOp::Mul => Ok(match (da, db) {
+                    (Dim::Known(x), Dim::Known(y)) => Dim::Known(x.mul(&y)),
+                    (Dim::Known(x), Dim::Literal) | (Dim::Literal, Dim::Known(x)) => Dim::Known(x),
+                    (Dim::Literal, Dim::Literal) => Dim::Literal,
+                    _ => Dim::Any,
+                }),
+                BinOp::Div => Ok(match (da, db) {
+                    (Dim::Known(x), Dim::Known(y)) => Dim::Known(x.div(&y)),
+                    (Dim::Known(x), Dim::Literal) => Dim::Known(x),
+                    (Dim::Literal, Dim::Known(y)) => Dim::Known(y.powi(-1)),
+                    (Dim::Literal, Dim::Literal) => Dim::Literal,
+                    _ => Dim::Any,
+                }),
+                BinOp::Pow => {
+                    expect_dimensionless(db)?;
+                    match da {
+                        Dim::Known(x) if !x.is_dimensionless() => match constant_value(b) {
+                            Some(n) if n.fract() == 0.0 => Ok(Dim::Known(x.powi(n as i8))),
+                            Some(n) if (2.0 * n).fract() == 0.0 => x.powi((2.0 * n) as i8)
+                                .sqrt()
+                                .map(Dim::Known)
+                                .ok_or_else(|| mismatch(&Dimension::DIMENSIONLESS, &x)),
+                            _ => Err(mismatch(&Dimension::DIMENSIONLESS, &x)),
+                        },
+                        other => Ok(other),
+                    }
+                }
+            }
+        }
+        Expr::Call(name, args) => {
+            let dims = args.iter()
+                .map(|a| infer(a, symbols))
+                .collect::<Result<Vec<_>>>()?;
+            match name.as_str() {
+                "abs" | "floor" | "ceil" | "int" => Ok(dims.first().copied().unwrap_or(Dim::Any)),
+                "sign" => Ok(Dim::dimensionless()),
+                "clip" | "min" | "max" | "minimum" | "maximum" => {
+                    dims.into_iter().try_fold(Dim::Literal, unify)
+                }
+                "sqrt" => match dims.first() {
+                    Some(Dim::Known(x)) => x.sqrt()
+                        .map(Dim::Known)
+                        .ok_or_else(|| mismatch(&x.powi(2), x)),
+                    Some(other) => Ok(*other),
+                    None => Ok(Dim::Any),
+                },
+                "rand" | "randn" => Ok(Dim::dimensionless()),
+                "exp" | "log" | "log10" | "sin" | "cos" | "tan" | "sinh" | "cosh" | "tanh" => {
+                    for d in dims {
+                        expect_dimensionless(d)?;
+                    }
+                    Ok(Dim::dimensionless())
+                }
+                // User-defined functions are not dimension-checked
+                _ => Ok(Dim::Any),
+            }
+        }
+    }
+}
+
+fn unify(a: Dim, b: Dim) -> Result<Dim> {
+    match (a, b) {
+        (Dim::Known(x), Dim::Known(y)) => {
+            if x == y {
+                Ok(a)
+            } else {
+                Err(mismatch(&x, &y))
+            }
+        }
+        (Dim::Known(_), _) => Ok(a),
+        (_, Dim::Known(_)) => Ok(b),
+        (Dim::Any, _) | (_, Dim::Any) => Ok(Dim::Any),
+        (Dim::Literal, Dim::Literal) => Ok(Dim::Literal),
+    }
+}
+
+fn expect_dimensionless(d: Dim) -> Result<()> {
+    match d {
+        Dim::Known(x) if !x.is_dimensionless() => Err(mismatch(&Dimension::DIMENSIONLESS, &x)),
+        _ => Ok(()),
+    }
+}
+
+fn constant_value(ast: &Expr) -> Option<f64> {
+    match ast {
+        Expr::Number(x) => Some(*x),
+        Expr::Neg(e) => constant_value(e).map(|x| -x),
+        Expr::Binary(BinOp::Div, a, b) => Some(constant_value(a)? / constant_value(b)?),
+        _ => None,
+    }
+}
+
+/// Check that `expr` has dimension `expected`
+pub fn check_expression(src: &str, expected: &Dimension, symbols: &DimensionTable) -> Result<()> {
+    let dim = infer(&expr::parse_expression(src)?, symbols)?;
+    if dim.matches(expected) {
+        Ok(())
+    } else if let Dim::Known(got) = dim {
+        Err(mismatch(expected, &got))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that a statement assigns a value of the target's dimension
+pub fn check_statement(stmt: &Statement, symbols: &DimensionTable) -> Result<()> {
+    let target = symbols.get(&stmt.target).copied().unwrap_or(Dim::Any);
+    let rhs = infer(&stmt.expr, symbols)?;
+    match stmt.op {
+        AssignOp::Set | AssignOp::Add | AssignOp::Sub => {
+            unify(target, rhs)?;
+        }
+        AssignOp::Mul | AssignOp::Div => expect_dimensionless(rhs)?,
+    }
+    Ok(())
+}
+
+impl NeuronEquations {
+    /// Dimensions of all symbols visible to the equations
+    pub fn dimension_table(&self) -> DimensionTable {
+        let mut symbols = DimensionTable::new();
+        for (name, quantity) in &self.parameters {
+            symbols.insert(name.clone(), Dim::Known(quantity.unit.dimension()));
+        }
+        for eq in &self.differential {
+            symbols.insert(eq.variable.clone(), Dim::Known(eq.unit.dimension()));
+        }
+        for eq in &self.algebraic {
+            symbols.insert(eq.variable.clone(), Dim::Known(eq.unit.dimension()));
+        }
+        for decl in &self.per_neuron {
+            symbols.insert(decl.variable.clone(), Dim::Known(decl.unit.dimension()));
+        }
+        symbols.entry(INPUT_SYMBOL.to_string()).or_insert(Dim::Any);
+        symbols.insert("t".into(), Dim::Known(Dimension::TIME));
+        symbols.insert("dt".into(), Dim::Known(Dimension::TIME));
+        symbols.insert("i".into(), Dim::dimensionless());
+        symbols.insert("N".into(), Dim::dimensionless());
+        symbols.insert(NOT_REFRACTORY_SYMBOL.into(), Dim::dimensionless());
+        symbols
+    }
+
+    /// Verify the dimensional consistency of all equations, the threshold,
+    /// reset statements and refractoriness condition
+    pub fn check_units(&self) -> Result<()> {
+        let symbols = self.dimension_table();
+
+        for eq in &self.differential {
+            let expected = eq.unit.dimension().div(&Dimension::TIME);
+            check_expression(&eq.expression, &expected, &symbols)?;
+        }
+        for eq in &self.algebraic {
+            check_expression(&eq.expression, &eq.unit.dimension(), &symbols)?;
+        }
+        if let Some(threshold) = &self.threshold {
+            infer(&expr::parse_expression(&threshold.condition)?, &symbols)?;
+        }
+        if let Some(reset) = &self.reset {
+            for line in &reset.equations {
+                for stmt in expr::parse_statements(line)? {
+                    check_statement(&stmt, &symbols)?;
+                }
+            }
+        }
+        if let Some(RefractorySpec::Condition(cond)) = &self.refractory {
+            infer(&expr::parse_expression(cond)?, &symbols)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_equations, AdExNeuron, IzhikevichNeuron, LIFNeuron, Quantity};
+
+    #[test]
+    fn test_internal_factors_agree_with_table() {
+        for unit in [Unit::Second, Unit::Volt, Unit::Nanoampere, Unit::Microsiemens,
+                     Unit::Picofarad, Unit::Megaohm, Unit::Hertz] {
+            let derived = unit.to_si_factor() * unit.dimension().internal_factor();
+            assert!((derived / unit.to_internal_factor() - 1.0).abs() < 1e-9, "{:?}", unit);
+        }
+    }
+
+    #[test]
+    fn test_parse_compound_unit() {
+        assert_eq!(Unit::parse("volt").unwrap(), Unit::Volt);
+        assert_eq!(
+            Unit::parse("volt/second").unwrap().dimension(),
+            Dimension::VOLTAGE.div(&Dimension::TIME)
+        );
+        assert_eq!(
+            Unit::parse("amp/meter**2").unwrap().dimension(),
+            Dimension([-2, 0, 0, 1, 0, 0, 0])
+        );
+        assert!(Unit::parse("mV*ms").is_err());
+    }
+
+    #[test]
+    fn test_consistent_equations() {
+        let mut eqs = parse_equations("dv/dt = (v_rest - v) / tau : volt").unwrap();
+        eqs.parameters.insert("v_rest".into(), Quantity::new(-70.0, Unit::Millivolt));
+        eqs.parameters.insert("tau".into(), Quantity::new(10.0, Unit::Millisecond));
+        assert!(eqs.check_units().is_ok());
+
+        assert!(LIFNeuron::default().to_equations().check_units().is_ok());
+        assert!(AdExNeuron::default().to_equations().check_units().is_ok());
+        assert!(IzhikevichNeuron::regular_spiking().to_equations().check_units().is_ok());
+    }
+
+    #[test]
+    fn test_inconsistent_equations() {
+        // Missing division by a time constant
+        let mut eqs = parse_equations("dv/dt = v_rest - v : volt").unwrap();
+        eqs.parameters.insert("v_rest".into(), Quantity::new(-70.0, Unit::Millivolt));
+        assert!(matches!(eqs.check_units(), Err(BrianError::UnitError { .. })));
+
+        // Adding a voltage to a time
+        let mut eqs = parse_equations("dv/dt = (v + tau) / tau : volt").unwrap();
+        eqs.parameters.insert("tau".into(), Quantity::new(10.0, Unit::Millisecond));
+        assert!(matches!(eqs.check_units(), Err(BrianError::UnitError { .. })));
+
+        // exp() of a dimensioned quantity
+        let eqs = parse_equations("dv/dt = exp(v) / second : volt").unwrap();
+        assert!(matches!(eqs.check_units(), Err(BrianError::UnitError { .. })));
+    }
+}
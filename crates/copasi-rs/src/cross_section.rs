@@ -0,0 +1,213 @@
+//! Cross sections of time courses
+//!
+//! [`CopasiSimulation::cross_section`] records the state wherever an
+//! expression crosses a threshold in a chosen direction, as COPASI's cross
+//! section task does. The crossings of a limit cycle with a section through
+//! it make a Poincaré map, and those of a periodic forcing term such as
+//! `sin(2 * pi * time / T)` a stroboscopic map.
+//!
+//! - The model is integrated deterministically, step by step, and each
+//!   crossing located by bisection as event triggers are
+//!   ([`crate::events`]). Events themselves are not executed.
+//! - Crossings during a transient are skipped.
+//! - Integration stops after a number of crossings if given, or when the
+//!   state at a crossing comes back to that at an earlier one within a
+//!   tolerance, which tells the number of crossings per period: 1 on a
+//!   simple limit cycle, 2 after a period doubling.
+
+use crate::*;
+
+/// Direction of the crossings recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrossingDirection {
+    /// The expression rising through the threshold
+    Rising,
+    /// The expression falling through the threshold
+    Falling,
+    /// Either
+    Both,
+}
+
+/// Settings of the cross section task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossSectionOptions {
+    /// Expression over the model's symbols and `time`
+    pub expression: String,
+    pub threshold: f64,
+    pub direction: CrossingDirection,
+    /// Time integrated first, whose crossings are skipped
+    pub transient: f64,
+    /// Time integrated after the transient at most
+    pub duration: f64,
+    /// Crossings recorded at most, if limited
+    pub max_crossings: Option<usize>,
+    /// Relative distance of the states at two crossings under which the
+    /// time course is taken as periodic, stopping integration, if given
+    pub convergence_tolerance: Option<f64>,
+}
+
+impl CrossSectionOptions {
+    /// Rising crossings of `expression` through `threshold` over a
+    /// duration of 1000
+    pub fn new(expression: &str, threshold: f64) -> Self {
+        Self {
+            expression: expression.to_string(),
+            threshold,
+            direction: CrossingDirection::Rising,
+            transient: 0.0,
+            duration: 1000.0,
+            max_crossings: None,
+            convergence_tolerance: None,
+        }
+    }
+}
+
+/// State at a crossing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Crossing {
+    pub time: Time,
+    /// Species concentrations
+    pub concentrations: HashMap<String, f64>,
+}
+
+/// Result of the cross section task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossSection {
+    pub crossings: Vec<Crossing>,
+    /// Crossings per period, if the time course converged to a periodic
+    /// one
+    pub periodicity: Option<usize>,
+}
+
+impl CrossSection {
+    /// Times between consecutive crossings
+    pub fn intervals(&self) -> Vec<f64> {
+        self.crossings.windows(2).map(|c| c[1].time - c[0].time).collect()
+    }
+}
+
+impl CopasiSimulation {
+    /// Integrate from the current state recording the crossings described
+    /// by `options`, and leave the simulation at the end
+    pub fn cross_section(&mut self, options: &CrossSectionOptions) -> Result<CrossSection> {
+        let expr = self.model.expression(&options.expression)?;
+        let stoich = self.model.stoichiometry_matrix();
+        if options.transient > 0.0 {
+            self.integrate(&stoich, self.t + options.transient);
+        }
+
+        let offset = |sim: &Self| expr.eval(&|id| sim.symbol_value(id, &[])) - options.threshold;
+        let crossed = |before: f64, now: f64| match options.direction {
+            CrossingDirection::Rising => before < 0.0 && now >= 0.0,
+            CrossingDirection::Falling => before > 0.0 && now <= 0.0,
+            CrossingDirection::Both => (before < 0.0 && now >= 0.0) || (before > 0.0 && now <= 0.0),
+        };
+
+        let end = self.t + options.duration;
+        let mut states: Vec<Array1<f64>> = Vec::new();
+        let mut result = CrossSection { crossings: Vec::new(), periodicity: None };
+        while self.t < end && options.max_crossings.is_none_or(|max| result.crossings.len() < max) {
+            let (t, y, before) = (self.t, self.ode_state(), offset(self));
+            self.integrate_steps(&stoich, end, 1);
+            if self.t == t {
+                return Err(OldiesError::NumericalError(format!("Integration stopped at t = {}", t)));
+            }
+            if !crossed(before, offset(self)) {
+                continue;
+            }
+            self.locate_crossing(&stoich, t, y, |sim| crossed(before, offset(sim)));
+            result.crossings.push(Crossing { time: self.t, concentrations: self.get_concentrations() });
+
+            // The latest earlier crossing the state has come back to
+            let state = self.ode_state();
+            if let Some(tolerance) = options.convergence_tolerance {
+                let scale = state.iter().fold(0.0f64, |m, x| m.max(x.abs())).max(f64::MIN_POSITIVE);
+                result.periodicity = states.iter()
+                    .rev()
+                    .position(|s| (s - &state).iter().all(|d| d.abs() <= tolerance * scale))
+                    .map(|k| k + 1);
+            }
+            states.push(state);
+            if result.periodicity.is_some() {
+                break;
+            }
+        }
+        Ok(result)
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brusselator() -> SbmlModel {
+        let mut model = SbmlModel::new("brusselator");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("X", "c", 1.0));
+        model.add_species(Species::new("Y", "c", 1.0));
+        model.add_parameter(Parameter::new("a", 1.0));
+        model.add_parameter(Parameter::new("b", 3.0));
+        model.add_parameter(Parameter::new("one", 1.0));
+        let mut inflow = Reaction::simple("inflow", "X", "X", "a");
+        inflow.reactants.clear();
+        model.add_reaction(inflow);
+        let mut autocatalysis = Reaction::simple("autocatalysis", "X", "X", "one");
+        autocatalysis.reactants[0].stoichiometry = 2.0;
+        autocatalysis.reactants.push(SpeciesReference::new("Y", 1.0));
+        autocatalysis.products[0].stoichiometry = 3.0;
+        model.add_reaction(autocatalysis);
+        model.add_reaction(Reaction::simple("conversion", "X", "Y", "b"));
+        let mut outflow = Reaction::simple("outflow", "X", "X", "one");
+        outflow.products.clear();
+        model.add_reaction(outflow);
+        model
+    }
+
+    #[test]
+    fn test_forcing_crossings() {
+        // sin(time) falls through 0 at odd multiples of π
+        let mut sim = CopasiSimulation::new(brusselator());
+        let mut options = CrossSectionOptions::new("sin(time)", 0.0);
+        options.direction = CrossingDirection::Falling;
+        options.transient = 4.0;
+        options.max_crossings = Some(3);
+        let result = sim.cross_section(&options).unwrap();
+        assert_eq!(result.crossings.len(), 3);
+        for (crossing, k) in result.crossings.iter().zip([3.0, 5.0, 7.0]) {
+            assert!((crossing.time - k * std::f64::consts::PI).abs() < 1e-8, "{}", crossing.time);
+        }
+        assert_eq!(result.periodicity, None);
+
+        options.expression = "sin(".into();
+        assert!(sim.cross_section(&options).is_err());
+    }
+
+    #[test]
+    fn test_limit_cycle_section() {
+        // X rising through 1 once per cycle of the Brusselator
+        let mut sim = CopasiSimulation::new(brusselator());
+        sim.set_ode(ode::OdeOptions { relative_tolerance: 1e-8, ..Default::default() });
+        let mut options = CrossSectionOptions::new("X", 1.0);
+        options.transient = 50.0;
+        options.convergence_tolerance = Some(1e-4);
+        let result = sim.cross_section(&options).unwrap();
+        assert_eq!(result.periodicity, Some(1));
+        let crossing = result.crossings.last().unwrap();
+        assert!((crossing.concentrations["X"] - 1.0).abs() < 1e-8);
+        let period = *result.intervals().last().unwrap();
+        assert!(period > 5.0 && period < 10.0, "{}", period);
+
+        // Later cycles keep the period
+        options.transient = 0.0;
+        options.convergence_tolerance = None;
+        options.max_crossings = Some(3);
+        let result = sim.cross_section(&options).unwrap();
+        for interval in result.intervals() {
+            assert!((interval - period).abs() < 1e-4, "{} {}", interval, period);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Elementary flux modes
+//!
+//! An elementary flux mode is a steady state flux distribution, N v = 0
+//! for the internal species, that respects the directions of the
+//! irreversible reactions and uses no proper subset of its reactions in
+//! another such distribution. As in Metatool, [`ElementaryModes`] finds
+//! them all by the double description method:
+//!
+//! - Reversible reactions are split into a forward and a backward
+//!   reaction, so that the flux distributions form the cone x >= 0,
+//!   N x = 0, and the elementary modes are its extreme rays, but for the
+//!   cycles of a reaction with its own reverse.
+//! - Starting from the positive orthant, each species' balance in turn
+//!   keeps the rays it already holds for and combines the pairs of
+//!   adjacent rays on either side of it, those with no other ray using
+//!   only reactions of the two. The species taking the fewest
+//!   combinations goes first.
+//!
+//! The number of modes grows combinatorially with the network. For larger
+//! networks, modes can be limited to a number of reactions, which prunes
+//! candidates exactly since combining rays only adds reactions, and
+//! reactions can be excluded; the enumeration stops with an error past a
+//! number of candidates.
+
+use crate::*;
+use crate::moieties::Reduction;
+use rayon::prelude::*;
+
+/// Balances and fluxes taken as zero, relative to the largest
+const TOLERANCE: f64 = 1e-9;
+
+/// Elementary flux mode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FluxMode {
+    /// Reactions of the mode and their relative fluxes, the smallest in
+    /// magnitude 1
+    pub fluxes: Vec<(String, f64)>,
+    /// Whether the mode also runs in reverse, with reversible reactions
+    /// only, when it is listed once
+    pub reversible: bool,
+}
+
+/// Elementary flux mode enumeration task
+#[derive(Debug, Clone)]
+pub struct ElementaryModes {
+    pub model: SbmlModel,
+    /// Largest number of reactions in a mode, if limited
+    pub max_reactions: Option<usize>,
+    /// Reactions in no mode
+    pub excluded: Vec<String>,
+    /// Candidate modes held at once before giving up
+    pub max_candidates: usize,
+}
+
+/// Candidate mode over the irreversible reactions, and the reactions it
+/// uses as bits
+#[derive(Debug, Clone)]
+struct Ray {
+    x: Vec<f64>,
+    support: Vec<u64>,
+}
+
+impl Ray {
+    fn new(x: Vec<f64>) -> Self {
+        let scale = x.iter().fold(0.0f64, |s, v| s.max(v.abs()));
+        let x: Vec<f64> = x.into_iter().map(|v| v / scale).collect();
+        let mut support = vec![0u64; x.len().div_ceil(64)];
+        for (k, v) in x.iter().enumerate() {
+            if *v > TOLERANCE {
+                support[k / 64] |= 1 << (k % 64);
+            }
+        }
+        Self { x, support }
+    }
+}
+
+/// Whether the reactions of `a` are among `b`'s
+fn subset(a: &[u64], b: &[u64]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a & !b == 0)
+}
+
+impl ElementaryModes {
+    pub fn new(model: SbmlModel) -> Self {
+        Self { model, max_reactions: None, excluded: Vec::new(), max_candidates: 1_000_000 }
+    }
+
+    /// All elementary flux modes, the smallest first
+    pub fn compute(&self) -> Result<Vec<FluxMode>> {
+        for id in &self.excluded {
+            if !self.model.reactions.iter().any(|r| &r.id == id) {
+                return Err(OldiesError::SimulationError(format!("Unknown reaction: {}", id)));
+            }
+        }
+
+        // Irreversible columns as (reaction, direction)
+        let columns: Vec<(usize, f64)> = self.model.reactions.iter()
+            .enumerate()
+            .filter(|(_, r)| !self.excluded.contains(&r.id))
+            .flat_map(|(j, r)| {
+                let backward = r.reversible.then_some((j, -1.0));
+                std::iter::once((j, 1.0)).chain(backward)
+            })
+            .collect();
+        let stoich = self.model.stoichiometry_matrix();
+        let balances = Reduction::new(&stoich).reduced(&stoich);
+        let mut rows: Vec<Vec<f64>> = balances.rows().into_iter()
+            .map(|row| columns.iter().map(|&(j, direction)| direction * row[j]).collect())
+            .collect();
+
+        let mut rays: Vec<Ray> = (0..columns.len())
+            .map(|k| {
+                let mut x = vec![0.0; columns.len()];
+                x[k] = 1.0;
+                Ray::new(x)
+            })
+            .collect();
+        while !rows.is_empty() {
+            let balance = |row: &[f64], ray: &Ray| -> f64 {
+                let scale = row.iter().fold(0.0f64, |s, v| s.max(v.abs()));
+                let value: f64 = row.iter().zip(&ray.x).map(|(a, x)| a * x).sum();
+                if value.abs() <= TOLERANCE * scale { 0.0 } else { value }
+            };
+            let combinations = |row: &[f64]| {
+                let values: Vec<f64> = rays.iter().map(|ray| balance(row, ray)).collect();
+                values.iter().filter(|v| **v > 0.0).count() * values.iter().filter(|v| **v < 0.0).count()
+            };
+            let next = (0..rows.len()).min_by_key(|&i| combinations(&rows[i])).unwrap();
+            let row = rows.swap_remove(next);
+            let values: Vec<f64> = rays.iter().map(|ray| balance(&row, ray)).collect();
+
+            let positive: Vec<usize> = (0..rays.len()).filter(|&i| values[i] > 0.0).collect();
+            let negative: Vec<usize> = (0..rays.len()).filter(|&i| values[i] < 0.0).collect();
+            let combined: Vec<Ray> = positive.par_iter()
+                .flat_map_iter(|&p| {
+                    let (rays, values) = (&rays, &values);
+                    negative.iter().filter_map(move |&n| {
+                        let union: Vec<u64> = rays[p].support.iter().zip(&rays[n].support).map(|(a, b)| a | b).collect();
+                        let size: usize = union.iter().map(|w| w.count_ones() as usize).sum();
+                        if self.max_reactions.is_some_and(|max| size > max) {
+                            return None;
+                        }
+                        let adjacent = rays.iter()
+                            .enumerate()
+                            .all(|(k, ray)| k == p || k == n || !subset(&ray.support, &union));
+                        adjacent.then(|| {
+                            Ray::new(rays[p].x.iter()
+                                .zip(&rays[n].x)
+                                .map(|(xp, xn)| values[p] * xn - values[n] * xp)
+                                .collect())
+                        })
+                    })
+                })
+                .collect();
+
+            rays = rays.into_iter()
+                .zip(&values)
+                .filter(|(_, v)| **v == 0.0)
+                .map(|(ray, _)| ray)
+                .chain(combined)
+                .collect();
+            if rays.len() > self.max_candidates {
+                return Err(OldiesError::SimulationError(format!(
+                    "Elementary flux modes exceed {} candidates", self.max_candidates
+                )));
+            }
+        }
+
+        // Fluxes of the reactions, without the cycles of a reaction with
+        // its reverse, and the reversible modes once
+        let mut modes: Vec<Vec<(usize, f64)>> = rays.iter()
+            .filter_map(|ray| {
+                let mut v = vec![0.0; self.model.reactions.len()];
+                for (&(j, direction), x) in columns.iter().zip(&ray.x) {
+                    v[j] += direction * x;
+                }
+                let smallest = v.iter()
+                    .filter(|v| v.abs() > TOLERANCE)
+                    .fold(f64::INFINITY, |s, v| s.min(v.abs()));
+                let fluxes: Vec<(usize, f64)> = v.iter()
+                    .enumerate()
+                    .filter(|(_, v)| v.abs() > TOLERANCE)
+                    .map(|(j, v)| {
+                        let v = v / smallest;
+                        (j, if (v - v.round()).abs() < TOLERANCE * v.abs() { v.round() } else { v })
+                    })
+                    .collect();
+                let reversible = fluxes.iter().all(|(j, _)| self.model.reactions[*j].reversible);
+                (!fluxes.is_empty() && (!reversible || fluxes[0].1 > 0.0)).then_some(fluxes)
+            })
+            .collect();
+        modes.sort_by(|a, b| {
+            a.len().cmp(&b.len()).then_with(|| a.iter().map(|(j, _)| j).cmp(b.iter().map(|(j, _)| j)))
+        });
+        Ok(modes.into_iter()
+            .map(|fluxes| FluxMode {
+                reversible: fluxes.iter().all(|(j, _)| self.model.reactions[*j].reversible),
+                fluxes: fluxes.into_iter()
+                    .map(|(j, v)| (self.model.reactions[j].id.clone(), v))
+                    .collect(),
+            })
+            .collect())
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network() -> SbmlModel {
+        // X -> A, A <-> B, A -> C, B -> C, C -> Y, B -> Z, 2 A -> D, D -> Y
+        // between the boundary species X, Y and Z
+        let mut model = SbmlModel::new("branches");
+        model.add_compartment(Compartment::new("c", 1.0));
+        for s in ["X", "Y", "Z", "A", "B", "C", "D"] {
+            let mut species = Species::new(s, "c", 1.0);
+            species.boundary_condition = ["X", "Y", "Z"].contains(&s);
+            model.add_species(species);
+        }
+        model.add_parameter(Parameter::new("k", 1.0));
+        for (id, from, to, reversible) in [
+            ("R1", "X", "A", false),
+            ("R2", "A", "B", true),
+            ("R3", "A", "C", false),
+            ("R4", "B", "C", false),
+            ("R5", "C", "Y", false),
+            ("R6", "B", "Z", false),
+            ("R7", "A", "D", false),
+            ("R8", "D", "Y", false),
+        ] {
+            let mut reaction = Reaction::simple(id, from, to, "k");
+            reaction.reversible = reversible;
+            model.add_reaction(reaction);
+        }
+        model.reactions[6].reactants[0].stoichiometry = 2.0;
+        model
+    }
+
+    fn fluxes(modes: &[FluxMode]) -> Vec<Vec<(&str, f64)>> {
+        modes.iter()
+            .map(|m| m.fluxes.iter().map(|(r, v)| (r.as_str(), *v)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_elementary_modes() {
+        let model = network();
+        let mut task = ElementaryModes::new(model.clone());
+        let modes = task.compute().unwrap();
+        assert_eq!(fluxes(&modes), vec![
+            vec![("R1", 1.0), ("R2", 1.0), ("R6", 1.0)],
+            vec![("R1", 1.0), ("R3", 1.0), ("R5", 1.0)],
+            vec![("R1", 2.0), ("R7", 1.0), ("R8", 1.0)],
+            vec![("R1", 1.0), ("R2", 1.0), ("R4", 1.0), ("R5", 1.0)],
+        ]);
+        assert!(modes.iter().all(|m| !m.reversible));
+        let stoich = model.stoichiometry_matrix();
+        for mode in &modes {
+            let mut v = Array1::zeros(model.reactions.len());
+            for (id, flux) in &mode.fluxes {
+                v[model.reactions.iter().position(|r| &r.id == id).unwrap()] = *flux;
+            }
+            assert!(stoich.dot(&v).iter().all(|x| x.abs() < 1e-12));
+        }
+
+        // Pruned to three reactions, without R3, and past the candidates
+        task.max_reactions = Some(3);
+        task.excluded = vec!["R3".into()];
+        let modes = task.compute().unwrap();
+        assert_eq!(fluxes(&modes), vec![
+            vec![("R1", 1.0), ("R2", 1.0), ("R6", 1.0)],
+            vec![("R1", 2.0), ("R7", 1.0), ("R8", 1.0)],
+        ]);
+        task.max_candidates = 1;
+        assert!(task.compute().is_err());
+    }
+
+    #[test]
+    fn test_reversible_modes() {
+        // X <-> A <-> Y, and A -> Y
+        let mut model = SbmlModel::new("reversible");
+        model.add_compartment(Compartment::new("c", 1.0));
+        for s in ["X", "A", "Y"] {
+            let mut species = Species::new(s, "c", 1.0);
+            species.boundary_condition = s != "A";
+            model.add_species(species);
+        }
+        model.add_parameter(Parameter::new("k", 1.0));
+        model.add_reaction(Reaction::simple("in", "X", "A", "k"));
+        model.add_reaction(Reaction::simple("out", "A", "Y", "k"));
+        let mut irreversible = Reaction::simple("leak", "A", "Y", "k");
+        irreversible.reversible = false;
+        model.add_reaction(irreversible);
+        model.reactions[0].reversible = true;
+        model.reactions[1].reversible = true;
+
+        let modes = ElementaryModes::new(model).compute().unwrap();
+        assert_eq!(fluxes(&modes), vec![
+            vec![("in", 1.0), ("out", 1.0)],
+            vec![("in", 1.0), ("leak", 1.0)],
+            vec![("out", -1.0), ("leak", 1.0)],
+        ]);
+        assert_eq!(modes.iter().map(|m| m.reversible).collect::<Vec<_>>(), vec![true, false, false]);
+    }
+}
@@ -0,0 +1,288 @@
+//! Event execution
+//!
+//! Deterministic time courses of models with [`Event`]s watch the triggers
+//! after every step of the integrator, following SBML Level 3 semantics:
+//!
+//! - An event fires when its trigger turns from false to true. Triggers are
+//!   taken as true before the start, as with `initialValue="true"`, so an
+//!   event true from the start waits for its trigger to turn false first.
+//! - The crossing time is found by bisection, integrating again from the
+//!   last step, down to a relative tolerance of 1e-10.
+//! - Assignment values and the delay are computed when the event fires,
+//!   assignments as with `useValuesFromTriggerTime="true"`, and applied
+//!   then or after the delay, even if the trigger has turned false again.
+//! - Events due at the same time execute one by one in decreasing priority,
+//!   evaluated when they execute, events without a priority after all
+//!   others and ties in document order. Triggers are checked again after
+//!   each execution, so events triggered by another fire at the same time.
+//! - The integrator restarts after every execution, its step size
+//!   forgotten, as the state may jump.
+//!
+//! Stochastic, hybrid and steady state simulations ignore events.
+//! Sensitivities would jump at each execution, so
+//! [`CopasiSimulation::set_sensitivities`] refuses models with events.
+
+use crate::*;
+
+/// Relative precision of trigger crossing times
+const CROSSING_TOLERANCE: f64 = 1e-10;
+/// Event executions at one time before a cascade is cut off
+const MAX_CASCADE: usize = 10_000;
+
+/// An event waiting to execute
+#[derive(Debug, Clone)]
+struct Pending {
+    time: Time,
+    event: usize,
+    /// Assignment values computed at the trigger time
+    values: Vec<f64>,
+}
+
+/// Parsed events and their triggers' last values
+#[derive(Debug, Clone)]
+pub(crate) struct Events {
+    /// Trigger, delay, priority and assignments of each event (`None`
+    /// where the expression does not parse: such triggers never fire, such
+    /// delays never end and such assignments set NaN)
+    triggers: Vec<Option<Expr>>,
+    delays: Vec<Option<Expr>>,
+    priorities: Vec<Option<Expr>>,
+    assignments: Vec<Vec<(String, Option<Expr>)>>,
+    previous: Vec<bool>,
+    pending: Vec<Pending>,
+}
+
+impl Events {
+    pub fn new(model: &SbmlModel) -> Self {
+        let compile = |text: &str| model.expression(text).ok();
+        Self {
+            triggers: model.events.iter().map(|e| compile(&e.trigger)).collect(),
+            delays: model.events.iter()
+                .map(|e| e.delay.as_deref().map_or(Some(Expr::Number(0.0)), compile))
+                .collect(),
+            priorities: model.events.iter()
+                .map(|e| e.priority.as_deref().and_then(compile))
+                .collect(),
+            assignments: model.events.iter()
+                .map(|e| e.assignments.iter().map(|a| (a.variable.clone(), compile(&a.expression))).collect())
+                .collect(),
+            previous: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl CopasiSimulation {
+    /// Forget pending events and take the triggers' current values as
+    /// their last, so that those true now wait to turn false
+    pub(crate) fn reset_events(&mut self) {
+        self.events.pending.clear();
+        self.events.previous = self.trigger_values();
+    }
+
+    fn evaluate(&self, expr: &Expr) -> f64 {
+        expr.eval(&|id| self.symbol_value(id, &[]))
+    }
+
+    /// Current value of every trigger
+    fn trigger_values(&self) -> Vec<bool> {
+        self.events.triggers.iter()
+            .map(|trigger| trigger.as_ref().is_some_and(|t| {
+                let value = self.evaluate(t);
+                value != 0.0 && !value.is_nan()
+            }))
+            .collect()
+    }
+
+    /// Whether a trigger has turned true since the last check
+    fn newly_triggered(&self) -> bool {
+        self.trigger_values().iter().zip(&self.events.previous).any(|(&now, &before)| now && !before)
+    }
+
+    /// Integrate with events up to the time `end`
+    pub(crate) fn step_events(&mut self, end: Time) {
+        let stoich = self.model.stoichiometry_matrix();
+        while self.t < end {
+            let stop = self.events.pending.iter().map(|p| p.time).fold(end, f64::min);
+            while self.t < stop {
+                let (t, y) = (self.t, self.ode_state());
+                self.integrate_steps(&stoich, stop, 1);
+                if self.t == t {
+                    // The integrator gave up
+                    return;
+                }
+                if self.newly_triggered() {
+                    self.locate_crossing(&stoich, t, y, Self::newly_triggered);
+                    break;
+                }
+                self.events.previous = self.trigger_values();
+            }
+            self.execute_events();
+        }
+    }
+
+    /// Bisect for the first time `crossed` holds after (`t`, `y`), where
+    /// it does not, and the current state, where it does
+    pub(crate) fn locate_crossing(
+        &mut self,
+        stoich: &Array2<f64>,
+        mut t: Time,
+        mut y: Array1<f64>,
+        crossed: impl Fn(&Self) -> bool,
+    ) {
+        let (mut t_hit, mut y_hit) = (self.t, self.ode_state());
+        while t_hit - t > CROSSING_TOLERANCE * t_hit.abs().max(1.0) {
+            let middle = 0.5 * (t + t_hit);
+            self.t = t;
+            self.set_ode_state(&y);
+            self.ode_step = None;
+            self.integrate(stoich, middle);
+            if crossed(self) {
+                (t_hit, y_hit) = (self.t, self.ode_state());
+            } else {
+                (t, y) = (self.t, self.ode_state());
+            }
+        }
+        self.t = t_hit;
+        self.set_ode_state(&y_hit);
+        self.apply_assignment_rules();
+    }
+
+    /// Schedule the events triggered now and execute those due, one by
+    /// one, until none is left
+    fn execute_events(&mut self) {
+        for _ in 0..MAX_CASCADE {
+            let values = self.trigger_values();
+            for (i, (&now, &before)) in values.iter().zip(&self.events.previous).enumerate() {
+                if now && !before {
+                    let assignments = self.events.assignments[i].iter()
+                        .map(|(_, expr)| expr.as_ref().map_or(f64::NAN, |e| self.evaluate(e)))
+                        .collect();
+                    let delay = self.events.delays[i].as_ref().map_or(f64::INFINITY, |e| self.evaluate(e));
+                    self.events.pending.push(Pending {
+                        time: self.t + delay,
+                        event: i,
+                        values: assignments,
+                    });
+                }
+            }
+            self.events.previous = values;
+
+            // Highest priority first, then document order
+            let due = self.events.pending.iter()
+                .enumerate()
+                .filter(|(_, p)| p.time <= self.t)
+                .map(|(k, p)| {
+                    let priority = self.events.priorities[p.event].as_ref()
+                        .map_or(f64::NEG_INFINITY, |e| self.evaluate(e));
+                    (k, priority, p.event)
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1).then(b.2.cmp(&a.2)));
+            let Some((k, _, _)) = due else {
+                return;
+            };
+            let pending = self.events.pending.remove(k);
+            for ((variable, _), value) in self.events.assignments[pending.event].clone().iter().zip(pending.values) {
+                self.set_value(variable, value);
+            }
+            self.apply_assignment_rules();
+            self.ode_step = None;
+        }
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: &str, trigger: &str, assignments: &[(&str, &str)]) -> Event {
+        Event {
+            id: id.into(),
+            trigger: trigger.into(),
+            delay: None,
+            priority: None,
+            assignments: assignments.iter()
+                .map(|(v, e)| EventAssignment { variable: v.to_string(), expression: e.to_string() })
+                .collect(),
+        }
+    }
+
+    fn decay() -> SbmlModel {
+        let mut model = SbmlModel::new("refill");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 10.0));
+        model.add_parameter(Parameter::new("k", 1.0));
+        let mut decay = Reaction::simple("decay", "A", "A", "k");
+        decay.products.clear();
+        model.add_reaction(decay);
+        model
+    }
+
+    #[test]
+    fn test_refill_at_crossing() {
+        // A falls below 1 at t = ln 10, 2 ln 10, ... and is set back to 10
+        let mut model = decay();
+        model.events.push(event("refill", "A < 1", &[("A", "10")]));
+        let mut sim = CopasiSimulation::new(model);
+        sim.set_ode(ode::OdeOptions { relative_tolerance: 1e-10, ..Default::default() });
+        let result = sim.run(5.0, 50);
+        let crossing = 10.0f64.ln();
+        for (t, a) in result.time.iter().zip(&result.concentrations["A"]) {
+            let since = if *t < crossing { *t } else if *t < 2.0 * crossing { t - crossing } else { t - 2.0 * crossing };
+            assert!((a - 10.0 * (-since).exp()).abs() < 1e-5, "{} {}", t, a);
+        }
+    }
+
+    #[test]
+    fn test_priority_delay_and_cascade() {
+        let mut model = decay();
+        for id in ["X", "Y", "B", "C"] {
+            model.add_parameter(Parameter::new(id, 0.0));
+        }
+        let mut low = event("low", "time >= 1", &[("X", "1")]);
+        low.priority = Some("1".into());
+        let mut high = event("high", "time >= 1", &[("X", "2")]);
+        high.priority = Some("2".into());
+        // Half of k = 1, evaluated when the event fires
+        let mut delayed = event("delayed", "time >= 1", &[("Y", "A")]);
+        delayed.delay = Some("k / 2".into());
+        model.events.extend([low, high, delayed]);
+        model.events.push(event("first", "time >= 2", &[("B", "1")]));
+        model.events.push(event("second", "B > 0.5", &[("C", "5")]));
+
+        let mut sim = CopasiSimulation::new(model);
+        sim.set_ode(ode::OdeOptions { relative_tolerance: 1e-10, ..Default::default() });
+        sim.run(1.2, 4);
+        // The higher priority executes first, so the lower one sets X last
+        assert_eq!(sim.model.get_parameter("X").unwrap().value, 1.0);
+        assert_eq!(sim.model.get_parameter("Y").unwrap().value, 0.0);
+        sim.run(0.5, 5);
+        let y = sim.model.get_parameter("Y").unwrap().value;
+        assert!((y - 10.0 * (-1.0f64).exp()).abs() < 1e-6, "{}", y);
+        sim.run(0.2, 1);
+        assert_eq!(sim.model.get_parameter("C").unwrap().value, 0.0);
+        sim.run(0.2, 1);
+        assert_eq!(sim.model.get_parameter("C").unwrap().value, 5.0);
+    }
+
+    #[test]
+    fn test_delay_from_trigger_time() {
+        // The delay is k = 1 when the event fires at 1, though k is 10 by
+        // the time it is due
+        let mut model = decay();
+        model.add_parameter(Parameter::new("Y", 0.0));
+        let mut late = event("late", "time >= 1", &[("Y", "1")]);
+        late.delay = Some("k".into());
+        model.events.push(late);
+        model.events.push(event("faster", "time >= 1.5", &[("k", "10")]));
+        let mut sim = CopasiSimulation::new(model);
+        sim.run(1.9, 19);
+        assert_eq!(sim.model.get_parameter("Y").unwrap().value, 0.0);
+        sim.run(0.2, 2);
+        assert_eq!(sim.model.get_parameter("Y").unwrap().value, 1.0);
+    }
+}
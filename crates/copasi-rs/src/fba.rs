@@ -0,0 +1,234 @@
+//! Flux balance analysis
+//!
+//! [`FluxBalance`] analyzes the reaction network alone, without kinetics,
+//! by the constraints a steady state puts on the fluxes v:
+//!
+//! - The species do not change, N v = 0, except boundary species, which
+//!   are exchanged freely.
+//! - Each flux lies within bounds: those of the SBML fbc package where the
+//!   model has them ([`FluxBound`]), otherwise [0, ∞) for irreversible
+//!   reactions and unbounded for reversible ones.
+//!
+//! [`FluxBalance::optimize`] finds the fluxes optimizing a linear
+//! objective, by default the model's [`FluxObjective`], by the simplex
+//! method of [`crate::lp`]. [`FluxBalance::variability`] finds the range
+//! of each flux over the distributions keeping the objective within a
+//! fraction of its optimum, two linear programs per reaction run in
+//! parallel with rayon.
+
+use crate::*;
+use crate::lp::{self, LpSolution};
+use rayon::prelude::*;
+
+/// Optimal fluxes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FluxDistribution {
+    /// Optimal value of the objective
+    pub objective: f64,
+    /// Fluxes by reaction
+    pub fluxes: HashMap<String, f64>,
+}
+
+/// Flux balance analysis task
+#[derive(Debug, Clone)]
+pub struct FluxBalance {
+    pub model: SbmlModel,
+    /// Lower and upper bound of each flux, by reaction
+    pub bounds: HashMap<String, (f64, f64)>,
+    /// Coefficients of the objective, by reaction
+    pub objective: HashMap<String, f64>,
+    /// Whether the objective is maximized, or else minimized
+    pub maximize: bool,
+}
+
+impl FluxBalance {
+    /// Task with the flux bounds and objective of the model's fbc package,
+    /// where given
+    pub fn new(model: SbmlModel) -> Self {
+        let value = |id: &Option<String>| id.as_ref().and_then(|id| model.get_parameter(id)).map(|p| p.value);
+        let bounds = model.reactions.iter()
+            .map(|r| {
+                let default = (if r.reversible { f64::NEG_INFINITY } else { 0.0 }, f64::INFINITY);
+                let bound = model.flux_bounds.iter().find(|b| b.reaction == r.id);
+                let lower = bound.and_then(|b| value(&b.lower)).unwrap_or(default.0);
+                let upper = bound.and_then(|b| value(&b.upper)).unwrap_or(default.1);
+                (r.id.clone(), (lower, upper))
+            })
+            .collect();
+        let (objective, maximize) = match &model.flux_objective {
+            Some(o) => (o.coefficients.iter().cloned().collect(), o.maximize),
+            None => (HashMap::new(), true),
+        };
+        Self { model, bounds, objective, maximize }
+    }
+
+    /// Bound the flux of `reaction` between `lower` and `upper`
+    pub fn set_bounds(&mut self, reaction: &str, lower: f64, upper: f64) {
+        self.bounds.insert(reaction.to_string(), (lower, upper));
+    }
+
+    /// Weigh the flux of `reaction` by `coefficient` in the objective
+    pub fn set_objective(&mut self, reaction: &str, coefficient: f64) {
+        self.objective.insert(reaction.to_string(), coefficient);
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.objective.is_empty() {
+            return Err(OldiesError::SimulationError("Flux balance analysis needs an objective".into()));
+        }
+        for reaction in self.bounds.keys().chain(self.objective.keys()) {
+            if !self.model.reactions.iter().any(|r| &r.id == reaction) {
+                return Err(OldiesError::SimulationError(format!("Unknown reaction: {}", reaction)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Objective coefficients in reaction order, negated when minimizing
+    fn costs(&self) -> Vec<f64> {
+        let sign = if self.maximize { 1.0 } else { -1.0 };
+        self.model.reactions.iter()
+            .map(|r| sign * self.objective.get(&r.id).copied().unwrap_or(0.0))
+            .collect()
+    }
+
+    /// Maximize `c`^T v under the steady state and the bounds, and with
+    /// the maximized objective at least `threshold` if given
+    fn solve(&self, c: &[f64], threshold: Option<f64>) -> Result<LpSolution> {
+        let stoich = self.model.stoichiometry_matrix();
+        let (n, m) = stoich.dim();
+        let (mut lower, mut upper): (Vec<f64>, Vec<f64>) = self.model.reactions.iter()
+            .map(|r| self.bounds.get(&r.id).copied().unwrap_or((f64::NEG_INFINITY, f64::INFINITY)))
+            .unzip();
+        let Some(threshold) = threshold else {
+            return lp::maximize(c, &stoich, &vec![0.0; n], &lower, &upper);
+        };
+
+        // costs^T v - s = threshold with s >= 0
+        let mut a = Array2::zeros((n + 1, m + 1));
+        a.slice_mut(ndarray::s![..n, ..m]).assign(&stoich);
+        for (j, cost) in self.costs().into_iter().enumerate() {
+            a[[n, j]] = cost;
+        }
+        a[[n, m]] = -1.0;
+        let mut b = vec![0.0; n];
+        b.push(threshold);
+        lower.push(0.0);
+        upper.push(f64::INFINITY);
+        let c: Vec<f64> = c.iter().copied().chain([0.0]).collect();
+        let mut solution = lp::maximize(&c, &a, &b, &lower, &upper)?;
+        solution.x.truncate(m);
+        Ok(solution)
+    }
+
+    /// Fluxes optimizing the objective
+    pub fn optimize(&self) -> Result<FluxDistribution> {
+        self.validate()?;
+        let costs = self.costs();
+        let solution = self.solve(&costs, None)?;
+        Ok(FluxDistribution {
+            objective: if self.maximize { solution.objective } else { -solution.objective },
+            fluxes: self.model.reactions.iter()
+                .zip(solution.x)
+                .map(|(r, v)| (r.id.clone(), v))
+                .collect(),
+        })
+    }
+
+    /// Least and greatest flux of each reaction with the objective within
+    /// `fraction` of its optimum (1 keeping it optimal, 0 allowing any
+    /// value up to |optimum| worse)
+    pub fn variability(&self, fraction: f64) -> Result<HashMap<String, (f64, f64)>> {
+        self.validate()?;
+        let costs = self.costs();
+        let optimum = self.solve(&costs, None)?.objective;
+        let threshold = optimum - (1.0 - fraction) * optimum.abs();
+
+        let m = self.model.reactions.len();
+        let ranges = (0..2 * m).into_par_iter()
+            .map(|k| {
+                let mut c = vec![0.0; m];
+                c[k / 2] = if k % 2 == 0 { -1.0 } else { 1.0 };
+                let value = self.solve(&c, Some(threshold))?.objective;
+                Ok(if k % 2 == 0 { -value } else { value })
+            })
+            .collect::<Result<Vec<f64>>>()?;
+        Ok(self.model.reactions.iter()
+            .enumerate()
+            .map(|(j, r)| (r.id.clone(), (ranges[2 * j], ranges[2 * j + 1])))
+            .collect())
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network() -> SbmlModel {
+        // Uptake of A up to 10, A -> B or A -> C, and both secreted
+        let mut model = SbmlModel::new("branch");
+        model.add_compartment(Compartment::new("c", 1.0));
+        for s in ["A", "B", "C"] {
+            model.add_species(Species::new(s, "c", 0.0));
+        }
+        model.add_parameter(Parameter::new("k", 1.0));
+        model.add_parameter(Parameter::new("uptake", 10.0));
+        let mut uptake = Reaction::simple("uptake", "A", "A", "k");
+        uptake.reactants.clear();
+        model.add_reaction(uptake);
+        model.add_reaction(Reaction::simple("to_b", "A", "B", "k"));
+        model.add_reaction(Reaction::simple("to_c", "A", "C", "k"));
+        for s in ["B", "C"] {
+            let mut secretion = Reaction::simple(&format!("out_{}", s), s, s, "k");
+            secretion.products.clear();
+            model.add_reaction(secretion);
+        }
+        for r in &mut model.reactions {
+            r.reversible = false;
+        }
+        model.flux_bounds.push(FluxBound { reaction: "uptake".into(), lower: None, upper: Some("uptake".into()) });
+        model.flux_objective = Some(FluxObjective {
+            id: "secretion".into(),
+            maximize: true,
+            coefficients: vec![("out_B".into(), 1.0)],
+        });
+        model
+    }
+
+    #[test]
+    fn test_optimize() {
+        let mut fba = FluxBalance::new(network());
+        assert_eq!(fba.bounds["uptake"], (0.0, 10.0));
+        let optimum = fba.optimize().unwrap();
+        assert!((optimum.objective - 10.0).abs() < 1e-9);
+        assert!((optimum.fluxes["to_b"] - 10.0).abs() < 1e-9);
+        assert!(optimum.fluxes["to_c"].abs() < 1e-9);
+
+        // Minimizing C secreted with at least 4 of it
+        fba.objective = [("out_C".to_string(), 1.0)].into_iter().collect();
+        fba.maximize = false;
+        fba.set_bounds("out_C", 4.0, f64::INFINITY);
+        let optimum = fba.optimize().unwrap();
+        assert!((optimum.objective - 4.0).abs() < 1e-9);
+
+        fba.set_objective("missing", 1.0);
+        assert!(fba.optimize().is_err());
+    }
+
+    #[test]
+    fn test_variability() {
+        let fba = FluxBalance::new(network());
+        let ranges = fba.variability(1.0).unwrap();
+        assert!((ranges["out_B"].0 - 10.0).abs() < 1e-9 && (ranges["out_B"].1 - 10.0).abs() < 1e-9);
+        assert!(ranges["to_c"].1.abs() < 1e-9);
+        let ranges = fba.variability(0.5).unwrap();
+        for (reaction, (least, greatest)) in [("out_B", (5.0, 10.0)), ("to_c", (0.0, 5.0)), ("uptake", (5.0, 10.0))] {
+            let range = ranges[reaction];
+            assert!((range.0 - least).abs() < 1e-9 && (range.1 - greatest).abs() < 1e-9, "{} {:?}", reaction, range);
+        }
+    }
+}
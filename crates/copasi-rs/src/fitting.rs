@@ -0,0 +1,174 @@
+//! Parameter estimation
+//!
+//! A [`FitProblem`] measures how far deterministic time courses are from
+//! experimental data as the sum of squared residuals over every measured
+//! species and time, and fits parameters within their ranges by any
+//! [`Optimizer`] of [`crate::optimization`].
+
+use crate::*;
+use crate::global_sensitivity::ParameterRange;
+use crate::optimization::{Optimizer, Progress};
+
+/// Measured time course
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Experiment {
+    /// Measurement times, increasing
+    pub time: Vec<f64>,
+    /// Measured concentrations by species, one per time (NaN where not
+    /// measured)
+    pub data: HashMap<String, Vec<f64>>,
+}
+
+/// Result of a fit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FitResult {
+    /// Fitted parameter values
+    pub parameters: HashMap<String, f64>,
+    /// Sum of squared residuals
+    pub objective: f64,
+    /// Time courses simulated
+    pub evaluations: usize,
+}
+
+/// Parameter estimation task
+#[derive(Debug, Clone)]
+pub struct FitProblem {
+    pub model: SbmlModel,
+    pub parameters: Vec<ParameterRange>,
+    pub experiments: Vec<Experiment>,
+}
+
+impl FitProblem {
+    pub fn new(model: SbmlModel) -> Self {
+        Self { model, parameters: Vec::new(), experiments: Vec::new() }
+    }
+
+    /// Fit the parameter `id` between `lower` and `upper`
+    pub fn add_parameter(&mut self, id: &str, lower: f64, upper: f64) {
+        self.parameters.push(ParameterRange { id: id.to_string(), lower, upper });
+    }
+
+    pub fn add_experiment(&mut self, experiment: Experiment) {
+        self.experiments.push(experiment);
+    }
+
+    /// Sum of squared residuals with the parameters at `values`
+    pub fn objective(&self, values: &[f64]) -> f64 {
+        let mut model = self.model.clone();
+        for (range, value) in self.parameters.iter().zip(values) {
+            if let Some(p) = model.parameters.iter_mut().find(|p| p.id == range.id) {
+                p.value = *value;
+            }
+        }
+        let index: HashMap<&str, usize> = model.species.iter()
+            .enumerate()
+            .map(|(i, s)| (s.id.as_str(), i))
+            .collect();
+
+        let mut sum = 0.0;
+        for experiment in &self.experiments {
+            let mut sim = CopasiSimulation::new(model.clone());
+            for (k, &t) in experiment.time.iter().enumerate() {
+                if t > sim.t {
+                    sim.step(t - sim.t);
+                }
+                for (species, measured) in &experiment.data {
+                    let measured = measured[k];
+                    if !measured.is_nan() {
+                        sum += (sim.concentration(index[species.as_str()]) - measured).powi(2);
+                    }
+                }
+            }
+        }
+        sum
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.parameters.is_empty() || self.experiments.is_empty() {
+            return Err(OldiesError::SimulationError("Fitting needs parameters and experiments".into()));
+        }
+        for range in &self.parameters {
+            if self.model.get_parameter(&range.id).is_none() {
+                return Err(OldiesError::SimulationError(format!("Unknown parameter: {}", range.id)));
+            }
+        }
+        for experiment in &self.experiments {
+            for (species, values) in &experiment.data {
+                if !self.model.species.iter().any(|s| &s.id == species) {
+                    return Err(OldiesError::SimulationError(format!("Unknown species: {}", species)));
+                }
+                if values.len() != experiment.time.len() {
+                    return Err(OldiesError::SimulationError(format!(
+                        "{} measurements of {} for {} times",
+                        values.len(), species, experiment.time.len()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fit the parameters with `optimizer`, reporting to `progress`
+    pub fn fit(
+        &self,
+        optimizer: &mut dyn Optimizer,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<FitResult> {
+        self.validate()?;
+        let bounds: Vec<(f64, f64)> = self.parameters.iter().map(|r| (r.lower, r.upper)).collect();
+        let optimum = optimizer.minimize(&|values| self.objective(values), &bounds, progress);
+        Ok(FitResult {
+            parameters: self.parameters.iter()
+                .zip(&optimum.parameters)
+                .map(|(range, value)| (range.id.clone(), *value))
+                .collect(),
+            objective: optimum.value,
+            evaluations: optimum.evaluations,
+        })
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimization::ParticleSwarm;
+
+    #[test]
+    fn test_fit_decay() {
+        // Data of A -> B at k = 0.7 from A = 2
+        let mut model = SbmlModel::new("decay");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 2.0));
+        model.add_species(Species::new("B", "c", 0.0));
+        model.add_parameter(Parameter::new("k", 1.0));
+        model.add_reaction(Reaction::simple("conversion", "A", "B", "k"));
+
+        let time: Vec<f64> = (0..=10).map(|i| 0.5 * i as f64).collect();
+        let a: Vec<f64> = time.iter().map(|t| 2.0 * (-0.7 * t).exp()).collect();
+        let mut b: Vec<f64> = a.iter().map(|a| 2.0 - a).collect();
+        b[3] = f64::NAN;
+        let mut problem = FitProblem::new(model);
+        problem.add_parameter("k", 0.01, 5.0);
+        problem.add_experiment(Experiment {
+            time,
+            data: [("A".to_string(), a), ("B".to_string(), b)].into_iter().collect(),
+        });
+
+        let mut swarm = ParticleSwarm { swarm: 10, iterations: 30, ..Default::default() };
+        let mut reports = 0;
+        let result = problem.fit(&mut swarm, &mut |_| {
+            reports += 1;
+            true
+        }).unwrap();
+        assert_eq!(reports, 30);
+        assert!((result.parameters["k"] - 0.7).abs() < 1e-3, "{:?}", result);
+        assert!(result.objective < 1e-5);
+
+        problem.add_parameter("kk", 0.0, 1.0);
+        assert!(problem.fit(&mut swarm, &mut |_| true).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! Global sensitivity analysis
+//!
+//! [`GlobalSensitivity`] varies parameters over whole ranges rather than
+//! around one point, summarizing time courses by [`Observable`]s:
+//!
+//! - [`GlobalSensitivity::morris`] screens parameters by Morris elementary
+//!   effects: random one-at-a-time trajectories on a grid of levels. A
+//!   large mean absolute effect μ* marks an influential parameter, a large
+//!   standard deviation σ one acting nonlinearly or through interactions.
+//! - [`GlobalSensitivity::sobol`] estimates Sobol variance-based indices
+//!   from Saltelli's sampling scheme, N (k + 2) time courses for k
+//!   parameters: first order indices by Saltelli et al. (2010), total
+//!   indices by Jansen's estimator.
+//!
+//! Parameters are sampled uniformly between their bounds by the seeded
+//! [`oldies_core::Rng`], and the time courses of a sample run in
+//! parallel with rayon.
+
+use crate::*;
+use oldies_core::Rng;
+use rayon::prelude::*;
+
+/// Range of a parameter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParameterRange {
+    pub id: String,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// Summary of a time course
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Observable {
+    /// Species concentration at the end
+    Final(String),
+    /// Time average of a species concentration
+    Mean(String),
+    /// Largest species concentration
+    Maximum(String),
+}
+
+impl Observable {
+    fn species(&self) -> &str {
+        match self {
+            Observable::Final(s) | Observable::Mean(s) | Observable::Maximum(s) => s,
+        }
+    }
+
+    /// Name of the observable in results, such as `final(X)`
+    pub fn name(&self) -> String {
+        match self {
+            Observable::Final(s) => format!("final({})", s),
+            Observable::Mean(s) => format!("mean({})", s),
+            Observable::Maximum(s) => format!("max({})", s),
+        }
+    }
+
+    fn value(&self, result: &SimulationResult) -> f64 {
+        let values = &result.concentrations[self.species()];
+        match self {
+            Observable::Final(_) => values.last().copied().unwrap_or(f64::NAN),
+            Observable::Mean(_) => {
+                // Trapezoidal time average
+                let span = result.time.last().unwrap_or(&0.0) - result.time[0];
+                let area: f64 = result.time.windows(2)
+                    .zip(values.windows(2))
+                    .map(|(t, v)| 0.5 * (t[1] - t[0]) * (v[0] + v[1]))
+                    .sum();
+                if span > 0.0 { area / span } else { values[0] }
+            }
+            Observable::Maximum(_) => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+/// Morris elementary effects of a parameter on an observable, per whole
+/// range of the parameter
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ElementaryEffects {
+    /// Mean effect
+    pub mu: f64,
+    /// Mean absolute effect
+    pub mu_star: f64,
+    /// Standard deviation of the effects
+    pub sigma: f64,
+}
+
+/// Sobol indices of a parameter on an observable
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SobolIndices {
+    /// Share of the variance due to the parameter alone
+    pub first_order: f64,
+    /// Share of the variance due to the parameter and its interactions
+    pub total: f64,
+}
+
+/// Global sensitivity analysis task
+#[derive(Debug, Clone)]
+pub struct GlobalSensitivity {
+    pub model: SbmlModel,
+    pub parameters: Vec<ParameterRange>,
+    pub observables: Vec<Observable>,
+    /// Duration of each deterministic time course
+    pub duration: f64,
+    /// Output points of each time course
+    pub n_points: usize,
+    pub seed: u64,
+}
+
+impl GlobalSensitivity {
+    pub fn new(model: SbmlModel, duration: f64, n_points: usize) -> Self {
+        Self {
+            model,
+            parameters: Vec::new(),
+            observables: Vec::new(),
+            duration,
+            n_points,
+            seed: 42,
+        }
+    }
+
+    /// Vary the parameter `id` between `lower` and `upper`
+    pub fn add_parameter(&mut self, id: &str, lower: f64, upper: f64) {
+        self.parameters.push(ParameterRange { id: id.to_string(), lower, upper });
+    }
+
+    pub fn add_observable(&mut self, observable: Observable) {
+        self.observables.push(observable);
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.parameters.is_empty() || self.observables.is_empty() {
+            return Err(OldiesError::SimulationError(
+                "Global sensitivity analysis needs parameters and observables".into(),
+            ));
+        }
+        for range in &self.parameters {
+            if self.model.get_parameter(&range.id).is_none() {
+                return Err(OldiesError::SimulationError(format!("Unknown parameter: {}", range.id)));
+            }
+        }
+        for observable in &self.observables {
+            if !self.model.species.iter().any(|s| s.id == observable.species()) {
+                return Err(OldiesError::SimulationError(format!("Unknown species: {}", observable.species())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Observables of the time courses at the points of the unit cube,
+    /// mapped onto the parameter ranges, in parallel
+    fn evaluate(&self, points: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        points.par_iter()
+            .map(|point| {
+                let mut model = self.model.clone();
+                for (range, u) in self.parameters.iter().zip(point) {
+                    if let Some(p) = model.parameters.iter_mut().find(|p| p.id == range.id) {
+                        p.value = range.lower + u * (range.upper - range.lower);
+                    }
+                }
+                let result = CopasiSimulation::new(model).run(self.duration, self.n_points);
+                self.observables.iter().map(|o| o.value(&result)).collect()
+            })
+            .collect()
+    }
+
+    /// Morris screening with `trajectories` one-at-a-time trajectories on
+    /// `levels` levels, by observable name, then parameter
+    pub fn morris(&self, trajectories: usize, levels: usize) -> Result<HashMap<String, HashMap<String, ElementaryEffects>>> {
+        self.validate()?;
+        if levels < 2 || trajectories < 2 {
+            return Err(OldiesError::SimulationError("Morris screening needs 2 levels and 2 trajectories".into()));
+        }
+        let k = self.parameters.len();
+        let grid = (levels - 1) as f64;
+        // Δ = p / (2 (p - 1)), on the grid
+        let delta = (levels / 2) as f64 / grid;
+        let mut rng = Rng::new(self.seed);
+
+        // k + 1 points per trajectory, and the step taken to each point
+        let mut points = Vec::with_capacity(trajectories * (k + 1));
+        let mut moves = Vec::with_capacity(trajectories * k);
+        for _ in 0..trajectories {
+            let mut x: Vec<f64> = (0..k)
+                .map(|_| ((rng.uniform() * levels as f64).floor().min(grid)) / grid)
+                .collect();
+            let mut order: Vec<usize> = (0..k).collect();
+            for i in (1..k).rev() {
+                order.swap(i, (rng.uniform() * (i + 1) as f64) as usize % (i + 1));
+            }
+            points.push(x.clone());
+            for j in order {
+                let step = if x[j] + delta <= 1.0 + 1e-12 { delta } else { -delta };
+                x[j] += step;
+                points.push(x.clone());
+                moves.push((j, step));
+            }
+        }
+        let outputs = self.evaluate(&points);
+
+        let mut result = HashMap::new();
+        for (o, observable) in self.observables.iter().enumerate() {
+            let mut effects = vec![Vec::with_capacity(trajectories); k];
+            for r in 0..trajectories {
+                for s in 0..k {
+                    let (j, step) = moves[r * k + s];
+                    let before = outputs[r * (k + 1) + s][o];
+                    let after = outputs[r * (k + 1) + s + 1][o];
+                    effects[j].push((after - before) / step);
+                }
+            }
+            let by_parameter = self.parameters.iter()
+                .zip(&effects)
+                .map(|(range, e)| {
+                    let n = e.len() as f64;
+                    let mu = e.iter().sum::<f64>() / n;
+                    let mu_star = e.iter().map(|x| x.abs()).sum::<f64>() / n;
+                    let sigma = (e.iter().map(|x| (x - mu).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+                    (range.id.clone(), ElementaryEffects { mu, mu_star, sigma })
+                })
+                .collect();
+            result.insert(observable.name(), by_parameter);
+        }
+        Ok(result)
+    }
+
+    /// Sobol indices from `samples` base samples, by observable name, then
+    /// parameter
+    pub fn sobol(&self, samples: usize) -> Result<HashMap<String, HashMap<String, SobolIndices>>> {
+        self.validate()?;
+        if samples < 2 {
+            return Err(OldiesError::SimulationError("Sobol indices need 2 samples".into()));
+        }
+        let k = self.parameters.len();
+        let mut rng = Rng::new(self.seed);
+        let mut sample = || -> Vec<Vec<f64>> {
+            (0..samples).map(|_| (0..k).map(|_| rng.uniform()).collect()).collect()
+        };
+        let (a, b) = (sample(), sample());
+
+        // A, B, then A with column i from B for each i
+        let mut points = a.clone();
+        points.extend(b.iter().cloned());
+        for i in 0..k {
+            points.extend(a.iter().zip(&b).map(|(a, b)| {
+                let mut x = a.clone();
+                x[i] = b[i];
+                x
+            }));
+        }
+        let outputs = self.evaluate(&points);
+
+        let n = samples as f64;
+        let mut result = HashMap::new();
+        for (o, observable) in self.observables.iter().enumerate() {
+            let fa: Vec<f64> = outputs[..samples].iter().map(|y| y[o]).collect();
+            let fb: Vec<f64> = outputs[samples..2 * samples].iter().map(|y| y[o]).collect();
+            let all = fa.iter().chain(&fb);
+            let mean = all.clone().sum::<f64>() / (2.0 * n);
+            let variance = all.map(|y| (y - mean).powi(2)).sum::<f64>() / (2.0 * n - 1.0);
+
+            let by_parameter = self.parameters.iter()
+                .enumerate()
+                .map(|(i, range)| {
+                    let fab = &outputs[(2 + i) * samples..(3 + i) * samples];
+                    let (mut first, mut total) = (0.0, 0.0);
+                    for s in 0..samples {
+                        first += fb[s] * (fab[s][o] - fa[s]);
+                        total += (fa[s] - fab[s][o]).powi(2);
+                    }
+                    let indices = if variance > 0.0 {
+                        SobolIndices { first_order: first / n / variance, total: total / (2.0 * n) / variance }
+                    } else {
+                        SobolIndices { first_order: 0.0, total: 0.0 }
+                    };
+                    (range.id.clone(), indices)
+                })
+                .collect();
+            result.insert(observable.name(), by_parameter);
+        }
+        Ok(result)
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task() -> GlobalSensitivity {
+        // Y made at a + b over t = 1, so Y(1) = a + b; c does nothing
+        let mut model = SbmlModel::new("production");
+        model.add_compartment(Compartment::new("cell", 1.0));
+        model.add_species(Species::new("Y", "cell", 0.0));
+        for id in ["a", "b", "c"] {
+            model.add_parameter(Parameter::new(id, 0.0));
+        }
+        for id in ["a", "b"] {
+            let mut production = Reaction::simple(id, "Y", "Y", id);
+            production.reactants.clear();
+            model.add_reaction(production);
+        }
+        let mut task = GlobalSensitivity::new(model, 1.0, 4);
+        task.add_parameter("a", 0.0, 1.0);
+        task.add_parameter("b", 0.0, 2.0);
+        task.add_parameter("c", 0.0, 1.0);
+        task.add_observable(Observable::Final("Y".into()));
+        task
+    }
+
+    #[test]
+    fn test_morris() {
+        let effects = task().morris(10, 4).unwrap();
+        let effects = &effects["final(Y)"];
+        assert!((effects["a"].mu - 1.0).abs() < 1e-6);
+        assert!((effects["b"].mu_star - 2.0).abs() < 1e-6);
+        assert!(effects["b"].sigma < 1e-6);
+        assert_eq!(effects["c"].mu_star, 0.0);
+    }
+
+    #[test]
+    fn test_sobol() {
+        // Var a = 1/12 and Var b = 4/12: S_a = 0.2, S_b = 0.8
+        let indices = task().sobol(1000).unwrap();
+        let indices = &indices["final(Y)"];
+        assert!((indices["a"].first_order - 0.2).abs() < 0.06, "{:?}", indices);
+        assert!((indices["b"].first_order - 0.8).abs() < 0.1, "{:?}", indices);
+        assert!((indices["a"].total - 0.2).abs() < 0.06, "{:?}", indices);
+        assert!((indices["b"].total - 0.8).abs() < 0.1, "{:?}", indices);
+        assert_eq!(indices["c"].first_order, 0.0);
+        assert_eq!(indices["c"].total, 0.0);
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        let mut unknown = task();
+        unknown.add_parameter("d", 0.0, 1.0);
+        assert!(unknown.sobol(10).is_err());
+        let mut unknown = task();
+        unknown.add_observable(Observable::Mean("Z".into()));
+        assert!(unknown.morris(4, 4).is_err());
+    }
+}
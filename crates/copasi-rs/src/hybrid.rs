@@ -0,0 +1,210 @@
+//! Hybrid stochastic/deterministic simulation
+//!
+//! [`SimulationMethod::Hybrid`] splits the network into fast reactions,
+//! integrated as ODEs by [`crate::ode`], and slow ones fired one at a time,
+//! in the manner of COPASI's hybrid methods:
+//!
+//! - A species turns fast above `upper_limit` molecules and slow again
+//!   below `lower_limit`, so species near a limit do not switch back and
+//!   forth. Species turning slow are rounded to whole molecules.
+//! - A reaction is fast when every species it changes is fast and its
+//!   propensity is at least `fast_propensity`.
+//! - A slow reaction fires when the integral of the total slow propensity
+//!   along the continuous trajectory reaches an exponential sample, so
+//!   slow propensities may follow the fast species between firings.
+//! - The partition is revised after every slow firing and at every output
+//!   point.
+//!
+//! The state is taken as numbers of molecules, as in [`crate::stochastic`].
+
+use crate::*;
+
+/// Settings of hybrid simulation
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct HybridOptions {
+    /// Molecules below which a fast species turns slow
+    pub lower_limit: f64,
+    /// Molecules above which a slow species turns fast
+    pub upper_limit: f64,
+    /// Least propensity of a fast reaction
+    pub fast_propensity: f64,
+}
+
+impl Default for HybridOptions {
+    fn default() -> Self {
+        Self {
+            lower_limit: 800.0,
+            upper_limit: 1000.0,
+            fast_propensity: 10.0,
+        }
+    }
+}
+
+/// Overshoot of the exponential sample by the propensity integral that
+/// is accepted when placing a slow firing
+const FIRING_TOLERANCE: f64 = 1e-3;
+
+impl CopasiSimulation {
+    /// Revise the fast species, and tell the fast reactions
+    fn partition(&mut self, stoich: &Array2<f64>) -> Vec<bool> {
+        let options = self.hybrid;
+        let fast = match self.fast_species.take() {
+            Some(mut fast) => {
+                for (i, fast) in fast.iter_mut().enumerate() {
+                    if *fast && self.state[i] < options.lower_limit {
+                        *fast = false;
+                        self.state[i] = self.state[i].round();
+                    } else if !*fast && self.state[i] > options.upper_limit {
+                        *fast = true;
+                    }
+                }
+                fast
+            }
+            None => self.state.iter_mut()
+                .map(|x| {
+                    let fast = *x > options.upper_limit;
+                    if !fast {
+                        *x = x.round();
+                    }
+                    fast
+                })
+                .collect(),
+        };
+        let (n, m) = stoich.dim();
+        let reactions = (0..m)
+            .map(|j| {
+                (0..n).all(|i| stoich[[i, j]] == 0.0 || fast[i])
+                    && self.propensity(j) >= options.fast_propensity
+            })
+            .collect();
+        self.fast_species = Some(fast);
+        reactions
+    }
+
+    /// Total propensity of the slow reactions
+    fn slow_propensity(&self, fast: &[bool]) -> f64 {
+        (0..fast.len()).filter(|&j| !fast[j]).map(|j| self.propensity(j)).sum()
+    }
+
+    /// Hybrid simulation up to the time `end`
+    pub(crate) fn step_hybrid(&mut self, end: Time) {
+        let stoich = self.model.stoichiometry_matrix();
+        let mut fast = self.partition(&stoich);
+        let mut target = self.rng.exponential(1.0);
+
+        while self.t < end {
+            let mut fast_stoich = stoich.clone();
+            for j in (0..fast.len()).filter(|&j| !fast[j]) {
+                fast_stoich.column_mut(j).fill(0.0);
+            }
+
+            // Integrate to the firing predicted by the current total
+            // propensity, halving the step while it overshoots
+            let a0 = self.slow_propensity(&fast);
+            let mut h = if a0 > 0.0 { (target / a0).min(end - self.t) } else { end - self.t };
+            let (start, state) = (self.t, self.ode_state());
+            loop {
+                self.integrate(&fast_stoich, start + h);
+                if self.t < start + h {
+                    return;
+                }
+                let remaining = target - 0.5 * (a0 + self.slow_propensity(&fast)) * h;
+                if remaining < -FIRING_TOLERANCE {
+                    self.t = start;
+                    self.set_ode_state(&state);
+                    h /= 2.0;
+                    continue;
+                }
+                target = remaining;
+                break;
+            }
+            if target > FIRING_TOLERANCE {
+                continue;
+            }
+
+            let a: Vec<f64> = (0..fast.len())
+                .map(|j| if fast[j] { 0.0 } else { self.propensity(j) })
+                .collect();
+            let mut pick = self.rng.uniform() * a.iter().sum::<f64>();
+            if let Some(j) = (0..a.len()).filter(|&j| a[j] > 0.0).find(|&j| {
+                pick -= a[j];
+                pick < 0.0
+            }) {
+                self.fire(&stoich, j);
+            }
+            target = self.rng.exponential(1.0);
+            fast = self.partition(&stoich);
+        }
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hybrid(model: SbmlModel, seed: u64) -> CopasiSimulation {
+        let mut sim = CopasiSimulation::new(model);
+        sim.set_method(SimulationMethod::Hybrid);
+        sim.set_seed(seed);
+        sim
+    }
+
+    fn decay_model(x: f64) -> SbmlModel {
+        let mut model = SbmlModel::new("decay");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("X", "c", x));
+        model.add_parameter(Parameter::new("k", 1.0));
+        let mut decay = Reaction::simple("decay", "X", "X", "k");
+        decay.products.clear();
+        model.add_reaction(decay);
+        model
+    }
+
+    #[test]
+    fn test_slow_reaction_follows_fast_species() {
+        // X decays deterministically while each X makes Y at 1e-4: Y is
+        // Poisson of mean 10 (1 - e^-2) at t = 2
+        let mut model = decay_model(1e5);
+        model.add_species(Species::new("Y", "c", 0.0));
+        model.add_parameter(Parameter::new("c", 1e-4));
+        let mut make = Reaction::simple("make", "X", "X", "c");
+        make.products.push(SpeciesReference::new("Y", 1.0));
+        model.add_reaction(make);
+
+        let runs = 200;
+        let mut total = 0.0;
+        for seed in 0..runs {
+            let result = hybrid(model.clone(), seed).run(2.0, 4);
+            let x = *result.concentrations["X"].last().unwrap();
+            let y = *result.concentrations["Y"].last().unwrap();
+            assert!((x / (1e5 * (-2.0f64).exp()) - 1.0).abs() < 1e-4, "{}", x);
+            assert_eq!(y.fract(), 0.0);
+            total += y;
+        }
+        let mean = total / runs as f64;
+        assert!((mean - 10.0 * (1.0 - (-2.0f64).exp())).abs() < 0.8, "{}", mean);
+    }
+
+    #[test]
+    fn test_repartitioning() {
+        // X falls from 2000 through the lower limit and then decays
+        // molecule by molecule
+        let runs = 40;
+        let mut total = 0.0;
+        for seed in 0..runs {
+            let result = hybrid(decay_model(2000.0), seed).run(3.0, 30);
+            let xs = &result.concentrations["X"];
+            assert!(xs[1] < 2000.0 && xs[1].fract() != 0.0);
+            for x in xs.iter().filter(|&&x| x < 700.0) {
+                assert_eq!(x.fract(), 0.0);
+            }
+            total += xs.last().unwrap();
+        }
+        let mean = total / runs as f64;
+        assert!((mean - 2000.0 * (-3.0f64).exp()).abs() < 6.0, "{}", mean);
+    }
+}
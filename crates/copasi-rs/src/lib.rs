@@ -11,22 +11,83 @@
 //! ## SBML Support
 //!
 //! This crate also provides SBML (Systems Biology Markup Language) import
-//! capabilities, the standard format for biochemical models.
+//! and export capabilities, the standard format for biochemical models: see
+//! [`SbmlModel::from_sbml_str`], [`SbmlModel::to_sbml_string`] and the
+//! [`sbml`] module. SED-ML experiments on SBML models are run, and their
+//! reports and plots written, by [`sedml`].
+//!
+//! ## Amounts and Concentrations
+//!
+//! Simulations follow the amounts of species, numbers of molecules for the
+//! stochastic methods, which reactions change by their stoichiometry
+//! whatever the sizes of the compartments. As in SBML:
+//!
+//! - A species read in an expression is its concentration, its amount over
+//!   the size of its compartment, unless it has only substance units.
+//!   Values set by assignments and rules are taken the same way.
+//! - Custom kinetic laws give amounts per time. The built-in laws give
+//!   concentrations per time, scaled by the size of the compartment of the
+//!   reaction ([`SbmlModel::reaction_compartment`]).
+//! - Compartments whose sizes change, by rules or events, keep the amounts
+//!   of their species, diluting or concentrating them. Species given by
+//!   initial concentrations start at those in the initial sizes.
+//! - Rate rules of species give the rates of change of their
+//!   concentrations, or amounts with only substance units.
 //!
 //! ## Features
 //!
-//! 1. **ODE Simulation**: Deterministic simulation with LSODA
-//! 2. **Stochastic**: Gillespie's SSA (Stochastic Simulation Algorithm)
+//! 1. **ODE Simulation**: Deterministic simulation with a stiff Rosenbrock
+//!    integrator ([`ode`]), following assignment and rate rules ([`rules`])
+//!    and executing events at their trigger crossings ([`events`]), with
+//!    cross sections of time courses ([`cross_section`])
+//! 2. **Stochastic**: Gillespie's SSA (Stochastic Simulation Algorithm),
+//!    by the direct and next reaction methods ([`stochastic`]), and
+//!    adaptive tau-leaping ([`tau_leaping`])
 //! 3. **Hybrid**: Adaptive switching between deterministic/stochastic
-//! 4. **Steady State**: Newton's method for equilibrium
-//! 5. **Parameter Estimation**: Levenberg-Marquardt, genetic algorithms
+//!    ([`hybrid`])
+//! 4. **Steady State**: Newton's method for equilibrium, with its stability
+//!    and predicted oscillations ([`steady_state`]), on the species left
+//!    independent by conserved moieties ([`moieties`]), and Lyapunov
+//!    exponents of trajectories ([`lyapunov`])
+//! 5. **Parameter Estimation**: Genetic algorithms, particle swarm, simulated
+//!    annealing and evolution strategies ([`fitting`], [`optimization`])
 //! 6. **Sensitivity Analysis**: Local and global sensitivity
-
-use oldies_core::{OldiesError, Result, Time};
+//!    ([`sensitivity`], [`global_sensitivity`])
+//! 7. **Flux Balance Analysis**: Optimal flux distributions and flux
+//!    variability under SBML fbc bounds and objectives ([`fba`]), by a
+//!    bundled simplex solver ([`lp`])
+//! 8. **Elementary Flux Modes**: Enumeration by the double description
+//!    method ([`efm`])
+
+use oldies_core::{OldiesError, Result, Rng, Time};
 use ndarray::{Array1, Array2};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+pub mod cross_section;
+pub mod efm;
+pub mod events;
+pub mod fba;
+pub mod fitting;
+pub mod global_sensitivity;
+pub mod hybrid;
+pub mod linalg;
+pub mod lp;
+pub mod lyapunov;
+pub mod math;
+pub mod moieties;
+pub mod ode;
+pub mod optimization;
+pub mod rules;
+pub mod sbml;
+pub mod sedml;
+pub mod sensitivity;
+pub mod steady_state;
+pub mod stochastic;
+pub mod tau_leaping;
+
+use math::{Expr, Functions};
+
 // =============================================================================
 // SBML CORE TYPES
 // =============================================================================
@@ -167,7 +228,8 @@ impl SpeciesReference {
     }
 }
 
-/// Kinetic law expression
+/// Kinetic law expression. The built-in laws read concentrations and give
+/// concentrations per time in the compartment of the reaction.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum KineticLaw {
     /// Mass action: k * [A]^a * [B]^b
@@ -187,14 +249,15 @@ pub enum KineticLaw {
         substrate: String,
         n: f64,
     },
-    /// Reversible Michaelis-Menten
+    /// Reversible Michaelis-Menten:
+    /// (Vf [S] / Kmf - Vr [P] / Kmr) / (1 + [S] / Kmf + [P] / Kmr)
     ReversibleMM {
         vmax_f: String,
         km_f: String,
         vmax_r: String,
         km_r: String,
     },
-    /// Custom expression (MathML string or infix)
+    /// Custom expression, as infix text (see [`math`])
     Custom(String),
 }
 
@@ -252,6 +315,39 @@ impl Reaction {
             local_parameters: Vec::new(),
         }
     }
+
+    /// Kinetic law as infix text (see [`math`]). A reversible
+    /// Michaelis-Menten law reads the first reactant and product.
+    pub fn rate_law(&self) -> String {
+        match &self.kinetic_law {
+            KineticLaw::MassAction { rate_constant } => {
+                let mut law = rate_constant.clone();
+                for sr in &self.reactants {
+                    law += &if sr.stoichiometry == 1.0 {
+                        format!(" * {}", sr.species)
+                    } else {
+                        format!(" * {}^{}", sr.species, sr.stoichiometry)
+                    };
+                }
+                law
+            }
+            KineticLaw::MichaelisMenten { vmax, km, substrate } => {
+                format!("{} * {} / ({} + {})", vmax, substrate, km, substrate)
+            }
+            KineticLaw::Hill { vmax, k, substrate, n } => {
+                format!("{} * {}^{} / ({}^{} + {}^{})", vmax, substrate, n, k, n, substrate, n)
+            }
+            KineticLaw::ReversibleMM { vmax_f, km_f, vmax_r, km_r } => {
+                let first = |refs: &[SpeciesReference]| refs.first().map_or("0".to_string(), |r| r.species.clone());
+                let (s, p) = (first(&self.reactants), first(&self.products));
+                format!(
+                    "({} * {} / {} - {} * {} / {}) / (1 + {} / {} + {} / {})",
+                    vmax_f, s, km_f, vmax_r, p, km_r, s, km_f, p, km_r
+                )
+            }
+            KineticLaw::Custom(law) => law.clone(),
+        }
+    }
 }
 
 /// Assignment rule (algebraic constraint)
@@ -273,7 +369,12 @@ pub struct RateRule {
 pub struct Event {
     pub id: String,
     pub trigger: String,
-    pub delay: Option<f64>,
+    /// Time from the trigger to the execution, evaluated when the event
+    /// fires
+    pub delay: Option<String>,
+    /// Execution order among simultaneous events, highest first
+    #[serde(default)]
+    pub priority: Option<String>,
     pub assignments: Vec<EventAssignment>,
 }
 
@@ -284,6 +385,40 @@ pub struct EventAssignment {
     pub expression: String,
 }
 
+/// Flux bounds of a reaction, as parameter ids (SBML fbc package)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FluxBound {
+    pub reaction: String,
+    pub lower: Option<String>,
+    pub upper: Option<String>,
+}
+
+/// Linear objective of flux balance analysis (SBML fbc package)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FluxObjective {
+    pub id: String,
+    /// Whether the objective is maximized, or else minimized
+    pub maximize: bool,
+    /// Reactions and their coefficients
+    pub coefficients: Vec<(String, f64)>,
+}
+
+/// Function definition (`lambda`), called by name in expressions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub id: String,
+    pub name: Option<String>,
+    pub arguments: Vec<String>,
+    pub body: String,
+}
+
+/// Initial assignment (initial value computed from other values)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitialAssignment {
+    pub symbol: String,
+    pub expression: String,
+}
+
 // =============================================================================
 // SBML MODEL
 // =============================================================================
@@ -301,6 +436,14 @@ pub struct SbmlModel {
     pub assignment_rules: Vec<AssignmentRule>,
     pub rate_rules: Vec<RateRule>,
     pub events: Vec<Event>,
+    #[serde(default)]
+    pub function_definitions: Vec<FunctionDefinition>,
+    #[serde(default)]
+    pub initial_assignments: Vec<InitialAssignment>,
+    #[serde(default)]
+    pub flux_bounds: Vec<FluxBound>,
+    #[serde(default)]
+    pub flux_objective: Option<FluxObjective>,
 }
 
 impl SbmlModel {
@@ -316,6 +459,10 @@ impl SbmlModel {
             assignment_rules: Vec::new(),
             rate_rules: Vec::new(),
             events: Vec::new(),
+            function_definitions: Vec::new(),
+            initial_assignments: Vec::new(),
+            flux_bounds: Vec::new(),
+            flux_objective: None,
         }
     }
 
@@ -349,7 +496,37 @@ impl SbmlModel {
         self.parameters.iter().find(|p| p.id == id)
     }
 
-    /// Build stoichiometry matrix
+    /// Get compartment by ID
+    pub fn get_compartment(&self, id: &str) -> Option<&Compartment> {
+        self.compartments.iter().find(|c| c.id == id)
+    }
+
+    /// Compartment of a reaction, whose size scales built-in kinetic laws
+    /// to amounts per time: that of its first reactant, or else of its
+    /// first product
+    pub fn reaction_compartment(&self, reaction: &Reaction) -> Option<&Compartment> {
+        let species = reaction.reactants.first().or(reaction.products.first())?;
+        self.get_compartment(&self.get_species(&species.species)?.compartment)
+    }
+
+    /// Function definitions by name, each with the functions defined
+    /// before it inlined
+    pub fn functions(&self) -> Result<Functions> {
+        let mut functions = Functions::new();
+        for f in &self.function_definitions {
+            let body = Expr::parse(&f.body)?.inline(&functions);
+            functions.insert(f.id.clone(), (f.arguments.clone(), body));
+        }
+        Ok(functions)
+    }
+
+    /// Parse `text`, inlining the function definitions
+    pub fn expression(&self, text: &str) -> Result<Expr> {
+        Ok(Expr::parse(text)?.inline(&self.functions()?))
+    }
+
+    /// Build stoichiometry matrix. Boundary species are not changed by
+    /// reactions and have zero rows.
     pub fn stoichiometry_matrix(&self) -> Array2<f64> {
         let n_species = self.species.len();
         let n_reactions = self.reactions.len();
@@ -374,6 +551,11 @@ impl SbmlModel {
                 }
             }
         }
+        for (i, species) in self.species.iter().enumerate() {
+            if species.boundary_condition {
+                matrix.row_mut(i).fill(0.0);
+            }
+        }
 
         matrix
     }
@@ -386,13 +568,16 @@ impl SbmlModel {
 /// Simulation method
 #[derive(Debug, Clone, Copy)]
 pub enum SimulationMethod {
-    /// Deterministic ODE (LSODA)
+    /// Deterministic ODE (adaptive Rosenbrock, for stiff systems)
     Deterministic,
-    /// Stochastic (Gillespie SSA)
+    /// Stochastic (Gillespie SSA, direct method)
     Stochastic,
-    /// Hybrid (adaptive switching)
+    /// Stochastic (Gibson-Bruck next reaction method)
+    NextReaction,
+    /// Hybrid (fast reactions as ODEs, slow ones stochastic, repartitioned
+    /// as the simulation runs)
     Hybrid,
-    /// Tau-leaping (approximate stochastic)
+    /// Tau-leaping (approximate stochastic, adaptive)
     TauLeaping,
 }
 
@@ -405,13 +590,17 @@ pub struct SimulationResult {
     pub concentrations: HashMap<String, Vec<f64>>,
     /// Reaction fluxes (optional)
     pub fluxes: Option<HashMap<String, Vec<f64>>>,
+    /// Sensitivities dS/dp over time, by parameter, then species, of
+    /// deterministic time courses with sensitivities selected
+    #[serde(default)]
+    pub sensitivities: Option<HashMap<String, HashMap<String, Vec<f64>>>>,
 }
 
 /// COPASI-style simulator
 pub struct CopasiSimulation {
     model: SbmlModel,
     method: SimulationMethod,
-    /// Current state (concentrations)
+    /// Current state (species amounts)
     state: Array1<f64>,
     /// Current time
     t: Time,
@@ -419,42 +608,179 @@ pub struct CopasiSimulation {
     dt: Time,
     /// RNG for stochastic simulations
     rng_seed: u64,
+    rng: Rng,
+    /// Firing times of the next reaction method, kept between steps
+    next_reaction: Option<stochastic::NextReaction>,
+    tau_leaping: tau_leaping::TauLeapingOptions,
+    ode: ode::OdeOptions,
+    /// Step size of the deterministic integrator, kept between steps
+    ode_step: Option<f64>,
+    hybrid: hybrid::HybridOptions,
+    /// Species of hybrid simulation taken as fast, kept between steps
+    fast_species: Option<Vec<bool>>,
+    /// Sensitivities integrated alongside deterministic time courses
+    sensitivities: Option<sensitivity::Sensitivities>,
+    /// Parsed events and their pending executions
+    events: events::Events,
+    rules: rules::Rules,
+    /// Reduction of the last stoichiometry matrix integrated
+    reduction: Option<(Array2<f64>, moieties::Reduction)>,
+    /// Parsed custom kinetic laws, by reaction (`None` for the others and
+    /// for laws that do not parse, whose rate is NaN)
+    laws: Vec<Option<Expr>>,
 }
 
 impl CopasiSimulation {
     /// Create new simulation
     pub fn new(model: SbmlModel) -> Self {
-        let n = model.species.len();
-        let mut state = Array1::zeros(n);
-
-        // Initialize from model
-        for (i, species) in model.species.iter().enumerate() {
-            state[i] = species.initial_concentration.unwrap_or(0.0);
-        }
+        // Initial amounts, those of species given in concentrations set
+        // by `apply_initial_concentrations`
+        let state = model.species.iter().map(|s| s.initial_amount.unwrap_or(0.0)).collect();
+
+        let laws = model.reactions.iter()
+            .map(|r| match &r.kinetic_law {
+                KineticLaw::Custom(text) => model.expression(text).ok(),
+                _ => None,
+            })
+            .collect();
 
-        Self {
+        let events = events::Events::new(&model);
+        let rules = rules::Rules::new(&model);
+        let mut sim = Self {
             model,
             method: SimulationMethod::Deterministic,
             state,
             t: 0.0,
             dt: 0.01,
             rng_seed: 42,
+            rng: Rng::new(42),
+            next_reaction: None,
+            tau_leaping: Default::default(),
+            ode: Default::default(),
+            ode_step: None,
+            hybrid: Default::default(),
+            fast_species: None,
+            sensitivities: None,
+            events,
+            rules,
+            reduction: None,
+            laws,
+        };
+        sim.apply_initial_concentrations();
+        sim.apply_initial_assignments();
+        sim.apply_assignment_rules();
+        sim.apply_initial_concentrations();
+        sim.apply_assignment_rules();
+        sim.reset_events();
+        sim
+    }
+
+    /// Set the amounts of the species given by initial concentrations from
+    /// the current sizes of their compartments, which initial assignments
+    /// and assignment rules may have set, except species those set
+    fn apply_initial_concentrations(&mut self) {
+        let assigned: Vec<&str> = self.model.initial_assignments.iter()
+            .map(|a| a.symbol.as_str())
+            .chain(self.model.assignment_rules.iter().map(|r| r.variable.as_str()))
+            .collect();
+        for (i, species) in self.model.species.iter().enumerate() {
+            if let (Some(c), false) = (species.initial_concentration, assigned.contains(&species.id.as_str())) {
+                self.state[i] = c * self.volume(i);
+            }
+        }
+    }
+
+    /// Set the values the model's initial assignments compute. Passes are
+    /// repeated so assignments may read values assigned after them, or by
+    /// assignment rules.
+    fn apply_initial_assignments(&mut self) {
+        let assignments: Vec<(String, Expr)> = self.model.initial_assignments.iter()
+            .filter_map(|a| Some((a.symbol.clone(), self.model.expression(&a.expression).ok()?)))
+            .collect();
+        for _ in 0..assignments.len() {
+            self.apply_assignment_rules();
+            self.apply_initial_concentrations();
+            for (symbol, expr) in &assignments {
+                let value = expr.eval(&|id| self.symbol_value(id, &[]));
+                self.set_value(symbol, value);
+            }
+        }
+    }
+
+    /// Set a species concentration (amount if it has only substance
+    /// units), parameter value or compartment size
+    fn set_value(&mut self, id: &str, value: f64) {
+        if let Some(i) = self.model.species.iter().position(|s| s.id == id) {
+            self.state[i] = match self.model.species[i].has_only_substance_units {
+                true => value,
+                false => value * self.volume(i),
+            };
+        } else if let Some(p) = self.model.parameters.iter_mut().find(|p| p.id == id) {
+            p.value = value;
+        } else if let Some(c) = self.model.compartments.iter_mut().find(|c| c.id == id) {
+            c.size = value;
         }
     }
 
     /// Set simulation method
     pub fn set_method(&mut self, method: SimulationMethod) {
         self.method = method;
+        self.next_reaction = None;
+        self.ode_step = None;
+        self.fast_species = None;
+    }
+
+    /// Set the settings of the deterministic integrator
+    pub fn set_ode(&mut self, options: ode::OdeOptions) {
+        self.ode = options;
+        self.ode_step = None;
+    }
+
+    /// Set the settings of tau-leaping
+    pub fn set_tau_leaping(&mut self, options: tau_leaping::TauLeapingOptions) {
+        self.tau_leaping = options;
+    }
+
+    /// Set the settings of hybrid simulation
+    pub fn set_hybrid(&mut self, options: hybrid::HybridOptions) {
+        self.hybrid = options;
+        self.fast_species = None;
+    }
+
+    /// Seed the RNG of stochastic simulations
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng_seed = seed;
+        self.rng = Rng::new(seed);
+        self.next_reaction = None;
     }
 
     /// Get current concentrations
     pub fn get_concentrations(&self) -> HashMap<String, f64> {
         self.model.species.iter()
             .enumerate()
-            .map(|(i, s)| (s.id.clone(), self.state[i]))
+            .map(|(i, s)| (s.id.clone(), self.concentration(i)))
             .collect()
     }
 
+    /// Size of the compartment of the `i`th species
+    fn volume(&self, i: usize) -> f64 {
+        self.model.get_compartment(&self.model.species[i].compartment).map_or(1.0, |c| c.size)
+    }
+
+    /// Concentration of the `i`th species
+    fn concentration(&self, i: usize) -> f64 {
+        self.state[i] / self.volume(i)
+    }
+
+    /// Value of the `i`th species in expressions: its concentration, or
+    /// its amount if it has only substance units
+    fn species_value(&self, i: usize) -> f64 {
+        match self.model.species[i].has_only_substance_units {
+            true => self.state[i],
+            false => self.concentration(i),
+        }
+    }
+
     /// Run time course simulation
     pub fn run(&mut self, duration: f64, n_points: usize) -> SimulationResult {
         let dt = duration / n_points as f64;
@@ -466,15 +792,29 @@ impl CopasiSimulation {
         // Record initial state
         time.push(self.t);
         for (i, species) in self.model.species.iter().enumerate() {
-            concentrations.get_mut(&species.id).unwrap().push(self.state[i]);
+            concentrations.get_mut(&species.id).unwrap().push(self.concentration(i));
         }
+        let mut sensitivities: Option<HashMap<String, HashMap<String, Vec<f64>>>> = self.get_sensitivities()
+            .filter(|_| matches!(self.method, SimulationMethod::Deterministic))
+            .map(|current| {
+                current.into_iter()
+                    .map(|(p, by_species)| (p, by_species.into_iter().map(|(id, v)| (id, vec![v])).collect()))
+                    .collect()
+            });
 
         // Run simulation
         for _ in 0..n_points {
             self.step(dt);
             time.push(self.t);
             for (i, species) in self.model.species.iter().enumerate() {
-                concentrations.get_mut(&species.id).unwrap().push(self.state[i]);
+                concentrations.get_mut(&species.id).unwrap().push(self.concentration(i));
+            }
+            if let (Some(all), Some(current)) = (&mut sensitivities, self.get_sensitivities()) {
+                for (p, by_species) in current {
+                    for (id, v) in by_species {
+                        all.get_mut(&p).unwrap().get_mut(&id).unwrap().push(v);
+                    }
+                }
             }
         }
 
@@ -482,61 +822,24 @@ impl CopasiSimulation {
             time,
             concentrations,
             fluxes: None,
+            sensitivities,
         }
     }
 
     /// Single integration step
     fn step(&mut self, dt: f64) {
+        let end = self.t + dt;
         match self.method {
-            SimulationMethod::Deterministic => self.step_deterministic(dt),
-            SimulationMethod::Stochastic => self.step_stochastic(),
-            SimulationMethod::TauLeaping => self.step_tau_leap(dt),
-            SimulationMethod::Hybrid => self.step_hybrid(dt),
+            SimulationMethod::Deterministic if self.sensitivities.is_some() => self.step_sensitivities(end),
+            SimulationMethod::Deterministic if !self.model.events.is_empty() => self.step_events(end),
+            SimulationMethod::Deterministic => self.step_deterministic(end),
+            SimulationMethod::Stochastic => self.step_direct(end),
+            SimulationMethod::NextReaction => self.step_next_reaction(end),
+            SimulationMethod::TauLeaping => self.step_tau_leap(end),
+            SimulationMethod::Hybrid => self.step_hybrid(end),
         }
-        self.t += dt;
-    }
-
-    /// Deterministic step (Euler, simple)
-    fn step_deterministic(&mut self, dt: f64) {
-        let rates = self.compute_rates();
-        let stoich = self.model.stoichiometry_matrix();
-
-        // dS/dt = N * v
-        let dstate = stoich.dot(&rates);
-        self.state = &self.state + &(&dstate * dt);
-
-        // Clamp to non-negative
-        for x in self.state.iter_mut() {
-            if *x < 0.0 {
-                *x = 0.0;
-            }
-        }
-    }
-
-    /// Stochastic step (Gillespie SSA)
-    fn step_stochastic(&mut self) {
-        // Simplified Gillespie - full implementation would use propensities
-        // and exponential waiting times
-        let rates = self.compute_rates();
-        let total_rate: f64 = rates.iter().sum();
-
-        if total_rate > 0.0 {
-            // Simple random selection (not proper SSA)
-            let dt = 1.0 / total_rate;
-            self.step_deterministic(dt);
-        }
-    }
-
-    /// Tau-leaping step
-    fn step_tau_leap(&mut self, tau: f64) {
-        // Simplified tau-leaping
-        self.step_deterministic(tau);
-    }
-
-    /// Hybrid step
-    fn step_hybrid(&mut self, dt: f64) {
-        // For now, just use deterministic
-        self.step_deterministic(dt);
+        self.t = end;
+        self.apply_assignment_rules();
     }
 
     /// Compute reaction rates
@@ -545,15 +848,16 @@ impl CopasiSimulation {
         let mut rates = Array1::zeros(n);
 
         for (j, reaction) in self.model.reactions.iter().enumerate() {
-            rates[j] = self.compute_reaction_rate(reaction);
+            rates[j] = self.compute_reaction_rate(j, reaction);
         }
 
         rates
     }
 
-    /// Compute rate for a single reaction
-    fn compute_reaction_rate(&self, reaction: &Reaction) -> f64 {
-        match &reaction.kinetic_law {
+    /// Compute rate for a single reaction (the `j`th), in amount per time
+    fn compute_reaction_rate(&self, j: usize, reaction: &Reaction) -> f64 {
+        let volume = self.model.reaction_compartment(reaction).map_or(1.0, |c| c.size);
+        volume * match &reaction.kinetic_law {
             KineticLaw::MassAction { rate_constant } => {
                 let k = self.get_value(rate_constant);
                 let mut rate = k;
@@ -577,10 +881,42 @@ impl CopasiSimulation {
                 let k_n = k_val.powf(*n);
                 vmax_val * s_n / (k_n + s_n)
             }
-            _ => 0.0,
+            KineticLaw::ReversibleMM { vmax_f, km_f, vmax_r, km_r } => {
+                let first = |refs: &[SpeciesReference]| {
+                    refs.first().map_or(0.0, |r| self.get_species_concentration(&r.species))
+                };
+                let (s, p) = (first(&reaction.reactants), first(&reaction.products));
+                let (s_km, p_km) = (s / self.get_value(km_f), p / self.get_value(km_r));
+                (self.get_value(vmax_f) * s_km - self.get_value(vmax_r) * p_km) / (1.0 + s_km + p_km)
+            }
+            // Amounts per time already
+            KineticLaw::Custom(_) => {
+                return match &self.laws[j] {
+                    Some(law) => law.eval(&|id| self.symbol_value(id, &reaction.local_parameters)),
+                    None => f64::NAN,
+                };
+            }
         }
     }
 
+    /// Value of a symbol in an expression: a local parameter, parameter,
+    /// species value ([`Self::species_value`]), compartment size or `time`
+    fn symbol_value(&self, id: &str, local_parameters: &[Parameter]) -> Option<f64> {
+        if let Some(p) = local_parameters.iter().find(|p| p.id == id) {
+            return Some(p.value);
+        }
+        if let Some(p) = self.model.get_parameter(id) {
+            return Some(p.value);
+        }
+        if let Some(i) = self.model.species.iter().position(|s| s.id == id) {
+            return Some(self.species_value(i));
+        }
+        if let Some(c) = self.model.get_compartment(id) {
+            return Some(c.size);
+        }
+        (id == "time").then_some(self.t)
+    }
+
     /// Get parameter or species value
     fn get_value(&self, id: &str) -> f64 {
         // Try parameters first
@@ -595,34 +931,11 @@ impl CopasiSimulation {
     fn get_species_concentration(&self, id: &str) -> f64 {
         for (i, s) in self.model.species.iter().enumerate() {
             if s.id == id {
-                return self.state[i];
+                return self.concentration(i);
             }
         }
         0.0
     }
-
-    /// Find steady state
-    pub fn steady_state(&mut self) -> Result<HashMap<String, f64>> {
-        // Simple iteration until convergence
-        let max_iter = 10000;
-        let tol = 1e-10;
-
-        for _ in 0..max_iter {
-            let old_state = self.state.clone();
-            self.step_deterministic(0.1);
-
-            let diff: f64 = (&self.state - &old_state)
-                .iter()
-                .map(|x| x.abs())
-                .sum();
-
-            if diff < tol {
-                return Ok(self.get_concentrations());
-            }
-        }
-
-        Err(OldiesError::NumericalError("Steady state not reached".into()))
-    }
 }
 
 // =============================================================================
@@ -752,4 +1065,105 @@ mod tests {
         let conc = sim.get_concentrations();
         assert_eq!(conc["A"], 2.0);
     }
+
+    #[test]
+    fn test_compartment_volumes() {
+        // A moves from the cytosol, of size 1, to the nucleus, of size 0.25:
+        // [A] = e^-kt and [B] = 4 (1 - e^-kt), the same built in or as the
+        // SBML law in amounts per time
+        let mut model = SbmlModel::new("transport");
+        model.add_compartment(Compartment::new("cyt", 1.0));
+        model.add_compartment(Compartment::new("nuc", 0.25));
+        model.add_species(Species::new("A", "cyt", 1.0));
+        model.add_species(Species::new("B", "nuc", 0.0));
+        model.add_parameter(Parameter::new("k", 0.5));
+        model.add_reaction(Reaction::simple("move", "A", "B", "k"));
+        let mut custom = model.clone();
+        custom.reactions[0].kinetic_law = KineticLaw::Custom("cyt * k * A".into());
+        for model in [model, custom] {
+            let mut sim = CopasiSimulation::new(model);
+            sim.set_ode(ode::OdeOptions { relative_tolerance: 1e-9, ..Default::default() });
+            let result = sim.run(4.0, 4);
+            for (k, t) in result.time.iter().enumerate() {
+                let a = (-0.5 * t).exp();
+                assert!((result.concentrations["A"][k] - a).abs() < 1e-5, "{}", t);
+                assert!((result.concentrations["B"][k] - 4.0 * (1.0 - a)).abs() < 1e-5, "{}", t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_changing_volume() {
+        // The cell grows as e^t, diluting X, which reactions leave alone,
+        // while Y keeps its concentration by a rate rule of 0 and the
+        // amount H, with only substance units, decays at 1 whatever the size
+        let mut model = SbmlModel::new("growth");
+        let mut cell = Compartment::new("cell", 1.0);
+        cell.constant = false;
+        model.add_compartment(cell);
+        model.add_species(Species::new("X", "cell", 2.0));
+        model.add_species(Species::new("Y", "cell", 3.0));
+        let mut h = Species::new("H", "cell", 0.0);
+        (h.initial_concentration, h.initial_amount, h.has_only_substance_units) = (None, Some(5.0), true);
+        model.add_species(h);
+        model.add_parameter(Parameter::new("one", 1.0));
+        let mut decay = Reaction::simple("decay", "H", "H", "one");
+        decay.products.clear();
+        decay.kinetic_law = KineticLaw::Custom("one * H".into());
+        model.add_reaction(decay);
+        model.rate_rules.push(RateRule { variable: "cell".into(), expression: "cell".into() });
+        model.rate_rules.push(RateRule { variable: "Y".into(), expression: "0".into() });
+
+        let mut sim = CopasiSimulation::new(model.clone());
+        sim.set_ode(ode::OdeOptions { relative_tolerance: 1e-9, ..Default::default() });
+        let result = sim.run(2.0, 2);
+        for (k, t) in result.time.iter().enumerate() {
+            let size = t.exp();
+            assert!((result.concentrations["X"][k] - 2.0 / size).abs() < 1e-5, "{}", t);
+            assert!((result.concentrations["Y"][k] - 3.0).abs() < 1e-5, "{}", t);
+            assert!((result.concentrations["H"][k] - 5.0 * (-t).exp() / size).abs() < 1e-5, "{}", t);
+        }
+        assert!((sim.symbol_value("H", &[]).unwrap() - 5.0 * (-2.0f64).exp()).abs() < 1e-5);
+
+        // Initial concentrations hold in sizes set by initial assignments,
+        // and amounts with events that resize the cell
+        model.rate_rules.clear();
+        model.initial_assignments.push(InitialAssignment { symbol: "cell".into(), expression: "4 * one".into() });
+        model.events.push(Event {
+            id: "divide".into(),
+            trigger: "time > 1".into(),
+            delay: None,
+            priority: None,
+            assignments: vec![EventAssignment { variable: "cell".into(), expression: "cell / 2".into() }],
+        });
+        let mut sim = CopasiSimulation::new(model);
+        assert_eq!(sim.state[0], 8.0);
+        assert_eq!(sim.get_concentrations()["X"], 2.0);
+        sim.run(2.0, 2);
+        assert_eq!(sim.model.get_compartment("cell").unwrap().size, 2.0);
+        assert!((sim.get_concentrations()["X"] - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_stochastic_volumes() {
+        // 2 A -> B in a compartment of size 2 at k = 0.5: propensity
+        // 2 k x (x - 1) / 2^2 for x molecules
+        let mut model = SbmlModel::new("dimerization");
+        model.add_compartment(Compartment::new("c", 2.0));
+        let mut a = Species::new("A", "c", 0.0);
+        (a.initial_concentration, a.initial_amount) = (None, Some(10.0));
+        model.add_species(a);
+        model.add_species(Species::new("B", "c", 0.0));
+        model.add_parameter(Parameter::new("k", 0.5));
+        let mut dimerization = Reaction::simple("dimerization", "A", "B", "k");
+        dimerization.reactants[0].stoichiometry = 2.0;
+        model.add_reaction(dimerization);
+
+        let mut sim = CopasiSimulation::new(model);
+        assert_eq!(sim.propensity(0), 22.5);
+        assert_eq!(sim.compute_rates()[0], 25.0);
+        sim.set_method(SimulationMethod::Stochastic);
+        sim.run(100.0, 1);
+        assert_eq!(sim.state[0] + 2.0 * sim.state[1], 10.0);
+    }
 }
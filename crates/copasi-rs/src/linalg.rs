@@ -0,0 +1,388 @@
+//! Dense linear algebra for the integrators and steady-state solvers
+
+use ndarray::{Array1, Array2};
+
+/// LU factorization with partial pivoting, P A = L U
+#[derive(Debug, Clone)]
+pub struct Lu {
+    /// L below the diagonal (unit diagonal implied) and U on and above
+    lu: Array2<f64>,
+    /// Row of A in each row of P A
+    pivots: Vec<usize>,
+}
+
+impl Lu {
+    /// Factor the square matrix `a`, or `None` if it is singular
+    pub fn new(a: &Array2<f64>) -> Option<Lu> {
+        let n = a.nrows();
+        let mut lu = a.clone();
+        let mut pivots: Vec<usize> = (0..n).collect();
+        let scale = a.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+        for k in 0..n {
+            let p = (k..n).max_by(|&i, &j| lu[[i, k]].abs().total_cmp(&lu[[j, k]].abs()))?;
+            if lu[[p, k]].abs() <= scale * 1e-14 || lu[[p, k]] == 0.0 {
+                return None;
+            }
+            if p != k {
+                for j in 0..n {
+                    lu.swap([k, j], [p, j]);
+                }
+                pivots.swap(k, p);
+            }
+            for i in k + 1..n {
+                let factor = lu[[i, k]] / lu[[k, k]];
+                lu[[i, k]] = factor;
+                for j in k + 1..n {
+                    lu[[i, j]] -= factor * lu[[k, j]];
+                }
+            }
+        }
+        Some(Lu { lu, pivots })
+    }
+
+    /// Solution x of A x = b
+    pub fn solve(&self, b: &Array1<f64>) -> Array1<f64> {
+        let n = self.pivots.len();
+        let mut x: Array1<f64> = self.pivots.iter().map(|&p| b[p]).collect();
+        for i in 0..n {
+            for j in 0..i {
+                x[i] -= self.lu[[i, j]] * x[j];
+            }
+        }
+        for i in (0..n).rev() {
+            for j in i + 1..n {
+                x[i] -= self.lu[[i, j]] * x[j];
+            }
+            x[i] /= self.lu[[i, i]];
+        }
+        x
+    }
+}
+
+/// Householder QR factorization with column pivoting, A P = Q R
+///
+/// Each step takes the remaining column of largest norm, the first of
+/// those equal to rounding, so that the diagonal of R does not increase
+/// and the rank shows in where it falls to zero.
+#[derive(Debug, Clone)]
+pub struct Qr {
+    /// R on and above the diagonal
+    pub r: Array2<f64>,
+    /// Column of A in each column of A P
+    pub permutation: Vec<usize>,
+}
+
+impl Qr {
+    pub fn new(a: &Array2<f64>) -> Qr {
+        let (m, n) = a.dim();
+        let mut r = a.clone();
+        let mut permutation: Vec<usize> = (0..n).collect();
+        for k in 0..m.min(n) {
+            let norms: Vec<f64> = (k..n)
+                .map(|j| (k..m).map(|i| r[[i, j]] * r[[i, j]]).sum())
+                .collect();
+            let mut p = 0;
+            for (j, &norm) in norms.iter().enumerate() {
+                if norm > norms[p] * (1.0 + 1e-10) {
+                    p = j;
+                }
+            }
+            if p != 0 {
+                for i in 0..m {
+                    r.swap([i, k], [i, k + p]);
+                }
+                permutation.swap(k, k + p);
+            }
+
+            // Reflect column k onto the diagonal
+            let norm = norms[p].sqrt();
+            if norm == 0.0 {
+                break;
+            }
+            let alpha = if r[[k, k]] > 0.0 { -norm } else { norm };
+            let mut v: Array1<f64> = (k..m).map(|i| r[[i, k]]).collect();
+            v[0] -= alpha;
+            let beta = v.dot(&v);
+            for j in k..n {
+                let s = (k..m).map(|i| v[i - k] * r[[i, j]]).sum::<f64>() * 2.0 / beta;
+                for i in k..m {
+                    r[[i, j]] -= s * v[i - k];
+                }
+            }
+            for i in k + 1..m {
+                r[[i, k]] = 0.0;
+            }
+        }
+        Qr { r, permutation }
+    }
+
+    /// Number of diagonal entries of R above `tolerance` times the first
+    pub fn rank(&self, tolerance: f64) -> usize {
+        let diagonal = self.r.diag();
+        let first = diagonal.first().map_or(0.0, |d| d.abs());
+        diagonal.iter().take_while(|d| d.abs() > tolerance * first).count()
+    }
+}
+
+/// Eigenvalues (real, imaginary) of the square matrix `a`, by reduction
+/// to Hessenberg form and the shifted QR algorithm, or `None` if the QR
+/// iteration does not converge
+pub fn eigenvalues(a: &Array2<f64>) -> Option<Vec<(f64, f64)>> {
+    let n = a.nrows();
+    // 1-based as in the EISPACK routines, row and column 0 unused
+    let mut h = Array2::<f64>::zeros((n + 1, n + 1));
+    h.slice_mut(ndarray::s![1.., 1..]).assign(a);
+    hessenberg(&mut h, n);
+    hessenberg_qr(&mut h, n)
+}
+
+/// Reduce `h[1..=n][1..=n]` to upper Hessenberg form by eliminations
+/// with pivoting, which keep the eigenvalues
+fn hessenberg(h: &mut Array2<f64>, n: usize) {
+    for m in 2..n {
+        let mut x: f64 = 0.0;
+        let mut i = m;
+        for j in m..=n {
+            if h[[j, m - 1]].abs() > x.abs() {
+                x = h[[j, m - 1]];
+                i = j;
+            }
+        }
+        if i != m {
+            for j in m - 1..=n {
+                h.swap([i, j], [m, j]);
+            }
+            for j in 1..=n {
+                h.swap([j, i], [j, m]);
+            }
+        }
+        if x != 0.0 {
+            for i in m + 1..=n {
+                let y = h[[i, m - 1]] / x;
+                if y != 0.0 {
+                    h[[i, m - 1]] = 0.0;
+                    for j in m..=n {
+                        h[[i, j]] -= y * h[[m, j]];
+                    }
+                    for j in 1..=n {
+                        h[[j, m]] += y * h[[j, i]];
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Eigenvalues of the upper Hessenberg `h[1..=n][1..=n]` by the Francis
+/// double shift QR algorithm
+fn hessenberg_qr(h: &mut Array2<f64>, n: usize) -> Option<Vec<(f64, f64)>> {
+    let mut wr = vec![0.0; n + 1];
+    let mut wi = vec![0.0; n + 1];
+    let mut norm = 0.0;
+    for i in 1..=n {
+        for j in i.max(2) - 1..=n {
+            norm += h[[i, j]].abs();
+        }
+    }
+    let mut nn = n;
+    let mut t = 0.0;
+    while nn >= 1 {
+        let mut its = 0;
+        loop {
+            // Look for a small subdiagonal element splitting the matrix
+            let mut l = nn;
+            while l >= 2 {
+                let mut s = h[[l - 1, l - 1]].abs() + h[[l, l]].abs();
+                if s == 0.0 {
+                    s = norm;
+                }
+                if h[[l, l - 1]].abs() + s == s {
+                    h[[l, l - 1]] = 0.0;
+                    break;
+                }
+                l -= 1;
+            }
+            let mut x = h[[nn, nn]];
+            if l == nn {
+                // One root found
+                wr[nn] = x + t;
+                wi[nn] = 0.0;
+                nn -= 1;
+            } else {
+                let mut y = h[[nn - 1, nn - 1]];
+                let mut w = h[[nn, nn - 1]] * h[[nn - 1, nn]];
+                if l == nn - 1 {
+                    // Two roots found
+                    let p = 0.5 * (y - x);
+                    let q = p * p + w;
+                    let z = q.abs().sqrt();
+                    x += t;
+                    if q >= 0.0 {
+                        let z = if p >= 0.0 { p + z } else { p - z };
+                        wr[nn - 1] = x + z;
+                        wr[nn] = if z != 0.0 { x - w / z } else { x + z };
+                        wi[nn - 1] = 0.0;
+                        wi[nn] = 0.0;
+                    } else {
+                        wr[nn - 1] = x + p;
+                        wr[nn] = x + p;
+                        wi[nn - 1] = -z;
+                        wi[nn] = z;
+                    }
+                    nn -= 2;
+                } else {
+                    if its == 30 {
+                        return None;
+                    }
+                    if its == 10 || its == 20 {
+                        // Exceptional shift
+                        t += x;
+                        for i in 1..=nn {
+                            h[[i, i]] -= x;
+                        }
+                        let s = h[[nn, nn - 1]].abs() + h[[nn - 1, nn - 2]].abs();
+                        x = 0.75 * s;
+                        y = x;
+                        w = -0.4375 * s * s;
+                    }
+                    its += 1;
+                    // Look for two consecutive small subdiagonal elements
+                    let (mut p, mut q, mut r);
+                    let mut m = nn - 2;
+                    loop {
+                        let z = h[[m, m]];
+                        let rr = x - z;
+                        let ss = y - z;
+                        p = (rr * ss - w) / h[[m + 1, m]] + h[[m, m + 1]];
+                        q = h[[m + 1, m + 1]] - z - rr - ss;
+                        r = h[[m + 2, m + 1]];
+                        let s = p.abs() + q.abs() + r.abs();
+                        p /= s;
+                        q /= s;
+                        r /= s;
+                        if m == l {
+                            break;
+                        }
+                        let u = h[[m, m - 1]].abs() * (q.abs() + r.abs());
+                        let v = p.abs() * (h[[m - 1, m - 1]].abs() + z.abs() + h[[m + 1, m + 1]].abs());
+                        if u + v == v {
+                            break;
+                        }
+                        m -= 1;
+                    }
+                    for i in m + 2..=nn {
+                        h[[i, i - 2]] = 0.0;
+                        if i != m + 2 {
+                            h[[i, i - 3]] = 0.0;
+                        }
+                    }
+                    // Double QR step on rows l..=nn and columns m..=nn
+                    for k in m..nn {
+                        if k != m {
+                            p = h[[k, k - 1]];
+                            q = h[[k + 1, k - 1]];
+                            r = if k != nn - 1 { h[[k + 2, k - 1]] } else { 0.0 };
+                            x = p.abs() + q.abs() + r.abs();
+                            if x != 0.0 {
+                                p /= x;
+                                q /= x;
+                                r /= x;
+                            }
+                        }
+                        let s = (p * p + q * q + r * r).sqrt();
+                        let s = if p >= 0.0 { s } else { -s };
+                        if s == 0.0 {
+                            continue;
+                        }
+                        if k == m {
+                            if l != m {
+                                h[[k, k - 1]] = -h[[k, k - 1]];
+                            }
+                        } else {
+                            h[[k, k - 1]] = -s * x;
+                        }
+                        p += s;
+                        x = p / s;
+                        y = q / s;
+                        let z = r / s;
+                        q /= p;
+                        r /= p;
+                        for j in k..=nn {
+                            let mut p = h[[k, j]] + q * h[[k + 1, j]];
+                            if k != nn - 1 {
+                                p += r * h[[k + 2, j]];
+                                h[[k + 2, j]] -= p * z;
+                            }
+                            h[[k + 1, j]] -= p * y;
+                            h[[k, j]] -= p * x;
+                        }
+                        for i in l..=nn.min(k + 3) {
+                            let mut p = x * h[[i, k]] + y * h[[i, k + 1]];
+                            if k != nn - 1 {
+                                p += z * h[[i, k + 2]];
+                                h[[i, k + 2]] -= p * r;
+                            }
+                            h[[i, k + 1]] -= p * q;
+                            h[[i, k]] -= p;
+                        }
+                    }
+                }
+            }
+            if nn < 2 || l + 1 >= nn {
+                break;
+            }
+        }
+    }
+    Some(wr.into_iter().zip(wi).skip(1).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_lu_solve() {
+        let a = array![[0.0, 2.0, 1.0], [1.0, 1.0, 0.0], [3.0, 0.0, -1.0]];
+        let x = array![1.0, -2.0, 3.0];
+        let lu = Lu::new(&a).unwrap();
+        let solved = lu.solve(&a.dot(&x));
+        assert!((&solved - &x).iter().all(|e| e.abs() < 1e-12), "{}", solved);
+        assert!(Lu::new(&array![[1.0, 2.0], [2.0, 4.0]]).is_none());
+    }
+
+    #[test]
+    fn test_pivoted_qr() {
+        // Rank 2: the third column is the sum of the others
+        let a = array![[1.0, 0.0, 1.0], [0.0, 2.0, 2.0], [1.0, 0.0, 1.0], [0.0, 0.0, 0.0]];
+        let qr = Qr::new(&a);
+        assert_eq!(qr.permutation[0], 2);
+        assert_eq!(qr.rank(1e-9), 2);
+        let diagonal: Vec<f64> = qr.r.diag().iter().map(|d| d.abs()).collect();
+        assert!((diagonal[0] - 6.0f64.sqrt()).abs() < 1e-12 && diagonal[1] > diagonal[2]);
+        // Q^T A P = R leaves the column norms
+        for (k, &j) in qr.permutation.iter().enumerate() {
+            let norm = a.column(j).dot(&a.column(j)).sqrt();
+            assert!((qr.r.column(k).dot(&qr.r.column(k)).sqrt() - norm).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_eigenvalues() {
+        // Companion matrix of (x - 1)(x - 2)(x^2 + 1)
+        let a = array![
+            [3.0, -3.0, 3.0, -2.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+        ];
+        let mut values = eigenvalues(&a).unwrap();
+        values.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        let expected = [(0.0, -1.0), (0.0, 1.0), (1.0, 0.0), (2.0, 0.0)];
+        for (value, expected) in values.iter().zip(expected) {
+            assert!((value.0 - expected.0).abs() < 1e-9 && (value.1 - expected.1).abs() < 1e-9, "{:?}", values);
+        }
+        assert_eq!(eigenvalues(&array![[-3.0]]).unwrap(), vec![(-3.0, 0.0)]);
+        assert!(eigenvalues(&Array2::zeros((0, 0))).unwrap().is_empty());
+    }
+}
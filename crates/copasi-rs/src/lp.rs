@@ -0,0 +1,207 @@
+//! Linear programming
+//!
+//! [`maximize`] solves max c^T x subject to A x = b and l <= x <= u, any
+//! bound possibly infinite, by the bounded-variable primal simplex method
+//! on a dense tableau:
+//!
+//! - Nonbasic variables sit at a finite bound, or at zero when free, and
+//!   an entering variable may move from one of its bounds to the other
+//!   without a pivot.
+//! - Phase one starts from a basis of artificial variables, one per row,
+//!   and drives their sum to zero to find a feasible basis; those left
+//!   basic are then held at zero.
+//! - Bland's rule picks the entering and leaving variables, so that
+//!   degenerate problems, common in flux balance analysis, cannot cycle.
+
+use crate::*;
+
+/// Reduced costs, pivots and infeasibilities taken as zero
+const TOLERANCE: f64 = 1e-9;
+/// Simplex iterations before giving up
+const MAX_ITERATIONS: usize = 100_000;
+
+/// Optimum of a linear program
+#[derive(Debug, Clone)]
+pub struct LpSolution {
+    pub x: Vec<f64>,
+    pub objective: f64,
+}
+
+/// Simplex tableau over the structural and artificial variables
+struct Tableau {
+    /// B^-1 times the constraint matrix
+    t: Array2<f64>,
+    /// Variable basic in each row
+    basis: Vec<usize>,
+    /// Values of all the variables
+    x: Vec<f64>,
+    lower: Vec<f64>,
+    upper: Vec<f64>,
+}
+
+impl Tableau {
+    /// Maximize c^T x from the current basis, false if unbounded
+    fn optimize(&mut self, c: &[f64]) -> Result<bool> {
+        let (m, n) = self.t.dim();
+        for _ in 0..MAX_ITERATIONS {
+            let mut basic = vec![false; n];
+            for &b in &self.basis {
+                basic[b] = true;
+            }
+            // First variable whose reduced cost improves the objective in a
+            // direction it can move
+            let entering = (0..n).filter(|&j| !basic[j]).find_map(|j| {
+                let d = c[j] - (0..m).map(|i| c[self.basis[i]] * self.t[[i, j]]).sum::<f64>();
+                if d > TOLERANCE && self.x[j] < self.upper[j] {
+                    Some((j, 1.0))
+                } else if d < -TOLERANCE && self.x[j] > self.lower[j] {
+                    Some((j, -1.0))
+                } else {
+                    None
+                }
+            });
+            let Some((j, direction)) = entering else {
+                return Ok(true);
+            };
+
+            // Longest step before a variable reaches a bound, the entering
+            // one's own included; ties leave by the lowest variable
+            let mut step = self.upper[j] - self.lower[j];
+            let mut leaving: Option<usize> = None;
+            for i in 0..m {
+                let alpha = direction * self.t[[i, j]];
+                let b = self.basis[i];
+                let limit = if alpha > TOLERANCE {
+                    (self.x[b] - self.lower[b]) / alpha
+                } else if alpha < -TOLERANCE {
+                    (self.upper[b] - self.x[b]) / -alpha
+                } else {
+                    continue;
+                }
+                .max(0.0);
+                if limit < step || (limit == step && leaving.is_some_and(|r| b < self.basis[r])) {
+                    step = limit;
+                    leaving = Some(i);
+                }
+            }
+            if step.is_infinite() {
+                return Ok(false);
+            }
+
+            self.x[j] += direction * step;
+            for i in 0..m {
+                self.x[self.basis[i]] -= direction * step * self.t[[i, j]];
+            }
+            if let Some(r) = leaving {
+                let b = self.basis[r];
+                self.x[b] = if direction * self.t[[r, j]] > 0.0 { self.lower[b] } else { self.upper[b] };
+                self.pivot(r, j);
+            }
+        }
+        Err(OldiesError::NumericalError("Simplex method did not converge".into()))
+    }
+
+    /// Make variable `j` basic in row `r`
+    fn pivot(&mut self, r: usize, j: usize) {
+        let p = self.t[[r, j]];
+        self.t.row_mut(r).mapv_inplace(|v| v / p);
+        let row = self.t.row(r).to_owned();
+        for i in 0..self.t.nrows() {
+            let factor = self.t[[i, j]];
+            if i != r && factor != 0.0 {
+                self.t.row_mut(i).scaled_add(-factor, &row);
+            }
+        }
+        self.basis[r] = j;
+    }
+}
+
+/// Maximize `c`^T x subject to `a` x = `b` and `lower` <= x <= `upper`
+pub fn maximize(c: &[f64], a: &Array2<f64>, b: &[f64], lower: &[f64], upper: &[f64]) -> Result<LpSolution> {
+    let (m, n) = a.dim();
+    if let Some(j) = (0..n).find(|&j| lower[j] > upper[j]) {
+        return Err(OldiesError::NumericalError(format!("Bounds of variable {} are crossed", j)));
+    }
+
+    // Nonbasic variables start at a finite bound, the artificial ones
+    // taking up the residuals
+    let mut x: Vec<f64> = (0..n)
+        .map(|j| match (lower[j].is_finite(), upper[j].is_finite()) {
+            (true, _) => lower[j],
+            (false, true) => upper[j],
+            (false, false) => 0.0,
+        })
+        .collect();
+    let residual: Vec<f64> = (0..m)
+        .map(|i| b[i] - (0..n).map(|j| a[[i, j]] * x[j]).sum::<f64>())
+        .collect();
+    let mut t = Array2::zeros((m, n + m));
+    for i in 0..m {
+        let sign = if residual[i] < 0.0 { -1.0 } else { 1.0 };
+        for j in 0..n {
+            t[[i, j]] = sign * a[[i, j]];
+        }
+        t[[i, n + i]] = 1.0;
+        x.push(residual[i].abs());
+    }
+    let mut tableau = Tableau {
+        t,
+        basis: (n..n + m).collect(),
+        x,
+        lower: lower.iter().copied().chain(std::iter::repeat_n(0.0, m)).collect(),
+        upper: upper.iter().copied().chain(std::iter::repeat_n(f64::INFINITY, m)).collect(),
+    };
+
+    let phase_one: Vec<f64> = std::iter::repeat_n(0.0, n).chain(std::iter::repeat_n(-1.0, m)).collect();
+    tableau.optimize(&phase_one)?;
+    let scale = b.iter().fold(1.0f64, |s, v| s.max(v.abs()));
+    if tableau.x[n..].iter().sum::<f64>() > TOLERANCE * scale * m as f64 {
+        return Err(OldiesError::NumericalError("Linear program is infeasible".into()));
+    }
+    for k in n..n + m {
+        tableau.upper[k] = 0.0;
+        tableau.x[k] = 0.0;
+    }
+
+    let phase_two: Vec<f64> = c.iter().copied().chain(std::iter::repeat_n(0.0, m)).collect();
+    if !tableau.optimize(&phase_two)? {
+        return Err(OldiesError::NumericalError("Linear program is unbounded".into()));
+    }
+    let x = tableau.x[..n].to_vec();
+    Ok(LpSolution { objective: c.iter().zip(&x).map(|(c, x)| c * x).sum(), x })
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_maximize() {
+        // max 3x + 2y with x + y + s = 4, x + 3y + s' = 6, x <= 3:
+        // x = 3, y = 1, objective 11
+        let a = array![[1.0, 1.0, 1.0, 0.0], [1.0, 3.0, 0.0, 1.0]];
+        let inf = f64::INFINITY;
+        let solution = maximize(
+            &[3.0, 2.0, 0.0, 0.0],
+            &a,
+            &[4.0, 6.0],
+            &[0.0, 0.0, 0.0, 0.0],
+            &[3.0, inf, inf, inf],
+        ).unwrap();
+        assert!((solution.objective - 11.0).abs() < 1e-9);
+        assert!((solution.x[0] - 3.0).abs() < 1e-9 && (solution.x[1] - 1.0).abs() < 1e-9);
+
+        // A free variable below zero: max -x with x - y = -2, y in [0, 1]
+        let solution = maximize(&[-1.0, 0.0], &array![[1.0, -1.0]], &[-2.0], &[-inf, 0.0], &[inf, 1.0]).unwrap();
+        assert!((solution.x[0] + 2.0).abs() < 1e-9, "{:?}", solution);
+
+        // Infeasible, and unbounded
+        assert!(maximize(&[1.0], &array![[1.0]], &[2.0], &[0.0], &[1.0]).is_err());
+        assert!(maximize(&[1.0, 0.0], &array![[1.0, -1.0]], &[0.0], &[0.0, 0.0], &[inf, inf]).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Lyapunov exponents
+//!
+//! [`CopasiSimulation::lyapunov_exponents`] measures the average rates at
+//! which nearby trajectories approach or leave each other, as COPASI's
+//! Lyapunov exponents task does, by the method of Benettin et al.:
+//!
+//! - Tangent vectors follow the linearized system dq/dt = J q along the
+//!   trajectory, integrated with it by the Rosenbrock method of
+//!   [`crate::ode`] on the independent species, so that conserved
+//!   moieties add no zero exponents.
+//! - At every orthonormalization interval the tangent vectors are
+//!   orthonormalized by Gram-Schmidt, and the logarithms of their
+//!   stretching summed. The exponents are the sums over the time taken.
+//! - The trace of J, the divergence of the flow, is averaged along the
+//!   trajectory too; all the exponents together sum to it.
+//!
+//! A positive largest exponent marks chaos, a zero one with the others
+//! negative a limit cycle, as of glycolytic oscillations, and all
+//! negative a stable steady state. Events are not executed.
+
+use crate::*;
+use crate::ode::{rosenbrock, OdeSystem, Reduced};
+use ndarray::s;
+
+/// Settings of the Lyapunov exponents task
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LyapunovOptions {
+    /// Time integrated first, to settle on the attractor
+    pub transient: f64,
+    /// Time the exponents are averaged over
+    pub duration: f64,
+    /// Time between orthonormalizations of the tangent vectors
+    pub orthonormalization_interval: f64,
+    /// Number of exponents, the largest, if not all
+    pub exponents: Option<usize>,
+}
+
+impl Default for LyapunovOptions {
+    fn default() -> Self {
+        Self {
+            transient: 0.0,
+            duration: 100.0,
+            orthonormalization_interval: 1.0,
+            exponents: None,
+        }
+    }
+}
+
+/// Result of the Lyapunov exponents task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LyapunovExponents {
+    /// Exponents, the largest first
+    pub exponents: Vec<f64>,
+    /// Trace of the Jacobian averaged along the trajectory
+    pub divergence: f64,
+}
+
+/// The reduced reaction system, followed by tangent vectors and the
+/// integral of the divergence
+struct Tangent<'a> {
+    system: Reduced<'a>,
+    /// Size of the reduced system
+    n: usize,
+}
+
+impl OdeSystem for Tangent<'_> {
+    fn rates(&mut self, t: Time, y: &Array1<f64>) -> Array1<f64> {
+        let n = self.n;
+        let x = y.slice(s![..n]).to_owned();
+        let f = self.system.rates(t, &x);
+        let (jacobian, _) = self.system.jacobian(t, &x, &f);
+        let mut dydt = Array1::zeros(y.len());
+        dydt.slice_mut(s![..n]).assign(&f);
+        for k in 1..(y.len() - 1) / n.max(1) {
+            let q = y.slice(s![k * n..(k + 1) * n]);
+            dydt.slice_mut(s![k * n..(k + 1) * n]).assign(&jacobian.dot(&q));
+        }
+        dydt[y.len() - 1] = jacobian.diag().sum();
+        dydt
+    }
+
+    /// Block diagonal, without the second derivatives of the rates
+    fn jacobian(&mut self, t: Time, y: &Array1<f64>, f0: &Array1<f64>) -> (Array2<f64>, Array1<f64>) {
+        let n = self.n;
+        let x = y.slice(s![..n]).to_owned();
+        let (jacobian, dfdt) = self.system.jacobian(t, &x, &f0.slice(s![..n]).to_owned());
+        let mut full = Array2::zeros((y.len(), y.len()));
+        for k in 0..(y.len() - 1) / n.max(1) {
+            full.slice_mut(s![k * n..(k + 1) * n, k * n..(k + 1) * n]).assign(&jacobian);
+        }
+        let mut time = Array1::zeros(y.len());
+        time.slice_mut(s![..n]).assign(&dfdt);
+        (full, time)
+    }
+}
+
+impl CopasiSimulation {
+    /// Lyapunov exponents along the trajectory from the current state,
+    /// leaving the simulation at its end
+    pub fn lyapunov_exponents(&mut self, options: LyapunovOptions) -> Result<LyapunovExponents> {
+        if !(options.duration > 0.0 && options.orthonormalization_interval > 0.0) {
+            return Err(OldiesError::SimulationError(
+                "Lyapunov exponents need a positive duration and orthonormalization interval".into(),
+            ));
+        }
+        let stoich = self.model.stoichiometry_matrix();
+        if options.transient > 0.0 {
+            self.integrate(&stoich, self.t + options.transient);
+        }
+
+        let reduction = self.reduction(&stoich);
+        let (ode, start) = (self.ode, self.t);
+        let (system, x) = Reduced::new(self, &stoich, &reduction);
+        let n = x.len();
+        let count = options.exponents.map_or(n, |k| k.min(n));
+        let mut y = Array1::zeros(n * (count + 1) + 1);
+        y.slice_mut(s![..n]).assign(&x);
+        for k in 0..count {
+            y[n * (k + 1) + k] = 1.0;
+        }
+
+        let mut tangent = Tangent { system, n };
+        let (mut t, mut step) = (start, None);
+        let end = start + options.duration;
+        let mut sums = vec![0.0; count];
+        while t < end {
+            let stop = (t + options.orthonormalization_interval).min(end);
+            rosenbrock(&mut tangent, ode, &mut t, &mut y, stop, &mut step);
+            if t < stop {
+                return Err(OldiesError::NumericalError(format!("Tangent vectors not integrated past t = {}", t)));
+            }
+            let block = |k: usize| s![n * (k + 1)..n * (k + 2)];
+            for (k, sum) in sums.iter_mut().enumerate() {
+                for l in 0..k {
+                    let dot = y.slice(block(k)).dot(&y.slice(block(l)));
+                    let q = y.slice(block(l)).to_owned();
+                    y.slice_mut(block(k)).scaled_add(-dot, &q);
+                }
+                let norm = y.slice(block(k)).dot(&y.slice(block(k))).sqrt();
+                if !(norm > 0.0 && norm.is_finite()) {
+                    return Err(OldiesError::NumericalError("Tangent vectors degenerated".into()));
+                }
+                *sum += norm.ln();
+                y.slice_mut(block(k)).mapv_inplace(|v| v / norm);
+            }
+        }
+        let state = tangent.system.expand(&y.slice(s![..n]).to_owned());
+
+        self.t = t;
+        self.set_ode_state(&state);
+        self.apply_assignment_rules();
+        let mut exponents: Vec<f64> = sums.iter().map(|s| s / options.duration).collect();
+        exponents.sort_by(|a, b| b.total_cmp(a));
+        Ok(LyapunovExponents { exponents, divergence: y[y.len() - 1] / options.duration })
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear_decay() {
+        // A and B decaying at rates 1 and 2, and C <-> D at rate 1 each
+        // way, which conserves C + D: exponents -1, -2 and -2
+        let mut model = SbmlModel::new("decays");
+        model.add_compartment(Compartment::new("c", 1.0));
+        for s in ["A", "B", "C", "D"] {
+            model.add_species(Species::new(s, "c", 1.0));
+        }
+        model.add_parameter(Parameter::new("k1", 1.0));
+        model.add_parameter(Parameter::new("k2", 2.0));
+        for (id, s, k) in [("a", "A", "k1"), ("b", "B", "k2")] {
+            let mut decay = Reaction::simple(id, s, s, k);
+            decay.products.clear();
+            model.add_reaction(decay);
+        }
+        model.add_reaction(Reaction::simple("c", "C", "D", "k1"));
+        model.add_reaction(Reaction::simple("d", "D", "C", "k1"));
+
+        let mut sim = CopasiSimulation::new(model);
+        let options = LyapunovOptions { duration: 10.0, ..Default::default() };
+        let result = sim.lyapunov_exponents(options).unwrap();
+        let expected = [-1.0, -2.0, -2.0];
+        assert_eq!(result.exponents.len(), 3);
+        for (exponent, expected) in result.exponents.iter().zip(expected) {
+            assert!((exponent - expected).abs() < 1e-4, "{:?}", result);
+        }
+        assert!((result.divergence + 5.0).abs() < 1e-4);
+        assert_eq!(sim.t, 10.0);
+
+        let options = LyapunovOptions { exponents: Some(1), ..options };
+        let result = sim.lyapunov_exponents(options).unwrap();
+        assert!((result.exponents[0] + 1.0).abs() < 1e-4, "{:?}", result);
+    }
+
+    #[test]
+    fn test_limit_cycle() {
+        // The Brusselator at a = 1, b = 3 settles on a limit cycle: one
+        // exponent zero, along the cycle, and the other negative
+        let mut model = SbmlModel::new("brusselator");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("X", "c", 1.0));
+        model.add_species(Species::new("Y", "c", 1.0));
+        model.add_parameter(Parameter::new("a", 1.0));
+        model.add_parameter(Parameter::new("b", 3.0));
+        model.add_parameter(Parameter::new("one", 1.0));
+        let mut inflow = Reaction::simple("inflow", "X", "X", "a");
+        inflow.reactants.clear();
+        model.add_reaction(inflow);
+        let mut autocatalysis = Reaction::simple("autocatalysis", "X", "X", "one");
+        autocatalysis.reactants[0].stoichiometry = 2.0;
+        autocatalysis.reactants.push(SpeciesReference::new("Y", 1.0));
+        autocatalysis.products[0].stoichiometry = 3.0;
+        model.add_reaction(autocatalysis);
+        model.add_reaction(Reaction::simple("conversion", "X", "Y", "b"));
+        let mut outflow = Reaction::simple("outflow", "X", "X", "one");
+        outflow.products.clear();
+        model.add_reaction(outflow);
+
+        let mut sim = CopasiSimulation::new(model);
+        let options = LyapunovOptions { transient: 30.0, duration: 100.0, ..Default::default() };
+        let result = sim.lyapunov_exponents(options).unwrap();
+        assert!(result.exponents[0].abs() < 0.02, "{:?}", result);
+        assert!(result.exponents[1] < -0.5, "{:?}", result);
+        let sum: f64 = result.exponents.iter().sum();
+        assert!((sum - result.divergence).abs() < 1e-3, "{:?}", result);
+    }
+}
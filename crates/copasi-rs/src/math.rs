@@ -0,0 +1,881 @@
+//! Mathematical expressions of SBML models
+//!
+//! Kinetic laws, rules, events and function definitions hold their math as
+//! infix text in the syntax of the libSBML Level 3 formula parser:
+//!
+//! - `+ - * / ^` with the usual precedence, `^` binding tightest and to
+//!   the right (`-2^2` is `-(2^2)`)
+//! - comparisons `< <= > >= == !=` and `&& || !`, true being 1 and false 0
+//! - functions `abs exp ln log10 log(base, x) sqrt root(n, x) floor ceil
+//!   factorial min max rem quotient`, the trigonometric and hyperbolic
+//!   functions and their inverses, `xor`, `piecewise(value, condition,
+//!   ..., otherwise)` and `delay(x, d)`, taken as `x`
+//! - the symbols `time`, `avogadro`, `pi`, `exponentiale`, `true`,
+//!   `false`, `INF` and `NaN`
+//!
+//! [`Expr`] parses and prints that syntax, converts MathML to it and
+//! evaluates it.
+
+use oldies_core::{OldiesError, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Binary operators, loosest binding first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "||",
+            BinaryOp::And => "&&",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Pow => "^",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => OR,
+            BinaryOp::And => AND,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Eq | BinaryOp::Ne => RELATION,
+            BinaryOp::Add | BinaryOp::Sub => SUM,
+            BinaryOp::Mul | BinaryOp::Div => PRODUCT,
+            BinaryOp::Pow => POWER,
+        }
+    }
+
+    fn apply(self, a: f64, b: f64) -> f64 {
+        let truth = |x: bool| if x { 1.0 } else { 0.0 };
+        match self {
+            BinaryOp::Or => truth(a != 0.0 || b != 0.0),
+            BinaryOp::And => truth(a != 0.0 && b != 0.0),
+            BinaryOp::Lt => truth(a < b),
+            BinaryOp::Le => truth(a <= b),
+            BinaryOp::Gt => truth(a > b),
+            BinaryOp::Ge => truth(a >= b),
+            BinaryOp::Eq => truth(a == b),
+            BinaryOp::Ne => truth(a != b),
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+            BinaryOp::Pow => a.powf(b),
+        }
+    }
+}
+
+// Precedences of the printed forms
+const OR: u8 = 1;
+const AND: u8 = 2;
+const RELATION: u8 = 3;
+const SUM: u8 = 4;
+const PRODUCT: u8 = 5;
+const UNARY: u8 = 6;
+const POWER: u8 = 7;
+const ATOM: u8 = 8;
+
+/// Expression tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Symbol(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// Function definitions by name: argument names and body
+pub type Functions = HashMap<String, (Vec<String>, Expr)>;
+
+impl Expr {
+    /// Parse infix text
+    pub fn parse(text: &str) -> Result<Expr> {
+        let mut parser = Parser { text, tokens: tokenize(text)?, pos: 0 };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(parse_error(text, &format!("unexpected {:?}", token))),
+        }
+    }
+
+    fn binary(op: BinaryOp, a: Expr, b: Expr) -> Expr {
+        Expr::Binary(op, Box::new(a), Box::new(b))
+    }
+
+    /// Value of the expression, with `value` giving the values of symbols.
+    /// Unknown symbols and functions give NaN.
+    pub fn eval(&self, value: &dyn Fn(&str) -> Option<f64>) -> f64 {
+        match self {
+            Expr::Number(x) => *x,
+            Expr::Symbol(name) => value(name).or_else(|| constant(name)).unwrap_or(f64::NAN),
+            Expr::Neg(a) => -a.eval(value),
+            Expr::Not(a) => if a.eval(value) == 0.0 { 1.0 } else { 0.0 },
+            Expr::Binary(op, a, b) => {
+                let a = a.eval(value);
+                // Conditions short-circuit, as piecewise pieces do
+                match op {
+                    BinaryOp::And if a == 0.0 => 0.0,
+                    BinaryOp::Or if a != 0.0 => 1.0,
+                    _ => op.apply(a, b.eval(value)),
+                }
+            }
+            Expr::Call(name, args) if name == "piecewise" => {
+                for piece in args.chunks(2) {
+                    match piece {
+                        [result, condition] if condition.eval(value) != 0.0 => return result.eval(value),
+                        [otherwise] => return otherwise.eval(value),
+                        _ => {}
+                    }
+                }
+                f64::NAN
+            }
+            Expr::Call(name, args) => {
+                let args: Vec<f64> = args.iter().map(|a| a.eval(value)).collect();
+                call(name, &args).unwrap_or(f64::NAN)
+            }
+        }
+    }
+
+    /// The expression with calls of `functions` replaced by their bodies
+    pub fn inline(&self, functions: &Functions) -> Expr {
+        match self {
+            Expr::Number(_) | Expr::Symbol(_) => self.clone(),
+            Expr::Neg(a) => Expr::Neg(Box::new(a.inline(functions))),
+            Expr::Not(a) => Expr::Not(Box::new(a.inline(functions))),
+            Expr::Binary(op, a, b) => Expr::binary(*op, a.inline(functions), b.inline(functions)),
+            Expr::Call(name, args) => {
+                let args: Vec<Expr> = args.iter().map(|a| a.inline(functions)).collect();
+                match functions.get(name) {
+                    Some((names, body)) => {
+                        let bound: HashMap<&str, &Expr> = names.iter().map(String::as_str).zip(&args).collect();
+                        body.substitute(&bound)
+                    }
+                    None => Expr::Call(name.clone(), args),
+                }
+            }
+        }
+    }
+
+    fn substitute(&self, bound: &HashMap<&str, &Expr>) -> Expr {
+        match self {
+            Expr::Number(_) => self.clone(),
+            Expr::Symbol(name) => bound.get(name.as_str()).map_or_else(|| self.clone(), |e| (*e).clone()),
+            Expr::Neg(a) => Expr::Neg(Box::new(a.substitute(bound))),
+            Expr::Not(a) => Expr::Not(Box::new(a.substitute(bound))),
+            Expr::Binary(op, a, b) => Expr::binary(*op, a.substitute(bound), b.substitute(bound)),
+            Expr::Call(name, args) => Expr::Call(name.clone(), args.iter().map(|a| a.substitute(bound)).collect()),
+        }
+    }
+
+    /// Symbols the expression reads, each once, in order of appearance
+    pub fn symbols(&self) -> Vec<&str> {
+        fn collect<'a>(expr: &'a Expr, found: &mut Vec<&'a str>) {
+            match expr {
+                Expr::Number(_) => {}
+                Expr::Symbol(name) => {
+                    if !found.contains(&name.as_str()) {
+                        found.push(name);
+                    }
+                }
+                Expr::Neg(a) | Expr::Not(a) => collect(a, found),
+                Expr::Binary(_, a, b) => {
+                    collect(a, found);
+                    collect(b, found);
+                }
+                Expr::Call(_, args) => args.iter().for_each(|a| collect(a, found)),
+            }
+        }
+        let mut found = vec![];
+        collect(self, &mut found);
+        found
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Number(x) if *x < 0.0 => UNARY,
+            Expr::Number(_) | Expr::Symbol(_) | Expr::Call(..) => ATOM,
+            Expr::Neg(_) | Expr::Not(_) => UNARY,
+            Expr::Binary(op, ..) => op.precedence(),
+        }
+    }
+
+    /// Convert the MathML element `node`: `<math>`, or an expression in it
+    pub(crate) fn from_mathml(node: roxmltree::Node) -> Result<Expr> {
+        let invalid = |what: &str| OldiesError::ParseError(format!("MathML: {}", what));
+        let name = node.tag_name().name();
+        Ok(match name {
+            "math" | "semantics" => {
+                let first = elements(node).next().ok_or_else(|| invalid(&format!("empty <{}>", name)))?;
+                Expr::from_mathml(first)?
+            }
+            "cn" => Expr::Number(cn_value(node).ok_or_else(|| invalid("bad <cn>"))?),
+            "ci" => Expr::Symbol(node.text().unwrap_or("").trim().to_string()),
+            "csymbol" => {
+                let url = node.attribute("definitionURL").unwrap_or("");
+                match url.rsplit('/').next() {
+                    Some("time") => Expr::Symbol("time".into()),
+                    Some("avogadro") => Expr::Symbol("avogadro".into()),
+                    _ => return Err(invalid(&format!("unknown csymbol {}", url))),
+                }
+            }
+            "true" | "false" | "pi" | "exponentiale" => Expr::Symbol(name.into()),
+            "infinity" => Expr::Symbol("INF".into()),
+            "notanumber" => Expr::Symbol("NaN".into()),
+            "piecewise" => {
+                let mut args = vec![];
+                let mut otherwise = None;
+                for child in elements(node) {
+                    let parts: Vec<_> = elements(child).map(Expr::from_mathml).collect::<Result<_>>()?;
+                    match (child.tag_name().name(), parts.len()) {
+                        ("piece", 2) => args.extend(parts),
+                        ("otherwise", 1) => otherwise = parts.into_iter().next(),
+                        (other, _) => return Err(invalid(&format!("bad <{}> in <piecewise>", other))),
+                    }
+                }
+                args.extend(otherwise);
+                Expr::Call("piecewise".into(), args)
+            }
+            "apply" => apply_mathml(node)?,
+            other => return Err(invalid(&format!("unsupported <{}>", other))),
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = |f: &mut fmt::Formatter, e: &Expr, min: u8| {
+            if e.precedence() < min {
+                write!(f, "({})", e)
+            } else {
+                write!(f, "{}", e)
+            }
+        };
+        match self {
+            Expr::Number(x) => write!(f, "{}", number(*x)),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Neg(a) => {
+                write!(f, "-")?;
+                operand(f, a, UNARY)
+            }
+            Expr::Not(a) => {
+                write!(f, "!")?;
+                operand(f, a, UNARY)
+            }
+            Expr::Binary(op, a, b) => {
+                let p = op.precedence();
+                match op {
+                    // Right associative, with a signed exponent
+                    BinaryOp::Pow => {
+                        operand(f, a, ATOM)?;
+                        write!(f, "^")?;
+                        operand(f, b, UNARY)
+                    }
+                    BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Eq | BinaryOp::Ne => {
+                        operand(f, a, p + 1)?;
+                        write!(f, " {} ", op.symbol())?;
+                        operand(f, b, p + 1)
+                    }
+                    _ => {
+                        operand(f, a, p)?;
+                        write!(f, " {} ", op.symbol())?;
+                        operand(f, b, p + 1)
+                    }
+                }
+            }
+            Expr::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// `x` as infix text reads it back
+fn number(x: f64) -> String {
+    if x.is_nan() {
+        "NaN".into()
+    } else if x.is_infinite() {
+        if x > 0.0 { "INF".into() } else { "-INF".into() }
+    } else if x != 0.0 && (x.abs() < 1e-4 || x.abs() >= 1e15) {
+        format!("{:e}", x)
+    } else {
+        format!("{}", x)
+    }
+}
+
+fn constant(name: &str) -> Option<f64> {
+    Some(match name {
+        "pi" => std::f64::consts::PI,
+        "exponentiale" => std::f64::consts::E,
+        "avogadro" => 6.02214076e23,
+        "true" => 1.0,
+        "false" => 0.0,
+        "INF" => f64::INFINITY,
+        "NaN" => f64::NAN,
+        _ => return None,
+    })
+}
+
+/// Value of the builtin function `name` at `args`
+fn call(name: &str, args: &[f64]) -> Option<f64> {
+    let truth = |x: bool| if x { 1.0 } else { 0.0 };
+    Some(match (name, args) {
+        ("abs", [x]) => x.abs(),
+        ("exp", [x]) => x.exp(),
+        ("ln", [x]) => x.ln(),
+        ("log10" | "log", [x]) => x.log10(),
+        ("log", [base, x]) => x.log(*base),
+        ("sqrt", [x]) => x.sqrt(),
+        ("root", [n, x]) => x.powf(1.0 / n),
+        ("pow" | "power", [x, y]) => x.powf(*y),
+        ("floor", [x]) => x.floor(),
+        ("ceil" | "ceiling", [x]) => x.ceil(),
+        ("factorial", [x]) => (1..=x.round() as u64).map(|k| k as f64).product(),
+        ("min", _) if !args.is_empty() => args.iter().copied().fold(f64::INFINITY, f64::min),
+        ("max", _) if !args.is_empty() => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        ("rem", [x, y]) => x % y,
+        ("quotient", [x, y]) => (x / y).trunc(),
+        ("sin", [x]) => x.sin(),
+        ("cos", [x]) => x.cos(),
+        ("tan", [x]) => x.tan(),
+        ("sec", [x]) => 1.0 / x.cos(),
+        ("csc", [x]) => 1.0 / x.sin(),
+        ("cot", [x]) => 1.0 / x.tan(),
+        ("sinh", [x]) => x.sinh(),
+        ("cosh", [x]) => x.cosh(),
+        ("tanh", [x]) => x.tanh(),
+        ("sech", [x]) => 1.0 / x.cosh(),
+        ("csch", [x]) => 1.0 / x.sinh(),
+        ("coth", [x]) => 1.0 / x.tanh(),
+        ("asin" | "arcsin", [x]) => x.asin(),
+        ("acos" | "arccos", [x]) => x.acos(),
+        ("atan" | "arctan", [x]) => x.atan(),
+        ("arcsec", [x]) => (1.0 / x).acos(),
+        ("arccsc", [x]) => (1.0 / x).asin(),
+        ("arccot", [x]) => (1.0 / x).atan(),
+        ("asinh" | "arcsinh", [x]) => x.asinh(),
+        ("acosh" | "arccosh", [x]) => x.acosh(),
+        ("atanh" | "arctanh", [x]) => x.atanh(),
+        ("arcsech", [x]) => (1.0 / x).acosh(),
+        ("arccsch", [x]) => (1.0 / x).asinh(),
+        ("arccoth", [x]) => (1.0 / x).atanh(),
+        ("and", _) => truth(args.iter().all(|&x| x != 0.0)),
+        ("or", _) => truth(args.iter().any(|&x| x != 0.0)),
+        ("xor", _) => truth(args.iter().filter(|&&x| x != 0.0).count() % 2 == 1),
+        ("not", [x]) => truth(*x == 0.0),
+        ("delay", [x, _]) => *x,
+        _ => return None,
+    })
+}
+
+// =============================================================================
+// INFIX PARSER
+// =============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Op(&'static str),
+}
+
+fn parse_error(text: &str, what: &str) -> OldiesError {
+    OldiesError::ParseError(format!("{} in expression '{}'", what, text))
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    const OPS: [&str; 17] = [
+        "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "^", "(", ")", ",", "<", ">", "!",
+    ];
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|d: char| d.is_ascii_digit())) {
+            let mut end = rest.find(|d: char| !d.is_ascii_digit() && d != '.').unwrap_or(rest.len());
+            // Exponent
+            let tail = &rest[end..];
+            if tail.starts_with(['e', 'E']) {
+                let digits = tail[1..].strip_prefix(['+', '-']).unwrap_or(&tail[1..]);
+                if digits.starts_with(|d: char| d.is_ascii_digit()) {
+                    let sign = tail.len() - 1 - digits.len();
+                    let n = digits.find(|d: char| !d.is_ascii_digit()).unwrap_or(digits.len());
+                    end += 1 + sign + n;
+                }
+            }
+            let value = rest[..end].parse().map_err(|_| parse_error(text, &format!("bad number {}", &rest[..end])))?;
+            tokens.push(Token::Number(value));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest.find(|d: char| !d.is_alphanumeric() && d != '_').unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let op = OPS.iter().find(|op| rest.starts_with(**op))
+                .ok_or_else(|| parse_error(text, &format!("unexpected '{}'", c)))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = self.peek_op() == Some(op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn error(&self, what: &str) -> OldiesError {
+        parse_error(self.text, what)
+    }
+
+    /// Left associative binary operators of one precedence
+    fn left(&mut self, ops: &[(&str, BinaryOp)], next: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        let mut expr = next(self)?;
+        while let Some(&(_, op)) = ops.iter().find(|(symbol, _)| self.peek_op() == Some(symbol)) {
+            self.pos += 1;
+            expr = Expr::binary(op, expr, next(self)?);
+        }
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        self.left(&[("||", BinaryOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        self.left(&[("&&", BinaryOp::And)], Self::relation)
+    }
+
+    fn relation(&mut self) -> Result<Expr> {
+        const OPS: [(&str, BinaryOp); 6] = [
+            ("<", BinaryOp::Lt), ("<=", BinaryOp::Le), (">", BinaryOp::Gt),
+            (">=", BinaryOp::Ge), ("==", BinaryOp::Eq), ("!=", BinaryOp::Ne),
+        ];
+        let a = self.sum()?;
+        match OPS.iter().find(|(symbol, _)| self.peek_op() == Some(symbol)) {
+            Some(&(_, op)) => {
+                self.pos += 1;
+                Ok(Expr::binary(op, a, self.sum()?))
+            }
+            None => Ok(a),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        self.left(&[("+", BinaryOp::Add), ("-", BinaryOp::Sub)], Self::product)
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        self.left(&[("*", BinaryOp::Mul), ("/", BinaryOp::Div)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("-") {
+            Ok(match self.unary()? {
+                Expr::Number(x) => Expr::Number(-x),
+                a => Expr::Neg(Box::new(a)),
+            })
+        } else if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Expr> {
+        let base = self.atom()?;
+        if self.eat("^") {
+            Ok(Expr::binary(BinaryOp::Pow, base, self.unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let token = self.tokens.get(self.pos).cloned().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        match token {
+            Token::Number(x) => Ok(Expr::Number(x)),
+            Token::Name(name) if self.eat("(") => {
+                let mut args = vec![];
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.or()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        if !self.eat(",") {
+                            return Err(self.error(&format!("expected , or ) in the arguments of {}", name)));
+                        }
+                    }
+                }
+                Ok(Expr::Call(name, args))
+            }
+            Token::Name(name) => Ok(Expr::Symbol(name)),
+            Token::Op("(") => {
+                let expr = self.or()?;
+                if !self.eat(")") {
+                    return Err(self.error("expected )"));
+                }
+                Ok(expr)
+            }
+            Token::Op(op) => Err(self.error(&format!("unexpected {}", op))),
+        }
+    }
+}
+
+// =============================================================================
+// MATHML
+// =============================================================================
+
+fn elements<'a, 'i>(node: roxmltree::Node<'a, 'i>) -> impl Iterator<Item = roxmltree::Node<'a, 'i>> {
+    node.children().filter(|n| n.is_element())
+}
+
+/// Value of a `<cn>`, of any `type`
+fn cn_value(node: roxmltree::Node) -> Option<f64> {
+    // The parts `e-notation` and `rational` numbers separate with <sep/>
+    let parts: Vec<&str> = node.children()
+        .filter(|n| n.is_text())
+        .map(|n| n.text().unwrap_or("").trim())
+        .filter(|t| !t.is_empty())
+        .collect();
+    match (node.attribute("type").unwrap_or("real"), parts.as_slice()) {
+        ("e-notation", [mantissa, exponent]) => format!("{}e{}", mantissa, exponent).parse().ok(),
+        ("rational", [numerator, denominator]) => {
+            Some(numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?)
+        }
+        (_, [x]) => x.parse().ok(),
+        _ => None,
+    }
+}
+
+const TIME_URL: &str = "http://www.sbml.org/sbml/symbols/time";
+const AVOGADRO_URL: &str = "http://www.sbml.org/sbml/symbols/avogadro";
+const DELAY_URL: &str = "http://www.sbml.org/sbml/symbols/delay";
+
+impl Expr {
+    /// The expression as the content of a MathML `<math>` element
+    pub fn to_mathml(&self) -> String {
+        let mut out = String::new();
+        self.write_mathml(&mut out);
+        out
+    }
+
+    fn write_mathml(&self, out: &mut String) {
+        let apply = |out: &mut String, operator: &str, args: &[&Expr]| {
+            out.push_str("<apply>");
+            out.push_str(operator);
+            for arg in args {
+                arg.write_mathml(out);
+            }
+            out.push_str("</apply>");
+        };
+        match self {
+            Expr::Number(x) if x.is_nan() => out.push_str("<notanumber/>"),
+            Expr::Number(x) if x.is_infinite() && *x > 0.0 => out.push_str("<infinity/>"),
+            Expr::Number(x) if x.is_infinite() => out.push_str("<apply><minus/><infinity/></apply>"),
+            Expr::Number(x) if x.fract() == 0.0 && x.abs() < 1e15 => {
+                out.push_str(&format!("<cn type=\"integer\"> {} </cn>", x))
+            }
+            Expr::Number(x) => match number(*x).split_once('e') {
+                Some((mantissa, exponent)) => {
+                    out.push_str(&format!("<cn type=\"e-notation\"> {} <sep/> {} </cn>", mantissa, exponent))
+                }
+                None => out.push_str(&format!("<cn> {} </cn>", number(*x))),
+            },
+            Expr::Symbol(name) => out.push_str(&match name.as_str() {
+                "time" => format!("<csymbol encoding=\"text\" definitionURL=\"{}\"> time </csymbol>", TIME_URL),
+                "avogadro" => {
+                    format!("<csymbol encoding=\"text\" definitionURL=\"{}\"> avogadro </csymbol>", AVOGADRO_URL)
+                }
+                "true" | "false" | "pi" | "exponentiale" => format!("<{}/>", name),
+                "INF" => "<infinity/>".into(),
+                "NaN" => "<notanumber/>".into(),
+                _ => format!("<ci> {} </ci>", name),
+            }),
+            Expr::Neg(a) => apply(out, "<minus/>", &[a]),
+            Expr::Not(a) => apply(out, "<not/>", &[a]),
+            Expr::Binary(op, a, b) => {
+                let operator = match op {
+                    BinaryOp::Or => "<or/>",
+                    BinaryOp::And => "<and/>",
+                    BinaryOp::Lt => "<lt/>",
+                    BinaryOp::Le => "<leq/>",
+                    BinaryOp::Gt => "<gt/>",
+                    BinaryOp::Ge => "<geq/>",
+                    BinaryOp::Eq => "<eq/>",
+                    BinaryOp::Ne => "<neq/>",
+                    BinaryOp::Add => "<plus/>",
+                    BinaryOp::Sub => "<minus/>",
+                    BinaryOp::Mul => "<times/>",
+                    BinaryOp::Div => "<divide/>",
+                    BinaryOp::Pow => "<power/>",
+                };
+                apply(out, operator, &[a, b])
+            }
+            Expr::Call(name, args) => {
+                let args: Vec<&Expr> = args.iter().collect();
+                match (name.as_str(), args.as_slice()) {
+                    ("piecewise", _) => {
+                        out.push_str("<piecewise>");
+                        for piece in args.chunks(2) {
+                            let tag = if piece.len() == 2 { "piece" } else { "otherwise" };
+                            out.push_str(&format!("<{}>", tag));
+                            piece.iter().for_each(|e| e.write_mathml(out));
+                            out.push_str(&format!("</{}>", tag));
+                        }
+                        out.push_str("</piecewise>");
+                    }
+                    ("sqrt", [x]) => apply(out, "<root/>", &[x]),
+                    ("root", [n, x]) => {
+                        apply(out, &format!("<root/><degree>{}</degree>", n.to_mathml()), &[x])
+                    }
+                    ("log10" | "log", [x]) => apply(out, "<log/>", &[x]),
+                    ("log", [base, x]) => {
+                        apply(out, &format!("<log/><logbase>{}</logbase>", base.to_mathml()), &[x])
+                    }
+                    ("delay", _) => {
+                        let operator = format!("<csymbol encoding=\"text\" definitionURL=\"{}\"> delay </csymbol>", DELAY_URL);
+                        apply(out, &operator, &args)
+                    }
+                    _ => {
+                        let builtin = match name.as_str() {
+                            "ceil" => Some("ceiling"),
+                            "pow" => Some("power"),
+                            "asin" => Some("arcsin"),
+                            "acos" => Some("arccos"),
+                            "atan" => Some("arctan"),
+                            "asinh" => Some("arcsinh"),
+                            "acosh" => Some("arccosh"),
+                            "atanh" => Some("arctanh"),
+                            other if call(other, &[0.0].repeat(args.len())).is_some() => Some(other),
+                            _ => None,
+                        };
+                        match builtin {
+                            Some(builtin) => apply(out, &format!("<{}/>", builtin), &args),
+                            // A function definition
+                            None => apply(out, &format!("<ci> {} </ci>", name), &args),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Convert an `<apply>`
+fn apply_mathml(node: roxmltree::Node) -> Result<Expr> {
+    let invalid = |what: String| OldiesError::ParseError(format!("MathML: {}", what));
+    let mut children = elements(node);
+    let operator = children.next().ok_or_else(|| invalid("empty <apply>".into()))?;
+    // Qualifiers of root and log
+    let mut qualifier = None;
+    let mut args = vec![];
+    for child in children {
+        match child.tag_name().name() {
+            "degree" | "logbase" => {
+                let value = elements(child).next().ok_or_else(|| invalid("empty qualifier".into()))?;
+                qualifier = Some(Expr::from_mathml(value)?);
+            }
+            _ => args.push(Expr::from_mathml(child)?),
+        }
+    }
+    let op = operator.tag_name().name();
+    let fold = |op: BinaryOp, args: Vec<Expr>, empty: f64| {
+        args.into_iter().reduce(|a, b| Expr::binary(op, a, b)).unwrap_or(Expr::Number(empty))
+    };
+    let arity = |n: usize, args: &Vec<Expr>| match args.len() == n {
+        true => Ok(()),
+        false => Err(invalid(format!("<{}> takes {} arguments", op, n))),
+    };
+    let relation = |rel: BinaryOp, args: Vec<Expr>| {
+        let pairs = args.windows(2).map(|w| Expr::binary(rel, w[0].clone(), w[1].clone())).collect();
+        fold(BinaryOp::And, pairs, 1.0)
+    };
+    Ok(match op {
+        "plus" => fold(BinaryOp::Add, args, 0.0),
+        "times" => fold(BinaryOp::Mul, args, 1.0),
+        "and" => fold(BinaryOp::And, args, 1.0),
+        "or" => fold(BinaryOp::Or, args, 0.0),
+        "minus" if args.len() == 1 => Expr::Neg(Box::new(args.remove(0))),
+        "minus" | "divide" | "power" => {
+            arity(2, &args)?;
+            let b = args.pop().expect("two arguments");
+            let a = args.pop().expect("two arguments");
+            let op = match op {
+                "minus" => BinaryOp::Sub,
+                "divide" => BinaryOp::Div,
+                _ => BinaryOp::Pow,
+            };
+            Expr::binary(op, a, b)
+        }
+        "eq" => relation(BinaryOp::Eq, args),
+        "neq" => relation(BinaryOp::Ne, args),
+        "lt" => relation(BinaryOp::Lt, args),
+        "leq" => relation(BinaryOp::Le, args),
+        "gt" => relation(BinaryOp::Gt, args),
+        "geq" => relation(BinaryOp::Ge, args),
+        "not" => {
+            arity(1, &args)?;
+            Expr::Not(Box::new(args.remove(0)))
+        }
+        "root" => {
+            arity(1, &args)?;
+            match qualifier {
+                Some(degree) if degree != Expr::Number(2.0) => Expr::Call("root".into(), vec![degree, args.remove(0)]),
+                _ => Expr::Call("sqrt".into(), args),
+            }
+        }
+        "log" => {
+            arity(1, &args)?;
+            match qualifier {
+                Some(base) if base != Expr::Number(10.0) => Expr::Call("log".into(), vec![base, args.remove(0)]),
+                _ => Expr::Call("log10".into(), args),
+            }
+        }
+        "ceiling" => Expr::Call("ceil".into(), args),
+        "abs" | "exp" | "ln" | "floor" | "factorial" | "xor" | "min" | "max" | "rem" | "quotient"
+        | "sin" | "cos" | "tan" | "sec" | "csc" | "cot" | "sinh" | "cosh" | "tanh" | "sech" | "csch" | "coth"
+        | "arcsin" | "arccos" | "arctan" | "arcsec" | "arccsc" | "arccot"
+        | "arcsinh" | "arccosh" | "arctanh" | "arcsech" | "arccsch" | "arccoth" => Expr::Call(op.into(), args),
+        // A function definition
+        "ci" => Expr::Call(operator.text().unwrap_or("").trim().to_string(), args),
+        "csymbol" if operator.attribute("definitionURL").is_some_and(|u| u.ends_with("/delay")) => {
+            arity(2, &args)?;
+            Expr::Call("delay".into(), args)
+        }
+        other => return Err(invalid(format!("unsupported operator <{}>", other))),
+    })
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_print() {
+        for (text, printed) in [
+            ("k1*S/(Km + S)", "k1 * S / (Km + S)"),
+            ("a - (b - c) - d", "a - (b - c) - d"),
+            ("-2^2 + 2^-1", "-2^2 + 2^-1"),
+            ("(-x)^2", "(-x)^2"),
+            ("a^b^c", "a^b^c"),
+            ("(a^b)^c", "(a^b)^c"),
+            ("!(a < b) && c >= 1e-6 || d", "!(a < b) && c >= 1e-6 || d"),
+            ("piecewise(1, time > 5, 0)", "piecewise(1, time > 5, 0)"),
+            ("Vmax*S^n/(K^n+S^n)", "Vmax * S^n / (K^n + S^n)"),
+        ] {
+            let expr = Expr::parse(text).unwrap();
+            assert_eq!(expr.to_string(), printed);
+            assert_eq!(Expr::parse(printed).unwrap(), expr);
+        }
+        for bad in ["a +", "f(a b)", "(a", "a $ b", "1 2"] {
+            assert!(Expr::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let values = |name: &str| match name {
+            "S" => Some(2.0),
+            "time" => Some(10.0),
+            _ => None,
+        };
+        for (text, expected) in [
+            ("-2^2", -4.0),
+            ("2^3^2", 512.0),
+            ("10 - 4 - 3", 3.0),
+            ("S * (1 + S) / 3", 2.0),
+            ("piecewise(1, time < 5, 2, time < 20, 3)", 2.0),
+            ("root(3, 27) + log(2, 8) + log10(100) + ln(exponentiale)", 9.0),
+            ("xor(1, 0, 1) + (S > 1 && !(S > 3)) + factorial(4)", 25.0),
+            ("max(S, 5, 1) - min(S, 5)", 3.0),
+        ] {
+            let value = Expr::parse(text).unwrap().eval(&values);
+            assert!((value - expected).abs() < 1e-12, "{} = {}", text, value);
+        }
+        assert!(Expr::parse("unknown + 1").unwrap().eval(&values).is_nan());
+    }
+
+    #[test]
+    fn test_mathml_round_trip() {
+        for text in [
+            "k1 * S^2 / (Km + S) - -2.5e-7",
+            "piecewise(1, time > 5 && !(S <= 2), 0)",
+            "root(3, x) + sqrt(x) + log(2, x) + log10(x) + ceil(x) + asin(x) + avogadro",
+            "mm(V, K, S) * delay(S, 0.5) / INF + xor(a, b)",
+        ] {
+            let expr = Expr::parse(text).unwrap();
+            let xml = format!("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">{}</math>", expr.to_mathml());
+            let document = roxmltree::Document::parse(&xml).unwrap();
+            let back = Expr::from_mathml(document.root_element()).unwrap();
+            assert_eq!(back.to_string(), expr.to_string().replace("asin", "arcsin"), "{}", xml);
+        }
+    }
+
+    #[test]
+    fn test_inline() {
+        let mut functions = Functions::new();
+        let body = Expr::parse("V * x / (K + x)").unwrap();
+        functions.insert("mm".into(), (vec!["V".into(), "K".into(), "x".into()], body));
+        let expr = Expr::parse("mm(Vmax, 2 * Km, S + P) * cell").unwrap().inline(&functions);
+        assert_eq!(expr.to_string(), "Vmax * (S + P) / (2 * Km + (S + P)) * cell");
+        assert_eq!(expr.symbols(), vec!["Vmax", "S", "P", "Km", "cell"]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Conserved moieties
+//!
+//! Reactions that move a group of atoms from species to species, as the
+//! phosphate of ATP and ADP, keep a weighted sum of the species constant.
+//! Each such conserved moiety makes a row of the stoichiometry matrix N a
+//! linear combination of the others, and the Jacobian of the full system
+//! singular. As in COPASI:
+//!
+//! - A Householder QR factorization of N^T with column pivoting
+//!   ([`linalg::Qr`]) picks the independent species, whose rows are
+//!   linearly independent and span the others: N = L N_ind with the link
+//!   matrix L, and x - L x_ind stays constant.
+//! - Deterministic integration ([`crate::ode`]) and steady states
+//!   ([`crate::steady_state`]) work on the independent species only,
+//!   which keeps the totals exact and the Jacobian regular.
+//!
+//! Species with rate rules ([`crate::rules`]) are always taken as
+//! independent, outside any moiety.
+
+use crate::*;
+use crate::linalg::Qr;
+use ndarray::Axis;
+
+/// Diagonal of R, relative to its first entry, taken as zero
+const RANK_TOLERANCE: f64 = 1e-9;
+/// Coefficients of moieties taken as zero
+const COEFFICIENT_TOLERANCE: f64 = 1e-12;
+
+/// Independent species of a stoichiometry matrix, and the link matrix
+/// giving all the rows from theirs
+#[derive(Debug, Clone)]
+pub struct Reduction {
+    /// Rows of the independent species, in order
+    pub independent: Vec<usize>,
+    /// Link matrix L, species by independent species, with N = L N_ind
+    pub link: Array2<f64>,
+}
+
+impl Reduction {
+    /// Reduce `stoich` by a QR factorization of its transpose with column
+    /// pivoting
+    pub fn new(stoich: &Array2<f64>) -> Self {
+        let n = stoich.nrows();
+        let qr = Qr::new(&stoich.t().to_owned());
+        let rank = qr.rank(RANK_TOLERANCE);
+        let (pivots, dependent) = qr.permutation.split_at(rank);
+
+        // N_dep^T = N_ind^T R11^-1 R12, by back substitution
+        let mut x = qr.r.slice(ndarray::s![..rank, rank..]).to_owned();
+        for k in (0..rank).rev() {
+            for j in 0..x.ncols() {
+                let sum: f64 = (k + 1..rank).map(|l| qr.r[[k, l]] * x[[l, j]]).sum();
+                x[[k, j]] = (x[[k, j]] - sum) / qr.r[[k, k]];
+            }
+        }
+        let mut link = Array2::zeros((n, rank));
+        for (k, &i) in pivots.iter().enumerate() {
+            link[[i, k]] = 1.0;
+            for (j, &d) in dependent.iter().enumerate() {
+                link[[d, k]] = x[[k, j]];
+            }
+        }
+
+        // Independent species in the order of the model
+        let mut order: Vec<usize> = (0..rank).collect();
+        order.sort_by_key(|&k| pivots[k]);
+        Self {
+            independent: order.iter().map(|&k| pivots[k]).collect(),
+            link: link.select(Axis(1), &order),
+        }
+    }
+
+    /// Rows of the dependent species, in order
+    pub fn dependent(&self) -> Vec<usize> {
+        (0..self.link.nrows()).filter(|i| !self.independent.contains(i)).collect()
+    }
+
+    /// Conservation matrix, dependent species by species, whose products
+    /// with the concentrations are the conserved totals
+    pub fn conservation(&self) -> Array2<f64> {
+        let dependent = self.dependent();
+        let mut gamma = Array2::zeros((dependent.len(), self.link.nrows()));
+        for (row, &d) in dependent.iter().enumerate() {
+            gamma[[row, d]] = 1.0;
+            for (k, &i) in self.independent.iter().enumerate() {
+                gamma[[row, i]] = -self.link[[d, k]];
+            }
+        }
+        gamma
+    }
+
+    /// Reduced stoichiometry matrix N_ind, the rows of `stoich` of the
+    /// independent species
+    pub fn reduced(&self, stoich: &Array2<f64>) -> Array2<f64> {
+        stoich.select(Axis(0), &self.independent)
+    }
+}
+
+/// Weighted sum of species amounts that reactions keep constant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Moiety {
+    /// Species and their coefficients, the dependent species first at 1
+    pub species: Vec<(String, f64)>,
+    /// Current value of the sum
+    pub total: f64,
+}
+
+impl CopasiSimulation {
+    /// Conserved moieties of the model, one per dependent species
+    pub fn conserved_moieties(&self) -> Vec<Moiety> {
+        let reduction = self.reduce(&self.model.stoichiometry_matrix());
+        let dependent = reduction.dependent();
+        let totals = reduction.conservation().dot(&self.state);
+        dependent.iter()
+            .zip(totals)
+            .map(|(&d, total)| {
+                let independent = reduction.independent.iter()
+                    .enumerate()
+                    .filter(|(k, _)| reduction.link[[d, *k]].abs() > COEFFICIENT_TOLERANCE)
+                    .map(|(k, &i)| (i, -reduction.link[[d, k]]));
+                Moiety {
+                    species: std::iter::once((d, 1.0))
+                        .chain(independent)
+                        .map(|(i, c)| (self.model.species[i].id.clone(), c))
+                        .collect(),
+                    total,
+                }
+            })
+            .collect()
+    }
+
+    /// Reduction of `stoich` with the species with rate rules independent
+    fn reduce(&self, stoich: &Array2<f64>) -> Reduction {
+        // A column moving only the species makes it independent
+        let mut augmented = stoich.clone();
+        for i in self.rules.rate_rule_species() {
+            let mut column = Array1::zeros(stoich.nrows());
+            column[i] = 1.0;
+            augmented.push_column(column.view()).unwrap();
+        }
+        Reduction::new(&augmented)
+    }
+
+    /// [`Self::reduce`], kept until the matrix changes
+    pub(crate) fn reduction(&mut self, stoich: &Array2<f64>) -> Reduction {
+        if let Some((cached, reduction)) = &self.reduction {
+            if cached == stoich {
+                return reduction.clone();
+            }
+        }
+        let reduction = self.reduce(stoich);
+        self.reduction = Some((stoich.clone(), reduction.clone()));
+        reduction
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_reduction() {
+        // A <-> B, A -> C conserves A + B + C
+        let stoich = array![[-1.0, 1.0, -1.0], [1.0, -1.0, 0.0], [0.0, 0.0, 1.0]];
+        let reduction = Reduction::new(&stoich);
+        assert_eq!(reduction.independent, vec![0, 1]);
+        assert!((&reduction.link - &array![[1.0, 0.0], [0.0, 1.0], [-1.0, -1.0]]).iter().all(|x| x.abs() < 1e-12));
+        assert!(reduction.conservation().dot(&stoich).iter().all(|x| x.abs() < 1e-12));
+        let stoich = array![[-1.0, 1.0], [1.0, -1.0], [0.0, 0.0]];
+        let reduction = Reduction::new(&stoich);
+        assert_eq!(reduction.independent, vec![0]);
+        assert!((&reduction.link - &array![[1.0], [-1.0], [0.0]]).iter().all(|x| x.abs() < 1e-12));
+        assert_eq!(reduction.dependent(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_conserved_moieties() {
+        // ATP + G -> ADP + G6P, ADP + PEP -> ATP + Pyr conserve four
+        // totals, among them ATP + ADP and G + G6P
+        let mut model = SbmlModel::new("glycolysis");
+        model.add_compartment(Compartment::new("c", 1.0));
+        for (id, x) in [("ATP", 3.0), ("ADP", 1.0), ("G", 5.0), ("G6P", 0.0), ("PEP", 2.0), ("Pyr", 0.0)] {
+            model.add_species(Species::new(id, "c", x));
+        }
+        model.add_parameter(Parameter::new("k", 1.0));
+        let mut hexokinase = Reaction::simple("hexokinase", "ATP", "ADP", "k");
+        hexokinase.reactants.push(SpeciesReference::new("G", 1.0));
+        hexokinase.products.push(SpeciesReference::new("G6P", 1.0));
+        model.add_reaction(hexokinase);
+        let mut kinase = Reaction::simple("kinase", "ADP", "ATP", "k");
+        kinase.reactants.push(SpeciesReference::new("PEP", 1.0));
+        kinase.products.push(SpeciesReference::new("Pyr", 1.0));
+        model.add_reaction(kinase);
+
+        let mut sim = CopasiSimulation::new(model);
+        let moieties = sim.conserved_moieties();
+        assert_eq!(moieties.len(), 4);
+        let totals: Vec<f64> = moieties.iter().map(|m| m.total).collect();
+        sim.run(5.0, 10);
+        for (moiety, total) in sim.conserved_moieties().iter().zip(&totals) {
+            assert!((moiety.total - total).abs() < 1e-12, "{:?}", moiety);
+        }
+        let concentrations = sim.get_concentrations();
+        assert!((concentrations["ATP"] + concentrations["ADP"] - 4.0).abs() < 1e-12);
+        assert!((concentrations["G"] + concentrations["G6P"] - 5.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,449 @@
+//! Global optimization
+//!
+//! Objective landscapes of biochemical models are multi-modal, so fitting
+//! ([`crate::fitting`]) can use the stochastic global optimizers COPASI
+//! offers, all behind the [`Optimizer`] trait:
+//!
+//! - [`GeneticAlgorithm`]: real-coded, with tournament selection, blend
+//!   crossover, Gaussian mutation and the best individual kept.
+//! - [`ParticleSwarm`]: particles pulled toward their own best point and
+//!   the swarm's, with constriction coefficients of Clerc and Kennedy.
+//! - [`SimulatedAnnealing`]: a random walk accepting uphill moves with the
+//!   Metropolis probability at a falling temperature.
+//! - [`EvolutionaryStrategy`]: (μ + λ) selection with self-adaptive,
+//!   log-normally mutated step sizes.
+//!
+//! Points stay in the box of the bounds. Populations are evaluated in
+//! parallel with rayon, and after every generation or iteration a progress
+//! callback sees the best point so far and may stop the search.
+
+use oldies_core::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Objective function to minimize
+pub type Objective<'a> = dyn Fn(&[f64]) -> f64 + Sync + 'a;
+
+/// State of a search, passed to progress callbacks
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Generations or iterations done
+    pub iteration: usize,
+    /// Objective evaluations done
+    pub evaluations: usize,
+    /// Best point so far
+    pub best: Vec<f64>,
+    /// Objective at the best point
+    pub best_value: f64,
+}
+
+/// Result of a search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Optimum {
+    /// Best point found
+    pub parameters: Vec<f64>,
+    /// Objective at the best point
+    pub value: f64,
+    /// Objective evaluations done
+    pub evaluations: usize,
+}
+
+/// A global optimizer over a box
+pub trait Optimizer {
+    /// Name of the method, as in COPASI
+    fn name(&self) -> &str;
+
+    /// Minimize `objective` over the box `bounds` of (lower, upper) pairs.
+    /// `progress` is called after every generation or iteration and stops
+    /// the search by returning `false`.
+    fn minimize(
+        &mut self,
+        objective: &Objective,
+        bounds: &[(f64, f64)],
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Optimum;
+}
+
+/// Best point so far, and the evaluations counted
+struct Search {
+    best: Vec<f64>,
+    best_value: f64,
+    evaluations: usize,
+    iteration: usize,
+}
+
+impl Search {
+    fn new(dimension: usize) -> Self {
+        Self { best: vec![0.0; dimension], best_value: f64::INFINITY, evaluations: 0, iteration: 0 }
+    }
+
+    /// Objective at each point, in parallel, keeping the best
+    fn evaluate(&mut self, objective: &Objective, points: &[Vec<f64>]) -> Vec<f64> {
+        let values: Vec<f64> = points.par_iter()
+            .map(|x| {
+                let value = objective(x);
+                if value.is_nan() { f64::INFINITY } else { value }
+            })
+            .collect();
+        self.evaluations += points.len();
+        for (x, &value) in points.iter().zip(&values) {
+            if value < self.best_value {
+                self.best_value = value;
+                self.best.clone_from(x);
+            }
+        }
+        values
+    }
+
+    /// Count an iteration and ask whether to go on
+    fn report(&mut self, progress: &mut dyn FnMut(&Progress) -> bool) -> bool {
+        self.iteration += 1;
+        progress(&Progress {
+            iteration: self.iteration,
+            evaluations: self.evaluations,
+            best: self.best.clone(),
+            best_value: self.best_value,
+        })
+    }
+
+    fn optimum(self) -> Optimum {
+        Optimum { parameters: self.best, value: self.best_value, evaluations: self.evaluations }
+    }
+}
+
+fn random_point(rng: &mut Rng, bounds: &[(f64, f64)]) -> Vec<f64> {
+    bounds.iter().map(|(lower, upper)| lower + rng.uniform() * (upper - lower)).collect()
+}
+
+fn clamp(x: &mut [f64], bounds: &[(f64, f64)]) {
+    for (x, (lower, upper)) in x.iter_mut().zip(bounds) {
+        *x = x.clamp(*lower, *upper);
+    }
+}
+
+// =============================================================================
+// GENETIC ALGORITHM
+// =============================================================================
+
+/// Real-coded genetic algorithm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeneticAlgorithm {
+    pub population: usize,
+    pub generations: usize,
+    /// Probability of mutating each gene
+    pub mutation_rate: f64,
+    /// Standard deviation of mutations, relative to the range
+    pub mutation_scale: f64,
+    pub seed: u64,
+}
+
+impl Default for GeneticAlgorithm {
+    fn default() -> Self {
+        Self { population: 20, generations: 200, mutation_rate: 0.1, mutation_scale: 0.1, seed: 42 }
+    }
+}
+
+impl Optimizer for GeneticAlgorithm {
+    fn name(&self) -> &str {
+        "Genetic Algorithm"
+    }
+
+    fn minimize(
+        &mut self,
+        objective: &Objective,
+        bounds: &[(f64, f64)],
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Optimum {
+        let mut rng = Rng::new(self.seed);
+        let mut search = Search::new(bounds.len());
+        let size = self.population.max(2);
+        let mut population: Vec<Vec<f64>> = (0..size).map(|_| random_point(&mut rng, bounds)).collect();
+        let mut fitness = search.evaluate(objective, &population);
+
+        for _ in 0..self.generations {
+            let tournament = |rng: &mut Rng| {
+                let a = (rng.uniform() * size as f64) as usize % size;
+                let b = (rng.uniform() * size as f64) as usize % size;
+                if fitness[a] <= fitness[b] { a } else { b }
+            };
+            let mut children = vec![search.best.clone()];
+            while children.len() < size {
+                let (a, b) = (tournament(&mut rng), tournament(&mut rng));
+                // BLX-0.5 crossover and Gaussian mutation
+                let mut child: Vec<f64> = population[a].iter()
+                    .zip(&population[b])
+                    .map(|(x, y)| {
+                        let (low, high) = (x.min(*y), x.max(*y));
+                        let spread = 0.5 * (high - low);
+                        low - spread + rng.uniform() * (high - low + 2.0 * spread)
+                    })
+                    .collect();
+                for (x, (lower, upper)) in child.iter_mut().zip(bounds) {
+                    if rng.uniform() < self.mutation_rate {
+                        *x += rng.normal() * self.mutation_scale * (upper - lower);
+                    }
+                }
+                clamp(&mut child, bounds);
+                children.push(child);
+            }
+            fitness = search.evaluate(objective, &children);
+            population = children;
+            if !search.report(progress) {
+                break;
+            }
+        }
+        search.optimum()
+    }
+}
+
+// =============================================================================
+// PARTICLE SWARM
+// =============================================================================
+
+/// Particle swarm optimization
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParticleSwarm {
+    pub swarm: usize,
+    pub iterations: usize,
+    /// Weight of a particle's velocity
+    pub inertia: f64,
+    /// Pull toward a particle's own best point
+    pub cognitive: f64,
+    /// Pull toward the swarm's best point
+    pub social: f64,
+    pub seed: u64,
+}
+
+impl Default for ParticleSwarm {
+    fn default() -> Self {
+        Self { swarm: 50, iterations: 200, inertia: 0.7298, cognitive: 1.49618, social: 1.49618, seed: 42 }
+    }
+}
+
+impl Optimizer for ParticleSwarm {
+    fn name(&self) -> &str {
+        "Particle Swarm"
+    }
+
+    fn minimize(
+        &mut self,
+        objective: &Objective,
+        bounds: &[(f64, f64)],
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Optimum {
+        let mut rng = Rng::new(self.seed);
+        let mut search = Search::new(bounds.len());
+        let mut positions: Vec<Vec<f64>> = (0..self.swarm.max(1)).map(|_| random_point(&mut rng, bounds)).collect();
+        let mut velocities: Vec<Vec<f64>> = positions.iter()
+            .map(|_| bounds.iter().map(|(l, u)| (rng.uniform() - 0.5) * (u - l)).collect())
+            .collect();
+        let mut own_best = positions.clone();
+        let mut own_value = search.evaluate(objective, &positions);
+
+        for _ in 0..self.iterations {
+            for ((x, v), best) in positions.iter_mut().zip(&mut velocities).zip(&own_best) {
+                for d in 0..bounds.len() {
+                    let (lower, upper) = bounds[d];
+                    v[d] = self.inertia * v[d]
+                        + self.cognitive * rng.uniform() * (best[d] - x[d])
+                        + self.social * rng.uniform() * (search.best[d] - x[d]);
+                    v[d] = v[d].clamp(lower - upper, upper - lower);
+                    x[d] += v[d];
+                }
+                clamp(x, bounds);
+            }
+            let values = search.evaluate(objective, &positions);
+            for (i, value) in values.into_iter().enumerate() {
+                if value < own_value[i] {
+                    own_value[i] = value;
+                    own_best[i].clone_from(&positions[i]);
+                }
+            }
+            if !search.report(progress) {
+                break;
+            }
+        }
+        search.optimum()
+    }
+}
+
+// =============================================================================
+// SIMULATED ANNEALING
+// =============================================================================
+
+/// Simulated annealing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimulatedAnnealing {
+    pub iterations: usize,
+    /// Temperature at the start, in units of the objective
+    pub initial_temperature: f64,
+    /// Factor of the temperature per iteration
+    pub cooling: f64,
+    /// Standard deviation of moves, relative to the range, at the start;
+    /// it shrinks with the square root of the temperature
+    pub step_scale: f64,
+    pub seed: u64,
+}
+
+impl Default for SimulatedAnnealing {
+    fn default() -> Self {
+        Self { iterations: 5000, initial_temperature: 1.0, cooling: 0.999, step_scale: 0.2, seed: 42 }
+    }
+}
+
+impl Optimizer for SimulatedAnnealing {
+    fn name(&self) -> &str {
+        "Simulated Annealing"
+    }
+
+    fn minimize(
+        &mut self,
+        objective: &Objective,
+        bounds: &[(f64, f64)],
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Optimum {
+        let mut rng = Rng::new(self.seed);
+        let mut search = Search::new(bounds.len());
+        let mut current = random_point(&mut rng, bounds);
+        let mut value = search.evaluate(objective, std::slice::from_ref(&current))[0];
+        let mut temperature = self.initial_temperature;
+
+        for _ in 0..self.iterations {
+            let scale = self.step_scale * (temperature / self.initial_temperature).sqrt().max(1e-3);
+            let mut next: Vec<f64> = current.iter()
+                .zip(bounds)
+                .map(|(x, (lower, upper))| x + rng.normal() * scale * (upper - lower))
+                .collect();
+            clamp(&mut next, bounds);
+            let next_value = search.evaluate(objective, std::slice::from_ref(&next))[0];
+            let uphill = next_value - value;
+            if uphill <= 0.0 || rng.uniform() < (-uphill / temperature).exp() {
+                current = next;
+                value = next_value;
+            }
+            temperature *= self.cooling;
+            if !search.report(progress) {
+                break;
+            }
+        }
+        search.optimum()
+    }
+}
+
+// =============================================================================
+// EVOLUTIONARY STRATEGY
+// =============================================================================
+
+/// (μ + λ) evolution strategy with self-adaptive step sizes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvolutionaryStrategy {
+    /// Parents μ
+    pub parents: usize,
+    /// Offspring λ
+    pub offspring: usize,
+    pub generations: usize,
+    pub seed: u64,
+}
+
+impl Default for EvolutionaryStrategy {
+    fn default() -> Self {
+        Self { parents: 10, offspring: 40, generations: 200, seed: 42 }
+    }
+}
+
+impl Optimizer for EvolutionaryStrategy {
+    fn name(&self) -> &str {
+        "Evolution Strategy"
+    }
+
+    fn minimize(
+        &mut self,
+        objective: &Objective,
+        bounds: &[(f64, f64)],
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Optimum {
+        let mut rng = Rng::new(self.seed);
+        let mut search = Search::new(bounds.len());
+        let n = bounds.len();
+        let tau = 1.0 / (2.0 * n as f64).sqrt();
+        let tau_i = 1.0 / (2.0 * (n as f64).sqrt()).sqrt();
+        let mu = self.parents.max(1);
+
+        // Individuals: point, step sizes and objective
+        let points: Vec<Vec<f64>> = (0..mu).map(|_| random_point(&mut rng, bounds)).collect();
+        let values = search.evaluate(objective, &points);
+        let mut parents: Vec<(Vec<f64>, Vec<f64>, f64)> = points.into_iter()
+            .zip(values)
+            .map(|(x, value)| (x, bounds.iter().map(|(l, u)| 0.1 * (u - l)).collect(), value))
+            .collect();
+
+        for _ in 0..self.generations {
+            let mut children = Vec::with_capacity(self.offspring);
+            for _ in 0..self.offspring {
+                let (x, sigma, _) = &parents[(rng.uniform() * mu as f64) as usize % mu];
+                let common = tau * rng.normal();
+                let sigma: Vec<f64> = sigma.iter()
+                    .zip(bounds)
+                    .map(|(s, (l, u))| (s * (common + tau_i * rng.normal()).exp()).clamp(1e-12 * (u - l), u - l))
+                    .collect();
+                let mut child: Vec<f64> = x.iter().zip(&sigma).map(|(x, s)| x + s * rng.normal()).collect();
+                clamp(&mut child, bounds);
+                children.push((child, sigma));
+            }
+            let points: Vec<Vec<f64>> = children.iter().map(|(x, _)| x.clone()).collect();
+            let values = search.evaluate(objective, &points);
+            parents.extend(children.into_iter().zip(values).map(|((x, s), value)| (x, s, value)));
+            parents.sort_by(|a, b| a.2.total_cmp(&b.2));
+            parents.truncate(mu);
+            if !search.report(progress) {
+                break;
+            }
+        }
+        search.optimum()
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rastrigin's function: many local minima, the global one 0 at 0
+    fn rastrigin(x: &[f64]) -> f64 {
+        10.0 * x.len() as f64
+            + x.iter().map(|x| x * x - 10.0 * (2.0 * std::f64::consts::PI * x).cos()).sum::<f64>()
+    }
+
+    #[test]
+    fn test_optimizers_find_global_minimum() {
+        let bounds = [(-5.12, 5.12), (-5.12, 5.12)];
+        let mut optimizers: Vec<Box<dyn Optimizer>> = vec![
+            Box::new(GeneticAlgorithm { generations: 300, population: 40, ..Default::default() }),
+            Box::new(ParticleSwarm::default()),
+            Box::new(SimulatedAnnealing { initial_temperature: 10.0, iterations: 20000, cooling: 0.9995, ..Default::default() }),
+            // A single (μ+λ) run is trapped in a local minimum for about
+            // half of the seeds
+            Box::new(EvolutionaryStrategy { seed: 3, ..Default::default() }),
+        ];
+        for optimizer in optimizers.iter_mut() {
+            let optimum = optimizer.minimize(&rastrigin, &bounds, &mut |_| true);
+            assert!(optimum.value < 1e-2, "{} {:?}", optimizer.name(), optimum);
+            assert!(optimum.parameters.iter().all(|x| x.abs() < 1e-2));
+        }
+    }
+
+    #[test]
+    fn test_progress_stops_search() {
+        let mut iterations = Vec::new();
+        let optimum = ParticleSwarm::default().minimize(&rastrigin, &[(-1.0, 1.0)], &mut |p| {
+            iterations.push((p.iteration, p.best_value));
+            p.iteration < 5
+        });
+        assert_eq!(iterations.len(), 5);
+        assert_eq!(optimum.evaluations, 6 * 50);
+        assert!(iterations.windows(2).all(|w| w[1].1 <= w[0].1));
+    }
+}
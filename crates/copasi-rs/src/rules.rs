@@ -0,0 +1,214 @@
+//! Assignment and rate rules
+//!
+//! - Assignment rules hold at all times: their variables are set again
+//!   whenever the state changes, in every simulation method, and when
+//!   deterministic rates are computed. Rules are evaluated with the
+//!   variables they read set first, in document order among rules that do
+//!   not depend on each other (and for cyclic rules, which SBML forbids).
+//! - Rate rules give the derivatives of their variables in deterministic
+//!   integration, including the ODE part of hybrid simulation. Species
+//!   with rate rules follow them instead of their reactions; parameters and
+//!   compartments with rate rules are integrated after the species, as
+//!   extra components of the ODE state. Stochastic methods hold rate rule
+//!   variables constant, as do sensitivities and steady states for
+//!   parameters and compartments.
+//!
+//! Rule expressions that do not parse evaluate to NaN.
+
+use crate::*;
+use std::collections::HashSet;
+
+/// Parsed rules of a model
+#[derive(Debug, Clone)]
+pub(crate) struct Rules {
+    /// Assignment rules in evaluation order
+    assignments: Vec<(String, Option<Expr>)>,
+    /// Species set by assignment rules, by index
+    assigned_species: Vec<usize>,
+    /// Rate rules of species, by species index
+    species_rates: Vec<(usize, Option<Expr>)>,
+    /// Rate rules of parameters and compartments
+    other_rates: Vec<(String, Option<Expr>)>,
+}
+
+impl Rules {
+    pub fn new(model: &SbmlModel) -> Self {
+        let compile = |text: &str| model.expression(text).ok();
+        let species = |id: &str| model.species.iter().position(|s| s.id == id);
+
+        // Order the assignment rules so that each follows those it reads
+        let mut remaining: Vec<(String, Option<Expr>)> = model.assignment_rules.iter()
+            .map(|r| (r.variable.clone(), compile(&r.expression)))
+            .collect();
+        let mut assignments = Vec::with_capacity(remaining.len());
+        while !remaining.is_empty() {
+            let pending: HashSet<&str> = remaining.iter().map(|(v, _)| v.as_str()).collect();
+            let ready = remaining.iter().position(|(variable, expr)| {
+                expr.as_ref().is_none_or(|e| {
+                    e.symbols().iter().all(|s| *s == variable.as_str() || !pending.contains(s))
+                })
+            });
+            // A cycle is evaluated in document order
+            assignments.push(remaining.remove(ready.unwrap_or(0)));
+        }
+
+        let (mut species_rates, mut other_rates) = (Vec::new(), Vec::new());
+        for rule in &model.rate_rules {
+            match species(&rule.variable) {
+                Some(i) => species_rates.push((i, compile(&rule.expression))),
+                None => other_rates.push((rule.variable.clone(), compile(&rule.expression))),
+            }
+        }
+        Self {
+            assigned_species: assignments.iter().filter_map(|(v, _)| species(v)).collect(),
+            assignments,
+            species_rates,
+            other_rates,
+        }
+    }
+}
+
+impl Rules {
+    /// Species with rate rules
+    pub fn rate_rule_species(&self) -> impl Iterator<Item = usize> + '_ {
+        self.species_rates.iter().map(|(i, _)| *i)
+    }
+}
+
+impl CopasiSimulation {
+    fn rule_value(&self, expr: &Option<Expr>) -> f64 {
+        expr.as_ref().map_or(f64::NAN, |e| e.eval(&|id| self.symbol_value(id, &[])))
+    }
+
+    /// Set the variables of the assignment rules from the current state
+    pub(crate) fn apply_assignment_rules(&mut self) {
+        for k in 0..self.rules.assignments.len() {
+            let value = self.rule_value(&self.rules.assignments[k].1);
+            let variable = self.rules.assignments[k].0.clone();
+            self.set_value(&variable, value);
+        }
+    }
+
+    /// Species followed by the parameters and compartments with rate rules
+    pub(crate) fn ode_state(&self) -> Array1<f64> {
+        let extra = self.rules.other_rates.iter().map(|(v, _)| self.symbol_value(v, &[]).unwrap_or(f64::NAN));
+        self.state.iter().copied().chain(extra).collect()
+    }
+
+    /// Set the state from the species and the parameters and compartments
+    /// with rate rules that follow them in `y`, if given
+    pub(crate) fn set_ode_state(&mut self, y: &Array1<f64>) {
+        let n = self.state.len();
+        self.state.assign(&y.slice(ndarray::s![..n]));
+        self.swap_rate_rule_variables(y.slice(ndarray::s![n..]).to_vec());
+    }
+
+    /// Set the first parameters and compartments with rate rules to
+    /// `values`, returning their previous values
+    pub(crate) fn swap_rate_rule_variables(&mut self, values: Vec<f64>) -> Vec<f64> {
+        values.into_iter()
+            .enumerate()
+            .map(|(k, value)| {
+                let variable = self.rules.other_rates[k].0.clone();
+                let previous = self.symbol_value(&variable, &[]).unwrap_or(f64::NAN);
+                self.set_value(&variable, value);
+                previous
+            })
+            .collect()
+    }
+
+    /// Replace the rates of change from the reactions, `dydt`, by those the
+    /// rules give
+    pub(crate) fn apply_rate_rules(&self, dydt: &mut Array1<f64>) {
+        let n = self.state.len();
+        for &i in &self.rules.assigned_species {
+            dydt[i] = 0.0;
+        }
+        let others = dydt.len() - n;
+        for (k, (_, expr)) in self.rules.other_rates.iter().enumerate().take(others) {
+            dydt[n + k] = self.rule_value(expr);
+        }
+        // Amounts from concentrations c in sizes V: dn/dt = V dc/dt + c dV/dt
+        for (i, expr) in &self.rules.species_rates {
+            let species = &self.model.species[*i];
+            dydt[*i] = if species.has_only_substance_units {
+                self.rule_value(expr)
+            } else {
+                let growth = self.rules.other_rates.iter()
+                    .take(others)
+                    .position(|(v, _)| *v == species.compartment)
+                    .map_or(0.0, |k| dydt[n + k]);
+                self.volume(*i) * self.rule_value(expr) + self.concentration(*i) * growth
+            };
+        }
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> SbmlModel {
+        // A -> 0 at k, with k growing linearly and total = 2 * half, half = A / 2
+        let mut model = SbmlModel::new("rules");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 1.0));
+        model.add_parameter(Parameter::new("k", 0.0));
+        model.add_parameter(Parameter::new("total", 0.0));
+        model.add_parameter(Parameter::new("half", 0.0));
+        let mut decay = Reaction::simple("decay", "A", "A", "k");
+        decay.products.clear();
+        model.add_reaction(decay);
+        model.assignment_rules.push(AssignmentRule { variable: "total".into(), expression: "2 * half".into() });
+        model.assignment_rules.push(AssignmentRule { variable: "half".into(), expression: "A / 2".into() });
+        model.rate_rules.push(RateRule { variable: "k".into(), expression: "1".into() });
+        model
+    }
+
+    #[test]
+    fn test_deterministic_rules() {
+        // k = t, so A = exp(-t^2 / 2)
+        let mut sim = CopasiSimulation::new(model());
+        assert_eq!(sim.model.get_parameter("total").unwrap().value, 1.0);
+        let result = sim.run(2.0, 10);
+        for (t, a) in result.time.iter().zip(&result.concentrations["A"]) {
+            assert!((a - (-t * t / 2.0).exp()).abs() < 1e-4, "{} {}", t, a);
+        }
+        assert!((sim.model.get_parameter("k").unwrap().value - 2.0).abs() < 1e-9);
+        let a = result.concentrations["A"][10];
+        assert_eq!(sim.model.get_parameter("total").unwrap().value, a);
+    }
+
+    #[test]
+    fn test_species_rules() {
+        // B follows a rate rule and C an assignment rule, both over the
+        // reactions they take part in as boundary species
+        let mut model = model();
+        let mut b = Species::new("B", "c", 0.0);
+        b.boundary_condition = true;
+        model.add_species(b);
+        let mut c = Species::new("C", "c", 0.0);
+        c.boundary_condition = true;
+        model.add_species(c);
+        model.rate_rules.push(RateRule { variable: "B".into(), expression: "3".into() });
+        model.assignment_rules.push(AssignmentRule { variable: "C".into(), expression: "B + time".into() });
+        model.reactions[0].products = vec![SpeciesReference::new("C", 1.0)];
+
+        let mut sim = CopasiSimulation::new(model.clone());
+        let result = sim.run(1.0, 4);
+        assert!((result.concentrations["B"][4] - 3.0).abs() < 1e-9);
+        assert!((result.concentrations["C"][4] - 4.0).abs() < 1e-9);
+
+        // Stochastic runs keep assignment rules too
+        let mut sim = CopasiSimulation::new(model);
+        sim.set_method(SimulationMethod::Stochastic);
+        let result = sim.run(1.0, 4);
+        for (t, c) in result.time.iter().zip(&result.concentrations["C"]) {
+            assert_eq!(*c, *t);
+        }
+    }
+}
@@ -0,0 +1,827 @@
+//! SBML files
+//!
+//! [`SbmlModel::from_sbml_str`] reads SBML Level 2 and 3 documents, such
+//! as the curated models of BioModels: compartments, species, parameters,
+//! function definitions, initial assignments, assignment and rate rules,
+//! reactions and events. MathML becomes the infix text of [`crate::math`],
+//! so kinetic laws are [`KineticLaw::Custom`]. Units, annotations and
+//! other package extensions are skipped, except for the flux bounds and
+//! active objective of the flux balance constraints package (fbc version
+//! 2); algebraic rules, Level 1 documents and math that varies the
+//! stoichiometry are errors.
+//!
+//! [`SbmlModel::to_sbml_string`] writes the same parts as SBML Level 3
+//! Version 2, kinetic laws being written from [`Reaction::rate_law`],
+//! built-in ones times the size of the compartment of their reaction.
+//! Units are not written, as they are not read.
+
+use crate::math::Expr;
+use crate::*;
+use roxmltree::Node;
+use std::path::Path;
+
+type Element<'a, 'i> = Node<'a, 'i>;
+
+fn invalid(what: String) -> OldiesError {
+    OldiesError::ParseError(format!("SBML: {}", what))
+}
+
+pub(crate) fn child<'a, 'i>(node: Element<'a, 'i>, name: &str) -> Option<Element<'a, 'i>> {
+    node.children().find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// The `item` elements of the list `list` in `node`
+pub(crate) fn list<'a, 'i>(node: Element<'a, 'i>, list: &str, item: &'static str) -> Vec<Element<'a, 'i>> {
+    child(node, list)
+        .map(|l| l.children().filter(|n| n.is_element() && n.tag_name().name() == item).collect())
+        .unwrap_or_default()
+}
+
+fn id(node: Element) -> Result<String> {
+    node.attribute("id")
+        .map(String::from)
+        .ok_or_else(|| invalid(format!("<{}> without id", node.tag_name().name())))
+}
+
+fn name(node: Element) -> Option<String> {
+    node.attribute("name").map(String::from)
+}
+
+fn number(node: Element, attribute: &str) -> Result<Option<f64>> {
+    node.attribute(attribute)
+        .map(|text| {
+            text.trim().parse().map_err(|_| {
+                invalid(format!("{} of <{}> is not a number: {}", attribute, node.tag_name().name(), text))
+            })
+        })
+        .transpose()
+}
+
+/// Attribute `name` of the flux balance constraints package
+fn fbc(node: Element, name: &str) -> Option<String> {
+    node.attributes()
+        .find(|a| a.name() == name && a.namespace() == Some(FBC))
+        .map(|a| a.value().to_string())
+}
+
+fn flag(node: Element, attribute: &str, default: bool) -> bool {
+    match node.attribute(attribute) {
+        Some(text) => text == "true" || text == "1",
+        None => default,
+    }
+}
+
+/// The `<math>` of `node` as infix text
+fn math(node: Element) -> Result<Option<Expr>> {
+    child(node, "math").map(Expr::from_mathml).transpose()
+}
+
+fn required_math(node: Element) -> Result<String> {
+    math(node)?
+        .map(|expr| expr.to_string())
+        .ok_or_else(|| invalid(format!("<{}> without math", node.tag_name().name())))
+}
+
+fn constant(node: Element, what: &str) -> Result<Option<f64>> {
+    match math(node)? {
+        None => Ok(None),
+        Some(Expr::Number(x)) => Ok(Some(x)),
+        Some(expr) => Err(invalid(format!("{} {} is not a constant", what, expr))),
+    }
+}
+
+fn species_reference(node: Element) -> Result<SpeciesReference> {
+    let species = node.attribute("species")
+        .ok_or_else(|| invalid("species reference without species".into()))?;
+    let stoichiometry = match child(node, "stoichiometryMath") {
+        Some(math) => constant(math, "stoichiometry")?.unwrap_or(1.0),
+        None => number(node, "stoichiometry")?.unwrap_or(1.0),
+    };
+    Ok(SpeciesReference {
+        species: species.to_string(),
+        stoichiometry,
+        constant: flag(node, "constant", true),
+    })
+}
+
+impl SbmlModel {
+    /// Read an SBML Level 2 or 3 document
+    pub fn from_sbml_str(xml: &str) -> Result<SbmlModel> {
+        let document = roxmltree::Document::parse(xml).map_err(|e| invalid(e.to_string()))?;
+        let sbml = document.root_element();
+        if sbml.tag_name().name() != "sbml" {
+            return Err(invalid(format!("<{}> is not an SBML document", sbml.tag_name().name())));
+        }
+        let level = number(sbml, "level")?.unwrap_or(3.0) as u8;
+        let version = number(sbml, "version")?.unwrap_or(1.0) as u8;
+        if level < 2 {
+            return Err(invalid(format!("Level {} is not supported", level)));
+        }
+        let node = child(sbml, "model").ok_or_else(|| invalid("no <model>".into()))?;
+
+        let mut model = SbmlModel::new(node.attribute("id").unwrap_or("model"));
+        model.name = name(node);
+        model.sbml_version = SbmlVersion { level, version };
+
+        for f in list(node, "listOfFunctionDefinitions", "functionDefinition") {
+            let lambda = child(f, "math")
+                .and_then(|m| m.children().find(|n| n.is_element()))
+                .filter(|l| l.tag_name().name() == "lambda")
+                .ok_or_else(|| invalid(format!("function {} without <lambda>", f.attribute("id").unwrap_or(""))))?;
+            let mut arguments = vec![];
+            let mut body = None;
+            for part in lambda.children().filter(|n| n.is_element()) {
+                match part.tag_name().name() {
+                    "bvar" => {
+                        let ci = child(part, "ci").ok_or_else(|| invalid("<bvar> without <ci>".into()))?;
+                        arguments.push(ci.text().unwrap_or("").trim().to_string());
+                    }
+                    _ => body = Some(Expr::from_mathml(part)?),
+                }
+            }
+            model.function_definitions.push(FunctionDefinition {
+                id: id(f)?,
+                name: name(f),
+                arguments,
+                body: body.ok_or_else(|| invalid("<lambda> without a body".into()))?.to_string(),
+            });
+        }
+
+        for c in list(node, "listOfCompartments", "compartment") {
+            model.add_compartment(Compartment {
+                id: id(c)?,
+                name: name(c),
+                spatial_dimensions: number(c, "spatialDimensions")?.unwrap_or(3.0) as u8,
+                size: match number(c, "size")? {
+                    Some(size) => size,
+                    None => number(c, "volume")?.unwrap_or(1.0),
+                },
+                units: c.attribute("units").map(String::from),
+                constant: flag(c, "constant", true),
+            });
+        }
+
+        for s in list(node, "listOfSpecies", "species") {
+            model.add_species(Species {
+                id: id(s)?,
+                name: name(s),
+                compartment: s.attribute("compartment").unwrap_or("").to_string(),
+                initial_concentration: number(s, "initialConcentration")?,
+                initial_amount: number(s, "initialAmount")?,
+                substance_units: s.attribute("substanceUnits").map(String::from),
+                has_only_substance_units: flag(s, "hasOnlySubstanceUnits", false),
+                boundary_condition: flag(s, "boundaryCondition", false),
+                constant: flag(s, "constant", false),
+            });
+        }
+
+        for p in list(node, "listOfParameters", "parameter") {
+            model.add_parameter(parameter(p)?);
+        }
+
+        for a in list(node, "listOfInitialAssignments", "initialAssignment") {
+            let symbol = a.attribute("symbol").ok_or_else(|| invalid("initial assignment without symbol".into()))?;
+            model.initial_assignments.push(InitialAssignment {
+                symbol: symbol.to_string(),
+                expression: required_math(a)?,
+            });
+        }
+
+        if let Some(rules) = child(node, "listOfRules") {
+            for rule in rules.children().filter(|n| n.is_element()) {
+                let variable = rule.attribute("variable").unwrap_or("").to_string();
+                let expression = required_math(rule)?;
+                match rule.tag_name().name() {
+                    "assignmentRule" => model.assignment_rules.push(AssignmentRule { variable, expression }),
+                    "rateRule" => model.rate_rules.push(RateRule { variable, expression }),
+                    other => return Err(invalid(format!("<{}> is not supported", other))),
+                }
+            }
+        }
+
+        for r in list(node, "listOfReactions", "reaction") {
+            let law = child(r, "kineticLaw");
+            let local_parameters = match law {
+                Some(law) => {
+                    let mut local = list(law, "listOfLocalParameters", "localParameter");
+                    local.extend(list(law, "listOfParameters", "parameter"));
+                    local.into_iter().map(parameter).collect::<Result<_>>()?
+                }
+                None => vec![],
+            };
+            model.add_reaction(Reaction {
+                id: id(r)?,
+                name: name(r),
+                reversible: flag(r, "reversible", true),
+                reactants: list(r, "listOfReactants", "speciesReference").into_iter()
+                    .map(species_reference)
+                    .collect::<Result<_>>()?,
+                products: list(r, "listOfProducts", "speciesReference").into_iter()
+                    .map(species_reference)
+                    .collect::<Result<_>>()?,
+                modifiers: list(r, "listOfModifiers", "modifierSpeciesReference").into_iter()
+                    .filter_map(|m| m.attribute("species").map(String::from))
+                    .collect(),
+                kinetic_law: KineticLaw::Custom(match law {
+                    Some(law) => required_math(law)?,
+                    None => "0".into(),
+                }),
+                local_parameters,
+            });
+            let (lower, upper) = (fbc(r, "lowerFluxBound"), fbc(r, "upperFluxBound"));
+            if lower.is_some() || upper.is_some() {
+                model.flux_bounds.push(FluxBound { reaction: id(r)?, lower, upper });
+            }
+        }
+
+        if let Some(objectives) = child(node, "listOfObjectives") {
+            let active = fbc(objectives, "activeObjective");
+            let objective = list(node, "listOfObjectives", "objective").into_iter()
+                .find(|o| active.is_none() || fbc(*o, "id") == active)
+                .ok_or_else(|| invalid("no active objective".into()))?;
+            model.flux_objective = Some(FluxObjective {
+                id: fbc(objective, "id").unwrap_or_default(),
+                maximize: fbc(objective, "type").as_deref() != Some("minimize"),
+                coefficients: list(objective, "listOfFluxObjectives", "fluxObjective").into_iter()
+                    .map(|f| {
+                        let coefficient = fbc(f, "coefficient").unwrap_or_default();
+                        Ok((
+                            fbc(f, "reaction").unwrap_or_default(),
+                            coefficient.trim().parse().map_err(|_| {
+                                invalid(format!("flux objective coefficient is not a number: {}", coefficient))
+                            })?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            });
+        }
+
+        for e in list(node, "listOfEvents", "event") {
+            let trigger = child(e, "trigger").ok_or_else(|| invalid("event without trigger".into()))?;
+            model.events.push(Event {
+                id: e.attribute("id").unwrap_or("").to_string(),
+                trigger: required_math(trigger)?,
+                priority: child(e, "priority").map(required_math).transpose()?,
+                delay: child(e, "delay").map(required_math).transpose()?,
+                assignments: list(e, "listOfEventAssignments", "eventAssignment").into_iter()
+                    .map(|a| Ok(EventAssignment {
+                        variable: a.attribute("variable").unwrap_or("").to_string(),
+                        expression: required_math(a)?,
+                    }))
+                    .collect::<Result<_>>()?,
+            });
+        }
+
+        Ok(model)
+    }
+
+    /// Read an SBML Level 2 or 3 file
+    pub fn read_sbml(path: impl AsRef<Path>) -> Result<SbmlModel> {
+        SbmlModel::from_sbml_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// Write the model as an SBML Level 3 Version 2 document. Expressions
+    /// that do not parse are errors.
+    pub fn to_sbml_string(&self) -> Result<String> {
+        let mut w = Writer::default();
+        w.line("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        let uses_fbc = !self.flux_bounds.is_empty() || self.flux_objective.is_some();
+        let (package, strict) = match uses_fbc {
+            true => (format!(" xmlns:fbc=\"{}\" fbc:required=\"false\"", FBC), " fbc:strict=\"false\""),
+            false => (String::new(), ""),
+        };
+        w.open(&format!(
+            "<sbml xmlns=\"http://www.sbml.org/sbml/level3/version2/core\"{} level=\"3\" version=\"2\">",
+            package
+        ));
+        w.open(&format!("<model id=\"{}\"{}{}>", escape(&self.id), name_attribute(&self.name), strict));
+
+        w.list("listOfFunctionDefinitions", &self.function_definitions, |w, f| {
+            let bvars: String = f.arguments.iter().map(|a| format!("<bvar><ci> {} </ci></bvar>", a)).collect();
+            let body = Expr::parse(&f.body)?.to_mathml();
+            w.open(&format!("<functionDefinition id=\"{}\"{}>", escape(&f.id), name_attribute(&f.name)));
+            w.line(&format!("<math xmlns=\"{}\"><lambda>{}{}</lambda></math>", MATHML, bvars, body));
+            w.close("</functionDefinition>");
+            Ok(())
+        })?;
+
+        w.list("listOfCompartments", &self.compartments, |w, c| {
+            w.line(&format!(
+                "<compartment id=\"{}\"{} spatialDimensions=\"{}\" size=\"{}\" constant=\"{}\"/>",
+                escape(&c.id), name_attribute(&c.name), c.spatial_dimensions, c.size, c.constant
+            ));
+            Ok(())
+        })?;
+
+        w.list("listOfSpecies", &self.species, |w, s| {
+            let initial = match (s.initial_concentration, s.initial_amount) {
+                (Some(c), _) => format!(" initialConcentration=\"{}\"", c),
+                (None, Some(a)) => format!(" initialAmount=\"{}\"", a),
+                (None, None) => String::new(),
+            };
+            w.line(&format!(
+                "<species id=\"{}\"{} compartment=\"{}\"{} hasOnlySubstanceUnits=\"{}\" \
+                 boundaryCondition=\"{}\" constant=\"{}\"/>",
+                escape(&s.id), name_attribute(&s.name), escape(&s.compartment), initial,
+                s.has_only_substance_units, s.boundary_condition, s.constant
+            ));
+            Ok(())
+        })?;
+
+        w.list("listOfParameters", &self.parameters, |w, p| {
+            w.line(&format!(
+                "<parameter id=\"{}\"{} value=\"{}\" constant=\"{}\"/>",
+                escape(&p.id), name_attribute(&p.name), p.value, p.constant
+            ));
+            Ok(())
+        })?;
+
+        w.list("listOfInitialAssignments", &self.initial_assignments, |w, a| {
+            w.open(&format!("<initialAssignment symbol=\"{}\">", escape(&a.symbol)));
+            w.math(&a.expression)?;
+            w.close("</initialAssignment>");
+            Ok(())
+        })?;
+
+        let rules: Vec<(&str, &str, &str)> = self.assignment_rules.iter()
+            .map(|r| ("assignmentRule", r.variable.as_str(), r.expression.as_str()))
+            .chain(self.rate_rules.iter().map(|r| ("rateRule", r.variable.as_str(), r.expression.as_str())))
+            .collect();
+        w.list("listOfRules", &rules, |w, (tag, variable, expression)| {
+            w.open(&format!("<{} variable=\"{}\">", tag, escape(variable)));
+            w.math(expression)?;
+            w.close(&format!("</{}>", tag));
+            Ok(())
+        })?;
+
+        w.list("listOfReactions", &self.reactions, |w, r| {
+            let bounds = self.flux_bounds.iter()
+                .filter(|b| b.reaction == r.id)
+                .flat_map(|b| [("lowerFluxBound", &b.lower), ("upperFluxBound", &b.upper)])
+                .filter_map(|(name, bound)| Some(format!(" fbc:{}=\"{}\"", name, escape(bound.as_ref()?))))
+                .collect::<String>();
+            w.open(&format!(
+                "<reaction id=\"{}\"{} reversible=\"{}\"{}>",
+                escape(&r.id), name_attribute(&r.name), r.reversible, bounds
+            ));
+            for (list, refs) in [("listOfReactants", &r.reactants), ("listOfProducts", &r.products)] {
+                w.list(list, refs, |w, sr| {
+                    w.line(&format!(
+                        "<speciesReference species=\"{}\" stoichiometry=\"{}\" constant=\"{}\"/>",
+                        escape(&sr.species), sr.stoichiometry, sr.constant
+                    ));
+                    Ok(())
+                })?;
+            }
+            w.list("listOfModifiers", &r.modifiers, |w, m| {
+                w.line(&format!("<modifierSpeciesReference species=\"{}\"/>", escape(m)));
+                Ok(())
+            })?;
+            w.open("<kineticLaw>");
+            // Built-in laws in amounts per time
+            w.math(&match (&r.kinetic_law, self.reaction_compartment(r)) {
+                (KineticLaw::Custom(law), _) => law.clone(),
+                (_, Some(c)) => format!("{} * ({})", c.id, r.rate_law()),
+                (_, None) => r.rate_law(),
+            })?;
+            w.list("listOfLocalParameters", &r.local_parameters, |w, p| {
+                w.line(&format!("<localParameter id=\"{}\" value=\"{}\"/>", escape(&p.id), p.value));
+                Ok(())
+            })?;
+            w.close("</kineticLaw>");
+            w.close("</reaction>");
+            Ok(())
+        })?;
+
+        w.list("listOfEvents", &self.events, |w, e| {
+            let id = match e.id.is_empty() {
+                true => String::new(),
+                false => format!(" id=\"{}\"", escape(&e.id)),
+            };
+            w.open(&format!("<event{} useValuesFromTriggerTime=\"true\">", id));
+            w.open("<trigger initialValue=\"true\" persistent=\"true\">");
+            w.math(&e.trigger)?;
+            w.close("</trigger>");
+            if let Some(priority) = &e.priority {
+                w.open("<priority>");
+                w.math(priority)?;
+                w.close("</priority>");
+            }
+            if let Some(delay) = &e.delay {
+                w.open("<delay>");
+                w.math(delay)?;
+                w.close("</delay>");
+            }
+            w.list("listOfEventAssignments", &e.assignments, |w, a| {
+                w.open(&format!("<eventAssignment variable=\"{}\">", escape(&a.variable)));
+                w.math(&a.expression)?;
+                w.close("</eventAssignment>");
+                Ok(())
+            })?;
+            w.close("</event>");
+            Ok(())
+        })?;
+
+        if let Some(objective) = &self.flux_objective {
+            w.open(&format!("<fbc:listOfObjectives fbc:activeObjective=\"{}\">", escape(&objective.id)));
+            w.open(&format!(
+                "<fbc:objective fbc:id=\"{}\" fbc:type=\"{}\">",
+                escape(&objective.id),
+                if objective.maximize { "maximize" } else { "minimize" }
+            ));
+            w.list("fbc:listOfFluxObjectives", &objective.coefficients, |w, (reaction, coefficient)| {
+                w.line(&format!(
+                    "<fbc:fluxObjective fbc:reaction=\"{}\" fbc:coefficient=\"{}\"/>",
+                    escape(reaction), coefficient
+                ));
+                Ok(())
+            })?;
+            w.close("</fbc:objective>");
+            w.close("</fbc:listOfObjectives>");
+        }
+
+        w.close("</model>");
+        w.close("</sbml>");
+        Ok(w.out)
+    }
+}
+
+const MATHML: &str = "http://www.w3.org/1998/Math/MathML";
+const FBC: &str = "http://www.sbml.org/sbml/level3/version1/fbc/version2";
+
+/// Indenting XML writer
+#[derive(Default)]
+struct Writer {
+    out: String,
+    depth: usize,
+}
+
+impl Writer {
+    fn line(&mut self, text: &str) {
+        self.out.push_str(&"  ".repeat(self.depth));
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, tag: &str) {
+        self.line(tag);
+        self.depth += 1;
+    }
+
+    fn close(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.line(tag);
+    }
+
+    /// The infix `expression` as `<math>`
+    fn math(&mut self, expression: &str) -> Result<()> {
+        let mathml = Expr::parse(expression)?.to_mathml();
+        self.line(&format!("<math xmlns=\"{}\">{}</math>", MATHML, mathml));
+        Ok(())
+    }
+
+    /// The list `list` of `items`, unless there are none
+    fn list<T>(&mut self, list: &str, items: &[T], mut item: impl FnMut(&mut Writer, &T) -> Result<()>) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        self.open(&format!("<{}>", list));
+        for i in items {
+            item(self, i)?;
+        }
+        self.close(&format!("</{}>", list));
+        Ok(())
+    }
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn name_attribute(name: &Option<String>) -> String {
+    name.as_ref().map_or(String::new(), |n| format!(" name=\"{}\"", escape(n)))
+}
+
+fn parameter(p: Element) -> Result<Parameter> {
+    Ok(Parameter {
+        id: id(p)?,
+        name: name(p),
+        value: number(p, "value")?.unwrap_or(0.0),
+        units: p.attribute("units").map(String::from),
+        constant: flag(p, "constant", true),
+    })
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL3: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<sbml xmlns="http://www.sbml.org/sbml/level3/version2/core" level="3" version="2">
+  <model id="decay" name="Enzymatic decay">
+    <listOfFunctionDefinitions>
+      <functionDefinition id="mm">
+        <math xmlns="http://www.w3.org/1998/Math/MathML">
+          <lambda>
+            <bvar><ci> V </ci></bvar>
+            <bvar><ci> K </ci></bvar>
+            <bvar><ci> S </ci></bvar>
+            <apply><divide/>
+              <apply><times/><ci> V </ci><ci> S </ci></apply>
+              <apply><plus/><ci> K </ci><ci> S </ci></apply>
+            </apply>
+          </lambda>
+        </math>
+      </functionDefinition>
+    </listOfFunctionDefinitions>
+    <listOfCompartments>
+      <compartment id="cell" spatialDimensions="3" size="1" constant="true"/>
+    </listOfCompartments>
+    <listOfSpecies>
+      <species id="A" compartment="cell" initialConcentration="10" hasOnlySubstanceUnits="false"
+               boundaryCondition="false" constant="false"/>
+      <species id="B" compartment="cell" initialAmount="0" hasOnlySubstanceUnits="false"
+               boundaryCondition="false" constant="false"/>
+      <species id="E" compartment="cell" initialConcentration="1" hasOnlySubstanceUnits="false"
+               boundaryCondition="true" constant="true"/>
+    </listOfSpecies>
+    <listOfParameters>
+      <parameter id="k" value="0.5" constant="true"/>
+      <parameter id="Km" constant="true"/>
+      <parameter id="total" constant="false"/>
+    </listOfParameters>
+    <listOfInitialAssignments>
+      <initialAssignment symbol="Km">
+        <math xmlns="http://www.w3.org/1998/Math/MathML">
+          <apply><times/><cn type="e-notation"> 1 <sep/> 3 </cn><ci> k </ci></apply>
+        </math>
+      </initialAssignment>
+    </listOfInitialAssignments>
+    <listOfRules>
+      <assignmentRule variable="total">
+        <math xmlns="http://www.w3.org/1998/Math/MathML">
+          <apply><plus/><ci> A </ci><ci> B </ci></apply>
+        </math>
+      </assignmentRule>
+    </listOfRules>
+    <listOfReactions>
+      <reaction id="conversion" reversible="false">
+        <listOfReactants>
+          <speciesReference species="A" stoichiometry="1" constant="true"/>
+        </listOfReactants>
+        <listOfProducts>
+          <speciesReference species="B" stoichiometry="1" constant="true"/>
+        </listOfProducts>
+        <listOfModifiers>
+          <modifierSpeciesReference species="E"/>
+        </listOfModifiers>
+        <kineticLaw>
+          <math xmlns="http://www.w3.org/1998/Math/MathML">
+            <apply><times/>
+              <ci> cell </ci>
+              <apply><ci> mm </ci><apply><times/><ci> kcat </ci><ci> E </ci></apply><ci> Km </ci><ci> A </ci></apply>
+            </apply>
+          </math>
+          <listOfLocalParameters>
+            <localParameter id="kcat" value="2"/>
+          </listOfLocalParameters>
+        </kineticLaw>
+      </reaction>
+    </listOfReactions>
+    <listOfEvents>
+      <event id="refill" useValuesFromTriggerTime="true">
+        <trigger initialValue="false" persistent="true">
+          <math xmlns="http://www.w3.org/1998/Math/MathML">
+            <apply><lt/><ci> A </ci><cn> 1 </cn></apply>
+          </math>
+        </trigger>
+        <priority>
+          <math xmlns="http://www.w3.org/1998/Math/MathML"><ci> k </ci></math>
+        </priority>
+        <delay>
+          <math xmlns="http://www.w3.org/1998/Math/MathML">
+            <apply><divide/><ci> k </ci><cn> 2 </cn></apply>
+          </math>
+        </delay>
+        <listOfEventAssignments>
+          <eventAssignment variable="A">
+            <math xmlns="http://www.w3.org/1998/Math/MathML"><cn type="integer"> 10 </cn></math>
+          </eventAssignment>
+        </listOfEventAssignments>
+      </event>
+    </listOfEvents>
+  </model>
+</sbml>"#;
+
+    #[test]
+    fn test_read_level3() {
+        let model = SbmlModel::from_sbml_str(LEVEL3).unwrap();
+        assert_eq!(model.id, "decay");
+        assert_eq!((model.sbml_version.level, model.sbml_version.version), (3, 2));
+        assert_eq!(model.species.len(), 3);
+        assert_eq!(model.get_species("B").unwrap().initial_amount, Some(0.0));
+        assert!(model.get_species("E").unwrap().boundary_condition);
+        assert_eq!(model.function_definitions[0].arguments, vec!["V", "K", "S"]);
+        assert_eq!(model.function_definitions[0].body, "V * S / (K + S)");
+        assert_eq!(model.initial_assignments[0].expression, "1000 * k");
+        assert_eq!(model.assignment_rules[0].expression, "A + B");
+
+        let reaction = &model.reactions[0];
+        assert!(!reaction.reversible);
+        assert_eq!(reaction.modifiers, vec!["E"]);
+        assert_eq!(reaction.local_parameters[0].value, 2.0);
+        match &reaction.kinetic_law {
+            KineticLaw::Custom(law) => assert_eq!(law, "cell * mm(kcat * E, Km, A)"),
+            other => panic!("{:?}", other),
+        }
+
+        let event = &model.events[0];
+        assert_eq!(event.trigger, "A < 1");
+        assert_eq!(event.priority.as_deref(), Some("k"));
+        assert_eq!(event.delay.as_deref(), Some("k / 2"));
+        assert_eq!(event.assignments[0].expression, "10");
+    }
+
+    #[test]
+    fn test_simulate_level3() {
+        let model = SbmlModel::from_sbml_str(LEVEL3).unwrap();
+        let mut sim = CopasiSimulation::new(model);
+        // Km = 500 from its initial assignment: dA/dt = -2 A / (500 + A),
+        // so 500 ln(A / 10) + A - 10 = -2 t
+        let result = sim.run(100.0, 1000);
+        let a = *result.concentrations["A"].last().unwrap();
+        assert!((500.0 * (a / 10.0).ln() + a - 10.0 + 200.0).abs() < 0.2, "{}", a);
+        let b = *result.concentrations["B"].last().unwrap();
+        assert!((a + b - 10.0).abs() < 1e-9);
+        assert_eq!(*result.concentrations["E"].last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_read_level2() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<sbml xmlns="http://www.sbml.org/sbml/level2/version4" level="2" version="4">
+  <model id="dimerization">
+    <listOfCompartments>
+      <compartment id="c" size="2"/>
+    </listOfCompartments>
+    <listOfSpecies>
+      <species id="M" compartment="c" initialAmount="4"/>
+      <species id="D" compartment="c" initialConcentration="0"/>
+    </listOfSpecies>
+    <listOfRules>
+      <rateRule variable="c">
+        <math xmlns="http://www.w3.org/1998/Math/MathML">
+          <apply><minus/><apply><power/><ci> M </ci><cn> 2 </cn></apply></apply>
+        </math>
+      </rateRule>
+    </listOfRules>
+    <listOfReactions>
+      <reaction id="dimerize">
+        <listOfReactants>
+          <speciesReference species="M" stoichiometry="2"/>
+        </listOfReactants>
+        <listOfProducts>
+          <speciesReference species="D"/>
+        </listOfProducts>
+        <kineticLaw>
+          <math xmlns="http://www.w3.org/1998/Math/MathML">
+            <apply><times/><ci> k </ci><apply><power/><ci> M </ci><cn> 2 </cn></apply>
+              <piecewise>
+                <piece><cn> 1 </cn><apply><lt/><csymbol encoding="text"
+                  definitionURL="http://www.sbml.org/sbml/symbols/time"> t </csymbol><cn> 5 </cn></apply></piece>
+                <otherwise><cn> 0 </cn></otherwise>
+              </piecewise>
+            </apply>
+          </math>
+          <listOfParameters>
+            <parameter id="k" value="0.1"/>
+          </listOfParameters>
+        </kineticLaw>
+      </reaction>
+    </listOfReactions>
+  </model>
+</sbml>"#;
+        let model = SbmlModel::from_sbml_str(xml).unwrap();
+        assert_eq!(model.compartments[0].size, 2.0);
+        assert!(model.compartments[0].constant);
+        assert_eq!(model.rate_rules[0].expression, "-M^2");
+        let reaction = &model.reactions[0];
+        assert!(reaction.reversible);
+        assert_eq!(reaction.reactants[0].stoichiometry, 2.0);
+        assert_eq!(reaction.products[0].stoichiometry, 1.0);
+        match &reaction.kinetic_law {
+            KineticLaw::Custom(law) => assert_eq!(law, "k * M^2 * piecewise(1, time < 5, 0)"),
+            other => panic!("{:?}", other),
+        }
+
+        // The species in amounts start at amount / size
+        let sim = CopasiSimulation::new(model);
+        assert_eq!(sim.get_concentrations()["M"], 2.0);
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let model = SbmlModel::from_sbml_str(LEVEL3).unwrap();
+        let xml = model.to_sbml_string().unwrap();
+        assert!(xml.contains("<sbml xmlns=\"http://www.sbml.org/sbml/level3/version2/core\" level=\"3\" version=\"2\">"));
+        let again = SbmlModel::from_sbml_str(&xml).unwrap();
+        assert_eq!(again.to_sbml_string().unwrap(), xml);
+        assert_eq!(again.function_definitions[0].body, model.function_definitions[0].body);
+        assert_eq!(again.initial_assignments[0].expression, "1000 * k");
+        assert_eq!(again.reactions[0].rate_law(), "cell * mm(kcat * E, Km, A)");
+        assert_eq!(again.events[0].delay.as_deref(), Some("k / 2"));
+        assert_eq!(again.events[0].priority.as_deref(), Some("k"));
+
+        // Built-in kinetic laws are written as their expressions, in
+        // amounts per time, and give the same time courses read back
+        let mut model = models::michaelis_menten();
+        model.compartments[0].size = 2.0;
+        model.add_species(Species::new("Q", "cell", 0.0));
+        model.add_parameter(Parameter::new("Vmax", 0.3));
+        model.add_parameter(Parameter::new("Km", 2.5e-7));
+        model.add_reaction(Reaction::enzymatic("convert", "P", "Q", "E", "Vmax", "Km"));
+        model.reactions[0].reactants[0].stoichiometry = 2.0;
+        model.rate_rules.push(RateRule { variable: "Km".into(), expression: "-Km / 1e3".into() });
+        let again = SbmlModel::from_sbml_str(&model.to_sbml_string().unwrap()).unwrap();
+        assert_eq!(again.reactions[0].rate_law(), "cell * (k1 * S^2 * E)");
+        assert_eq!(again.reactions[3].rate_law(), "cell * (Vmax * P / (Km + P))");
+        assert_eq!(again.get_parameter("Km").unwrap().value, 2.5e-7);
+        assert_eq!(again.rate_rules[0].expression, "-Km / 1000");
+        let ours = CopasiSimulation::new(model).run(10.0, 100);
+        let theirs = CopasiSimulation::new(again).run(10.0, 100);
+        for species in ["S", "E", "ES", "P", "Q"] {
+            assert_eq!(ours.concentrations[species], theirs.concentrations[species], "{}", species);
+        }
+    }
+
+    #[test]
+    fn test_flux_balance_constraints() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<sbml xmlns="http://www.sbml.org/sbml/level3/version1/core" level="3" version="1"
+      xmlns:fbc="http://www.sbml.org/sbml/level3/version1/fbc/version2" fbc:required="false">
+  <model id="uptake" fbc:strict="true">
+    <listOfCompartments><compartment id="c" size="1" constant="true"/></listOfCompartments>
+    <listOfSpecies>
+      <species id="A" compartment="c" initialConcentration="0" hasOnlySubstanceUnits="false"
+               boundaryCondition="false" constant="false"/>
+    </listOfSpecies>
+    <listOfParameters>
+      <parameter id="zero" value="0" constant="true"/>
+      <parameter id="most" value="7" constant="true"/>
+    </listOfParameters>
+    <listOfReactions>
+      <reaction id="in" reversible="false" fbc:lowerFluxBound="zero" fbc:upperFluxBound="most">
+        <listOfProducts><speciesReference species="A" stoichiometry="1" constant="true"/></listOfProducts>
+      </reaction>
+      <reaction id="out" reversible="false">
+        <listOfReactants><speciesReference species="A" stoichiometry="1" constant="true"/></listOfReactants>
+      </reaction>
+    </listOfReactions>
+    <fbc:listOfObjectives fbc:activeObjective="most_out">
+      <fbc:objective fbc:id="least_out" fbc:type="minimize">
+        <fbc:listOfFluxObjectives><fbc:fluxObjective fbc:reaction="out" fbc:coefficient="1"/></fbc:listOfFluxObjectives>
+      </fbc:objective>
+      <fbc:objective fbc:id="most_out" fbc:type="maximize">
+        <fbc:listOfFluxObjectives><fbc:fluxObjective fbc:reaction="out" fbc:coefficient="2"/></fbc:listOfFluxObjectives>
+      </fbc:objective>
+    </fbc:listOfObjectives>
+  </model>
+</sbml>"#;
+        let model = SbmlModel::from_sbml_str(xml).unwrap();
+        assert_eq!(model.flux_bounds.len(), 1);
+        assert_eq!(model.flux_bounds[0].upper.as_deref(), Some("most"));
+        let objective = model.flux_objective.as_ref().unwrap();
+        assert_eq!((objective.id.as_str(), objective.maximize), ("most_out", true));
+        assert_eq!(objective.coefficients, vec![("out".to_string(), 2.0)]);
+        let optimum = crate::fba::FluxBalance::new(model.clone()).optimize().unwrap();
+        assert!((optimum.objective - 14.0).abs() < 1e-9);
+
+        let written = model.to_sbml_string().unwrap();
+        let again = SbmlModel::from_sbml_str(&written).unwrap();
+        assert_eq!(again.to_sbml_string().unwrap(), written);
+        assert_eq!(again.flux_bounds[0].lower.as_deref(), Some("zero"));
+        assert_eq!(again.flux_objective.unwrap().coefficients, objective.coefficients);
+    }
+
+    #[test]
+    fn test_errors() {
+        for (xml, message) in [
+            ("<sbml level=\"3\" version=\"2\"><model", "SBML"),
+            ("<notsbml/>", "not an SBML document"),
+            ("<sbml level=\"1\" version=\"2\"><model/></sbml>", "Level 1"),
+            ("<sbml level=\"3\" version=\"2\"><model><listOfRules><algebraicRule><math \
+              xmlns=\"http://www.w3.org/1998/Math/MathML\"><ci>x</ci></math></algebraicRule>\
+              </listOfRules></model></sbml>", "algebraicRule"),
+            ("<sbml level=\"3\" version=\"2\"><model><listOfParameters>\
+              <parameter id=\"k\" value=\"fast\"/></listOfParameters></model></sbml>", "not a number"),
+        ] {
+            let error = SbmlModel::from_sbml_str(xml).unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+        assert!(SbmlModel::read_sbml("/nonexistent/model.xml").is_err());
+    }
+}
@@ -0,0 +1,726 @@
+//! SED-ML simulation experiments
+//!
+//! [`SedDocument::from_sedml_str`] reads the parts of a SED-ML Level 1
+//! document (Versions 1 to 4) that describe experiments on SBML models:
+//!
+//! - Models, from SBML files relative to the document or from models
+//!   declared before it, with `changeAttribute` changes to the values of
+//!   parameters, initial concentrations and amounts of species, and sizes
+//!   of compartments.
+//! - Uniform time courses, one steps and steady states, run by the method
+//!   its KiSAO algorithm names: Gillespie's direct method, the next
+//!   reaction method, tau-leaping, hybrid methods, or else, for the
+//!   deterministic ODE solvers, [`crate::ode`]. The relative and absolute
+//!   tolerances and the seed are taken from the algorithm parameters.
+//! - Tasks, data generators and their variables, with targets naming
+//!   species, parameters, compartments and reaction fluxes by id, or the
+//!   time symbol.
+//! - Reports and 2D plots.
+//!
+//! Other changes, repeated tasks and 3D plots are errors.
+//!
+//! [`SedDocument::execute`] runs every task and computes the data
+//! generators from their results, and [`SedDocument::write_outputs`]
+//! writes each report as CSV, a column per data set, and each plot as SVG.
+
+use crate::math::Expr;
+use crate::sbml::{child, escape, list};
+use crate::*;
+use roxmltree::Node;
+use std::path::{Path, PathBuf};
+
+fn invalid(what: String) -> OldiesError {
+    OldiesError::ParseError(format!("SED-ML: {}", what))
+}
+
+fn id(node: Node) -> Result<String> {
+    node.attribute("id")
+        .map(String::from)
+        .ok_or_else(|| invalid(format!("<{}> without id", node.tag_name().name())))
+}
+
+fn reference(node: Node, attribute: &str) -> Result<String> {
+    node.attribute(attribute)
+        .map(String::from)
+        .ok_or_else(|| invalid(format!("<{}> without {}", node.tag_name().name(), attribute)))
+}
+
+fn number(node: Node, attribute: &str) -> Result<f64> {
+    let text = reference(node, attribute)?;
+    text.trim().parse().map_err(|_| {
+        invalid(format!("{} of <{}> is not a number: {}", attribute, node.tag_name().name(), text))
+    })
+}
+
+/// Id of the SBML element an XPath target selects by `[@id='...']`, and
+/// the attribute it selects after, if any
+fn target_id(target: &str) -> Option<(String, Option<String>)> {
+    let start = target.rfind("@id=")? + 4;
+    let quote = target[start..].chars().next()?;
+    let rest = &target[start + 1..];
+    let end = rest.find(quote)?;
+    let attribute = rest[end..].split("/@").nth(1).map(|a| a.trim_end_matches(']').to_string());
+    Some((rest[..end].to_string(), attribute))
+}
+
+/// Model with changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SedModel {
+    pub id: String,
+    /// SBML file, relative to the document, or the id of an earlier model
+    pub source: String,
+    /// New values by XPath target of the attribute
+    pub changes: Vec<(String, f64)>,
+}
+
+/// Kind of simulation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SedSimulationKind {
+    /// Output at `number_of_steps` + 1 evenly spaced times from
+    /// `output_start_time` to `output_end_time`
+    UniformTimeCourse {
+        initial_time: f64,
+        output_start_time: f64,
+        output_end_time: f64,
+        number_of_steps: usize,
+    },
+    /// Output after one step
+    OneStep { step: f64 },
+    /// Output at the steady state
+    SteadyState,
+}
+
+/// Simulation settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SedSimulation {
+    pub id: String,
+    pub kind: SedSimulationKind,
+    /// KiSAO id of the algorithm, such as `KISAO_0000019`
+    pub algorithm: String,
+    /// Algorithm parameters by KiSAO id
+    pub parameters: Vec<(String, f64)>,
+}
+
+/// Simulation of a model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SedTask {
+    pub id: String,
+    pub model: String,
+    pub simulation: String,
+}
+
+/// Variable of a data generator: a target or symbol in the results of a
+/// task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SedVariable {
+    pub id: String,
+    pub task: String,
+    /// XPath of an SBML element
+    pub target: Option<String>,
+    /// Symbol, such as `urn:sedml:symbol:time`
+    pub symbol: Option<String>,
+}
+
+/// Data computed from task results
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataGenerator {
+    pub id: String,
+    pub name: Option<String>,
+    pub variables: Vec<SedVariable>,
+    pub parameters: Vec<(String, f64)>,
+    /// Infix expression over the variables and parameters
+    pub math: String,
+}
+
+/// Column of a report
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataSet {
+    pub id: String,
+    pub label: String,
+    /// Data generator
+    pub data: String,
+}
+
+/// Line of a plot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Curve {
+    pub id: String,
+    pub name: Option<String>,
+    /// Data generators of the x and y values
+    pub x: String,
+    pub y: String,
+    pub log_x: bool,
+    pub log_y: bool,
+}
+
+/// Output of an experiment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SedOutput {
+    Report { id: String, name: Option<String>, data_sets: Vec<DataSet> },
+    Plot2D { id: String, name: Option<String>, curves: Vec<Curve> },
+}
+
+impl SedOutput {
+    pub fn id(&self) -> &str {
+        match self {
+            SedOutput::Report { id, .. } | SedOutput::Plot2D { id, .. } => id,
+        }
+    }
+}
+
+/// SED-ML document
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SedDocument {
+    pub models: Vec<SedModel>,
+    pub simulations: Vec<SedSimulation>,
+    pub tasks: Vec<SedTask>,
+    pub data_generators: Vec<DataGenerator>,
+    pub outputs: Vec<SedOutput>,
+}
+
+/// KiSAO ids of the deterministic ODE solvers, run by [`crate::ode`]
+const ODE_ALGORITHMS: &[&str] = &[
+    "KISAO_0000019", // CVODE
+    "KISAO_0000030", // forward Euler
+    "KISAO_0000032", // Runge-Kutta 4
+    "KISAO_0000086", // Fehlberg
+    "KISAO_0000087", // Dormand-Prince
+    "KISAO_0000088", // LSODA
+    "KISAO_0000304", // Radau
+    "KISAO_0000560", // LSODA/LSODAR
+];
+/// KiSAO ids of the relative and absolute tolerances and the seed
+const RELATIVE_TOLERANCE: &str = "KISAO_0000209";
+const ABSOLUTE_TOLERANCE: &str = "KISAO_0000211";
+const SEED: &str = "KISAO_0000488";
+const TIME_SYMBOL: &str = "urn:sedml:symbol:time";
+
+impl SedDocument {
+    /// Read a SED-ML Level 1 document
+    pub fn from_sedml_str(xml: &str) -> Result<SedDocument> {
+        let document = roxmltree::Document::parse(xml).map_err(|e| invalid(e.to_string()))?;
+        let root = document.root_element();
+        if root.tag_name().name() != "sedML" {
+            return Err(invalid(format!("<{}> is not a SED-ML document", root.tag_name().name())));
+        }
+        let mut sed = SedDocument::default();
+
+        for m in list(root, "listOfModels", "model") {
+            let mut changes = Vec::new();
+            for change in child(m, "listOfChanges").into_iter().flat_map(|l| l.children().filter(|n| n.is_element())) {
+                match change.tag_name().name() {
+                    "changeAttribute" => changes.push((reference(change, "target")?, number(change, "newValue")?)),
+                    other => return Err(invalid(format!("<{}> is not supported", other))),
+                }
+            }
+            sed.models.push(SedModel { id: id(m)?, source: reference(m, "source")?, changes });
+        }
+
+        for s in child(root, "listOfSimulations").into_iter().flat_map(|l| l.children().filter(|n| n.is_element())) {
+            let kind = match s.tag_name().name() {
+                "uniformTimeCourse" => SedSimulationKind::UniformTimeCourse {
+                    initial_time: number(s, "initialTime")?,
+                    output_start_time: number(s, "outputStartTime")?,
+                    output_end_time: number(s, "outputEndTime")?,
+                    number_of_steps: match s.attribute("numberOfSteps") {
+                        Some(_) => number(s, "numberOfSteps")?,
+                        None => number(s, "numberOfPoints")?,
+                    } as usize,
+                },
+                "oneStep" => SedSimulationKind::OneStep { step: number(s, "step")? },
+                "steadyState" => SedSimulationKind::SteadyState,
+                other => return Err(invalid(format!("<{}> is not supported", other))),
+            };
+            let algorithm = child(s, "algorithm").ok_or_else(|| invalid(format!("simulation {} without algorithm", id(s).unwrap_or_default())))?;
+            sed.simulations.push(SedSimulation {
+                id: id(s)?,
+                kind,
+                algorithm: reference(algorithm, "kisaoID")?,
+                parameters: list(algorithm, "listOfAlgorithmParameters", "algorithmParameter").into_iter()
+                    .map(|p| Ok((reference(p, "kisaoID")?, number(p, "value")?)))
+                    .collect::<Result<_>>()?,
+            });
+        }
+
+        for t in child(root, "listOfTasks").into_iter().flat_map(|l| l.children().filter(|n| n.is_element())) {
+            match t.tag_name().name() {
+                "task" => sed.tasks.push(SedTask {
+                    id: id(t)?,
+                    model: reference(t, "modelReference")?,
+                    simulation: reference(t, "simulationReference")?,
+                }),
+                other => return Err(invalid(format!("<{}> is not supported", other))),
+            }
+        }
+
+        for d in list(root, "listOfDataGenerators", "dataGenerator") {
+            let math = child(d, "math").ok_or_else(|| invalid(format!("data generator {} without math", id(d).unwrap_or_default())))?;
+            sed.data_generators.push(DataGenerator {
+                id: id(d)?,
+                name: d.attribute("name").map(String::from),
+                variables: list(d, "listOfVariables", "variable").into_iter()
+                    .map(|v| Ok(SedVariable {
+                        id: id(v)?,
+                        task: reference(v, "taskReference")?,
+                        target: v.attribute("target").map(String::from),
+                        symbol: v.attribute("symbol").map(String::from),
+                    }))
+                    .collect::<Result<_>>()?,
+                parameters: list(d, "listOfParameters", "parameter").into_iter()
+                    .map(|p| Ok((id(p)?, number(p, "value")?)))
+                    .collect::<Result<_>>()?,
+                math: Expr::from_mathml(math)?.to_string(),
+            });
+        }
+
+        for o in child(root, "listOfOutputs").into_iter().flat_map(|l| l.children().filter(|n| n.is_element())) {
+            let name = o.attribute("name").map(String::from);
+            sed.outputs.push(match o.tag_name().name() {
+                "report" => SedOutput::Report {
+                    id: id(o)?,
+                    name,
+                    data_sets: list(o, "listOfDataSets", "dataSet").into_iter()
+                        .map(|d| Ok(DataSet {
+                            id: id(d)?,
+                            label: d.attribute("label").or(d.attribute("name")).map_or(id(d)?, String::from),
+                            data: reference(d, "dataReference")?,
+                        }))
+                        .collect::<Result<_>>()?,
+                },
+                "plot2D" => SedOutput::Plot2D {
+                    id: id(o)?,
+                    name,
+                    curves: list(o, "listOfCurves", "curve").into_iter()
+                        .map(|c| Ok(Curve {
+                            id: id(c)?,
+                            name: c.attribute("name").map(String::from),
+                            x: reference(c, "xDataReference")?,
+                            y: reference(c, "yDataReference")?,
+                            log_x: c.attribute("logX") == Some("true"),
+                            log_y: c.attribute("logY") == Some("true"),
+                        }))
+                        .collect::<Result<_>>()?,
+                },
+                other => return Err(invalid(format!("<{}> is not supported", other))),
+            });
+        }
+        Ok(sed)
+    }
+
+    /// Read a SED-ML document
+    pub fn read_sedml(path: impl AsRef<Path>) -> Result<SedDocument> {
+        SedDocument::from_sedml_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// The models with their changes, reading sources relative to
+    /// `directory`
+    fn load_models(&self, directory: &Path) -> Result<HashMap<String, SbmlModel>> {
+        let mut models: HashMap<String, SbmlModel> = HashMap::new();
+        for m in &self.models {
+            let mut model = match models.get(m.source.trim_start_matches('#')) {
+                Some(earlier) => earlier.clone(),
+                None if m.source.starts_with("urn:") || m.source.contains("://") => {
+                    return Err(OldiesError::ModelNotFound(format!("{} (only files are read)", m.source)));
+                }
+                None => SbmlModel::read_sbml(directory.join(&m.source))?,
+            };
+            for (target, value) in &m.changes {
+                let (id, attribute) = target_id(target)
+                    .ok_or_else(|| invalid(format!("change target not understood: {}", target)))?;
+                let value = *value;
+                match attribute.as_deref() {
+                    Some("value") if model.get_parameter(&id).is_some() => {
+                        model.parameters.iter_mut().find(|p| p.id == id).unwrap().value = value;
+                    }
+                    Some("initialConcentration") | Some("initialAmount") => {
+                        let species = model.species.iter_mut()
+                            .find(|s| s.id == id)
+                            .ok_or_else(|| invalid(format!("no species {}", id)))?;
+                        if attribute.as_deref() == Some("initialAmount") {
+                            (species.initial_concentration, species.initial_amount) = (None, Some(value));
+                        } else {
+                            (species.initial_concentration, species.initial_amount) = (Some(value), None);
+                        }
+                    }
+                    Some("size") | Some("volume") if model.get_compartment(&id).is_some() => {
+                        model.compartments.iter_mut().find(|c| c.id == id).unwrap().size = value;
+                    }
+                    _ => return Err(invalid(format!("change target not supported: {}", target))),
+                }
+            }
+            models.insert(m.id.clone(), model);
+        }
+        Ok(models)
+    }
+
+    /// Run `simulation` of `model`: time, species, parameters,
+    /// compartments and fluxes by id at each output point
+    fn run_task(&self, model: SbmlModel, simulation: &SedSimulation) -> Result<HashMap<String, Vec<f64>>> {
+        let mut sim = CopasiSimulation::new(model);
+        let parameter = |kisao: &str| simulation.parameters.iter().find(|(k, _)| k == kisao).map(|(_, v)| *v);
+        let mut ode = sim.ode;
+        if let Some(tolerance) = parameter(RELATIVE_TOLERANCE) {
+            ode.relative_tolerance = tolerance;
+        }
+        if let Some(tolerance) = parameter(ABSOLUTE_TOLERANCE) {
+            ode.absolute_tolerance = tolerance;
+        }
+        sim.set_ode(ode);
+        if let Some(seed) = parameter(SEED) {
+            sim.set_seed(seed as u64);
+        }
+
+        let mut table: HashMap<String, Vec<f64>> = HashMap::new();
+        match simulation.kind {
+            SedSimulationKind::SteadyState => {
+                sim.steady_state()?;
+                sim.record(&mut table);
+                return Ok(table);
+            }
+            SedSimulationKind::OneStep { step } => {
+                sim.set_method(method(&simulation.algorithm)?);
+                sim.step(step);
+                sim.record(&mut table);
+            }
+            SedSimulationKind::UniformTimeCourse { initial_time, output_start_time, output_end_time, number_of_steps } => {
+                sim.set_method(method(&simulation.algorithm)?);
+                sim.t = initial_time;
+                if output_start_time > initial_time {
+                    sim.step(output_start_time - initial_time);
+                }
+                sim.record(&mut table);
+                let dt = (output_end_time - output_start_time) / number_of_steps.max(1) as f64;
+                for _ in 0..number_of_steps {
+                    sim.step(dt);
+                    sim.record(&mut table);
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Run every task, models being read relative to `directory`, and
+    /// compute the data generators: their values by id
+    pub fn execute(&self, directory: &Path) -> Result<HashMap<String, Vec<f64>>> {
+        let models = self.load_models(directory)?;
+        let mut results: HashMap<&str, HashMap<String, Vec<f64>>> = HashMap::new();
+        for task in &self.tasks {
+            let model = models.get(&task.model)
+                .ok_or_else(|| invalid(format!("task {} refers to no model {}", task.id, task.model)))?;
+            let simulation = self.simulations.iter()
+                .find(|s| s.id == task.simulation)
+                .ok_or_else(|| invalid(format!("task {} refers to no simulation {}", task.id, task.simulation)))?;
+            results.insert(&task.id, self.run_task(model.clone(), simulation)?);
+        }
+
+        let mut data = HashMap::new();
+        for generator in &self.data_generators {
+            let values = generator.variables.iter()
+                .map(|v| {
+                    let table = results.get(v.task.as_str())
+                        .ok_or_else(|| invalid(format!("variable {} refers to no task {}", v.id, v.task)))?;
+                    let key = match (&v.symbol, &v.target) {
+                        (Some(symbol), _) if symbol == TIME_SYMBOL => "time".to_string(),
+                        (_, Some(target)) => target_id(target).map(|(id, _)| id).unwrap_or_default(),
+                        _ => String::new(),
+                    };
+                    table.get(&key).ok_or_else(|| invalid(format!("variable {} is not understood", v.id)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let expr = Expr::parse(&generator.math)?;
+            let points = values.iter().map(|v| v.len()).min().unwrap_or(1);
+            let series = (0..points)
+                .map(|i| {
+                    expr.eval(&|symbol| {
+                        generator.variables.iter()
+                            .position(|v| v.id == symbol)
+                            .map(|k| values[k][i])
+                            .or_else(|| generator.parameters.iter().find(|(p, _)| p == symbol).map(|(_, v)| *v))
+                    })
+                })
+                .collect();
+            data.insert(generator.id.clone(), series);
+        }
+        Ok(data)
+    }
+
+    /// The report of `data_sets` of `data` from [`Self::execute`] as CSV, a
+    /// column per data set
+    pub fn report_csv(&self, data_sets: &[DataSet], data: &HashMap<String, Vec<f64>>) -> Result<String> {
+        let columns = data_sets.iter()
+            .map(|d| data.get(&d.data).ok_or_else(|| invalid(format!("data set {} refers to no data generator {}", d.id, d.data))))
+            .collect::<Result<Vec<_>>>()?;
+        let quote = |label: &str| format!("\"{}\"", label.replace('"', "\"\""));
+        let mut csv = data_sets.iter().map(|d| quote(&d.label)).collect::<Vec<_>>().join(",");
+        csv.push('\n');
+        for i in 0..columns.iter().map(|c| c.len()).max().unwrap_or(0) {
+            let row: Vec<String> = columns.iter().map(|c| c.get(i).map_or(String::new(), |v| v.to_string())).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        Ok(csv)
+    }
+
+    /// The plot of `curves` over `data` from [`Self::execute`] as SVG
+    pub fn plot_svg(&self, title: &str, curves: &[Curve], data: &HashMap<String, Vec<f64>>) -> Result<String> {
+        const WIDTH: f64 = 640.0;
+        const HEIGHT: f64 = 420.0;
+        const MARGIN: f64 = 60.0;
+        const COLORS: [&str; 8] = ["#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#17becf"];
+        let series = |id: &String| data.get(id).ok_or_else(|| invalid(format!("curve refers to no data generator {}", id)));
+        let scale = |v: f64, log: bool| if log { v.log10() } else { v };
+        let lines = curves.iter()
+            .map(|c| {
+                let points: Vec<(f64, f64)> = series(&c.x)?.iter()
+                    .zip(series(&c.y)?)
+                    .map(|(x, y)| (scale(*x, c.log_x), scale(*y, c.log_y)))
+                    .filter(|(x, y)| x.is_finite() && y.is_finite())
+                    .collect();
+                Ok(points)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let range = |values: &mut dyn Iterator<Item = f64>| {
+            let (low, high) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(l, h), v| (l.min(v), h.max(v)));
+            match (low.is_finite(), high > low) {
+                (false, _) => (0.0, 1.0),
+                (true, false) => (low - 0.5, high + 0.5),
+                (true, true) => (low, high),
+            }
+        };
+        let (x0, x1) = range(&mut lines.iter().flatten().map(|p| p.0));
+        let (y0, y1) = range(&mut lines.iter().flatten().map(|p| p.1));
+        let px = |x: f64| MARGIN + (x - x0) / (x1 - x0) * (WIDTH - 2.0 * MARGIN);
+        let py = |y: f64| HEIGHT - MARGIN - (y - y0) / (y1 - y0) * (HEIGHT - 2.0 * MARGIN);
+        let tick = |v: f64, log: bool| format!("{:.4}", if log { 10f64.powf(v) } else { v });
+        let (log_x, log_y) = (curves.iter().any(|c| c.log_x), curves.iter().any(|c| c.log_y));
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n\
+             <rect width=\"{w}\" height=\"{h}\" fill=\"white\"/>\n\
+             <text x=\"{}\" y=\"24\" text-anchor=\"middle\" font-family=\"sans-serif\" font-size=\"16\">{}</text>\n\
+             <path d=\"M{m} {m} V{} H{}\" fill=\"none\" stroke=\"black\"/>\n",
+            WIDTH / 2.0,
+            escape(title),
+            HEIGHT - MARGIN,
+            WIDTH - MARGIN,
+            w = WIDTH,
+            h = HEIGHT,
+            m = MARGIN,
+        );
+        let label = |x: f64, y: f64, anchor: &str, text: String| {
+            format!("<text x=\"{}\" y=\"{}\" text-anchor=\"{}\" font-family=\"sans-serif\" font-size=\"11\">{}</text>\n", x, y, anchor, text)
+        };
+        svg += &label(MARGIN, HEIGHT - MARGIN + 16.0, "middle", tick(x0, log_x));
+        svg += &label(WIDTH - MARGIN, HEIGHT - MARGIN + 16.0, "middle", tick(x1, log_x));
+        svg += &label(MARGIN - 6.0, HEIGHT - MARGIN, "end", tick(y0, log_y));
+        svg += &label(MARGIN - 6.0, MARGIN + 4.0, "end", tick(y1, log_y));
+        for (k, (curve, points)) in curves.iter().zip(&lines).enumerate() {
+            let color = COLORS[k % COLORS.len()];
+            let path: Vec<String> = points.iter().map(|(x, y)| format!("{:.2},{:.2}", px(*x), py(*y))).collect();
+            svg += &format!("<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"/>\n", path.join(" "), color);
+            let y = MARGIN + 14.0 * k as f64;
+            svg += &format!(
+                "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" stroke-width=\"2\"/>\n",
+                WIDTH - MARGIN - 110.0, y, WIDTH - MARGIN - 95.0, y, color
+            );
+            svg += &label(WIDTH - MARGIN - 90.0, y + 4.0, "start", escape(curve.name.as_ref().unwrap_or(&curve.id)));
+        }
+        svg.push_str("</svg>\n");
+        Ok(svg)
+    }
+
+    /// Write each report of `data` from [`Self::execute`] to `<id>.csv`
+    /// and each plot to `<id>.svg` in `directory`, returning the files
+    pub fn write_outputs(&self, data: &HashMap<String, Vec<f64>>, directory: &Path) -> Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(directory)?;
+        let mut files = Vec::new();
+        for output in &self.outputs {
+            let (file, contents) = match output {
+                SedOutput::Report { id, data_sets, .. } => (format!("{}.csv", id), self.report_csv(data_sets, data)?),
+                SedOutput::Plot2D { id, name, curves } => {
+                    (format!("{}.svg", id), self.plot_svg(name.as_ref().unwrap_or(id), curves, data)?)
+                }
+            };
+            let path = directory.join(file);
+            std::fs::write(&path, contents)?;
+            files.push(path);
+        }
+        Ok(files)
+    }
+}
+
+/// Simulation method of the KiSAO algorithm `kisao`
+fn method(kisao: &str) -> Result<SimulationMethod> {
+    Ok(match kisao {
+        "KISAO_0000029" => SimulationMethod::Stochastic,
+        "KISAO_0000027" => SimulationMethod::NextReaction,
+        "KISAO_0000039" => SimulationMethod::TauLeaping,
+        "KISAO_0000352" | "KISAO_0000561" | "KISAO_0000562" => SimulationMethod::Hybrid,
+        _ if ODE_ALGORITHMS.contains(&kisao) => SimulationMethod::Deterministic,
+        _ => return Err(OldiesError::SimulationError(format!("Algorithm {} is not supported", kisao))),
+    })
+}
+
+impl CopasiSimulation {
+    /// Append the time, species, parameters, compartments and fluxes to
+    /// their columns in `table`
+    fn record(&self, table: &mut HashMap<String, Vec<f64>>) {
+        let rates = self.compute_rates();
+        let values = std::iter::once(("time", self.t))
+            .chain(self.model.species.iter().enumerate().map(|(i, s)| (s.id.as_str(), self.species_value(i))))
+            .chain(self.model.parameters.iter().map(|p| (p.id.as_str(), p.value)))
+            .chain(self.model.compartments.iter().map(|c| (c.id.as_str(), c.size)))
+            .chain(self.model.reactions.iter().zip(&rates).map(|(r, v)| (r.id.as_str(), *v)));
+        for (id, value) in values {
+            table.entry(id.to_string()).or_default().push(value);
+        }
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEDML: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<sedML xmlns="http://sed-ml.org/sed-ml/level1/version3" level="1" version="3"
+       xmlns:math="http://www.w3.org/1998/Math/MathML">
+  <listOfModels>
+    <model id="original" language="urn:sedml:language:sbml" source="decay.xml"/>
+    <model id="fast" language="urn:sedml:language:sbml" source="#original">
+      <listOfChanges>
+        <changeAttribute target="/sbml:sbml/sbml:model/sbml:listOfParameters/sbml:parameter[@id='k']/@value" newValue="2"/>
+        <changeAttribute target="/sbml:sbml/sbml:model/sbml:listOfSpecies/sbml:species[@id=&quot;A&quot;]/@initialConcentration" newValue="4"/>
+      </listOfChanges>
+    </model>
+  </listOfModels>
+  <listOfSimulations>
+    <uniformTimeCourse id="course" initialTime="0" outputStartTime="1" outputEndTime="2" numberOfPoints="4">
+      <algorithm kisaoID="KISAO_0000019">
+        <listOfAlgorithmParameters>
+          <algorithmParameter kisaoID="KISAO_0000209" value="1e-10"/>
+        </listOfAlgorithmParameters>
+      </algorithm>
+    </uniformTimeCourse>
+    <steadyState id="rest">
+      <algorithm kisaoID="KISAO_0000282"/>
+    </steadyState>
+  </listOfSimulations>
+  <listOfTasks>
+    <task id="slow_task" modelReference="original" simulationReference="course"/>
+    <task id="fast_task" modelReference="fast" simulationReference="course"/>
+    <task id="rest_task" modelReference="fast" simulationReference="rest"/>
+  </listOfTasks>
+  <listOfDataGenerators>
+    <dataGenerator id="time">
+      <listOfVariables>
+        <variable id="t" taskReference="slow_task" symbol="urn:sedml:symbol:time"/>
+      </listOfVariables>
+      <math xmlns="http://www.w3.org/1998/Math/MathML"><ci>t</ci></math>
+    </dataGenerator>
+    <dataGenerator id="slow" name="A, k = 1">
+      <listOfVariables>
+        <variable id="a" taskReference="slow_task"
+                  target="/sbml:sbml/sbml:model/sbml:listOfSpecies/sbml:species[@id='A']"/>
+      </listOfVariables>
+      <math xmlns="http://www.w3.org/1998/Math/MathML"><ci>a</ci></math>
+    </dataGenerator>
+    <dataGenerator id="fast_scaled">
+      <listOfVariables>
+        <variable id="a" taskReference="fast_task"
+                  target="/sbml:sbml/sbml:model/sbml:listOfSpecies/sbml:species[@id='A']"/>
+        <variable id="v" taskReference="fast_task"
+                  target="/sbml:sbml/sbml:model/sbml:listOfReactions/sbml:reaction[@id='decay']"/>
+      </listOfVariables>
+      <listOfParameters>
+        <parameter id="scale" value="10"/>
+      </listOfParameters>
+      <math xmlns="http://www.w3.org/1998/Math/MathML">
+        <apply><times/><ci>scale</ci><apply><minus/><ci>a</ci><ci>v</ci></apply></apply>
+      </math>
+    </dataGenerator>
+    <dataGenerator id="rest">
+      <listOfVariables>
+        <variable id="a" taskReference="rest_task"
+                  target="/sbml:sbml/sbml:model/sbml:listOfSpecies/sbml:species[@id='A']"/>
+      </listOfVariables>
+      <math xmlns="http://www.w3.org/1998/Math/MathML"><ci>a</ci></math>
+    </dataGenerator>
+  </listOfDataGenerators>
+  <listOfOutputs>
+    <report id="table">
+      <listOfDataSets>
+        <dataSet id="d_time" label="time" dataReference="time"/>
+        <dataSet id="d_slow" label="A" dataReference="slow"/>
+      </listOfDataSets>
+    </report>
+    <plot2D id="figure" name="Decay">
+      <listOfCurves>
+        <curve id="c_slow" logX="false" logY="true" xDataReference="time" yDataReference="slow"/>
+      </listOfCurves>
+    </plot2D>
+  </listOfOutputs>
+</sedML>"##;
+
+    fn decay() -> SbmlModel {
+        let mut model = SbmlModel::new("decay");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 1.0));
+        model.add_parameter(Parameter::new("k", 1.0));
+        let mut decay = Reaction::simple("decay", "A", "A", "k");
+        decay.products.clear();
+        model.add_reaction(decay);
+        model
+    }
+
+    #[test]
+    fn test_read_sedml() {
+        let sed = SedDocument::from_sedml_str(SEDML).unwrap();
+        assert_eq!(sed.models[1].changes.len(), 2);
+        assert_eq!(target_id(&sed.models[1].changes[1].0), Some(("A".into(), Some("initialConcentration".into()))));
+        assert!(matches!(sed.simulations[0].kind, SedSimulationKind::UniformTimeCourse { number_of_steps: 4, .. }));
+        assert_eq!(sed.simulations[0].parameters, vec![("KISAO_0000209".to_string(), 1e-10)]);
+        assert_eq!(sed.data_generators[2].math, "scale * (a - v)");
+        assert_eq!(sed.outputs.iter().map(|o| o.id()).collect::<Vec<_>>(), vec!["table", "figure"]);
+
+        let repeated = SEDML.replace("<task id=\"rest_task\"", "<repeatedTask id=\"rest_task\"");
+        let error = SedDocument::from_sedml_str(&repeated).unwrap_err().to_string();
+        assert!(error.contains("repeatedTask"), "{}", error);
+        assert!(SedDocument::from_sedml_str("<sbml/>").is_err());
+    }
+
+    #[test]
+    fn test_execute_sedml() {
+        let directory = std::env::temp_dir().join(format!("oldies-sedml-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("decay.xml"), decay().to_sbml_string().unwrap()).unwrap();
+        let sed = SedDocument::from_sedml_str(SEDML).unwrap();
+        let data = sed.execute(&directory).unwrap();
+
+        // From time 1 to 2, A = e^-t and, changed, A = 4 e^-2t, flux 2 A
+        assert_eq!(data["time"], vec![1.0, 1.25, 1.5, 1.75, 2.0]);
+        for (t, a) in data["time"].iter().zip(&data["slow"]) {
+            assert!((a - (-t).exp()).abs() < 1e-7, "{} {}", t, a);
+        }
+        for (t, scaled) in data["time"].iter().zip(&data["fast_scaled"]) {
+            let a = 4.0 * (-2.0 * t).exp();
+            assert!((scaled - 10.0 * (a - 2.0 * a)).abs() < 1e-6, "{} {}", t, scaled);
+        }
+        assert_eq!(data["rest"].len(), 1);
+        assert!(data["rest"][0].abs() < 1e-8);
+
+        let output = directory.join("outputs");
+        let files = sed.write_outputs(&data, &output).unwrap();
+        assert_eq!(files, vec![output.join("table.csv"), output.join("figure.svg")]);
+        let csv = std::fs::read_to_string(&files[0]).unwrap();
+        assert_eq!(csv.lines().count(), 6);
+        assert!(csv.starts_with("\"time\",\"A\"\n1,"), "{}", csv);
+        let svg = std::fs::read_to_string(&files[1]).unwrap();
+        assert!(svg.contains("<polyline") && svg.contains(">Decay</text>") && svg.contains(">c_slow</text>"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Local forward sensitivity analysis
+//!
+//! With parameters chosen by [`CopasiSimulation::set_sensitivities`],
+//! deterministic time courses also integrate the sensitivities
+//! s_k = dS/dp_k of the concentrations to each parameter p_k, by the
+//! forward sensitivity equations
+//!
+//! ds_k/dt = J s_k + df/dp_k
+//!
+//! with J the Jacobian of the reaction system f. Each right-hand side is
+//! one directional forward difference of f, moving S along s_k and p_k
+//! together. The sensitivities are integrated alongside the state by the
+//! same Rosenbrock method ([`crate::ode`]), under the same error control,
+//! starting from zero: initial concentrations are taken as independent of
+//! the parameters. Models with events are refused, as the sensitivities
+//! would jump at every event execution (see [`crate::events`]).
+
+use crate::*;
+use crate::ode::{rosenbrock, OdeSystem};
+
+/// Sensitivities of the state to the selected parameters
+#[derive(Debug, Clone)]
+pub(crate) struct Sensitivities {
+    /// Parameter ids
+    pub parameters: Vec<String>,
+    /// Indices of the parameters in the model
+    indices: Vec<usize>,
+    /// dS_i/dp_k, species by parameter
+    pub values: Array2<f64>,
+}
+
+/// State and sensitivities, stacked as [S, s_1, ..., s_p]
+struct Augmented<'a> {
+    sim: &'a mut CopasiSimulation,
+    stoich: &'a Array2<f64>,
+    indices: &'a [usize],
+}
+
+impl Augmented<'_> {
+    fn species(&self) -> usize {
+        self.stoich.nrows()
+    }
+}
+
+impl OdeSystem for Augmented<'_> {
+    fn rates(&mut self, t: Time, y: &Array1<f64>) -> Array1<f64> {
+        let n = self.species();
+        let x = y.slice(ndarray::s![..n]).to_owned();
+        let f = self.sim.derivatives(self.stoich, t, &x);
+        let root_eps = f64::EPSILON.sqrt();
+        let x_scale = root_eps * x.iter().fold(0.0f64, |m, v| m.max(v.abs())).max(1e-12);
+
+        let mut rates = Array1::zeros(y.len());
+        rates.slice_mut(ndarray::s![..n]).assign(&f);
+        for (k, &index) in self.indices.iter().enumerate() {
+            let s = y.slice(ndarray::s![n * (k + 1)..n * (k + 2)]);
+            let p = self.sim.model.parameters[index].value;
+            // Step along (s_k, 1), small against both p_k and S
+            let mut delta = root_eps * p.abs().max(1e-8);
+            let s_norm = s.iter().fold(0.0f64, |m, v| m.max(v.abs()));
+            if delta * s_norm > x_scale {
+                delta = x_scale / s_norm;
+            }
+            self.sim.model.parameters[index].value = p + delta;
+            let shifted = self.sim.derivatives(self.stoich, t, &(&x + &(&s * delta)));
+            self.sim.model.parameters[index].value = p;
+            rates
+                .slice_mut(ndarray::s![n * (k + 1)..n * (k + 2)])
+                .assign(&((shifted - &f) / delta));
+        }
+        rates
+    }
+
+    fn jacobian(&mut self, t: Time, y: &Array1<f64>, f0: &Array1<f64>) -> (Array2<f64>, Array1<f64>) {
+        // J on every diagonal block, leaving out the dependence of J s_k
+        // on S, which the Rosenbrock step tolerates as an approximation
+        let n = self.species();
+        let x = y.slice(ndarray::s![..n]).to_owned();
+        let f = f0.slice(ndarray::s![..n]).to_owned();
+        let (block, _) = self.sim.jacobian(self.stoich, t, &x, &f);
+        let mut jacobian = Array2::zeros((y.len(), y.len()));
+        for k in 0..=self.indices.len() {
+            jacobian
+                .slice_mut(ndarray::s![n * k..n * (k + 1), n * k..n * (k + 1)])
+                .assign(&block);
+        }
+        let delta = f64::EPSILON.sqrt() * t.abs().max(1.0);
+        let dfdt = (self.rates(t + delta, y) - f0) / delta;
+        (jacobian, dfdt)
+    }
+}
+
+impl CopasiSimulation {
+    /// Integrate the sensitivities to `parameters` alongside deterministic
+    /// time courses, from zero at the current time; none turns them off.
+    /// Models with events are errors.
+    pub fn set_sensitivities(&mut self, parameters: &[&str]) -> Result<()> {
+        if parameters.is_empty() {
+            self.sensitivities = None;
+            return Ok(());
+        }
+        if !self.model.events.is_empty() {
+            return Err(OldiesError::SimulationError(format!(
+                "Sensitivities are not integrated across events, and {} has {}",
+                self.model.id, self.model.events.len()
+            )));
+        }
+        let indices = parameters.iter()
+            .map(|id| {
+                self.model.parameters.iter().position(|p| p.id == *id).ok_or_else(|| {
+                    OldiesError::SimulationError(format!("Unknown parameter: {}", id))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.sensitivities = Some(Sensitivities {
+            parameters: parameters.iter().map(|p| p.to_string()).collect(),
+            indices,
+            values: Array2::zeros((self.state.len(), parameters.len())),
+        });
+        self.ode_step = None;
+        Ok(())
+    }
+
+    /// Sensitivities dS/dp of the current concentrations, by parameter,
+    /// then species
+    pub fn get_sensitivities(&self) -> Option<HashMap<String, HashMap<String, f64>>> {
+        let sensitivities = self.sensitivities.as_ref()?;
+        Some(sensitivities.parameters.iter()
+            .enumerate()
+            .map(|(k, p)| {
+                let by_species = self.model.species.iter()
+                    .enumerate()
+                    .map(|(i, s)| (s.id.clone(), sensitivities.values[[i, k]] / self.volume(i)))
+                    .collect();
+                (p.clone(), by_species)
+            })
+            .collect())
+    }
+
+    /// Integrate the reaction system and the sensitivities up to the time
+    /// `end`
+    pub(crate) fn step_sensitivities(&mut self, end: Time) {
+        let Some(mut sensitivities) = self.sensitivities.take() else {
+            return;
+        };
+        let stoich = self.model.stoichiometry_matrix();
+        let n = self.state.len();
+        let mut y = Array1::zeros(n * (sensitivities.indices.len() + 1));
+        y.slice_mut(ndarray::s![..n]).assign(&self.state);
+        for k in 0..sensitivities.indices.len() {
+            y.slice_mut(ndarray::s![n * (k + 1)..n * (k + 2)])
+                .assign(&sensitivities.values.column(k));
+        }
+
+        let (mut t, mut step, options) = (self.t, self.ode_step, self.ode);
+        let mut system = Augmented { sim: self, stoich: &stoich, indices: &sensitivities.indices };
+        rosenbrock(&mut system, options, &mut t, &mut y, end, &mut step);
+
+        self.t = t;
+        self.ode_step = step;
+        self.state.assign(&y.slice(ndarray::s![..n]));
+        for k in 0..sensitivities.indices.len() {
+            sensitivities.values.column_mut(k)
+                .assign(&y.slice(ndarray::s![n * (k + 1)..n * (k + 2)]));
+        }
+        self.sensitivities = Some(sensitivities);
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(k2: f64) -> SbmlModel {
+        // A -> B -> 0 at k1 = 1 and k2
+        let mut model = SbmlModel::new("chain");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 1.0));
+        model.add_species(Species::new("B", "c", 0.0));
+        model.add_parameter(Parameter::new("k1", 1.0));
+        model.add_parameter(Parameter::new("k2", k2));
+        model.add_reaction(Reaction::simple("conversion", "A", "B", "k1"));
+        let mut decay = Reaction::simple("decay", "B", "B", "k2");
+        decay.products.clear();
+        model.add_reaction(decay);
+        model
+    }
+
+    #[test]
+    fn test_decay_sensitivity() {
+        // dA/dk1 = -t e^-t
+        let mut sim = CopasiSimulation::new(chain(0.5));
+        sim.set_sensitivities(&["k1"]).unwrap();
+        let result = sim.run(10.0, 20);
+        let sensitivities = &result.sensitivities.unwrap()["k1"]["A"];
+        for (t, s) in result.time.iter().zip(sensitivities) {
+            assert!((s + t * (-t).exp()).abs() < 1e-5, "{} {}", t, s);
+        }
+        assert!(sim.set_sensitivities(&["k3"]).is_err());
+    }
+
+    #[test]
+    fn test_matches_finite_differences() {
+        let mut sim = CopasiSimulation::new(chain(0.5));
+        sim.set_sensitivities(&["k2", "k1"]).unwrap();
+        let result = sim.run(5.0, 10);
+        let sensitivities = result.sensitivities.unwrap();
+        let delta = 1e-4;
+        let plus = CopasiSimulation::new(chain(0.5 + delta)).run(5.0, 10);
+        let minus = CopasiSimulation::new(chain(0.5 - delta)).run(5.0, 10);
+        for (i, s) in sensitivities["k2"]["B"].iter().enumerate() {
+            let difference = (plus.concentrations["B"][i] - minus.concentrations["B"][i]) / (2.0 * delta);
+            assert!((s - difference).abs() < 1e-4, "{} {}", s, difference);
+        }
+        assert!(sensitivities["k2"]["A"].iter().all(|s| s.abs() < 1e-12));
+        assert_eq!(sim.get_sensitivities().unwrap()["k1"]["B"], *sensitivities["k1"]["B"].last().unwrap());
+    }
+
+    #[test]
+    fn test_events_refused() {
+        let mut model = chain(0.5);
+        model.events.push(Event {
+            id: "refill".into(),
+            trigger: "A < 0.5".into(),
+            delay: None,
+            priority: None,
+            assignments: vec![EventAssignment { variable: "A".into(), expression: "1".into() }],
+        });
+        let mut sim = CopasiSimulation::new(model);
+        let err = sim.set_sensitivities(&["k1"]).unwrap_err();
+        assert!(err.to_string().contains("events"), "{}", err);
+        assert!(sim.set_sensitivities(&[]).is_ok());
+        // Events still execute without sensitivities
+        let result = sim.run(2.0, 20);
+        assert!(result.concentrations["A"].iter().all(|&a| a > 0.49));
+    }
+}
@@ -0,0 +1,273 @@
+//! Steady state task
+//!
+//! [`CopasiSimulation::steady_state`] looks for a state where every rate
+//! of change vanishes, as COPASI's steady-state task does:
+//!
+//! - Conserved totals leave the Jacobian of the full system singular, so
+//!   Newton's method works on the independent species only
+//!   ([`crate::moieties`]), with x - L x_ind held at its initial value.
+//! - Each Newton step is damped, halving it until the rates shrink and no
+//!   concentration goes negative.
+//! - If Newton's method fails from the current state, the model is
+//!   integrated over times growing tenfold up to 1e10 and Newton's method
+//!   retried from each end point.
+//!
+//! The state found is reported with its fluxes, and with the eigenvalues
+//! of the Jacobian of the independent species, which tell its stability.
+//! Complex eigenvalues predict oscillations near the state, of the period
+//! of the least damped pair: transient ones about a stable focus, and
+//! growing ones about an unstable focus, which typically settle on a limit
+//! cycle whose [`crate::lyapunov`] exponents tell it apart from chaos.
+
+use crate::*;
+use crate::linalg::{self, Lu};
+use crate::moieties::Reduction;
+use ndarray::Axis;
+
+/// Rates of change, relative to the largest concentration, taken as zero
+const RESOLUTION: f64 = 1e-9;
+/// Newton iterations from one starting state
+const NEWTON_ITERATIONS: usize = 50;
+/// Halvings of a Newton step before giving up
+const DAMPING_STEPS: usize = 32;
+
+/// Stability of a steady state, from the real parts of its eigenvalues
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stability {
+    /// All negative: asymptotically stable
+    Stable,
+    /// Some positive
+    Unstable,
+    /// None positive, some zero: stability not decided by linearization
+    Marginal,
+}
+
+/// Oscillations predicted near a steady state by the complex pair of
+/// eigenvalues with the largest real part
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Oscillation {
+    /// Period 2π / |imaginary part|
+    pub period: f64,
+    /// Real part: negative for damped oscillations, positive for growing
+    /// ones
+    pub growth_rate: f64,
+}
+
+/// Result of the steady state task
+#[derive(Debug, Clone)]
+pub struct SteadyState {
+    /// Species concentrations
+    pub concentrations: HashMap<String, f64>,
+    /// Reaction fluxes
+    pub fluxes: HashMap<String, f64>,
+    /// Jacobian of the independent species
+    pub jacobian: Array2<f64>,
+    /// Independent species, in the order of the Jacobian
+    pub independent_species: Vec<String>,
+    /// Eigenvalues (real, imaginary) of the Jacobian
+    pub eigenvalues: Vec<(f64, f64)>,
+    /// Stability from the real parts of the eigenvalues
+    pub stability: Stability,
+    /// Oscillations predicted from complex eigenvalues, if any
+    pub oscillation: Option<Oscillation>,
+    /// Whether the model had to be integrated before Newton's method
+    /// converged
+    pub integrated: bool,
+}
+
+impl CopasiSimulation {
+    /// Find a steady state by damped Newton iteration, integrating first
+    /// if that fails, and leave the simulation in it
+    pub fn steady_state(&mut self) -> Result<SteadyState> {
+        let stoich = self.model.stoichiometry_matrix();
+        let reduction = self.reduction(&stoich);
+
+        let mut integrated = false;
+        let mut found = self.newton(&stoich, &reduction);
+        let mut duration = 0.1;
+        while !found && duration <= 1e10 {
+            integrated = true;
+            self.step_deterministic(self.t + duration);
+            found = self.newton(&stoich, &reduction) || self.is_steady(&stoich, &reduction);
+            duration *= 10.0;
+        }
+        if !found {
+            return Err(OldiesError::NumericalError("Steady state not reached".into()));
+        }
+
+        let jacobian = self.reduced_jacobian(&stoich, &reduction);
+        let eigenvalues = linalg::eigenvalues(&jacobian).ok_or_else(|| {
+            OldiesError::NumericalError("Eigenvalues of the steady state did not converge".into())
+        })?;
+        let largest = eigenvalues.iter().fold(0.0f64, |m, e| m.max(e.0.hypot(e.1)));
+        let zero = RESOLUTION * largest.max(1.0);
+        let stability = if eigenvalues.iter().any(|e| e.0 > zero) {
+            Stability::Unstable
+        } else if eigenvalues.iter().any(|e| e.0 >= -zero) {
+            Stability::Marginal
+        } else {
+            Stability::Stable
+        };
+        let oscillation = eigenvalues.iter()
+            .filter(|e| e.1.abs() > zero)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|e| Oscillation { period: 2.0 * std::f64::consts::PI / e.1.abs(), growth_rate: e.0 });
+
+        let rates = self.compute_rates();
+        Ok(SteadyState {
+            concentrations: self.get_concentrations(),
+            fluxes: self.model.reactions.iter()
+                .zip(rates.iter())
+                .map(|(r, v)| (r.id.clone(), *v))
+                .collect(),
+            jacobian,
+            independent_species: reduction.independent.iter()
+                .map(|&i| self.model.species[i].id.clone())
+                .collect(),
+            eigenvalues,
+            stability,
+            oscillation,
+            integrated,
+        })
+    }
+
+    /// Rates of change of the independent species
+    fn reduced_rates(&mut self, stoich: &Array2<f64>, reduction: &Reduction, x: &Array1<f64>) -> Array1<f64> {
+        self.derivatives(stoich, self.t, x).select(Axis(0), &reduction.independent)
+    }
+
+    /// Whether the rates of change vanish in the current state
+    fn is_steady(&mut self, stoich: &Array2<f64>, reduction: &Reduction) -> bool {
+        let x = self.state.clone();
+        let rates = self.reduced_rates(stoich, reduction, &x);
+        max_abs(&rates) < RESOLUTION * max_abs(&x).max(1.0)
+    }
+
+    /// Jacobian of the independent species in the current state
+    fn reduced_jacobian(&mut self, stoich: &Array2<f64>, reduction: &Reduction) -> Array2<f64> {
+        let x = self.state.clone();
+        let f0 = self.derivatives(stoich, self.t, &x);
+        let (jacobian, _) = self.jacobian(stoich, self.t, &x, &f0);
+        jacobian.select(Axis(0), &reduction.independent).dot(&reduction.link)
+    }
+
+    /// Damped Newton iteration from the current state, which it is left
+    /// in on success
+    fn newton(&mut self, stoich: &Array2<f64>, reduction: &Reduction) -> bool {
+        let start = self.state.clone();
+        let totals = &start - &reduction.link.dot(&start.select(Axis(0), &reduction.independent));
+        let full = |independent: &Array1<f64>| reduction.link.dot(independent) + &totals;
+
+        let mut x = start.clone();
+        let mut rates = self.reduced_rates(stoich, reduction, &x);
+        for _ in 0..NEWTON_ITERATIONS {
+            if max_abs(&rates) < RESOLUTION * max_abs(&x).max(1.0) {
+                self.state = x;
+                return true;
+            }
+            let jacobian = {
+                let saved = std::mem::replace(&mut self.state, x.clone());
+                let jacobian = self.reduced_jacobian(stoich, reduction);
+                self.state = saved;
+                jacobian
+            };
+            let Some(lu) = Lu::new(&jacobian) else {
+                return false;
+            };
+            let step = lu.solve(&rates);
+            let independent = x.select(Axis(0), &reduction.independent);
+
+            let mut lambda = 1.0;
+            let mut accepted = None;
+            for _ in 0..DAMPING_STEPS {
+                let candidate = full(&(&independent - &(&step * lambda)));
+                if candidate.iter().all(|&c| c >= 0.0) {
+                    let candidate_rates = self.reduced_rates(stoich, reduction, &candidate);
+                    if max_abs(&candidate_rates) < max_abs(&rates) {
+                        accepted = Some((candidate, candidate_rates));
+                        break;
+                    }
+                }
+                lambda /= 2.0;
+            }
+            match accepted {
+                Some((next, next_rates)) => (x, rates) = (next, next_rates),
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+fn max_abs(x: &Array1<f64>) -> f64 {
+    x.iter().fold(0.0f64, |m, v| m.max(v.abs()))
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conserved_equilibrium() {
+        // A <-> B at kf = 2, kb = 1 from A + B = 1: A = 1/3, eigenvalue -3
+        let mut model = SbmlModel::new("equilibrium");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 1.0));
+        model.add_species(Species::new("B", "c", 0.0));
+        model.add_parameter(Parameter::new("kf", 2.0));
+        model.add_parameter(Parameter::new("kb", 1.0));
+        model.add_reaction(Reaction::simple("forward", "A", "B", "kf"));
+        model.add_reaction(Reaction::simple("backward", "B", "A", "kb"));
+
+        let result = CopasiSimulation::new(model).steady_state().unwrap();
+        assert!(!result.integrated);
+        assert!((result.concentrations["A"] - 1.0 / 3.0).abs() < 1e-9);
+        assert!((result.concentrations["B"] - 2.0 / 3.0).abs() < 1e-9);
+        assert!((result.fluxes["forward"] - 2.0 / 3.0).abs() < 1e-8);
+        assert_eq!(result.independent_species, vec!["A".to_string()]);
+        assert_eq!(result.eigenvalues.len(), 1);
+        assert!((result.eigenvalues[0].0 + 3.0).abs() < 1e-5);
+        assert_eq!(result.stability, Stability::Stable);
+        assert_eq!(result.oscillation, None);
+    }
+
+    #[test]
+    fn test_unstable_focus() {
+        // Brusselator at a = 1, b = 3: steady state (1, 3), eigenvalues
+        // (1 ± i√3) / 2
+        let mut model = SbmlModel::new("brusselator");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("X", "c", 1.2));
+        model.add_species(Species::new("Y", "c", 2.5));
+        model.add_parameter(Parameter::new("a", 1.0));
+        model.add_parameter(Parameter::new("b", 3.0));
+        model.add_parameter(Parameter::new("one", 1.0));
+        let mut inflow = Reaction::simple("inflow", "X", "X", "a");
+        inflow.reactants.clear();
+        model.add_reaction(inflow);
+        let mut autocatalysis = Reaction::simple("autocatalysis", "X", "X", "one");
+        autocatalysis.reactants[0].stoichiometry = 2.0;
+        autocatalysis.reactants.push(SpeciesReference::new("Y", 1.0));
+        autocatalysis.products[0].stoichiometry = 3.0;
+        model.add_reaction(autocatalysis);
+        model.add_reaction(Reaction::simple("conversion", "X", "Y", "b"));
+        let mut outflow = Reaction::simple("outflow", "X", "X", "one");
+        outflow.products.clear();
+        model.add_reaction(outflow);
+
+        let result = CopasiSimulation::new(model).steady_state().unwrap();
+        assert!((result.concentrations["X"] - 1.0).abs() < 1e-8);
+        assert!((result.concentrations["Y"] - 3.0).abs() < 1e-8);
+        assert_eq!(result.stability, Stability::Unstable);
+        for (re, im) in &result.eigenvalues {
+            assert!((re - 0.5).abs() < 1e-5 && (im.abs() - 0.75f64.sqrt()).abs() < 1e-5);
+        }
+        let oscillation = result.oscillation.unwrap();
+        assert!((oscillation.period - 2.0 * std::f64::consts::PI / 0.75f64.sqrt()).abs() < 1e-4);
+        assert!((oscillation.growth_rate - 0.5).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,398 @@
+//! Exact stochastic simulation
+//!
+//! Both methods take the state as numbers of molecules, rounded when a
+//! step starts, and fire whole reactions, changing the species by their
+//! stoichiometry:
+//!
+//! - [`SimulationMethod::Stochastic`], Gillespie's direct method, draws
+//!   the time to the next firing from the total propensity and picks the
+//!   reaction in proportion to its own, recomputing every propensity after
+//!   each firing.
+//! - [`SimulationMethod::NextReaction`], the next reaction method of
+//!   Gibson and Bruck (2000), keeps an absolute firing time per reaction
+//!   in an indexed priority queue. After a firing only the reactions whose
+//!   propensities read a species it changed (its dependents in the
+//!   [`DependencyGraph`]) are recomputed, and their times are rescaled
+//!   rather than redrawn, so a firing costs O(log M) rather than O(M) for
+//!   sparse networks of M reactions.
+//!
+//! Mass action propensities are V k x (x - 1) ... (x - n + 1) / V_x^n for
+//! a reactant of stoichiometry n in a compartment of size V_x, V being
+//! that of the reaction, which tends to the deterministic V k (x / V_x)^n
+//! for many molecules; other laws are their deterministic rates, in
+//! amounts per time.
+//! Propensities that depend on time are taken as constant between
+//! firings.
+
+use crate::*;
+use std::collections::HashSet;
+
+/// Reactions whose propensities change when each reaction fires
+#[derive(Debug, Clone)]
+pub struct DependencyGraph {
+    dependents: Vec<Vec<usize>>,
+}
+
+impl DependencyGraph {
+    /// Graph of the reactions of `model`. A reaction depends on the
+    /// species its kinetic law reads, directly or through assignment
+    /// rules; a firing changes the species of nonzero net stoichiometry.
+    pub fn new(model: &SbmlModel) -> Self {
+        let stoich = model.stoichiometry_matrix();
+        let index: HashMap<&str, usize> = model.species.iter()
+            .enumerate()
+            .map(|(i, s)| (s.id.as_str(), i))
+            .collect();
+        let rules: HashMap<&str, Option<Expr>> = model.assignment_rules.iter()
+            .map(|r| (r.variable.as_str(), Expr::parse(&r.expression).ok()))
+            .collect();
+        let reads: Vec<HashSet<usize>> = model.reactions.iter()
+            .map(|r| {
+                let law = Expr::parse(&r.rate_law()).ok();
+                let symbols = law.as_ref().map(|e| e.symbols()).unwrap_or_default();
+                let mut names: Vec<&str> = symbols.into_iter()
+                    .chain(r.reactants.iter().map(|s| s.species.as_str()))
+                    .chain(r.modifiers.iter().map(String::as_str))
+                    .collect();
+                // Follow the assignment rules to the names they read
+                let mut seen: HashSet<&str> = HashSet::new();
+                let mut everything = law.is_none();
+                while let Some(name) = names.pop() {
+                    if !seen.insert(name) {
+                        continue;
+                    }
+                    match rules.get(name) {
+                        Some(Some(rule)) => names.extend(rule.symbols()),
+                        Some(None) => everything = true,
+                        None => {}
+                    }
+                }
+                // Laws and rules that do not parse depend on everything
+                // they may name
+                match everything {
+                    false => seen.into_iter().filter_map(|name| index.get(name).copied()).collect(),
+                    true => (0..model.species.len()).collect(),
+                }
+            })
+            .collect();
+        let dependents = (0..model.reactions.len())
+            .map(|j| {
+                let changed: Vec<usize> = (0..model.species.len()).filter(|&i| stoich[[i, j]] != 0.0).collect();
+                (0..model.reactions.len())
+                    .filter(|&k| k == j || changed.iter().any(|i| reads[k].contains(i)))
+                    .collect()
+            })
+            .collect();
+        Self { dependents }
+    }
+
+    /// Reactions to update after reaction `j` fires, `j` included
+    pub fn dependents(&self, j: usize) -> &[usize] {
+        &self.dependents[j]
+    }
+}
+
+/// Binary min-heap of reaction firing times that can change the time of
+/// any reaction in O(log M)
+#[derive(Debug, Clone)]
+pub struct IndexedPriorityQueue {
+    times: Vec<f64>,
+    /// Reactions in heap order
+    heap: Vec<usize>,
+    /// Place of each reaction in `heap`
+    position: Vec<usize>,
+}
+
+impl IndexedPriorityQueue {
+    pub fn new(times: Vec<f64>) -> Self {
+        let n = times.len();
+        let mut queue = Self { times, heap: (0..n).collect(), position: (0..n).collect() };
+        for i in (0..n / 2).rev() {
+            queue.sift_down(i);
+        }
+        queue
+    }
+
+    /// Reaction of the earliest time, and the time
+    pub fn min(&self) -> Option<(usize, f64)> {
+        self.heap.first().map(|&j| (j, self.times[j]))
+    }
+
+    pub fn time(&self, j: usize) -> f64 {
+        self.times[j]
+    }
+
+    /// Set the time of reaction `j`
+    pub fn update(&mut self, j: usize, time: f64) {
+        let earlier = time < self.times[j];
+        self.times[j] = time;
+        if earlier {
+            self.sift_up(self.position[j]);
+        } else {
+            self.sift_down(self.position[j]);
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.position[self.heap[a]] = a;
+        self.position[self.heap[b]] = b;
+    }
+
+    fn before(&self, a: usize, b: usize) -> bool {
+        self.times[self.heap[a]] < self.times[self.heap[b]]
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 && self.before(i, (i - 1) / 2) {
+            self.swap(i, (i - 1) / 2);
+            i = (i - 1) / 2;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let mut first = i;
+            for child in [2 * i + 1, 2 * i + 2] {
+                if child < self.heap.len() && self.before(child, first) {
+                    first = child;
+                }
+            }
+            if first == i {
+                return;
+            }
+            self.swap(i, first);
+            i = first;
+        }
+    }
+}
+
+/// State of the next reaction method kept between steps
+#[derive(Debug, Clone)]
+pub(crate) struct NextReaction {
+    graph: DependencyGraph,
+    queue: IndexedPriorityQueue,
+    propensities: Vec<f64>,
+}
+
+impl CopasiSimulation {
+    /// Propensity of the `j`th reaction
+    pub(crate) fn propensity(&self, j: usize) -> f64 {
+        let reaction = &self.model.reactions[j];
+        match &reaction.kinetic_law {
+            KineticLaw::MassAction { rate_constant } => {
+                let volume = self.model.reaction_compartment(reaction).map_or(1.0, |c| c.size);
+                let mut a = self.get_value(rate_constant) * volume;
+                for sr in &reaction.reactants {
+                    let Some(i) = self.model.species.iter().position(|s| s.id == sr.species) else {
+                        return 0.0;
+                    };
+                    let (x, v) = (self.state[i], self.volume(i));
+                    if sr.stoichiometry.fract() == 0.0 {
+                        a *= (0..sr.stoichiometry as u32).map(|k| (x - k as f64).max(0.0) / v).product::<f64>();
+                    } else {
+                        a *= (x / v).powf(sr.stoichiometry);
+                    }
+                }
+                a
+            }
+            _ => self.compute_reaction_rate(j, reaction),
+        }
+        .max(0.0)
+    }
+
+    /// Change the species by the stoichiometry of the `j`th reaction
+    pub(crate) fn fire(&mut self, stoich: &Array2<f64>, j: usize) {
+        self.state.scaled_add(1.0, &stoich.column(j));
+        self.apply_assignment_rules();
+    }
+
+    pub(crate) fn round_state(&mut self) {
+        self.state.mapv_inplace(f64::round);
+        self.apply_assignment_rules();
+    }
+
+    /// Gillespie's direct method up to the time `end`
+    pub(crate) fn step_direct(&mut self, end: Time) {
+        self.round_state();
+        self.direct_firings(end, usize::MAX);
+    }
+
+    /// Fire up to `limit` reactions by the direct method before the time
+    /// `end`, leaving the time at the last firing, or at `end` once no
+    /// other reaction fires before it
+    pub(crate) fn direct_firings(&mut self, end: Time, limit: usize) {
+        let stoich = self.model.stoichiometry_matrix();
+        for _ in 0..limit {
+            let propensities: Vec<f64> = (0..self.model.reactions.len()).map(|j| self.propensity(j)).collect();
+            let total: f64 = propensities.iter().sum();
+            let t = self.t + self.rng.exponential(total);
+            if t > end {
+                self.t = end;
+                return;
+            }
+            let mut target = self.rng.uniform() * total;
+            let mut j = 0;
+            while j + 1 < propensities.len() && target >= propensities[j] {
+                target -= propensities[j];
+                j += 1;
+            }
+            self.t = t;
+            self.fire(&stoich, j);
+        }
+    }
+
+    /// Gibson and Bruck's next reaction method up to the time `end`
+    pub(crate) fn step_next_reaction(&mut self, end: Time) {
+        self.round_state();
+        let stoich = self.model.stoichiometry_matrix();
+        let mut state = match self.next_reaction.take() {
+            Some(state) => state,
+            None => {
+                let propensities: Vec<f64> = (0..self.model.reactions.len()).map(|j| self.propensity(j)).collect();
+                let times = propensities.iter().map(|&a| self.t + self.rng.exponential(a)).collect();
+                NextReaction {
+                    graph: DependencyGraph::new(&self.model),
+                    queue: IndexedPriorityQueue::new(times),
+                    propensities,
+                }
+            }
+        };
+        while let Some((mu, t)) = state.queue.min().filter(|&(_, t)| t <= end) {
+            self.t = t;
+            self.fire(&stoich, mu);
+            for &k in state.graph.dependents(mu) {
+                let (old, new) = (state.propensities[k], self.propensity(k));
+                state.propensities[k] = new;
+                let time = if k != mu && old > 0.0 && new > 0.0 {
+                    // The unused part of the waiting time, at the new rate
+                    t + (state.queue.time(k) - t) * old / new
+                } else {
+                    t + self.rng.exponential(new)
+                };
+                state.queue.update(k, time);
+            }
+        }
+        self.next_reaction = Some(state);
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oldies_core::Rng;
+
+    #[test]
+    fn test_indexed_priority_queue() {
+        let mut rng = Rng::new(7);
+        let mut times: Vec<f64> = (0..50).map(|_| rng.uniform()).collect();
+        let mut queue = IndexedPriorityQueue::new(times.clone());
+        for _ in 0..500 {
+            let j = (rng.uniform() * 50.0) as usize;
+            times[j] = if rng.uniform() < 0.1 { f64::INFINITY } else { rng.uniform() };
+            queue.update(j, times[j]);
+            let (first, time) = queue.min().unwrap();
+            let least = times.iter().copied().fold(f64::INFINITY, f64::min);
+            assert_eq!(time, least);
+            assert_eq!(times[first], least);
+        }
+    }
+
+    #[test]
+    fn test_dependency_graph() {
+        // A -> B -> C, and C catalyzing D -> E
+        let mut model = SbmlModel::new("chain");
+        model.add_compartment(Compartment::new("c", 1.0));
+        for s in ["A", "B", "C", "D", "E"] {
+            model.add_species(Species::new(s, "c", 10.0));
+        }
+        model.add_parameter(Parameter::new("k", 1.0));
+        model.add_reaction(Reaction::simple("r0", "A", "B", "k"));
+        model.add_reaction(Reaction::simple("r1", "B", "C", "k"));
+        let mut r2 = Reaction::simple("r2", "D", "E", "k");
+        r2.kinetic_law = KineticLaw::Custom("k * C * D".into());
+        model.add_reaction(r2);
+        let graph = DependencyGraph::new(&model);
+        assert_eq!(graph.dependents(0), [0, 1]);
+        assert_eq!(graph.dependents(1), [1, 2]);
+        assert_eq!(graph.dependents(2), [2]);
+
+        // With D -> E catalyzed by A through an assignment rule instead
+        model.add_parameter(Parameter::new("kA", 0.0));
+        model.assignment_rules.push(AssignmentRule { variable: "kA".into(), expression: "k * A".into() });
+        model.reactions[2].kinetic_law = KineticLaw::MassAction { rate_constant: "kA".into() };
+        let graph = DependencyGraph::new(&model);
+        assert_eq!(graph.dependents(0), [0, 1, 2]);
+        assert_eq!(graph.dependents(1), [1]);
+    }
+
+    /// `n` decays X_i -> 0 at rate k, of 1000 molecules each
+    fn decays(n: usize, k: f64) -> SbmlModel {
+        let mut model = SbmlModel::new("decays");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_parameter(Parameter::new("k", k));
+        for i in 0..n {
+            let x = format!("X{}", i);
+            model.add_species(Species::new(&x, "c", 1000.0));
+            let mut r = Reaction::simple(&format!("decay{}", i), &x, &x, "k");
+            r.products.clear();
+            model.add_reaction(r);
+        }
+        model
+    }
+
+    #[test]
+    fn test_exact_methods_match_decay() {
+        // Each X_i is binomial(1000, e^-1) at t = 1
+        let expected = 1000.0 * (-1.0f64).exp();
+        for method in [SimulationMethod::Stochastic, SimulationMethod::NextReaction] {
+            let mut sim = CopasiSimulation::new(decays(20, 1.0));
+            sim.set_method(method);
+            let result = sim.run(1.0, 10);
+            let finals: Vec<f64> = (0..20).map(|i| *result.concentrations[&format!("X{}", i)].last().unwrap()).collect();
+            assert!(finals.iter().all(|x| x.fract() == 0.0));
+            let mean = finals.iter().sum::<f64>() / 20.0;
+            // Standard deviation of the mean: sqrt(1000 p (1 - p) / 20)
+            assert!((mean - expected).abs() < 15.0, "{:?} {}", method, mean);
+        }
+    }
+
+    #[test]
+    fn test_next_reaction_conserves_and_reproduces() {
+        // 2 A <-> A2: A + 2 A2 stays 100
+        let mut model = SbmlModel::new("dimer");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 100.0));
+        model.add_species(Species::new("A2", "c", 0.0));
+        model.add_parameter(Parameter::new("kf", 0.01));
+        model.add_parameter(Parameter::new("kb", 0.5));
+        let mut bind = Reaction::simple("bind", "A", "A2", "kf");
+        bind.reactants[0].stoichiometry = 2.0;
+        let mut unbind = Reaction::simple("unbind", "A2", "A", "kb");
+        unbind.products[0].stoichiometry = 2.0;
+        model.add_reaction(bind);
+        model.add_reaction(unbind);
+
+        let run = |seed: u64| {
+            let mut sim = CopasiSimulation::new(model.clone());
+            sim.set_method(SimulationMethod::NextReaction);
+            sim.set_seed(seed);
+            sim.run(20.0, 200)
+        };
+        let result = run(1);
+        for (a, a2) in result.concentrations["A"].iter().zip(&result.concentrations["A2"]) {
+            assert_eq!(a + 2.0 * a2, 100.0);
+        }
+        // Equilibrium: kf A (A - 1) = kb A2, near A = 40 and A2 = 30
+        let tail = &result.concentrations["A2"][100..];
+        let mean = tail.iter().sum::<f64>() / tail.len() as f64;
+        assert!((mean - 30.0).abs() < 5.0, "{}", mean);
+
+        assert_eq!(run(1).concentrations["A"], result.concentrations["A"]);
+        assert_ne!(run(2).concentrations["A"], result.concentrations["A"]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Adaptive tau-leaping
+//!
+//! [`SimulationMethod::TauLeaping`] fires each reaction a Poisson number
+//! of times over a leap τ, after Cao, Gillespie and Petzold (2006):
+//!
+//! - Reactions within `critical_firings` firings of exhausting a reactant
+//!   are critical. At most one critical reaction fires per leap, at an
+//!   exponential time of the total critical propensity, so they cannot
+//!   drive a species negative.
+//! - τ for the others keeps the expected change and the standard
+//!   deviation of the change of every reactant species within
+//!   `epsilon` x / g of its amount x, g growing with the highest order of
+//!   the reactions consuming it.
+//! - A leap that still makes a species negative is retried with half τ.
+//! - When τ falls below `ssa_factor` / a0, a0 being the total propensity,
+//!   leaping gains nothing and `ssa_steps` reactions are fired exactly by
+//!   the direct method instead.
+//!
+//! The state is taken as numbers of molecules, as in [`crate::stochastic`].
+
+use crate::*;
+
+/// Settings of adaptive tau-leaping
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TauLeapingOptions {
+    /// Bound of the relative change of a species over a leap
+    pub epsilon: f64,
+    /// Firings left before exhausting a reactant that make a reaction
+    /// critical
+    pub critical_firings: f64,
+    /// Leaps shorter than this many mean waiting times fall back to the
+    /// direct method
+    pub ssa_factor: f64,
+    /// Reactions fired by the direct method on falling back
+    pub ssa_steps: usize,
+}
+
+impl Default for TauLeapingOptions {
+    fn default() -> Self {
+        Self {
+            epsilon: 0.03,
+            critical_firings: 10.0,
+            ssa_factor: 10.0,
+            ssa_steps: 100,
+        }
+    }
+}
+
+/// Highest order of the reactions consuming a species, and the most of
+/// the species one of those consumes
+#[derive(Debug, Clone, Copy, Default)]
+struct Order {
+    order: f64,
+    molecules: f64,
+}
+
+impl Order {
+    /// g of Cao et al. for `x` molecules
+    fn g(self, x: f64) -> f64 {
+        let two = 2.0 + 1.0 / (x - 1.0);
+        match (self.order, self.molecules) {
+            (o, _) if o <= 1.0 => 1.0,
+            (o, m) if o <= 2.0 => if m >= 2.0 { two } else { 2.0 },
+            (o, m) if o <= 3.0 => match m {
+                m if m >= 3.0 => 3.0 + 1.0 / (x - 1.0) + 2.0 / (x - 2.0),
+                m if m >= 2.0 => 1.5 * two,
+                _ => 3.0,
+            },
+            (o, _) => o,
+        }
+    }
+}
+
+impl CopasiSimulation {
+    /// Adaptive tau-leaping up to the time `end`
+    pub(crate) fn step_tau_leap(&mut self, end: Time) {
+        self.round_state();
+        let options = self.tau_leaping;
+        let stoich = self.model.stoichiometry_matrix();
+        let (n, m) = stoich.dim();
+
+        let index: HashMap<&str, usize> = self.model.species.iter()
+            .enumerate()
+            .map(|(i, s)| (s.id.as_str(), i))
+            .collect();
+        let mut orders = vec![Order::default(); n];
+        for reaction in &self.model.reactions {
+            let order: f64 = reaction.reactants.iter().map(|r| r.stoichiometry).sum();
+            for r in &reaction.reactants {
+                if let Some(&i) = index.get(r.species.as_str()) {
+                    let o = &mut orders[i];
+                    if order > o.order {
+                        *o = Order { order, molecules: 0.0 };
+                    }
+                    if order == o.order {
+                        o.molecules = o.molecules.max(r.stoichiometry);
+                    }
+                }
+            }
+        }
+
+        while self.t < end {
+            let a: Vec<f64> = (0..m).map(|j| self.propensity(j)).collect();
+            let a0: f64 = a.iter().sum();
+            if a0 <= 0.0 {
+                break;
+            }
+            // Firings left before a reactant runs out
+            let critical: Vec<bool> = (0..m)
+                .map(|j| {
+                    let left = (0..n)
+                        .filter(|&i| stoich[[i, j]] < 0.0)
+                        .map(|i| (self.state[i] / -stoich[[i, j]]).floor())
+                        .fold(f64::INFINITY, f64::min);
+                    a[j] > 0.0 && left < options.critical_firings
+                })
+                .collect();
+
+            let mut tau1 = f64::INFINITY;
+            for i in 0..n {
+                let consumed = (0..m).any(|j| !critical[j] && stoich[[i, j]] < 0.0);
+                if !consumed {
+                    continue;
+                }
+                let (mut mean, mut variance) = (0.0, 0.0);
+                for j in (0..m).filter(|&j| !critical[j]) {
+                    mean += stoich[[i, j]] * a[j];
+                    variance += stoich[[i, j]].powi(2) * a[j];
+                }
+                let x = self.state[i];
+                let bound = (options.epsilon * x / orders[i].g(x)).max(1.0);
+                tau1 = tau1.min(bound / mean.abs()).min(bound * bound / variance);
+            }
+
+            if tau1 < options.ssa_factor / a0 {
+                self.direct_firings(end, options.ssa_steps);
+                continue;
+            }
+
+            let critical_total: f64 = (0..m).filter(|&j| critical[j]).map(|j| a[j]).sum();
+            loop {
+                let tau2 = self.rng.exponential(critical_total);
+                let (mut tau, mut fires_critical) = if tau1 < tau2 { (tau1, false) } else { (tau2, true) };
+                if self.t + tau > end {
+                    (tau, fires_critical) = (end - self.t, false);
+                }
+                let mut next = self.state.clone();
+                for j in (0..m).filter(|&j| !critical[j] && a[j] > 0.0) {
+                    let k = self.rng.poisson(a[j] * tau);
+                    next.scaled_add(k, &stoich.column(j));
+                }
+                if fires_critical {
+                    let mut target = self.rng.uniform() * critical_total;
+                    let mut chosen = None;
+                    for j in (0..m).filter(|&j| critical[j]) {
+                        chosen = Some(j);
+                        if target < a[j] {
+                            break;
+                        }
+                        target -= a[j];
+                    }
+                    if let Some(j) = chosen {
+                        next.scaled_add(1.0, &stoich.column(j));
+                    }
+                }
+                if next.iter().any(|&x| x < 0.0) {
+                    tau1 /= 2.0;
+                    continue;
+                }
+                self.state = next;
+                self.t += tau;
+                self.apply_assignment_rules();
+                break;
+            }
+        }
+    }
+}
+
+// =============================================================================
+// TESTS
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tau_leaping(model: SbmlModel, seed: u64) -> CopasiSimulation {
+        let mut sim = CopasiSimulation::new(model);
+        sim.set_method(SimulationMethod::TauLeaping);
+        sim.set_seed(seed);
+        sim
+    }
+
+    #[test]
+    fn test_decay_mean() {
+        // 10^5 molecules decaying at rate 1: e^-1 of them left at t = 1
+        let mut model = SbmlModel::new("decay");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("X", "c", 1e5));
+        model.add_parameter(Parameter::new("k", 1.0));
+        let mut decay = Reaction::simple("decay", "X", "X", "k");
+        decay.products.clear();
+        model.add_reaction(decay);
+        let result = tau_leaping(model, 3).run(1.0, 10);
+        let x = *result.concentrations["X"].last().unwrap();
+        assert!((x - 1e5 * (-1.0f64).exp()).abs() < 1000.0, "{}", x);
+    }
+
+    #[test]
+    fn test_no_negative_populations() {
+        // A + B -> C consumes the scarcer B to exactly zero
+        let mut model = SbmlModel::new("binding");
+        model.add_compartment(Compartment::new("c", 1.0));
+        model.add_species(Species::new("A", "c", 5000.0));
+        model.add_species(Species::new("B", "c", 300.0));
+        model.add_species(Species::new("C", "c", 0.0));
+        model.add_parameter(Parameter::new("k", 0.01));
+        let mut bind = Reaction::simple("bind", "A", "C", "k");
+        bind.reactants.push(SpeciesReference::new("B", 1.0));
+        model.add_reaction(bind);
+        for seed in 0..5 {
+            let result = tau_leaping(model.clone(), seed).run(2.0, 40);
+            for (b, c) in result.concentrations["B"].iter().zip(&result.concentrations["C"]) {
+                assert!(*b >= 0.0);
+                assert_eq!(b + c, 300.0);
+            }
+            assert_eq!(*result.concentrations["B"].last().unwrap(), 0.0);
+            assert_eq!(*result.concentrations["A"].last().unwrap(), 4700.0);
+        }
+    }
+
+    #[test]
+    fn test_g() {
+        let unimolecular = Order { order: 1.0, molecules: 1.0 };
+        let dimerizing = Order { order: 2.0, molecules: 2.0 };
+        assert_eq!(unimolecular.g(100.0), 1.0);
+        assert_eq!(Order { order: 2.0, molecules: 1.0 }.g(100.0), 2.0);
+        assert!((dimerizing.g(11.0) - 2.1).abs() < 1e-12);
+        assert!((Order { order: 3.0, molecules: 3.0 }.g(3.0) - 5.5).abs() < 1e-12);
+    }
+}
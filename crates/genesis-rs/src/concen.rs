@@ -0,0 +1,144 @@
+//! Calcium concentration (`Ca_concen`)
+//!
+//! A single-shell pool whose concentration `Ca` follows the calcium
+//! current of its channels, sent with `addmsg chan pool I_Ca Ik`, and
+//! decays to `Ca_base` with time constant `tau`:
+//!
+//! `dCa/dt = B I_Ca - (Ca - Ca_base) / tau`
+//!
+//! `B` scales current to concentration, usually `1 / (2 F vol)` for a
+//! shell of volume `vol` (`area * thick`). Channels with a
+//! concentration-dependent Z gate (`Z_conc` set) read the pool with
+//! `addmsg pool chan CONCEN Ca`, as K_Ca channels do.
+
+use crate::{messages, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Time};
+
+/// Start every pool at its base concentration
+pub(crate) fn reset(sim: &mut GenesisSimulation) {
+    for element in sim.elements.values_mut() {
+        if matches!(element.element_type, ElementType::CaConcen) {
+            let base = element.get_param("Ca_base").unwrap_or(0.0);
+            element.set_param("Ca", base);
+        }
+    }
+}
+
+/// Advance the concentrations of `pools` by `dt` with exponential Euler
+pub(crate) fn step(sim: &mut GenesisSimulation, pools: &[String], dt: Time) -> Result<()> {
+    for path in pools {
+        let pool = &sim.elements[path];
+        let field = |name: &str| pool.get_param(name).unwrap_or(0.0);
+        let tau = field("tau");
+        if tau <= 0.0 {
+            return Err(OldiesError::SimulationError(format!("{} needs a positive tau", path)));
+        }
+        let current: f64 = messages::inputs(sim, pool, "I_Ca")?.iter().map(|(_, slots)| slots[0]).sum();
+        let steady = field("Ca_base") + field("B") * current * tau;
+        let ca = steady + (field("Ca") - steady) * (-dt / tau).exp();
+        sim.elements.get_mut(path).unwrap().set_param("Ca", ca);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    fn field(sli: &Sli, path: &str, name: &str) -> f64 {
+        sli.sim.get(path).unwrap().get_param(name).unwrap()
+    }
+
+    #[test]
+    fn test_pool_follows_current() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create neutral /src
+            addfield /src I
+            setfield /src I 2e-9
+            create Ca_concen /Ca
+            setfield /Ca Ca_base 5e-5 tau 0.02 B 1e6
+            addmsg /src /Ca I_Ca I
+            create tabchannel /KC
+            setfield /KC Zpower 1 Z_conc 1 Gbar 1e-8 Ek -0.08
+            // alpha = 2e4 Ca, beta = 10
+            setupalpha /KC Z 0 2e4 0 0 1e12  10 0 0 0 1e12 -size 1000 -range 0 1e-4
+            addmsg /Ca /KC CONCEN Ca
+            create compartment /soma
+            setfield /soma Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07
+            addmsg /soma /KC VOLTAGE Vm
+            setclock 0 1e-4
+            reset
+        "#).unwrap();
+        let z_inf = |ca: f64| 2e4 * ca / (2e4 * ca + 10.0);
+        assert_eq!(field(&sli, "/Ca", "Ca"), 5e-5);
+        assert!((field(&sli, "/KC", "Z") - z_inf(5e-5)).abs() < 1e-6);
+
+        // One time constant brings the pool 63% of the way to base + B I tau
+        sli.execute("step 0.02 -time").unwrap();
+        let ca = field(&sli, "/Ca", "Ca");
+        let expected = 5e-5 + 4e-5 * (1.0 - (-1f64).exp());
+        assert!((ca - expected).abs() < 1e-9, "{} {}", ca, expected);
+        sli.execute("step 0.5 -time").unwrap();
+        assert!((field(&sli, "/Ca", "Ca") - 9e-5).abs() < 1e-12);
+        assert!((field(&sli, "/KC", "Z") - z_inf(9e-5)).abs() < 1e-3);
+        assert!(field(&sli, "/KC", "Gk") > 0.0);
+
+        assert!(sli.execute("create tabchannel /lost; setfield /lost Zpower 1 Z_conc 1; \
+            setupalpha /lost Z 0 1 0 0 1 1 0 0 0 1; addmsg /soma /lost VOLTAGE Vm; reset").is_err());
+        assert!(sli.execute("setfield /Ca tau 0; step").is_err());
+    }
+
+    #[test]
+    fn test_afterhyperpolarization_adapts_firing() {
+        fn spikes(ahp: f64) -> Vec<usize> {
+            let mut sli = Sli::new();
+            sli.execute(&format!(r#"
+                create compartment /axon
+                setfield /axon Cm 7.854e-9 Rm 4.244e5 Em -0.0594 initVm -0.07 inject 7.854e-8
+                create Na_squid_hh /axon/Na
+                create K_squid_hh /axon/K
+                setfield /axon/Na Gbar 9.425e-4
+                setfield /axon/K Gbar 2.827e-4
+                // High-threshold calcium channel
+                create tabchannel /axon/Ca
+                setfield /axon/Ca Ek 0.075 Gbar 2e-6 Xpower 1
+                setupalpha /axon/Ca X 1600 0 1 -0.005 -0.0139  100 0 0 0 1e12
+                create Ca_concen /axon/pool
+                setfield /axon/pool tau 0.05 B 5e4
+                // Calcium-activated potassium channel
+                create tabchannel /axon/AHP
+                setfield /axon/AHP Ek -0.082 Gbar {} Zpower 1 Z_conc 1
+                setupalpha /axon/AHP Z 0 2e3 0 0 1e12  20 0 0 0 1e12 -size 1000 -range 0 0.1
+                addmsg /axon/Ca /axon/pool I_Ca Ik
+                addmsg /axon/pool /axon/AHP CONCEN Ca
+                addmsg /axon /axon/Na VOLTAGE Vm
+                addmsg /axon/Na /axon CHANNEL Gk Ek
+                addmsg /axon /axon/K VOLTAGE Vm
+                addmsg /axon/K /axon CHANNEL Gk Ek
+                addmsg /axon /axon/Ca VOLTAGE Vm
+                addmsg /axon/Ca /axon CHANNEL Gk Ek
+                addmsg /axon /axon/AHP VOLTAGE Vm
+                addmsg /axon/AHP /axon CHANNEL Gk Ek
+                reset
+            "#, ahp)).unwrap();
+            let mut times = vec![];
+            let mut previous = -0.07;
+            for step in 0..10000 {
+                sli.sim.step().unwrap();
+                let vm = field(&sli, "/axon", "Vm");
+                if previous < 0.0 && vm >= 0.0 {
+                    times.push(step);
+                }
+                previous = vm;
+            }
+            times
+        }
+        let (plain, adapting) = (spikes(0.0), spikes(1.5e-4));
+        assert!(plain.len() > adapting.len() && adapting.len() >= 2, "{:?} {:?}", plain, adapting);
+        // Intervals lengthen as calcium builds up
+        let first = adapting[1] - adapting[0];
+        let last = adapting[adapting.len() - 1] - adapting[adapting.len() - 2];
+        assert!(last > first, "{:?}", adapting);
+    }
+}
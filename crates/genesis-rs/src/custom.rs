@@ -0,0 +1,238 @@
+//! User-defined objects
+//!
+//! GENESIS could be extended with libraries of compiled objects, each
+//! declaring its fields, the messages it accepts and the action run at
+//! every step. A crate extends genesis-rs the same way: it implements
+//! [`CustomObject`] and registers it in the simulation's [`Registry`]
+//! (`sim.registry.register(Integrator)?`). Scripts then `create` the
+//! object by its name like any other, making elements of type
+//! [`ElementType::Custom`], which are set up at `reset` and processed
+//! after the calcium pools of each step, before the compartments (see
+//! [`crate::schedule`]).
+//!
+//! Message types an object declares are accepted only by its elements and
+//! carry the slots it asks for; the standard types (see
+//! [`crate::messages`]) can be sent to them too.
+
+use crate::{messages, Element, ElementType, GenesisSimulation, Message};
+use oldies_core::{OldiesError, Result, Time};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Behavior of a user-defined object
+pub trait CustomObject: Send + Sync {
+    /// Name scripts create the object by
+    fn name(&self) -> &str;
+
+    /// Give a new element its fields, with `set_param` for numbers and
+    /// `strings` for text
+    fn init(&self, element: &mut Element);
+
+    /// Message types its elements accept, with their numbers of slots
+    fn messages(&self) -> &[(&str, usize)] {
+        &[]
+    }
+
+    /// Return an element to its initial state, after fields with an `init`
+    /// counterpart have taken their initial values
+    fn reset(&self, _element: &mut Element) -> Result<()> {
+        Ok(())
+    }
+
+    /// Advance an element by `dt`, given the messages arriving at it and
+    /// the present values of their slots
+    fn process(&self, element: &mut Element, inputs: &[(Message, Vec<f64>)], dt: Time) -> Result<()>;
+}
+
+/// Objects registered with a simulation, by name
+#[derive(Clone, Default)]
+pub struct Registry {
+    objects: BTreeMap<String, Arc<dyn CustomObject>>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.objects.keys()).finish()
+    }
+}
+
+impl Registry {
+    /// Add `object`, which may not take the name of a standard object or
+    /// of one already registered
+    pub fn register(&mut self, object: impl CustomObject + 'static) -> Result<()> {
+        let name = object.name().to_string();
+        if self.objects.contains_key(&name) || crate::objects::fields(&name).is_ok() {
+            return Err(OldiesError::SimulationError(format!("object {} already exists", name)));
+        }
+        for (msg_type, _) in object.messages() {
+            if messages::MESSAGE_TYPES.iter().any(|(standard, _)| standard == msg_type) {
+                return Err(OldiesError::SimulationError(format!(
+                    "{} redefines the message type {}", name, msg_type
+                )));
+            }
+        }
+        self.objects.insert(name, Arc::new(object));
+        Ok(())
+    }
+
+    /// Object registered as `name`
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn CustomObject>> {
+        self.objects.get(name)
+    }
+
+    /// Names of the registered objects, sorted
+    pub fn names(&self) -> Vec<&str> {
+        self.objects.keys().map(String::as_str).collect()
+    }
+}
+
+/// Object of the custom element `element`
+fn object_of(sim: &GenesisSimulation, element: &Element) -> Result<Arc<dyn CustomObject>> {
+    let ElementType::Custom(name) = &element.element_type else {
+        return Err(OldiesError::SimulationError(format!("{} is not a custom element", element.path)));
+    };
+    sim.registry.get(name).cloned()
+        .ok_or_else(|| OldiesError::ModelNotFound(format!("GENESIS object '{}'", name)))
+}
+
+/// Create an element of the registered object `name`, if there is one
+pub(crate) fn create<'a>(sim: &'a mut GenesisSimulation, name: &str, path: &str) -> Option<&'a mut Element> {
+    let object = sim.registry.get(name)?.clone();
+    let element = sim.create(path, ElementType::Custom(name.to_string()));
+    object.init(element);
+    Some(element)
+}
+
+/// Slots of messages of type `msg_type` to `dest`, when its object
+/// declares the type
+pub(crate) fn message_slots(sim: &GenesisSimulation, dest: &str, msg_type: &str) -> Option<usize> {
+    let object = object_of(sim, sim.elements.get(dest)?).ok()?;
+    object.messages().iter().find(|(name, _)| *name == msg_type).map(|&(_, slots)| slots)
+}
+
+/// Reset every custom element
+pub(crate) fn reset(sim: &mut GenesisSimulation) -> Result<()> {
+    for path in sim.paths() {
+        if matches!(sim.elements[&path].element_type, ElementType::Custom(_)) {
+            let object = object_of(sim, &sim.elements[&path])?;
+            object.reset(sim.elements.get_mut(&path).unwrap())?;
+        }
+    }
+    Ok(())
+}
+
+/// Process the custom elements `paths` for a step of `dt`
+pub(crate) fn step(sim: &mut GenesisSimulation, paths: &[String], dt: Time) -> Result<()> {
+    for path in paths {
+        let element = &sim.elements[path];
+        let object = object_of(sim, element)?;
+        let inputs = element.messages_in.iter()
+            .map(|m| Ok((m.clone(), messages::values(sim, m)?)))
+            .collect::<Result<Vec<_>>>()?;
+        object.process(sim.elements.get_mut(path).unwrap(), &inputs, dt)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    /// Leaky integrator of the values sent with DRIVE messages:
+    /// `tau dvalue/dt = gain * drive - value`
+    struct Integrator;
+
+    impl CustomObject for Integrator {
+        fn name(&self) -> &str {
+            "integrator"
+        }
+
+        fn init(&self, element: &mut Element) {
+            for (field, value) in [("value", 0.0), ("initvalue", 0.0), ("gain", 1.0), ("tau", 0.01)] {
+                element.set_param(field, value);
+            }
+        }
+
+        fn messages(&self) -> &[(&str, usize)] {
+            &[("DRIVE", 1)]
+        }
+
+        fn process(&self, element: &mut Element, inputs: &[(Message, Vec<f64>)], dt: Time) -> Result<()> {
+            let field = |name: &str| element.get_param(name).unwrap_or(0.0);
+            let drive: f64 = inputs.iter().filter(|(m, _)| m.msg_type == "DRIVE").map(|(_, slots)| slots[0]).sum();
+            let steady = field("gain") * drive;
+            let value = steady + (field("value") - steady) * (-dt / field("tau")).exp();
+            element.set_param("value", value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_registered_object() {
+        let mut sli = Sli::new();
+        sli.sim.registry.register(Integrator).unwrap();
+        sli.execute(r#"
+            create neutral /src
+            addfield /src I
+            setfield /src I 1e-10
+            create integrator /smooth -gain 2 -initvalue 1e-10
+            addmsg /src /smooth DRIVE I
+            create compartment /soma
+            setfield /soma Rm 1e8 Cm 1e-11 Em 0 initVm 0
+            // The integrator's output drives the cell
+            addmsg /smooth /soma INJECT value
+            setclock 0 1e-4
+            reset
+        "#).unwrap();
+        let field = |sli: &Sli, path: &str, name: &str| sli.sim.get(path).unwrap().get_param(name).unwrap();
+        assert_eq!(field(&sli, "/smooth", "value"), 1e-10);
+
+        // One time constant brings it 63% of the way to gain * drive
+        sli.execute("step 0.01 -time").unwrap();
+        let expected = 2e-10 - 1e-10 * (-1f64).exp();
+        assert!((field(&sli, "/smooth", "value") - expected).abs() < 1e-15);
+        sli.execute("step 0.2 -time").unwrap();
+        assert!((field(&sli, "/soma", "Vm") - 0.02).abs() < 1e-5, "{}", field(&sli, "/soma", "Vm"));
+
+        // It behaves as any element to the rest of the language
+        assert_eq!(sli.call("getfield /smooth gain").unwrap(), "2");
+        assert_eq!(sli.call("el /#[TYPE=integrator]").unwrap(), "/smooth");
+        sli.execute("showsched").unwrap();
+        assert!(sli.output.contains("1 user objects"), "{}", sli.output);
+        sli.execute("reset").unwrap();
+        assert_eq!(field(&sli, "/smooth", "value"), 1e-10);
+        assert_eq!(sli.sim.registry.names(), ["integrator"]);
+    }
+
+    #[test]
+    fn test_registry_checks() {
+        let mut sli = Sli::new();
+        sli.sim.registry.register(Integrator).unwrap();
+        assert!(sli.sim.registry.register(Integrator).is_err());
+        sli.execute("create compartment /soma; create integrator /i").unwrap();
+        // DRIVE is only known to integrators, and carries one slot
+        assert!(sli.execute("addmsg /soma /soma DRIVE Vm").is_err());
+        assert!(sli.execute("addmsg /soma /i DRIVE Vm Em").is_err());
+        sli.execute("addmsg /soma /i DRIVE Vm").unwrap();
+
+        struct Impostor;
+        impl CustomObject for Impostor {
+            fn name(&self) -> &str {
+                "compartment"
+            }
+            fn init(&self, _: &mut Element) {}
+            fn process(&self, _: &mut Element, _: &[(Message, Vec<f64>)], _: Time) -> Result<()> {
+                Ok(())
+            }
+        }
+        assert!(sli.sim.registry.register(Impostor).is_err());
+
+        // A simulation without the object cannot run its elements
+        let mut sim = GenesisSimulation::new();
+        sim.create("/i", ElementType::Custom("integrator".into()));
+        assert!(sim.reset().is_err());
+        assert!(Sli::new().execute("create integrator /i").is_err());
+    }
+}
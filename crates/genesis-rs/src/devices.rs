@@ -0,0 +1,317 @@
+//! Stimulus devices (`pulsegen`, `randomspike`, `noisegen`)
+//!
+//! Devices compute an input for the rest of the model on their clock,
+//! before any other element of the step sees it:
+//!
+//! - A `pulsegen` puts out `baselevel`, then `level1` for `width1` seconds
+//!   starting `delay1` after the start of its cycle, and `level2` for
+//!   `width2` starting `delay2` after the start of the first pulse. With
+//!   `trig_mode` 0 it runs freely with a period of
+//!   `delay1 + max(width1, delay2 + width2)` (`delay2 999` gives a single
+//!   pulse); with 1 the cycle starts, once, when its INPUT turns nonzero;
+//!   with 2 it runs freely from then on while the INPUT stays nonzero. The
+//!   level over a step is the one at its middle.
+//! - A `randomspike` fires as a Poisson process of `rate` spikes per
+//!   second, at most once every `abs_refract` seconds. Its `state` is an
+//!   amplitude drawn between `min_amp` and `max_amp` on the steps it fires,
+//!   and `reset_value` on the others unless `reset` is 0, and its SPIKE
+//!   messages give synchans synapses as a spikegen's do (see
+//!   [`crate::synapse`]).
+//! - A `noisegen`, which genesis-rs adds to the GENESIS objects for
+//!   background noise currents, puts out Gaussian values of mean `mean`
+//!   and standard deviation `sd`: independent on every tick when `tau` is
+//!   0, and otherwise an Ornstein-Uhlenbeck process with correlation time
+//!   `tau`.
+//!
+//! `addmsg /pulse /soma INJECT output` injects a device's `output` into a
+//! compartment. The random devices draw from the simulation's generator,
+//! so `randseed` makes their input, like the rest of a run, reproducible
+//! (see [`oldies_core::random`]).
+
+use crate::{messages, synapse, Element, ElementType, GenesisSimulation};
+use oldies_core::{Result, Time};
+
+fn field(element: &Element, name: &str) -> f64 {
+    element.get_param(name).unwrap_or(0.0)
+}
+
+/// Whether `element` is a device
+pub fn is_device(element: &Element) -> bool {
+    matches!(element.element_type, ElementType::PulseGen | ElementType::RandomSpike | ElementType::NoiseGen)
+}
+
+/// Put devices in their initial state
+pub(crate) fn reset(sim: &mut GenesisSimulation) -> Result<()> {
+    for path in sim.paths() {
+        let element = &sim.elements[&path];
+        let fields = match element.element_type {
+            ElementType::PulseGen => {
+                let trigger = input(sim, element)?.unwrap_or(0.0);
+                let base = field(element, "baselevel");
+                vec![("output", base), ("previous", trigger), ("trig_time", -1.0)]
+            }
+            ElementType::RandomSpike => {
+                let refract = field(element, "abs_refract");
+                vec![("state", field(element, "reset_value")), ("lastevent", -refract)]
+            }
+            ElementType::NoiseGen => vec![("output", field(element, "mean"))],
+            _ => continue,
+        };
+        let element = sim.elements.get_mut(&path).unwrap();
+        for (name, value) in fields {
+            element.set_param(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Trigger or gate of a pulsegen, if it has an INPUT message
+fn input(sim: &GenesisSimulation, pulsegen: &Element) -> Result<Option<f64>> {
+    Ok(messages::inputs(sim, pulsegen, "INPUT")?.first().map(|(_, slots)| slots[0]))
+}
+
+/// Level of `pulsegen` `time` after the start of its cycle
+fn level(pulsegen: &Element, time: Time) -> f64 {
+    let (delay1, width1) = (field(pulsegen, "delay1"), field(pulsegen, "width1"));
+    let start2 = delay1 + field(pulsegen, "delay2");
+    if time >= delay1 && time < delay1 + width1 {
+        field(pulsegen, "level1")
+    } else if time >= start2 && time < start2 + field(pulsegen, "width2") {
+        field(pulsegen, "level2")
+    } else {
+        field(pulsegen, "baselevel")
+    }
+}
+
+/// Period of a freely running `pulsegen`
+fn period(pulsegen: &Element) -> Time {
+    let second = field(pulsegen, "delay2") + field(pulsegen, "width2");
+    field(pulsegen, "delay1") + field(pulsegen, "width1").max(second)
+}
+
+/// Advance the devices `paths` by a step of `dt`
+pub(crate) fn step(sim: &mut GenesisSimulation, paths: &[String], dt: Time) -> Result<()> {
+    let middle = sim.time + 0.5 * dt;
+    for path in paths {
+        let element = &sim.elements[path];
+        match element.element_type {
+            ElementType::PulseGen => {
+                let trigger = input(sim, element)?.unwrap_or(0.0);
+                let opened = trigger != 0.0 && field(element, "previous") == 0.0;
+                let mode = field(element, "trig_mode");
+                let mut start = field(element, "trig_time");
+                if opened && (mode == 2.0 || (mode == 1.0 && start < 0.0)) {
+                    start = sim.time;
+                }
+                let running = match mode {
+                    0.0 => Some(middle % period(element)),
+                    1.0 if start >= 0.0 => Some(middle - start),
+                    2.0 if trigger != 0.0 => Some((middle - start) % period(element)),
+                    _ => None,
+                };
+                let output = match running {
+                    Some(time) => level(element, time),
+                    None => field(element, "baselevel"),
+                };
+                let pulsegen = sim.elements.get_mut(path).unwrap();
+                pulsegen.set_param("output", output);
+                pulsegen.set_param("previous", trigger);
+                pulsegen.set_param("trig_time", start);
+            }
+            ElementType::RandomSpike => {
+                let ready = sim.time - field(element, "lastevent") + 0.5 * dt >= field(element, "abs_refract");
+                let probability = -(-field(element, "rate") * dt).exp_m1();
+                let fire = ready && sim.rng.uniform() < probability;
+                let state = if fire {
+                    let (low, high) = (field(element, "min_amp"), field(element, "max_amp"));
+                    low + (high - low) * sim.rng.uniform()
+                } else if field(element, "reset") != 0.0 {
+                    field(element, "reset_value")
+                } else {
+                    field(element, "state")
+                };
+                let time = sim.time;
+                let randomspike = sim.elements.get_mut(path).unwrap();
+                randomspike.set_param("state", state);
+                if fire {
+                    randomspike.set_param("lastevent", time);
+                    synapse::send_spike(sim, path);
+                }
+            }
+            ElementType::NoiseGen => {
+                let (mean, sd, tau) = (field(element, "mean"), field(element, "sd"), field(element, "tau"));
+                let output = if tau > 0.0 {
+                    // Exact update of the process over the step
+                    let decay = (-dt / tau).exp();
+                    let spread = sd * (1.0 - decay * decay).sqrt();
+                    mean + (field(element, "output") - mean) * decay + spread * sim.rng.normal()
+                } else {
+                    mean + sd * sim.rng.normal()
+                };
+                sim.elements.get_mut(path).unwrap().set_param("output", output);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    fn field(sli: &Sli, path: &str, name: &str) -> f64 {
+        sli.sim.get(path).unwrap().get_param(name).unwrap()
+    }
+
+    /// Values of `name` of `path` over `steps` steps
+    fn trace(sli: &mut Sli, path: &str, name: &str, steps: usize) -> Vec<f64> {
+        (0..steps)
+            .map(|_| {
+                sli.sim.step().unwrap();
+                field(sli, path, name)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pulsegen_modes() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create pulsegen /pulse
+            setfield /pulse level1 1 width1 0.002 delay1 0.001 level2 2 width2 0.001 delay2 0.004 baselevel -1
+            setclock 0 0.001
+            reset
+        "#).unwrap();
+        // A period of 1 + max(2, 4 + 1) ms
+        assert_eq!(trace(&mut sli, "/pulse", "output", 12), [-1.0, 1.0, 1.0, -1.0, -1.0, 2.0, -1.0, 1.0, 1.0, -1.0, -1.0, 2.0]);
+
+        // Triggered once by the first rise of its input
+        sli.execute(r#"
+            create neutral /switch
+            addfield /switch on
+            addmsg /switch /pulse INPUT on
+            setfield /pulse trig_mode 1 delay2 999
+            reset
+        "#).unwrap();
+        assert_eq!(trace(&mut sli, "/pulse", "output", 3), [-1.0; 3]);
+        sli.execute("setfield /switch on 1").unwrap();
+        let triggered = trace(&mut sli, "/pulse", "output", 4);
+        sli.execute("setfield /switch on 0").unwrap();
+        assert_eq!(triggered, [-1.0, 1.0, 1.0, -1.0]);
+        assert!((field(&sli, "/pulse", "trig_time") - 0.003).abs() < 1e-12);
+        sli.execute("setfield /switch on 1").unwrap();
+        assert_eq!(trace(&mut sli, "/pulse", "output", 3), [-1.0; 3]);
+
+        // Gated: running while its input is on, restarting each time
+        sli.execute("setfield /pulse trig_mode 2; setfield /switch on 0; reset; step 2").unwrap();
+        sli.execute("setfield /switch on 1").unwrap();
+        let gated = trace(&mut sli, "/pulse", "output", 5);
+        sli.execute("setfield /switch on 0").unwrap();
+        assert_eq!(gated, [-1.0, 1.0, 1.0, -1.0, -1.0]);
+        assert_eq!(trace(&mut sli, "/pulse", "output", 2), [-1.0; 2]);
+    }
+
+    #[test]
+    fn test_pulsegen_current_clamp() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create compartment /soma
+            setfield /soma Rm 1e8 Cm 1e-10 Em 0 initVm 0
+            create pulsegen /pulse
+            setfield /pulse level1 1e-10 width1 0.05 delay1 0.02 delay2 999
+            addmsg /pulse /soma INJECT output
+            setclock 0 1e-5
+            reset
+            step 0.02 -time
+        "#).unwrap();
+        assert_eq!(field(&sli, "/soma", "Vm"), 0.0);
+        sli.execute("step 0.05 -time").unwrap();
+        let charged = 0.01 * (1.0 - (-5f64).exp());
+        assert!((field(&sli, "/soma", "Vm") - charged).abs() < 1e-6, "{}", field(&sli, "/soma", "Vm"));
+        sli.execute("step 0.05 -time").unwrap();
+        assert!(field(&sli, "/soma", "Vm") < 1e-4);
+    }
+
+    const BACKGROUND: &str = r#"
+        create randomspike /input
+        setfield /input rate 50 abs_refract 0.005 min_amp 1 max_amp 2
+        create compartment /cell
+        setfield /cell Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07
+        create synchan /cell/syn
+        setfield /cell/syn tau1 0.001 tau2 0.002 gmax 1e-9 Ek 0
+        addmsg /input /cell/syn SPIKE
+        addmsg /cell /cell/syn VOLTAGE Vm
+        addmsg /cell/syn /cell CHANNEL Gk Ek
+        create noisegen /noise
+        setfield /noise sd 1e-11 tau 0.002
+        addmsg /noise /cell INJECT output
+        setclock 0 1e-4
+    "#;
+
+    /// Steps `/input` fires on and the potential of `/cell` over 10 s run
+    /// after `randseed seed`
+    fn background(seed: u64) -> (Vec<usize>, Vec<f64>) {
+        let mut sli = Sli::new();
+        sli.execute(BACKGROUND).unwrap();
+        sli.execute(&format!("randseed {}; reset", seed)).unwrap();
+        let (mut fired, mut vm) = (vec![], vec![]);
+        for step in 0..100_000 {
+            sli.sim.step().unwrap();
+            let state = field(&sli, "/input", "state");
+            if state != 0.0 {
+                assert!((1.0..2.0).contains(&state));
+                fired.push(step);
+            }
+            vm.push(field(&sli, "/cell", "Vm"));
+        }
+        (fired, vm)
+    }
+
+    #[test]
+    fn test_random_input_is_seeded() {
+        let (fired, vm) = background(7);
+        // 50/s less the refractory time, 400 spikes in 10 s with a
+        // standard deviation under 20, and none within the refractory
+        // period of the last
+        assert!((340..460).contains(&fired.len()), "{}", fired.len());
+        assert!(fired.windows(2).all(|w| w[1] - w[0] >= 50));
+        assert!(vm.iter().any(|&v| v > -0.069));
+
+        assert_eq!(background(7), (fired.clone(), vm));
+        assert_ne!(background(8).0, fired);
+    }
+
+    #[test]
+    fn test_noise_statistics() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create noisegen /white
+            setfield /white mean 1 sd 0.5
+            create noisegen /colored
+            setfield /colored mean 1 sd 0.5 tau 0.001
+            setclock 0 1e-4
+            randseed 3
+            reset
+        "#).unwrap();
+        let (mut white, mut colored) = (vec![], vec![]);
+        for _ in 0..200_000 {
+            sli.sim.step().unwrap();
+            white.push(field(&sli, "/white", "output"));
+            colored.push(field(&sli, "/colored", "output"));
+        }
+        let stats = |x: &[f64], lag: usize| {
+            let n = x.len() as f64;
+            let mean = x.iter().sum::<f64>() / n;
+            let var = x.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+            let cov = x.windows(lag + 1).map(|w| (w[0] - mean) * (w[lag] - mean)).sum::<f64>() / n;
+            (mean, var.sqrt(), cov / var)
+        };
+        let (mean, sd, correlation) = stats(&white, 1);
+        assert!((mean - 1.0).abs() < 0.01 && (sd - 0.5).abs() < 0.01 && correlation.abs() < 0.02);
+        // Correlated over tau, at e^-1 one tau apart
+        let (mean, sd, correlation) = stats(&colored, 10);
+        assert!((mean - 1.0).abs() < 0.03 && (sd - 0.5).abs() < 0.02, "{} {}", mean, sd);
+        assert!((correlation - (-1f64).exp()).abs() < 0.03, "{}", correlation);
+    }
+}
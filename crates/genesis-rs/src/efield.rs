@@ -0,0 +1,165 @@
+//! Extracellular field potentials (`efield`)
+//!
+//! An `efield` is an electrode at its `x`, `y`, `z` in a homogeneous
+//! conducting medium. Compartments send it their membrane current
+//! (`addmsg /cell/soma /electrode CURRENT Im`, see [`crate::solver`]) and
+//! it sums them as point sources at the compartments' positions:
+//! `field = scale * sum Im / r`, with `r` the distance from the electrode
+//! and `scale = 1 / (4 pi sigma)` for the conductivity `sigma` of the
+//! medium, 0.3 S/m unless set. A compartment closer to the electrode than
+//! its radius counts as at its surface.
+//!
+//! Electrodes are updated after the compartments of their clock, so the
+//! field of a step is ready for output in the same step.
+
+use crate::{messages, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result};
+use std::f64::consts::PI;
+
+/// Conductivity of the medium electrodes are in by default (S/m)
+pub const CONDUCTIVITY: f64 = 0.3;
+
+/// Default `scale` of an efield, `1 / (4 pi CONDUCTIVITY)` (ohm m)
+pub fn default_scale() -> f64 {
+    1.0 / (4.0 * PI * CONDUCTIVITY)
+}
+
+fn field(element: &Element, name: &str) -> f64 {
+    element.get_param(name).unwrap_or(0.0)
+}
+
+/// Distance between the positions of two elements
+fn distance(a: &Element, b: &Element) -> f64 {
+    ["x", "y", "z"].iter().map(|axis| (field(a, axis) - field(b, axis)).powi(2)).sum::<f64>().sqrt()
+}
+
+/// Potential at `efield` of the currents it receives
+pub fn potential(sim: &GenesisSimulation, efield: &Element) -> Result<f64> {
+    let mut sum = 0.0;
+    for (msg, slots) in messages::inputs(sim, efield, "CURRENT")? {
+        let source = &sim.elements[&msg.source];
+        let r = distance(efield, source).max(field(source, "dia") / 2.0);
+        if r <= 0.0 {
+            return Err(OldiesError::SimulationError(format!(
+                "{} is at the point source {}; give it a dia", efield.path, source.path
+            )));
+        }
+        sum += slots[0] / r;
+    }
+    Ok(field(efield, "scale") * sum)
+}
+
+/// Clear the fields of the electrodes
+pub(crate) fn reset(sim: &mut GenesisSimulation) {
+    for element in sim.elements.values_mut() {
+        if matches!(element.element_type, ElementType::EField) {
+            element.set_param("field", 0.0);
+        }
+    }
+}
+
+/// Update the fields of `efields` from the currents of the step just taken
+pub(crate) fn step(sim: &mut GenesisSimulation, efields: &[String]) -> Result<()> {
+    for path in efields {
+        let value = potential(sim, &sim.elements[path])?;
+        sim.elements.get_mut(path).unwrap().set_param("field", value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    /// A passive soma and dendrite along x, current injected into the soma
+    /// and an electrode 20 um off the soma
+    const DIPOLE: &str = r#"
+        create compartment /soma
+        setfield /soma Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07 inject 1e-10 dia 2e-5
+        create compartment /dend
+        setfield /dend Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07 Ra 1e7 x 1e-4 dia 2e-6
+        addmsg /soma /dend AXIAL Vm
+        addmsg /dend /soma RAXIAL Ra Vm
+        create efield /electrode
+        setfield /electrode y 2e-5
+        addmsg /soma /electrode CURRENT Im
+        addmsg /dend /electrode CURRENT Im
+        setclock 0 1e-5
+    "#;
+
+    fn field(sli: &Sli, path: &str, name: &str) -> f64 {
+        sli.sim.get(path).unwrap().get_param(name).unwrap()
+    }
+
+    #[test]
+    fn test_dipole_field() {
+        for solve in ["", "create hsolve /solve; setfield /solve path /##[TYPE=compartment]; call /solve SETUP"] {
+            let mut sli = Sli::new();
+            sli.execute(DIPOLE).unwrap();
+            sli.execute(solve).unwrap();
+            sli.execute("reset").unwrap();
+            assert_eq!(field(&sli, "/electrode", "field"), 0.0);
+            for _ in 0..100 {
+                sli.sim.step().unwrap();
+                // Current leaves the cell where it is injected
+                let (soma, dend) = (field(&sli, "/soma", "Im"), field(&sli, "/dend", "Im"));
+                assert!((soma + dend - 1e-10).abs() < 1e-22, "{} {}", soma, dend);
+            }
+            sli.execute("step 0.1 -time").unwrap();
+
+            // At steady state the current divides between the soma's
+            // membrane and the dendrite's, behind its axial resistance
+            let (soma, dend) = (field(&sli, "/soma", "Im"), field(&sli, "/dend", "Im"));
+            assert!((dend - 1e-10 / 2.1).abs() < 1e-15, "{}", dend);
+            let expected = default_scale() * (soma / 2e-5 + dend / (1e-4f64.powi(2) + 2e-5f64.powi(2)).sqrt());
+            assert!((field(&sli, "/electrode", "field") - expected).abs() < 1e-12);
+            assert!(expected > 5e-7);
+            sli.execute("setfield /electrode scale 1").unwrap();
+            let scaled = potential(&sli.sim, sli.sim.get("/electrode").unwrap()).unwrap();
+            assert!((scaled - expected / default_scale()).abs() < 1e-9);
+        }
+
+        // Inside the soma the potential is that at its surface
+        let mut sli = Sli::new();
+        sli.execute(DIPOLE).unwrap();
+        sli.execute("setfield /electrode y 0; reset; step 0.1 -time").unwrap();
+        let surface = default_scale() * field(&sli, "/soma", "Im") / 1e-5;
+        assert!(field(&sli, "/electrode", "field") > surface);
+        sli.execute("setfield /soma dia 0").unwrap();
+        assert!(sli.execute("step").is_err());
+    }
+
+    #[test]
+    fn test_extracellular_spike() {
+        let mut sli = Sli::new();
+        sli.sim.set_clock(0, 5e-5).unwrap();
+        sli.sim.set_dt(5e-5);
+        crate::models::traub91(&mut sli.sim, "/cell").unwrap();
+        sli.execute(r#"
+            create efield /electrode
+            setfield /electrode x {getfield /cell/soma x} y 5e-5
+            foreach comp ({el /cell/#[TYPE=compartment]})
+                addmsg {comp} /electrode CURRENT Im
+            end
+            reset
+            step 0.02 -time
+            setfield /cell/soma inject 2e-10
+        "#).unwrap();
+        let compartments = crate::wildcard::find(&sli.sim, "/cell/#[TYPE=compartment]").unwrap();
+        let (mut lowest, mut when) = (0.0, 0.0);
+        for _ in 0..400 {
+            sli.sim.step().unwrap();
+            let total: f64 = compartments.iter().map(|c| field(&sli, c, "Im")).sum();
+            assert!((total - 2e-10).abs() < 1e-16, "{}", total);
+            let potential = field(&sli, "/electrode", "field");
+            if potential < lowest {
+                (lowest, when) = (potential, sli.sim.current_time());
+            }
+        }
+        // The sodium current of the somatic spike at 30 ms makes a negative
+        // extracellular spike
+        assert!(lowest < -1e-5, "{}", lowest);
+        assert!((when - 0.03).abs() < 5e-4, "{}", when);
+    }
+}
@@ -0,0 +1,516 @@
+//! Hines solver (`hsolve`)
+//!
+//! An `hsolve` element takes over the compartments its `path` names,
+//! usually a wildcard path (see [`crate::wildcard`]), with the channels
+//! they hold, and integrates the whole cell in one solve per step:
+//!
+//! ```text
+//! create hsolve /cell/solve
+//! setfield /cell/solve path /cell/##[TYPE=compartment]
+//! call /cell/solve SETUP
+//! reset
+//! ```
+//!
+//! SETUP numbers the compartments in Hines order, children before
+//! parents, so that the Crank-Nicolson step of [`crate::solver`] is one
+//! sweep up the tree and one back down, and folds the channels in: their
+//! rates are looked up in tables, their own for tabchannels and tabulated
+//! over [`tabchannel::DEFAULT_RANGE`] for HH channels. The elements the
+//! solver owns leave the schedule (see [`crate::schedule`]) and run on
+//! the solver's clock; their fields are written back every step, so
+//! messages, output and `getfield` see them as before.
+//!
+//! Fields of the owned elements (`Cm`, `Rm`, `Ra`, `Gbar`, ...) are read
+//! at SETUP and `reset`. `inject` and messages from elements outside the
+//! solver (synchans, other compartments, CONCEN) are read every step.
+//! `chanmode` is accepted for old scripts; every mode solves the same way.
+
+use crate::{messages, solver, tabchannel, wildcard, Element, ElementType, GenesisSimulation, Message, Table};
+use oldies_core::{OldiesError, Result, Time};
+use std::collections::{HashMap, HashSet};
+
+/// A message from outside the solver, read every step
+#[derive(Debug, Clone, Copy)]
+enum Input {
+    /// Conductance and reversal potential of a channel
+    Channel,
+    /// Injected current
+    Inject,
+    /// Potential in slot `slot` of a compartment coupled through `ra`
+    Axial { ra: f64, slot: usize },
+}
+
+#[derive(Debug)]
+struct Gate {
+    field: &'static str,
+    power: f64,
+    instant: bool,
+    a: Table,
+    b: Table,
+    /// CONCEN message the gate takes its rates at, instead of the potential
+    conc: Option<Message>,
+    state: f64,
+}
+
+#[derive(Debug)]
+struct Channel {
+    path: String,
+    /// Index of the compartment in Hines order
+    compartment: usize,
+    gbar: f64,
+    ek: f64,
+    gates: Vec<Gate>,
+}
+
+/// A cell set up for an `hsolve` element
+#[derive(Debug)]
+pub struct Hines {
+    /// Compartments given at SETUP
+    requested: Vec<String>,
+    /// Compartments in Hines order
+    compartments: Vec<String>,
+    parent: Vec<Option<usize>>,
+    /// Entry of row `k` in the column of its parent
+    lower: Vec<f64>,
+    /// Entry of the row of the parent of `k` in column `k`
+    upper: Vec<f64>,
+    cm: Vec<f64>,
+    /// Leak and axial conductance
+    g: Vec<f64>,
+    /// Leak current at 0 V, `Em / Rm`
+    leak: Vec<f64>,
+    /// Leak conductance alone, `1 / Rm`
+    gm: Vec<f64>,
+    inputs: Vec<(usize, Input, Message)>,
+    channels: Vec<Channel>,
+    owned: HashSet<String>,
+    v: Vec<f64>,
+    /// Membrane current over the last step
+    im: Vec<f64>,
+}
+
+impl Hines {
+    /// Set up the cell of `compartments` from their fields and messages
+    fn build(sim: &GenesisSimulation, compartments: Vec<String>) -> Result<Hines> {
+        let error = |msg: String| OldiesError::SimulationError(msg);
+        let index: HashMap<&str, usize> = compartments.iter().enumerate().map(|(i, p)| (p.as_str(), i)).collect();
+        let n = compartments.len();
+        let field = |e: &Element, name: &str| e.get_param(name).unwrap_or(0.0);
+        let (mut cm, mut g, mut leak, mut v) = (vec![0.0; n], vec![0.0; n], vec![0.0; n], vec![0.0; n]);
+        let mut gm = vec![0.0; n];
+        let mut off: HashMap<(usize, usize), f64> = HashMap::new();
+        let mut inputs = vec![];
+        let mut channels = vec![];
+        for (i, path) in compartments.iter().enumerate() {
+            let c = sim.get(path).ok_or_else(|| OldiesError::ModelNotFound(path.clone()))?;
+            if !matches!(c.element_type, ElementType::Compartment) {
+                return Err(error(format!("hsolve can only take compartments, not {}", path)));
+            }
+            if field(c, "Cm") <= 0.0 {
+                return Err(error(format!("{} needs a positive Cm", path)));
+            }
+            cm[i] = field(c, "Cm");
+            v[i] = field(c, "Vm");
+            let rm = field(c, "Rm");
+            g[i] = if rm > 0.0 { 1.0 / rm } else { 0.0 };
+            gm[i] = g[i];
+            leak[i] = g[i] * field(c, "Em");
+            for m in &c.messages_in {
+                let axial = match m.msg_type.as_str() {
+                    "CHANNEL" => {
+                        match Self::channel(sim, m, path)? {
+                            Some(channel) => channels.push(Channel { compartment: i, ..channel }),
+                            None => inputs.push((i, Input::Channel, m.clone())),
+                        }
+                        continue;
+                    }
+                    "INJECT" => {
+                        inputs.push((i, Input::Inject, m.clone()));
+                        continue;
+                    }
+                    "AXIAL" => (field(c, "Ra"), 0),
+                    "RAXIAL" => (messages::values(sim, m)?[0], 1),
+                    _ => continue,
+                };
+                let (ra, slot) = axial;
+                if ra <= 0.0 {
+                    return Err(error(format!("{} message to {} needs a positive Ra", m.msg_type, path)));
+                }
+                g[i] += 1.0 / ra;
+                match index.get(m.source.as_str()) {
+                    Some(&j) => *off.entry((i, j)).or_insert(0.0) -= 1.0 / ra,
+                    None => inputs.push((i, Input::Axial { ra, slot }, m.clone())),
+                }
+            }
+        }
+
+        // Breadth-first from each root puts parents before children; Hines
+        // order is the reverse
+        let mut neighbours = vec![vec![]; n];
+        for &(i, j) in off.keys() {
+            if !neighbours[i].contains(&j) {
+                neighbours[i].push(j);
+                neighbours[j].push(i);
+            }
+        }
+        let mut parent: Vec<Option<usize>> = vec![None; n];
+        let mut visited = vec![false; n];
+        let mut order = Vec::with_capacity(n);
+        for root in 0..n {
+            if visited[root] {
+                continue;
+            }
+            visited[root] = true;
+            let mut k = order.len();
+            order.push(root);
+            while k < order.len() {
+                let i = order[k];
+                for &j in &neighbours[i] {
+                    if Some(j) == parent[i] {
+                        continue;
+                    }
+                    if visited[j] {
+                        return Err(error(format!("compartments coupled in a loop at {}", compartments[j])));
+                    }
+                    visited[j] = true;
+                    parent[j] = Some(i);
+                    order.push(j);
+                }
+                k += 1;
+            }
+        }
+        order.reverse();
+        let mut renumber = vec![0; n];
+        for (k, &i) in order.iter().enumerate() {
+            renumber[i] = k;
+        }
+        let entry = |i: usize, j: usize| off.get(&(i, j)).copied().unwrap_or(0.0);
+        let pick = |values: &[f64]| order.iter().map(|&i| values[i]).collect::<Vec<f64>>();
+        for channel in &mut channels {
+            channel.compartment = renumber[channel.compartment];
+        }
+        for (i, _, _) in &mut inputs {
+            *i = renumber[*i];
+        }
+        let owned = compartments.iter().cloned().chain(channels.iter().map(|c| c.path.clone())).collect();
+        Ok(Hines {
+            parent: order.iter().map(|&i| parent[i].map(|p| renumber[p])).collect(),
+            lower: order.iter().map(|&i| parent[i].map_or(0.0, |p| entry(i, p))).collect(),
+            upper: order.iter().map(|&i| parent[i].map_or(0.0, |p| entry(p, i))).collect(),
+            cm: pick(&cm),
+            gm: pick(&gm),
+            g: pick(&g),
+            leak: pick(&leak),
+            im: vec![0.0; n],
+            v: pick(&v),
+            compartments: order.iter().map(|&i| compartments[i].clone()).collect(),
+            requested: compartments,
+            inputs,
+            channels,
+            owned,
+        })
+    }
+
+    /// The channel sending `msg` to `compartment`, if the solver can take
+    /// it over: a channel with the compartment's potential sending its
+    /// `Gk` and `Ek`
+    fn channel(sim: &GenesisSimulation, msg: &Message, compartment: &str) -> Result<Option<Channel>> {
+        let Some(channel) = sim.get(&msg.source) else { return Ok(None) };
+        let voltage = channel.messages_in.iter().find(|m| m.msg_type == "VOLTAGE");
+        if !solver::is_channel(channel) || msg.source_field != "Gk Ek"
+            || voltage.is_none_or(|m| m.source != compartment || m.source_field != "Vm")
+        {
+            return Ok(None);
+        }
+        let field = |name: &str| channel.get_param(name).unwrap_or(0.0);
+        let mut gates = vec![];
+        for (k, (gate, power)) in solver::GATES.into_iter().enumerate() {
+            if field(power) <= 0.0 {
+                continue;
+            }
+            let conc = match gate == "Z" && field("Z_conc") != 0.0 {
+                true => Some(channel.messages_in.iter().find(|m| m.msg_type == "CONCEN").cloned().ok_or_else(|| {
+                    OldiesError::SimulationError(format!("{} has a Z_conc gate but no CONCEN message", channel.path))
+                })?),
+                false => None,
+            };
+            let (a, b) = match channel.element_type {
+                ElementType::TabChannel => {
+                    let table = |t: &str| channel.tables.get(&format!("{}_{}", gate, t)).cloned().ok_or_else(|| {
+                        OldiesError::SimulationError(format!("{} has a {} gate but no tables", channel.path, gate))
+                    });
+                    (table("A")?, table("B")?)
+                }
+                _ if conc.is_some() => {
+                    return Err(OldiesError::SimulationError(format!(
+                        "hsolve needs tables for the concentration gate of {}", channel.path
+                    )));
+                }
+                _ => {
+                    let (xmin, xmax) = tabchannel::DEFAULT_RANGE;
+                    let mut a = Table::new(tabchannel::DEFAULT_XDIVS, xmin, xmax);
+                    let mut b = a.clone();
+                    for i in 0..=a.xdivs() {
+                        (a.values[i], b.values[i]) = solver::rates(channel, gate, a.x(i))?;
+                    }
+                    (a, b)
+                }
+            };
+            gates.push(Gate {
+                field: gate,
+                power: field(power),
+                instant: (field("instant") as u32) & (1 << k) != 0,
+                a,
+                b,
+                conc,
+                state: field(gate),
+            });
+        }
+        Ok(Some(Channel {
+            path: channel.path.clone(),
+            compartment: 0,
+            gbar: field("Gbar"),
+            ek: field("Ek"),
+            gates,
+        }))
+    }
+
+    /// Whether the solver has taken over the element at `path`
+    pub fn owns(&self, path: &str) -> bool {
+        self.owned.contains(path)
+    }
+
+    /// Paths of the elements the solver has taken over
+    pub fn owned(&self) -> impl Iterator<Item = &str> {
+        self.owned.iter().map(String::as_str)
+    }
+
+    /// Compartments in Hines order: every child before its parent
+    pub fn compartments(&self) -> &[String] {
+        &self.compartments
+    }
+
+    /// Advance the cell by `dt`
+    fn advance(&mut self, sim: &GenesisSimulation, dt: Time) -> Result<()> {
+        let n = self.compartments.len();
+        let mut diag: Vec<f64> = (0..n).map(|k| 2.0 * self.cm[k] / dt + self.g[k]).collect();
+        let mut rhs: Vec<f64> = (0..n).map(|k| 2.0 * self.cm[k] / dt * self.v[k] + self.leak[k]).collect();
+        let mut membrane: Vec<(f64, f64)> = (0..n).map(|k| (self.gm[k], self.leak[k])).collect();
+        for (k, path) in self.compartments.iter().enumerate() {
+            rhs[k] += sim.elements[path].get_param("inject").unwrap_or(0.0);
+        }
+        for (k, input, msg) in &self.inputs {
+            let slots = messages::values(sim, msg)?;
+            match *input {
+                Input::Channel => {
+                    diag[*k] += slots[0];
+                    rhs[*k] += slots[0] * slots[1];
+                    membrane[*k].0 += slots[0];
+                    membrane[*k].1 += slots[0] * slots[1];
+                }
+                Input::Inject => rhs[*k] += slots[0],
+                Input::Axial { ra, slot } => rhs[*k] += slots[slot] / ra,
+            }
+        }
+        for channel in &mut self.channels {
+            let v = self.v[channel.compartment];
+            let mut gk = channel.gbar;
+            for gate in &mut channel.gates {
+                let x = match &gate.conc {
+                    Some(msg) => messages::values(sim, msg)?[0],
+                    None => v,
+                };
+                let (a, b) = (gate.a.lookup(x), gate.b.lookup(x));
+                let inf = a / b;
+                gate.state = match gate.instant {
+                    true => inf,
+                    false => inf + (gate.state - inf) * (-b * dt).exp(),
+                };
+                gk *= gate.state.powf(gate.power);
+            }
+            diag[channel.compartment] += gk;
+            rhs[channel.compartment] += gk * channel.ek;
+            membrane[channel.compartment].0 += gk;
+            membrane[channel.compartment].1 += gk * channel.ek;
+        }
+
+        // Children come first: eliminate up the tree, substitute back down
+        for k in 0..n {
+            if let Some(p) = self.parent[k] {
+                let factor = self.upper[k] / diag[k];
+                diag[p] -= factor * self.lower[k];
+                rhs[p] -= factor * rhs[k];
+            }
+        }
+        let mut half = vec![0.0; n];
+        for k in (0..n).rev() {
+            let coupled = self.parent[k].map_or(0.0, |p| self.lower[k] * half[p]);
+            half[k] = (rhs[k] - coupled) / diag[k];
+        }
+        for k in 0..n {
+            self.im[k] = solver::membrane_current(self.cm[k], membrane[k], self.v[k], half[k], dt);
+            self.v[k] = 2.0 * half[k] - self.v[k];
+        }
+        Ok(())
+    }
+
+    /// Write the state of the cell to its elements
+    fn store(&self, sim: &mut GenesisSimulation) {
+        fn set(element: &mut Element, field: &str, value: f64) {
+            match element.params.get_mut(field) {
+                Some(x) => *x = value,
+                None => element.set_param(field, value),
+            }
+        }
+        for (k, path) in self.compartments.iter().enumerate() {
+            let compartment = sim.elements.get_mut(path).unwrap();
+            set(compartment, "Vm", self.v[k]);
+            set(compartment, "Im", self.im[k]);
+        }
+        for channel in &self.channels {
+            let element = sim.elements.get_mut(&channel.path).unwrap();
+            let mut gk = channel.gbar;
+            for gate in &channel.gates {
+                set(element, gate.field, gate.state);
+                gk *= gate.state.powf(gate.power);
+            }
+            set(element, "Gk", gk);
+            set(element, "Ik", gk * (channel.ek - self.v[channel.compartment]));
+        }
+    }
+}
+
+/// Set up the solver at `path` for the compartments its `path` field
+/// names (`call solver SETUP`)
+pub fn setup(sim: &mut GenesisSimulation, path: &str) -> Result<()> {
+    let element = sim.get(path).ok_or_else(|| OldiesError::ModelNotFound(path.to_string()))?;
+    let pattern = element.get_text("path").unwrap_or("").to_string();
+    if !pattern.starts_with('/') {
+        return Err(OldiesError::SimulationError(format!("{} needs an absolute path, not '{}'", path, pattern)));
+    }
+    let compartments = match wildcard::is_pattern(&pattern) {
+        true => wildcard::find(sim, &pattern)?,
+        false => vec![pattern],
+    };
+    let hines = Hines::build(sim, compartments)?;
+    sim.solvers.insert(path.to_string(), hines);
+    sim.tasks = None;
+    Ok(())
+}
+
+/// Set the solvers up again from the fields of their elements, after the
+/// gates are reset
+pub(crate) fn reset(sim: &mut GenesisSimulation) -> Result<()> {
+    for path in sim.solvers.keys().cloned().collect::<Vec<_>>() {
+        let hines = Hines::build(sim, sim.solvers[&path].requested.clone())?;
+        sim.solvers.insert(path, hines);
+    }
+    sim.tasks = None;
+    Ok(())
+}
+
+/// Advance the cells of the solvers among `paths` by `dt`
+pub(crate) fn step(sim: &mut GenesisSimulation, paths: &[String], dt: Time) -> Result<()> {
+    for path in paths {
+        let mut hines = sim.solvers.remove(path).ok_or_else(|| {
+            OldiesError::SimulationError(format!("{} is not set up (call {} SETUP)", path, path))
+        })?;
+        let result = hines.advance(sim, dt);
+        if result.is_ok() {
+            hines.store(sim);
+        }
+        sim.solvers.insert(path.clone(), hines);
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    /// A soma with squid channels and two dendrites of three compartments,
+    /// one with a tabchannel
+    const CELL: &str = r#"
+        create neutral /cell
+        create compartment /cell/soma
+        setfield /cell/soma Cm 7.854e-9 Rm 4.244e5 Em -0.0594 initVm -0.07
+        create Na_squid_hh /cell/soma/Na
+        create K_squid_hh /cell/soma/K
+        setfield /cell/soma/Na Gbar 9.425e-4
+        setfield /cell/soma/K Gbar 2.827e-4
+        addmsg /cell/soma /cell/soma/Na VOLTAGE Vm
+        addmsg /cell/soma/Na /cell/soma CHANNEL Gk Ek
+        addmsg /cell/soma /cell/soma/K VOLTAGE Vm
+        addmsg /cell/soma/K /cell/soma CHANNEL Gk Ek
+        foreach branch (a b)
+            str parent = "/cell/soma"
+            int i
+            for (i = 1; i <= 3; i = i + 1)
+                str comp = "/cell/" @ branch @ i
+                create compartment {comp}
+                setfield {comp} Cm 1e-9 Rm 4e6 Em -0.0594 initVm -0.07 Ra 2e5
+                addmsg {parent} {comp} AXIAL Vm
+                addmsg {comp} {parent} RAXIAL Ra Vm
+                parent = comp
+            end
+        end
+        create tabchannel /cell/b2/K
+        setfield /cell/b2/K Ek -0.077 Gbar 2e-5 Xpower 4
+        setupalpha /cell/b2/K X 10e3 1e5 -1 0.01 -0.01  125 0 0 0.07 0.08
+        addmsg /cell/b2 /cell/b2/K VOLTAGE Vm
+        addmsg /cell/b2/K /cell/b2 CHANNEL Gk Ek
+        setfield /cell/soma inject 1e-7
+        setclock 0 2e-5
+    "#;
+
+    fn trace(sli: &mut Sli, steps: usize) -> Vec<f64> {
+        (0..steps).map(|_| {
+            sli.sim.step().unwrap();
+            sli.sim.get("/cell/soma").unwrap().get_param("Vm").unwrap()
+        }).collect()
+    }
+
+    fn spikes(trace: &[f64]) -> Vec<usize> {
+        (1..trace.len()).filter(|&i| trace[i - 1] < 0.0 && trace[i] >= 0.0).collect()
+    }
+
+    #[test]
+    fn test_hsolve_matches_elements() {
+        let mut plain = Sli::new();
+        plain.execute(&format!("{}\nreset", CELL)).unwrap();
+        let mut solved = Sli::new();
+        solved.execute(&format!(r#"{}
+            create hsolve /cell/solve
+            setfield /cell/solve path /cell/##[][TYPE=compartment] chanmode 4
+            call /cell/solve SETUP
+            reset
+        "#, CELL)).unwrap();
+
+        // The solver owns the cell, down to its channels
+        assert_eq!(solved.call("showsched").unwrap(), "");
+        assert_eq!(solved.output, "clock 0 (dt = 0.00002): 1 hsolve\n");
+        let hines = &solved.sim.solvers["/cell/solve"];
+        assert!(hines.owns("/cell/b2/K") && hines.owns("/cell/soma/Na") && !hines.owns("/cell/solve"));
+        let order = hines.compartments();
+        assert_eq!(order.len(), 7);
+        assert_eq!(order[6], "/cell/soma");
+        let at = |p: &str| order.iter().position(|c| c == p).unwrap();
+        assert!(at("/cell/a3") < at("/cell/a2") && at("/cell/a2") < at("/cell/a1"));
+
+        let (expected, actual) = (trace(&mut plain, 2500), trace(&mut solved, 2500));
+        let (expected, actual) = (spikes(&expected), spikes(&actual));
+        assert!(expected.len() >= 3, "{:?}", expected);
+        assert_eq!(expected.len(), actual.len());
+        assert!(expected.iter().zip(&actual).all(|(e, a)| e.abs_diff(*a) <= 2), "{:?} {:?}", expected, actual);
+
+        // Fields of owned elements are written back
+        let field = |sli: &Sli, p: &str, f: &str| sli.sim.get(p).unwrap().get_param(f).unwrap();
+        for (path, f) in [("/cell/a3", "Vm"), ("/cell/b2/K", "X"), ("/cell/soma/K", "Ik")] {
+            assert!((field(&plain, path, f) - field(&solved, path, f)).abs() < 1e-2 * field(&plain, path, f).abs().max(1e-3));
+        }
+
+        assert!(solved.execute("create hsolve /bad; setfield /bad path cell/soma; call /bad SETUP").is_err());
+        assert!(solved.execute("create hsolve /unset; step").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+//! Chemical kinetics (Kinetikit)
+//!
+//! GENESIS's kinetics library models signaling networks as pools of
+//! molecules joined by reactions and enzymes, the elements Kinetikit
+//! (`kkit`) models are made of:
+//!
+//! - `kpool`: `n` molecules (`Co = n/vol`), starting from `nInit`, held at
+//!   `nInit` when buffered (`slave_enable` 4)
+//! - `kreac`: `substrates <-> products` by mass action with rates `kf` and
+//!   `kb`
+//! - `kenz`: an enzyme acting on its substrates, either explicitly,
+//!   `E + S <-> ES -> E + P` with `k1`, `k2`, `k3` and the complex in
+//!   `nComplex` (`mode` 0), or with Michaelis-Menten kinetics
+//!   `kcat E S / (Km + S)` (`mode` 1)
+//!
+//! Rates act on numbers of molecules. Elements are wired as in Kinetikit
+//! scripts:
+//!
+//! ```text
+//! addmsg /k/A /k/reac SUBSTRATE n      addmsg /k/reac /k/A REAC A B
+//! addmsg /k/B /k/reac PRODUCT n        addmsg /k/reac /k/B REAC B A
+//! addmsg /k/E /k/E/enz ENZYME n        addmsg /k/E/enz /k/E REAC eA B
+//! addmsg /k/S /k/E/enz SUBSTRATE n     addmsg /k/E/enz /k/S REAC sA B
+//!                                      addmsg /k/E/enz /k/P MM_PRD pA
+//! ```
+//!
+//! A pool changes by `dn/dt = A - B` summed over its REAC messages plus its
+//! MM_PRD messages. Reactions and enzymes compute their fluxes (`A` back
+//! to the substrates, `B` out of them) before the pools are integrated,
+//! both by exponential Euler as in GENESIS. With the `copasi` feature,
+//! [`to_sbml`] exports a network to `oldies-copasi`.
+
+use crate::{messages, Element, ElementType, GenesisSimulation};
+use oldies_core::{Result, Time};
+
+/// `slave_enable` flag of a buffered pool
+const BUFFERED: u32 = 4;
+
+fn field(element: &Element, name: &str) -> f64 {
+    element.get_param(name).unwrap_or(0.0)
+}
+
+/// Product of the first slots of the messages of `msg_type` to `element`
+fn product(sim: &GenesisSimulation, element: &Element, msg_type: &str) -> Result<f64> {
+    Ok(messages::inputs(sim, element, msg_type)?.iter().map(|(_, slots)| slots[0]).product())
+}
+
+/// `x` after `dt` of `dx/dt = gain - loss * x`
+fn exp_euler(x: f64, gain: f64, loss: f64, dt: Time) -> f64 {
+    if loss > 1e-12 {
+        let steady = gain / loss;
+        steady + (x - steady) * (-loss * dt).exp()
+    } else {
+        x + gain * dt
+    }
+}
+
+/// Start pools and enzyme complexes at their initial numbers
+pub(crate) fn reset(sim: &mut GenesisSimulation) {
+    for path in sim.paths() {
+        let element = sim.elements.get_mut(&path).unwrap();
+        match element.element_type {
+            ElementType::Pool => {
+                let (n, vol) = (field(element, "nInit"), field(element, "vol"));
+                element.set_param("n", n);
+                if vol > 0.0 {
+                    element.set_param("CoInit", n / vol);
+                    element.set_param("Co", n / vol);
+                }
+            }
+            ElementType::Enzyme => {
+                let n = field(element, "nComplexInit");
+                element.set_param("nComplex", n);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Compute the fluxes of `reactions` (reactions and enzymes) and advance
+/// enzyme complexes by `dt`
+pub(crate) fn step_reactions(sim: &mut GenesisSimulation, reactions: &[String], dt: Time) -> Result<()> {
+    for path in reactions {
+        let element = &sim.elements[path];
+        let substrates = product(sim, element, "SUBSTRATE")?;
+        let fields: Vec<(&str, f64)> = match element.element_type {
+            ElementType::Reaction => {
+                let products = product(sim, element, "PRODUCT")?;
+                vec![("A", field(element, "kb") * products), ("B", field(element, "kf") * substrates)]
+            }
+            ElementType::Enzyme if field(element, "mode") == 1.0 => {
+                // Michaelis-Menten on the substrate concentration
+                let enzyme = product(sim, element, "ENZYME")?;
+                let (km, kcat) = (field(element, "Km"), field(element, "kcat"));
+                let rate = kcat * enzyme * substrates / (km + substrates);
+                vec![("sA", 0.0), ("eA", 0.0), ("pA", rate), ("B", rate)]
+            }
+            ElementType::Enzyme => {
+                let enzyme = product(sim, element, "ENZYME")?;
+                let (k1, k2, k3) = (field(element, "k1"), field(element, "k2"), field(element, "k3"));
+                let forward = k1 * enzyme * substrates;
+                let complex = exp_euler(field(element, "nComplex"), forward, k2 + k3, dt);
+                vec![
+                    ("nComplex", complex),
+                    ("sA", k2 * complex),
+                    ("eA", (k2 + k3) * complex),
+                    ("pA", k3 * complex),
+                    ("B", forward),
+                ]
+            }
+            _ => continue,
+        };
+        let element = sim.elements.get_mut(path).unwrap();
+        for (name, value) in fields {
+            element.set_param(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Advance `pools` by `dt` with the fluxes they receive
+pub(crate) fn step_pools(sim: &mut GenesisSimulation, pools: &[String], dt: Time) -> Result<()> {
+    for path in pools {
+        let pool = &sim.elements[path];
+        let n = field(pool, "n");
+        let n = if field(pool, "slave_enable") as u32 & BUFFERED != 0 {
+            field(pool, "nInit")
+        } else {
+            let (mut gain, mut loss) = (0.0, 0.0);
+            for (_, slots) in messages::inputs(sim, pool, "REAC")? {
+                gain += slots[0];
+                loss += slots[1];
+            }
+            for (_, slots) in messages::inputs(sim, pool, "MM_PRD")? {
+                gain += slots[0];
+            }
+            // The loss is proportional to the pool, so decays it exponentially
+            let rate = if n > 0.0 { loss / n } else { 0.0 };
+            let next = exp_euler(n, gain, rate, dt);
+            if n <= 0.0 { (next - loss * dt).max(0.0) } else { next.max(0.0) }
+        };
+        let pool = sim.elements.get_mut(path).unwrap();
+        pool.set_param("n", n);
+        let vol = field(pool, "vol");
+        if vol > 0.0 {
+            pool.set_param("Co", n / vol);
+        }
+    }
+    Ok(())
+}
+
+/// The network of pools, reactions and enzymes under `root` as an SBML
+/// model, in numbers of molecules. Explicit enzymes become three mass
+/// action reactions through a complex species; Michaelis-Menten enzymes
+/// keep their rate law as an expression.
+#[cfg(feature = "copasi")]
+pub fn to_sbml(sim: &GenesisSimulation, root: &str) -> oldies_copasi::SbmlModel {
+    use oldies_copasi::{Compartment, KineticLaw, Parameter, Reaction, SbmlModel, Species, SpeciesReference};
+
+    let prefix = format!("{}/", root.trim_end_matches('/'));
+    let id = |path: &str| path.trim_start_matches(&prefix).replace(['/', '[', ']'], "_");
+    let sources = |element: &Element, msg_type: &str| -> Vec<String> {
+        element.messages_in.iter().filter(|m| m.msg_type == msg_type).map(|m| id(&m.source)).collect()
+    };
+    let refs = |species: &[String]| species.iter().map(|s| SpeciesReference::new(s, 1.0)).collect::<Vec<_>>();
+    let mass_action = |name: String, reactants: Vec<String>, products: Vec<String>, k: String| Reaction {
+        id: name,
+        name: None,
+        reversible: false,
+        reactants: refs(&reactants),
+        products: refs(&products),
+        modifiers: vec![],
+        kinetic_law: KineticLaw::MassAction { rate_constant: k },
+        local_parameters: vec![],
+    };
+
+    let mut model = SbmlModel::new(&id(root));
+    model.add_compartment(Compartment::new("kinetics", 1.0));
+    let paths: Vec<String> = sim.paths().into_iter().filter(|p| p.starts_with(&prefix)).collect();
+    for path in &paths {
+        let element = &sim.elements[path];
+        let name = id(path);
+        let parameter = |model: &mut SbmlModel, suffix: &str, field_name: &str| {
+            let pid = format!("{}_{}", name, suffix);
+            model.add_parameter(Parameter::new(&pid, field(element, field_name)));
+            pid
+        };
+        match element.element_type {
+            ElementType::Pool => {
+                let mut species = Species::new(&name, "kinetics", field(element, "nInit"));
+                if field(element, "slave_enable") as u32 & BUFFERED != 0 {
+                    species.boundary_condition = true;
+                    species.constant = true;
+                }
+                model.add_species(species);
+            }
+            ElementType::Reaction => {
+                let (subs, prods) = (sources(element, "SUBSTRATE"), sources(element, "PRODUCT"));
+                let kf = parameter(&mut model, "kf", "kf");
+                model.add_reaction(mass_action(format!("{}_f", name), subs.clone(), prods.clone(), kf));
+                if field(element, "kb") > 0.0 {
+                    let kb = parameter(&mut model, "kb", "kb");
+                    model.add_reaction(mass_action(format!("{}_b", name), prods, subs, kb));
+                }
+            }
+            ElementType::Enzyme => {
+                let (enzyme, subs) = (sources(element, "ENZYME"), sources(element, "SUBSTRATE"));
+                let prods: Vec<String> = element.messages_out.iter()
+                    .filter(|m| m.msg_type == "MM_PRD")
+                    .map(|m| id(&m.dest))
+                    .collect();
+                if field(element, "mode") == 1.0 {
+                    let (kcat, km) = (parameter(&mut model, "kcat", "kcat"), parameter(&mut model, "Km", "Km"));
+                    let s = subs.join(" * ");
+                    let law = format!("{} * {} * {} / ({} + {})", kcat, enzyme.join(" * "), s, km, s);
+                    model.add_reaction(Reaction {
+                        id: name.clone(),
+                        name: None,
+                        reversible: false,
+                        reactants: refs(&subs),
+                        products: refs(&prods),
+                        modifiers: enzyme,
+                        kinetic_law: KineticLaw::Custom(law),
+                        local_parameters: vec![],
+                    });
+                } else {
+                    let complex = format!("{}_cplx", name);
+                    model.add_species(Species::new(&complex, "kinetics", field(element, "nComplexInit")));
+                    let (k1, k2, k3) = (
+                        parameter(&mut model, "k1", "k1"),
+                        parameter(&mut model, "k2", "k2"),
+                        parameter(&mut model, "k3", "k3"),
+                    );
+                    let free: Vec<String> = enzyme.iter().chain(&subs).cloned().collect();
+                    let released: Vec<String> = enzyme.iter().chain(&prods).cloned().collect();
+                    model.add_reaction(mass_action(format!("{}_bind", name), free.clone(), vec![complex.clone()], k1));
+                    model.add_reaction(mass_action(format!("{}_unbind", name), vec![complex.clone()], free, k2));
+                    model.add_reaction(mass_action(format!("{}_cat", name), vec![complex], released, k3));
+                }
+            }
+            _ => {}
+        }
+    }
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    const NETWORK: &str = r#"
+        create neutral /k
+        create kpool /k/A
+        create kpool /k/B
+        create kpool /k/E
+        create kpool /k/P
+        setfield /k/A nInit 100 vol 10
+        setfield /k/E nInit 2
+        create kreac /k/reac
+        setfield /k/reac kf 0.2 kb 0.1
+        addmsg /k/A /k/reac SUBSTRATE n
+        addmsg /k/B /k/reac PRODUCT n
+        addmsg /k/reac /k/A REAC A B
+        addmsg /k/reac /k/B REAC B A
+        create kenz /k/E/enz
+        setfield /k/E/enz k1 0.01 k2 0.4 k3 0.1
+        addmsg /k/E /k/E/enz ENZYME n
+        addmsg /k/B /k/E/enz SUBSTRATE n
+        addmsg /k/E/enz /k/E REAC eA B
+        addmsg /k/E/enz /k/B REAC sA B
+        addmsg /k/E/enz /k/P MM_PRD pA
+        setclock 0 0.01
+        reset
+    "#;
+
+    fn n(sli: &Sli, path: &str) -> f64 {
+        sli.sim.get(path).unwrap().get_param("n").unwrap()
+    }
+
+    #[test]
+    fn test_reversible_reaction_equilibrates() {
+        let mut sli = Sli::new();
+        sli.execute(NETWORK).unwrap();
+        sli.execute("setfield /k/E nInit 0; reset; step 100 -time").unwrap();
+        // kf A = kb B, and A + B = 100 up to the integration error
+        let (a, b) = (n(&sli, "/k/A"), n(&sli, "/k/B"));
+        assert!((b / a - 2.0).abs() < 1e-9, "{} {}", a, b);
+        assert!((a + b - 100.0).abs() < 0.1, "{}", a + b);
+        assert!((sli.sim.get("/k/A").unwrap().get_param("Co").unwrap() - a / 10.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_enzyme_conserves_mass() {
+        let mut sli = Sli::new();
+        sli.execute(NETWORK).unwrap();
+        for _ in 0..5 {
+            sli.execute("step 10 -time").unwrap();
+            let complex = sli.sim.get("/k/E/enz").unwrap().get_param("nComplex").unwrap();
+            let total = n(&sli, "/k/A") + n(&sli, "/k/B") + n(&sli, "/k/P") + complex;
+            assert!((total - 100.0).abs() < 0.1, "{}", total);
+            assert!((n(&sli, "/k/E") + complex - 2.0).abs() < 0.01);
+        }
+        // The enzyme drains the pools into the product
+        sli.execute("step 2000 -time").unwrap();
+        assert!(n(&sli, "/k/P") > 95.0, "{}", n(&sli, "/k/P"));
+    }
+
+    #[test]
+    fn test_michaelis_menten_and_buffering() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create kpool /S
+            create kpool /E
+            create kpool /P
+            setfield /S nInit 1000 slave_enable 4
+            setfield /E nInit 3
+            create kenz /E/enz
+            setfield /E/enz mode 1 Km 500 kcat 2
+            addmsg /E /E/enz ENZYME n
+            addmsg /S /E/enz SUBSTRATE n
+            addmsg /E/enz /S REAC sA B
+            addmsg /E/enz /P MM_PRD pA
+            setclock 0 0.01
+            reset
+            step 10 -time
+        "#).unwrap();
+        // Constant substrate: P grows at kcat E S/(Km + S) = 4 per second
+        assert_eq!(n(&sli, "/S"), 1000.0);
+        assert_eq!(n(&sli, "/E"), 3.0);
+        assert!((n(&sli, "/P") - 40.0).abs() < 1e-6, "{}", n(&sli, "/P"));
+    }
+
+    #[cfg(feature = "copasi")]
+    #[test]
+    fn test_export_to_copasi() {
+        let mut sli = Sli::new();
+        sli.execute(NETWORK).unwrap();
+        let model = super::to_sbml(&sli.sim, "/k");
+        let species: Vec<&str> = model.species.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(species, vec!["A", "B", "E", "E_enz_cplx", "P"]);
+        assert_eq!(model.reactions.len(), 5);
+
+        sli.execute("step 20 -time").unwrap();
+        let mut copasi = oldies_copasi::CopasiSimulation::new(model);
+        let result = copasi.run(20.0, 20000);
+        for pool in ["A", "B", "P"] {
+            let theirs = *result.concentrations[pool].last().unwrap();
+            let ours = n(&sli, &format!("/k/{}", pool));
+            assert!((ours - theirs).abs() < 0.05 * theirs.max(1.0), "{} {} {}", pool, ours, theirs);
+        }
+    }
+}
@@ -14,8 +14,9 @@
 //! This crate provides:
 //!
 //! 1. SLI parser
-//! 2. Script interpreter
-//! 3. Native Rust model execution
+//! 2. Script interpreter ([`sli`])
+//! 3. Native Rust model execution ([`solver`], [`kinetics`])
+//! 4. Export of models to NeuroML2 ([`neuroml`])
 //!
 //! ## Key GENESIS Concepts
 //!
@@ -30,10 +31,35 @@
 //! GENESIS parser. This crate aims to be compatible with both GENESIS and
 //! MOOSE script formats.
 
-use oldies_core::{OldiesError, Result, TimeSeries, StateVector, Time, Voltage};
+use oldies_core::{OldiesError, Result, Rng, TimeSeries, Time};
 use pest_derive::Parser;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub mod concen;
+pub mod custom;
+pub mod devices;
+pub mod efield;
+pub mod hsolve;
+pub mod kinetics;
+pub mod messages;
+pub mod models;
+pub mod moose;
+pub mod neuroml;
+pub mod output;
+pub mod paramsearch;
+pub mod parallel;
+pub mod readcell;
+pub mod schedule;
+pub mod sli;
+pub mod solver;
+pub mod synapse;
+pub mod tabchannel;
+pub mod units;
+pub mod wildcard;
+pub mod xodus;
 
 /// SLI (Script Language Interpreter) parser
 #[derive(Parser)]
@@ -65,14 +91,52 @@ pub enum ElementType {
     NaChannel,
     /// HH potassium channel
     KChannel,
+    /// Channel with tabulated gates (`tabchannel`)
+    TabChannel,
     /// Calcium channel
     CaChannel,
-    /// Synapse
+    /// Calcium pool (`Ca_concen`)
+    CaConcen,
+    /// Hines solver (`hsolve`)
+    HSolve,
+    /// Pool of molecules (`kpool`)
+    Pool,
+    /// Mass-action reaction (`kreac`)
+    Reaction,
+    /// Enzyme (`kenz`)
+    Enzyme,
+    /// Synaptic channel (`synchan`)
     Synapse,
-    /// Spike generator
+    /// Spike generator (`spikegen`)
     SpikeGen,
+    /// Pulse generator (`pulsegen`)
+    PulseGen,
+    /// Poisson spike source (`randomspike`)
+    RandomSpike,
+    /// Gaussian noise source (`noisegen`)
+    NoiseGen,
+    /// Extracellular electrode (`efield`)
+    EField,
     /// Recorder (output)
     Recorder,
+    /// Brute-force parameter search (`paramtableBF`)
+    ParamTableBF,
+    /// Simulated-annealing parameter search (`paramtableSA`)
+    ParamTableSA,
+    /// Genetic parameter search (`paramtableGA`)
+    ParamTableGA,
+    /// Text output file (`asc_file`)
+    AscFile,
+    /// Binary output file (`disk_out`)
+    DiskOut,
+    /// Xodus graph (`xgraph`)
+    XGraph,
+    /// Plot on an Xodus graph (`xplot`)
+    XPlot,
+    /// Xodus cell view (`xcell`)
+    XCell,
+    /// Other Xodus widget, named by its object
+    XWidget(String),
     /// Neutral (container)
     Neutral,
     /// Custom object
@@ -94,6 +158,17 @@ pub struct Element {
     pub messages_in: Vec<Message>,
     /// Outgoing messages
     pub messages_out: Vec<Message>,
+    /// Clock the element is updated on (`useclock`)
+    pub clock: usize,
+    /// Lookup tables (`X_A`, `X_B`, ... of a tabchannel)
+    #[serde(default)]
+    pub tables: HashMap<String, Table>,
+    /// Left out of the simulation with its descendants (`disable`)
+    #[serde(default)]
+    pub disabled: bool,
+    /// Text fields (`filename` of an output element)
+    #[serde(default)]
+    pub strings: HashMap<String, String>,
 }
 
 impl Element {
@@ -106,9 +181,31 @@ impl Element {
             children: Vec::new(),
             messages_in: Vec::new(),
             messages_out: Vec::new(),
+            clock: 0,
+            tables: HashMap::new(),
+            disabled: false,
+            strings: HashMap::new(),
         }
     }
 
+    /// Name of the element: the last component of its path
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or("")
+    }
+
+    /// Value of a text field
+    pub fn get_text(&self, field: &str) -> Option<&str> {
+        self.strings.get(field).map(String::as_str)
+    }
+
+    /// Set a text field the element has
+    pub fn set_text(&mut self, field: &str, value: &str) -> Result<()> {
+        let text = self.strings.get_mut(field)
+            .ok_or_else(|| OldiesError::SimulationError(format!("{} has no text field {}", self.path, field)))?;
+        *text = value.to_string();
+        Ok(())
+    }
+
     /// Set a parameter
     pub fn set_param(&mut self, name: &str, value: f64) {
         self.params.insert(name.to_string(), value);
@@ -118,9 +215,154 @@ impl Element {
     pub fn get_param(&self, name: &str) -> Option<f64> {
         self.params.get(name).copied()
     }
+
+    /// Set a field the element has: a parameter, or an entry
+    /// (`X_A->table[3]`) or the range (`X_A->xmin`, `X_A->xmax`) of a table
+    pub fn set_field(&mut self, field: &str, value: f64) -> Result<()> {
+        let missing = || OldiesError::SimulationError(format!("{} has no field {}", self.path, field));
+        let Some((name, sub)) = field.split_once("->") else {
+            *self.params.get_mut(field).ok_or_else(missing)? = value;
+            return Ok(());
+        };
+        let table = self.tables.get_mut(name).ok_or_else(missing)?;
+        match sub {
+            "xmin" => table.xmin = value,
+            "xmax" => table.xmax = value,
+            _ => *table_index(sub).and_then(|i| table.values.get_mut(i)).ok_or_else(missing)? = value,
+        }
+        Ok(())
+    }
+
+    /// Value of a field, as named for [`Element::set_field`] or
+    /// `X_A->xdivs`
+    pub fn get_field(&self, field: &str) -> Option<f64> {
+        let Some((name, sub)) = field.split_once("->") else {
+            return self.get_param(field);
+        };
+        let table = self.tables.get(name)?;
+        match sub {
+            "xmin" => Some(table.xmin),
+            "xmax" => Some(table.xmax),
+            "xdivs" => Some(table.xdivs() as f64),
+            _ => table.values.get(table_index(sub)?).copied(),
+        }
+    }
+}
+
+impl Element {
+    /// Whether the element has `field`, numeric as named for
+    /// [`Element::get_field`] or text
+    pub fn has_field(&self, field: &str) -> bool {
+        self.get_text(field).is_some() || self.get_field(field).is_some()
+    }
+
+    /// Names of the numeric and text fields, sorted. Table entries are
+    /// left out.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields: Vec<String> = self.params.keys().chain(self.strings.keys()).cloned().collect();
+        fields.sort();
+        fields
+    }
+
+    /// Add a numeric field starting at 0, as `addfield` does
+    pub fn add_field(&mut self, field: &str) -> Result<()> {
+        if field.is_empty() || field.contains("->") || self.has_field(field) {
+            return Err(OldiesError::SimulationError(format!("cannot add field {} to {}", field, self.path)));
+        }
+        self.set_param(field, 0.0);
+        Ok(())
+    }
+
+    /// Present value of `field` as text, as `getfield` prints it
+    pub fn field_text(&self, field: &str) -> Option<String> {
+        match self.get_text(field) {
+            Some(text) => Some(text.to_string()),
+            None => self.get_field(field).map(sli::format_number),
+        }
+    }
+
+    /// [`Element::field_text`] in the units of `system`
+    pub fn field_text_in(&self, field: &str, system: units::UnitSystem) -> Option<String> {
+        match (units::quantity(self, field), self.get_text(field)) {
+            (Some(quantity), None) => self.get_param(field).map(|v| sli::format_number(system.from_si(quantity, v))),
+            _ => self.field_text(field),
+        }
+    }
+
+    /// `showfield` listing of `fields`, or of all of them when empty: the
+    /// path, then a line per field
+    pub fn show(&self, fields: &[&str]) -> Result<String> {
+        self.show_in(fields, units::UnitSystem::SI)
+    }
+
+    /// [`Element::show`] with values in the units of `system`
+    pub fn show_in(&self, fields: &[&str], system: units::UnitSystem) -> Result<String> {
+        let all = self.fields();
+        let fields: Vec<&str> = match fields {
+            [] => all.iter().map(String::as_str).collect(),
+            _ => fields.to_vec(),
+        };
+        let mut text = format!("[ {} ]\n", self.path);
+        for field in fields {
+            let value = self.field_text_in(field, system)
+                .ok_or_else(|| OldiesError::SimulationError(format!("{} has no field {}", self.path, field)))?;
+            text.push_str(&format!("{:<20} = {}\n", field, value));
+        }
+        Ok(text)
+    }
+}
+
+/// Index `i` of `table[i]`
+fn table_index(sub: &str) -> Option<usize> {
+    sub.strip_prefix("table[")?.strip_suffix(']')?.parse().ok()
+}
+
+/// Table over `[xmin, xmax]` in `xdivs` equal divisions, looked up with
+/// linear interpolation (GENESIS's `interpol_struct`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    pub xmin: f64,
+    pub xmax: f64,
+    /// `xdivs + 1` values
+    pub values: Vec<f64>,
+}
+
+impl Table {
+    /// Table of zeros
+    pub fn new(xdivs: usize, xmin: f64, xmax: f64) -> Self {
+        Self { xmin, xmax, values: vec![0.0; xdivs + 1] }
+    }
+
+    /// Number of divisions
+    pub fn xdivs(&self) -> usize {
+        self.values.len().saturating_sub(1)
+    }
+
+    /// Abscissa of entry `i`
+    pub fn x(&self, i: usize) -> f64 {
+        self.xmin + (self.xmax - self.xmin) * i as f64 / self.xdivs().max(1) as f64
+    }
+
+    /// Value at `x`, interpolated between entries and held at the ends
+    pub fn lookup(&self, x: f64) -> f64 {
+        let n = self.xdivs();
+        if n == 0 || x <= self.xmin {
+            return self.values.first().copied().unwrap_or(0.0);
+        }
+        if x >= self.xmax {
+            return self.values[n];
+        }
+        let position = (x - self.xmin) / (self.xmax - self.xmin) * n as f64;
+        let i = (position as usize).min(n - 1);
+        let f = position - i as f64;
+        self.values[i] + f * (self.values[i + 1] - self.values[i])
+    }
 }
 
 /// GENESIS message (connection between elements)
+///
+/// `addmsg /a /b CHANNEL Gk Ek` has message type `CHANNEL` and the source
+/// fields `Gk Ek` (separated by spaces) in `source_field`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     /// Source element path
@@ -144,8 +386,43 @@ pub struct GenesisSimulation {
     time: Time,
     /// Time step
     dt: Time,
-    /// Recorded data
+    /// Time steps of the clocks set with `setclock`
+    clocks: BTreeMap<usize, Time>,
+    /// Time at which each clock next updates its elements
+    next_tick: BTreeMap<usize, Time>,
+    /// Spikes on their way to each synchan: arrival time and weight
+    spikes: HashMap<String, Vec<(Time, f64)>>,
+    /// Spikes for synchans of other domains: source, synchan and the time
+    /// they were emitted (see [`parallel`])
+    outbox: Vec<(String, String, Time)>,
+    /// Open files of output elements
+    files: HashMap<String, BufWriter<File>>,
+    /// Values plotted on Xodus graphs, by plot path
     recordings: HashMap<String, TimeSeries>,
+    /// Values shown by Xodus cell views
+    snapshots: HashMap<String, xodus::Snapshots>,
+    /// Cells of the `hsolve` elements that have been set up
+    solvers: HashMap<String, hsolve::Hines>,
+    /// Schedule of the present elements and clocks, built when first needed
+    tasks: Option<Vec<schedule::Task>>,
+    /// Random number generator (`randseed`)
+    pub rng: Rng,
+    /// Parameter searches started with `initsearch`
+    searches: HashMap<String, paramsearch::Search>,
+    /// User-defined objects scripts can create
+    pub registry: custom::Registry,
+}
+
+/// Where prototypes are built to be copied into cells. It is created
+/// disabled, so prototypes are never simulated.
+pub const LIBRARY: &str = "/library";
+
+/// Path of the parent of `path` (`/` for top-level elements)
+pub fn parent_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
 }
 
 impl GenesisSimulation {
@@ -155,17 +432,148 @@ impl GenesisSimulation {
             elements: HashMap::new(),
             time: 0.0,
             dt: 1e-5, // 10 microseconds
+            clocks: BTreeMap::from([(0, 1e-5)]),
+            next_tick: BTreeMap::new(),
+            spikes: HashMap::new(),
+            outbox: Vec::new(),
+            files: HashMap::new(),
             recordings: HashMap::new(),
+            snapshots: HashMap::new(),
+            solvers: HashMap::new(),
+            tasks: None,
+            rng: Rng::default(),
+            searches: HashMap::new(),
+            registry: custom::Registry::default(),
         }
     }
 
-    /// Create an element
+    /// Create an element, listing it among the children of its parent if
+    /// the parent exists
     pub fn create(&mut self, path: &str, element_type: ElementType) -> &mut Element {
-        let element = Element::new(path, element_type);
+        self.tasks = None;
+        let mut element = Element::new(path, element_type);
+        element.disabled = path == LIBRARY;
+        // Every element has a position
+        for axis in ["x", "y", "z"] {
+            element.set_param(axis, 0.0);
+        }
+        if let Some(parent) = self.elements.get_mut(parent_path(path)) {
+            if !parent.children.iter().any(|c| c == path) {
+                parent.children.push(path.to_string());
+            }
+        }
         self.elements.insert(path.to_string(), element);
         self.elements.get_mut(path).unwrap()
     }
 
+    /// Whether `path` is an element or the root
+    pub fn exists(&self, path: &str) -> bool {
+        path == "/" || self.elements.contains_key(path)
+    }
+
+    /// Include the element tree at `path` in the simulation (`enable`) or
+    /// leave it out (`disable`)
+    pub fn set_enabled(&mut self, path: &str, enabled: bool) -> Result<()> {
+        self.tasks = None;
+        let element = self.elements.get_mut(path).ok_or_else(|| OldiesError::ModelNotFound(path.to_string()))?;
+        element.disabled = !enabled;
+        Ok(())
+    }
+
+    /// Whether the element at `path` is simulated: neither it nor an
+    /// ancestor is disabled
+    pub fn is_simulated(&self, path: &str) -> bool {
+        let mut path = path;
+        while path != "/" {
+            if self.elements.get(path).is_some_and(|e| e.disabled) {
+                return false;
+            }
+            path = parent_path(path);
+        }
+        true
+    }
+
+    /// Whether the element at `path` has been taken over by a simulated
+    /// `hsolve` element
+    pub fn is_solved(&self, path: &str) -> bool {
+        self.solved().contains(path)
+    }
+
+    /// Paths of the elements taken over by simulated `hsolve` elements
+    pub(crate) fn solved(&self) -> HashSet<&str> {
+        self.solvers.iter()
+            .filter(|(solver, _)| self.is_simulated(solver))
+            .flat_map(|(_, hines)| hines.owned())
+            .collect()
+    }
+
+    /// Paths of all elements, sorted
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.elements.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    /// Copy the element tree at `source` to `dest`, or into `dest` under the
+    /// name of `source` if `dest` exists. Messages between elements of the
+    /// tree are copied with it. Returns the path of the copy.
+    pub fn copy(&mut self, source: &str, dest: &str) -> Result<String> {
+        if !self.elements.contains_key(source) {
+            return Err(OldiesError::ModelNotFound(source.to_string()));
+        }
+        let target = if self.exists(dest) {
+            let name = source.rsplit('/').next().unwrap_or(source);
+            format!("{}/{}", dest.trim_end_matches('/'), name)
+        } else {
+            dest.to_string()
+        };
+        if self.elements.contains_key(&target) {
+            return Err(OldiesError::SimulationError(format!("copy: element {} already exists", target)));
+        }
+        if !self.exists(parent_path(&target)) {
+            return Err(OldiesError::ModelNotFound(parent_path(&target).to_string()));
+        }
+        if target == source || target.starts_with(&format!("{}/", source)) {
+            return Err(OldiesError::SimulationError(format!("copy: cannot copy {} into itself", source)));
+        }
+
+        let inside = |path: &str| path == source || path.starts_with(&format!("{}/", source));
+        let rename = |path: &str| format!("{}{}", target, &path[source.len()..]);
+        let mut copies: Vec<Element> = self.paths().into_iter()
+            .filter(|path| inside(path))
+            .map(|path| {
+                let mut element = self.elements[&path].clone();
+                element.path = rename(&path);
+                element.children = element.children.iter().map(|c| rename(c)).collect();
+                if matches!(element.element_type, ElementType::Synapse) {
+                    let kept: Vec<usize> = element.messages_in.iter()
+                        .filter(|m| m.msg_type == "SPIKE")
+                        .enumerate()
+                        .filter(|(_, m)| inside(&m.source))
+                        .map(|(i, _)| i)
+                        .collect();
+                    synapse::renumber(&mut element, &kept);
+                }
+                for messages in [&mut element.messages_in, &mut element.messages_out] {
+                    messages.retain(|m| inside(&m.source) && inside(&m.dest));
+                    for m in messages.iter_mut() {
+                        m.source = rename(&m.source);
+                        m.dest = rename(&m.dest);
+                    }
+                }
+                element
+            })
+            .collect();
+        // The root of the copy goes first so the parent learns of it
+        let root = copies.remove(0);
+        let element_type = root.element_type.clone();
+        *self.create(&target, element_type) = root;
+        for element in copies {
+            self.elements.insert(element.path.clone(), element);
+        }
+        Ok(target)
+    }
+
     /// Get an element
     pub fn get(&self, path: &str) -> Option<&Element> {
         self.elements.get(path)
@@ -173,10 +581,13 @@ impl GenesisSimulation {
 
     /// Get a mutable element
     pub fn get_mut(&mut self, path: &str) -> Option<&mut Element> {
+        // The caller may change the clock or type of the element
+        self.tasks = None;
         self.elements.get_mut(path)
     }
 
-    /// Add a message between elements
+    /// Add a message between elements, checking its type and slots (see
+    /// [`messages`])
     pub fn add_message(
         &mut self,
         source: &str,
@@ -185,6 +596,18 @@ impl GenesisSimulation {
         dest_field: &str,
         msg_type: &str,
     ) -> Result<()> {
+        match custom::message_slots(self, dest, msg_type) {
+            Some(slots) => messages::check_slots(msg_type, slots, source_field)?,
+            None => messages::check(msg_type, source_field)?,
+        }
+        for path in [source, dest] {
+            if !self.elements.contains_key(path) {
+                return Err(OldiesError::ModelNotFound(path.to_string()));
+            }
+        }
+        if msg_type == "SPIKE" {
+            synapse::add_synapse(self.elements.get_mut(dest).unwrap())?;
+        }
         let msg = Message {
             source: source.to_string(),
             source_field: source_field.to_string(),
@@ -193,40 +616,115 @@ impl GenesisSimulation {
             msg_type: msg_type.to_string(),
         };
 
-        // Add to source's outgoing
-        if let Some(elem) = self.elements.get_mut(source) {
-            elem.messages_out.push(msg.clone());
-        } else {
-            return Err(OldiesError::ModelNotFound(source.to_string()));
-        }
+        self.elements.get_mut(source).unwrap().messages_out.push(msg.clone());
+        self.elements.get_mut(dest).unwrap().messages_in.push(msg);
+        Ok(())
+    }
 
-        // Add to destination's incoming
-        if let Some(elem) = self.elements.get_mut(dest) {
-            elem.messages_in.push(msg);
-        } else {
-            return Err(OldiesError::ModelNotFound(dest.to_string()));
+    /// Set the time step of clock `n`; the simulation advances by the
+    /// smallest clock
+    pub fn set_clock(&mut self, n: usize, dt: Time) -> Result<()> {
+        if dt.is_nan() || dt <= 0.0 {
+            return Err(OldiesError::SimulationError(format!("clock {} needs a positive time step, not {}", n, dt)));
         }
+        self.tasks = None;
+        self.clocks.insert(n, dt);
+        self.dt = self.clocks.values().copied().fold(f64::INFINITY, f64::min);
+        Ok(())
+    }
+
+    /// Time step of clock `n`
+    pub fn clock(&self, n: usize) -> Option<Time> {
+        self.clocks.get(&n).copied()
+    }
 
+    /// Update the element at `path` on clock `n`
+    pub fn use_clock(&mut self, path: &str, n: usize) -> Result<()> {
+        if !self.clocks.contains_key(&n) {
+            return Err(OldiesError::SimulationError(format!("clock {} has not been set", n)));
+        }
+        self.tasks = None;
+        let element = self.elements.get_mut(path)
+            .ok_or_else(|| OldiesError::ModelNotFound(path.to_string()))?;
+        element.clock = n;
         Ok(())
     }
 
+    /// Return to time zero: every field with an `init` counterpart
+    /// (`Vm` and `initVm`) takes its initial value and recordings are
+    /// cleared. Channel gates start at their steady state.
+    pub fn reset(&mut self) -> Result<()> {
+        self.time = 0.0;
+        self.next_tick.clear();
+        for element in self.elements.values_mut() {
+            let initial: Vec<(String, f64)> = element.params.iter()
+                .filter_map(|(name, &value)| Some((name.strip_prefix("init")?.to_string(), value)))
+                .filter(|(name, _)| element.params.contains_key(name))
+                .collect();
+            element.params.extend(initial);
+        }
+        xodus::reset(self);
+        kinetics::reset(self);
+        concen::reset(self);
+        synapse::reset(self)?;
+        devices::reset(self)?;
+        efield::reset(self);
+        output::reset(self)?;
+        solver::reset(self)?;
+        custom::reset(self)?;
+        hsolve::reset(self)
+    }
+
+    /// Values plotted as `plot` (`/form/graph/name`) since `reset`
+    pub fn recording(&self, plot: &str) -> Option<&TimeSeries> {
+        self.recordings.get(plot)
+    }
+
+    /// Paths of the plots recorded since `reset`, sorted
+    pub fn recording_paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = self.recordings.keys().map(String::as_str).collect();
+        paths.sort();
+        paths
+    }
+
+    /// Values shown by the `xcell` at `path` since `reset`
+    pub fn snapshots(&self, path: &str) -> Option<&xodus::Snapshots> {
+        self.snapshots.get(path)
+    }
+
     /// Run simulation step
-    pub fn step(&mut self) {
-        // TODO: Implement actual simulation logic
+    pub fn step(&mut self) -> Result<()> {
+        schedule::step(self)?;
         self.time += self.dt;
+        Ok(())
     }
 
     /// Run simulation for specified duration
-    pub fn run(&mut self, duration: Time) {
+    pub fn run(&mut self, duration: Time) -> Result<()> {
         let steps = (duration / self.dt) as usize;
         for _ in 0..steps {
-            self.step();
+            self.step()?;
+        }
+        self.flush()
+    }
+
+    /// Write out what output elements hold in their buffers
+    pub fn flush(&mut self) -> Result<()> {
+        for writer in self.files.values_mut() {
+            writer.flush()?;
         }
+        Ok(())
     }
 
-    /// Set time step
+    /// Set time step (clock 0)
     pub fn set_dt(&mut self, dt: Time) {
-        self.dt = dt;
+        self.clocks.insert(0, dt);
+        self.dt = self.clocks.values().copied().fold(f64::INFINITY, f64::min);
+    }
+
+    /// Time step
+    pub fn dt(&self) -> Time {
+        self.dt
     }
 
     /// Get current time
@@ -254,30 +752,296 @@ pub mod objects {
         elem.set_param("Em", -0.065);   // Resting potential (V)
         elem.set_param("initVm", -0.065);
         elem.set_param("Vm", -0.065);
+        elem.set_param("inject", 0.0);  // Injected current (A)
+        elem.set_param("Im", 0.0);      // Membrane current (A)
+        elem.set_param("dia", 0.0);     // Diameter (m)
+        elem.set_param("len", 0.0);     // Length (m)
         elem
     }
 
-    /// Create HH sodium channel
+    /// Set the rate of an `hh_channel` gate: `form` 1 is exponential, 2
+    /// sigmoid and 3 linoid (see [`crate::solver`])
+    pub fn hh_rate(elem: &mut Element, rate: &str, form: f64, a: f64, b: f64, v0: f64) {
+        elem.set_param(&format!("{}_FORM", rate), form);
+        elem.set_param(&format!("{}_A", rate), a);
+        elem.set_param(&format!("{}_B", rate), b);
+        elem.set_param(&format!("{}_V0", rate), v0);
+    }
+
+    /// Create HH sodium channel (squid kinetics, m^3 h)
     pub fn na_channel<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let erest = -0.07;
         let elem = sim.create(path, ElementType::NaChannel);
-        elem.set_param("Gbar", 0.12);   // Max conductance (S/cm^2)
-        elem.set_param("Ek", 0.045);    // Reversal potential (V)
+        elem.set_param("Gbar", 1.2e-6);  // Max conductance (S), 1200 S/m^2 over 1e-9 m^2
+        elem.set_param("Ek", 0.045);     // Reversal potential (V)
+        for field in ["Gk", "Ik", "X", "Y"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("Xpower", 3.0);
+        elem.set_param("Ypower", 1.0);
+        hh_rate(elem, "X_alpha", 3.0, -1e5, -0.010, erest + 0.025);
+        hh_rate(elem, "X_beta", 1.0, 4e3, -0.018, erest);
+        hh_rate(elem, "Y_alpha", 1.0, 70.0, -0.020, erest);
+        hh_rate(elem, "Y_beta", 2.0, 1e3, -0.010, erest + 0.030);
         elem
     }
 
-    /// Create HH potassium channel
+    /// Create HH potassium channel (squid kinetics, n^4)
     pub fn k_channel<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let erest = -0.07;
         let elem = sim.create(path, ElementType::KChannel);
-        elem.set_param("Gbar", 0.036);  // Max conductance (S/cm^2)
-        elem.set_param("Ek", -0.082);   // Reversal potential (V)
+        elem.set_param("Gbar", 3.6e-7);  // Max conductance (S), 360 S/m^2 over 1e-9 m^2
+        elem.set_param("Ek", -0.082);    // Reversal potential (V)
+        for field in ["Gk", "Ik", "X"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("Xpower", 4.0);
+        elem.set_param("Ypower", 0.0);
+        hh_rate(elem, "X_alpha", 3.0, -1e4, -0.010, erest + 0.010);
+        hh_rate(elem, "X_beta", 1.0, 125.0, -0.080, erest);
+        elem
+    }
+
+    /// Create a channel with tabulated gates, to be filled with
+    /// `setupalpha`, `setuptau` or `TABCREATE`
+    pub fn tabchannel<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::TabChannel);
+        for field in ["Gbar", "Ek", "Gk", "Ik", "Xpower", "Ypower", "Zpower", "X", "Y", "Z", "instant", "Z_conc"] {
+            elem.set_param(field, 0.0);
+        }
+        elem
+    }
+
+    /// Create a calcium pool
+    pub fn ca_concen<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::CaConcen);
+        for field in ["Ca", "Ca_base", "B", "thick"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("tau", 0.01);  // Decay time constant (s)
+        elem
+    }
+
+    /// Create a Hines solver, set up with `call solver SETUP`
+    pub fn hsolve<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::HSolve);
+        elem.strings.insert("path".to_string(), String::new());
+        elem.set_param("chanmode", 0.0);
+        elem
+    }
+
+    /// Create a parameter table of the search `element_type`
+    pub fn param_table<'a>(sim: &'a mut GenesisSimulation, path: &str, element_type: ElementType) -> &'a mut Element {
+        let elem = sim.create(path, element_type.clone());
+        for field in ["num_params", "current_match", "best_match", "done", "iteration_number"] {
+            elem.set_param(field, 0.0);
+        }
+        match element_type {
+            ElementType::ParamTableBF => elem.set_param("divisions", 5.0),  // Grid points per parameter
+            ElementType::ParamTableSA => {
+                elem.set_param("max_iterations", 1000.0);
+                elem.set_param("inittemp", 1.0);
+                elem.set_param("annealing_rate", 0.99);  // Cooling per iteration
+                elem.set_param("step", 0.1);  // Proposal size, in ranges
+            }
+            _ => {
+                elem.set_param("num_individuals", 20.0);
+                elem.set_param("max_generations", 50.0);
+                elem.set_param("crossover_probability", 0.5);
+                elem.set_param("mutation_probability", 0.1);
+                elem.set_param("generation", 0.0);
+            }
+        }
+        elem
+    }
+
+    /// Create an Xodus graph
+    pub fn xgraph<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::XGraph);
+        for (field, value) in [("xmin", 0.0), ("xmax", 1.0), ("ymin", 0.0), ("ymax", 1.0), ("overlay", 0.0)] {
+            elem.set_param(field, value);
+        }
+        elem.strings.insert("title".to_string(), String::new());
+        elem
+    }
+
+    /// Create a plot of an Xodus graph
+    pub fn xplot<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::XPlot);
+        elem.set_param("scale", 1.0);
+        elem.set_param("offset", 0.0);
+        elem.strings.insert("fg".to_string(), String::new());  // Color
+        elem
+    }
+
+    /// Create an Xodus cell view
+    pub fn xcell<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::XCell);
+        elem.set_param("colmin", 0.0);
+        elem.set_param("colmax", 1.0);
+        elem.strings.insert("path".to_string(), String::new());
+        elem.strings.insert("field".to_string(), "Vm".to_string());
+        elem
+    }
+
+    /// Create a spike generator, firing when its input reaches `thresh`
+    pub fn spikegen<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::SpikeGen);
+        for field in ["thresh", "abs_refract", "edge_triggered", "state", "lastevent", "input"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("output_amp", 1.0);
         elem
     }
+
+    /// Create a pulse generator, running freely until given a `trig_mode`
+    pub fn pulsegen<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::PulseGen);
+        for field in ["level1", "width1", "delay1", "level2", "width2", "delay2", "baselevel", "trig_mode",
+            "previous", "output"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("trig_time", -1.0);
+        elem
+    }
+
+    /// Create a source of spikes at random times, silent until given a
+    /// `rate` (1/s)
+    pub fn randomspike<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::RandomSpike);
+        for field in ["rate", "abs_refract", "reset_value", "state", "lastevent"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("min_amp", 1.0);
+        elem.set_param("max_amp", 1.0);
+        elem.set_param("reset", 1.0);
+        elem
+    }
+
+    /// Create a source of Gaussian noise, white until given a `tau` (s)
+    pub fn noisegen<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::NoiseGen);
+        for field in ["mean", "sd", "tau", "output"] {
+            elem.set_param(field, 0.0);
+        }
+        elem
+    }
+
+    /// Create an extracellular electrode in a medium of the default
+    /// conductivity (see [`crate::efield`])
+    pub fn efield<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::EField);
+        elem.set_param("scale", efield::default_scale());  // 1 / (4 pi sigma) (ohm m)
+        elem.set_param("field", 0.0);                       // Potential (V)
+        elem
+    }
+
+    /// Create a synaptic channel with a dual exponential conductance
+    pub fn synchan<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::Synapse);
+        for field in ["Ek", "gmax", "Gk", "Ik", "X", "Y", "activation"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("tau1", 1e-3);  // Time constants (s)
+        elem.set_param("tau2", 1e-3);
+        elem
+    }
+
+    /// Create an output element writing what it is sent with SAVE
+    /// messages as text (`asc_file`) or binary FMT1 (`disk_out`)
+    pub fn output<'a>(sim: &'a mut GenesisSimulation, path: &str, element_type: ElementType) -> &'a mut Element {
+        let elem = sim.create(path, element_type);
+        elem.strings.insert("filename".into(), String::new());
+        elem.set_param("append", 0.0);
+        if matches!(elem.element_type, ElementType::AscFile) {
+            elem.set_param("notime", 0.0);
+        }
+        elem
+    }
+
+    /// Create a pool of molecules, with `n` molecules in volume `vol`
+    pub fn kpool<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::Pool);
+        for field in ["n", "nInit", "Co", "CoInit", "slave_enable"] {
+            elem.set_param(field, 0.0);
+        }
+        elem.set_param("vol", 1.0);
+        elem
+    }
+
+    /// Create a reversible mass-action reaction
+    pub fn kreac<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::Reaction);
+        elem.set_param("kf", 0.1);  // Forward rate (1/s per substrate molecule)
+        elem.set_param("kb", 0.1);  // Backward rate (1/s per product molecule)
+        elem.set_param("A", 0.0);
+        elem.set_param("B", 0.0);
+        elem
+    }
+
+    /// Create an enzyme, explicit (`mode` 0) or Michaelis-Menten (`mode` 1)
+    pub fn kenz<'a>(sim: &'a mut GenesisSimulation, path: &str) -> &'a mut Element {
+        let elem = sim.create(path, ElementType::Enzyme);
+        let (k1, k2, k3) = (0.1, 0.4, 0.1);
+        elem.set_param("k1", k1);
+        elem.set_param("k2", k2);
+        elem.set_param("k3", k3);
+        elem.set_param("Km", (k2 + k3) / k1);
+        elem.set_param("kcat", k3);
+        for field in ["mode", "nComplex", "nComplexInit", "sA", "eA", "pA", "B"] {
+            elem.set_param(field, 0.0);
+        }
+        elem
+    }
+
+    /// Fields of elements of the GENESIS object `object`, sorted
+    pub fn fields(object: &str) -> Result<Vec<String>> {
+        let mut sim = GenesisSimulation::new();
+        Ok(create(&mut sim, object, "/prototype")?.fields())
+    }
+
+    /// Create an element of the GENESIS object `object`, as `create` does,
+    /// or of an object in the simulation's registry (see [`crate::custom`])
+    pub fn create<'a>(sim: &'a mut GenesisSimulation, object: &str, path: &str) -> Result<&'a mut Element> {
+        Ok(match object {
+            "neutral" => sim.create(path, ElementType::Neutral),
+            "compartment" => compartment(sim, path),
+            "Na_squid_hh" => na_channel(sim, path),
+            "K_squid_hh" => k_channel(sim, path),
+            "tabchannel" => tabchannel(sim, path),
+            "Ca_concen" => ca_concen(sim, path),
+            "hsolve" => hsolve(sim, path),
+            "paramtableBF" => param_table(sim, path, ElementType::ParamTableBF),
+            "paramtableSA" => param_table(sim, path, ElementType::ParamTableSA),
+            "paramtableGA" => param_table(sim, path, ElementType::ParamTableGA),
+            "spikegen" => spikegen(sim, path),
+            "synchan" => synchan(sim, path),
+            "pulsegen" => pulsegen(sim, path),
+            "randomspike" => randomspike(sim, path),
+            "noisegen" => noisegen(sim, path),
+            "efield" => efield(sim, path),
+            "asc_file" => output(sim, path, ElementType::AscFile),
+            "disk_out" => output(sim, path, ElementType::DiskOut),
+            "kpool" => kpool(sim, path),
+            "kreac" => kreac(sim, path),
+            "kenz" => kenz(sim, path),
+            "xgraph" => xgraph(sim, path),
+            "xplot" => xplot(sim, path),
+            "xcell" => xcell(sim, path),
+            widget if xodus::WIDGETS.contains(&widget) => sim.create(path, ElementType::XWidget(widget.to_string())),
+            _ => match custom::create(sim, object, path) {
+                Some(element) => element,
+                None => return Err(OldiesError::ModelNotFound(format!("GENESIS object '{}'", object))),
+            },
+        })
+    }
 }
 
 /// Load and execute a GENESIS script
-pub fn load_script(_script: &str) -> Result<GenesisSimulation> {
-    // TODO: Implement full script parser
-    Ok(GenesisSimulation::new())
+pub fn load_script(script: &str) -> Result<GenesisSimulation> {
+    let mut sli = sli::Sli::new();
+    sli.execute(script)?;
+    Ok(sli.sim)
 }
 
 #[cfg(test)]
@@ -293,13 +1057,78 @@ mod tests {
         assert!(soma.get_param("Rm").is_some());
     }
 
+    #[test]
+    fn test_library_prototypes() {
+        let mut sli = sli::Sli::new();
+        sli.execute(r#"
+            create neutral /library
+            create compartment /library/soma
+            setfield /library/soma Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07 inject 1e-10
+            create synchan /library/soma/syn
+            create spikegen /library/soma/spike
+            addmsg /library/soma /library/soma/spike INPUT Vm
+            addmsg /library/soma/spike /library/soma/syn SPIKE
+            setfield /library/soma/syn synapse[0].weight 3
+
+            create neutral /net
+            copy /library/soma /net/a
+            copy /library/soma /net/b
+            addmsg /net/a/spike /net/b/syn SPIKE
+            setfield /net/b/syn synapse[1].weight 5
+            copy /net/b /net/c
+            reset
+            step 100
+        "#).unwrap();
+        let sim = &sli.sim;
+        let vm = |path: &str| sim.get(path).unwrap().get_param("Vm").unwrap();
+
+        // Prototypes are copied with their fields, children and internal
+        // messages, but not simulated
+        assert_eq!(vm("/library/soma"), -0.07);
+        assert!(vm("/net/a") > -0.07 && vm("/net/a") == vm("/net/c"));
+        assert!(!sim.is_simulated("/library/soma/syn") && sim.is_simulated("/net/a/syn"));
+        assert_eq!(sim.get("/net/a").unwrap().children, vec!["/net/a/syn", "/net/a/spike"]);
+        assert_eq!(sim.get("/net/a/spike").unwrap().messages_out[0].dest, "/net/a/syn");
+        assert_eq!(synapse::synapse_count(sim.get("/net/b/syn").unwrap()), 2);
+        // The copy of /net/b leaves the synapse from /net/a behind
+        let c = sim.get("/net/c/syn").unwrap();
+        assert_eq!(synapse::synapse_count(c), 1);
+        assert_eq!(c.get_param("synapse[0].weight"), Some(3.0));
+        assert_eq!(c.get_param("synapse[1].weight"), None);
+
+        sli.execute("disable /net/a; enable /library; reset; step 10").unwrap();
+        assert!(sli.sim.get("/library/soma").unwrap().get_param("Vm").unwrap() > -0.07);
+        assert_eq!(sli.sim.get("/net/a").unwrap().get_param("Vm"), Some(-0.07));
+        assert!(sli.execute("disable /nowhere").is_err());
+    }
+
+    #[test]
+    fn test_fields() {
+        let fields = objects::fields("Ca_concen").unwrap();
+        assert_eq!(fields, ["B", "Ca", "Ca_base", "tau", "thick", "x", "y", "z"]);
+        assert!(objects::fields("asc_file").unwrap().contains(&"filename".to_string()));
+        assert!(objects::fields("squid").is_err());
+
+        let mut sim = GenesisSimulation::new();
+        let pool = objects::ca_concen(&mut sim, "/Ca");
+        assert!(pool.has_field("tau") && !pool.has_field("Vm"));
+        assert!(pool.set_field("Vm", 1.0).is_err());
+        pool.add_field("Vm").unwrap();
+        pool.set_field("Vm", 0.5).unwrap();
+        assert!(pool.add_field("Vm").is_err());
+        assert_eq!(pool.field_text("Vm").as_deref(), Some("0.5"));
+        assert_eq!(pool.show(&["tau", "Vm"]).unwrap(), "[ /Ca ]\ntau                  = 0.01\nVm                   = 0.5\n");
+        assert_eq!(pool.show(&[]).unwrap().lines().count(), 10);
+        assert!(pool.show(&["Rm"]).is_err());
+    }
+
     #[test]
     fn test_simulation_step() {
         let mut sim = GenesisSimulation::new();
         sim.set_dt(0.001);
         assert_eq!(sim.current_time(), 0.0);
 
-        sim.step();
+        sim.step().unwrap();
         assert!((sim.current_time() - 0.001).abs() < 1e-10);
     }
 }
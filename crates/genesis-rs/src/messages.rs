@@ -0,0 +1,134 @@
+//! Message dataflow
+//!
+//! A GENESIS message carries the values of fields of its source, its
+//! slots, to its destination, which reads them when it is processed:
+//! `addmsg /soma/Na /soma CHANNEL Gk Ek` hands the compartment the
+//! conductance and reversal potential of the channel at every step. Values
+//! are read from the source when the destination updates, so the order of
+//! the schedule (see [`crate::schedule`]) decides whether a destination
+//! sees the source's present or previous step.
+//!
+//! | Type | Slots | Destination |
+//! |------|-------|-------------|
+//! | `VOLTAGE` | potential | channel |
+//! | `CHANNEL` | conductance, reversal potential | compartment |
+//! | `AXIAL` | potential of the parent | compartment |
+//! | `RAXIAL` | axial resistance, potential of the child | compartment |
+//! | `INJECT` | current, added to `inject` | compartment |
+//! | `CURRENT` | membrane current | efield |
+//! | `INPUT` | potential, or trigger | spikegen, pulsegen |
+//! | `SPIKE` | none, adds a synapse (from a spikegen or randomspike) | synchan |
+//! | `SAVE` | value to write | asc_file, disk_out |
+//! | `I_Ca` | calcium current | Ca_concen |
+//! | `CONCEN` | concentration, for a `Z_conc` gate | channel |
+//! | `PLOT` | value to plot, named by the destination field | xgraph |
+//! | `SUBSTRATE` | molecules of a substrate | reaction, enzyme |
+//! | `PRODUCT` | molecules of a product | reaction |
+//! | `ENZYME` | molecules of the enzyme | enzyme |
+//! | `REAC` | flux into and out of the pool | pool |
+//! | `MM_PRD` | flux into the pool | pool |
+//!
+//! Objects registered by other crates add message types of their own (see
+//! [`crate::custom`]).
+//!
+//! Slots name fields of the source (any field [`Element::get_field`]
+//! reads), so `addmsg /pulse /soma INJECT output` injects whatever the
+//! source computes in `output`.
+
+use crate::{Element, GenesisSimulation, Message};
+use oldies_core::{OldiesError, Result};
+
+/// Message types and their numbers of slots
+pub const MESSAGE_TYPES: &[(&str, usize)] = &[
+    ("VOLTAGE", 1),
+    ("CHANNEL", 2),
+    ("AXIAL", 1),
+    ("RAXIAL", 2),
+    ("INJECT", 1),
+    ("CURRENT", 1),
+    ("INPUT", 1),
+    ("SPIKE", 0),
+    ("SAVE", 1),
+    ("I_Ca", 1),
+    ("CONCEN", 1),
+    ("PLOT", 1),
+    ("SUBSTRATE", 1),
+    ("PRODUCT", 1),
+    ("ENZYME", 1),
+    ("REAC", 2),
+    ("MM_PRD", 1),
+];
+
+/// Check that `msg_type` is known and given the right number of slots
+pub fn check(msg_type: &str, slots: &str) -> Result<()> {
+    let &(_, expected) = MESSAGE_TYPES.iter()
+        .find(|(name, _)| *name == msg_type)
+        .ok_or_else(|| OldiesError::SimulationError(format!("unknown message type {}", msg_type)))?;
+    check_slots(msg_type, expected, slots)
+}
+
+/// Check that `slots` names the `expected` number of fields
+pub fn check_slots(msg_type: &str, expected: usize, slots: &str) -> Result<()> {
+    let given = slots.split_whitespace().count();
+    if given != expected {
+        return Err(OldiesError::SimulationError(format!(
+            "{} messages carry {} slot(s), not {}", msg_type, expected, given
+        )));
+    }
+    Ok(())
+}
+
+/// Present values of the slots of `msg`
+pub fn values(sim: &GenesisSimulation, msg: &Message) -> Result<Vec<f64>> {
+    let source = sim.elements.get(&msg.source).ok_or_else(|| OldiesError::ModelNotFound(msg.source.clone()))?;
+    msg.source_field.split_whitespace()
+        .map(|field| {
+            source.get_field(field).ok_or_else(|| OldiesError::SimulationError(format!(
+                "{} message from {} to {}: no field {}", msg.msg_type, msg.source, msg.dest, field
+            )))
+        })
+        .collect()
+}
+
+/// Slot values of the messages of type `msg_type` arriving at `element`,
+/// with the messages
+pub fn inputs<'a>(sim: &GenesisSimulation, element: &'a Element, msg_type: &str) -> Result<Vec<(&'a Message, Vec<f64>)>> {
+    element.messages_in.iter()
+        .filter(|m| m.msg_type == msg_type)
+        .map(|m| Ok((m, values(sim, m)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    #[test]
+    fn test_message_slots() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create compartment /a
+            create compartment /b
+            addfield /a current
+            setfield /a Rm 1e8 Cm 1e-11 Em 0 initVm 0 current 2e-10
+            setfield /b Rm 1e8 Cm 1e-11 Em 0 initVm 0
+            // /a injects whatever is in its field "current" into /b
+            addmsg /a /b INJECT current
+            reset
+            step 0.01 -time
+        "#).unwrap();
+        let vm = |sli: &Sli, p: &str| sli.sim.get(p).unwrap().get_param("Vm").unwrap();
+        assert!(vm(&sli, "/a").abs() < 1e-12);
+        assert!((vm(&sli, "/b") - 0.02).abs() < 1e-6, "{}", vm(&sli, "/b"));
+
+        // Changing the source field changes what arrives
+        sli.execute("setfield /a current -1e-10; step 0.02 -time").unwrap();
+        assert!((vm(&sli, "/b") + 0.01).abs() < 1e-6);
+
+        for bad in ["addmsg /a /b INJECT", "addmsg /a /b CHANNEL Gk", "addmsg /a /b TELEPORT Vm"] {
+            assert!(sli.execute(bad).is_err(), "{}", bad);
+        }
+        sli.execute("addmsg /a /b INJECT nothing").unwrap();
+        assert!(sli.execute("step").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! Reference models
+//!
+//! Native constructions of the canonical GENESIS tutorial models, built
+//! from the same objects, prototypes and messages their scripts create.
+//! They serve as examples of building models from Rust and, with the
+//! golden outputs of their tests, as regression checks of the solvers.
+//!
+//! - [`squid`]: the squid giant axon of the `Squid` tutorial, a 500 um
+//!   cylinder with the Hodgkin-Huxley channels
+//! - [`traub91`]: the 19-compartment CA3 pyramidal cell of Traub et al.
+//!   (1991) from the `traub91` tutorial: a soma between an apical dendrite
+//!   of ten compartments and a basal dendrite of eight, with fast sodium,
+//!   calcium, delayed rectifier, transient (A), calcium-activated (C) and
+//!   afterhyperpolarization (AHP) potassium channels and a calcium pool in
+//!   every compartment with calcium channels
+//! - [`purkinje`]: a simplified cerebellar Purkinje cell after De Schutter
+//!   and Bower (1994): a spherical soma firing fast sodium spikes, and a
+//!   branching dendrite of 15 compartments whose P-type calcium and
+//!   calcium-activated potassium channels fire calcium spikes
+//!
+//! Channel prototypes are created in [`LIBRARY`] when missing, as the
+//! tutorials' `make_*` functions do, and inserted with
+//! [`readcell`](crate::readcell::readcell). Calcium is in the arbitrary
+//! units of Traub's model, where the C and AHP channels open fully at 250
+//! and 500. Each model returns the paths of its compartments, soma first;
+//! nothing is injected, and `reset` starts it at rest.
+
+use crate::tabchannel::{self, Form, DEFAULT_RANGE, DEFAULT_XDIVS};
+use crate::{objects, readcell, Element, GenesisSimulation, LIBRARY};
+use oldies_core::{OldiesError, Result};
+use std::f64::consts::PI;
+
+/// Resting potential of the squid axon (V)
+const SQUID_EREST: f64 = -0.07;
+/// Resting potential of the CA3 cell (V)
+const TRAUB_EREST: f64 = -0.06;
+/// Resting potential of the Purkinje cell (V)
+const PURKINJE_EREST: f64 = -0.068;
+/// Area of the CA3 soma (m^2), which Traub's calcium scaling refers to
+const SOMA_A: f64 = 3.320e-9;
+/// Calcium per unit of charge in the CA3 soma (`B` of its pool)
+const SOMA_B: f64 = 17.402e12;
+/// Calcium decay time constant (s)
+const CA_TAU: f64 = 0.01333;
+/// Range of the calcium tables
+const CA_RANGE: (f64, f64) = (0.0, 1000.0);
+
+/// Create the squid axon of the `Squid` tutorial at `path`: one
+/// compartment 500 um long and wide with `Na` and `K` channels, returning
+/// its path
+pub fn squid(sim: &mut GenesisSimulation, path: &str) -> Result<String> {
+    let (len, dia) = (500e-6, 500e-6);
+    let area = PI * dia * len;
+    let axon = objects::compartment(sim, path);
+    for (field, value) in [
+        ("Rm", 0.33333 / area),  // RM (ohm m^2) over the area
+        ("Cm", 0.01 * area),     // CM (F/m^2)
+        ("Ra", 0.3 * len / (PI * dia * dia / 4.0)),
+        ("Em", SQUID_EREST + 0.0106),
+        ("initVm", SQUID_EREST),
+        ("Vm", SQUID_EREST),
+        ("dia", dia),
+        ("len", len),
+    ] {
+        axon.set_param(field, value);
+    }
+    objects::na_channel(sim, &format!("{}/Na", path)).set_param("Gbar", 1200.0 * area);
+    objects::k_channel(sim, &format!("{}/K", path)).set_param("Gbar", 360.0 * area);
+    for channel in ["Na", "K"] {
+        let channel = format!("{}/{}", path, channel);
+        sim.add_message(path, "Vm", &channel, "VOLTAGE", "VOLTAGE")?;
+        sim.add_message(&channel, "Gk Ek", path, "CHANNEL", "CHANNEL")?;
+    }
+    Ok(path.to_string())
+}
+
+/// Create the tabchannel prototype `/library/<name>` unless it exists,
+/// calling `build` to fill it
+fn prototype(sim: &mut GenesisSimulation, name: &str, build: impl FnOnce(&mut Element) -> Result<()>) -> Result<()> {
+    if !sim.exists(LIBRARY) {
+        objects::create(sim, "neutral", LIBRARY)?;
+    }
+    let path = format!("{}/{}", LIBRARY, name);
+    if !sim.exists(&path) {
+        build(objects::tabchannel(sim, &path))?;
+    }
+    Ok(())
+}
+
+fn set(channel: &mut Element, fields: &[(&str, f64)]) {
+    for &(field, value) in fields {
+        channel.set_param(field, value);
+    }
+}
+
+/// Fill the tables of `gate` over `(xmin, xmax)` from `rates`, giving
+/// alpha and beta
+fn tabulate(channel: &mut Element, gate: &str, (xmin, xmax): (f64, f64), rates: impl Fn(f64) -> (f64, f64)) -> Result<()> {
+    tabchannel::tabcreate(channel, gate, DEFAULT_XDIVS, xmin, xmax)?;
+    let (a, b) = (format!("{}_A", gate), format!("{}_B", gate));
+    for i in 0..=DEFAULT_XDIVS {
+        let (alpha, beta) = rates(channel.tables[&a].x(i));
+        channel.tables.get_mut(&a).unwrap().values[i] = alpha;
+        channel.tables.get_mut(&b).unwrap().values[i] = alpha + beta;
+    }
+    Ok(())
+}
+
+/// `setupalpha` over the default range
+fn setup_alpha(channel: &mut Element, gate: &str, params: [f64; 10]) -> Result<()> {
+    tabchannel::setup(channel, gate, Form::Alpha, &params, DEFAULT_XDIVS, DEFAULT_RANGE.0, DEFAULT_RANGE.1)
+}
+
+/// The C channel's activation in Traub's form (s^-1), with `v` in mV
+/// above rest
+fn k_c_rates(v: f64) -> (f64, f64) {
+    let beta = 2e3 * ((6.5 - v) / 27.0).exp();
+    if v <= 50.0 {
+        let alpha = 1e3 * ((v - 10.0) / 11.0 - (v - 6.5) / 27.0).exp() / 18.975;
+        (alpha, beta - alpha)
+    } else {
+        (beta, 0.0)
+    }
+}
+
+/// Create the channel prototypes of the `traub91` tutorial
+fn traub91_prototypes(sim: &mut GenesisSimulation) -> Result<()> {
+    let e = TRAUB_EREST;
+    let (ena, ek, eca) = (e + 0.115, e - 0.015, e + 0.140);
+    prototype(sim, "Na", |na| {
+        set(na, &[("Ek", ena), ("Xpower", 2.0), ("Ypower", 1.0)]);
+        setup_alpha(na, "X", [
+            320e3 * (0.0131 + e), -320e3, -1.0, -(0.0131 + e), -0.004,
+            -280e3 * (0.0401 + e), 280e3, -1.0, -(0.0401 + e), 0.005,
+        ])?;
+        setup_alpha(na, "Y", [128.0, 0.0, 0.0, -(0.017 + e), 0.018, 4e3, 0.0, 1.0, -(0.040 + e), -0.005])
+    })?;
+    prototype(sim, "Ca", |ca| {
+        set(ca, &[("Ek", eca), ("Xpower", 2.0), ("Ypower", 1.0)]);
+        setup_alpha(ca, "X", [
+            1.6e3, 0.0, 1.0, -(0.065 + e), -0.01388,
+            -20e3 * (0.0511 + e), 20e3, -1.0, -(0.0511 + e), 0.005,
+        ])?;
+        // Inactivation: alpha 5/s below rest, decaying above it
+        tabulate(ca, "Y", DEFAULT_RANGE, |v| {
+            let alpha = if v > e { 5.0 * (-50.0 * (v - e)).exp() } else { 5.0 };
+            (alpha, 5.0 - alpha)
+        })
+    })?;
+    prototype(sim, "K_DR", |kdr| {
+        set(kdr, &[("Ek", ek), ("Xpower", 1.0)]);
+        setup_alpha(kdr, "X", [
+            16e3 * (0.0351 + e), -16e3, -1.0, -(0.0351 + e), -0.005,
+            250.0, 0.0, 0.0, -(0.02 + e), 0.04,
+        ])
+    })?;
+    prototype(sim, "K_A", |ka| {
+        set(ka, &[("Ek", ek), ("Xpower", 1.0), ("Ypower", 1.0)]);
+        setup_alpha(ka, "X", [
+            20e3 * (0.0131 + e), -20e3, -1.0, -(0.0131 + e), -0.01,
+            -17.5e3 * (0.0401 + e), 17.5e3, -1.0, -(0.0401 + e), 0.01,
+        ])?;
+        setup_alpha(ka, "Y", [1.6, 0.0, 0.0, 0.013 - e, 0.018, 50.0, 0.0, 1.0, -(0.0101 + e), -0.005])
+    })?;
+    // Calcium-dependent gates: AHP opens with alpha 0.02 Ca /s up to
+    // 10/s, C in proportion to Ca up to 250, instantly
+    prototype(sim, "K_AHP", |ahp| {
+        set(ahp, &[("Ek", ek), ("Zpower", 1.0), ("Z_conc", 1.0)]);
+        tabulate(ahp, "Z", CA_RANGE, |ca| ((0.02 * ca).min(10.0), 1.0))
+    })?;
+    prototype(sim, "K_C", |kc| {
+        set(kc, &[("Ek", ek), ("Xpower", 1.0), ("Zpower", 1.0), ("Z_conc", 1.0), ("instant", 4.0)]);
+        tabulate(kc, "X", DEFAULT_RANGE, |v| k_c_rates(1e3 * (v - e)))?;
+        tabulate(kc, "Z", CA_RANGE, |ca| ((ca / 250.0).min(1.0), 1.0 - (ca / 250.0).min(1.0)))
+    })
+}
+
+/// Give every compartment of `cell` with a `Ca` channel a calcium pool
+/// `Ca_conc`, fed by the channel and read by its `K_C` and `K_AHP`
+/// channels. `B` scales with the inverse of the area, as in Traub's model.
+fn calcium_pools(sim: &mut GenesisSimulation, compartments: &[String], ca: &str) -> Result<()> {
+    for compartment in compartments {
+        let channel = format!("{}/{}", compartment, ca);
+        if !sim.exists(&channel) {
+            continue;
+        }
+        let c = sim.get(compartment).unwrap();
+        let (dia, len) = (c.get_param("dia").unwrap_or(0.0), c.get_param("len").unwrap_or(0.0));
+        let area = if len == 0.0 { PI * dia * dia } else { PI * dia * len };
+        let pool = format!("{}/Ca_conc", compartment);
+        let element = objects::ca_concen(sim, &pool);
+        set(element, &[("tau", CA_TAU), ("B", SOMA_B * SOMA_A / area), ("Ca_base", 0.0)]);
+        sim.add_message(&channel, "Ik", &pool, "I_Ca", "I_Ca")?;
+        for k in ["K_C", "K_AHP", "KC"] {
+            let k = format!("{}/{}", compartment, k);
+            if sim.exists(&k) {
+                sim.add_message(&pool, "Ca", &k, "CONCEN", "CONCEN")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Cell parameter file of the CA3 cell: densities in S/m^2, Na and K_DR
+/// near the soma and calcium channels all along the dendrites
+const CA3: &str = "
+    *cartesian
+    *relative
+    *set_global RM 1.0
+    *set_global RA 1.0
+    *set_global CM 0.03
+    *set_global EREST_ACT -0.06
+    soma      none       125 0 0  8.46  Na 300  Ca 40  K_DR 150  K_A 50  K_AHP 8  K_C 100
+    apical_10 soma       120 0 0  5.78  Na 150  Ca 80  K_DR 50  K_AHP 8  K_C 50
+    apical_11 apical_10  120 0 0  5.78  Na 150  Ca 80  K_DR 50  K_AHP 8  K_C 50
+    apical_12 apical_11  120 0 0  5.78  Na 200  Ca 170  K_DR 200  K_AHP 8  K_C 50
+    apical_13 apical_12  120 0 0  5.78  Ca 170  K_AHP 8  K_C 50
+    apical_14 apical_13  120 0 0  5.78  Ca 170  K_AHP 8  K_C 50
+    apical_15 apical_14  120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    apical_16 apical_15  120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    apical_17 apical_16  120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    apical_18 apical_17  120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    apical_19 apical_18  120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    basal_8   soma      -120 0 0  5.78  Na 150  Ca 80  K_DR 50  K_AHP 8  K_C 50
+    basal_7   basal_8   -120 0 0  5.78  Na 150  Ca 80  K_DR 50  K_AHP 8  K_C 50
+    basal_6   basal_7   -120 0 0  5.78  Na 200  Ca 170  K_DR 200  K_AHP 8  K_C 50
+    basal_5   basal_6   -120 0 0  5.78  Ca 170  K_AHP 8  K_C 50
+    basal_4   basal_5   -120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    basal_3   basal_4   -120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    basal_2   basal_3   -120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+    basal_1   basal_2   -120 0 0  5.78  Ca 50  K_AHP 8  K_C 50
+";
+
+/// Create the CA3 pyramidal cell of the `traub91` tutorial at `cell`,
+/// returning the paths of its compartments
+pub fn traub91(sim: &mut GenesisSimulation, cell: &str) -> Result<Vec<String>> {
+    traub91_prototypes(sim)?;
+    let compartments = readcell::readcell(sim, CA3, cell)?;
+    calcium_pools(sim, &compartments, "Ca")?;
+    Ok(compartments)
+}
+
+/// Create the channel prototypes of the Purkinje cell: Traub's sodium
+/// channel and delayed rectifier referred to the Purkinje rest, De Schutter
+/// and Bower's P-type calcium channel and Traub's C channel
+fn purkinje_prototypes(sim: &mut GenesisSimulation) -> Result<()> {
+    prototype(sim, "NaF", |na| {
+        let e = PURKINJE_EREST;
+        set(na, &[("Ek", 0.045), ("Xpower", 2.0), ("Ypower", 1.0)]);
+        setup_alpha(na, "X", [
+            320e3 * (0.0131 + e), -320e3, -1.0, -(0.0131 + e), -0.004,
+            -280e3 * (0.0401 + e), 280e3, -1.0, -(0.0401 + e), 0.005,
+        ])?;
+        setup_alpha(na, "Y", [128.0, 0.0, 0.0, -(0.017 + e), 0.018, 4e3, 0.0, 1.0, -(0.040 + e), -0.005])
+    })?;
+    prototype(sim, "Kdr", |kdr| {
+        let e = PURKINJE_EREST;
+        set(kdr, &[("Ek", -0.085), ("Xpower", 1.0)]);
+        setup_alpha(kdr, "X", [
+            16e3 * (0.0351 + e), -16e3, -1.0, -(0.0351 + e), -0.005,
+            250.0, 0.0, 0.0, -(0.02 + e), 0.04,
+        ])
+    })?;
+    prototype(sim, "CaP", |cap| {
+        set(cap, &[("Ek", 0.135), ("Xpower", 1.0), ("Ypower", 1.0)]);
+        setup_alpha(cap, "X", [8.5e3, 0.0, 1.0, -0.008, -0.0125, 35e3, 0.0, 1.0, 0.074, 0.0145])?;
+        setup_alpha(cap, "Y", [1.5, 0.0, 1.0, 0.029, 0.008, 5.5, 0.0, 1.0, 0.023, -0.008])
+    })?;
+    prototype(sim, "KC", |kc| {
+        let e = PURKINJE_EREST;
+        set(kc, &[("Ek", -0.085), ("Xpower", 1.0), ("Zpower", 1.0), ("Z_conc", 1.0), ("instant", 4.0)]);
+        tabulate(kc, "X", DEFAULT_RANGE, |v| k_c_rates(1e3 * (v - e)))?;
+        tabulate(kc, "Z", CA_RANGE, |ca| ((ca / 250.0).min(1.0), 1.0 - (ca / 250.0).min(1.0)))
+    })
+}
+
+/// Cell parameter file of the Purkinje cell: a spherical soma, a main
+/// dendrite of three compartments and two levels of branches
+const PURKINJE: &str = "
+    *cartesian
+    *relative
+    *set_global RM 1.1
+    *set_global RA 2.5
+    *set_global CM 0.0164
+    *set_global EREST_ACT -0.068
+    soma    none    0   0  0  29.8  NaF 1000  Kdr 600
+    main1   soma    0  20  0   6    CaP 20  KC 80
+    main2   main1   0  20  0   5    CaP 40  KC 150
+    main3   main2   0  20  0   4    CaP 40  KC 150
+    br1a    main3 -15  15  0   3    CaP 45  KC 200
+    br1b    br1a  -15  15  0   3    CaP 45  KC 200
+    br2a    main3  15  15  0   3    CaP 45  KC 200
+    br2b    br2a   15  15  0   3    CaP 45  KC 200
+    br11    br1b  -10  20  0   2    CaP 45  KC 200
+    br11b   br11  -10  20  0   2    CaP 45  KC 200
+    br12    br1b   10  20  0   2    CaP 45  KC 200
+    br12b   br12   10  20  0   2    CaP 45  KC 200
+    br21    br2b  -10  20  0   2    CaP 45  KC 200
+    br21b   br21  -10  20  0   2    CaP 45  KC 200
+    br22    br2b   10  20  0   2    CaP 45  KC 200
+    br22b   br22   10  20  0   2    CaP 45  KC 200
+";
+
+/// Create the simplified Purkinje cell at `cell`, returning the paths of
+/// its compartments
+pub fn purkinje(sim: &mut GenesisSimulation, cell: &str) -> Result<Vec<String>> {
+    purkinje_prototypes(sim)?;
+    let compartments = readcell::readcell(sim, PURKINJE, cell)?;
+    calcium_pools(sim, &compartments, "CaP")?;
+    Ok(compartments)
+}
+
+/// Build the reference model `name` (`squid`, `traub91` or `purkinje`) at
+/// `path`, returning its compartments
+pub fn build(sim: &mut GenesisSimulation, name: &str, path: &str) -> Result<Vec<String>> {
+    match name {
+        "squid" => Ok(vec![squid(sim, path)?]),
+        "traub91" => traub91(sim, path),
+        "purkinje" => purkinje(sim, path),
+        _ => Err(OldiesError::ModelNotFound(format!("reference model '{}'", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    /// Times at which `Vm` of `path` rises through `threshold` while the
+    /// simulation runs for `duration`, relative to its start
+    fn spike_times(sim: &mut GenesisSimulation, path: &str, threshold: f64, duration: f64) -> Vec<f64> {
+        let start = sim.current_time();
+        let mut times = vec![];
+        let mut above = true;
+        for _ in 0..(duration / sim.dt()).round() as usize {
+            sim.step().unwrap();
+            let v = sim.get(path).unwrap().get_param("Vm").unwrap();
+            if v > threshold && !above {
+                times.push(sim.current_time() - start);
+            }
+            above = v > threshold;
+        }
+        times
+    }
+
+    /// Golden spike times (s), to within a step
+    fn assert_times(times: &[f64], golden: &[f64], dt: f64) {
+        assert_eq!(times.len(), golden.len(), "{:?}", times);
+        for (t, g) in times.iter().zip(golden) {
+            assert!((t - g).abs() < 1.5 * dt, "{:?}", times);
+        }
+    }
+
+    /// A model on clock 0 at `dt`, settled at rest for `settle`
+    fn settled(build: impl FnOnce(&mut GenesisSimulation) -> Vec<String>, dt: f64, settle: f64) -> (GenesisSimulation, Vec<String>) {
+        let mut sim = GenesisSimulation::new();
+        sim.set_clock(0, dt).unwrap();
+        sim.set_dt(dt);
+        let compartments = build(&mut sim);
+        sim.reset().unwrap();
+        sim.run(settle).unwrap();
+        (sim, compartments)
+    }
+
+    #[test]
+    fn test_squid() {
+        let (mut sim, axon) = settled(|sim| vec![squid(sim, "/squid").unwrap()], 1e-5, 0.0);
+        assert_eq!(sim.get(&axon[0]).unwrap().children, vec!["/squid/Na", "/squid/K"]);
+        assert!(spike_times(&mut sim, "/squid", 0.0, 0.01).is_empty());
+
+        // 10 uA/cm^2 fires the axon at the 68 Hz of Hodgkin and Huxley
+        sim.reset().unwrap();
+        let area = PI * 500e-6 * 500e-6;
+        sim.get_mut("/squid").unwrap().set_param("inject", 0.1 * area);
+        let times = spike_times(&mut sim, "/squid", 0.0, 0.05);
+        assert_times(&times, &[0.00192, 0.01685, 0.03151, 0.04614], 1e-5);
+        let period = times[2] - times[1];
+        assert!((period - 0.0146).abs() < 3e-4, "{}", period);
+    }
+
+    #[test]
+    fn test_traub91() {
+        let (mut sim, cell) = settled(|sim| traub91(sim, "/cell").unwrap(), 5e-5, 0.02);
+        assert_eq!(cell.len(), 19);
+        assert_eq!((cell[0].as_str(), cell[18].as_str()), ("/cell/soma", "/cell/basal_1"));
+        let soma = sim.get("/cell/soma").unwrap();
+        assert_eq!(soma.children.len(), 7);
+        assert!((soma.get_param("Vm").unwrap() + 0.058).abs() < 1e-3);
+        let pools = sim.paths().iter().filter(|p| p.ends_with("/Ca_conc")).count();
+        assert_eq!(pools, 19);
+
+        // A somatic current gives a spike, then a calcium-driven burst
+        // that the AHP ends
+        let mut solved = Sli::new();
+        solved.sim = GenesisSimulation::new();
+        solved.sim.set_clock(0, 5e-5).unwrap();
+        solved.sim.set_dt(5e-5);
+        traub91(&mut solved.sim, "/cell").unwrap();
+        solved.execute("create hsolve /cell/solve; setfield /cell/solve path /cell/#[TYPE=compartment]; call /cell/solve SETUP").unwrap();
+        solved.sim.reset().unwrap();
+        solved.sim.run(0.02).unwrap();
+        for sim in [&mut sim, &mut solved.sim] {
+            sim.get_mut("/cell/soma").unwrap().set_param("inject", 2e-10);
+            let times = spike_times(sim, "/cell/soma", -0.02, 0.17);
+            assert_times(&times, &[0.01, 0.1284, 0.13635, 0.14445, 0.1528, 0.1618], 5e-5);
+        }
+        let ca = sim.get("/cell/apical_12/Ca_conc").unwrap().get_param("Ca").unwrap();
+        assert!(ca > 100.0, "{}", ca);
+    }
+
+    #[test]
+    fn test_purkinje() {
+        let (mut sim, cell) = settled(|sim| purkinje(sim, "/cell").unwrap(), 2e-5, 0.02);
+        assert_eq!(cell.len(), 16);
+        assert_eq!(sim.get("/cell/soma").unwrap().get_param("len"), Some(0.0));
+
+        // The soma fires fast spikes
+        sim.get_mut("/cell/soma").unwrap().set_param("inject", 1.5e-9);
+        let times = spike_times(&mut sim, "/cell/soma", -0.02, 0.05);
+        assert_times(&times, &[0.00112, 0.00768, 0.01416, 0.02066, 0.02714, 0.03364, 0.04014, 0.04664], 2e-5);
+
+        // Current into a branch fires calcium spikes there
+        sim.get_mut("/cell/soma").unwrap().set_param("inject", 0.0);
+        sim.reset().unwrap();
+        sim.run(0.02).unwrap();
+        sim.get_mut("/cell/br11").unwrap().set_param("inject", 1e-9);
+        let times = spike_times(&mut sim, "/cell/br11", 0.0, 0.05);
+        assert_times(&times, &[0.00268, 0.0115, 0.02006, 0.02866, 0.03726, 0.04584], 2e-5);
+        assert!(sim.get("/cell/br11/Ca_conc").unwrap().get_param("Ca").unwrap() > 250.0);
+
+        assert!(build(&mut sim, "hippocampus", "/h").is_err());
+        assert_eq!(build(&mut sim, "squid", "/squid").unwrap(), ["/squid"]);
+    }
+}
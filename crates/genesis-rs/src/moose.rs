@@ -0,0 +1,428 @@
+//! MOOSE compatibility (`dialect moose`)
+//!
+//! MOOSE inherited the SLI parser of GENESIS but names classes and fields
+//! its own way, and scripts written for it use those names. In MOOSE mode
+//! an [`Sli`] takes the MOOSE forms below as their GENESIS equivalents and
+//! records each one it meets in [`Sli::report`], with the portable form,
+//! so scripts can be ported:
+//!
+//! - classes: `Neutral`, `Compartment`, `SymCompartment` (simulated as
+//!   an asymmetric compartment), `HHChannel` (tabchannel), `CaConc`,
+//!   `HSolve`, `SpikeGen`, `SynChan`, `PulseGen`, `RandSpike`, `Pool`,
+//!   `BufPool` (a buffered kpool), `Reac`, `Enz` and `MMenz` (a kenz in
+//!   mode 1)
+//! - fields, by class: `diameter` and `length` of compartments,
+//!   `useConcentration` of channels (`Z_conc`), `CaBasal` of pools
+//!   (`Ca_base`), `threshold`, `refractT` and `edgeTriggered` of spike
+//!   generators, `Gbar` of synchans (`gmax`), `firstLevel`, `firstWidth`,
+//!   `firstDelay`, `secondLevel`, ... or `level[0]`, `width[1]`, ...,
+//!   `baseLevel` and `trigMode` of pulse generators and `numKf` and
+//!   `numKb` of reactions
+//! - element indices: `/cell[0]/soma[0]` is `/cell/soma`, unless an
+//!   element is named with the index
+//! - messages between fields, `addmsg /soma/axial /dend/raxial`:
+//!   `axial`/`raxial` (AXIAL and RAXIAL), `channel`/`channel` (VOLTAGE
+//!   and CHANNEL), `IkOut`/`current` (I_Ca), `concOut`/`concen` (CONCEN),
+//!   `VmOut`/`Vm` (INPUT), `spikeOut`/`addSpike` (SPIKE, to a synchan or
+//!   its `synapse[i]`) and `output`/`injectMsg` (INJECT)
+//! - commands: `reinit` (`reset`), `start time` (`step time -time`) and
+//!   `useclock tick path [process | init]`
+//! - the solvers `Ksolve`, `Gsolve`, `Stoich` and `Dsolve`, made neutral
+//!   elements whose fields are ignored: genesis-rs integrates kinetics
+//!   itself
+//!
+//! GENESIS names keep working. Rates and amounts in concentration units
+//! (`Kf`, `Kb`, `conc`, `concInit`, `volume`) have no counterpart in the
+//! molecule numbers of [`crate::kinetics`] and are errors, also reported.
+
+use crate::sli::Sli;
+use crate::{wildcard, ElementType};
+use std::collections::HashSet;
+
+/// Script dialect an [`Sli`] reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    #[default]
+    Genesis,
+    Moose,
+}
+
+impl Dialect {
+    /// Name as the SLI `dialect` command takes it
+    pub fn name(self) -> &'static str {
+        match self {
+            Dialect::Genesis => "genesis",
+            Dialect::Moose => "moose",
+        }
+    }
+
+    /// Dialect named `name` (case is ignored)
+    pub fn parse(name: &str) -> Option<Dialect> {
+        match name.to_ascii_lowercase().as_str() {
+            "genesis" => Some(Dialect::Genesis),
+            "moose" => Some(Dialect::Moose),
+            _ => None,
+        }
+    }
+}
+
+/// MOOSE solvers genesis-rs has no need of
+const SOLVERS: &[&str] = &["Ksolve", "Gsolve", "Stoich", "Dsolve"];
+
+/// Fields setting a GENESIS object to behave as a MOOSE class
+type Fields = &'static [(&'static str, &'static str)];
+
+/// GENESIS object of the MOOSE class `class`, the type of its elements and
+/// the fields that make it behave as the class
+pub fn object(class: &str) -> Option<(&'static str, ElementType, Fields)> {
+    use ElementType::*;
+    Some(match class {
+        "Neutral" => ("neutral", Neutral, &[]),
+        "Compartment" | "SymCompartment" => ("compartment", Compartment, &[]),
+        "HHChannel" => ("tabchannel", TabChannel, &[]),
+        "CaConc" => ("Ca_concen", CaConcen, &[]),
+        "HSolve" => ("hsolve", HSolve, &[]),
+        "SpikeGen" => ("spikegen", SpikeGen, &[]),
+        "SynChan" => ("synchan", Synapse, &[]),
+        "PulseGen" => ("pulsegen", PulseGen, &[]),
+        "RandSpike" => ("randomspike", RandomSpike, &[]),
+        "Pool" => ("kpool", Pool, &[]),
+        "BufPool" => ("kpool", Pool, &[("slave_enable", "4")]),
+        "Reac" => ("kreac", Reaction, &[]),
+        "Enz" => ("kenz", Enzyme, &[]),
+        "MMenz" => ("kenz", Enzyme, &[("mode", "1")]),
+        solver if SOLVERS.contains(&solver) => ("neutral", Neutral, &[]),
+        _ => return None,
+    })
+}
+
+/// GENESIS name of the MOOSE field `field` of elements of `element_type`
+pub fn field(element_type: &ElementType, field: &str) -> Option<&'static str> {
+    use ElementType::*;
+    Some(match (element_type, field) {
+        (Compartment, "diameter") => "dia",
+        (Compartment, "length") => "len",
+        (TabChannel | NaChannel | KChannel | CaChannel, "useConcentration") => "Z_conc",
+        (CaConcen, "CaBasal") => "Ca_base",
+        (SpikeGen, "threshold") => "thresh",
+        (SpikeGen | RandomSpike, "refractT") => "abs_refract",
+        (SpikeGen, "edgeTriggered") => "edge_triggered",
+        (Synapse, "Gbar") => "gmax",
+        (PulseGen, "firstLevel" | "level[0]") => "level1",
+        (PulseGen, "firstWidth" | "width[0]") => "width1",
+        (PulseGen, "firstDelay" | "delay[0]") => "delay1",
+        (PulseGen, "secondLevel" | "level[1]") => "level2",
+        (PulseGen, "secondWidth" | "width[1]") => "width2",
+        (PulseGen, "secondDelay" | "delay[1]") => "delay2",
+        (PulseGen, "baseLevel") => "baselevel",
+        (PulseGen, "trigMode") => "trig_mode",
+        (Reaction, "numKf") => "kf",
+        (Reaction, "numKb") => "kb",
+        _ => return None,
+    })
+}
+
+/// Why the MOOSE field `field` of elements of `element_type` cannot be
+/// ported, if it cannot
+fn unportable(element_type: &ElementType, field: &str) -> Option<&'static str> {
+    match (element_type, field) {
+        (ElementType::Reaction, "Kf" | "Kb") => Some("a rate in concentration units; give numKf and numKb"),
+        (ElementType::Pool, "conc" | "concInit" | "volume") => Some("in concentration units; give n and nInit"),
+        _ => None,
+    }
+}
+
+/// GENESIS messages of the MOOSE message from the field `source` to the
+/// field `dest`: whether each runs backwards, its type and its slots
+fn messages(source: &str, dest: &str) -> Option<&'static [(bool, &'static str, &'static str)]> {
+    Some(match (source, dest) {
+        ("axial", "raxial") => &[(false, "AXIAL", "Vm"), (true, "RAXIAL", "Ra Vm")],
+        ("IkOut", "current") => &[(false, "I_Ca", "Ik")],
+        ("concOut", "concen") => &[(false, "CONCEN", "Ca")],
+        ("VmOut", "Vm") => &[(false, "INPUT", "Vm")],
+        ("spikeOut", "addSpike") => &[(false, "SPIKE", "")],
+        ("output", "injectMsg") => &[(false, "INJECT", "output")],
+        _ => return None,
+    })
+}
+
+/// Commands a MOOSE command stands for, and notes on the MOOSE forms it
+/// used
+#[derive(Debug, Default)]
+pub(crate) struct Translation {
+    pub commands: Vec<Vec<String>>,
+    pub notes: Vec<String>,
+}
+
+/// `path` without the element indices `[0]` MOOSE gives every element,
+/// where no element is named with them
+fn path(sli: &Sli, path: &str, notes: &mut Vec<String>) -> String {
+    if !path.contains("[0]") || !path.contains('/') {
+        return path.to_string();
+    }
+    let mut parts: Vec<&str> = vec![];
+    for part in path.split('/') {
+        let prefix = match parts.is_empty() {
+            true => part.to_string(),
+            false => format!("{}/{}", parts.join("/"), part),
+        };
+        let named = || sli.sim.exists(&sli.resolve(&prefix));
+        match part.strip_suffix("[0]") {
+            Some(name) if !name.is_empty() && !name.contains(['[', '=']) && !named() => parts.push(name),
+            _ => parts.push(part),
+        }
+    }
+    let ported = parts.join("/");
+    if ported != path {
+        notes.push(format!("MOOSE path {} is {}", path, ported));
+    }
+    ported
+}
+
+/// Element types of the elements `path` names, or none if it names none
+fn types(sli: &Sli, path: &str) -> Vec<(String, ElementType)> {
+    sli.elements(0, path).unwrap_or_default().into_iter()
+        .map(|p| {
+            let element_type = sli.sim.elements[&p].element_type.clone();
+            (p, element_type)
+        })
+        .collect()
+}
+
+/// `field` of an element of `element_type` in GENESIS, noting a MOOSE
+/// name
+fn port_field(element_type: &ElementType, name: &str, notes: &mut Vec<String>) -> Result<String, String> {
+    if let Some(reason) = unportable(element_type, name) {
+        return Err(format!("MOOSE field {} of {} is {}", name, element_type.object(), reason));
+    }
+    Ok(match field(element_type, name) {
+        Some(genesis) => {
+            notes.push(format!("MOOSE field {} of {} is {}", name, element_type.object(), genesis));
+            genesis.to_string()
+        }
+        None => name.to_string(),
+    })
+}
+
+/// The GENESIS commands for the command `words` of a MOOSE script, or why
+/// it has none
+pub(crate) fn translate(sli: &Sli, words: &[String]) -> Result<Translation, String> {
+    let mut t = Translation::default();
+    let (name, args) = words.split_first().expect("statements have words");
+    let mut args: Vec<String> = args.iter().map(|a| path(sli, a, &mut t.notes)).collect();
+    let command = |name: &str, args: &[String]| {
+        std::iter::once(name.to_string()).chain(args.iter().cloned()).collect::<Vec<_>>()
+    };
+    match name.as_str() {
+        "reinit" => {
+            t.notes.push("MOOSE command reinit is reset".into());
+            t.commands.push(command("reset", &args));
+        }
+        "start" => {
+            t.notes.push("MOOSE command start is step -time".into());
+            args.push("-time".into());
+            t.commands.push(command("step", &args));
+        }
+        "useclock" if args.len() >= 2 && args[0].parse::<usize>().is_ok() && args[1].parse::<usize>().is_err() => {
+            t.notes.push(format!("MOOSE useclock {} {} is useclock {} {}", args[0], args[1], args[1], args[0]));
+            if let Some(stage) = args.get(2) {
+                match stage.as_str() {
+                    "process" | "init" => t.notes.push(format!("MOOSE {} stage is ignored", stage)),
+                    _ => return Err(format!("MOOSE useclock has no stage {}", stage)),
+                }
+            }
+            t.commands.push(command("useclock", &[args[1].clone(), args[0].clone()]));
+        }
+        "create" if args.len() >= 2 => {
+            let class = args[0].clone();
+            let (object, element_type, fields) = match object(&class) {
+                Some((object, element_type, fields)) => {
+                    t.notes.push(match SOLVERS.contains(&class.as_str()) {
+                        true => format!("MOOSE solver {} is ignored", class),
+                        false => format!("MOOSE class {} is {}", class, object),
+                    });
+                    if class == "SymCompartment" {
+                        t.notes.push("SymCompartment is simulated as an asymmetric compartment".into());
+                    }
+                    (object, element_type, fields)
+                }
+                None => return Ok(Translation { commands: vec![command(name, &args)], notes: t.notes }),
+            };
+            args[0] = object.to_string();
+            for k in (2..args.len()).filter(|k| k % 2 == 0) {
+                if let Some(option) = args[k].strip_prefix('-') {
+                    args[k] = format!("-{}", port_field(&element_type, option, &mut t.notes)?);
+                }
+            }
+            let path = args[1].clone();
+            t.commands.push(command(name, &args));
+            for (field, value) in fields {
+                t.commands.push(command("setfield", &[path.clone(), field.to_string(), value.to_string()]));
+            }
+        }
+        "setfield" => {
+            let (target, pairs) = match args.len() % 2 {
+                1 => (args[0].clone(), &args[1..]),
+                _ => (".".to_string(), &args[..]),
+            };
+            let targets = types(sli, &target);
+            if targets.is_empty() {
+                return Ok(Translation { commands: vec![command(name, &args)], notes: t.notes });
+            }
+            for (path, element_type) in targets {
+                let mut ported = vec![path.clone()];
+                for pair in pairs.chunks(2) {
+                    // Fields of MOOSE solvers, and of no neutral element,
+                    // are ignored
+                    let neutral = matches!(element_type, ElementType::Neutral);
+                    if neutral && !sli.sim.elements[&path].has_field(&pair[0]) {
+                        t.notes.push(format!("field {} of the neutral element {} is ignored", pair[0], path));
+                        continue;
+                    }
+                    ported.push(port_field(&element_type, &pair[0], &mut t.notes)?);
+                    ported.extend(pair.get(1).cloned());
+                }
+                if ported.len() > 1 {
+                    t.commands.push(command(name, &ported));
+                }
+            }
+        }
+        "getfield" | "showfield" if !args.is_empty() => {
+            // As the commands read them: `getfield [path] field`, and
+            // `showfield [path] fields` when the first word names elements
+            let names_elements = wildcard::is_pattern(&args[0]) || sli.sim.exists(&sli.resolve(&args[0]));
+            let (target, first) = match names_elements && (name == "showfield" || args.len() == 2) {
+                true => (args[0].clone(), 1),
+                false => (".".to_string(), 0),
+            };
+            if let Some((_, element_type)) = types(sli, &target).into_iter().next() {
+                for arg in &mut args[first..] {
+                    *arg = port_field(&element_type, arg, &mut t.notes)?;
+                }
+            }
+            t.commands.push(command(name, &args));
+        }
+        "addmsg" if args.len() == 2 => {
+            let split = |p: &str| p.rsplit_once('/').map(|(e, f)| (e.to_string(), f.to_string()));
+            let (Some((mut source, from)), Some((mut dest, to))) = (split(&args[0]), split(&args[1])) else {
+                return Err(format!("MOOSE message {} {} needs element/field ends", args[0], args[1]));
+            };
+            // Spikes go to the synchan owning the synapse
+            if dest.rsplit('/').next().is_some_and(|n| n.starts_with("synapse")) {
+                dest = crate::parent_path(&sli.resolve(&dest)).to_string();
+            }
+            let forms: Vec<(bool, &str, &str)> = match messages(&from, &to) {
+                Some(forms) => forms.to_vec(),
+                None if (from.as_str(), to.as_str()) == ("channel", "channel") => {
+                    // From the compartment to its channel or back
+                    let compartment_first = types(sli, &source).first()
+                        .is_some_and(|(_, t)| matches!(t, ElementType::Compartment));
+                    if !compartment_first {
+                        std::mem::swap(&mut source, &mut dest);
+                    }
+                    vec![(false, "VOLTAGE", "Vm"), (true, "CHANNEL", "Gk Ek")]
+                }
+                None => return Err(format!("MOOSE message {} to {} has no GENESIS counterpart", from, to)),
+            };
+            let types: Vec<&str> = forms.iter().map(|(_, msg_type, _)| *msg_type).collect();
+            t.notes.push(format!("MOOSE message {} to {} is {}", from, to, types.join(" and ")));
+            for (backwards, msg_type, slots) in forms {
+                let (a, b) = if backwards { (&dest, &source) } else { (&source, &dest) };
+                let mut words = vec![a.clone(), b.clone(), msg_type.to_string()];
+                words.extend(slots.split_whitespace().map(String::from));
+                t.commands.push(command(name, &words));
+            }
+        }
+        "loadModel" | "readSBML" | "readNeuroML" => {
+            return Err(format!("MOOSE command {} has no GENESIS counterpart", name));
+        }
+        _ => t.commands.push(command(name, &args)),
+    }
+    let mut seen = HashSet::new();
+    t.notes.retain(|note| seen.insert(note.clone()));
+    Ok(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        dialect moose
+        create Neutral /cell
+        create Compartment /cell[0]/soma[0] -diameter 20e-6 -length 20e-6
+        create Compartment /cell/dend
+        setfield /cell/dend diameter 2e-6 length 100e-6 Ra 1e7
+        addmsg /cell[0]/soma[0]/axial /cell/dend/raxial
+        create HHChannel /cell/soma/K
+        setfield /cell/soma/K Gbar 1e-7 Ek -0.077
+        addmsg /cell/soma/K/channel /cell/soma/channel
+        create SpikeGen /cell/soma/spike -threshold 0 -refractT 0.01
+        addmsg /cell/soma/VmOut /cell/soma/spike/Vm
+        create SynChan /cell/dend/syn
+        setfield /cell/dend/syn Gbar 1e-9 tau1 1e-3 tau2 2e-3
+        addmsg /cell/soma/spike/spikeOut /cell/dend/syn/synapse[0]/addSpike
+        addmsg /cell/dend/syn/channel /cell/dend/channel
+        create Ksolve /cell/ksolve
+        setfield /cell/ksolve method rk5
+        useclock 0 /cell/##[TYPE=compartment] process
+        setclock 0 1e-5
+        reinit
+        start 1e-3
+    "#;
+
+    #[test]
+    fn test_moose_script() {
+        let mut sli = Sli::new();
+        sli.execute(SCRIPT).unwrap();
+        let sim = &sli.sim;
+
+        let soma = sim.get("/cell/soma").unwrap();
+        assert_eq!(soma.get_param("dia"), Some(20e-6));
+        assert_eq!(soma.get_param("len"), Some(20e-6));
+        assert_eq!(sim.get("/cell/dend").unwrap().get_param("dia"), Some(2e-6));
+        let message_types = |path: &str| -> Vec<String> {
+            sim.get(path).unwrap().messages_in.iter().map(|m| m.msg_type.clone()).collect()
+        };
+        assert_eq!(message_types("/cell/dend"), ["AXIAL", "CHANNEL"]);
+        assert_eq!(message_types("/cell/soma"), ["RAXIAL", "CHANNEL"]);
+        assert_eq!(message_types("/cell/soma/K"), ["VOLTAGE"]);
+        assert_eq!(message_types("/cell/soma/spike"), ["INPUT"]);
+        assert_eq!(message_types("/cell/dend/syn"), ["SPIKE", "VOLTAGE"]);
+
+        assert_eq!(sim.get("/cell/soma/spike").unwrap().get_param("abs_refract"), Some(0.01));
+        assert_eq!(sim.get("/cell/dend/syn").unwrap().get_param("gmax"), Some(1e-9));
+        assert!(matches!(sim.get("/cell/ksolve").unwrap().element_type, ElementType::Neutral));
+        assert!((sim.current_time() - 1e-3).abs() < 1e-9);
+
+        for note in [
+            "line 4: MOOSE path /cell[0]/soma[0] is /cell/soma",
+            "line 4: MOOSE field diameter of compartment is dia",
+            "line 8: MOOSE class HHChannel is tabchannel",
+            "line 10: MOOSE message channel to channel is VOLTAGE and CHANNEL",
+            "line 17: MOOSE solver Ksolve is ignored",
+            "line 18: field method of the neutral element /cell/ksolve is ignored",
+            "line 22: MOOSE command start is step -time",
+        ] {
+            assert!(sli.report.iter().any(|n| n == note), "{} not in {:?}", note, sli.report);
+        }
+        assert_eq!(sli.call("getfield /cell/soma/spike threshold").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_unportable() {
+        let mut sli = Sli::new();
+        sli.execute("create kpool /a").unwrap();
+        assert!(sli.execute("create Pool /b").is_err());
+        assert!(sli.execute("setfield /a diameter 1").is_err());
+
+        sli.execute("dialect moose; create Reac /r; create Pool /b").unwrap();
+        assert!(sli.execute("setfield /r Kf 0.1").is_err());
+        assert!(sli.execute("loadModel model.g /model").is_err());
+        assert!(sli.report.iter().any(|n| n.contains("MOOSE field Kf of kreac is a rate")));
+        assert!(sli.report.iter().any(|n| n.contains("MOOSE command loadModel")));
+        sli.execute("setfield /r numKf 0.1").unwrap();
+        assert_eq!(sli.sim.get("/r").unwrap().get_param("kf"), Some(0.1));
+        assert_eq!(sli.call("dialect genesis").unwrap(), "genesis");
+    }
+}
@@ -0,0 +1,719 @@
+//! NeuroML2 export
+//!
+//! [`to_neuroml`] writes the cells and network under an element as a
+//! NeuroML2 document, so models built by GENESIS scripts and `.p` files
+//! can be run and analysed by the tools that read NeuroML.
+//!
+//! - Each tree of compartments coupled by AXIAL messages is a `cell`,
+//!   named after the element holding its root compartment. A compartment
+//!   is a segment keeping its `len` and `dia`, drawn from the end of its
+//!   parent towards its `x`, `y`, `z`; a compartment of zero length is a
+//!   sphere. `Rm`, `Cm` and `Ra` become specific values over its area and
+//!   cross section (`Ra pi dia / 8` for spheres, as `readcell` makes
+//!   them), and `Em` the reversal potential of a passive `leak` channel.
+//! - Channels become `ionChannelHH` components, one per kinetics: an
+//!   `hh_channel` gate takes the standard HH rates of its forms, and a
+//!   tabulated, instantaneous or calcium-dependent gate a LEMS
+//!   `ComponentType` interpolating its tables linearly, resampled to
+//!   [`MAX_DIVS`] divisions when finer. `Gbar` becomes a conductance
+//!   density over the area of the compartment.
+//! - A `Ca_concen` pool becomes a `fixedFactorConcentrationModel` of the
+//!   species `ca` of its compartment, with `rho = B area` so that
+//!   `dCa/dt = rho iCa / area - (Ca - Ca_base) / tau` matches GENESIS.
+//!   Channels feeding a pool carry calcium.
+//! - Synchans become `expTwoSynapse` components (`alphaSynapse` when
+//!   `tau1 = tau2`), and the SPIKE messages from spikegens of exported
+//!   cells `connectionWD`s with the weight and delay of their synapses,
+//!   the spikegen's `thresh` giving the spike threshold of its compartment.
+//!
+//! Stimuli (`inject`, pulsegens, random spikes) describe a simulation
+//! rather than the model and are left out, as are outputs and graphs.
+//! GENESIS couples compartments through their `Ra` rather than through
+//! the segment geometry, so multi-compartment cells agree with GENESIS
+//! only as far as their discretizations do.
+//!
+//! [`ion_channel`] converts an `hh_channel` to the shared
+//! [`oldies_core::IonChannel`], in the units of neuron-rs and brian-rs.
+
+use crate::{parent_path, solver, tabchannel, Element, ElementType, GenesisSimulation, Table};
+use oldies_core::{GateVariable, IonChannel, OldiesError, RateFunction, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::f64::consts::PI;
+use std::fmt::Write;
+use std::mem::discriminant;
+
+/// Most divisions of the tables of an exported gate
+pub const MAX_DIVS: usize = 200;
+
+fn field(element: &Element, name: &str) -> f64 {
+    element.get_param(name).unwrap_or(0.0)
+}
+
+fn error(msg: String) -> OldiesError {
+    OldiesError::SimulationError(msg)
+}
+
+/// Membrane area of a compartment: `pi dia len`, or `pi dia^2` for a
+/// sphere
+fn area(compartment: &Element) -> f64 {
+    let (dia, len) = (field(compartment, "dia"), field(compartment, "len"));
+    if len == 0.0 { PI * dia * dia } else { PI * dia * len }
+}
+
+/// Destination of the first message of `msg_type` from `element`
+fn dest<'a>(element: &'a Element, msg_type: &str) -> Option<&'a str> {
+    element.messages_out.iter().find(|m| m.msg_type == msg_type).map(|m| m.dest.as_str())
+}
+
+/// Integer power of `gate`, 0 for a gate the channel does not use
+fn power(channel: &Element, gate: &str) -> Result<u32> {
+    let p = field(channel, &format!("{}power", gate));
+    if p < 0.0 || p.fract() != 0.0 {
+        return Err(error(format!("{} has the power {} for {}; NeuroML takes whole powers", channel.path, p, gate)));
+    }
+    Ok(p as u32)
+}
+
+/// Rate function of an `hh_channel` rate in mV and 1/ms
+fn rate_function(channel: &Element, rate: &str) -> Result<RateFunction> {
+    let field = |name: &str| field(channel, &format!("{}_{}", rate, name));
+    let (a, b, v0) = (field("A"), 1e3 * field("B"), -1e3 * field("V0"));
+    Ok(match field("FORM") as i32 {
+        1 => RateFunction::Exponential { a: a / 1e3, b: v0, c: b },
+        2 => RateFunction::Sigmoid { a: a / 1e3, b: v0, c: b },
+        3 => RateFunction::HodgkinHuxley { a: a / 1e6, b: v0, c: b },
+        form => return Err(error(format!("{} has no rate form {} for {}", channel.path, form, rate))),
+    })
+}
+
+/// The `hh_channel` at `path` as the shared channel model, with its
+/// conductance density over the area of the compartment it is in
+pub fn ion_channel(sim: &GenesisSimulation, path: &str) -> Result<IonChannel> {
+    let channel = sim.get(path).ok_or_else(|| OldiesError::ModelNotFound(path.to_string()))?;
+    if !matches!(channel.element_type, ElementType::NaChannel | ElementType::KChannel | ElementType::CaChannel) {
+        return Err(error(format!("{} is not an hh_channel; only they have rate functions", path)));
+    }
+    if field(channel, "instant") != 0.0 {
+        return Err(error(format!("{} has instantaneous gates", path)));
+    }
+    let compartment = dest(channel, "CHANNEL").and_then(|c| sim.get(c))
+        .ok_or_else(|| error(format!("{} is in no compartment", path)))?;
+    let mut gates = vec![];
+    for (gate, _) in solver::GATES {
+        let power = power(channel, gate)?;
+        if power > 0 {
+            let alpha = rate_function(channel, &format!("{}_alpha", gate))?;
+            let beta = rate_function(channel, &format!("{}_beta", gate))?;
+            gates.push(GateVariable { name: gate.to_string(), power, alpha, beta });
+        }
+    }
+    Ok(IonChannel {
+        name: channel.name().to_string(),
+        // S/m^2 to mS/cm^2
+        g_max: 0.1 * field(channel, "Gbar") / area(compartment),
+        e_rev: 1e3 * field(channel, "Ek"),
+        gates,
+    })
+}
+
+/// Number with a unit, as NeuroML quantities are written
+fn quantity(x: f64, unit: &str) -> String {
+    format!("{:e}{}", x, unit)
+}
+
+/// `+ x` or `- |x|`, for terms of LEMS expressions
+fn term(x: f64) -> String {
+    if x < 0.0 { format!("- {:e}", -x) } else { format!("+ {:e}", x.abs()) }
+}
+
+/// Length in um, as morphologies are written
+fn um(x: f64) -> f64 {
+    (x * 1e12).round() / 1e6
+}
+
+/// `table`, resampled to [`MAX_DIVS`] divisions if it has more
+fn resample(table: &Table) -> Table {
+    if table.xdivs() <= MAX_DIVS {
+        return table.clone();
+    }
+    let mut resampled = Table::new(MAX_DIVS, table.xmin, table.xmax);
+    for i in 0..=MAX_DIVS {
+        resampled.values[i] = table.lookup(resampled.x(i));
+    }
+    resampled
+}
+
+/// What a tabulated gate component computes
+#[derive(Clone, Copy)]
+enum Quantity {
+    Rate,
+    SteadyState,
+}
+
+/// LEMS component type `name` interpolating `values` over the divisions
+/// of `range`, a rate (1/s) or steady state of the potential or, with
+/// `conc`, of the calcium concentration
+fn interpolation(name: &str, quantity: Quantity, conc: bool, (xmin, xmax): (f64, f64), values: &[f64]) -> String {
+    let (base, exposure, dimension, scale) = match quantity {
+        Quantity::Rate => ("Rate", "r", "per_time", " / TIME_SCALE"),
+        Quantity::SteadyState => ("Variable", "x", "none", ""),
+    };
+    let (base, input) = match conc {
+        false => (format!("baseVoltageDep{}", base), "v / VOLT_SCALE"),
+        true => (format!("baseVoltageConcDep{}", base), "caConc / CONC_SCALE"),
+    };
+    let n = values.len() - 1;
+    let k = n as f64 / (xmax - xmin);
+    let mut xml = format!("    <ComponentType name=\"{}\" extends=\"{}\">\n", name, base);
+    xml += "        <Constant name=\"TIME_SCALE\" dimension=\"time\" value=\"1s\"/>\n";
+    xml += "        <Constant name=\"VOLT_SCALE\" dimension=\"voltage\" value=\"1V\"/>\n";
+    xml += "        <Constant name=\"CONC_SCALE\" dimension=\"concentration\" value=\"1mM\"/>\n";
+    xml += "        <Dynamics>\n";
+    // Position in the table, in divisions from xmin
+    let _ = writeln!(xml, "            <DerivedVariable name=\"u\" dimension=\"none\" value=\"{} * {:e} {}\"/>",
+        input, k, term(-xmin * k));
+    let _ = writeln!(xml, "            <ConditionalDerivedVariable name=\"{}\" dimension=\"{}\" exposure=\"{}\">",
+        exposure, dimension, exposure);
+    let _ = writeln!(xml, "                <Case condition=\"u .lt. 0\" value=\"{:e}{}\"/>", values[0], scale);
+    for i in 0..n {
+        let _ = writeln!(xml, "                <Case condition=\"u .lt. {}\" value=\"({:e} * ({} - u) {} * (u - {})){}\"/>",
+            i + 1, values[i], i + 1, term(values[i + 1]), i, scale);
+    }
+    let _ = writeln!(xml, "                <Case value=\"{:e}{}\"/>", values[n], scale);
+    xml += "            </ConditionalDerivedVariable>\n        </Dynamics>\n    </ComponentType>\n";
+    xml
+}
+
+/// `A` and `B` tables of `gate`: a tabchannel's own, or an `hh_channel`'s
+/// tabulated over the default range
+fn gate_tables(channel: &Element, gate: &str) -> Result<(Table, Table)> {
+    if let ElementType::TabChannel = channel.element_type {
+        let table = |t: &str| channel.tables.get(&format!("{}_{}", gate, t)).map(resample);
+        return match (table("A"), table("B")) {
+            (Some(a), Some(b)) if a.values.len() == b.values.len() => Ok((a, b)),
+            _ => Err(error(format!("{} has a {} gate but no tables of one size", channel.path, gate))),
+        };
+    }
+    let (xmin, xmax) = tabchannel::DEFAULT_RANGE;
+    let (mut a, mut b) = (Table::new(MAX_DIVS, xmin, xmax), Table::new(MAX_DIVS, xmin, xmax));
+    for i in 0..=MAX_DIVS {
+        (a.values[i], b.values[i]) = solver::rates(channel, gate, a.x(i))?;
+    }
+    Ok((a, b))
+}
+
+/// NeuroML rate element `tag` of an `hh_channel` rate
+fn hh_rate(channel: &Element, tag: &str, rate: &str) -> Result<String> {
+    let field = |name: &str| field(channel, &format!("{}_{}", rate, name));
+    let (a, b, v0) = (field("A"), field("B"), field("V0"));
+    let (kind, rate_value, scale) = match field("FORM") as i32 {
+        1 => ("HHExpRate", a, b),
+        2 => ("HHSigmoidRate", a, -b),
+        3 => ("HHExpLinearRate", a * b, -b),
+        form => return Err(error(format!("{} has no rate form {} for {}", channel.path, form, rate))),
+    };
+    Ok(format!(
+        "        <{} type=\"{}\" rate=\"{}\" midpoint=\"{}\" scale=\"{}\"/>\n",
+        tag, kind, quantity(rate_value, "per_s"), quantity(v0, "V"), quantity(scale, "V")
+    ))
+}
+
+/// The `ionChannelHH` `id` of `channel` and the component types of its
+/// tabulated gates
+fn channel_xml(channel: &Element, id: &str) -> Result<(String, Vec<String>)> {
+    let instant = field(channel, "instant") as u32;
+    let tabulated = matches!(channel.element_type, ElementType::TabChannel);
+    let mut xml = format!("    <ionChannelHH id=\"{}\" conductance=\"10pS\">\n", id);
+    let mut types = vec![];
+    for (k, (gate, _)) in solver::GATES.into_iter().enumerate() {
+        let instances = power(channel, gate)?;
+        if instances == 0 {
+            continue;
+        }
+        let conc = gate == "Z" && field(channel, "Z_conc") != 0.0;
+        let is_instant = instant & (1 << k) != 0;
+        let name = |suffix: &str| format!("{}_{}_{}", id, gate, suffix);
+        match is_instant {
+            false => {
+                let _ = writeln!(xml, "        <gateHHrates id=\"{}\" instances=\"{}\">", gate, instances);
+                if tabulated || conc {
+                    let (a, b) = gate_tables(channel, gate)?;
+                    let beta: Vec<f64> = b.values.iter().zip(&a.values).map(|(b, a)| b - a).collect();
+                    let range = (a.xmin, a.xmax);
+                    types.push(interpolation(&name("alpha"), Quantity::Rate, conc, range, &a.values));
+                    types.push(interpolation(&name("beta"), Quantity::Rate, conc, range, &beta));
+                    let _ = writeln!(xml, "            <forwardRate type=\"{}\"/>", name("alpha"));
+                    let _ = writeln!(xml, "            <reverseRate type=\"{}\"/>", name("beta"));
+                } else {
+                    xml += "    ";
+                    xml += &hh_rate(channel, "forwardRate", &format!("{}_alpha", gate))?;
+                    xml += "    ";
+                    xml += &hh_rate(channel, "reverseRate", &format!("{}_beta", gate))?;
+                }
+                xml += "        </gateHHrates>\n";
+            }
+            true => {
+                let (a, b) = gate_tables(channel, gate)?;
+                let inf: Vec<f64> = a.values.iter().zip(&b.values).map(|(a, b)| a / b).collect();
+                types.push(interpolation(&name("inf"), Quantity::SteadyState, conc, (a.xmin, a.xmax), &inf));
+                let _ = writeln!(xml, "        <gateHHInstantaneous id=\"{}\" instances=\"{}\">", gate, instances);
+                let _ = writeln!(xml, "            <steadyState type=\"{}\"/>", name("inf"));
+                xml += "        </gateHHInstantaneous>\n";
+            }
+        }
+    }
+    xml += "    </ionChannelHH>\n";
+    Ok((xml, types))
+}
+
+/// What makes two channels the same component: their type, gates and
+/// tables
+fn kinetics(channel: &Element) -> String {
+    let params: BTreeMap<&String, &f64> = channel.params.iter()
+        .filter(|(name, _)| {
+            name.ends_with("power") || name.contains("_alpha_") || name.contains("_beta_")
+                || *name == "instant" || *name == "Z_conc"
+        })
+        .collect();
+    let tables: BTreeMap<&String, &Table> = channel.tables.iter().collect();
+    format!("{:?} {:?} {:?}", discriminant(&channel.element_type), params, tables)
+}
+
+/// Segment of an exported compartment
+struct Segment {
+    path: String,
+    name: String,
+    parent: Option<usize>,
+    proximal: [f64; 3],
+    distal: [f64; 3],
+}
+
+/// Exported cell: its id and segments, parents first
+struct Cell {
+    id: String,
+    segments: Vec<Segment>,
+}
+
+/// Unit vector from `from` to `to`, or along x if they coincide
+fn direction(from: [f64; 3], to: [f64; 3]) -> [f64; 3] {
+    let d = [to[0] - from[0], to[1] - from[1], to[2] - from[2]];
+    let norm = d.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 { d.map(|x| x / norm) } else { [1.0, 0.0, 0.0] }
+}
+
+/// Write the cells and network under `root` as a NeuroML2 document with
+/// the id `root`'s name
+pub fn to_neuroml(sim: &GenesisSimulation, root: &str) -> Result<String> {
+    let root = root.trim_end_matches('/');
+    let prefix = format!("{}/", root);
+    let root_name = root.rsplit('/').next().filter(|n| !n.is_empty()).unwrap_or("model");
+    let id = |path: &str| match path.strip_prefix(&prefix) {
+        Some(rest) => rest.replace(['/', '[', ']'], "_"),
+        None => root_name.to_string(),
+    };
+
+    let compartments: HashSet<&str> = sim.elements.iter()
+        .filter(|(path, e)| {
+            path.starts_with(&prefix) && matches!(e.element_type, ElementType::Compartment) && sim.is_simulated(path)
+        })
+        .map(|(path, _)| path.as_str())
+        .collect();
+    let parent_of = |path: &str| {
+        sim.elements[path].messages_in.iter()
+            .find(|m| m.msg_type == "AXIAL" && compartments.contains(m.source.as_str()))
+            .map(|m| m.source.as_str())
+    };
+    let mut children: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut roots = vec![];
+    for &path in &compartments {
+        let element = &sim.elements[path];
+        if field(element, "dia") <= 0.0 {
+            return Err(error(format!("{} needs a positive dia to be a segment", path)));
+        }
+        match parent_of(path) {
+            Some(parent) => children.entry(parent).or_default().push(path),
+            None => roots.push(path),
+        }
+    }
+    roots.sort_unstable();
+    for list in children.values_mut() {
+        list.sort_unstable();
+    }
+
+    // Cells, their segments laid out from the root
+    let mut cells: Vec<Cell> = vec![];
+    let mut segment_of: HashMap<&str, (usize, usize)> = HashMap::new();
+    // Segment group of each compartment: its path in the cell
+    let mut names: HashMap<&str, String> = HashMap::new();
+    let mut cell_ids = HashSet::new();
+    for &first in &roots {
+        let cell_path = parent_path(first);
+        let mut cell_id = id(cell_path);
+        if !cell_ids.insert(cell_id.clone()) {
+            cell_id = id(first);
+            cell_ids.insert(cell_id.clone());
+        }
+        let mut segments: Vec<Segment> = vec![];
+        let mut queue: Vec<(&str, Option<usize>)> = vec![(first, None)];
+        while let Some((path, parent)) = queue.pop() {
+            let element = &sim.elements[path];
+            let position = ["x", "y", "z"].map(|axis| field(element, axis));
+            let len = field(element, "len");
+            let (proximal, distal) = match parent {
+                Some(p) => {
+                    let start = segments[p].distal;
+                    let d = direction(start, position);
+                    (start, [0, 1, 2].map(|k| start[k] + len * d[k]))
+                }
+                None => {
+                    let d = direction([0.0; 3], position);
+                    ([0, 1, 2].map(|k| position[k] - len * d[k]), position)
+                }
+            };
+            segment_of.insert(path, (cells.len(), segments.len()));
+            let index = segments.len();
+            let name = path[cell_path.len()..].trim_start_matches('/').replace(['/', '[', ']'], "_");
+            names.insert(path, name.clone());
+            segments.push(Segment { path: path.to_string(), name, parent, proximal, distal });
+            for child in children.get(path).into_iter().flatten().rev() {
+                queue.push((child, Some(index)));
+            }
+        }
+        cells.push(Cell { id: cell_id, segments });
+    }
+
+    // Channels, pools and synchans of each compartment
+    let mut channel_ids: HashMap<String, String> = HashMap::new();
+    let mut used_ids: HashSet<String> = HashSet::from(["leak".to_string()]);
+    let (mut channels_xml, mut pools_xml, mut synapses_xml, mut types) = (String::new(), String::new(), String::new(), vec![]);
+    let mut densities: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut species: HashMap<&str, String> = HashMap::new();
+    let mut thresholds: HashMap<&str, f64> = HashMap::new();
+    let mut synapse_ids: HashMap<&str, String> = HashMap::new();
+    let mut paths: Vec<&String> = sim.elements.keys().filter(|p| p.starts_with(&prefix) && sim.is_simulated(p)).collect();
+    paths.sort_unstable();
+    for path in paths {
+        let element = &sim.elements[path];
+        let Some(compartment) = dest(element, "CHANNEL").and_then(|c| compartments.get(c).copied()) else {
+            // Spikegens and pools are found through their messages
+            match element.element_type {
+                ElementType::SpikeGen => {
+                    let source = element.messages_in.iter().find(|m| m.msg_type == "INPUT").map(|m| m.source.as_str());
+                    if let Some(c) = source.and_then(|s| compartments.get(s)) {
+                        thresholds.insert(c, field(element, "thresh"));
+                    }
+                }
+                ElementType::CaConcen => {
+                    let channel = element.messages_in.iter().find(|m| m.msg_type == "I_Ca").map(|m| m.source.as_str());
+                    let compartment = channel.and_then(|c| sim.get(c)).and_then(|c| dest(c, "CHANNEL"))
+                        .and_then(|c| compartments.get(c).copied());
+                    let Some(compartment) = compartment else { continue };
+                    if species.contains_key(compartment) {
+                        return Err(error(format!("{} has more than one calcium pool", compartment)));
+                    }
+                    let pool = id(path);
+                    let rho = field(element, "B") * area(&sim.elements[compartment]);
+                    let _ = writeln!(pools_xml,
+                        "    <fixedFactorConcentrationModel id=\"{}\" ion=\"ca\" restingConc=\"{}\" decayConstant=\"{}\" rho=\"{}\"/>",
+                        pool, quantity(field(element, "Ca_base"), "mM"), quantity(field(element, "tau"), "s"),
+                        quantity(rho, "mol_per_m_per_A_per_s"));
+                    species.insert(compartment, format!(
+                        "<species id=\"ca\" ion=\"ca\" concentrationModel=\"{}\" initialConcentration=\"{}\" initialExtConcentration=\"2mM\" segmentGroup=\"{}\"/>",
+                        pool, quantity(field(element, "Ca_base"), "mM"), names[compartment]));
+                }
+                _ => {}
+            }
+            continue;
+        };
+        let density = |g: f64| quantity(g / area(&sim.elements[compartment]), "S_per_m2");
+        if let ElementType::Synapse = element.element_type {
+            let synapse = id(path);
+            let (tau1, tau2) = (field(element, "tau1"), field(element, "tau2"));
+            let (gmax, erev) = (quantity(field(element, "gmax"), "S"), quantity(field(element, "Ek"), "V"));
+            if (tau1 - tau2).abs() <= 1e-9 * tau1.max(tau2) {
+                let _ = writeln!(synapses_xml, "    <alphaSynapse id=\"{}\" gbase=\"{}\" erev=\"{}\" tau=\"{}\"/>",
+                    synapse, gmax, erev, quantity(tau1, "s"));
+            } else {
+                let _ = writeln!(synapses_xml,
+                    "    <expTwoSynapse id=\"{}\" gbase=\"{}\" erev=\"{}\" tauRise=\"{}\" tauDecay=\"{}\"/>",
+                    synapse, gmax, erev, quantity(tau1.min(tau2), "s"), quantity(tau1.max(tau2), "s"));
+            }
+            synapse_ids.insert(path, synapse);
+            continue;
+        }
+        if !solver::is_channel(element) {
+            continue;
+        }
+        let key = kinetics(element);
+        let channel = match channel_ids.get(&key) {
+            Some(channel) => channel.clone(),
+            None => {
+                let mut channel = element.name().replace(['[', ']'], "_");
+                if !used_ids.insert(channel.clone()) {
+                    channel = id(path);
+                    used_ids.insert(channel.clone());
+                }
+                let (xml, gate_types) = channel_xml(element, &channel)?;
+                channels_xml += &xml;
+                types.extend(gate_types);
+                channel_ids.insert(key, channel.clone());
+                channel
+            }
+        };
+        let ion = match element.messages_out.iter().any(|m| m.msg_type == "I_Ca") {
+            true => "ca",
+            false => "non_specific",
+        };
+        densities.entry(compartment).or_default().push(format!(
+            "<channelDensity id=\"{}_{}\" ionChannel=\"{}\" condDensity=\"{}\" erev=\"{}\" ion=\"{}\" segmentGroup=\"{}\"/>",
+            names[compartment], element.name(), channel, density(field(element, "Gbar")),
+            quantity(field(element, "Ek"), "V"), ion, names[compartment]
+        ));
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(xml, "<neuroml xmlns=\"http://www.neuroml.org/schema/neuroml2\" \
+        xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+        xsi:schemaLocation=\"http://www.neuroml.org/schema/neuroml2 \
+        https://raw.github.com/NeuroML/NeuroML2/development/Schemas/NeuroML2/NeuroML_v2.3.xsd\" id=\"{}\">", root_name);
+    xml += "    <ionChannel id=\"leak\" type=\"ionChannelPassive\" conductance=\"10pS\"/>\n";
+    xml += &channels_xml;
+    xml += &pools_xml;
+    xml += &synapses_xml;
+
+    for cell in &cells {
+        let _ = writeln!(xml, "    <cell id=\"{}\">", cell.id);
+        let _ = writeln!(xml, "        <morphology id=\"{}_morphology\">", cell.id);
+        for (i, segment) in cell.segments.iter().enumerate() {
+            let dia = um(field(&sim.elements[&segment.path], "dia"));
+            let _ = writeln!(xml, "            <segment id=\"{}\" name=\"{}\">", i, segment.name);
+            if let Some(parent) = segment.parent {
+                let _ = writeln!(xml, "                <parent segment=\"{}\"/>", parent);
+            }
+            for (tag, [x, y, z]) in [("proximal", segment.proximal), ("distal", segment.distal)] {
+                let _ = writeln!(xml, "                <{} x=\"{}\" y=\"{}\" z=\"{}\" diameter=\"{}\"/>",
+                    tag, um(x), um(y), um(z), dia);
+            }
+            xml += "            </segment>\n";
+        }
+        for (i, segment) in cell.segments.iter().enumerate() {
+            let _ = writeln!(xml, "            <segmentGroup id=\"{}\">\n                <member segment=\"{}\"/>\n            </segmentGroup>",
+                segment.name, i);
+        }
+        xml += "            <segmentGroup id=\"all\">\n";
+        for segment in &cell.segments {
+            let _ = writeln!(xml, "                <include segmentGroup=\"{}\"/>", segment.name);
+        }
+        xml += "            </segmentGroup>\n        </morphology>\n";
+
+        let _ = writeln!(xml, "        <biophysicalProperties id=\"{}_biophysics\">", cell.id);
+        xml += "            <membraneProperties>\n";
+        let mut extra = String::new();
+        let mut intracellular = String::new();
+        for segment in &cell.segments {
+            let c = &sim.elements[&segment.path];
+            let (a, group) = (area(c), &segment.name);
+            let _ = writeln!(xml,
+                "                <channelDensity id=\"{}_leak\" ionChannel=\"leak\" condDensity=\"{}\" erev=\"{}\" ion=\"non_specific\" segmentGroup=\"{}\"/>",
+                group, quantity(1.0 / (field(c, "Rm") * a), "S_per_m2"), quantity(field(c, "Em"), "V"), group);
+            for density in densities.get(segment.path.as_str()).into_iter().flatten() {
+                let _ = writeln!(xml, "                {}", density);
+            }
+            if let Some(thresh) = thresholds.get(segment.path.as_str()) {
+                let _ = writeln!(extra, "                <spikeThresh value=\"{}\" segmentGroup=\"{}\"/>", quantity(*thresh, "V"), group);
+            }
+            let _ = writeln!(extra, "                <specificCapacitance value=\"{}\" segmentGroup=\"{}\"/>",
+                quantity(field(c, "Cm") / a, "F_per_m2"), group);
+            let _ = writeln!(extra, "                <initMembPotential value=\"{}\" segmentGroup=\"{}\"/>",
+                quantity(field(c, "initVm"), "V"), group);
+            let (dia, len) = (field(c, "dia"), field(c, "len"));
+            let resistivity = match len {
+                0.0 => field(c, "Ra") * PI * dia / 8.0,
+                _ => field(c, "Ra") * PI * dia * dia / (4.0 * len),
+            };
+            if let Some(s) = species.get(segment.path.as_str()) {
+                let _ = writeln!(intracellular, "                {}", s);
+            }
+            let _ = writeln!(intracellular, "                <resistivity value=\"{}\" segmentGroup=\"{}\"/>",
+                quantity(resistivity, "ohm_m"), group);
+        }
+        xml += &extra;
+        xml += "            </membraneProperties>\n            <intracellularProperties>\n";
+        xml += &intracellular;
+        xml += "            </intracellularProperties>\n        </biophysicalProperties>\n    </cell>\n";
+    }
+
+    // Network: a population of one for each cell and the spike connections
+    // between them
+    xml += "    <network id=\"network\">\n";
+    for cell in &cells {
+        let _ = writeln!(xml, "        <population id=\"{}_pop\" component=\"{}\" size=\"1\"/>", cell.id, cell.id);
+    }
+    let mut projections: BTreeMap<(usize, usize, &str), Vec<String>> = BTreeMap::new();
+    let mut synchans: Vec<(&&str, &String)> = synapse_ids.iter().collect();
+    synchans.sort_unstable();
+    for (synchan, synapse) in synchans {
+        let element = &sim.elements[*synchan];
+        let post = segment_of[dest(element, "CHANNEL").unwrap()];
+        let spikes = element.messages_in.iter().filter(|m| m.msg_type == "SPIKE").enumerate();
+        for (i, msg) in spikes {
+            let source = sim.get(&msg.source)
+                .filter(|s| matches!(s.element_type, ElementType::SpikeGen))
+                .and_then(|s| s.messages_in.iter().find(|m| m.msg_type == "INPUT"))
+                .and_then(|m| segment_of.get(m.source.as_str()));
+            let Some(&pre) = source else { continue };
+            let connections = projections.entry((pre.0, post.0, synapse.as_str())).or_default();
+            connections.push(format!(
+                "<connectionWD id=\"{}\" preCellId=\"../{}_pop[0]\" preSegmentId=\"{}\" preFractionAlong=\"0.5\" \
+                postCellId=\"../{}_pop[0]\" postSegmentId=\"{}\" postFractionAlong=\"0.5\" weight=\"{:e}\" delay=\"{}\"/>",
+                connections.len(), cells[pre.0].id, pre.1, cells[post.0].id, post.1,
+                field(element, &format!("synapse[{}].weight", i)),
+                quantity(field(element, &format!("synapse[{}].delay", i)), "s")
+            ));
+        }
+    }
+    for (n, ((pre, post, synapse), connections)) in projections.iter().enumerate() {
+        let _ = writeln!(xml, "        <projection id=\"projection{}\" presynapticPopulation=\"{}_pop\" postsynapticPopulation=\"{}_pop\" synapse=\"{}\">",
+            n, cells[*pre].id, cells[*post].id, synapse);
+        for connection in connections {
+            let _ = writeln!(xml, "            {}", connection);
+        }
+        xml += "        </projection>\n";
+    }
+    xml += "    </network>\n";
+    for t in types {
+        xml += &t;
+    }
+    xml += "</neuroml>\n";
+    Ok(xml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use crate::sli::Sli;
+
+    /// Check that the tags of `xml` nest
+    fn check_nesting(xml: &str) {
+        let mut open: Vec<&str> = vec![];
+        for tag in xml.split('<').skip(2).map(|t| t.split('>').next().unwrap()) {
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name));
+            } else if !tag.ends_with('/') {
+                open.push(tag.split_whitespace().next().unwrap());
+            }
+        }
+        assert!(open.is_empty(), "{:?}", open);
+    }
+
+    /// Values of `attribute` of the `tag` elements of `xml`, without their
+    /// unit
+    fn values(xml: &str, tag: &str, attribute: &str) -> Vec<f64> {
+        let (tag, attribute) = (format!("<{} ", tag), format!(" {}=\"", attribute));
+        xml.lines().filter_map(|l| l.trim_start().strip_prefix(tag.as_str()))
+            .filter_map(|l| l.split(attribute.as_str()).nth(1))
+            .map(|v| {
+                let number = v.split('"').next().unwrap();
+                let end = number.find(|c: char| !(c.is_ascii_digit() || ".-e".contains(c))).unwrap_or(number.len());
+                number[..end].parse().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hh_channels_to_shared_model() {
+        let mut sim = GenesisSimulation::new();
+        models::squid(&mut sim, "/axon").unwrap();
+        let na = ion_channel(&sim, "/axon/Na").unwrap();
+        let k = ion_channel(&sim, "/axon/K").unwrap();
+        assert!((na.g_max - 120.0).abs() < 1e-9 && (k.g_max - 36.0).abs() < 1e-9);
+        assert!((na.e_rev - 45.0).abs() < 1e-9 && (k.e_rev + 82.0).abs() < 1e-9);
+        assert_eq!(na.gates.iter().map(|g| g.power).collect::<Vec<_>>(), vec![3, 1]);
+
+        // The shared model's rates (mV, 1/ms) are GENESIS's (V, 1/s)
+        for (path, channel) in [("/axon/Na", &na), ("/axon/K", &k)] {
+            let element = sim.get(path).unwrap();
+            for gate in &channel.gates {
+                for mv in (-100..=50).step_by(5) {
+                    let (a, b) = solver::rates(element, &gate.name, mv as f64 * 1e-3).unwrap();
+                    let (alpha, beta) = (gate.alpha.eval(mv as f64), gate.beta.eval(mv as f64));
+                    assert!((alpha - a / 1e3).abs() <= 1e-9 * alpha.abs().max(1e-3), "{} {} {}", path, mv, alpha);
+                    assert!((beta - (b - a) / 1e3).abs() <= 1e-9 * beta.abs().max(1e-3), "{} {} {}", path, mv, beta);
+                }
+            }
+        }
+
+        models::traub91(&mut sim, "/cell").unwrap();
+        assert!(ion_channel(&sim, "/cell/soma/Na").is_err());
+        assert!(ion_channel(&sim, "/cell/soma").is_err());
+    }
+
+    #[test]
+    fn test_export_network() {
+        let mut sli = Sli::new();
+        sli.execute("create neutral /net").unwrap();
+        for cell in ["/net/a", "/net/b"] {
+            models::traub91(&mut sli.sim, cell).unwrap();
+        }
+        models::squid(&mut sli.sim, "/net/axon").unwrap();
+        sli.execute(r#"
+            create spikegen /net/a/soma/spike
+            setfield /net/a/soma/spike thresh -0.02 abs_refract 0.001
+            addmsg /net/a/soma /net/a/soma/spike INPUT Vm
+            create synchan /net/b/apical_10/syn
+            setfield /net/b/apical_10/syn tau1 0.0005 tau2 0.003 gmax 1e-9 Ek 0
+            addmsg /net/a/soma/spike /net/b/apical_10/syn SPIKE
+            setfield /net/b/apical_10/syn synapse[0].weight 2 synapse[0].delay 0.004
+            addmsg /net/b/apical_10 /net/b/apical_10/syn VOLTAGE Vm
+            addmsg /net/b/apical_10/syn /net/b/apical_10 CHANNEL Gk Ek
+        "#).unwrap();
+        let xml = to_neuroml(&sli.sim, "/net").unwrap();
+        check_nesting(&xml);
+        assert!(xml.contains("<neuroml ") && xml.contains("id=\"net\">"));
+
+        // Three cells: two CA3 cells of 19 compartments and the axon,
+        // which being directly under /net takes its name
+        let compartments = crate::wildcard::find(&sli.sim, "/net/a/#[TYPE=compartment]").unwrap().len();
+        assert_eq!(xml.matches("<cell id=").count(), 3);
+        assert!(xml.contains("<cell id=\"a\">") && xml.contains("<cell id=\"net\">"));
+        assert_eq!(xml.matches("<segment id=").count(), 2 * compartments + 1);
+        for value in values(&xml, "specificCapacitance", "value").iter().take(compartments) {
+            assert!((value - 0.03).abs() < 1e-12, "{}", value);
+        }
+
+        // The CA3 cells share six tabulated channels, the squid channels
+        // keep their HH rates and the Na of the axon is told apart from the
+        // CA3 Na
+        assert_eq!(xml.matches("<ionChannelHH ").count(), 8);
+        assert!(xml.contains("<ionChannelHH id=\"axon_Na\""));
+        assert!(xml.contains("<forwardRate type=\"HHExpLinearRate\" rate=\"1e3per_s\""));
+        let squid_na: Vec<f64> = values(&xml, "channelDensity", "condDensity").into_iter()
+            .filter(|g| (g - 1200.0).abs() < 1e-6).collect();
+        assert_eq!(squid_na.len(), 1);
+        assert!(xml.contains("<ComponentType name=\"K_C_Z_inf\" extends=\"baseVoltageConcDepVariable\">"));
+        assert!(xml.contains("<ComponentType name=\"K_AHP_Z_alpha\" extends=\"baseVoltageConcDepRate\">"));
+        assert!(xml.contains("<ComponentType name=\"Na_X_alpha\" extends=\"baseVoltageDepRate\">"));
+        let pools = xml.matches("<fixedFactorConcentrationModel ").count();
+        assert_eq!(pools, crate::wildcard::find(&sli.sim, "/net/##[TYPE=Ca_concen]").unwrap().len());
+        assert_eq!(xml.matches("ion=\"ca\" segmentGroup").count(), pools);
+
+        // Resampled tables follow the originals
+        let table = &sli.sim.get("/library/K_A").unwrap().tables["Y_B"];
+        let resampled = resample(table);
+        assert_eq!(resampled.xdivs(), MAX_DIVS);
+        let peak = table.values.iter().cloned().fold(0.0, f64::max);
+        for i in 0..=1000 {
+            let v = table.xmin + (table.xmax - table.xmin) * i as f64 / 1000.0;
+            assert!((resampled.lookup(v) - table.lookup(v)).abs() < 1e-3 * peak, "{}", v);
+        }
+
+        // The spike connection, from the soma of a to apical_10 of b
+        assert!(xml.contains("<expTwoSynapse id=\"b_apical_10_syn\" gbase=\"1e-9S\" erev=\"0e0V\" tauRise=\"5e-4s\" tauDecay=\"3e-3s\"/>"));
+        assert!(xml.contains("<spikeThresh value=\"-2e-2V\" segmentGroup=\"soma\"/>"));
+        assert_eq!(xml.matches("<projection ").count(), 1);
+        assert!(xml.contains("presynapticPopulation=\"a_pop\" postsynapticPopulation=\"b_pop\" synapse=\"b_apical_10_syn\""));
+        assert_eq!(values(&xml, "connectionWD", "weight"), vec![2.0]);
+        assert_eq!(values(&xml, "connectionWD", "delay"), vec![0.004]);
+    }
+}
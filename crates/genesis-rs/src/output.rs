@@ -0,0 +1,214 @@
+//! Output to files (`asc_file`, `disk_out`)
+//!
+//! Output elements write the fields sent to them with SAVE messages
+//! (`addmsg /cell/soma /out SAVE Vm`) each time their clock ticks, after
+//! the compartments and channels of the step, to the file named in
+//! `filename` (the element's name when empty). Files are opened at
+//! `reset`, and appended to rather than replaced when `append` is set.
+//!
+//! - `asc_file` writes a line per tick: the time, left out when `notime`
+//!   is set, and the values, separated by spaces
+//! - `disk_out` writes GENESIS's binary FMT1 format, read back by
+//!   [`read_fmt1`]
+//!
+//! An FMT1 file, little-endian, starts with a 240-byte title beginning
+//! `FMT1`, the start time and time step (`f32`), the number of values per
+//! frame and their type (`i32`, 4 for `f32`), and the `x y z` position of
+//! the source of each value (`f32`). Frames of `f32` values follow.
+
+use crate::{messages, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Time};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+
+/// Length of the FMT1 title
+const TITLE: usize = 240;
+/// FMT1 type code of `f32` values
+const FLOAT: i32 = 4;
+
+pub(crate) fn is_output(element: &Element) -> bool {
+    matches!(element.element_type, ElementType::AscFile | ElementType::DiskOut)
+}
+
+fn file_name(element: &Element) -> String {
+    match element.get_text("filename") {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => element.name().to_string(),
+    }
+}
+
+/// Open the file of the output element at `path`, writing the FMT1 header
+/// of a new `disk_out` file
+fn open(sim: &mut GenesisSimulation, path: &str) -> Result<()> {
+    let element = &sim.elements[path];
+    let name = file_name(element);
+    let append = element.get_param("append").unwrap_or(0.0) != 0.0;
+    let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(&name)?;
+    let fresh = file.metadata()?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if matches!(element.element_type, ElementType::DiskOut) && fresh {
+        let mut title = [0u8; TITLE];
+        title[..4].copy_from_slice(b"FMT1");
+        writer.write_all(&title)?;
+        let dt = sim.clocks.get(&element.clock).copied().unwrap_or(sim.dt);
+        writer.write_all(&(sim.time as f32).to_le_bytes())?;
+        writer.write_all(&(dt as f32).to_le_bytes())?;
+        let sources: Vec<&str> = element.messages_in.iter()
+            .filter(|m| m.msg_type == "SAVE")
+            .map(|m| m.source.as_str())
+            .collect();
+        writer.write_all(&(sources.len() as i32).to_le_bytes())?;
+        writer.write_all(&FLOAT.to_le_bytes())?;
+        for source in sources {
+            let source = &sim.elements[source];
+            for axis in ["x", "y", "z"] {
+                writer.write_all(&(source.get_param(axis).unwrap_or(0.0) as f32).to_le_bytes())?;
+            }
+        }
+    }
+    sim.files.insert(path.to_string(), writer);
+    Ok(())
+}
+
+/// Open the files of the simulated output elements, closing any open
+pub(crate) fn reset(sim: &mut GenesisSimulation) -> Result<()> {
+    for mut writer in std::mem::take(&mut sim.files).into_values() {
+        writer.flush()?;
+    }
+    for path in sim.paths() {
+        if is_output(&sim.elements[&path]) && sim.is_simulated(&path) {
+            open(sim, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the present values of the SAVE messages of `outputs` at `time`
+pub(crate) fn write(sim: &mut GenesisSimulation, outputs: &[String], time: Time) -> Result<()> {
+    for path in outputs {
+        if !sim.files.contains_key(path) {
+            open(sim, path)?;
+        }
+        let element = &sim.elements[path];
+        let values: Vec<f64> = messages::inputs(sim, element, "SAVE")?.into_iter().map(|(_, slots)| slots[0]).collect();
+        let ascii = matches!(element.element_type, ElementType::AscFile);
+        let notime = element.get_param("notime").unwrap_or(0.0) != 0.0;
+        let writer = sim.files.get_mut(path).unwrap();
+        if ascii {
+            let mut line: Vec<String> = values.iter().map(f64::to_string).collect();
+            if !notime {
+                line.insert(0, time.to_string());
+            }
+            writeln!(writer, "{}", line.join(" "))?;
+        } else {
+            for value in values {
+                writer.write_all(&(value as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Contents of an FMT1 file written by `disk_out`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fmt1 {
+    pub start_time: f32,
+    pub dt: f32,
+    /// Position of the source of each value
+    pub positions: Vec<[f32; 3]>,
+    /// Values at each tick
+    pub frames: Vec<Vec<f32>>,
+}
+
+/// Read an FMT1 file
+pub fn read_fmt1(bytes: &[u8]) -> Result<Fmt1> {
+    let bad = |msg: &str| OldiesError::ParseError(format!("FMT1: {}", msg));
+    if bytes.len() < TITLE + 16 || !bytes.starts_with(b"FMT1") {
+        return Err(bad("not an FMT1 file"));
+    }
+    let word = |i: usize| -> [u8; 4] { bytes[i..i + 4].try_into().unwrap() };
+    let (start_time, dt) = (f32::from_le_bytes(word(TITLE)), f32::from_le_bytes(word(TITLE + 4)));
+    let n = i32::from_le_bytes(word(TITLE + 8));
+    if n < 0 || i32::from_le_bytes(word(TITLE + 12)) != FLOAT {
+        return Err(bad("unsupported header"));
+    }
+    let n = n as usize;
+    let data = TITLE + 16 + 12 * n;
+    if bytes.len() < data || !(bytes.len() - data).is_multiple_of(4 * n) {
+        return Err(bad("truncated file"));
+    }
+    let floats = |from: usize, count: usize| (0..count).map(|k| f32::from_le_bytes(word(from + 4 * k))).collect::<Vec<_>>();
+    let positions = (0..n).map(|k| {
+        let p = floats(TITLE + 16 + 12 * k, 3);
+        [p[0], p[1], p[2]]
+    }).collect();
+    let frames = match n {
+        0 => vec![],
+        _ => (data..bytes.len()).step_by(4 * n).map(|at| floats(at, n)).collect(),
+    };
+    Ok(Fmt1 { start_time, dt, positions, frames })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    #[test]
+    fn test_asc_file_and_disk_out() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let (asc, disk) = (dir.join(format!("oldies-vm-{}.txt", id)), dir.join(format!("oldies-vm-{}.fmt1", id)));
+        let mut sli = Sli::new();
+        sli.execute(&format!(r#"
+            create compartment /a
+            create compartment /b
+            setfield /a Rm 1e8 Cm 1e-11 Em 0 initVm -0.07 x 1e-5
+            setfield /b Rm 1e8 Cm 1e-11 Em 0 initVm -0.07 inject 1e-10
+            create asc_file /vm
+            setfield /vm filename {}
+            addmsg /a /vm SAVE Vm
+            addmsg /b /vm SAVE Vm
+            create disk_out /bin
+            setfield /bin filename {}
+            addmsg /a /bin SAVE Vm
+            addmsg /b /bin SAVE inject
+            setclock 0 1e-4
+            setclock 1 1e-3
+            useclock /vm 1
+            useclock /bin 1
+            reset
+            step 0.01 -time
+        "#, asc.display(), disk.display())).unwrap();
+        assert_eq!(sli.call("getfield /vm filename").unwrap(), asc.display().to_string());
+
+        // One line per tick of clock 1, at the end of the step it falls in
+        let text = std::fs::read_to_string(&asc).unwrap();
+        let lines: Vec<Vec<f64>> = text.lines()
+            .map(|l| l.split(' ').map(|w| w.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(lines.len(), 10);
+        assert!((lines[1][0] - 1.1e-3).abs() < 1e-12, "{:?}", lines[1]);
+        let a = sli.sim.get("/a").unwrap().get_param("Vm").unwrap();
+        assert!(lines[9][1] < a && lines[0][1] < lines[9][1]);
+        assert!(lines[9][2] > lines[9][1]);
+
+        let fmt1 = read_fmt1(&std::fs::read(&disk).unwrap()).unwrap();
+        assert_eq!((fmt1.start_time, fmt1.dt), (0.0, 1e-3));
+        assert_eq!(fmt1.positions, vec![[1e-5, 0.0, 0.0], [0.0, 0.0, 0.0]]);
+        assert_eq!(fmt1.frames.len(), 10);
+        assert_eq!(fmt1.frames[4], vec![lines[4][1] as f32, 1e-10]);
+
+        // Reset starts the files again, or appends to them
+        sli.execute("setfield /vm notime 1 append 1; reset; step 2e-3 -time").unwrap();
+        let text = std::fs::read_to_string(&asc).unwrap();
+        assert_eq!(text.lines().count(), 12);
+        assert_eq!(text.lines().last().unwrap().split(' ').count(), 2);
+        assert_eq!(read_fmt1(&std::fs::read(&disk).unwrap()).unwrap().frames.len(), 2);
+
+        std::fs::remove_file(&asc).unwrap();
+        std::fs::remove_file(&disk).unwrap();
+        assert!(read_fmt1(b"FMT0").is_err());
+        assert!(sli.execute("setfield /vm filename /nonexistent/dir/vm.txt; reset").is_err());
+    }
+}
@@ -0,0 +1,456 @@
+//! Parallel element domains (PGENESIS)
+//!
+//! PGENESIS ran large models on several nodes, each simulating part of the
+//! element tree and passing the messages that crossed nodes at barriers.
+//! [`Parallel`] runs a simulation the same way on threads: the tree is
+//! split into domains of whole top-level subtrees ([`partition`] balances
+//! them by element count, so no cell is cut), every domain is a
+//! simulation of its own stepped on its own thread, and the domains meet
+//! every `sync` seconds, a whole number of steps.
+//!
+//! Messages between domains are buffered until the next meeting:
+//!
+//! - A spike for a synchan of another domain is handed over at the meeting
+//!   with the time it was emitted, and arrives `delay` after that like any
+//!   other. It is on time when every synaptic delay between domains is at
+//!   least the sync interval, the lookahead PGENESIS asks for, and arrives
+//!   at the meeting otherwise.
+//! - Other messages read a copy of their source kept in the destination's
+//!   domain and refreshed at each meeting, so their values are up to a sync
+//!   interval old. `xcell`s showing compartments of other domains see
+//!   copies the same way.
+//!
+//! Splitting a simulation and [joining](Parallel::join) its domains gives
+//! back the simulation with all its state, so a model is built and reset
+//! as one and only run in parallel; the SLI `paron` command does this for
+//! every `step` (see [`crate::sli`]). Every domain has a random number
+//! generator of its own, the first the simulation's and the others seeded
+//! from it, so a parallel run is reproducible for a given partition.
+//!
+//! Domains are threads of one process; the buffering would carry over to
+//! processes unchanged.
+
+use oldies_core::Rng;
+use crate::{synapse, wildcard, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Time};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::thread;
+
+/// How the SLI runs its steps (`paron -nodes count -sync time`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Nodes {
+    /// Number of domains
+    pub count: usize,
+    /// Time between meetings, every step when `None`
+    pub sync: Option<Time>,
+}
+
+/// Top-level element of the tree `path` is in
+fn root_of(path: &str) -> &str {
+    match path[1..].find('/') {
+        Some(i) => &path[..i + 1],
+        None => path,
+    }
+}
+
+/// Split the top-level subtrees of `sim` into `count` domains of about as
+/// many elements each, returning the top-level elements of each domain
+pub fn partition(sim: &GenesisSimulation, count: usize) -> Vec<Vec<String>> {
+    let mut sizes: BTreeMap<&str, usize> = BTreeMap::new();
+    for path in sim.elements.keys() {
+        *sizes.entry(root_of(path)).or_default() += 1;
+    }
+    let mut roots: Vec<(&str, usize)> = sizes.into_iter().collect();
+    roots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    // Largest first, each into the domain with the fewest elements so far
+    let mut domains = vec![(0, vec![]); count.max(1)];
+    for (root, size) in roots {
+        let lightest = (0..domains.len()).min_by_key(|&d| domains[d].0).unwrap();
+        domains[lightest].0 += size;
+        domains[lightest].1.push(root.to_string());
+    }
+    domains.into_iter()
+        .map(|(_, mut roots)| {
+            roots.sort();
+            roots
+        })
+        .collect()
+}
+
+/// Copy of `element` standing in for it in another domain: its fields,
+/// without messages or children, left out of the simulation
+fn ghost(element: &Element) -> Element {
+    Element {
+        children: vec![],
+        messages_in: vec![],
+        messages_out: vec![],
+        disabled: true,
+        ..element.clone()
+    }
+}
+
+/// Domain of every element, and the elements of other domains each domain
+/// needs copies of
+struct Plan {
+    owner: HashMap<String, usize>,
+    ghosts: Vec<BTreeSet<String>>,
+    sync_steps: usize,
+}
+
+impl Plan {
+    fn new(sim: &GenesisSimulation, roots: &[Vec<String>], sync: Time) -> Result<Plan> {
+        let error = |msg: String| OldiesError::SimulationError(msg);
+        if roots.is_empty() {
+            return Err(error("a parallel run needs a domain".into()));
+        }
+        if sync.is_nan() || sync <= 0.0 {
+            return Err(error(format!("domains need a positive sync interval, not {}", sync)));
+        }
+        let mut domain_of_root = HashMap::new();
+        for (d, list) in roots.iter().enumerate() {
+            for root in list {
+                if !sim.elements.contains_key(root) || root_of(root) != root {
+                    return Err(error(format!("{} is not a top-level element", root)));
+                }
+                if domain_of_root.insert(root.as_str(), d).is_some() {
+                    return Err(error(format!("{} is in two domains", root)));
+                }
+            }
+        }
+        // Trees no domain lists go to the first
+        let owner: HashMap<String, usize> = sim.elements.keys()
+            .map(|path| (path.clone(), domain_of_root.get(root_of(path)).copied().unwrap_or(0)))
+            .collect();
+        for (solver, hines) in &sim.solvers {
+            if hines.owned().any(|path| owner[path] != owner[solver]) {
+                return Err(error(format!("{} solves elements of several domains", solver)));
+            }
+        }
+
+        let mut ghosts = vec![BTreeSet::new(); roots.len()];
+        for (path, element) in &sim.elements {
+            let mut sources: Vec<String> = element.messages_in.iter().map(|m| m.source.clone()).collect();
+            if matches!(element.element_type, ElementType::XCell) {
+                let pattern = element.get_text("path").unwrap_or("");
+                match wildcard::is_pattern(pattern) {
+                    true => sources.extend(wildcard::find(sim, pattern)?),
+                    false => sources.push(pattern.to_string()),
+                }
+            }
+            let d = owner[path];
+            for source in sources {
+                if owner.get(&source).is_some_and(|&o| o != d) {
+                    ghosts[d].insert(source);
+                }
+            }
+        }
+        let sync_steps = ((sync / sim.dt).round() as usize).max(1);
+        Ok(Plan { owner, ghosts, sync_steps })
+    }
+}
+
+/// Move the entries of `map` into the domains owning their keys, as
+/// `slot` picks the map of a domain
+fn scatter<T>(
+    map: HashMap<String, T>,
+    domains: &mut [GenesisSimulation],
+    owner: &HashMap<String, usize>,
+    slot: impl Fn(&mut GenesisSimulation) -> &mut HashMap<String, T>,
+) {
+    for (key, value) in map {
+        let d = owner.get(&key).copied().unwrap_or(0);
+        slot(&mut domains[d]).insert(key, value);
+    }
+}
+
+/// A simulation split into domains run in parallel
+#[derive(Debug)]
+pub struct Parallel {
+    domains: Vec<GenesisSimulation>,
+    owner: HashMap<String, usize>,
+    /// Elements of other domains each domain holds copies of
+    ghosts: Vec<Vec<String>>,
+    /// Steps between meetings
+    sync_steps: usize,
+}
+
+impl Parallel {
+    /// Split `sim` into a domain for each list of top-level elements in
+    /// `roots`, meeting every `sync`. Trees no list names go to the first
+    /// domain.
+    pub fn new(sim: GenesisSimulation, roots: &[Vec<String>], sync: Time) -> Result<Parallel> {
+        let plan = Plan::new(&sim, roots, sync)?;
+        Ok(Self::split(sim, plan))
+    }
+
+    fn split(mut sim: GenesisSimulation, plan: Plan) -> Parallel {
+        let mut seeds = sim.rng.clone();
+        let mut domains: Vec<GenesisSimulation> = (0..plan.ghosts.len())
+            .map(|d| GenesisSimulation {
+                time: sim.time,
+                dt: sim.dt,
+                clocks: sim.clocks.clone(),
+                next_tick: sim.next_tick.clone(),
+                rng: if d == 0 { sim.rng.clone() } else { Rng::new(seeds.next_u64()) },
+                registry: sim.registry.clone(),
+                ..GenesisSimulation::new()
+            })
+            .collect();
+        for (domain, ghosts) in domains.iter_mut().zip(&plan.ghosts) {
+            for path in ghosts {
+                domain.elements.insert(path.clone(), ghost(&sim.elements[path]));
+            }
+        }
+        let owner = &plan.owner;
+        scatter(std::mem::take(&mut sim.elements), &mut domains, owner, |s| &mut s.elements);
+        scatter(std::mem::take(&mut sim.spikes), &mut domains, owner, |s| &mut s.spikes);
+        scatter(std::mem::take(&mut sim.files), &mut domains, owner, |s| &mut s.files);
+        scatter(std::mem::take(&mut sim.snapshots), &mut domains, owner, |s| &mut s.snapshots);
+        scatter(std::mem::take(&mut sim.solvers), &mut domains, owner, |s| &mut s.solvers);
+        scatter(std::mem::take(&mut sim.searches), &mut domains, owner, |s| &mut s.searches);
+        // Recordings are named by their plots, which belong to the graph's
+        // domain
+        scatter(std::mem::take(&mut sim.recordings), &mut domains, owner, |s| &mut s.recordings);
+        for (source, dest, time) in sim.outbox.drain(..) {
+            let d = owner.get(&dest).copied().unwrap_or(0);
+            domains[d].outbox.push((source, dest, time));
+        }
+        let mut parallel = Parallel {
+            domains,
+            ghosts: plan.ghosts.into_iter().map(|g| g.into_iter().collect()).collect(),
+            owner: plan.owner,
+            sync_steps: plan.sync_steps,
+        };
+        parallel.exchange();
+        parallel
+    }
+
+    /// The domains, in the order they were given
+    pub fn domains(&self) -> &[GenesisSimulation] {
+        &self.domains
+    }
+
+    /// Domain the element at `path` belongs to
+    pub fn domain_of(&self, path: &str) -> Option<usize> {
+        self.owner.get(path).copied()
+    }
+
+    /// Current simulation time
+    pub fn current_time(&self) -> Time {
+        self.domains[0].time
+    }
+
+    /// Run every domain for `steps` steps, meeting every sync interval
+    pub fn run_steps(&mut self, steps: usize) -> Result<()> {
+        let mut left = steps;
+        while left > 0 {
+            let chunk = left.min(self.sync_steps);
+            let results: Vec<Result<()>> = thread::scope(|scope| {
+                let threads: Vec<_> = self.domains.iter_mut()
+                    .map(|sim| scope.spawn(move || (0..chunk).try_for_each(|_| sim.step())))
+                    .collect();
+                threads.into_iter().map(|t| t.join().expect("domain thread panicked")).collect()
+            });
+            results.into_iter().collect::<Result<Vec<()>>>()?;
+            self.exchange();
+            left -= chunk;
+        }
+        self.domains.iter_mut().try_for_each(GenesisSimulation::flush)
+    }
+
+    /// Run every domain for `duration`
+    pub fn run(&mut self, duration: Time) -> Result<()> {
+        self.run_steps((duration / self.domains[0].dt) as usize)
+    }
+
+    /// Hand over the spikes sent between domains and refresh the copies
+    fn exchange(&mut self) {
+        let spikes: Vec<(String, String, Time)> = self.domains.iter_mut().flat_map(|sim| sim.outbox.drain(..)).collect();
+        for (source, dest, time) in spikes {
+            if let Some(&d) = self.owner.get(&dest) {
+                synapse::receive_spike(&mut self.domains[d], &dest, &source, time);
+            }
+        }
+        for d in 0..self.domains.len() {
+            for path in &self.ghosts[d] {
+                let copy = ghost(&self.domains[self.owner[path]].elements[path]);
+                self.domains[d].elements.insert(path.clone(), copy);
+            }
+        }
+    }
+
+    /// Gather the domains back into one simulation
+    pub fn join(mut self) -> GenesisSimulation {
+        self.exchange();
+        let mut domains = self.domains.into_iter().zip(self.ghosts);
+        let (mut sim, ghosts) = domains.next().expect("a domain");
+        for path in ghosts {
+            sim.elements.remove(&path);
+        }
+        for (mut domain, ghosts) in domains {
+            for path in ghosts {
+                domain.elements.remove(&path);
+            }
+            sim.elements.extend(domain.elements);
+            sim.spikes.extend(domain.spikes);
+            sim.files.extend(domain.files);
+            sim.recordings.extend(domain.recordings);
+            sim.snapshots.extend(domain.snapshots);
+            sim.solvers.extend(domain.solvers);
+            sim.searches.extend(domain.searches);
+        }
+        sim.tasks = None;
+        sim
+    }
+}
+
+/// Run `sim` for `steps` steps split as `nodes` asks, joining it back
+/// even when a step fails
+pub fn run_steps(sim: &mut GenesisSimulation, nodes: Nodes, steps: usize) -> Result<()> {
+    let roots = partition(sim, nodes.count);
+    let plan = Plan::new(sim, &roots, nodes.sync.unwrap_or(sim.dt))?;
+    let mut parallel = Parallel::split(std::mem::take(sim), plan);
+    let result = parallel.run_steps(steps);
+    *sim = parallel.join();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    /// Four cells in a chain, each firing the next through a synapse with
+    /// a delay of 3 ms; the first is driven by a pulse and the second
+    /// integrated by an hsolve element
+    const CHAIN: &str = r#"
+        function cell(name)
+            str name
+            create compartment /{name}/soma
+            setfield /{name}/soma Cm 7.854e-9 Rm 4.244e5 Em -0.0594 initVm -0.07
+            create Na_squid_hh /{name}/soma/Na
+            create K_squid_hh /{name}/soma/K
+            setfield /{name}/soma/Na Gbar 9.425e-4
+            setfield /{name}/soma/K Gbar 2.827e-4
+            addmsg /{name}/soma /{name}/soma/Na VOLTAGE Vm
+            addmsg /{name}/soma/Na /{name}/soma CHANNEL Gk Ek
+            addmsg /{name}/soma /{name}/soma/K VOLTAGE Vm
+            addmsg /{name}/soma/K /{name}/soma CHANNEL Gk Ek
+            create spikegen /{name}/soma/spike
+            setfield /{name}/soma/spike thresh 0 abs_refract 0.002 edge_triggered 1
+            addmsg /{name}/soma /{name}/soma/spike INPUT Vm
+            create synchan /{name}/soma/syn
+            setfield /{name}/soma/syn tau1 0.0005 tau2 0.001 gmax 5e-6 Ek 0
+            addmsg /{name}/soma /{name}/soma/syn VOLTAGE Vm
+            addmsg /{name}/soma/syn /{name}/soma CHANNEL Gk Ek
+        end
+        foreach name (c0 c1 c2 c3)
+            create neutral /{name}
+            cell {name}
+        end
+        foreach link (c0:c1 c1:c2 c2:c3)
+            str from = {substring {link} 0 1}
+            str to = {substring {link} 3 4}
+            addmsg /{from}/soma/spike /{to}/soma/syn SPIKE
+            setfield /{to}/soma/syn synapse[0].delay 0.003
+        end
+        create pulsegen /c0/stim
+        setfield /c0/stim level1 8e-8 width1 0.004 delay1 0.002 delay2 999
+        addmsg /c0/stim /c0/soma INJECT output
+        create hsolve /c1/solve
+        setfield /c1/solve path /c1/soma
+        call /c1/solve SETUP
+        reset
+    "#;
+
+    fn chain() -> GenesisSimulation {
+        let mut sli = Sli::new();
+        sli.execute(CHAIN).unwrap();
+        sli.sim
+    }
+
+    /// Time each cell last fired and its potential
+    fn state(sim: &GenesisSimulation) -> Vec<(f64, f64)> {
+        (0..4)
+            .map(|i| {
+                let soma = sim.get(&format!("/c{}/soma", i)).unwrap();
+                let spike = sim.get(&format!("/c{}/soma/spike", i)).unwrap();
+                (spike.get_param("lastevent").unwrap(), soma.get_param("Vm").unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_domains_match_serial_run() {
+        let mut serial = chain();
+        serial.run(0.03).unwrap();
+        let expected = state(&serial);
+        // The spike runs down the whole chain
+        assert!(expected[3].0 > 0.01, "{:?}", expected);
+
+        let sim = chain();
+        let roots = partition(&sim, 2);
+        assert_eq!(roots, [["/c0", "/c2"], ["/c1", "/c3"]]);
+        let mut parallel = Parallel::new(sim, &roots, 0.001).unwrap();
+        assert_eq!(parallel.domain_of("/c1/solve"), Some(1));
+        // The synchans of the second domain read copies of the first's
+        // spikegens
+        assert!(parallel.domains()[1].get("/c0/soma/spike").unwrap().disabled);
+        parallel.run(0.03).unwrap();
+        assert_eq!(parallel.current_time(), serial.current_time());
+        let joined = parallel.join();
+        // Every link is between domains, and all its delays are longer
+        // than the sync interval
+        assert_eq!(state(&joined), expected);
+        assert_eq!(joined.paths(), serial.paths());
+        assert!(joined.paths().iter().all(|p| !joined.get(p).unwrap().disabled));
+
+        // Delays shorter than the interval arrive late
+        let mut late = Parallel::new(chain(), &roots, 0.01).unwrap();
+        late.run(0.03).unwrap();
+        let fired = state(&late.join());
+        // The first spike reaches the second cell at the meeting at 10 ms,
+        // not 6.9 ms
+        assert_eq!(fired[0], expected[0]);
+        assert!((fired[1].0 - expected[1].0 - 0.0031).abs() < 1e-4, "{:?} {:?}", fired, expected);
+    }
+
+    #[test]
+    fn test_bad_partitions() {
+        let sim = chain();
+        let roots = |lists: &[&[&str]]| -> Vec<Vec<String>> {
+            lists.iter().map(|l| l.iter().map(|s| s.to_string()).collect()).collect()
+        };
+        let plan = |lists: &[&[&str]], sync: f64| Plan::new(&sim, &roots(lists), sync).map(|_| ());
+        assert!(plan(&[&["/c0"], &["/c0"]], 0.001).is_err());
+        assert!(plan(&[&["/c0/soma"]], 0.001).is_err());
+        assert!(plan(&[&["/c0"]], 0.0).is_err());
+        assert!(plan(&[], 0.001).is_err());
+        // The unlisted trees join the first domain
+        plan(&[&["/c0"]], 0.001).unwrap();
+
+        // A solver may not reach into another domain
+        let mut sli = Sli::new();
+        sli.sim = chain();
+        sli.execute("create hsolve /solve; setfield /solve path /c2/soma; call /solve SETUP").unwrap();
+        assert!(Plan::new(&sli.sim, &roots(&[&["/solve"], &["/c2"]]), 0.001).is_err());
+    }
+
+    #[test]
+    fn test_paron() {
+        let mut serial = Sli::new();
+        serial.execute(CHAIN).unwrap();
+        serial.execute("step 0.03 -time").unwrap();
+
+        let mut sli = Sli::new();
+        sli.execute(CHAIN).unwrap();
+        assert_eq!(sli.call("nnodes").unwrap(), "1");
+        sli.execute("paron -nodes 3 -sync 0.002; step 0.015 -time; step 0.015 -time").unwrap();
+        assert_eq!(sli.call("nnodes").unwrap(), "3");
+        assert_eq!(state(&sli.sim), state(&serial.sim));
+        sli.execute("paroff").unwrap();
+        assert_eq!(sli.call("nnodes").unwrap(), "1");
+        assert!(sli.execute("paron -nodes 0").is_err());
+        assert!(sli.execute("paron -cores 2").is_err());
+    }
+}
@@ -0,0 +1,418 @@
+//! Parameter searches (`paramtableBF`, `paramtableSA`, `paramtableGA`)
+//!
+//! The objects of GENESIS's param library fit model parameters to a
+//! fitness the script computes. Each parameter is declared with
+//! `call table initparam<BF|SA|GA> i type range center [label]`: type 0
+//! varies it additively over `center ± range`, type 1 multiplicatively
+//! over `center / range` to `center * range`. The search then proposes
+//! values in the fields `current[i]` and is told how good they were:
+//!
+//! ```text
+//! call /SA initsearch
+//! while (!{getfield /SA done})
+//!     setfield /cell/soma/K Gbar {getfield /SA current[0]}
+//!     ... run and compare with the data ...
+//!     setfield /SA current_match {fitness}
+//!     call /SA update_params
+//! end
+//! ```
+//!
+//! or `call /SA search fitness`, which runs the same loop calling the
+//! script function `fitness` with the table's path for each proposal. Larger matches are
+//! better; the best so far is kept in `best[i]` and `best_match`, and
+//! `call table recenter` moves the centers to it for a finer search.
+//!
+//! - `paramtableBF` tries every point of a grid of `divisions` values
+//!   per parameter
+//! - `paramtableSA` anneals from the centers for `max_iterations`
+//!   proposals, stepping by `step` (a fraction of the range) and cooling
+//!   from `inittemp` by `annealing_rate` each proposal
+//! - `paramtableGA` evolves `num_individuals` for `max_generations`,
+//!   with tournament selection, uniform crossover
+//!   (`crossover_probability`) and mutation (`mutation_probability`)
+//!
+//! Random choices use the simulation's generator (`randseed`).
+
+use crate::{Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Rng};
+
+/// Whether `element` is a parameter table
+pub(crate) fn is_param_table(element: &Element) -> bool {
+    matches!(element.element_type, ElementType::ParamTableBF | ElementType::ParamTableSA | ElementType::ParamTableGA)
+}
+
+fn error(msg: String) -> OldiesError {
+    OldiesError::SimulationError(msg)
+}
+
+/// How a search moves through the parameter space, in coordinates
+/// scaled to [0, 1] per parameter
+#[derive(Debug, Clone)]
+enum Method {
+    BruteForce { divisions: usize, point: Vec<usize> },
+    Annealing { temperature: f64, rate: f64, step: f64, max_iterations: usize, accepted: Option<(Vec<f64>, f64)> },
+    Genetic {
+        population: Vec<Vec<f64>>,
+        fitness: Vec<f64>,
+        individual: usize,
+        generation: usize,
+        max_generations: usize,
+        crossover: f64,
+        mutation: f64,
+    },
+}
+
+/// A search in progress
+#[derive(Debug, Clone)]
+pub struct Search {
+    /// Scale (0 additive, 1 multiplicative), range and center of each
+    /// parameter
+    params: Vec<(f64, f64, f64)>,
+    method: Method,
+    /// Proposal being evaluated, scaled
+    current: Vec<f64>,
+    best: Option<(Vec<f64>, f64)>,
+    iteration: usize,
+    done: bool,
+}
+
+impl Search {
+    /// Value of parameter `i` at scaled coordinate `u`
+    fn value(&self, i: usize, u: f64) -> f64 {
+        let (scale, range, center) = self.params[i];
+        match scale as i32 {
+            1 => center * range.powf(2.0 * u - 1.0),
+            _ => center + range * (2.0 * u - 1.0),
+        }
+    }
+
+    /// Values of the parameters at scaled coordinates `u`
+    pub fn values(&self, u: &[f64]) -> Vec<f64> {
+        u.iter().enumerate().map(|(i, &u)| self.value(i, u)).collect()
+    }
+
+    /// Values being evaluated
+    pub fn current(&self) -> Vec<f64> {
+        self.values(&self.current)
+    }
+
+    /// Best values and match found so far
+    pub fn best(&self) -> Option<(Vec<f64>, f64)> {
+        self.best.as_ref().map(|(u, m)| (self.values(u), *m))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Take the match of the current values and propose the next
+    fn update(&mut self, matched: f64, rng: &mut Rng) {
+        if self.done {
+            return;
+        }
+        if self.best.as_ref().is_none_or(|(_, best)| matched > *best) {
+            self.best = Some((self.current.clone(), matched));
+        }
+        self.iteration += 1;
+        let n = self.params.len();
+        let clamp = |u: f64| u.clamp(0.0, 1.0);
+        match &mut self.method {
+            Method::BruteForce { divisions, point } => {
+                // Next point of the grid, the first parameter fastest
+                let mut k = 0;
+                while k < n {
+                    point[k] += 1;
+                    if point[k] < *divisions {
+                        break;
+                    }
+                    point[k] = 0;
+                    k += 1;
+                }
+                self.done = k == n;
+                self.current = grid(point, *divisions);
+            }
+            Method::Annealing { temperature, rate, step, max_iterations, accepted } => {
+                let keep = match accepted {
+                    Some((_, previous)) => {
+                        matched >= *previous || rng.uniform() < ((matched - *previous) / *temperature).exp()
+                    }
+                    None => true,
+                };
+                if keep {
+                    *accepted = Some((self.current.clone(), matched));
+                }
+                *temperature *= *rate;
+                let from = &accepted.as_ref().expect("a proposal is accepted first").0;
+                self.current = from.iter().map(|&u| clamp(u + *step * rng.normal())).collect();
+                self.done = self.iteration >= *max_iterations;
+            }
+            Method::Genetic { population, fitness, individual, generation, max_generations, crossover, mutation } => {
+                fitness[*individual] = matched;
+                *individual += 1;
+                if *individual == population.len() {
+                    *generation += 1;
+                    *individual = 0;
+                    if *generation >= *max_generations {
+                        self.done = true;
+                        return;
+                    }
+                    // The best individual survives; the rest are bred
+                    let size = population.len();
+                    let best = (0..size).max_by(|&a, &b| fitness[a].total_cmp(&fitness[b])).unwrap_or(0);
+                    let pick = |rng: &mut Rng| {
+                        let (a, b) = (rng.below(size), rng.below(size));
+                        if fitness[a] >= fitness[b] { a } else { b }
+                    };
+                    let mut next = vec![population[best].clone()];
+                    while next.len() < size {
+                        let (a, b) = (pick(rng), pick(rng));
+                        let child = (0..n).map(|k| {
+                            let gene = match rng.uniform() < *crossover {
+                                true => population[b][k],
+                                false => population[a][k],
+                            };
+                            match rng.uniform() < *mutation {
+                                true => clamp(gene + 0.1 * rng.normal()),
+                                false => gene,
+                            }
+                        }).collect();
+                        next.push(child);
+                    }
+                    *population = next;
+                }
+                self.current = population[*individual].clone();
+            }
+        }
+    }
+}
+
+/// Scaled coordinates of grid point `point`
+fn grid(point: &[usize], divisions: usize) -> Vec<f64> {
+    point.iter().map(|&k| if divisions > 1 { k as f64 / (divisions - 1) as f64 } else { 0.5 }).collect()
+}
+
+/// Declare parameter `index` of the table `element` (`initparam`)
+pub fn init_param(element: &mut Element, index: usize, scale: f64, range: f64, center: f64, label: &str) -> Result<()> {
+    match scale as i32 {
+        0 if range >= 0.0 => {}
+        1 if range >= 1.0 && center != 0.0 => {}
+        _ => return Err(error(format!("bad range {} of type {} parameter {} of {}", range, scale, index, element.path))),
+    }
+    let count = element.get_param("num_params").unwrap_or(0.0) as usize;
+    for i in count..=index {
+        for field in ["type", "range", "center", "current", "best"] {
+            element.set_param(&format!("{}[{}]", field, i), 0.0);
+        }
+        element.strings.insert(format!("label[{}]", i), String::new());
+    }
+    element.set_param("num_params", count.max(index + 1) as f64);
+    element.set_param(&format!("type[{}]", index), scale);
+    element.set_param(&format!("range[{}]", index), range);
+    element.set_param(&format!("center[{}]", index), center);
+    element.set_param(&format!("current[{}]", index), center);
+    element.strings.insert(format!("label[{}]", index), label.to_string());
+    Ok(())
+}
+
+/// Show the state of the search at `path` in the fields of its table
+fn publish(sim: &mut GenesisSimulation, path: &str) {
+    let search = &sim.searches[path];
+    let current = search.current();
+    let best = search.best();
+    let (done, iteration) = (search.done, search.iteration);
+    let element = sim.elements.get_mut(path).unwrap();
+    for (i, value) in current.iter().enumerate() {
+        element.set_param(&format!("current[{}]", i), *value);
+    }
+    if let Some((values, matched)) = best {
+        for (i, value) in values.iter().enumerate() {
+            element.set_param(&format!("best[{}]", i), *value);
+        }
+        element.set_param("best_match", matched);
+    }
+    element.set_param("done", if done { 1.0 } else { 0.0 });
+    element.set_param("iteration_number", iteration as f64);
+    if let Method::Genetic { generation, .. } = &sim.searches[path].method {
+        let generation = *generation as f64;
+        sim.elements.get_mut(path).unwrap().set_param("generation", generation);
+    }
+}
+
+/// Start the search of the table at `path` from its fields (`initsearch`)
+pub fn start(sim: &mut GenesisSimulation, path: &str) -> Result<()> {
+    let element = sim.elements.get(path).ok_or_else(|| OldiesError::ModelNotFound(path.to_string()))?;
+    let field = |name: &str| element.get_param(name).unwrap_or(0.0);
+    let count = field("num_params") as usize;
+    if count == 0 {
+        return Err(error(format!("{} has no parameters (call {} initparam first)", path, path)));
+    }
+    let params = (0..count)
+        .map(|i| (field(&format!("type[{}]", i)), field(&format!("range[{}]", i)), field(&format!("center[{}]", i))))
+        .collect();
+    let whole = |name: &str| {
+        let x = field(name);
+        if x < 1.0 || x.fract() != 0.0 {
+            return Err(error(format!("{} of {} must be a positive whole number", name, path)));
+        }
+        Ok(x as usize)
+    };
+    let center = vec![0.5; count];
+    let (method, current) = match element.element_type {
+        ElementType::ParamTableBF => {
+            let divisions = whole("divisions")?;
+            let point = vec![0; count];
+            (Method::BruteForce { divisions, point: point.clone() }, grid(&point, divisions))
+        }
+        ElementType::ParamTableSA => (
+            Method::Annealing {
+                temperature: field("inittemp"),
+                rate: field("annealing_rate"),
+                step: field("step"),
+                max_iterations: whole("max_iterations")?,
+                accepted: None,
+            },
+            center,
+        ),
+        ElementType::ParamTableGA => {
+            let size = whole("num_individuals")?;
+            // The centers and random individuals
+            let mut population = vec![center.clone()];
+            while population.len() < size {
+                population.push((0..count).map(|_| sim.rng.uniform()).collect());
+            }
+            let method = Method::Genetic {
+                population,
+                fitness: vec![0.0; size],
+                individual: 0,
+                generation: 0,
+                max_generations: whole("max_generations")?,
+                crossover: field("crossover_probability"),
+                mutation: field("mutation_probability"),
+            };
+            (method, center)
+        }
+        _ => return Err(error(format!("{} is not a parameter table", path))),
+    };
+    let search = Search { params, method, current, best: None, iteration: 0, done: false };
+    sim.searches.insert(path.to_string(), search);
+    publish(sim, path);
+    Ok(())
+}
+
+/// Take the match in `current_match` of the table at `path` and propose
+/// the next values (`update_params`)
+pub fn update(sim: &mut GenesisSimulation, path: &str) -> Result<()> {
+    let matched = sim.get(path).and_then(|e| e.get_param("current_match")).unwrap_or(0.0);
+    let search = sim.searches.get_mut(path)
+        .ok_or_else(|| error(format!("{} has no search (call {} initsearch first)", path, path)))?;
+    search.update(matched, &mut sim.rng);
+    publish(sim, path);
+    Ok(())
+}
+
+/// Move the centers of the table at `path` to the best values found
+/// (`recenter`)
+pub fn recenter(sim: &mut GenesisSimulation, path: &str) -> Result<()> {
+    let (values, _) = sim.searches.get(path).and_then(Search::best)
+        .ok_or_else(|| error(format!("{} has no best values to recenter on", path)))?;
+    let element = sim.elements.get_mut(path).unwrap();
+    for (i, value) in values.into_iter().enumerate() {
+        element.set_param(&format!("center[{}]", i), value);
+    }
+    Ok(())
+}
+
+/// Run the search of the table at `path` to the end, evaluating each
+/// proposal with `fitness`. Returns the best values and match.
+pub fn search(
+    sim: &mut GenesisSimulation,
+    path: &str,
+    mut fitness: impl FnMut(&mut GenesisSimulation, &[f64]) -> Result<f64>,
+) -> Result<(Vec<f64>, f64)> {
+    start(sim, path)?;
+    while !sim.searches[path].done {
+        let current = sim.searches[path].current();
+        let matched = fitness(sim, &current)?;
+        sim.get_mut(path).expect("table checked").set_param("current_match", matched);
+        update(sim, path)?;
+    }
+    Ok(sim.searches[path].best().expect("every search evaluates a proposal"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{objects, sli::Sli};
+
+    #[test]
+    fn test_searches_find_the_optimum() {
+        let peak = |p: &[f64]| -(p[0] - 3.0).powi(2) - (p[1].log10() + 1.0).powi(2);
+        for (object, tolerance) in [("paramtableBF", 1e-12), ("paramtableSA", 0.05), ("paramtableGA", 0.05)] {
+            let mut sim = GenesisSimulation::new();
+            let table = objects::create(&mut sim, object, "/search").unwrap();
+            // x over 0..4, y over 0.01..1
+            init_param(table, 0, 0.0, 2.0, 2.0, "x").unwrap();
+            init_param(table, 1, 1.0, 10.0, 0.1, "y").unwrap();
+            let mut evaluations = 0;
+            let (best, matched) = search(&mut sim, "/search", |_, p| {
+                evaluations += 1;
+                Ok(peak(p))
+            }).unwrap();
+            assert!(matched > -tolerance, "{} {:?} {}", object, best, matched);
+            assert_eq!(sim.get("/search").unwrap().get_param("best_match"), Some(matched));
+            assert_eq!(sim.get("/search").unwrap().get_param("done"), Some(1.0));
+            match object {
+                "paramtableBF" => assert_eq!(evaluations, 25),
+                "paramtableSA" => assert_eq!(evaluations, 1000),
+                _ => assert_eq!(evaluations, 20 * 50),
+            }
+        }
+    }
+
+    #[test]
+    fn test_script_fits_a_conductance() {
+        // Find the leak conductance giving a steady potential of -60 mV
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create compartment /soma
+            setfield /soma Cm 1e-11 Em -0.07 initVm -0.07 inject 1e-11
+            setclock 0 1e-4
+            function fitness(table)
+                setfield /soma Rm {getfield {table} current[0]}
+                reset
+                step 0.1 -time
+                return -abs({getfield /soma Vm} + 0.06)
+            end
+            randseed 7
+            create paramtableSA /SA
+            setfield /SA max_iterations 150 step 0.2
+            call /SA initparamSA 0 1 10 1e8 Rm
+            call /SA search fitness
+            call /SA recenter
+        "#).unwrap();
+        let best: f64 = sli.call("getfield /SA best[0]").unwrap().parse().unwrap();
+        assert!((best - 1e9).abs() < 2e7, "{}", best);
+        assert_eq!(sli.call("getfield /SA center[0]").unwrap(), sli.call("getfield /SA best[0]").unwrap());
+        assert_eq!(sli.call("getfield /SA label[0]").unwrap(), "Rm");
+
+        // The same loop by hand, on a grid
+        sli.execute(r#"
+            create paramtableBF /BF
+            setfield /BF divisions 3
+            call /BF initparamBF 0 0 5e8 1e9
+            call /BF initsearch
+            int runs = 0
+            while (!{getfield /BF done})
+                setfield /BF current_match {fitness /BF}
+                call /BF update_params
+                runs = runs + 1
+            end
+            echo {runs} {getfield /BF best[0]}
+        "#).unwrap();
+        assert_eq!(sli.output, "3 1000000000\n");
+
+        for bad in ["call /BF initparamBF 0 1 0.5 1", "create paramtableGA /GA; call /GA initsearch", "call /GA update_params"] {
+            assert!(sli.execute(bad).is_err(), "{}", bad);
+        }
+    }
+}
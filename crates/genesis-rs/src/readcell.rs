@@ -0,0 +1,293 @@
+//! Cell parameter files (`readcell`)
+//!
+//! Nearly every published GENESIS model ships its morphology as a `.p`
+//! file, read with `readcell file.p /cell`. Each line names a compartment,
+//! its parent (`none` for the root, `.` for the previous line), the end
+//! point `x y z` and diameter `d` in um, and optionally channels with their
+//! densities:
+//!
+//! ```text
+//! *relative
+//! *set_global RM 0.33333
+//! soma  none  30  0  0  30  Na_squid_hh 1200  K_squid_hh 360
+//! dend  soma 100  0  0   2
+//! ```
+//!
+//! Compartments get `Rm = RM/area`, `Cm = CM*area`, `Ra = RA*len/xarea`
+//! (`8 RA/(pi d)` for spheres), `Em = ELEAK` (or `EREST_ACT`) and
+//! `initVm = EREST_ACT`, and are coupled by AXIAL/RAXIAL messages. Channels are copied from the prototypes
+//! `/library/<name>` into the compartment, given `Gbar = density*area`
+//! (or `-density` when negative, an absolute conductance; `gmax` for
+//! synchans) and connected by VOLTAGE and CHANNEL messages. Spikegens get
+//! the density as their threshold and the potential by INPUT.
+//!
+//! Options: `*relative` (the default) and `*absolute` coordinates,
+//! `*cartesian` and `*polar` (`r theta phi` in degrees), `*spherical` and
+//! `*cylindrical` compartments (a zero-length compartment is a sphere),
+//! `*set_global`/`*set_compt_param` of `RM`, `RA`, `CM`, `EREST_ACT` and
+//! `ELEAK`, `*compt` (prototype compartment), and `*symmetric`,
+//! `*asymmetric`, `*start_cell`, `*append_to_cell`, `*makeproto`, which are
+//! accepted and ignored. Units are SI.
+
+use crate::{objects, solver, ElementType, GenesisSimulation, LIBRARY};
+use oldies_core::{OldiesError, Result};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/// Options accepted without effect
+const IGNORED: &[&str] = &["*symmetric", "*asymmetric", "*start_cell", "*append_to_cell", "*makeproto"];
+
+fn parse_error(line: usize, msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::ParseError(format!("cell file line {}: {}", line, msg))
+}
+
+/// Read the cell parameter file `src` into the cell `cell`, returning the
+/// paths of its compartments in file order
+pub fn readcell(sim: &mut GenesisSimulation, src: &str, cell: &str) -> Result<Vec<String>> {
+    if !sim.exists(cell) {
+        objects::create(sim, "neutral", cell)?;
+    }
+    let mut globals: HashMap<&str, f64> =
+        HashMap::from([("RM", 0.33333), ("RA", 0.3), ("CM", 0.01), ("EREST_ACT", -0.07)]);
+    let (mut relative, mut polar, mut spherical) = (true, false, false);
+    let mut prototype: Option<String> = None;
+    // End points (m) of the compartments read so far, by name
+    let mut ends: HashMap<String, [f64; 3]> = HashMap::new();
+    let mut compartments: Vec<String> = vec![];
+    let mut previous: Option<String> = None;
+
+    for (number, text) in src.lines().enumerate() {
+        let line = number + 1;
+        let text = text.split("//").next().unwrap_or("");
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let Some(&first) = words.first() else { continue };
+        let value = |k: usize| -> Result<f64> {
+            let word = words.get(k).ok_or_else(|| parse_error(line, format!("{} needs a value", first)))?;
+            word.parse().map_err(|_| parse_error(line, format!("expected a number, got '{}'", word)))
+        };
+
+        if first.starts_with('*') {
+            match first {
+                "*relative" => relative = true,
+                "*absolute" => relative = false,
+                "*cartesian" => polar = false,
+                "*polar" => polar = true,
+                "*spherical" => spherical = true,
+                "*cylindrical" => spherical = false,
+                "*set_global" | "*set_compt_param" => {
+                    let name = *words.get(1).ok_or_else(|| parse_error(line, "expected a parameter name"))?;
+                    match name {
+                        "RM" | "RA" | "CM" | "EREST_ACT" | "ELEAK" => {
+                            globals.insert(name, value(2)?);
+                        }
+                        _ => return Err(parse_error(line, format!("unknown cell parameter {}", name))),
+                    }
+                }
+                "*compt" => {
+                    let path = *words.get(1).ok_or_else(|| parse_error(line, "*compt needs a prototype"))?;
+                    if sim.get(path).is_none() {
+                        return Err(OldiesError::ModelNotFound(path.to_string()));
+                    }
+                    prototype = Some(path.to_string());
+                }
+                _ if IGNORED.contains(&first) => {}
+                _ => return Err(parse_error(line, format!("unknown option {}", first))),
+            }
+            continue;
+        }
+
+        if words.len() < 6 || !words.len().is_multiple_of(2) {
+            return Err(parse_error(line, "expected name, parent, x, y, z, d and channel density pairs"));
+        }
+        let name = first;
+        let parent = match words[1] {
+            "none" | "nil" => None,
+            "." => Some(previous.clone().ok_or_else(|| parse_error(line, "no previous compartment"))?),
+            p => Some(p.to_string()),
+        };
+        let start = match &parent {
+            None => [0.0; 3],
+            Some(p) => *ends.get(p).ok_or_else(|| parse_error(line, format!("unknown parent {}", p)))?,
+        };
+        let (a, b, c) = (value(2)? * 1e-6, value(3)?, value(4)?);
+        let coords = if polar {
+            let (theta, phi) = (b.to_radians(), c.to_radians());
+            [a * phi.sin() * theta.cos(), a * phi.sin() * theta.sin(), a * phi.cos()]
+        } else {
+            [a, b * 1e-6, c * 1e-6]
+        };
+        let end = if relative { std::array::from_fn(|k| start[k] + coords[k]) } else { coords };
+        let len = (0..3).map(|k| (end[k] - start[k]).powi(2)).sum::<f64>().sqrt();
+        let dia = value(5)? * 1e-6;
+        if dia <= 0.0 {
+            return Err(parse_error(line, format!("compartment {} needs a positive diameter", name)));
+        }
+
+        let path = format!("{}/{}", cell.trim_end_matches('/'), name);
+        if sim.exists(&path) {
+            return Err(parse_error(line, format!("compartment {} already exists", path)));
+        }
+        match &prototype {
+            Some(proto) => {
+                let copy = sim.copy(proto, &path)?;
+                debug_assert_eq!(copy, path);
+            }
+            None => {
+                objects::compartment(sim, &path);
+            }
+        }
+        let area = if spherical || len == 0.0 { PI * dia * dia } else { PI * dia * len };
+        let xarea = PI * dia * dia / 4.0;
+        let erest = globals["EREST_ACT"];
+        let compartment = sim.get_mut(&path).unwrap();
+        for (field, x) in [
+            ("Rm", globals["RM"] / area),
+            ("Cm", globals["CM"] * area),
+            ("Ra", if spherical || len == 0.0 { 8.0 * globals["RA"] / (PI * dia) } else { globals["RA"] * len / xarea }),
+            ("Em", globals.get("ELEAK").copied().unwrap_or(erest)),
+            ("initVm", erest),
+            ("Vm", erest),
+            ("dia", dia),
+            ("len", len),
+            ("x", end[0]),
+            ("y", end[1]),
+            ("z", end[2]),
+        ] {
+            compartment.set_param(field, x);
+        }
+
+        if let Some(p) = &parent {
+            let parent_path = format!("{}/{}", cell.trim_end_matches('/'), p);
+            sim.add_message(&parent_path, "Vm", &path, "AXIAL", "AXIAL")?;
+            sim.add_message(&path, "Ra Vm", &parent_path, "RAXIAL", "RAXIAL")?;
+        }
+        for pair in words[6..].chunks(2) {
+            let density: f64 = pair[1].parse()
+                .map_err(|_| parse_error(line, format!("expected a density, got '{}'", pair[1])))?;
+            insert(sim, pair[0], &path, density, area)?;
+        }
+
+        ends.insert(name.to_string(), end);
+        compartments.push(path);
+        previous = Some(name.to_string());
+    }
+    Ok(compartments)
+}
+
+/// Copy the prototype `/library/<name>` into `compartment` and connect it
+fn insert(sim: &mut GenesisSimulation, name: &str, compartment: &str, density: f64, area: f64) -> Result<()> {
+    let proto = format!("{}/{}", LIBRARY, name);
+    let element = sim.get(&proto).ok_or_else(|| OldiesError::ModelNotFound(proto.clone()))?;
+    let conductance = match element.element_type {
+        _ if solver::is_channel(element) => Some("Gbar"),
+        ElementType::Synapse => Some("gmax"),
+        ElementType::SpikeGen => None,
+        _ => return Err(OldiesError::SimulationError(format!("readcell cannot insert {} into a compartment", proto))),
+    };
+    let path = sim.copy(&proto, compartment)?;
+    let Some(conductance) = conductance else {
+        // The density of a spikegen is its threshold
+        sim.get_mut(&path).unwrap().set_param("thresh", density);
+        return sim.add_message(compartment, "Vm", &path, "INPUT", "INPUT");
+    };
+    let g = if density < 0.0 { -density } else { density * area };
+    sim.get_mut(&path).unwrap().set_param(conductance, g);
+    sim.add_message(compartment, "Vm", &path, "VOLTAGE", "VOLTAGE")?;
+    sim.add_message(&path, "Gk Ek", compartment, "CHANNEL", "CHANNEL")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> GenesisSimulation {
+        let mut sim = GenesisSimulation::new();
+        objects::create(&mut sim, "neutral", "/library").unwrap();
+        objects::na_channel(&mut sim, "/library/Na_squid_hh");
+        objects::k_channel(&mut sim, "/library/K_squid_hh");
+        objects::synchan(&mut sim, "/library/syn");
+        objects::spikegen(&mut sim, "/library/spike");
+        sim
+    }
+
+    const CELL: &str = "
+        // soma and a two-compartment dendrite
+        *relative
+        *cartesian
+        *set_global RM 0.4
+        *set_global ELEAK -0.0594
+        soma  none   20  0  0  20  Na_squid_hh 1200  K_squid_hh 360  spike -0.02
+        d1    soma  100  0  0   2  syn 10
+        d2    .      50 50  0   1  K_squid_hh -1e-9
+    ";
+
+    #[test]
+    fn test_readcell() {
+        let mut sim = library();
+        let compartments = readcell(&mut sim, CELL, "/cell").unwrap();
+        assert_eq!(compartments, vec!["/cell/soma", "/cell/d1", "/cell/d2"]);
+
+        let soma = sim.get("/cell/soma").unwrap();
+        let area = PI * 20e-6 * 20e-6;
+        assert!((soma.get_param("Rm").unwrap() - 0.4 / area).abs() < 1e-3 * 0.4 / area);
+        assert!((soma.get_param("Cm").unwrap() - 0.01 * area).abs() < 1e-20);
+        assert_eq!(soma.get_param("initVm"), Some(-0.07));
+        assert_eq!(soma.get_param("Em"), Some(-0.0594));
+        assert_eq!(soma.children, vec!["/cell/soma/Na_squid_hh", "/cell/soma/K_squid_hh", "/cell/soma/spike"]);
+        let spike = sim.get("/cell/soma/spike").unwrap();
+        assert_eq!(spike.get_param("thresh"), Some(-0.02));
+        assert_eq!(spike.messages_in[0].msg_type, "INPUT");
+        let na = sim.get("/cell/soma/Na_squid_hh").unwrap();
+        assert!((na.get_param("Gbar").unwrap() - 1200.0 * area).abs() < 1e-15);
+        assert_eq!(na.messages_in[0].msg_type, "VOLTAGE");
+
+        let d1 = sim.get("/cell/d1").unwrap();
+        assert!((d1.get_param("len").unwrap() - 100e-6).abs() < 1e-12);
+        let xarea = PI * 1e-12;
+        assert!((d1.get_param("Ra").unwrap() - 0.3 * 100e-6 / xarea).abs() < 1.0);
+        assert_eq!(d1.messages_in[0].msg_type, "AXIAL");
+        assert_eq!(d1.messages_in[0].source, "/cell/soma");
+        let syn = sim.get("/cell/d1/syn").unwrap();
+        assert!((syn.get_param("gmax").unwrap() - 10.0 * PI * 2e-6 * 100e-6).abs() < 1e-18);
+
+        let d2 = sim.get("/cell/d2").unwrap();
+        assert!((d2.get_param("x").unwrap() - 170e-6).abs() < 1e-12);
+        assert!((d2.get_param("len").unwrap() - 50e-6 * 2f64.sqrt()).abs() < 1e-12);
+        assert_eq!(sim.get("/cell/d2/K_squid_hh").unwrap().get_param("Gbar"), Some(1e-9));
+
+        // The cell rests between EREST_ACT and the leak potential of the
+        // passive dendrite, and fires as one
+        sim.reset().unwrap();
+        sim.run(0.01).unwrap();
+        for path in &compartments {
+            let vm = sim.get(path).unwrap().get_param("Vm").unwrap();
+            assert!(vm > -0.0705 && vm < -0.0594, "{} {}", path, vm);
+        }
+        sim.get_mut("/cell/soma").unwrap().set_param("inject", 2e-10);
+        let mut peak = f64::MIN;
+        for _ in 0..2000 {
+            sim.step().unwrap();
+            peak = peak.max(sim.get("/cell/d1").unwrap().get_param("Vm").unwrap());
+        }
+        assert!(peak > 0.0, "{}", peak);
+    }
+
+    #[test]
+    fn test_readcell_errors() {
+        for bad in [
+            "soma none 10 0 0",
+            "soma none 10 0 0 0",
+            "d1 soma 10 0 0 1",
+            "soma none 10 0 0 10 CaT 1",
+            "*frobnicate",
+            "*set_global XX 1",
+            "soma none 10 0 0 10\nsoma none 10 0 0 10",
+        ] {
+            assert!(readcell(&mut library(), bad, "/cell").is_err(), "{}", bad);
+        }
+        let mut sim = library();
+        readcell(&mut sim, "*polar\n*absolute\nsoma none 10 90 90 10", "/c").unwrap();
+        assert!((sim.get("/c/soma").unwrap().get_param("y").unwrap() - 10e-6).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,259 @@
+//! Clock-based scheduling
+//!
+//! GENESIS has numbered clocks, each with its own time step
+//! (`setclock n dt`), and updates every element on one of them
+//! (`useclock path n`, clock 0 by default). The simulation advances by the
+//! smallest time step. On each step the clocks that are due, those last
+//! ticked a full time step of theirs ago, process their elements with their
+//! own time step. Clocks run in ascending number and the elements of a
+//! clock in the order of [`Stage`], so stimuli (see [`crate::devices`])
+//! are ready for everything else, pools see the fluxes of the present
+//! step and channel gates the potentials of the previous step as in
+//! GENESIS's default schedule. Channels, output
+//! elements and synapses can thus run at different rates than the
+//! compartments, as in the original models. Elements an `hsolve` element
+//! has taken over are left to it (see [`crate::hsolve`]), and elements of
+//! user-defined objects run after the calcium pools (see
+//! [`crate::custom`]).
+
+use crate::{concen, custom, devices, efield, hsolve, kinetics, output, solver, synapse, xodus, Element, ElementType, GenesisSimulation};
+use oldies_core::{Result, Time};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Kinds of element processed at a clock tick, in processing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Pulse, spike and noise generators
+    Devices,
+    /// Fluxes of reactions and enzymes
+    Reactions,
+    /// Molecules in pools
+    Pools,
+    /// Spike generators
+    Spikes,
+    /// Synaptic conductances
+    Synapses,
+    /// Channel gates and conductances
+    Channels,
+    /// Calcium pools
+    Concentrations,
+    /// Elements of user-defined objects
+    Custom,
+    /// Cells integrated by `hsolve` elements
+    Solvers,
+    /// Membrane potentials, in one implicit solve per clock
+    Compartments,
+    /// Extracellular potentials of the membrane currents
+    Fields,
+    /// Output to files, once the step is complete
+    Output,
+    /// Xodus displays, recorded once the step is complete
+    Display,
+}
+
+impl Stage {
+    /// Stage `element` is processed in, if it has one
+    pub fn of(element: &Element) -> Option<Stage> {
+        match element.element_type {
+            ElementType::Compartment => Some(Stage::Compartments),
+            ElementType::Reaction | ElementType::Enzyme => Some(Stage::Reactions),
+            ElementType::Pool => Some(Stage::Pools),
+            ElementType::SpikeGen => Some(Stage::Spikes),
+            ElementType::Synapse => Some(Stage::Synapses),
+            ElementType::CaConcen => Some(Stage::Concentrations),
+            ElementType::HSolve => Some(Stage::Solvers),
+            ElementType::EField => Some(Stage::Fields),
+            ElementType::Custom(_) => Some(Stage::Custom),
+            _ if devices::is_device(element) => Some(Stage::Devices),
+            _ if output::is_output(element) => Some(Stage::Output),
+            _ if xodus::is_display(element) => Some(Stage::Display),
+            _ if solver::is_channel(element) => Some(Stage::Channels),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Devices => "devices",
+            Stage::Reactions => "reactions",
+            Stage::Pools => "pools",
+            Stage::Spikes => "spikegens",
+            Stage::Synapses => "synchans",
+            Stage::Channels => "channels",
+            Stage::Concentrations => "Ca pools",
+            Stage::Custom => "user objects",
+            Stage::Solvers => "hsolve",
+            Stage::Compartments => "compartments",
+            Stage::Fields => "efields",
+            Stage::Output => "outputs",
+            Stage::Display => "displays",
+        }
+    }
+}
+
+/// Elements processed together at a tick of a clock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Task {
+    pub clock: usize,
+    pub dt: Time,
+    pub stage: Stage,
+    /// Paths of the elements, sorted
+    pub elements: Vec<String>,
+}
+
+/// Tasks of a tick of every clock, in processing order
+pub fn schedule(sim: &GenesisSimulation) -> Vec<Task> {
+    let mut tasks: BTreeMap<(usize, Stage), Vec<String>> = BTreeMap::new();
+    let solved = sim.solved();
+    for path in sim.paths() {
+        let element = &sim.elements[&path];
+        if !sim.is_simulated(&path) || solved.contains(path.as_str()) {
+            continue;
+        }
+        if let Some(stage) = Stage::of(element) {
+            tasks.entry((element.clock, stage)).or_default().push(path);
+        }
+    }
+    tasks.into_iter()
+        .map(|((clock, stage), elements)| Task {
+            clock,
+            dt: sim.clocks.get(&clock).copied().unwrap_or(sim.dt),
+            stage,
+            elements,
+        })
+        .collect()
+}
+
+/// Advance the simulation by one step of the smallest clock
+pub(crate) fn step(sim: &mut GenesisSimulation) -> Result<()> {
+    // A clock is due when its next tick falls within this step
+    let horizon = sim.time + 0.5 * sim.dt;
+    let due: Vec<usize> = sim.clocks.keys()
+        .copied()
+        .filter(|n| sim.next_tick.get(n).is_none_or(|&t| t <= horizon))
+        .collect();
+    // The schedule is kept until the elements or clocks change
+    let tasks = sim.tasks.take().unwrap_or_else(|| schedule(sim));
+    let mut channels = vec![];
+    let mut outputs = vec![];
+    let mut displays = vec![];
+    for task in &tasks {
+        if !due.contains(&task.clock) {
+            continue;
+        }
+        match task.stage {
+            Stage::Devices => devices::step(sim, &task.elements, task.dt)?,
+            Stage::Reactions => kinetics::step_reactions(sim, &task.elements, task.dt)?,
+            Stage::Pools => kinetics::step_pools(sim, &task.elements, task.dt)?,
+            Stage::Spikes => synapse::step_spikegens(sim, &task.elements, task.dt)?,
+            Stage::Synapses => {
+                synapse::step_synchans(sim, &task.elements, task.dt)?;
+                channels.extend(task.elements.iter().cloned());
+            }
+            Stage::Channels => {
+                solver::step_channels(sim, &task.elements, task.dt)?;
+                channels.extend(task.elements.iter().cloned());
+            }
+            Stage::Concentrations => concen::step(sim, &task.elements, task.dt)?,
+            Stage::Custom => custom::step(sim, &task.elements, task.dt)?,
+            Stage::Solvers => hsolve::step(sim, &task.elements, task.dt)?,
+            Stage::Compartments => solver::step_compartments(sim, &task.elements, task.dt)?,
+            Stage::Fields => efield::step(sim, &task.elements)?,
+            Stage::Output => outputs.extend(task.elements.iter().cloned()),
+            Stage::Display => displays.extend(task.elements.iter().cloned()),
+        }
+    }
+    sim.tasks = Some(tasks);
+    solver::update_currents(sim, &channels)?;
+    output::write(sim, &outputs, sim.time + sim.dt)?;
+    xodus::record(sim, &displays, sim.time + sim.dt)?;
+    for n in due {
+        let dt = sim.clocks[&n];
+        let next = sim.next_tick.get(&n).copied().unwrap_or(sim.time) + dt;
+        sim.next_tick.insert(n, next);
+    }
+    Ok(())
+}
+
+/// The clocks and their time steps, as `showclocks` prints them
+pub fn show_clocks(sim: &GenesisSimulation) -> String {
+    let mut text = String::new();
+    for (n, dt) in &sim.clocks {
+        let _ = writeln!(text, "clock {}: dt = {}", n, dt);
+    }
+    text
+}
+
+/// The schedule, as `showsched` prints it
+pub fn show_schedule(sim: &GenesisSimulation) -> String {
+    let mut text = String::new();
+    for task in schedule(sim) {
+        let _ = writeln!(
+            text, "clock {} (dt = {}): {} {}", task.clock, task.dt, task.elements.len(), task.stage.name()
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    const CELLS: &str = r#"
+        create compartment /fast
+        create compartment /slow
+        setfield /fast Rm 1e8 Cm 1e-11 Em 0 initVm -0.07
+        setfield /slow Rm 1e8 Cm 1e-11 Em 0 initVm -0.07
+        create K_squid_hh /slow/K
+        addmsg /slow /slow/K VOLTAGE Vm
+        setclock 0 1e-5
+        setclock 1 1e-4
+        useclock /slow 1
+        useclock /slow/K 1
+        reset
+    "#;
+
+    #[test]
+    fn test_schedule_order() {
+        let mut sli = Sli::new();
+        sli.execute(CELLS).unwrap();
+        let tasks = schedule(&sli.sim);
+        let order: Vec<(usize, Stage, usize)> = tasks.iter().map(|t| (t.clock, t.stage, t.elements.len())).collect();
+        assert_eq!(order, vec![(0, Stage::Compartments, 1), (1, Stage::Channels, 1), (1, Stage::Compartments, 1)]);
+        assert_eq!(tasks[2].dt, 1e-4);
+
+        sli.execute("showclocks; showsched").unwrap();
+        assert_eq!(sli.output, "clock 0: dt = 0.00001\nclock 1: dt = 0.0001\n\
+            clock 0 (dt = 0.00001): 1 compartments\nclock 1 (dt = 0.0001): 1 channels\n\
+            clock 1 (dt = 0.0001): 1 compartments\n");
+    }
+
+    #[test]
+    fn test_clocks_tick_at_their_rate() {
+        let mut sli = Sli::new();
+        sli.execute(CELLS).unwrap();
+        let vm = |sli: &Sli, p: &str| sli.sim.get(p).unwrap().get_param("Vm").unwrap();
+        let k = |sli: &Sli| sli.sim.get("/slow/K").unwrap().get_param("X").unwrap();
+
+        // The slow clock ticks at once, then after ten fast steps
+        sli.execute("step").unwrap();
+        let (slow, gate) = (vm(&sli, "/slow"), k(&sli));
+        assert!(slow > -0.07);
+        sli.execute("step 9").unwrap();
+        assert_eq!((vm(&sli, "/slow"), k(&sli)), (slow, gate));
+        sli.execute("step").unwrap();
+        assert!(vm(&sli, "/slow") > slow && k(&sli) > gate);
+
+        // Each tick is one Crank-Nicolson step of the clock's own dt
+        let tau = 1e8 * 1e-11;
+        let factor = |dt: f64| (1.0 - dt / (2.0 * tau)) / (1.0 + dt / (2.0 * tau));
+        assert!((slow + 0.07 * factor(1e-4)).abs() < 1e-12);
+        assert!((vm(&sli, "/fast") + 0.07 * factor(1e-5).powi(11)).abs() < 1e-12);
+
+        // Reset restarts the clocks
+        sli.execute("reset; step").unwrap();
+        assert!((vm(&sli, "/slow") - slow).abs() < 1e-15);
+    }
+}
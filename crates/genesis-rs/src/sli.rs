@@ -0,0 +1,1720 @@
+//! SLI interpreter
+//!
+//! Executes GENESIS scripts against a [`GenesisSimulation`], so existing
+//! `.g` files can build and run models. SLI is command oriented: every
+//! statement is a command name followed by words, so scripts are split
+//! into statements and words by hand rather than parsed with the
+//! [`crate::SliParser`] grammar.
+//!
+//! Commands:
+//!
+//! - `create object path [-field value ...]`, with the objects of
+//!   [`objects::create`]
+//! - `setfield [path] field value ...` and `getfield [path] field`, where
+//!   fields may be table entries (`X_A->table[3]`) or text (`filename`),
+//!   `showfield [path] [field ... | *]` and `addfield [path] field`,
+//!   adding a numeric field to an element. Only fields an element has can
+//!   be set (see [`crate::Element::fields`]).
+//! - `addmsg source dest TYPE [fields ...]`, see [`crate::messages`]
+//! - `copy source dest`, copying the element tree and its internal messages
+//! - `disable path` and `enable path`, leaving an element tree out of the
+//!   simulation or back in (prototypes under `/library` start disabled)
+//! - `readcell file.p path`, see [`crate::readcell`]
+//! - `getsyncount synchan`, see [`crate::synapse`]
+//! - `setupalpha`, `setuptau`, `tweakalpha`, `tweaktau` and
+//!   `call chan TABCREATE gate xdivs xmin xmax`, see [`crate::tabchannel`]
+//! - `call solver SETUP`, see [`crate::hsolve`]
+//! - `call table initparamBF|initparamSA|initparamGA`, `initsearch`,
+//!   `update_params`, `search function` and `recenter`, see
+//!   [`crate::paramsearch`]
+//! - `randseed [seed]` and `rand low high`
+//! - Xodus widgets, `PLOT` and `PLOTSCALE` messages and `xshow`, `xhide`,
+//!   `xupdate`, `xflushevents` and `xcolorscale`, recorded or ignored
+//!   without a display, see [`crate::xodus`]
+//! - `setclock n dt`, `useclock path n`, `showclocks` and `showsched`, see
+//!   [`crate::schedule`]
+//! - `reset`, `step [n]` and `step time -time`; `reset` prints a warning
+//!   for each field whose value looks like it is in the wrong units
+//! - `paron [-nodes n] [-sync time]`, running every `step` split into `n`
+//!   domains meeting every `sync` (every step by default), `paroff` and
+//!   `nnodes`, see [`crate::parallel`]
+//! - `units [SI | physiological]`, the units of the values of fields,
+//!   `setclock` and `step -time` (SI by default), see [`crate::units`]
+//! - `dialect [genesis | moose]`, taking the class, field and message
+//!   names and commands of MOOSE scripts and reporting them in
+//!   [`Sli::report`], see [`crate::moose`]
+//! - `ce path`, `pwe`, `el path`, listing the elements a path names, and
+//!   `echo words ...`
+//! - `exp`, `log`, `sqrt`, `sin`, `cos`, `tan`, `abs`, `trunc`, `round`,
+//!   `pow`, `min`, `max`, `strcat`, `strlen`, `substring s start [end]`,
+//!   `strcmp` and `exists path`
+//!
+//! The language around them:
+//!
+//! - `int`, `float` and `str` variables (`float x = 1, y`), assigned with
+//!   `x = expression`
+//! - `function name(a, b) ... end`, where `str a` or `float b` in the body
+//!   give the parameters types, and `return [expression]`
+//! - `if (...) ... elif (...) ... else ... end`, `while (...) ... end`,
+//!   `for (i = 0; i < n; i = i + 1) ... end` and
+//!   `foreach name (words ...) ... end`
+//! - expressions with numbers, `"strings"`, variables, `+ - * / % **`,
+//!   comparisons, `&& || !`, `@` to concatenate strings, and calls
+//!   `name(args)` of commands and functions
+//! - `{}` blocks in words, replaced by the value of the expression they
+//!   hold (`setfield {path}/soma Rm {RM / area}`) or the result of the
+//!   command they run (`{getfield /soma Vm}`); the command form is used
+//!   when the block starts with a name that is not a variable
+//!
+//! Paths are absolute or relative to the working element (`ce`), with `.`
+//! and `..`, or start at `^`, the element last made by `create` or `copy`. `setfield`, `showfield`, `addmsg`, `useclock`, `disable`,
+//! `enable` and the table commands take wildcard paths naming several
+//! elements (see [`crate::wildcard`]). Statements end at a newline or `;`
+//! outside brackets, a trailing `\` continues a line and `//` and `/* */`
+//! start comments. Text printed by `echo` is collected in [`Sli::output`],
+//! as are `showfield`, `showclocks` and `showsched`.
+
+use crate::moose::{self, Dialect};
+use crate::parallel::{self, Nodes};
+use crate::units::{self, Quantity, UnitSystem};
+use crate::{hsolve, objects, paramsearch, parent_path, xodus, Element, readcell, schedule, synapse, tabchannel, wildcard, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Rng};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+fn parse_error(line: usize, msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::ParseError(format!("line {}: {}", line, msg))
+}
+
+fn runtime_error(line: usize, msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::SimulationError(format!("SLI line {}: {}", line, msg))
+}
+
+/// Statements of a script, as text with the line each starts on. Newlines
+/// and `;` inside quotes, `()` and `{}` do not end a statement.
+fn split(src: &str) -> Result<Vec<(usize, String)>> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut statements = vec![];
+    let mut text = String::new();
+    // Open brackets, `(` and `{`
+    let mut depth = 0usize;
+    let mut line = 1;
+    let mut start = 1;
+    let mut i = 0;
+
+    fn end_statement(statements: &mut Vec<(usize, String)>, text: &mut String, start: usize) {
+        let statement = std::mem::take(text);
+        if !statement.trim().is_empty() {
+            statements.push((start, statement.trim().to_string()));
+        }
+    }
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let word_start = text.chars().last().is_none_or(char::is_whitespace);
+        match c {
+            '\\' if next == Some('\n') => {
+                text.push(' ');
+                line += 1;
+                i += 2;
+            }
+            '\n' | ';' if depth == 0 => {
+                end_statement(&mut statements, &mut text, start);
+                if c == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            '/' if next == Some('/') && word_start => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if next == Some('*') && word_start => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(parse_error(line, "unterminated comment"));
+                }
+                text.push(' ');
+                i += 2;
+            }
+            _ => {
+                if text.trim().is_empty() && !c.is_whitespace() {
+                    start = line;
+                }
+                match c {
+                    '"' => {
+                        let end = chars[i + 1..].iter().position(|&c| c == '"' || c == '\n')
+                            .map(|k| i + 1 + k)
+                            .filter(|&k| chars[k] == '"')
+                            .ok_or_else(|| parse_error(line, "unterminated string"))?;
+                        text.extend(&chars[i..=end]);
+                        i = end + 1;
+                        continue;
+                    }
+                    '(' | '{' => depth += 1,
+                    ')' | '}' => {
+                        depth = depth.checked_sub(1).ok_or_else(|| parse_error(line, format!("unmatched {}", c)))?;
+                    }
+                    '\n' => line += 1,
+                    _ => {}
+                }
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    if depth > 0 {
+        return Err(parse_error(start, "unclosed bracket"));
+    }
+    end_statement(&mut statements, &mut text, start);
+    Ok(statements)
+}
+
+/// Index of the bracket closing the one at `open`
+fn closing(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quoted = false;
+    for (i, &c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '"' => quoted = !quoted,
+            '{' | '(' if !quoted => depth += 1,
+            '}' | ')' if !quoted => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Part of a word: literal text, or a `{}` block evaluated when the
+/// statement runs
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Block(String),
+}
+
+type Word = Vec<Part>;
+
+/// Words of a command. Quotes group text into a word and blocks may sit
+/// inside words, as in `{cell}/soma`.
+fn words(line: usize, text: &str) -> Result<Vec<Word>> {
+    fn push_text(word: &mut Word, text: &str) {
+        match word.last_mut() {
+            Some(Part::Text(t)) => t.push_str(text),
+            _ => word.push(Part::Text(text.to_string())),
+        }
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = vec![];
+    let mut word: Word = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                i += 1;
+            }
+            '"' => {
+                let end = chars[i + 1..].iter().position(|&c| c == '"')
+                    .ok_or_else(|| parse_error(line, "unterminated string"))?;
+                let quoted: String = chars[i + 1..i + 1 + end].iter().collect();
+                push_text(&mut word, &quoted);
+                i += end + 2;
+            }
+            '{' => {
+                let end = closing(&chars, i).ok_or_else(|| parse_error(line, "unclosed {"))?;
+                word.push(Part::Block(chars[i + 1..end].iter().collect()));
+                i = end + 1;
+            }
+            c => {
+                push_text(&mut word, c.encode_utf8(&mut [0; 4]));
+                i += 1;
+            }
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Split `text` at `sep` outside quotes and brackets
+fn split_top(text: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let (mut depth, mut quoted) = (0i32, false);
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '{' | '(' if !quoted => depth += 1,
+            '}' | ')' if !quoted => depth -= 1,
+            _ if c == sep && depth == 0 && !quoted => {
+                parts.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        parts.last_mut().unwrap().push(c);
+    }
+    parts.into_iter().map(|p| p.trim().to_string()).collect()
+}
+
+/// Leading identifier of `text` and the rest, trimmed
+fn keyword(text: &str) -> (&str, &str) {
+    let end = text.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+/// `(inner)` without its brackets
+fn parenthesized(line: usize, text: &str) -> Result<&str> {
+    text.strip_prefix('(')
+        .and_then(|t| t.strip_suffix(')'))
+        .ok_or_else(|| parse_error(line, format!("expected (...), got '{}'", text)))
+}
+
+/// Types of SLI variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Int,
+    Float,
+    Str,
+}
+
+/// Value of a variable or an expression
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    fn number(&self, line: usize) -> Result<f64> {
+        match self {
+            Value::Num(x) => Ok(*x),
+            Value::Str(s) => number(line, s.trim()),
+        }
+    }
+
+    fn truth(&self) -> bool {
+        match self {
+            Value::Num(x) => *x != 0.0,
+            Value::Str(s) => s.trim().parse::<f64>().map_or(!s.is_empty(), |x| x != 0.0),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Num(x) => f.write_str(&format_number(*x)),
+            Value::Str(s) => f.write_str(s),
+        }
+    }
+}
+
+impl Kind {
+    fn default_value(self) -> Value {
+        match self {
+            Kind::Str => Value::Str(String::new()),
+            _ => Value::Num(0.0),
+        }
+    }
+
+    fn coerce(self, line: usize, value: Value) -> Result<Value> {
+        Ok(match self {
+            Kind::Str => Value::Str(value.to_string()),
+            Kind::Float => Value::Num(value.number(line)?),
+            Kind::Int => Value::Num(value.number(line)?.trunc()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Variable {
+    kind: Kind,
+    value: Value,
+}
+
+/// A parsed statement. Expressions are kept as text and evaluated when
+/// the statement runs.
+#[derive(Debug, Clone)]
+enum Stmt {
+    Command(usize, Vec<Word>),
+    Declare(usize, Kind, Vec<(String, Option<String>)>),
+    Assign(usize, String, String),
+    /// Conditions with their bodies, then the `else` body
+    If(Vec<(usize, String, Vec<Stmt>)>, Vec<Stmt>),
+    While(usize, String, Vec<Stmt>),
+    For(usize, Box<Stmt>, String, Box<Stmt>, Vec<Stmt>),
+    Foreach(usize, String, String, Vec<Stmt>),
+    Function(String, Rc<Function>),
+    Return(usize, Option<String>),
+}
+
+/// A function defined with `function name(params) ... end`
+#[derive(Debug)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+/// How a statement finished
+enum Flow {
+    /// On to the next statement, with the result of a command
+    Normal(String),
+    /// `return`ed from a function
+    Return(Value),
+}
+
+/// Builds the statements of a script, matching blocks with their `end`
+struct Parser {
+    statements: Vec<(usize, String)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn parse(src: &str) -> Result<Vec<Stmt>> {
+        let mut parser = Parser { statements: split(src)?, pos: 0 };
+        let mut program = vec![];
+        while let Some((line, text)) = parser.next() {
+            program.push(parser.statement(line, &text)?);
+        }
+        Ok(program)
+    }
+
+    fn next(&mut self) -> Option<(usize, String)> {
+        let statement = self.statements.get(self.pos).cloned();
+        self.pos += 1;
+        statement
+    }
+
+    /// Statements of the block opened on line `opened`, up to one starting
+    /// with a keyword of `ends`, which is returned with its line and rest
+    fn block(&mut self, opened: usize, ends: &[&str]) -> Result<(Vec<Stmt>, (usize, String, String))> {
+        let mut body = vec![];
+        while let Some((line, text)) = self.next() {
+            let (key, rest) = keyword(&text);
+            if ends.contains(&key) {
+                return Ok((body, (line, key.to_string(), rest.to_string())));
+            }
+            body.push(self.statement(line, &text)?);
+        }
+        Err(parse_error(opened, "block without end"))
+    }
+
+    fn statement(&mut self, line: usize, text: &str) -> Result<Stmt> {
+        let (key, rest) = keyword(text);
+        Ok(match key {
+            "if" => {
+                let mut branches = vec![];
+                let mut condition = (line, rest.to_string());
+                loop {
+                    let (body, (next, end, rest)) = self.block(line, &["elif", "else", "end"])?;
+                    branches.push((condition.0, condition.1, body));
+                    match end.as_str() {
+                        "elif" => condition = (next, rest),
+                        "else" => break Stmt::If(branches, self.block(line, &["end"])?.0),
+                        _ => break Stmt::If(branches, vec![]),
+                    }
+                }
+            }
+            "while" => Stmt::While(line, rest.to_string(), self.block(line, &["end"])?.0),
+            "for" => {
+                let header = split_top(parenthesized(line, rest)?, ';');
+                let [init, condition, update] = header.as_slice() else {
+                    return Err(parse_error(line, "for needs (init; condition; update)"));
+                };
+                let (init, update) = (self.simple(line, init)?, self.simple(line, update)?);
+                let body = self.block(line, &["end"])?.0;
+                Stmt::For(line, Box::new(init), condition.clone(), Box::new(update), body)
+            }
+            "foreach" => {
+                let (name, list) = keyword(rest);
+                let list = parenthesized(line, list)?.to_string();
+                Stmt::Foreach(line, name.to_string(), list, self.block(line, &["end"])?.0)
+            }
+            "function" => {
+                let (name, params) = keyword(rest);
+                if name.is_empty() {
+                    return Err(parse_error(line, "function needs a name"));
+                }
+                let params = match params {
+                    "" => vec![],
+                    _ => split_top(parenthesized(line, params)?, ',').into_iter().filter(|p| !p.is_empty()).collect(),
+                };
+                let body = self.block(line, &["end"])?.0;
+                Stmt::Function(name.to_string(), Rc::new(Function { params, body }))
+            }
+            "return" => Stmt::Return(line, (!rest.is_empty()).then(|| rest.to_string())),
+            "end" | "else" | "elif" => return Err(parse_error(line, format!("{} outside a block", key))),
+            "int" | "float" | "str" if !rest.is_empty() && !rest.starts_with('=') => {
+                let kind = match key {
+                    "int" => Kind::Int,
+                    "float" => Kind::Float,
+                    _ => Kind::Str,
+                };
+                let names = split_top(rest, ',').iter()
+                    .map(|item| match keyword(item) {
+                        (name, "") if !name.is_empty() => Ok((name.to_string(), None)),
+                        (name, init) if !name.is_empty() && init.starts_with('=') => {
+                            Ok((name.to_string(), Some(init[1..].trim().to_string())))
+                        }
+                        _ => Err(parse_error(line, format!("bad declaration '{}'", item))),
+                    })
+                    .collect::<Result<_>>()?;
+                Stmt::Declare(line, kind, names)
+            }
+            _ => self.simple(line, text)?,
+        })
+    }
+
+    /// An assignment `name = expression` or a command
+    fn simple(&self, line: usize, text: &str) -> Result<Stmt> {
+        match keyword(text) {
+            (name, rest) if !name.is_empty() && rest.starts_with('=') && !rest.starts_with("==") => {
+                Ok(Stmt::Assign(line, name.to_string(), rest[1..].trim().to_string()))
+            }
+            _ => Ok(Stmt::Command(line, words(line, text)?)),
+        }
+    }
+}
+
+/// Tokens of expressions
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    Block(String),
+    Op(&'static str),
+    Open,
+    Close,
+    Comma,
+}
+
+/// Operators, longest first
+const OPERATORS: [&str; 16] = ["**", "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "@"];
+
+/// Binding strength of binary operators
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "==" | "!=" => 3,
+        "<" | ">" | "<=" | ">=" => 4,
+        "@" => 5,
+        "+" | "-" => 6,
+        "*" | "/" | "%" => 7,
+        "**" => 9,
+        _ => return None,
+    })
+}
+
+fn tokens(line: usize, text: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if matches!(chars.get(i), Some('e' | 'E')) {
+                let mut j = i + 1;
+                if matches!(chars.get(j), Some('+' | '-')) {
+                    j += 1;
+                }
+                if chars.get(j).is_some_and(char::is_ascii_digit) {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let word: String = chars[start..i].iter().collect();
+            let x = word.parse().map_err(|_| parse_error(line, format!("bad number {}", word)))?;
+            tokens.push(Token::Num(x));
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            let end = chars[i + 1..].iter().position(|&c| c == '"')
+                .ok_or_else(|| parse_error(line, "unterminated string"))?;
+            tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else if c == '{' {
+            let end = closing(&chars, i).ok_or_else(|| parse_error(line, "unclosed {"))?;
+            tokens.push(Token::Block(chars[i + 1..end].iter().collect()));
+            i = end + 1;
+        } else if let Some(token) = match c {
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            ',' => Some(Token::Comma),
+            _ => None,
+        } {
+            tokens.push(token);
+            i += 1;
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            let op = OPERATORS.iter().find(|op| rest.starts_with(*op))
+                .ok_or_else(|| parse_error(line, format!("unexpected '{}' in expression", c)))?;
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Result of the binary operator `op`
+fn apply(line: usize, op: &str, a: Value, b: Value) -> Result<Value> {
+    let compare = |a: &Value, b: &Value| match (a.number(line), b.number(line)) {
+        (Ok(x), Ok(y)) => x.partial_cmp(&y),
+        _ => Some(a.to_string().cmp(&b.to_string())),
+    };
+    let truth = |x: bool| Value::Num(if x { 1.0 } else { 0.0 });
+    Ok(match op {
+        "@" => Value::Str(format!("{}{}", a, b)),
+        "&&" => truth(a.truth() && b.truth()),
+        "||" => truth(a.truth() || b.truth()),
+        "==" => truth(compare(&a, &b) == Some(Ordering::Equal)),
+        "!=" => truth(compare(&a, &b) != Some(Ordering::Equal)),
+        "<" => truth(compare(&a, &b) == Some(Ordering::Less)),
+        ">" => truth(compare(&a, &b) == Some(Ordering::Greater)),
+        "<=" => truth(matches!(compare(&a, &b), Some(Ordering::Less | Ordering::Equal))),
+        ">=" => truth(matches!(compare(&a, &b), Some(Ordering::Greater | Ordering::Equal))),
+        _ => {
+            let (x, y) = (a.number(line)?, b.number(line)?);
+            Value::Num(match op {
+                "+" => x + y,
+                "-" => x - y,
+                "*" => x * y,
+                "/" => x / y,
+                "%" => x % y,
+                _ => x.powf(y),
+            })
+        }
+    })
+}
+
+fn number(line: usize, word: &str) -> Result<f64> {
+    word.parse().map_err(|_| runtime_error(line, format!("expected a number, got '{}'", word)))
+}
+
+fn index(line: usize, word: &str) -> Result<usize> {
+    word.parse().map_err(|_| runtime_error(line, format!("expected a whole number, got '{}'", word)))
+}
+
+/// Values to give `fields` of `element`: a number, in SI units when given
+/// in `system`, or `None` for a text field taking the word. Xodus elements
+/// take fields they lack as text.
+fn field_values<'a>(
+    line: usize,
+    element: &Element,
+    fields: &[(&'a str, &'a str)],
+    system: UnitSystem,
+) -> Result<Vec<(&'a str, Option<f64>, &'a str)>> {
+    fields.iter()
+        .map(|&(field, word)| {
+            let text = element.get_text(field).is_some() || (xodus::is_xodus(element) && !element.has_field(field));
+            if !text && !element.has_field(field) {
+                return Err(runtime_error(line, format!("{} has no field {}", element.path, field)));
+            }
+            if text {
+                return Ok((field, None, word));
+            }
+            let value = number(line, word)?;
+            Ok((field, Some(units::quantity(element, field).map_or(value, |q| system.to_si(q, value))), word))
+        })
+        .collect()
+}
+
+/// Set fields to the values of [`field_values`]
+fn set_fields(element: &mut Element, values: Vec<(&str, Option<f64>, &str)>) -> Result<()> {
+    for (field, value, word) in values {
+        match value {
+            Some(value) => element.set_field(field, value)?,
+            None => {
+                element.strings.insert(field.to_string(), word.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Number as SLI prints it: exponent notation for very small and large
+/// magnitudes
+pub(crate) fn format_number(x: f64) -> String {
+    if x != 0.0 && (x.abs() < 1e-4 || x.abs() >= 1e15) {
+        format!("{:e}", x)
+    } else {
+        x.to_string()
+    }
+}
+
+/// Deepest nesting of function calls
+const MAX_DEPTH: usize = 256;
+
+/// SLI interpreter state
+pub struct Sli {
+    /// Simulation built by the script
+    pub sim: GenesisSimulation,
+    /// Text printed by `echo`
+    pub output: String,
+    /// Working element
+    cwe: String,
+    /// Element last made by `create` or `copy` (`^`)
+    last: Option<String>,
+    /// Variables declared outside functions
+    globals: HashMap<String, Variable>,
+    /// Variables of the functions being run, innermost last
+    frames: Vec<HashMap<String, Variable>>,
+    functions: HashMap<String, Rc<Function>>,
+    /// Units field values, clock steps and times are given in (`units`)
+    pub units: UnitSystem,
+    /// Domains steps are run in (`paron`), one when `None`
+    pub nodes: Option<Nodes>,
+    /// Dialect of the scripts run (`dialect`)
+    pub dialect: Dialect,
+    /// MOOSE constructs met in MOOSE mode, with their GENESIS forms
+    pub report: Vec<String>,
+}
+
+impl Default for Sli {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sli {
+    pub fn new() -> Self {
+        Self {
+            sim: GenesisSimulation::new(),
+            output: String::new(),
+            cwe: "/".into(),
+            last: None,
+            globals: HashMap::new(),
+            frames: vec![],
+            functions: HashMap::new(),
+            units: UnitSystem::SI,
+            nodes: None,
+            dialect: Dialect::Genesis,
+            report: vec![],
+        }
+    }
+
+    /// Run a script
+    pub fn execute(&mut self, src: &str) -> Result<()> {
+        self.call(src).map(|_| ())
+    }
+
+    /// Run a command and return its result (`getfield`, `pwe`, ...), or
+    /// the result of the last command of several
+    pub fn call(&mut self, src: &str) -> Result<String> {
+        match self.run(&Parser::parse(src)?)? {
+            Flow::Normal(result) => Ok(result),
+            Flow::Return(value) => Ok(value.to_string()),
+        }
+    }
+
+    /// Value of the variable `name`, printed as `echo {name}` would
+    pub fn variable(&self, name: &str) -> Option<String> {
+        self.lookup(name).map(|v| v.value.to_string())
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.frames.last().and_then(|f| f.get(name)).or_else(|| self.globals.get(name))
+    }
+
+    /// Variables of the innermost function, or the globals
+    fn scope(&mut self) -> &mut HashMap<String, Variable> {
+        self.frames.last_mut().unwrap_or(&mut self.globals)
+    }
+
+    fn assign(&mut self, line: usize, name: &str, value: Value) -> Result<()> {
+        let variable = match self.frames.last_mut().and_then(|f| f.get_mut(name)) {
+            Some(variable) => variable,
+            None => self.globals.get_mut(name)
+                .ok_or_else(|| runtime_error(line, format!("undeclared variable {}", name)))?,
+        };
+        variable.value = variable.kind.coerce(line, value)?;
+        Ok(())
+    }
+
+    fn run(&mut self, statements: &[Stmt]) -> Result<Flow> {
+        let mut result = String::new();
+        for statement in statements {
+            match self.exec(statement)? {
+                Flow::Normal(r) => result = r,
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal(result))
+    }
+
+    /// Run `body` of a loop, passing a `return` on
+    fn iterate(&mut self, body: &[Stmt]) -> Result<Option<Flow>> {
+        Ok(match self.run(body)? {
+            Flow::Normal(_) => None,
+            flow => Some(flow),
+        })
+    }
+
+    fn exec(&mut self, statement: &Stmt) -> Result<Flow> {
+        match statement {
+            Stmt::Command(line, words) => return self.run_command(*line, words).map(Flow::Normal),
+            Stmt::Declare(line, kind, names) => {
+                for (name, init) in names {
+                    let value = match init {
+                        Some(expr) => self.eval(*line, expr)?,
+                        // Declaring a parameter gives it a type
+                        None => self.frames.last()
+                            .and_then(|f| f.get(name))
+                            .map_or_else(|| kind.default_value(), |v| v.value.clone()),
+                    };
+                    let value = kind.coerce(*line, value)?;
+                    self.scope().insert(name.clone(), Variable { kind: *kind, value });
+                }
+            }
+            Stmt::Assign(line, name, expr) => {
+                let value = self.eval(*line, expr)?;
+                self.assign(*line, name, value)?;
+            }
+            Stmt::If(branches, otherwise) => {
+                for (line, condition, body) in branches {
+                    if self.eval(*line, condition)?.truth() {
+                        return self.run(body);
+                    }
+                }
+                return self.run(otherwise);
+            }
+            Stmt::While(line, condition, body) => {
+                while self.eval(*line, condition)?.truth() {
+                    if let Some(flow) = self.iterate(body)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            Stmt::For(line, init, condition, update, body) => {
+                self.exec(init)?;
+                while self.eval(*line, condition)?.truth() {
+                    if let Some(flow) = self.iterate(body)? {
+                        return Ok(flow);
+                    }
+                    self.exec(update)?;
+                }
+            }
+            Stmt::Foreach(line, name, list, body) => {
+                let mut items = vec![];
+                for word in words(*line, list)? {
+                    items.extend(self.expand(*line, &word)?.split_whitespace().map(str::to_string));
+                }
+                if self.lookup(name).is_none() {
+                    self.scope().insert(name.clone(), Variable { kind: Kind::Str, value: Kind::Str.default_value() });
+                }
+                for item in items {
+                    self.assign(*line, name, Value::Str(item))?;
+                    if let Some(flow) = self.iterate(body)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            Stmt::Function(name, function) => {
+                self.functions.insert(name.clone(), function.clone());
+            }
+            Stmt::Return(line, expr) => {
+                let value = match expr {
+                    Some(expr) => self.eval(*line, expr)?,
+                    None => Value::Str(String::new()),
+                };
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Normal(String::new()))
+    }
+
+    /// Text of `word` with its blocks evaluated
+    fn expand(&mut self, line: usize, word: &Word) -> Result<String> {
+        let mut text = String::new();
+        for part in word {
+            match part {
+                Part::Text(t) => text.push_str(t),
+                Part::Block(b) => text.push_str(&self.block(line, b)?.to_string()),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Run a command or function given as words
+    fn run_command(&mut self, line: usize, words: &[Word]) -> Result<String> {
+        let words = words.iter().map(|w| self.expand(line, w)).collect::<Result<Vec<_>>>()?;
+        if self.functions.contains_key(&words[0]) {
+            let args = words[1..].iter().map(|w| Value::Str(w.clone())).collect();
+            return Ok(self.call_function(line, &words[0], args)?.to_string());
+        }
+        self.command(line, &words)
+    }
+
+    /// Call a command or function with evaluated arguments, as `name(args)`
+    /// in an expression
+    fn invoke(&mut self, line: usize, name: &str, args: Vec<Value>) -> Result<Value> {
+        if self.functions.contains_key(name) {
+            return self.call_function(line, name, args);
+        }
+        let mut words = vec![name.to_string()];
+        words.extend(args.iter().map(Value::to_string));
+        self.command(line, &words).map(Value::Str)
+    }
+
+    fn call_function(&mut self, line: usize, name: &str, args: Vec<Value>) -> Result<Value> {
+        let function = self.functions[name].clone();
+        if args.len() > function.params.len() {
+            return Err(runtime_error(line, format!("{} takes {} argument(s)", name, function.params.len())));
+        }
+        if self.frames.len() >= MAX_DEPTH {
+            return Err(runtime_error(line, format!("calls nested too deeply in {}", name)));
+        }
+        let mut args = args.into_iter();
+        let frame = function.params.iter()
+            .map(|p| (p.clone(), Variable { kind: Kind::Str, value: args.next().unwrap_or(Kind::Str.default_value()) }))
+            .collect();
+        self.frames.push(frame);
+        let flow = self.run(&function.body);
+        self.frames.pop();
+        Ok(match flow? {
+            Flow::Return(value) => value,
+            Flow::Normal(_) => Kind::Str.default_value(),
+        })
+    }
+
+    /// Value of a `{}` block: the result of the command or function it
+    /// names (`{getfield /soma Vm}`), or else of the expression it holds
+    fn block(&mut self, line: usize, text: &str) -> Result<Value> {
+        let (name, rest) = keyword(text);
+        let is_number = name.starts_with(|c: char| c.is_ascii_digit());
+        if !name.is_empty() && !is_number && self.lookup(name).is_none() && !rest.starts_with('(') {
+            return self.run_command(line, &words(line, text)?).map(Value::Str);
+        }
+        self.eval(line, text)
+    }
+
+    fn eval(&mut self, line: usize, text: &str) -> Result<Value> {
+        let tokens = tokens(line, text)?;
+        let mut pos = 0;
+        let value = self.binary(line, &tokens, &mut pos, 0)?;
+        match tokens.get(pos) {
+            None => Ok(value),
+            Some(token) => Err(parse_error(line, format!("unexpected {:?} in '{}'", token, text))),
+        }
+    }
+
+    /// Operators binding at least as strongly as `min`, by precedence
+    /// climbing
+    fn binary(&mut self, line: usize, tokens: &[Token], pos: &mut usize, min: u8) -> Result<Value> {
+        let mut lhs = self.unary(line, tokens, pos)?;
+        while let Some(&Token::Op(op)) = tokens.get(*pos) {
+            let Some(prec) = precedence(op).filter(|&p| p >= min) else { break };
+            *pos += 1;
+            // ** is right associative
+            let next = if op == "**" { prec } else { prec + 1 };
+            let rhs = self.binary(line, tokens, pos, next)?;
+            lhs = apply(line, op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self, line: usize, tokens: &[Token], pos: &mut usize) -> Result<Value> {
+        match tokens.get(*pos) {
+            Some(Token::Op("-")) => {
+                *pos += 1;
+                Ok(Value::Num(-self.binary(line, tokens, pos, 8)?.number(line)?))
+            }
+            Some(Token::Op("!")) => {
+                *pos += 1;
+                let x = self.binary(line, tokens, pos, 8)?;
+                Ok(Value::Num(if x.truth() { 0.0 } else { 1.0 }))
+            }
+            _ => self.primary(line, tokens, pos),
+        }
+    }
+
+    fn primary(&mut self, line: usize, tokens: &[Token], pos: &mut usize) -> Result<Value> {
+        let token = tokens.get(*pos).ok_or_else(|| parse_error(line, "expression ends early"))?;
+        *pos += 1;
+        let expect_close = |pos: &mut usize| match tokens.get(*pos) {
+            Some(Token::Close) => {
+                *pos += 1;
+                Ok(())
+            }
+            _ => Err(parse_error(line, "expected )")),
+        };
+        match token {
+            Token::Num(x) => Ok(Value::Num(*x)),
+            Token::Str(s) => Ok(Value::Str(s.clone())),
+            Token::Block(text) => self.block(line, text),
+            Token::Open => {
+                let value = self.binary(line, tokens, pos, 0)?;
+                expect_close(pos)?;
+                Ok(value)
+            }
+            Token::Ident(name) if tokens.get(*pos) == Some(&Token::Open) => {
+                *pos += 1;
+                let mut args = vec![];
+                if tokens.get(*pos) != Some(&Token::Close) {
+                    loop {
+                        args.push(self.binary(line, tokens, pos, 0)?);
+                        if tokens.get(*pos) != Some(&Token::Comma) {
+                            break;
+                        }
+                        *pos += 1;
+                    }
+                }
+                expect_close(pos)?;
+                self.invoke(line, name, args)
+            }
+            Token::Ident(name) => self.lookup(name)
+                .map(|v| v.value.clone())
+                .ok_or_else(|| runtime_error(line, format!("undeclared variable {}", name))),
+            token => Err(parse_error(line, format!("unexpected {:?}", token))),
+        }
+    }
+
+    /// Absolute form of `path`, relative to the working element or, from
+    /// `^`, to the element last made
+    pub fn resolve(&self, path: &str) -> String {
+        let (base, path) = match (&self.last, path.strip_prefix('^')) {
+            (Some(last), Some(rest)) if rest.is_empty() || rest.starts_with('/') => (last.as_str(), rest),
+            _ if path.starts_with('/') => ("/", path),
+            _ => (self.cwe.as_str(), path),
+        };
+        let mut parts: Vec<&str> = base.split('/').filter(|p| !p.is_empty()).collect();
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                _ => parts.push(part),
+            }
+        }
+        format!("/{}", parts.join("/"))
+    }
+
+    /// Resolved path of an existing element
+    fn element(&self, line: usize, path: &str) -> Result<String> {
+        let path = self.resolve(path);
+        if self.sim.get(&path).is_none() {
+            return Err(runtime_error(line, format!("no element {}", path)));
+        }
+        Ok(path)
+    }
+
+    /// Resolved paths of the elements `path` names: those matching a
+    /// wildcard path (see [`crate::wildcard`]), or one existing element
+    pub(crate) fn elements(&self, line: usize, path: &str) -> Result<Vec<String>> {
+        if !wildcard::is_pattern(path) {
+            return Ok(vec![self.element(line, path)?]);
+        }
+        let pattern: Vec<String> = path.split(',').map(|p| self.resolve(p)).collect();
+        wildcard::find(&self.sim, &pattern.join(",")).map_err(|e| runtime_error(line, e.to_string()))
+    }
+
+    /// `addmsg source graph PLOT field *name *color`, or `PLOTSCALE` with
+    /// a scale and offset after the color (see [`crate::xodus`])
+    fn plot(&mut self, line: usize, sources: &[String], graphs: &[String], scaled: bool, words: &[String]) -> Result<String> {
+        let (names, values): (Vec<&String>, Vec<&String>) = words.iter().partition(|w| w.starts_with('*'));
+        let (field, scale, offset) = match (values.as_slice(), scaled) {
+            ([field], false) => (field.as_str(), 1.0, 0.0),
+            ([field, scale, offset], true) => (field.as_str(), number(line, scale)?, number(line, offset)?),
+            _ => return Err(runtime_error(line, "PLOT takes a field, PLOTSCALE a field, a scale and an offset")),
+        };
+        let name = names.first().map_or(field, |n| &n[1..]);
+        let color = names.get(1).map_or("", |c| &c[1..]);
+        for source in sources {
+            for graph in graphs {
+                xodus::add_plot(&mut self.sim, (source, field), graph, name, color, (scale, offset))
+                    .map_err(|e| runtime_error(line, e.to_string()))?;
+            }
+        }
+        Ok(String::new())
+    }
+
+    /// Run a command, taking MOOSE forms as their GENESIS equivalents in
+    /// MOOSE mode
+    fn command(&mut self, line: usize, words: &[String]) -> Result<String> {
+        if self.dialect == Dialect::Genesis {
+            return self.builtin(line, words);
+        }
+        let translation = moose::translate(self, words).map_err(|reason| {
+            self.report.push(format!("line {}: {}", line, reason));
+            runtime_error(line, reason)
+        })?;
+        self.report.extend(translation.notes.iter().map(|note| format!("line {}: {}", line, note)));
+        let mut result = String::new();
+        for words in &translation.commands {
+            result = self.builtin(line, words)?;
+        }
+        Ok(result)
+    }
+
+    fn builtin(&mut self, line: usize, words: &[String]) -> Result<String> {
+        let (name, args) = words.split_first().expect("statements have words");
+        let arity = |min: usize, max: usize| {
+            if args.len() < min || args.len() > max {
+                Err(runtime_error(line, format!("wrong number of arguments to {}", name)))
+            } else {
+                Ok(())
+            }
+        };
+        match name.as_str() {
+            "create" => {
+                if args.len() < 2 {
+                    return Err(runtime_error(line, "create needs an object and a path"));
+                }
+                // Options set fields (`-field value`); Xodus geometry
+                // (`[x,y,w,h]`) is ignored
+                let mut options = vec![];
+                let mut rest = args[2..].iter().filter(|w| !w.starts_with('['));
+                while let Some(option) = rest.next() {
+                    match (option.strip_prefix('-'), rest.next()) {
+                        (Some(field), Some(value)) => options.push((field, value.as_str())),
+                        _ => return Err(runtime_error(line, format!("bad option {} to create", option))),
+                    }
+                }
+                let path = self.resolve(&args[1]);
+                if self.sim.exists(&path) {
+                    return Err(runtime_error(line, format!("element {} already exists", path)));
+                }
+                if !self.sim.exists(parent_path(&path)) {
+                    return Err(runtime_error(line, format!("no parent element {}", parent_path(&path))));
+                }
+                let element = objects::create(&mut self.sim, &args[0], &path)?;
+                let values = field_values(line, element, &options, self.units)?;
+                set_fields(element, values)?;
+                self.last = Some(path);
+            }
+            "setfield" => {
+                let (paths, pairs) = if args.len() % 2 == 1 {
+                    (self.elements(line, &args[0])?, &args[1..])
+                } else {
+                    (vec![self.element(line, &self.cwe.clone())?], args)
+                };
+                if pairs.is_empty() {
+                    return Err(runtime_error(line, "setfield needs field and value pairs"));
+                }
+                let pairs: Vec<(&str, &str)> = pairs.chunks(2).map(|p| (p[0].as_str(), p[1].as_str())).collect();
+                let mut updates = vec![];
+                for path in paths {
+                    updates.push((path.clone(), field_values(line, self.sim.get(&path).expect("element checked"), &pairs, self.units)?));
+                }
+                for (path, values) in updates {
+                    set_fields(self.sim.get_mut(&path).expect("element checked"), values)?;
+                }
+            }
+            "getfield" => {
+                arity(1, 2)?;
+                let (path, field) = match args {
+                    [field] => (self.element(line, &self.cwe.clone())?, field),
+                    [path, field] => (self.element(line, path)?, field),
+                    _ => unreachable!(),
+                };
+                let element = self.sim.get(&path).expect("element checked");
+                return element.field_text_in(field, self.units)
+                    .ok_or_else(|| runtime_error(line, format!("{} has no field {}", path, field)));
+            }
+            "showfield" => {
+                // The fields of the working element unless the first word
+                // names elements; all of them for none, `*` or `-all`
+                let (paths, fields) = match args.split_first() {
+                    Some((first, rest)) if wildcard::is_pattern(first) || self.sim.exists(&self.resolve(first)) => {
+                        (self.elements(line, first)?, rest)
+                    }
+                    _ => (vec![self.element(line, &self.cwe.clone())?], args),
+                };
+                let fields: Vec<&str> = match fields {
+                    [all] if all == "*" || all == "-all" => vec![],
+                    _ => fields.iter().map(String::as_str).collect(),
+                };
+                for path in paths {
+                    let text = self.sim.get(&path).expect("element checked").show_in(&fields, self.units)
+                        .map_err(|e| runtime_error(line, e.to_string()))?;
+                    self.output.push_str(&text);
+                }
+            }
+            "addfield" => {
+                let (paths, field) = match args {
+                    [field] => (vec![self.element(line, &self.cwe.clone())?], field),
+                    [path, field] => (self.elements(line, path)?, field),
+                    _ => return Err(runtime_error(line, "wrong number of arguments to addfield")),
+                };
+                for path in paths {
+                    self.sim.get_mut(&path).expect("element checked").add_field(field)?;
+                }
+            }
+            "el" => {
+                arity(1, 1)?;
+                return Ok(self.elements(line, &args[0])?.join(" "));
+            }
+            "addmsg" => {
+                if args.len() < 3 {
+                    return Err(runtime_error(line, "addmsg needs a source, a destination and a type"));
+                }
+                // Every source to every destination
+                let sources = self.elements(line, &args[0])?;
+                let dests = self.elements(line, &args[1])?;
+                let msg_type = &args[2];
+                if msg_type == "PLOT" || msg_type == "PLOTSCALE" {
+                    return self.plot(line, &sources, &dests, msg_type == "PLOTSCALE", &args[3..]);
+                }
+                for source in &sources {
+                    for dest in &dests {
+                        self.sim.add_message(source, &args[3..].join(" "), dest, msg_type, msg_type)?;
+                    }
+                }
+            }
+            "copy" => {
+                arity(2, 2)?;
+                let source = self.element(line, &args[0])?;
+                let dest = self.resolve(&args[1]);
+                let copy = self.sim.copy(&source, &dest)?;
+                self.last = Some(copy.clone());
+                return Ok(copy);
+            }
+            "readcell" => {
+                arity(2, 2)?;
+                let src = std::fs::read_to_string(&args[0])?;
+                let cell = self.resolve(&args[1]);
+                readcell::readcell(&mut self.sim, &src, &cell)?;
+            }
+            "setupalpha" | "setuptau" => {
+                if args.len() < 12 {
+                    return Err(runtime_error(line, format!("{} needs a channel, a gate and 10 parameters", name)));
+                }
+                let paths = self.elements(line, &args[0])?;
+                let mut params = [0.0; 10];
+                for (p, word) in params.iter_mut().zip(&args[2..12]) {
+                    *p = number(line, word)?;
+                }
+                let mut xdivs = tabchannel::DEFAULT_XDIVS as f64;
+                let (mut xmin, mut xmax) = tabchannel::DEFAULT_RANGE;
+                let options = &args[12..];
+                let numbers = options.iter().map(|w| number(line, w)).collect::<Result<Vec<f64>>>();
+                match numbers {
+                    // Older scripts give size, min and max without flags
+                    Ok(n) if n.len() == 3 => (xdivs, xmin, xmax) = (n[0], n[1], n[2]),
+                    _ => {
+                        let mut k = 0;
+                        while k < options.len() {
+                            match options[k].as_str() {
+                                "-size" if k + 1 < options.len() => {
+                                    xdivs = number(line, &options[k + 1])?;
+                                    k += 2;
+                                }
+                                "-range" if k + 2 < options.len() => {
+                                    xmin = number(line, &options[k + 1])?;
+                                    xmax = number(line, &options[k + 2])?;
+                                    k += 3;
+                                }
+                                option => return Err(runtime_error(line, format!("bad option {} to {}", option, name))),
+                            }
+                        }
+                    }
+                }
+                if xdivs < 1.0 || xdivs.fract() != 0.0 {
+                    return Err(runtime_error(line, format!("bad table size {}", xdivs)));
+                }
+                let form = if name == "setupalpha" { tabchannel::Form::Alpha } else { tabchannel::Form::Tau };
+                for path in paths {
+                    let element = self.sim.get_mut(&path).expect("element checked");
+                    tabchannel::setup(element, &args[1], form, &params, xdivs as usize, xmin, xmax)?;
+                }
+            }
+            "tweakalpha" | "tweaktau" => {
+                arity(2, 2)?;
+                let form = if name == "tweakalpha" { tabchannel::Form::Alpha } else { tabchannel::Form::Tau };
+                for path in self.elements(line, &args[0])? {
+                    tabchannel::tweak(self.sim.get_mut(&path).expect("element checked"), &args[1], form)?;
+                }
+            }
+            "call" => {
+                if args.len() < 2 {
+                    return Err(runtime_error(line, "call needs an element and an action"));
+                }
+                let paths = self.elements(line, &args[0])?;
+                match (args[1].as_str(), &args[2..]) {
+                    ("TABCREATE", [gate, xdivs, xmin, xmax]) => {
+                        let xdivs = number(line, xdivs)?;
+                        if xdivs < 1.0 || xdivs.fract() != 0.0 {
+                            return Err(runtime_error(line, format!("bad table size {}", xdivs)));
+                        }
+                        let (xmin, xmax) = (number(line, xmin)?, number(line, xmax)?);
+                        for path in paths {
+                            let element = self.sim.get_mut(&path).expect("element checked");
+                            tabchannel::tabcreate(element, gate, xdivs as usize, xmin, xmax)?;
+                        }
+                    }
+                    ("SETUP", []) => {
+                        for path in paths {
+                            hsolve::setup(&mut self.sim, &path)?;
+                        }
+                    }
+                    (init, [i, scale, range, center, label @ ..]) if init.starts_with("initparam") && label.len() <= 1 => {
+                        let (i, scale) = (index(line, i)?, number(line, scale)?);
+                        let (range, center) = (number(line, range)?, number(line, center)?);
+                        for path in paths {
+                            let element = self.sim.get_mut(&path).expect("element checked");
+                            if !paramsearch::is_param_table(element) {
+                                return Err(runtime_error(line, format!("{} is not a parameter table", path)));
+                            }
+                            let label = label.first().map_or("", String::as_str);
+                            paramsearch::init_param(element, i, scale, range, center, label)?;
+                        }
+                    }
+                    ("initsearch", []) => {
+                        for path in paths {
+                            paramsearch::start(&mut self.sim, &path)?;
+                        }
+                    }
+                    ("update_params", []) => {
+                        for path in paths {
+                            paramsearch::update(&mut self.sim, &path)?;
+                        }
+                    }
+                    ("recenter", []) => {
+                        for path in paths {
+                            paramsearch::recenter(&mut self.sim, &path)?;
+                        }
+                    }
+                    ("search", [function]) => {
+                        if !self.functions.contains_key(function.as_str()) {
+                            return Err(runtime_error(line, format!("no function {}", function)));
+                        }
+                        // Evaluate every proposal with the script's function
+                        for path in paths {
+                            paramsearch::start(&mut self.sim, &path)?;
+                            while self.sim.get(&path).and_then(|e| e.get_param("done")) == Some(0.0) {
+                                let table = vec![Value::Str(path.clone())];
+                                let matched = self.invoke(line, function, table)?.number(line)?;
+                                self.sim.get_mut(&path).expect("element checked").set_field("current_match", matched)?;
+                                paramsearch::update(&mut self.sim, &path)?;
+                            }
+                        }
+                    }
+                    (action, _) => return Err(runtime_error(line, format!("cannot call {} on {}", action, args[0]))),
+                }
+            }
+            "randseed" => {
+                arity(0, 1)?;
+                let seed = match args.first() {
+                    Some(seed) => seed.parse().map_err(|_| runtime_error(line, format!("bad seed {}", seed)))?,
+                    None => std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |t| t.as_nanos() as u64),
+                };
+                self.sim.rng = Rng::new(seed);
+                return Ok(seed.to_string());
+            }
+            "rand" => {
+                arity(2, 2)?;
+                let (low, high) = (number(line, &args[0])?, number(line, &args[1])?);
+                return Ok(format_number(low + (high - low) * self.sim.rng.uniform()));
+            }
+            "xshow" | "xhide" | "xupdate" => {
+                arity(1, 1)?;
+                self.elements(line, &args[0])?;
+            }
+            "xflushevents" => arity(0, 0)?,
+            "xcolorscale" => arity(1, 1)?,
+            "setclock" => {
+                arity(2, 2)?;
+                let dt = self.units.to_si(Quantity::Time, number(line, &args[1])?);
+                self.sim.set_clock(index(line, &args[0])?, dt)?;
+            }
+            "useclock" => {
+                arity(2, 2)?;
+                let clock = index(line, &args[1])?;
+                for path in self.elements(line, &args[0])? {
+                    self.sim.use_clock(&path, clock)?;
+                }
+            }
+            "reset" => {
+                arity(0, 0)?;
+                self.sim.reset()?;
+                for warning in units::check(&self.sim) {
+                    self.output.push_str(&format!("Warning: {}\n", warning));
+                }
+            }
+            "paron" => {
+                let mut nodes = Nodes { count: 1, sync: None };
+                for pair in args.chunks(2) {
+                    let [option, value] = pair else {
+                        return Err(runtime_error(line, format!("{} needs a value", pair[0])));
+                    };
+                    match option.as_str() {
+                        "-nodes" => nodes.count = index(line, value)?,
+                        "-sync" => nodes.sync = Some(self.units.to_si(Quantity::Time, number(line, value)?)),
+                        _ => return Err(runtime_error(line, format!("unknown option {} to paron", option))),
+                    }
+                }
+                if nodes.count == 0 || nodes.sync.is_some_and(|t| t.is_nan() || t <= 0.0) {
+                    return Err(runtime_error(line, "paron needs a node and a positive -sync"));
+                }
+                self.nodes = Some(nodes);
+            }
+            "paroff" => {
+                arity(0, 0)?;
+                self.nodes = None;
+            }
+            "nnodes" => {
+                arity(0, 0)?;
+                return Ok(self.nodes.map_or(1, |n| n.count).to_string());
+            }
+            "units" => {
+                arity(0, 1)?;
+                if let Some(system) = args.first() {
+                    self.units = UnitSystem::parse(system)
+                        .ok_or_else(|| runtime_error(line, format!("unknown units {}", system)))?;
+                }
+                return Ok(self.units.name().to_string());
+            }
+            "dialect" => {
+                arity(0, 1)?;
+                if let Some(dialect) = args.first() {
+                    self.dialect = Dialect::parse(dialect)
+                        .ok_or_else(|| runtime_error(line, format!("unknown dialect {}", dialect)))?;
+                }
+                return Ok(self.dialect.name().to_string());
+            }
+            "step" => {
+                let is_flag = |a: &String| a.starts_with('-') && a.parse::<f64>().is_err();
+                let time = args.iter().filter(|a| is_flag(a)).try_fold(false, |_, flag| match flag.as_str() {
+                    "-time" | "-t" => Ok(true),
+                    _ => Err(runtime_error(line, format!("unknown option {} to step", flag))),
+                })?;
+                let values: Vec<&String> = args.iter().filter(|a| !is_flag(a)).collect();
+                let amount = match values.as_slice() {
+                    [] => 1.0,
+                    [x] => number(line, x)?,
+                    _ => return Err(runtime_error(line, "wrong number of arguments to step")),
+                };
+                let steps = if time { self.units.to_si(Quantity::Time, amount) / self.sim.dt() } else { amount };
+                if steps < 0.0 || (!time && steps.fract() != 0.0) {
+                    return Err(runtime_error(line, format!("cannot step {}", amount)));
+                }
+                match self.nodes {
+                    Some(nodes) => parallel::run_steps(&mut self.sim, nodes, steps.round() as usize)?,
+                    None => {
+                        for _ in 0..steps.round() as usize {
+                            self.sim.step()?;
+                        }
+                    }
+                }
+                self.sim.flush()?;
+            }
+            "disable" | "enable" => {
+                arity(1, 1)?;
+                for path in self.elements(line, &args[0])? {
+                    self.sim.set_enabled(&path, name == "enable")?;
+                }
+            }
+            "getsyncount" => {
+                arity(1, 1)?;
+                let path = self.element(line, &args[0])?;
+                return Ok(synapse::synapse_count(&self.sim.elements[&path]).to_string());
+            }
+            "showclocks" => {
+                arity(0, 0)?;
+                self.output.push_str(&schedule::show_clocks(&self.sim));
+            }
+            "showsched" => {
+                arity(0, 0)?;
+                self.output.push_str(&schedule::show_schedule(&self.sim));
+            }
+            "ce" => {
+                arity(1, 1)?;
+                let path = self.resolve(&args[0]);
+                if !self.sim.exists(&path) {
+                    return Err(runtime_error(line, format!("no element {}", path)));
+                }
+                self.cwe = path;
+            }
+            "pwe" => {
+                arity(0, 0)?;
+                return Ok(self.cwe.clone());
+            }
+            "exp" | "log" | "sqrt" | "sin" | "cos" | "tan" | "abs" | "trunc" | "round" => {
+                arity(1, 1)?;
+                let x = number(line, &args[0])?;
+                let f: fn(f64) -> f64 = match name.as_str() {
+                    "exp" => f64::exp,
+                    "log" => f64::ln,
+                    "sqrt" => f64::sqrt,
+                    "sin" => f64::sin,
+                    "cos" => f64::cos,
+                    "tan" => f64::tan,
+                    "abs" => f64::abs,
+                    "trunc" => f64::trunc,
+                    _ => f64::round,
+                };
+                return Ok(format_number(f(x)));
+            }
+            "pow" | "min" | "max" => {
+                arity(2, 2)?;
+                let (x, y) = (number(line, &args[0])?, number(line, &args[1])?);
+                let f: fn(f64, f64) -> f64 = match name.as_str() {
+                    "pow" => f64::powf,
+                    "min" => f64::min,
+                    _ => f64::max,
+                };
+                return Ok(format_number(f(x, y)));
+            }
+            "strcat" => return Ok(args.concat()),
+            "strlen" => {
+                arity(1, 1)?;
+                return Ok(args[0].chars().count().to_string());
+            }
+            "substring" => {
+                arity(2, 3)?;
+                let start = index(line, &args[1])?;
+                let end = match args.get(2) {
+                    Some(end) => index(line, end)?,
+                    None => usize::MAX,
+                };
+                return Ok(args[0].chars().skip(start).take(end.saturating_sub(start).saturating_add(1)).collect());
+            }
+            "strcmp" => {
+                arity(2, 2)?;
+                return Ok((args[0].cmp(&args[1]) as i8).to_string());
+            }
+            "exists" => {
+                arity(1, 1)?;
+                return Ok(if self.sim.exists(&self.resolve(&args[0])) { "1" } else { "0" }.to_string());
+            }
+            "echo" => {
+                self.output.push_str(&args.join(" "));
+                self.output.push('\n');
+            }
+            _ => return Err(runtime_error(line, format!("unknown command {}", name))),
+        }
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        // Two compartments and a copy of them
+        create neutral /cell
+        create compartment /cell/soma
+        setfield /cell/soma Rm 1e8 Cm 1e-10 Em -0.07 \
+            initVm -0.07
+        ce /cell
+        create compartment dend; setfield dend Ra 2e7
+        addmsg soma dend AXIAL Vm
+        addmsg dend ./soma RAXIAL Ra Vm   /* to the soma */
+        ce ..
+        copy /cell /cell2
+
+        setclock 0 1e-4
+        setclock 1 1e-3
+        useclock /cell/dend 1
+        reset
+        step 10
+        step 0.002 -time
+        echo "done at" step
+    "#;
+
+    #[test]
+    fn test_script() {
+        let mut sli = Sli::new();
+        sli.execute(SCRIPT).unwrap();
+        let sim = &sli.sim;
+
+        let cell = sim.get("/cell").unwrap();
+        assert_eq!(cell.children, vec!["/cell/soma", "/cell/dend"]);
+        let soma = sim.get("/cell/soma").unwrap();
+        assert_eq!(soma.get_param("initVm"), Some(-0.07));
+        assert!((soma.get_param("Vm").unwrap() + 0.07).abs() < 1e-3);
+        assert_eq!(soma.get_param("Rm"), Some(1e8));
+        assert_eq!(soma.messages_in[0].msg_type, "RAXIAL");
+        assert_eq!(soma.messages_in[0].source_field, "Ra Vm");
+        assert_eq!(sim.get("/cell/dend").unwrap().get_param("Ra"), Some(2e7));
+        assert_eq!(sim.get("/cell/dend").unwrap().clock, 1);
+
+        let copy = sim.get("/cell2/dend").unwrap();
+        assert_eq!(copy.messages_in[0].source, "/cell2/soma");
+        assert_eq!(copy.messages_out[0].dest, "/cell2/soma");
+        assert_eq!(sim.get("/cell2").unwrap().children, vec!["/cell2/soma", "/cell2/dend"]);
+
+        assert_eq!(sim.clock(1), Some(1e-3));
+        assert!((sim.current_time() - 0.003).abs() < 1e-12);
+        assert_eq!(sli.output, "done at step\n");
+
+        assert_eq!(sli.call("getfield /cell/soma Rm").unwrap(), "100000000");
+        assert_eq!(sli.call("ce /cell/soma; getfield Cm").unwrap(), "1e-10");
+        assert_eq!(sli.call("pwe").unwrap(), "/cell/soma");
+        assert_eq!(sli.call("copy /cell/dend /cell/dend2").unwrap(), "/cell/dend2");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut sli = Sli::new();
+        sli.execute("create neutral /a").unwrap();
+        for bad in [
+            "create neutral /a",
+            "create compartment /b/c",
+            "create squid /d",
+            "setfield /a x",
+            "setfield /a x one",
+            "getfield /a w",
+            "setfield /a w 1",
+            "showfield /a w",
+            "addfield /a x",
+            "addmsg /a /z AXIAL",
+            "setclock 0 -1",
+            "useclock /a 3",
+            "step 1.5",
+            "step 1 -forever",
+            "ce /nowhere",
+            "frobnicate",
+        ] {
+            assert!(sli.execute(bad).is_err(), "{}", bad);
+        }
+        assert!(split("echo \"open").is_err());
+        assert!(sli.execute("copy /a /a/b").is_err());
+    }
+
+    #[test]
+    fn test_language() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            int i
+            float total = 0
+            str names = ""
+            for (i = 1; i <= 4; i = i + 1)
+                total = total + i
+                names = names @ "c" @ i
+            end
+
+            // Builds a compartment and returns twice its diameter
+            function make_cell(path, dia)
+                str path
+                float dia
+                create compartment {path}
+                setfield {path} dia {dia} Rm {1 / (dia * 2)}
+                return {getfield {path} dia} * 2
+            end
+            float d = {make_cell /c1 0.5}
+            make_cell /c2 {d}
+
+            int n = 0, m = 2.7
+            while (n < 3 && !(n == m))
+                n = n + 1
+            end
+            if (total > 100)
+                echo big
+            elif (total == 10)
+                echo "ten:" {total} {names}
+            else
+                echo small
+            end
+            foreach name (/c1 {"/c" @ 2})
+                echo {name} {getfield {name} dia}
+            end
+
+            function fact(k)
+                int k
+                if (k <= 1)
+                    return 1
+                end
+                return k * {fact {k - 1}}
+            end
+            echo {fact 5} {sqrt 16} {strcat "a" "b"} {exp(0) + max(2, 3)} {2 ** 3 ** 2} {-2 ** 2}
+        "#).unwrap();
+        assert_eq!(sli.output, "ten: 10 c1c2c3c4\n/c1 0.5\n/c2 1\n120 4 ab 4 512 -4\n");
+        assert_eq!(sli.variable("d").as_deref(), Some("1"));
+        assert_eq!(sli.variable("n").as_deref(), Some("2"));
+        assert_eq!(sli.sim.get("/c2").unwrap().get_param("Rm"), Some(0.5));
+        // Parameters are local
+        assert_eq!(sli.variable("path"), None);
+        assert_eq!(sli.call("substring \"hello\" 1 3").unwrap(), "ell");
+        assert_eq!(sli.call("echo {strlen {names}}; strcmp a b").unwrap(), "-1");
+
+        for bad in [
+            "undeclared = 1",
+            "float f = \"abc\"",
+            "if (1)\necho open",
+            "end",
+            "make_cell /c3 1 2",
+            "echo {nosuch 1}",
+            "echo {1 +}",
+            "echo {(1}",
+            "for (i = 0; i < 3)\nend",
+            "function loop(x)\nloop x\nend\nloop 1",
+        ] {
+            assert!(sli.execute(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_last_element() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create neutral /cell
+            create compartment /cell/soma -Rm 1e8
+            setfield ^ Cm 1e-10 Ra 2e7
+            create compartment ^/../dend
+            addmsg ^ ^/../soma RAXIAL Ra Vm
+            copy /cell /cell2
+            setfield ^/soma Rm 2e8
+        "#).unwrap();
+        let soma = sli.sim.get("/cell/soma").unwrap();
+        assert_eq!(soma.get_param("Cm"), Some(1e-10));
+        assert_eq!(soma.get_param("Ra"), Some(2e7));
+        assert_eq!(soma.messages_in[0].source, "/cell/dend");
+        assert_eq!(sli.sim.get("/cell2/soma").unwrap().get_param("Rm"), Some(2e8));
+        assert_eq!(sli.call("el ^").unwrap(), "/cell2");
+        assert!(Sli::new().execute("setfield ^ Rm 1").is_err());
+    }
+
+    #[test]
+    fn test_readcell_command() {
+        let file = std::env::temp_dir().join(format!("oldies-genesis-{}.p", std::process::id()));
+        std::fs::write(&file, "soma none 10 0 0 10\ndend soma 20 0 0 2").unwrap();
+        let mut sli = Sli::new();
+        sli.execute(&format!("readcell {} /cell", file.display())).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(sli.sim.get("/cell").unwrap().children, vec!["/cell/soma", "/cell/dend"]);
+        assert!(sli.execute("readcell /nonexistent.p /cell2").is_err());
+    }
+
+    #[test]
+    fn test_load_script() {
+        let sim = crate::load_script("create compartment /soma\nsetfield /soma inject 1e-10").unwrap();
+        assert_eq!(sim.get("/soma").unwrap().get_param("inject"), Some(1e-10));
+    }
+}
@@ -0,0 +1,444 @@
+//! Compartment solver
+//!
+//! Integrates the membrane potential of every compartment with the
+//! Crank-Nicolson method GENESIS's `hsolve` uses: a backward Euler solve
+//! over half a step followed by extrapolation to the full step. Channel
+//! gates are updated first with exponential Euler at the old potential, so
+//! gates and voltage are staggered as in GENESIS. Units are SI.
+//!
+//! The model is read from the messages between elements (see
+//! [`crate::messages`]):
+//!
+//! - `addmsg /parent /child AXIAL Vm` couples the child to its parent
+//!   through the child's `Ra`, and `addmsg /child /parent RAXIAL Ra Vm`
+//!   couples the parent to the child the same way
+//! - `addmsg /comp /comp/chan VOLTAGE Vm` gives a channel its potential
+//!   and `addmsg /comp/chan /comp CHANNEL Gk Ek` adds its conductance to
+//!   the compartment
+//! - `addmsg /source /comp INJECT field` adds a current to `inject`
+//!
+//! A compartment obeys
+//! `Cm dVm/dt = (Em - Vm)/Rm + sum Gk (Ek - Vm) + inject + axial currents`,
+//! and reports the current through its membrane, capacitive and ionic and
+//! outward positive, as `Im`.
+//! Channels have `Gbar`, `Ek` and gates `X`, `Y`, `Z` raised to `Xpower`,
+//! `Ypower`, `Zpower`. Tabchannels look their rates up in tables (see
+//! [`crate::tabchannel`]); HH channels take GENESIS's `hh_channel` forms,
+//! set by the fields `X_alpha_FORM`, `X_alpha_A`, `X_alpha_B`,
+//! `X_alpha_V0` (and `X_beta_*`, `Y_alpha_*`, `Y_beta_*`):
+//!
+//! - 1, exponential: `A exp((V - V0)/B)`
+//! - 2, sigmoid: `A / (exp((V - V0)/B) + 1)`
+//! - 3, linoid: `A (V - V0) / (exp((V - V0)/B) - 1)`
+//!
+//! A Z gate with `Z_conc` set takes its rates at the concentration the
+//! channel receives with CONCEN (see [`crate::concen`]) instead.
+//!
+//! Coupled compartments must form trees, which are solved in linear time
+//! by eliminating from the leaves (Hines ordering). Channels and
+//! compartments are updated on their clocks by [`crate::schedule`], or
+//! by an `hsolve` element that owns them (see [`crate::hsolve`]).
+
+use crate::{messages, tabchannel, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Time, Voltage};
+use std::collections::HashMap;
+
+/// Gates of a channel: the state field and its power
+pub(crate) const GATES: [(&str, &str); 3] = [("X", "Xpower"), ("Y", "Ypower"), ("Z", "Zpower")];
+
+pub(crate) fn is_channel(element: &Element) -> bool {
+    matches!(
+        element.element_type,
+        ElementType::NaChannel | ElementType::KChannel | ElementType::CaChannel | ElementType::TabChannel
+    )
+}
+
+/// Rate `alpha` or `beta` of `gate` at `v`
+fn rate(channel: &Element, gate: &str, rate: &str, v: Voltage) -> f64 {
+    let field = |name: &str| channel.get_param(&format!("{}_{}_{}", gate, rate, name)).unwrap_or(0.0);
+    let (a, b, v0) = (field("A"), field("B"), field("V0"));
+    let x = (v - v0) / b;
+    match field("FORM") as i32 {
+        1 => a * x.exp(),
+        2 => a / (x.exp() + 1.0),
+        3 if x.abs() < 1e-9 => a * b,
+        3 => a * (v - v0) / x.exp_m1(),
+        _ => 0.0,
+    }
+}
+
+/// Rate terms of `gate` at `v` in GENESIS's table form: `A = alpha` and
+/// `B = alpha + beta`
+pub(crate) fn rates(channel: &Element, gate: &str, v: Voltage) -> Result<(f64, f64)> {
+    match channel.element_type {
+        ElementType::TabChannel => tabchannel::rates(channel, gate, v).ok_or_else(|| {
+            OldiesError::SimulationError(format!("{} has a {} gate but no tables", channel.path, gate))
+        }),
+        _ => {
+            let a = rate(channel, gate, "alpha", v);
+            Ok((a, a + rate(channel, gate, "beta", v)))
+        }
+    }
+}
+
+/// Potential a channel receives through its VOLTAGE message
+fn voltage(sim: &GenesisSimulation, channel: &Element) -> Result<Option<Voltage>> {
+    Ok(messages::inputs(sim, channel, "VOLTAGE")?.first().map(|(_, slots)| slots[0]))
+}
+
+fn conductance(channel: &Element) -> f64 {
+    GATES.iter().fold(channel.get_param("Gbar").unwrap_or(0.0), |g, (gate, power)| {
+        match channel.get_param(power).unwrap_or(0.0) {
+            p if p > 0.0 => g * channel.get_param(gate).unwrap_or(0.0).powf(p),
+            _ => g,
+        }
+    })
+}
+
+/// Advance the gates of the channels among `paths` by `dt` with
+/// exponential Euler at the potential each receives, or set them to steady state if `dt` is
+/// `None`. Gates flagged in `instant` (1 for X, 2 for Y, 4 for Z) are
+/// always at steady state.
+fn update_gates(sim: &mut GenesisSimulation, paths: &[String], dt: Option<Time>) -> Result<()> {
+    for path in paths {
+        let channel = &sim.elements[path];
+        if !is_channel(channel) {
+            continue;
+        }
+        let Some(v) = voltage(sim, channel)? else { continue };
+        let conc = messages::inputs(sim, channel, "CONCEN")?.first().map(|(_, slots)| slots[0]);
+        let instant = channel.get_param("instant").unwrap_or(0.0) as u32;
+        let mut fields = vec![];
+        for (k, (gate, power)) in GATES.into_iter().enumerate() {
+            if channel.get_param(power).unwrap_or(0.0) <= 0.0 {
+                continue;
+            }
+            // A Z gate flagged in Z_conc depends on a concentration
+            let x = match gate {
+                "Z" if channel.get_param("Z_conc").unwrap_or(0.0) != 0.0 => conc.ok_or_else(|| {
+                    OldiesError::SimulationError(format!("{} has a Z_conc gate but no CONCEN message", channel.path))
+                })?,
+                _ => v,
+            };
+            let (a, b) = rates(channel, gate, x)?;
+            let inf = a / b;
+            let x = match dt {
+                Some(dt) if instant & (1 << k) == 0 => {
+                    let x = channel.get_param(gate).unwrap_or(inf);
+                    inf + (x - inf) * (-b * dt).exp()
+                }
+                _ => inf,
+            };
+            fields.push((gate, x));
+        }
+        let channel = sim.elements.get_mut(path).unwrap();
+        for (gate, x) in fields {
+            channel.set_param(gate, x);
+        }
+        let gk = conductance(channel);
+        channel.set_param("Gk", gk);
+        if dt.is_none() {
+            let ek = channel.get_param("Ek").unwrap_or(0.0);
+            channel.set_param("Ik", gk * (ek - v));
+        }
+    }
+    Ok(())
+}
+
+/// Set the gates of every channel to their steady state at its potential,
+/// and clear the membrane currents of the compartments
+pub(crate) fn reset(sim: &mut GenesisSimulation) -> Result<()> {
+    for element in sim.elements.values_mut() {
+        if matches!(element.element_type, ElementType::Compartment) {
+            element.set_param("Im", 0.0);
+        }
+    }
+    update_gates(sim, &sim.paths(), None)
+}
+
+/// Advance the gates of `channels` by `dt`
+pub(crate) fn step_channels(sim: &mut GenesisSimulation, channels: &[String], dt: Time) -> Result<()> {
+    update_gates(sim, channels, Some(dt))
+}
+
+/// Advance the membrane potentials of `compartments` by `dt` in one
+/// implicit solve. Compartments coupled to them but updated separately
+/// (on another clock) enter at their present potential.
+pub(crate) fn step_compartments(sim: &mut GenesisSimulation, compartments: &[String], dt: Time) -> Result<()> {
+    let index: HashMap<&str, usize> = compartments.iter().enumerate().map(|(i, p)| (p.as_str(), i)).collect();
+    let n = compartments.len();
+    let field = |e: &Element, name: &str| e.get_param(name).unwrap_or(0.0);
+
+    // Half-step backward Euler system: diagonal, right-hand side and the
+    // off-diagonal entries (row, column) of the axial coupling
+    let mut diag = vec![0.0; n];
+    let mut rhs = vec![0.0; n];
+    let mut off: HashMap<(usize, usize), f64> = HashMap::new();
+    let mut v = vec![0.0; n];
+    // Conductance of the membrane and its current at 0 V, for `Im`
+    let mut membrane = vec![(0.0, 0.0); n];
+    for (i, path) in compartments.iter().enumerate() {
+        let c = &sim.elements[path];
+        let (cm, rm) = (field(c, "Cm"), field(c, "Rm"));
+        if cm <= 0.0 {
+            return Err(OldiesError::SimulationError(format!("{} needs a positive Cm", path)));
+        }
+        v[i] = field(c, "Vm");
+        let mut g = if rm > 0.0 { 1.0 / rm } else { 0.0 };
+        let mut current = g * field(c, "Em");
+        for (_, slots) in messages::inputs(sim, c, "CHANNEL")? {
+            g += slots[0];
+            current += slots[0] * slots[1];
+        }
+        membrane[i] = (g, current);
+        current += field(c, "inject");
+        for (_, slots) in messages::inputs(sim, c, "INJECT")? {
+            current += slots[0];
+        }
+        // Parents and children are solved with this compartment if they
+        // update with it, and enter with their potential otherwise
+        let axial = messages::inputs(sim, c, "AXIAL")?.into_iter().map(|(m, s)| (m, field(c, "Ra"), s[0]));
+        let raxial = messages::inputs(sim, c, "RAXIAL")?.into_iter().map(|(m, s)| (m, s[0], s[1]));
+        for (m, ra, vm) in axial.chain(raxial) {
+            if ra <= 0.0 {
+                return Err(OldiesError::SimulationError(format!("{} message to {} needs a positive Ra", m.msg_type, path)));
+            }
+            g += 1.0 / ra;
+            match index.get(m.source.as_str()) {
+                Some(&j) => *off.entry((i, j)).or_insert(0.0) -= 1.0 / ra,
+                None => current += vm / ra,
+            }
+        }
+        diag[i] = 2.0 * cm / dt + g;
+        rhs[i] = 2.0 * cm / dt * v[i] + current;
+    }
+
+    let half = solve_tree(n, diag, rhs, &off)
+        .map_err(|i| OldiesError::SimulationError(format!("compartments coupled in a loop at {}", compartments[i])))?;
+    for (i, path) in compartments.iter().enumerate() {
+        let c = sim.elements.get_mut(path).unwrap();
+        let cm = field(c, "Cm");
+        c.set_param("Vm", 2.0 * half[i] - v[i]);
+        c.set_param("Im", membrane_current(cm, membrane[i], v[i], half[i], dt));
+    }
+    Ok(())
+}
+
+/// Outward current through a membrane of capacitance `cm` and conductance
+/// and current at 0 V `membrane` over a step of `dt` taking its potential
+/// from `v` to `2 half - v`: the capacitive current plus the ionic current
+/// at the middle of the step
+pub(crate) fn membrane_current(cm: f64, membrane: (f64, f64), v: Voltage, half: Voltage, dt: Time) -> f64 {
+    let (g, current) = membrane;
+    2.0 * cm / dt * (half - v) + g * half - current
+}
+
+/// Set the current `Ik` of `channels` from the potential they receive
+pub(crate) fn update_currents(sim: &mut GenesisSimulation, channels: &[String]) -> Result<()> {
+    for path in channels {
+        let channel = &sim.elements[path];
+        let Some(v) = voltage(sim, channel)? else { continue };
+        let ik = channel.get_param("Gk").unwrap_or(0.0) * (channel.get_param("Ek").unwrap_or(0.0) - v);
+        sim.elements.get_mut(path).unwrap().set_param("Ik", ik);
+    }
+    Ok(())
+}
+
+/// Solve a system whose coupling forms a forest by eliminating from the
+/// leaves of each tree towards its root. Fails with a node on a loop.
+fn solve_tree(
+    n: usize,
+    mut diag: Vec<f64>,
+    mut rhs: Vec<f64>,
+    off: &HashMap<(usize, usize), f64>,
+) -> std::result::Result<Vec<f64>, usize> {
+    let mut neighbours = vec![vec![]; n];
+    for &(i, j) in off.keys() {
+        if !neighbours[i].contains(&j) {
+            neighbours[i].push(j);
+            neighbours[j].push(i);
+        }
+    }
+    // Breadth-first order: parents come before their children
+    let mut parent: Vec<Option<usize>> = vec![None; n];
+    let mut visited = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    for root in 0..n {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let first = order.len();
+        order.push(root);
+        let mut k = first;
+        while k < order.len() {
+            let i = order[k];
+            for &j in &neighbours[i] {
+                if Some(j) == parent[i] {
+                    continue;
+                }
+                if visited[j] {
+                    return Err(j);
+                }
+                visited[j] = true;
+                parent[j] = Some(i);
+                order.push(j);
+            }
+            k += 1;
+        }
+    }
+    let entry = |i: usize, j: usize| off.get(&(i, j)).copied().unwrap_or(0.0);
+
+    for &k in order.iter().rev() {
+        if let Some(p) = parent[k] {
+            let factor = entry(p, k) / diag[k];
+            diag[p] -= factor * entry(k, p);
+            rhs[p] -= factor * rhs[k];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for &k in &order {
+        let coupled = parent[k].map_or(0.0, |p| entry(k, p) * x[p]);
+        x[k] = (rhs[k] - coupled) / diag[k];
+    }
+    Ok(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    /// The squid tutorial: a 500 um squid axon compartment with HH sodium
+    /// and potassium channels, in SI units, built as the tutorial script
+    /// builds it, with its constants, `{}` expressions and `^`
+    const SQUID: &str = r#"
+        float PI = 3.14159
+        float RM = 0.33333          // specific membrane resistance (ohm m^2)
+        float CM = 0.01             // specific membrane capacitance (F/m^2)
+        float RA = 0.3              // specific axial resistance (ohm m)
+        float EREST_ACT = -0.07     // resting membrane potential (V)
+        float Eleak = EREST_ACT + 0.0106
+        float ENA = 0.045
+        float EK = -0.082
+        float GNA = 1200            // max sodium conductance (S/m^2)
+        float GK = 360              // max potassium conductance (S/m^2)
+        float dia = 500e-6
+        float len = 500e-6
+        float area = PI * dia * len
+
+        create compartment /axon
+        setfield ^ Em {Eleak} initVm {EREST_ACT} Rm {RM / area} Cm {CM * area} \
+            Ra {4.0 * RA * len / (dia * dia * PI)} dia {dia} len {len}
+
+        create Na_squid_hh /axon/Na
+        setfield ^ Ek {ENA} Gbar {GNA * area}
+        addmsg /axon ^ VOLTAGE Vm
+        addmsg ^ /axon CHANNEL Gk Ek
+
+        create K_squid_hh /axon/K
+        setfield ^ Ek {EK} Gbar {GK * area}
+        addmsg /axon ^ VOLTAGE Vm
+        addmsg ^ /axon CHANNEL Gk Ek
+
+        setclock 0 1e-5
+        reset
+    "#;
+
+    /// Reference trace of the squid compartment by fourth-order
+    /// Runge-Kutta on the HH equations (mV, ms, uA/cm^2) with 1 us steps
+    fn reference(inject: f64, tstop: f64) -> Vec<(f64, f64)> {
+        let lin = |x: f64, a: f64| if x.abs() < 1e-9 { a } else { a * x / x.exp_m1() };
+        let f = |y: [f64; 4]| {
+            let [v, m, h, n] = y;
+            let u = v + 70.0;
+            let (am, bm) = (0.1 * lin((25.0 - u) / 10.0, 10.0), 4.0 * (-u / 18.0).exp());
+            let (ah, bh) = (0.07 * (-u / 20.0).exp(), 1.0 / (((30.0 - u) / 10.0).exp() + 1.0));
+            let (an, bn) = (0.01 * lin((10.0 - u) / 10.0, 10.0), 0.125 * (-u / 80.0).exp());
+            let i = 120.0 * m.powi(3) * h * (v - 45.0) + 36.0 * n.powi(4) * (v + 82.0) + 0.3 * (v + 59.4);
+            [inject - i, am * (1.0 - m) - bm * m, ah * (1.0 - h) - bh * h, an * (1.0 - n) - bn * n]
+        };
+        let inf = |a: f64, b: f64| a / (a + b);
+        let mut y = [-70.0, inf(0.1 * lin(2.5, 10.0), 4.0), inf(0.07, 1.0 / (3.0f64.exp() + 1.0)), inf(0.01 * lin(1.0, 10.0), 0.125)];
+        let dt = 0.001;
+        let mut trace = vec![(0.0, y[0])];
+        for step in 1..=(tstop / dt).round() as usize {
+            let k1 = f(y);
+            let add = |y: [f64; 4], k: [f64; 4], s: f64| std::array::from_fn(|i| y[i] + s * k[i]);
+            let k2 = f(add(y, k1, dt / 2.0));
+            let k3 = f(add(y, k2, dt / 2.0));
+            let k4 = f(add(y, k3, dt));
+            y = std::array::from_fn(|i| y[i] + dt / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i]));
+            trace.push((step as f64 * dt, y[0]));
+        }
+        trace
+    }
+
+    /// Times at which the potential first rises through `threshold`
+    fn spikes(trace: &[(f64, f64)], threshold: f64) -> Vec<f64> {
+        trace.windows(2).filter(|w| w[0].1 < threshold && w[1].1 >= threshold).map(|w| w[1].0).collect()
+    }
+
+    #[test]
+    fn test_squid_rest() {
+        let mut sli = Sli::new();
+        sli.execute(SQUID).unwrap();
+        let na = sli.sim.get("/axon/Na").unwrap();
+        assert!((na.get_param("X").unwrap() - 0.0529).abs() < 1e-3);
+        assert!((na.get_param("Y").unwrap() - 0.596).abs() < 1e-3);
+        sli.execute("step 0.01 -time").unwrap();
+        let vm = sli.sim.get("/axon").unwrap().get_param("Vm").unwrap();
+        assert!((vm + 0.07).abs() < 2e-4, "{}", vm);
+    }
+
+    #[test]
+    fn test_squid_matches_reference() {
+        // 10 uA/cm^2 over the 7.854e-3 cm^2 membrane
+        let mut sli = Sli::new();
+        sli.execute(SQUID).unwrap();
+        sli.execute("setfield /axon inject 7.854e-8").unwrap();
+        let mut trace = vec![(0.0, -70.0)];
+        for step in 1..=5000 {
+            sli.sim.step().unwrap();
+            trace.push((step as f64 * 0.01, 1e3 * sli.sim.get("/axon").unwrap().get_param("Vm").unwrap()));
+        }
+        let expected = reference(10.0, 50.0);
+
+        let (ours, theirs) = (spikes(&trace, 0.0), spikes(&expected, 0.0));
+        assert_eq!(ours.len(), theirs.len(), "{:?} {:?}", ours, theirs);
+        assert!(ours.len() >= 3);
+        for (a, b) in ours.iter().zip(&theirs) {
+            assert!((a - b).abs() < 0.1, "spike at {} ms, reference {} ms", a, b);
+        }
+        let peak = trace.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+        let reference_peak = expected.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+        assert!((peak - reference_peak).abs() < 1.0, "{} {}", peak, reference_peak);
+    }
+
+    #[test]
+    fn test_passive_cable() {
+        // Current into the end of a three-compartment cable settles to the
+        // resistive divider: 1 nA over Ra = 1e7 ohm steps of 10 mV
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create compartment /a
+            create compartment /b
+            create compartment /c
+            setfield /a Rm 1e12 Em 0 initVm 0 inject 1e-9
+            setfield /b Rm 1e12 Em 0 initVm 0
+            setfield /c Rm 1e7 Em 0 initVm 0
+            addmsg /a /b AXIAL Vm
+            addmsg /b /a RAXIAL Ra Vm
+            addmsg /b /c AXIAL Vm
+            addmsg /c /b RAXIAL Ra Vm
+            reset
+            step 0.05 -time
+        "#).unwrap();
+        let vm = |p: &str| sli.sim.get(p).unwrap().get_param("Vm").unwrap();
+        assert!((vm("/c") - 0.01).abs() < 1e-4, "{}", vm("/c"));
+        assert!((vm("/b") - 0.02).abs() < 1e-4, "{}", vm("/b"));
+        assert!((vm("/a") - 0.03).abs() < 1e-4, "{}", vm("/a"));
+
+        sli.execute("addmsg /c /a AXIAL Vm").unwrap();
+        assert!(sli.execute("step").is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Synaptic transmission (`spikegen`, `synchan`)
+//!
+//! A `spikegen` watches a potential it receives with INPUT
+//! (`addmsg /a/soma /a/soma/spike INPUT Vm`) and emits a spike when it
+//! reaches `thresh`, at most once every `abs_refract` seconds and, with
+//! `edge_triggered` set, only when crossing `thresh` from below. Its
+//! `state` is `output_amp` on the steps it fires and 0 otherwise.
+//!
+//! `addmsg /a/soma/spike /b/dend/syn SPIKE` gives the `synchan` a synapse
+//! with the fields `synapse[i].weight` (1) and `synapse[i].delay` (0 s),
+//! `i` counting the SPIKE messages of the synchan from 0. A spike arrives
+//! `delay` after it is emitted and starts a dual exponential conductance
+//! with time constants `tau1` and `tau2`, or an alpha function when they
+//! are equal, normalized to peak at `gmax * weight`. Like a channel, the
+//! synchan takes its potential with VOLTAGE and gives `Gk Ek` to its
+//! compartment with CHANNEL.
+
+use crate::{messages, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Time};
+use std::f64::consts::E;
+
+fn field(element: &Element, name: &str) -> f64 {
+    element.get_param(name).unwrap_or(0.0)
+}
+
+/// Number of synapses of `synchan`, one per SPIKE message
+pub fn synapse_count(synchan: &Element) -> usize {
+    synchan.messages_in.iter().filter(|m| m.msg_type == "SPIKE").count()
+}
+
+/// Give `synchan` the fields of a new synapse, before its SPIKE message is
+/// added
+pub(crate) fn add_synapse(synchan: &mut Element) -> Result<()> {
+    if !matches!(synchan.element_type, ElementType::Synapse) {
+        return Err(OldiesError::SimulationError(format!("SPIKE messages go to synchans, not {}", synchan.path)));
+    }
+    let i = synapse_count(synchan);
+    synchan.set_param(&format!("synapse[{}].weight", i), 1.0);
+    synchan.set_param(&format!("synapse[{}].delay", i), 0.0);
+    Ok(())
+}
+
+/// Keep only the synapses numbered `kept`, renumbered in order, when the
+/// others' messages are left behind by a copy
+pub(crate) fn renumber(synchan: &mut Element, kept: &[usize]) {
+    let synapses: Vec<(f64, f64)> = kept.iter()
+        .map(|i| (field(synchan, &format!("synapse[{}].weight", i)), field(synchan, &format!("synapse[{}].delay", i))))
+        .collect();
+    synchan.params.retain(|name, _| !name.starts_with("synapse["));
+    for (i, (weight, delay)) in synapses.into_iter().enumerate() {
+        synchan.set_param(&format!("synapse[{}].weight", i), weight);
+        synchan.set_param(&format!("synapse[{}].delay", i), delay);
+    }
+}
+
+/// Factor making the peak of the conductance after a spike of weight 1
+/// equal to 1
+pub fn normalization(tau1: Time, tau2: Time) -> f64 {
+    if (tau1 - tau2).abs() <= 1e-9 * tau1.max(tau2) {
+        return E / tau1;
+    }
+    let peak = tau1 * tau2 * (tau1 / tau2).ln() / (tau1 - tau2);
+    (tau1 - tau2) / (tau1 * tau2 * ((-peak / tau1).exp() - (-peak / tau2).exp()))
+}
+
+/// Input of a spikegen, if it has an INPUT message
+fn input(sim: &GenesisSimulation, spikegen: &Element) -> Result<Option<f64>> {
+    Ok(messages::inputs(sim, spikegen, "INPUT")?.first().map(|(_, slots)| slots[0]))
+}
+
+/// Clear spikes in flight, make spikegens ready to fire and synchans quiet
+pub(crate) fn reset(sim: &mut GenesisSimulation) -> Result<()> {
+    sim.spikes.clear();
+    sim.outbox.clear();
+    for path in sim.paths() {
+        let element = &sim.elements[&path];
+        let fields = match element.element_type {
+            ElementType::SpikeGen => {
+                let previous = input(sim, element)?.unwrap_or(0.0);
+                vec![("state", 0.0), ("lastevent", -field(element, "abs_refract")), ("input", previous)]
+            }
+            ElementType::Synapse => vec![("X", 0.0), ("Y", 0.0), ("Gk", 0.0), ("Ik", 0.0), ("activation", 0.0)],
+            _ => continue,
+        };
+        let element = sim.elements.get_mut(&path).unwrap();
+        for (name, value) in fields {
+            element.set_param(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Let `spikegens` fire, sending their spikes to the synapses they connect
+/// to
+pub(crate) fn step_spikegens(sim: &mut GenesisSimulation, spikegens: &[String], dt: Time) -> Result<()> {
+    for path in spikegens {
+        let spikegen = &sim.elements[path];
+        let Some(v) = input(sim, spikegen)? else { continue };
+        let thresh = field(spikegen, "thresh");
+        let ready = sim.time - field(spikegen, "lastevent") + 0.5 * dt >= field(spikegen, "abs_refract");
+        let crossed = field(spikegen, "edge_triggered") == 0.0 || field(spikegen, "input") < thresh;
+        let fire = v >= thresh && ready && crossed;
+
+        let time = sim.time;
+        let spikegen = sim.elements.get_mut(path).unwrap();
+        let amplitude = field(spikegen, "output_amp");
+        spikegen.set_param("state", if fire { amplitude } else { 0.0 });
+        spikegen.set_param("input", v);
+        if fire {
+            spikegen.set_param("lastevent", time);
+            send_spike(sim, path);
+        }
+    }
+    Ok(())
+}
+
+/// Send a spike from `source`, emitted now, to the synapses its SPIKE
+/// messages made, to arrive after their delays. Spikes for synchans of
+/// other domains wait in the outbox (see [`crate::parallel`]).
+pub(crate) fn send_spike(sim: &mut GenesisSimulation, source: &str) {
+    let mut dests: Vec<String> = sim.elements[source].messages_out.iter()
+        .filter(|m| m.msg_type == "SPIKE")
+        .map(|m| m.dest.clone())
+        .collect();
+    dests.sort_unstable();
+    dests.dedup();
+    for dest in dests {
+        match sim.elements.contains_key(&dest) {
+            true => receive_spike(sim, &dest, source, sim.time),
+            false => sim.outbox.push((source.to_string(), dest, sim.time)),
+        }
+    }
+}
+
+/// Queue the arrivals at the synapses `source` made on `synchan` of a
+/// spike it emitted at `time`
+pub(crate) fn receive_spike(sim: &mut GenesisSimulation, synchan: &str, source: &str, time: Time) {
+    let element = &sim.elements[synchan];
+    let synapses = element.messages_in.iter().filter(|m| m.msg_type == "SPIKE").enumerate();
+    let arrivals: Vec<(Time, f64)> = synapses.filter(|(_, m)| m.source == source)
+        .map(|(i, _)| {
+            let delay = field(element, &format!("synapse[{}].delay", i));
+            (time + delay, field(element, &format!("synapse[{}].weight", i)))
+        })
+        .collect();
+    sim.spikes.entry(synchan.to_string()).or_default().extend(arrivals);
+}
+
+/// Advance the conductances of `synchans` by `dt`, taking the spikes that
+/// arrive during the step
+pub(crate) fn step_synchans(sim: &mut GenesisSimulation, synchans: &[String], dt: Time) -> Result<()> {
+    let horizon = sim.time + 0.5 * dt;
+    for path in synchans {
+        let mut weight = 0.0;
+        if let Some(queue) = sim.spikes.get_mut(path) {
+            queue.retain(|&(arrival, w)| {
+                if arrival <= horizon {
+                    weight += w;
+                }
+                arrival > horizon
+            });
+        }
+        let synchan = sim.elements.get_mut(path).unwrap();
+        let (tau1, tau2) = (field(synchan, "tau1"), field(synchan, "tau2"));
+        if tau1 <= 0.0 || tau2 <= 0.0 {
+            return Err(OldiesError::SimulationError(format!("{} needs positive tau1 and tau2", path)));
+        }
+        // Exact for inputs constant over the step, as GENESIS integrates
+        let activation = weight / dt;
+        let x = activation * tau1 * -(-dt / tau1).exp_m1() + field(synchan, "X") * (-dt / tau1).exp();
+        let y = x * tau2 * -(-dt / tau2).exp_m1() + field(synchan, "Y") * (-dt / tau2).exp();
+        let gk = field(synchan, "gmax") * normalization(tau1, tau2) * y;
+        synchan.set_param("activation", activation);
+        synchan.set_param("X", x);
+        synchan.set_param("Y", y);
+        synchan.set_param("Gk", gk);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    fn field(sli: &Sli, path: &str, name: &str) -> f64 {
+        sli.sim.get(path).unwrap().get_param(name).unwrap()
+    }
+
+    #[test]
+    fn test_synchan_peaks_at_weight() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create compartment /a
+            setfield /a Rm 1e8 Cm 1e-11 Em 0.05 initVm 0.05
+            create spikegen /a/spike
+            setfield /a/spike thresh 0 abs_refract 1
+            addmsg /a /a/spike INPUT Vm
+            create compartment /b
+            setfield /b Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07
+            create synchan /b/syn
+            setfield /b/syn tau1 0.001 tau2 0.003 gmax 1e-9 Ek 0
+            addmsg /a/spike /b/syn SPIKE
+            addmsg /b /b/syn VOLTAGE Vm
+            addmsg /b/syn /b CHANNEL Gk Ek
+            setfield /b/syn synapse[0].weight 2 synapse[0].delay 0.005
+            reset
+        "#).unwrap();
+        assert_eq!(sli.call("getsyncount /b/syn").unwrap(), "1");
+
+        let (mut peak, mut when) = (0.0, 0.0);
+        for step in 0..3000 {
+            sli.sim.step().unwrap();
+            let gk = field(&sli, "/b/syn", "Gk");
+            if gk > peak {
+                (peak, when) = (gk, step as f64 * 1e-5);
+            }
+        }
+        // One spike at 0, then none within the refractory period
+        assert_eq!(field(&sli, "/a/spike", "lastevent"), 0.0);
+        let tpeak = 0.001 * 0.003 * 3f64.ln() / 0.002;
+        assert!((peak - 2e-9).abs() < 2e-11, "{}", peak);
+        assert!((when - 0.005 - tpeak).abs() < 2e-5, "{}", when);
+        assert!(field(&sli, "/b", "Vm") > -0.07);
+        assert!(field(&sli, "/b/syn", "Ik") > 0.0);
+    }
+
+    #[test]
+    fn test_spikes_drive_another_cell() {
+        let cell = |name: &str, inject: f64| format!(r#"
+            create compartment /{0}
+            setfield /{0} Cm 7.854e-9 Rm 4.244e5 Em -0.0594 initVm -0.07 inject {1}
+            create Na_squid_hh /{0}/Na
+            create K_squid_hh /{0}/K
+            setfield /{0}/Na Gbar 9.425e-4
+            setfield /{0}/K Gbar 2.827e-4
+            addmsg /{0} /{0}/Na VOLTAGE Vm
+            addmsg /{0}/Na /{0} CHANNEL Gk Ek
+            addmsg /{0} /{0}/K VOLTAGE Vm
+            addmsg /{0}/K /{0} CHANNEL Gk Ek
+        "#, name, inject);
+        let mut sli = Sli::new();
+        sli.execute(&cell("pre", 7.854e-8)).unwrap();
+        sli.execute(&cell("post", 0.0)).unwrap();
+        sli.execute(r#"
+            create spikegen /pre/spike
+            setfield /pre/spike thresh 0 abs_refract 0.001 edge_triggered 1
+            addmsg /pre /pre/spike INPUT Vm
+            create synchan /post/syn
+            setfield /post/syn tau1 0.0005 tau2 0.001 gmax 5e-6 Ek 0
+            addmsg /pre/spike /post/syn SPIKE
+            setfield /post/syn synapse[0].delay 0.002
+            addmsg /post /post/syn VOLTAGE Vm
+            addmsg /post/syn /post CHANNEL Gk Ek
+            reset
+        "#).unwrap();
+
+        let (mut sent, mut fired) = (vec![], vec![]);
+        let mut previous = -0.07;
+        for step in 0..4000 {
+            sli.sim.step().unwrap();
+            if field(&sli, "/pre/spike", "state") > 0.0 {
+                sent.push(step);
+            }
+            let vm = field(&sli, "/post", "Vm");
+            if previous < 0.0 && vm >= 0.0 {
+                fired.push(step);
+            }
+            previous = vm;
+        }
+        // Every presynaptic spike, sent once, makes a postsynaptic one
+        // after the delay
+        assert!(sent.len() >= 3, "{:?}", sent);
+        assert_eq!(sent.len(), fired.len(), "{:?} {:?}", sent, fired);
+        for (s, f) in sent.iter().zip(&fired) {
+            assert!(f - s > 200 && f - s < 600, "{:?} {:?}", sent, fired);
+        }
+
+        assert!(sli.execute("addmsg /pre/spike /post SPIKE").is_err());
+        assert!(sli.execute("setfield /post/syn tau1 0; step").is_err());
+    }
+
+    #[test]
+    fn test_normalization() {
+        for (tau1, tau2) in [(0.001, 0.003), (0.003, 0.001), (0.002, 0.002)] {
+            let g = |t: f64| if tau1 == tau2 {
+                t * (-t / tau1).exp()
+            } else {
+                tau1 * tau2 / (tau1 - tau2) * ((-t / tau1).exp() - (-t / tau2).exp())
+            };
+            let peak = (1..10000).map(|i| g(i as f64 * 1e-6)).fold(0.0, f64::max);
+            assert!((peak * normalization(tau1, tau2) - 1.0).abs() < 1e-6);
+        }
+    }
+}
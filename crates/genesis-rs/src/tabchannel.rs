@@ -0,0 +1,247 @@
+//! Tabulated channels (`tabchannel`)
+//!
+//! GENESIS defines most channels as tabchannels: each gate `X`, `Y`, `Z`
+//! has the tables `X_A = alpha` and `X_B = alpha + beta` over the membrane
+//! potential, looked up with linear interpolation during the solve.
+//!
+//! - [`setup`] (`setupalpha`, `setuptau`) fills the tables from the form
+//!   `(A + B V) / (C + exp((V + D) / F))` of alpha and beta, or of tau and
+//!   the steady state
+//! - [`tabcreate`] (`call chan TABCREATE X xdivs xmin xmax`) makes empty
+//!   tables, filled entry by entry with `setfield chan X_A->table[i] value`
+//! - [`tweak`] (`tweakalpha`, `tweaktau`) converts tables filled with alpha
+//!   and beta, or tau and the steady state, to the `A`/`B` form
+
+use crate::{Element, ElementType, Table};
+use oldies_core::{OldiesError, Result, Voltage};
+
+/// Table size used when `setupalpha` gives no `-size`
+pub const DEFAULT_XDIVS: usize = 3000;
+/// Table range (V) used when `setupalpha` gives no `-range`
+pub const DEFAULT_RANGE: (Voltage, Voltage) = (-0.1, 0.05);
+
+/// What the parameters of [`setup`] and the tables given to [`tweak`]
+/// describe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Form {
+    /// Alpha and beta
+    Alpha,
+    /// Tau and the steady state
+    Tau,
+}
+
+/// `(A + B v) / (C + exp((v + D) / F))` with `p = [A, B, C, D, F]`
+pub fn hh_form(p: &[f64], v: Voltage) -> f64 {
+    (p[0] + p[1] * v) / (p[2] + ((v + p[3]) / p[4]).exp())
+}
+
+fn check(element: &Element, gate: &str) -> Result<()> {
+    if !matches!(element.element_type, ElementType::TabChannel) {
+        return Err(OldiesError::SimulationError(format!("{} is not a tabchannel", element.path)));
+    }
+    if !matches!(gate, "X" | "Y" | "Z") {
+        return Err(OldiesError::SimulationError(format!("{} has no gate {}", element.path, gate)));
+    }
+    Ok(())
+}
+
+/// Create empty `A` and `B` tables for `gate`
+pub fn tabcreate(element: &mut Element, gate: &str, xdivs: usize, xmin: f64, xmax: f64) -> Result<()> {
+    check(element, gate)?;
+    if xdivs == 0 || xmax <= xmin {
+        return Err(OldiesError::SimulationError(format!(
+            "tables need divisions over an increasing range, not {} over [{}, {}]", xdivs, xmin, xmax
+        )));
+    }
+    for table in ["A", "B"] {
+        element.tables.insert(format!("{}_{}", gate, table), Table::new(xdivs, xmin, xmax));
+    }
+    Ok(())
+}
+
+/// Tabulate `f` over `table`, replacing singular points (`0/0` of the
+/// linoid form) by the mean of values just either side
+fn fill(table: &mut Table, f: impl Fn(f64) -> f64) {
+    let eps = 1e-6 * (table.xmax - table.xmin) / table.xdivs() as f64;
+    for i in 0..table.values.len() {
+        let x = table.x(i);
+        let y = f(x);
+        table.values[i] = if y.is_finite() { y } else { 0.5 * (f(x - eps) + f(x + eps)) };
+    }
+}
+
+/// Fill the tables of `gate` from the parameters `A B C D F` of alpha and
+/// then beta ([`Form::Alpha`]) or of tau and then the steady state
+/// ([`Form::Tau`])
+pub fn setup(
+    element: &mut Element,
+    gate: &str,
+    form: Form,
+    params: &[f64; 10],
+    xdivs: usize,
+    xmin: f64,
+    xmax: f64,
+) -> Result<()> {
+    tabcreate(element, gate, xdivs, xmin, xmax)?;
+    let (first, second) = params.split_at(5);
+    let a = |v| match form {
+        Form::Alpha => hh_form(first, v),
+        Form::Tau => hh_form(second, v) / hh_form(first, v),
+    };
+    let b = |v| match form {
+        Form::Alpha => hh_form(first, v) + hh_form(second, v),
+        Form::Tau => 1.0 / hh_form(first, v),
+    };
+    fill(element.tables.get_mut(&format!("{}_A", gate)).unwrap(), a);
+    fill(element.tables.get_mut(&format!("{}_B", gate)).unwrap(), b);
+    Ok(())
+}
+
+/// Convert the tables of `gate`, holding alpha and beta ([`Form::Alpha`])
+/// or tau and the steady state ([`Form::Tau`]), to `A` and `B`
+pub fn tweak(element: &mut Element, gate: &str, form: Form) -> Result<()> {
+    check(element, gate)?;
+    let (a, b) = (format!("{}_A", gate), format!("{}_B", gate));
+    let (Some(ta), Some(tb)) = (element.tables.get(&a), element.tables.get(&b)) else {
+        return Err(OldiesError::SimulationError(format!("{} has no {} tables", element.path, gate)));
+    };
+    if ta.values.len() != tb.values.len() {
+        return Err(OldiesError::SimulationError(format!("{} tables of {} differ in size", gate, element.path)));
+    }
+    let (new_a, new_b): (Vec<f64>, Vec<f64>) = ta.values.iter().zip(&tb.values)
+        .map(|(&x, &y)| match form {
+            Form::Alpha => (x, x + y),
+            Form::Tau => (y / x, 1.0 / x),
+        })
+        .unzip();
+    element.tables.get_mut(&a).unwrap().values = new_a;
+    element.tables.get_mut(&b).unwrap().values = new_b;
+    Ok(())
+}
+
+/// `A` and `B` of `gate` at `v`, or `None` without tables
+pub(crate) fn rates(element: &Element, gate: &str, v: Voltage) -> Option<(f64, f64)> {
+    let a = element.tables.get(&format!("{}_A", gate))?;
+    let b = element.tables.get(&format!("{}_B", gate))?;
+    Some((a.lookup(v), b.lookup(v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    const EREST: f64 = -0.07;
+
+    /// The squid compartment with the channels `/axon/Na` and `/axon/K` that
+    /// `channels` creates
+    fn squid(channels: &str) -> Sli {
+        let mut sli = Sli::new();
+        sli.execute(&format!(r#"
+            create compartment /axon
+            setfield /axon Cm 7.854e-9 Rm 4.244e5 Em -0.0594 initVm -0.07 inject 7.854e-8
+            {}
+            setfield /axon/Na Gbar 9.425e-4
+            setfield /axon/K Gbar 2.827e-4
+            addmsg /axon /axon/Na VOLTAGE Vm
+            addmsg /axon/Na /axon CHANNEL Gk Ek
+            addmsg /axon /axon/K VOLTAGE Vm
+            addmsg /axon/K /axon CHANNEL Gk Ek
+            reset
+        "#, channels)).unwrap();
+        sli
+    }
+
+    fn spike_times(sli: &mut Sli) -> Vec<usize> {
+        let mut times = vec![];
+        let mut previous = EREST;
+        for step in 0..4000 {
+            sli.sim.step().unwrap();
+            let vm = sli.sim.get("/axon").unwrap().get_param("Vm").unwrap();
+            if previous < 0.0 && vm >= 0.0 {
+                times.push(step);
+            }
+            previous = vm;
+        }
+        times
+    }
+
+    #[test]
+    fn test_setupalpha_matches_hh_channels() {
+        let v0 = EREST + 0.025;
+        let tabulated = format!(r#"
+            create tabchannel /axon/Na
+            setfield /axon/Na Ek 0.045 Xpower 3 Ypower 1
+            setupalpha /axon/Na X {} -1e5 -1 {} -0.01  4e3 0 0 {} 0.018
+            setupalpha /axon/Na Y 70 0 0 {} 0.02  1e3 0 1 {} -0.01
+            create tabchannel /axon/K
+            setfield /axon/K Ek -0.082 Xpower 4
+            setupalpha /axon/K X {} -1e4 -1 {} -0.01  125 0 0 {} 0.08 -size 1500 -range -0.1 0.05
+        "#,
+            1e5 * v0, -v0, -EREST, -EREST, -(EREST + 0.03),
+            1e4 * (EREST + 0.01), -(EREST + 0.01), -EREST,
+        );
+        let mut tab = squid(&tabulated);
+        let mut hh = squid("create Na_squid_hh /axon/Na\ncreate K_squid_hh /axon/K");
+
+        let na = tab.sim.get("/axon/Na").unwrap();
+        assert_eq!(na.tables["X_A"].xdivs(), DEFAULT_XDIVS);
+        assert_eq!(tab.sim.get("/axon/K").unwrap().tables["X_B"].xdivs(), 1500);
+        // The singular point of the linoid form is filled in
+        assert!(na.tables["X_A"].values.iter().all(|x| x.is_finite()));
+        let hh_m = hh.sim.get("/axon/Na").unwrap().get_param("X").unwrap();
+        assert!((na.get_param("X").unwrap() - hh_m).abs() < 1e-4);
+
+        let (ours, theirs) = (spike_times(&mut tab), spike_times(&mut hh));
+        assert!(ours.len() >= 3);
+        assert_eq!(ours.len(), theirs.len());
+        for (a, b) in ours.iter().zip(&theirs) {
+            assert!(a.abs_diff(*b) <= 10, "{:?} {:?}", ours, theirs);
+        }
+    }
+
+    #[test]
+    fn test_setuptau_and_tweak() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create tabchannel /k
+            setuptau /k X 0.005 0 1 0 1e9  1 0 1 0.04 -0.01 -size 100 -range -0.1 0.1
+            create tabchannel /h
+            call /h TABCREATE Y 2 -0.1 0.1
+            setfield /h Y_A->table[0] 0.01 Y_A->table[1] 0.02 Y_A->table[2] 0.04
+            setfield /h Y_B->table[0] 1 Y_B->table[1] 0.5 Y_B->table[2] 0
+            tweaktau /h Y
+        "#).unwrap();
+        // tau = 5 ms everywhere, the steady state is a sigmoid at -40 mV
+        let k = sli.sim.get("/k").unwrap();
+        let (a, b) = rates(k, "X", -0.04).unwrap();
+        assert!((1.0 / b - 0.0025).abs() < 1e-6, "{}", b);
+        assert!((a / b - 0.5).abs() < 1e-3);
+
+        let h = sli.sim.get("/h").unwrap();
+        let (a, b) = rates(h, "Y", 0.0).unwrap();
+        assert!((b - 50.0).abs() < 1e-9 && (a - 25.0).abs() < 1e-9, "{} {}", a, b);
+        assert_eq!(sli.call("getfield /h Y_B->xdivs").unwrap(), "2");
+        assert_eq!(sli.call("getfield /h Y_A->table[2]").unwrap(), "0");
+
+        let mut alpha = Sli::new();
+        alpha.execute(r#"
+            create tabchannel /c
+            call /c TABCREATE X 1 0 1
+            setfield /c X_A->table[1] 3 X_B->table[1] 4
+            tweakalpha /c X
+        "#).unwrap();
+        assert_eq!(alpha.call("getfield /c X_B->table[1]").unwrap(), "7");
+
+        for bad in [
+            "setupalpha /c W 1 0 0 0 1 1 0 0 0 1",
+            "setupalpha /c X 1 0 0 0 1 1 0 0 0",
+            "setfield /c X_A->table[9] 1",
+            "tweaktau /c Z",
+            "call /c TABCREATE X 0 0 1",
+            "call /c FROB",
+        ] {
+            assert!(alpha.execute(bad).is_err(), "{}", bad);
+        }
+    }
+}
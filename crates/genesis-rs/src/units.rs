@@ -0,0 +1,268 @@
+//! SI and physiological units
+//!
+//! GENESIS fields are in SI units: volts, seconds, amperes, ohms, siemens,
+//! farads, metres and mol/m³ (mM). Many scripts and most published models
+//! give values in physiological units instead (millivolts, milliseconds,
+//! ...), and a value in the wrong system is accepted silently: an `Em` of
+//! -65 is -65 V. This module names the quantity each field holds so values
+//! can be converted, and [`check`] flags values whose magnitude looks like
+//! the other system.
+//!
+//! | Quantity | SI | Physiological |
+//! |----------|----|---------------|
+//! | voltage | V | mV |
+//! | time | s | ms |
+//! | current | A | µA |
+//! | resistance | Ω | kΩ |
+//! | conductance | S | mS |
+//! | capacitance | F | µF |
+//! | length | m | µm |
+//! | concentration | mM | mM |
+//!
+//! Simulations always hold SI values; the SLI `units` command makes
+//! `setfield`, `getfield`, `showfield`, `create` options, `setclock` and
+//! `step -time` use physiological units (see [`crate::sli`]). Fields
+//! without a quantity here, tables and `setupalpha` parameters among
+//! them, are never converted.
+
+use crate::sli::format_number;
+use crate::{Element, ElementType, GenesisSimulation};
+
+/// System of units values are given in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnitSystem {
+    /// Volts, seconds, amperes, ..., as GENESIS stores them
+    #[default]
+    SI,
+    /// Millivolts, milliseconds, microamperes, ...
+    Physiological,
+}
+
+impl UnitSystem {
+    /// Name as the SLI `units` command takes it
+    pub fn name(self) -> &'static str {
+        match self {
+            UnitSystem::SI => "SI",
+            UnitSystem::Physiological => "physiological",
+        }
+    }
+
+    /// System named `name` (case is ignored)
+    pub fn parse(name: &str) -> Option<UnitSystem> {
+        match name.to_ascii_lowercase().as_str() {
+            "si" => Some(UnitSystem::SI),
+            "physiological" | "phys" => Some(UnitSystem::Physiological),
+            _ => None,
+        }
+    }
+
+    /// SI value of `value` of `quantity` given in this system
+    pub fn to_si(self, quantity: Quantity, value: f64) -> f64 {
+        match self {
+            UnitSystem::SI => value,
+            UnitSystem::Physiological => scale(value, quantity.exponent()),
+        }
+    }
+
+    /// Value in this system of the SI `value` of `quantity`
+    pub fn from_si(self, quantity: Quantity, value: f64) -> f64 {
+        match self {
+            UnitSystem::SI => value,
+            UnitSystem::Physiological => scale(value, -quantity.exponent()),
+        }
+    }
+}
+
+/// `value` times 10^`exponent`, dividing by exact powers of ten for
+/// negative exponents so that `-70` mV is exactly `-0.07` V
+fn scale(value: f64, exponent: i32) -> f64 {
+    if exponent < 0 {
+        value / 10f64.powi(-exponent)
+    } else {
+        value * 10f64.powi(exponent)
+    }
+}
+
+/// Physical quantity a field holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Voltage,
+    Time,
+    Current,
+    Resistance,
+    Conductance,
+    Capacitance,
+    Length,
+    Concentration,
+}
+
+impl Quantity {
+    /// Power of ten the physiological unit is of the SI one
+    pub fn exponent(self) -> i32 {
+        match self {
+            Quantity::Voltage | Quantity::Time | Quantity::Conductance => -3,
+            Quantity::Current | Quantity::Capacitance | Quantity::Length => -6,
+            Quantity::Resistance => 3,
+            Quantity::Concentration => 0,
+        }
+    }
+
+    /// Symbol of the unit of the quantity in `system`
+    pub fn unit(self, system: UnitSystem) -> &'static str {
+        let (si, physiological) = match self {
+            Quantity::Voltage => ("V", "mV"),
+            Quantity::Time => ("s", "ms"),
+            Quantity::Current => ("A", "uA"),
+            Quantity::Resistance => ("ohm", "kohm"),
+            Quantity::Conductance => ("S", "mS"),
+            Quantity::Capacitance => ("F", "uF"),
+            Quantity::Length => ("m", "um"),
+            Quantity::Concentration => ("mM", "mM"),
+        };
+        match system {
+            UnitSystem::SI => si,
+            UnitSystem::Physiological => physiological,
+        }
+    }
+
+    /// Magnitudes a nonzero SI value of the quantity plausibly has in a
+    /// neuron model, for the quantities where SI and physiological values
+    /// do not overlap
+    fn plausible(self) -> Option<(f64, f64)> {
+        match self {
+            Quantity::Voltage => Some((1e-4, 1.0)),
+            Quantity::Time => Some((1e-7, 10.0)),
+            Quantity::Current => Some((0.0, 1e-6)),
+            Quantity::Capacitance => Some((0.0, 1e-6)),
+            Quantity::Length => Some((1e-8, 1e-2)),
+            Quantity::Resistance | Quantity::Conductance | Quantity::Concentration => None,
+        }
+    }
+}
+
+/// Quantity `field` of `element` holds, if it is one with units
+pub fn quantity(element: &Element, field: &str) -> Option<Quantity> {
+    if !element.params.contains_key(field) {
+        return None;
+    }
+    let channel = element.element_type.isa("channel");
+    Some(match field {
+        "x" | "y" | "z" | "dia" | "len" | "thick" => Quantity::Length,
+        "Vm" | "initVm" | "Em" | "Ek" | "thresh" => Quantity::Voltage,
+        "Rm" | "Ra" => Quantity::Resistance,
+        "Cm" => Quantity::Capacitance,
+        "inject" | "Ik" | "Im" => Quantity::Current,
+        "Gbar" | "Gk" | "gmax" => Quantity::Conductance,
+        "tau" | "tau1" | "tau2" | "abs_refract" => Quantity::Time,
+        "Ca" | "Ca_base" if matches!(element.element_type, ElementType::CaConcen) => Quantity::Concentration,
+        // Half-activation and slope of `hh_channel` rates
+        rate if channel && (rate.ends_with("_V0") || rate.ends_with("_B")) => Quantity::Voltage,
+        _ => return None,
+    })
+}
+
+/// Warnings about the fields of `element` whose values look like they are
+/// in physiological units, or were converted from them twice
+pub fn check_element(element: &Element) -> Vec<String> {
+    element.fields().iter()
+        .filter_map(|field| {
+            let quantity = quantity(element, field)?;
+            let (low, high) = quantity.plausible()?;
+            let value = element.get_param(field)?;
+            let (si, physiological) = (quantity.unit(UnitSystem::SI), quantity.unit(UnitSystem::Physiological));
+            if value.abs() > high {
+                Some(format!(
+                    "{} {} = {} {} looks like a value in {}; GENESIS fields are SI",
+                    element.path, field, format_number(value), si, physiological
+                ))
+            } else if value != 0.0 && value.abs() < low {
+                Some(format!(
+                    "{} {} = {} {} is too small to be right: an SI value given in {}?",
+                    element.path, field, format_number(value), si, physiological
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Warnings of [`check_element`] for every element, in path order
+pub fn check(sim: &GenesisSimulation) -> Vec<String> {
+    sim.paths().iter().flat_map(|path| check_element(&sim.elements[path])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    const CELL: &str = r#"
+        create compartment /soma
+        setfield /soma Rm {RM} Cm {CM} Em {EM} initVm {EM} inject {INJECT} dia {DIA}
+        create Na_squid_hh /soma/Na
+        create K_squid_hh /soma/K
+        addmsg /soma /soma/Na VOLTAGE Vm
+        addmsg /soma/Na /soma CHANNEL Gk Ek
+        addmsg /soma /soma/K VOLTAGE Vm
+        addmsg /soma/K /soma CHANNEL Gk Ek
+        setclock 0 {DT}
+        reset
+        step {TIME} -time
+    "#;
+
+    fn run(units: &str, values: [(&str, &str); 7]) -> Sli {
+        let mut sli = Sli::new();
+        sli.execute(&format!("units {}", units)).unwrap();
+        let script = values.iter().fold(CELL.to_string(), |s, (k, v)| s.replace(&format!("{{{}}}", k), v));
+        sli.execute(&script).unwrap();
+        sli
+    }
+
+    #[test]
+    fn test_physiological_script_matches_si() {
+        let si = run("SI", [("RM", "1e9"), ("CM", "1e-11"), ("EM", "-0.07"), ("INJECT", "1e-10"),
+            ("DIA", "2e-5"), ("DT", "1e-5"), ("TIME", "0.02")]);
+        let physiological = run("physiological", [("RM", "1e6"), ("CM", "1e-5"), ("EM", "-70"), ("INJECT", "1e-4"),
+            ("DIA", "20"), ("DT", "0.01"), ("TIME", "20")]);
+        let vm = |sli: &Sli| sli.sim.get("/soma").unwrap().get_param("Vm").unwrap();
+        assert!((vm(&si) - vm(&physiological)).abs() < 1e-9, "{} {}", vm(&si), vm(&physiological));
+        assert!((si.sim.current_time() - 0.02).abs() < 1e-9);
+        assert!(si.output.is_empty() && physiological.output.is_empty());
+
+        // Fields read back in the units of the mode
+        let mut sli = physiological;
+        assert_eq!(sli.call("getfield /soma Em").unwrap(), "-70");
+        assert_eq!(sli.call("getfield /soma dia").unwrap(), "20");
+        assert_eq!(sli.call("units").unwrap(), "physiological");
+        sli.execute("showfield /soma Cm").unwrap();
+        assert!(sli.output.contains("Cm                   = 1e-5"), "{}", sli.output);
+        sli.execute("units SI").unwrap();
+        assert_eq!(sli.call("getfield /soma Em").unwrap(), "-0.07");
+        assert!(sli.execute("units cgs").is_err());
+    }
+
+    #[test]
+    fn test_wrong_units_warn() {
+        // Physiological values given as SI are flagged at reset
+        let sli = run("SI", [("RM", "1e6"), ("CM", "1e-5"), ("EM", "-70"), ("INJECT", "1e-4"),
+            ("DIA", "20"), ("DT", "1e-5"), ("TIME", "0")]);
+        let warnings = check(&sli.sim);
+        assert_eq!(warnings.len(), 6, "{:?}", warnings);
+        assert_eq!(warnings[0], "/soma Cm = 1e-5 F looks like a value in uF; GENESIS fields are SI");
+        assert!(warnings.iter().any(|w| w.starts_with("/soma Em = -70 V")));
+        assert!(warnings.iter().all(|w| sli.output.contains(w.as_str())));
+
+        // And SI values given as physiological ones
+        let mut sim = GenesisSimulation::new();
+        let soma = crate::objects::compartment(&mut sim, "/soma");
+        let em = UnitSystem::Physiological.to_si(Quantity::Voltage, -0.065);
+        soma.set_field("Em", em).unwrap();
+        let warnings = check(&sim);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].ends_with("V is too small to be right: an SI value given in mV?"), "{}", warnings[0]);
+        assert_eq!(UnitSystem::Physiological.from_si(Quantity::Resistance, 1e8), 1e5);
+        assert_eq!(quantity(sim.get("/soma").unwrap(), "Rm"), Some(Quantity::Resistance));
+        assert_eq!(quantity(sim.get("/soma").unwrap(), "nothing"), None);
+    }
+}
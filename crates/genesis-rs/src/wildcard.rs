@@ -0,0 +1,233 @@
+//! Wildcard paths
+//!
+//! Commands that act on elements accept GENESIS wildcard paths naming a
+//! set of elements, in the order of the element tree:
+//!
+//! - `#` in a name matches any run of characters and `?` any one, so
+//!   `/cell/dend#` names `/cell/dend1`, `/cell/dend2`, ...
+//! - `##` matches every descendant, at any depth: `/cell/##`
+//! - `[TYPE=object]` keeps the elements created as `object`, and
+//!   `[ISA=class]` those of the class (see [`ElementType::isa`]); `!=`
+//!   keeps the others: `/cell/##[TYPE=compartment]`
+//! - Commas join paths: `/cell/soma,/cell/dend#`
+//!
+//! Empty brackets are ignored (`##[]`), and brackets without `=` are part
+//! of the name, as in `dend[3]`.
+
+use crate::{parent_path, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result};
+
+/// Whether `path` is a wildcard path rather than the path of one element
+pub fn is_pattern(path: &str) -> bool {
+    path.contains(['#', '?', ',']) || path.split('/').any(|part| !filters(part).1.is_empty())
+}
+
+/// A `[KEY=VALUE]` or `[KEY!=VALUE]` filter
+struct Filter<'a> {
+    key: &'a str,
+    value: &'a str,
+    negated: bool,
+}
+
+impl Filter<'_> {
+    fn matches(&self, element: &Element) -> Result<bool> {
+        let found = match self.key {
+            "TYPE" => element.element_type.object() == self.value,
+            "ISA" => element.element_type.isa(self.value),
+            key => return Err(OldiesError::ParseError(format!("unknown wildcard filter {}", key))),
+        };
+        Ok(found != self.negated)
+    }
+}
+
+/// The name pattern of a path component and its trailing filters
+fn filters(part: &str) -> (&str, Vec<Filter<'_>>) {
+    let mut name = part;
+    let mut found = vec![];
+    while let Some(body) = name.strip_suffix(']') {
+        let Some(open) = body.rfind('[') else { break };
+        let condition = &body[open + 1..];
+        // `##[]` is `##`
+        if condition.is_empty() {
+            name = &body[..open];
+            continue;
+        }
+        let Some((key, value)) = condition.split_once('=') else { break };
+        let (key, negated) = match key.strip_suffix('!') {
+            Some(key) => (key, true),
+            None => (key, false),
+        };
+        found.push(Filter { key, value, negated });
+        name = &body[..open];
+    }
+    found.reverse();
+    (name, found)
+}
+
+/// Whether `name` matches `pattern`, with `#` for any run of characters
+/// and `?` for any one
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('#', rest)) => (0..=name.len()).any(|k| glob(rest, &name[k..])),
+        Some((&c, rest)) => name.split_first().is_some_and(|(&n, tail)| (c == '?' || c == n) && glob(rest, tail)),
+    }
+}
+
+/// Children of `path`, in creation order; top-level elements by path
+fn children(sim: &GenesisSimulation, path: &str) -> Vec<String> {
+    match path {
+        "/" => sim.paths().into_iter().filter(|p| parent_path(p) == "/").collect(),
+        _ => sim.get(path).map(|e| e.children.clone()).unwrap_or_default(),
+    }
+}
+
+/// Descendants of `path`, parents before their children
+fn descendants(sim: &GenesisSimulation, path: &str, found: &mut Vec<String>) {
+    for child in children(sim, path) {
+        found.push(child.clone());
+        descendants(sim, &child, found);
+    }
+}
+
+/// Paths of the elements the absolute wildcard path `pattern` names
+pub fn find(sim: &GenesisSimulation, pattern: &str) -> Result<Vec<String>> {
+    let mut found: Vec<String> = vec![];
+    for path in pattern.split(',').filter(|p| !p.is_empty()) {
+        let mut current = vec!["/".to_string()];
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            let (name, filters) = filters(part);
+            let name: Vec<char> = name.chars().collect();
+            let mut next = vec![];
+            for parent in &current {
+                let mut candidates = vec![];
+                if name == ['#', '#'] {
+                    descendants(sim, parent, &mut candidates);
+                } else {
+                    candidates = children(sim, parent);
+                    candidates.retain(|c| glob(&name, &c.rsplit('/').next().unwrap_or("").chars().collect::<Vec<_>>()));
+                }
+                for candidate in candidates {
+                    let element = &sim.elements[&candidate];
+                    if filters.iter().try_fold(true, |all, f| Ok::<_, OldiesError>(all && f.matches(element)?))? {
+                        next.push(candidate);
+                    }
+                }
+            }
+            current = next;
+        }
+        for path in current {
+            if path != "/" && !found.contains(&path) {
+                found.push(path);
+            }
+        }
+    }
+    Ok(found)
+}
+
+impl ElementType {
+    /// Name of the GENESIS object elements of this type are created as
+    pub fn object(&self) -> &str {
+        match self {
+            ElementType::Compartment => "compartment",
+            ElementType::NaChannel => "Na_squid_hh",
+            ElementType::KChannel => "K_squid_hh",
+            ElementType::TabChannel => "tabchannel",
+            ElementType::CaChannel => "Ca_channel",
+            ElementType::CaConcen => "Ca_concen",
+            ElementType::HSolve => "hsolve",
+            ElementType::ParamTableBF => "paramtableBF",
+            ElementType::ParamTableSA => "paramtableSA",
+            ElementType::ParamTableGA => "paramtableGA",
+            ElementType::Pool => "kpool",
+            ElementType::Reaction => "kreac",
+            ElementType::Enzyme => "kenz",
+            ElementType::Synapse => "synchan",
+            ElementType::SpikeGen => "spikegen",
+            ElementType::PulseGen => "pulsegen",
+            ElementType::RandomSpike => "randomspike",
+            ElementType::NoiseGen => "noisegen",
+            ElementType::EField => "efield",
+            ElementType::Recorder => "recorder",
+            ElementType::AscFile => "asc_file",
+            ElementType::DiskOut => "disk_out",
+            ElementType::XGraph => "xgraph",
+            ElementType::XPlot => "xplot",
+            ElementType::XCell => "xcell",
+            ElementType::XWidget(name) => name,
+            ElementType::Neutral => "neutral",
+            ElementType::Custom(name) => name,
+        }
+    }
+
+    /// Whether elements of this type belong to `class`: their object, or
+    /// `channel` (channels and synchans), `output` (output files) or
+    /// `neutral` (every element)
+    pub fn isa(&self, class: &str) -> bool {
+        class == self.object() || class == "neutral" || match class {
+            "channel" => matches!(
+                self,
+                ElementType::NaChannel | ElementType::KChannel | ElementType::CaChannel
+                    | ElementType::TabChannel | ElementType::Synapse
+            ),
+            "output" => matches!(self, ElementType::AscFile | ElementType::DiskOut),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sli::Sli;
+
+    fn names(sim: &GenesisSimulation, pattern: &str) -> String {
+        find(sim, pattern).unwrap().join(" ")
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create neutral /cell
+            create compartment /cell/soma
+            create Na_squid_hh /cell/soma/Na
+            create K_squid_hh /cell/soma/K
+            create compartment /cell/dend1
+            create compartment /cell/dend2
+            create synchan /cell/dend2/syn
+            create compartment /cell/axon[0]
+        "#).unwrap();
+        assert_eq!(names(&sli.sim, "/cell/#"), "/cell/soma /cell/dend1 /cell/dend2 /cell/axon[0]");
+        assert_eq!(names(&sli.sim, "/cell/dend#"), "/cell/dend1 /cell/dend2");
+        assert_eq!(names(&sli.sim, "/cell/dend?/#,/cell/soma/Na"), "/cell/dend2/syn /cell/soma/Na");
+        assert_eq!(names(&sli.sim, "/##[TYPE=compartment]"), "/cell/soma /cell/dend1 /cell/dend2 /cell/axon[0]");
+        assert_eq!(names(&sli.sim, "/cell/##[ISA=channel]"), "/cell/soma/Na /cell/soma/K /cell/dend2/syn");
+        assert_eq!(names(&sli.sim, "/cell/soma/#[ISA=channel][TYPE!=K_squid_hh]"), "/cell/soma/Na");
+        assert_eq!(names(&sli.sim, "/cell/axon[0]"), "/cell/axon[0]");
+        assert_eq!(names(&sli.sim, "/nothing/#"), "");
+        assert!(is_pattern("/cell/##") && is_pattern("/a,/b") && is_pattern("/x[ISA=channel]"));
+        assert!(!is_pattern("/cell/axon[0]"));
+        assert!(find(&sli.sim, "/#[COLOR=red]").is_err());
+
+        // Commands act on every element named
+        sli.execute(r#"
+            setfield /cell/##[TYPE=compartment] Rm 1e8 Cm 1e-11
+            ce /cell
+            setclock 1 1e-4
+            useclock dend# 1
+            addmsg soma/#[ISA=channel] soma CHANNEL Gk Ek
+            addmsg soma soma/#[ISA=channel] VOLTAGE Vm
+            disable soma/#
+        "#).unwrap();
+        let sim = &sli.sim;
+        assert!(names(sim, "/##[TYPE=compartment]").split(' ').all(|p| sim.get(p).unwrap().get_param("Cm") == Some(1e-11)));
+        assert_eq!((sim.get("/cell/dend1").unwrap().clock, sim.get("/cell/soma").unwrap().clock), (1, 0));
+        assert_eq!(sim.get("/cell/soma").unwrap().messages_in.len(), 2);
+        assert_eq!(sim.get("/cell/soma/K").unwrap().messages_in[0].msg_type, "VOLTAGE");
+        assert!(!sim.is_simulated("/cell/soma/Na") && sim.is_simulated("/cell/soma"));
+        assert_eq!(sli.call("el dend#").unwrap(), "/cell/dend1 /cell/dend2");
+        assert_eq!(sli.call("showfield /cell/dend# Rm").unwrap(), "");
+        assert!(sli.output.contains("[ /cell/dend2 ]\nRm                   = 100000000\n"), "{}", sli.output);
+    }
+}
@@ -0,0 +1,185 @@
+//! Xodus displays, recorded headlessly
+//!
+//! Scripts written for GENESIS's Xodus GUI build forms of graphs and cell
+//! views. Here the widgets are elements that draw nothing, and the
+//! displays record what they would have shown:
+//!
+//! - `addmsg /cell/soma /form/graph PLOT Vm *name *color` plots a field
+//!   on an `xgraph`, creating the `xplot` `/form/graph/name` (the field
+//!   name without `*name`); every tick of the graph's clock adds the
+//!   value to the [`TimeSeries`] of the plot, read with
+//!   [`GenesisSimulation::recording`]. `PLOTSCALE field *name *color scale
+//!   offset` records `value * scale + offset`.
+//! - an `xcell` records the field `field` (`Vm` by default) of the
+//!   compartments its `path` names at every tick, as [`Snapshots`] read
+//!   with [`GenesisSimulation::snapshots`]
+//! - `xform`, `xlabel`, `xbutton`, `xtoggle`, `xdialog`, `xdraw`,
+//!   `xtext`, `xshape` and `xaxis` are kept for the element tree; they
+//!   take any field, as text when they do not have it
+//! - `xshow`, `xhide`, `xupdate`, `xflushevents` and `xcolorscale` do
+//!   nothing
+//!
+//! Recordings are cleared at `reset`.
+
+use crate::{messages, wildcard, Element, ElementType, GenesisSimulation};
+use oldies_core::{OldiesError, Result, Time, TimeSeries};
+
+/// Widgets kept only for the element tree
+pub const WIDGETS: &[&str] = &["xform", "xlabel", "xbutton", "xtoggle", "xdialog", "xdraw", "xtext", "xshape", "xaxis"];
+
+/// Whether `element` is an Xodus widget or display
+pub(crate) fn is_xodus(element: &Element) -> bool {
+    matches!(
+        element.element_type,
+        ElementType::XGraph | ElementType::XPlot | ElementType::XCell | ElementType::XWidget(_)
+    )
+}
+
+/// Whether `element` records at its clock ticks
+pub(crate) fn is_display(element: &Element) -> bool {
+    matches!(element.element_type, ElementType::XGraph | ElementType::XCell)
+}
+
+/// Values of a field over the compartments of an `xcell` at each tick
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshots {
+    /// Compartments, in the order of the values
+    pub compartments: Vec<String>,
+    pub time: Vec<Time>,
+    /// Values at each tick, one per compartment
+    pub values: Vec<Vec<f64>>,
+}
+
+/// Plot `field` of `source` on the graph `graph` as `name`, recording
+/// `value * scale + offset` for `(scale, offset)`
+pub fn add_plot(
+    sim: &mut GenesisSimulation,
+    (source, field): (&str, &str),
+    graph: &str,
+    name: &str,
+    color: &str,
+    (scale, offset): (f64, f64),
+) -> Result<()> {
+    if !matches!(sim.get(graph).map(|g| &g.element_type), Some(ElementType::XGraph)) {
+        return Err(OldiesError::SimulationError(format!("{} is not an xgraph", graph)));
+    }
+    let plot = format!("{}/{}", graph, name);
+    if !sim.exists(&plot) {
+        crate::objects::create(sim, "xplot", &plot)?;
+    }
+    let element = sim.get_mut(&plot).expect("plot created");
+    element.set_field("scale", scale)?;
+    element.set_field("offset", offset)?;
+    element.set_text("fg", color)?;
+    sim.add_message(source, field, graph, name, "PLOT")
+}
+
+/// Start the recordings again (`reset`)
+pub(crate) fn reset(sim: &mut GenesisSimulation) {
+    sim.recordings.clear();
+    sim.snapshots.clear();
+}
+
+/// Record the displays among `paths` at `time`
+pub(crate) fn record(sim: &mut GenesisSimulation, paths: &[String], time: Time) -> Result<()> {
+    for path in paths {
+        let element = &sim.elements[path];
+        match element.element_type {
+            ElementType::XGraph => {
+                let mut points = vec![];
+                for (msg, slots) in messages::inputs(sim, element, "PLOT")? {
+                    let plot = format!("{}/{}", path, msg.dest_field);
+                    let (scale, offset) = sim.get(&plot)
+                        .map_or((1.0, 0.0), |p| (p.get_param("scale").unwrap_or(1.0), p.get_param("offset").unwrap_or(0.0)));
+                    points.push((plot, msg.dest_field.clone(), slots[0] * scale + offset));
+                }
+                for (plot, name, value) in points {
+                    sim.recordings.entry(plot).or_insert_with(|| TimeSeries::new(&name)).push(time, value);
+                }
+            }
+            ElementType::XCell => {
+                let field = element.get_text("field").unwrap_or("Vm").to_string();
+                if !sim.snapshots.contains_key(path) {
+                    let pattern = element.get_text("path").unwrap_or("");
+                    let compartments = match wildcard::is_pattern(pattern) {
+                        true => wildcard::find(sim, pattern)?,
+                        false => vec![pattern.to_string()],
+                    };
+                    sim.snapshots.insert(path.clone(), Snapshots { compartments, ..Default::default() });
+                }
+                let values = sim.snapshots[path].compartments.iter()
+                    .map(|c| {
+                        sim.get(c).and_then(|e| e.get_field(&field)).ok_or_else(|| {
+                            OldiesError::SimulationError(format!("{} shows {} of {}, which has no such field", path, field, c))
+                        })
+                    })
+                    .collect::<Result<Vec<f64>>>()?;
+                let snapshots = sim.snapshots.get_mut(path).unwrap();
+                snapshots.time.push(time);
+                snapshots.values.push(values);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sli::Sli;
+
+    #[test]
+    fn test_displays_record() {
+        let mut sli = Sli::new();
+        sli.execute(r#"
+            create neutral /cell
+            create compartment /cell/soma
+            create compartment /cell/dend
+            setfield /cell/## Rm 1e8 Cm 1e-11 Em -0.07 initVm -0.07
+            setfield /cell/soma inject 1e-10
+            create xform /data [500,100,500,500] -title "Membrane potential"
+            create xgraph /data/voltage -hgeom 90%
+            setfield /data/voltage xmax 0.01 ymin -0.1 ymax 0.05 title Vm
+            addmsg /cell/soma /data/voltage PLOT Vm *soma *red
+            addmsg /cell/dend /data/voltage PLOTSCALE Vm *dend_mV *blue 1000 0
+            addmsg /cell/soma /data/voltage PLOT inject
+            create xdialog /data/stim -label "Current" -value 1e-10
+            create xtoggle /data/run -script "step 100"
+            setfield /data/run state 1
+            create xcell /cells -path /cell/##[TYPE=compartment] -field Vm
+            setfield /cells colmin -0.1 colmax 0.05
+            xshow /data
+            xupdate /data/voltage
+            xcolorscale hot
+            setclock 0 1e-4
+            setclock 1 1e-3
+            useclock /data/voltage 1
+            reset
+            step 0.01 -time
+        "#).unwrap();
+        let sim = &sli.sim;
+        let soma = sim.recording("/data/voltage/soma").unwrap();
+        assert_eq!(soma.name, "soma");
+        assert_eq!(soma.values.len(), 10);
+        assert!((soma.time[1] - 1.1e-3).abs() < 1e-12);
+        let dend = sim.recording("/data/voltage/dend_mV").unwrap();
+        assert!((dend.values[9] - 1000.0 * sim.get("/cell/dend").unwrap().get_param("Vm").unwrap()).abs() < 1e-9);
+        assert_eq!(sim.recording("/data/voltage/inject").unwrap().values[0], 1e-10);
+        assert_eq!(sim.get("/data/voltage/dend_mV").unwrap().get_text("fg"), Some("blue"));
+
+        let cells = sim.snapshots("/cells").unwrap();
+        assert_eq!(cells.compartments, ["/cell/soma", "/cell/dend"]);
+        assert_eq!((cells.time.len(), cells.values[99].len()), (100, 2));
+        assert!(cells.values[99][0] > cells.values[99][1]);
+        // The graph's last tick, at 9.1 ms, is the cell view's 91st
+        assert_eq!(soma.values[9], cells.values[90][0]);
+
+        assert_eq!(sli.call("getfield /data/stim value").unwrap(), "1e-10");
+        assert_eq!(sli.call("getfield /data title").unwrap(), "Membrane potential");
+        sli.execute("reset").unwrap();
+        assert!(sli.sim.recording("/data/voltage/soma").is_none() && sli.sim.snapshots("/cells").is_none());
+        for bad in ["addmsg /cell/soma /data PLOT Vm", "addmsg /cell/soma /data/voltage PLOTSCALE Vm *x *red 1", "xshow /nothing"] {
+            assert!(sli.execute(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -15,8 +15,9 @@
 //! - Recording devices (spike detectors, multimeters)
 
 use ndarray::Array1;
+use oldies_core::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -693,17 +694,19 @@ pub struct Kernel {
     pub nodes: HashMap<NodeId, NodeState>,
     pub connections: Vec<Connection>,
     pub spike_data: HashMap<NodeId, SpikeData>,  // Keyed by detector ID
+    rng: Rng,
 }
 
 impl Kernel {
     pub fn new(params: KernelParams) -> Self {
         Self {
-            params,
             time: 0.0,
             next_node_id: 1,  // NEST node IDs start at 1
             nodes: HashMap::new(),
             connections: vec![],
             spike_data: HashMap::new(),
+            rng: Rng::new(params.rng_seed),
+            params,
         }
     }
 
@@ -714,10 +717,12 @@ impl Kernel {
         self.connections.clear();
         self.spike_data.clear();
         self.next_node_id = 1;
+        self.rng = Rng::new(self.params.rng_seed);
     }
 
     /// Set kernel parameters
     pub fn set_params(&mut self, params: KernelParams) {
+        self.rng = Rng::new(params.rng_seed);
         self.params = params;
     }
 
@@ -763,61 +768,7 @@ pub fn get_kernel_status() -> KernelParams {
 
 /// Create neurons
 pub fn create(model: NeuronModel, n: usize) -> Result<NodeCollection> {
-    let kernel = get_kernel();
-    let mut ids = Vec::with_capacity(n);
-
-    let model_name = model_to_string(&model);
-
-    for _ in 0..n {
-        let id = kernel.next_node_id;
-        kernel.next_node_id += 1;
-
-        let mut state = HashMap::new();
-
-        // Initialize state based on model
-        match &model {
-            NeuronModel::IafPscAlpha(p) => {
-                state.insert("V_m".into(), p.e_l);
-            }
-            NeuronModel::IafPscExp(p) => {
-                state.insert("V_m".into(), p.e_l);
-            }
-            NeuronModel::IafCondAlpha(p) => {
-                state.insert("V_m".into(), p.e_l);
-            }
-            NeuronModel::AeifCondAlpha(p) => {
-                state.insert("V_m".into(), p.e_l);
-                state.insert("w".into(), 0.0);
-            }
-            NeuronModel::HhPscAlpha(p) => {
-                state.insert("V_m".into(), p.e_l);
-                state.insert("n".into(), 0.3);
-                state.insert("m".into(), 0.05);
-                state.insert("h".into(), 0.6);
-            }
-            NeuronModel::Izhikevich(p) => {
-                state.insert("V_m".into(), p.c);
-                state.insert("U_m".into(), p.b * p.c);
-            }
-            NeuronModel::SpikeDetector => {
-                kernel.spike_data.insert(id, SpikeData::new());
-            }
-            _ => {}
-        }
-
-        kernel.nodes.insert(id, NodeState {
-            id,
-            model: model_name.clone(),
-            v_m: state.get("V_m").copied().unwrap_or(-70.0),
-            last_spike: f64::NEG_INFINITY,
-            refractory_until: f64::NEG_INFINITY,
-            state,
-        });
-
-        ids.push(id);
-    }
-
-    Ok(NodeCollection::new(ids))
+    get_kernel().create(model, n)
 }
 
 fn model_to_string(model: &NeuronModel) -> String {
@@ -846,91 +797,351 @@ pub fn connect(
     targets: &NodeCollection,
     spec: ConnectionSpec,
 ) -> Result<()> {
-    let kernel = get_kernel();
+    get_kernel().connect(sources, targets, spec)
+}
 
-    match spec.rule {
-        ConnectivityRule::AllToAll => {
-            for &src in &sources.ids {
-                for &tgt in &targets.ids {
-                    if !spec.allow_autapses && src == tgt {
-                        continue;
-                    }
+// ============================================================================
+// CONNECTION VALIDATION
+// ============================================================================
 
-                    let weight = sample_weight(&spec.weight);
-                    let delay = sample_delay(&spec.delay);
-
-                    kernel.connections.push(Connection {
-                        source: src,
-                        target: tgt,
-                        weight,
-                        delay,
-                        synapse_model: spec.synapse_model.clone(),
-                        state: HashMap::new(),
-                    });
-                }
+/// Validation layer shared by every connectivity rule.
+///
+/// Checks that all node IDs exist before any connection is made, filters
+/// autapses and multapses according to the spec, and rejects delays outside
+/// the kernel's `[min_delay, max_delay]` window.
+struct ConnectionValidator {
+    allow_autapses: bool,
+    allow_multapses: bool,
+    min_delay: f64,
+    max_delay: f64,
+    /// Pairs created by the current `connect` call
+    made: HashSet<(NodeId, NodeId)>,
+}
+
+impl ConnectionValidator {
+    fn new(
+        kernel: &Kernel,
+        sources: &NodeCollection,
+        targets: &NodeCollection,
+        spec: &ConnectionSpec,
+    ) -> Result<Self> {
+        for &id in sources.ids.iter().chain(targets.ids.iter()) {
+            if !kernel.nodes.contains_key(&id) {
+                return Err(NestError::ConnectionError(format!(
+                    "Node {} does not exist",
+                    id
+                )));
             }
         }
 
-        ConnectivityRule::OneToOne => {
-            if sources.len() != targets.len() {
-                return Err(NestError::ConnectionError(
-                    "OneToOne requires equal population sizes".into()
-                ));
-            }
+        Ok(Self {
+            allow_autapses: spec.allow_autapses,
+            allow_multapses: spec.allow_multapses,
+            min_delay: kernel.params.min_delay,
+            max_delay: kernel.params.max_delay,
+            made: HashSet::new(),
+        })
+    }
+
+    /// Whether a connection between `src` and `tgt` may be created
+    fn admits(&self, src: NodeId, tgt: NodeId) -> bool {
+        if !self.allow_autapses && src == tgt {
+            return false;
+        }
+        if !self.allow_multapses && self.made.contains(&(src, tgt)) {
+            return false;
+        }
+        true
+    }
+
+    fn check_delay(&self, delay: f64) -> Result<()> {
+        if !delay.is_finite() || delay < self.min_delay || delay > self.max_delay {
+            return Err(NestError::ConnectionError(format!(
+                "Delay {} ms outside [{}, {}] ms",
+                delay, self.min_delay, self.max_delay
+            )));
+        }
+        Ok(())
+    }
+
+    /// Number of distinct partners available to `node` among `pool`
+    fn available(&self, node: NodeId, pool: &[NodeId]) -> usize {
+        let unique: HashSet<NodeId> = pool.iter().copied().collect();
+        if !self.allow_autapses && unique.contains(&node) {
+            unique.len() - 1
+        } else {
+            unique.len()
+        }
+    }
+}
+
+impl Kernel {
+    /// Create neurons
+    pub fn create(&mut self, model: NeuronModel, n: usize) -> Result<NodeCollection> {
+        let mut ids = Vec::with_capacity(n);
+
+        let model_name = model_to_string(&model);
+
+        for _ in 0..n {
+            let id = self.next_node_id;
+            self.next_node_id += 1;
 
-            for (&src, &tgt) in sources.ids.iter().zip(targets.ids.iter()) {
-                let weight = sample_weight(&spec.weight);
-                let delay = sample_delay(&spec.delay);
-
-                kernel.connections.push(Connection {
-                    source: src,
-                    target: tgt,
-                    weight,
-                    delay,
-                    synapse_model: spec.synapse_model.clone(),
-                    state: HashMap::new(),
-                });
+            let mut state = HashMap::new();
+
+            // Initialize state based on model
+            match &model {
+                NeuronModel::IafPscAlpha(p) => {
+                    state.insert("V_m".into(), p.e_l);
+                }
+                NeuronModel::IafPscExp(p) => {
+                    state.insert("V_m".into(), p.e_l);
+                }
+                NeuronModel::IafCondAlpha(p) => {
+                    state.insert("V_m".into(), p.e_l);
+                }
+                NeuronModel::AeifCondAlpha(p) => {
+                    state.insert("V_m".into(), p.e_l);
+                    state.insert("w".into(), 0.0);
+                }
+                NeuronModel::HhPscAlpha(p) => {
+                    state.insert("V_m".into(), p.e_l);
+                    state.insert("n".into(), 0.3);
+                    state.insert("m".into(), 0.05);
+                    state.insert("h".into(), 0.6);
+                }
+                NeuronModel::Izhikevich(p) => {
+                    state.insert("V_m".into(), p.c);
+                    state.insert("U_m".into(), p.b * p.c);
+                }
+                NeuronModel::SpikeDetector => {
+                    self.spike_data.insert(id, SpikeData::new());
+                }
+                _ => {}
             }
+
+            self.nodes.insert(id, NodeState {
+                id,
+                model: model_name.clone(),
+                v_m: state.get("V_m").copied().unwrap_or(-70.0),
+                last_spike: f64::NEG_INFINITY,
+                refractory_until: f64::NEG_INFINITY,
+                state,
+            });
+
+            ids.push(id);
         }
 
-        ConnectivityRule::PairwiseBernoulli { p } => {
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::{Hash, Hasher};
+        Ok(NodeCollection::new(ids))
+    }
+
+    /// Connect neurons
+    ///
+    /// Every rule goes through [`ConnectionValidator`], so node existence,
+    /// autapse/multapse filtering and delay bounds are enforced uniformly.
+    /// On error no connections from this call are kept.
+    pub fn connect(
+        &mut self,
+        sources: &NodeCollection,
+        targets: &NodeCollection,
+        spec: ConnectionSpec,
+    ) -> Result<()> {
+        let mut validator = ConnectionValidator::new(self, sources, targets, &spec)?;
+        let n_before = self.connections.len();
+
+        let result = self.connect_rule(sources, targets, &spec, &mut validator);
+        if result.is_err() {
+            self.connections.truncate(n_before);
+        }
+        result
+    }
+
+    fn connect_rule(
+        &mut self,
+        sources: &NodeCollection,
+        targets: &NodeCollection,
+        spec: &ConnectionSpec,
+        validator: &mut ConnectionValidator,
+    ) -> Result<()> {
+        match spec.rule {
+            ConnectivityRule::AllToAll => {
+                for &src in &sources.ids {
+                    for &tgt in &targets.ids {
+                        self.try_connect(src, tgt, spec, validator)?;
+                    }
+                }
+            }
 
-            for &src in &sources.ids {
+            ConnectivityRule::OneToOne => {
+                if sources.len() != targets.len() {
+                    return Err(NestError::ConnectionError(
+                        "OneToOne requires equal population sizes".into()
+                    ));
+                }
+
+                for (&src, &tgt) in sources.ids.iter().zip(targets.ids.iter()) {
+                    self.try_connect(src, tgt, spec, validator)?;
+                }
+            }
+
+            ConnectivityRule::FixedIndegree { indegree } => {
                 for &tgt in &targets.ids {
-                    if !spec.allow_autapses && src == tgt {
-                        continue;
+                    let chosen = self.draw_partners(tgt, &sources.ids, indegree, validator)?;
+                    for src in chosen {
+                        self.try_connect(src, tgt, spec, validator)?;
+                    }
+                }
+            }
+
+            ConnectivityRule::FixedOutdegree { outdegree } => {
+                for &src in &sources.ids {
+                    let chosen = self.draw_partners(src, &targets.ids, outdegree, validator)?;
+                    for tgt in chosen {
+                        self.try_connect(src, tgt, spec, validator)?;
+                    }
+                }
+            }
+
+            ConnectivityRule::FixedTotalNumber { n } => {
+                if sources.is_empty() || targets.is_empty() {
+                    if n > 0 {
+                        return Err(NestError::ConnectionError(
+                            "FixedTotalNumber requires non-empty populations".into()
+                        ));
+                    }
+                    return Ok(());
+                }
+
+                if !validator.allow_multapses {
+                    let possible: usize = sources.ids.iter()
+                        .map(|&src| validator.available(src, &targets.ids))
+                        .sum();
+                    if n > possible {
+                        return Err(NestError::ConnectionError(format!(
+                            "FixedTotalNumber: {} connections requested but only {} possible without multapses",
+                            n, possible
+                        )));
+                    }
+                }
+
+                let mut made = 0;
+                let mut attempts = 0;
+                let max_attempts = 1000 * n.max(1);
+                while made < n {
+                    if attempts >= max_attempts {
+                        return Err(NestError::ConnectionError(
+                            "FixedTotalNumber: could not draw enough valid pairs".into()
+                        ));
                     }
+                    attempts += 1;
 
-                    let mut hasher = DefaultHasher::new();
-                    (src, tgt, kernel.time as u64).hash(&mut hasher);
-                    let hash = hasher.finish();
-                    let r = (hash as f64) / (u64::MAX as f64);
-
-                    if r < p {
-                        let weight = sample_weight(&spec.weight);
-                        let delay = sample_delay(&spec.delay);
-
-                        kernel.connections.push(Connection {
-                            source: src,
-                            target: tgt,
-                            weight,
-                            delay,
-                            synapse_model: spec.synapse_model.clone(),
-                            state: HashMap::new(),
-                        });
+                    let src = sources.ids[self.rng.below(sources.len())];
+                    let tgt = targets.ids[self.rng.below(targets.len())];
+                    if self.try_connect(src, tgt, spec, validator)? {
+                        made += 1;
+                    }
+                }
+            }
+
+            ConnectivityRule::PairwiseBernoulli { p } => {
+                for &src in &sources.ids {
+                    for &tgt in &targets.ids {
+                        if validator.admits(src, tgt) && self.rng.uniform() < p {
+                            self.try_connect(src, tgt, spec, validator)?;
+                        }
+                    }
+                }
+            }
+
+            ConnectivityRule::SymmetricPairwiseBernoulli { p } => {
+                // One draw per unordered pair, creating both directions
+                let mut drawn = HashSet::new();
+                for &src in &sources.ids {
+                    for &tgt in &targets.ids {
+                        let pair = (src.min(tgt), src.max(tgt));
+                        if !validator.admits(src, tgt) || !drawn.insert(pair) {
+                            continue;
+                        }
+                        if self.rng.uniform() < p {
+                            self.try_connect(src, tgt, spec, validator)?;
+                            self.try_connect(tgt, src, spec, validator)?;
+                        }
                     }
                 }
             }
         }
 
-        _ => {
-            // Other rules would require more complex implementation
+        Ok(())
+    }
+
+    /// Validate and create a single connection.
+    ///
+    /// Returns `Ok(false)` if the pair was filtered out (autapse/multapse).
+    fn try_connect(
+        &mut self,
+        src: NodeId,
+        tgt: NodeId,
+        spec: &ConnectionSpec,
+        validator: &mut ConnectionValidator,
+    ) -> Result<bool> {
+        if !validator.admits(src, tgt) {
+            return Ok(false);
         }
+
+        let weight = sample_weight(&spec.weight);
+        let delay = sample_delay(&spec.delay);
+        validator.check_delay(delay)?;
+
+        validator.made.insert((src, tgt));
+        self.connections.push(Connection {
+            source: src,
+            target: tgt,
+            weight,
+            delay,
+            synapse_model: spec.synapse_model.clone(),
+            state: HashMap::new(),
+        });
+
+        Ok(true)
     }
 
-    Ok(())
+    /// Draw `k` partners for `node` from `pool` (used by fixed in/outdegree)
+    fn draw_partners(
+        &mut self,
+        node: NodeId,
+        pool: &[NodeId],
+        k: usize,
+        validator: &ConnectionValidator,
+    ) -> Result<Vec<NodeId>> {
+        let available = validator.available(node, pool);
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        if available == 0 || (!validator.allow_multapses && k > available) {
+            return Err(NestError::ConnectionError(format!(
+                "Cannot draw {} partners for node {}: only {} available",
+                k, node, available
+            )));
+        }
+
+        let mut candidates: Vec<NodeId> = pool.iter()
+            .copied()
+            .filter(|&other| validator.allow_autapses || other != node)
+            .collect();
+
+        if validator.allow_multapses {
+            Ok((0..k).map(|_| candidates[self.rng.below(candidates.len())]).collect())
+        } else {
+            candidates.sort_unstable();
+            candidates.dedup();
+            // Partial Fisher-Yates shuffle
+            for i in 0..k {
+                let j = i + self.rng.below(candidates.len() - i);
+                candidates.swap(i, j);
+            }
+            candidates.truncate(k);
+            Ok(candidates)
+        }
+    }
 }
 
 fn sample_weight(dist: &WeightDistribution) -> f64 {
@@ -1204,4 +1415,138 @@ mod tests {
     }
 
     // test_balanced_network_creation disabled - uses global kernel state
+
+    fn kernel_with_nodes(n: usize) -> (Kernel, NodeCollection) {
+        let mut kernel = Kernel::new(KernelParams::default());
+        let nodes = kernel
+            .create(NeuronModel::IafPscAlpha(IafPscAlphaParams::default()), n)
+            .unwrap();
+        (kernel, nodes)
+    }
+
+    fn has_autapse(kernel: &Kernel) -> bool {
+        kernel.connections.iter().any(|c| c.source == c.target)
+    }
+
+    fn has_multapse(kernel: &Kernel) -> bool {
+        let mut seen = HashSet::new();
+        kernel.connections.iter().any(|c| !seen.insert((c.source, c.target)))
+    }
+
+    #[test]
+    fn test_autapses_filtered_for_all_rules() {
+        let rules = vec![
+            ConnectivityRule::AllToAll,
+            ConnectivityRule::OneToOne,
+            ConnectivityRule::FixedIndegree { indegree: 3 },
+            ConnectivityRule::FixedOutdegree { outdegree: 3 },
+            ConnectivityRule::FixedTotalNumber { n: 10 },
+            ConnectivityRule::PairwiseBernoulli { p: 1.0 },
+            ConnectivityRule::SymmetricPairwiseBernoulli { p: 1.0 },
+        ];
+
+        for rule in rules {
+            let (mut kernel, nodes) = kernel_with_nodes(5);
+            kernel.connect(&nodes, &nodes, ConnectionSpec {
+                rule: rule.clone(),
+                allow_multapses: false,
+                ..Default::default()
+            }).unwrap();
+            assert!(!has_autapse(&kernel), "autapse created by {:?}", rule);
+            assert!(!has_multapse(&kernel), "multapse created by {:?}", rule);
+        }
+    }
+
+    #[test]
+    fn test_autapses_allowed() {
+        let (mut kernel, nodes) = kernel_with_nodes(3);
+        kernel.connect(&nodes, &nodes, ConnectionSpec {
+            allow_autapses: true,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(kernel.connections.len(), 9);
+        assert!(has_autapse(&kernel));
+    }
+
+    #[test]
+    fn test_fixed_indegree_counts() {
+        let (mut kernel, nodes) = kernel_with_nodes(10);
+        kernel.connect(&nodes, &nodes, ConnectionSpec {
+            rule: ConnectivityRule::FixedIndegree { indegree: 4 },
+            allow_multapses: false,
+            ..Default::default()
+        }).unwrap();
+
+        for &tgt in &nodes.ids {
+            let indegree = kernel.connections.iter().filter(|c| c.target == tgt).count();
+            assert_eq!(indegree, 4);
+        }
+    }
+
+    #[test]
+    fn test_fixed_indegree_exceeds_pool_without_multapses() {
+        let (mut kernel, nodes) = kernel_with_nodes(3);
+        let result = kernel.connect(&nodes, &nodes, ConnectionSpec {
+            rule: ConnectivityRule::FixedIndegree { indegree: 3 },
+            allow_multapses: false,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(NestError::ConnectionError(_))));
+        assert!(kernel.connections.is_empty());
+    }
+
+    #[test]
+    fn test_fixed_total_number_too_many_pairs() {
+        let (mut kernel, nodes) = kernel_with_nodes(2);
+        let result = kernel.connect(&nodes, &nodes, ConnectionSpec {
+            rule: ConnectivityRule::FixedTotalNumber { n: 3 },
+            allow_multapses: false,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(NestError::ConnectionError(_))));
+    }
+
+    #[test]
+    fn test_unknown_node_rejected() {
+        let (mut kernel, nodes) = kernel_with_nodes(2);
+        let missing = NodeCollection::new(vec![42]);
+        let result = kernel.connect(&nodes, &missing, ConnectionSpec::default());
+        assert!(matches!(result, Err(NestError::ConnectionError(_))));
+        assert!(kernel.connections.is_empty());
+    }
+
+    #[test]
+    fn test_delay_bounds() {
+        let (mut kernel, nodes) = kernel_with_nodes(2);
+
+        let too_short = kernel.connect(&nodes, &nodes, ConnectionSpec {
+            delay: DelayDistribution::Constant(0.01),
+            ..Default::default()
+        });
+        assert!(matches!(too_short, Err(NestError::ConnectionError(_))));
+
+        let too_long = kernel.connect(&nodes, &nodes, ConnectionSpec {
+            delay: DelayDistribution::Constant(500.0),
+            ..Default::default()
+        });
+        assert!(matches!(too_long, Err(NestError::ConnectionError(_))));
+        assert!(kernel.connections.is_empty());
+
+        // Boundaries are inclusive
+        kernel.connect(&nodes, &nodes, ConnectionSpec {
+            delay: DelayDistribution::Constant(kernel.params.min_delay),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(kernel.connections.len(), 2);
+    }
+
+    #[test]
+    fn test_one_to_one_size_mismatch() {
+        let (mut kernel, nodes) = kernel_with_nodes(3);
+        let result = kernel.connect(&nodes, &nodes.slice(0, 2), ConnectionSpec {
+            rule: ConnectivityRule::OneToOne,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(NestError::ConnectionError(_))));
+    }
 }
@@ -0,0 +1,81 @@
+//! One thread vs. several, and the SoA engine, on a 1000-cell network
+//!
+//! Run with `cargo bench -p oldies-neuron`.
+
+use oldies_core::Rng;
+use oldies_neuron::netcon::{NetCon, NetSource, NetTarget};
+use oldies_neuron::soa::Engine;
+use oldies_neuron::{mechanisms, NeuronCell, NeuronSimulation};
+use std::time::Instant;
+
+const CELLS: usize = 1000;
+const INPUTS: usize = 10;
+const TSTOP: f64 = 50.0;
+
+/// Ball-and-stick HH cells, each receiving `INPUTS` random connections
+/// of 1-5 ms delay, a tenth of them driven by a current pulse
+fn network(threads: usize, engine: Engine) -> NeuronSimulation {
+    let mut sim = NeuronSimulation::new();
+    for i in 0..CELLS {
+        let mut cell = NeuronCell::new(&format!("cell{}", i));
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::hh());
+        let dend = cell.create("dend");
+        dend.length = 200.0;
+        dend.set_nseg(5);
+        dend.insert(mechanisms::pas());
+        cell.connect("dend", 0.0, "soma", 1.0).expect("connect");
+        cell.add_point_process(mechanisms::exp_syn("dend", 0.5));
+        if i % 10 == 0 {
+            cell.add_point_process(mechanisms::iclamp("soma", 0.5, 1.0, 1.0, 1.0));
+        }
+        sim.add_cell(cell);
+    }
+    // Seeded, for the same network every run
+    let mut rng = Rng::new(1);
+    for target in 0..CELLS {
+        for _ in 0..INPUTS {
+            let source = NetSource::Voltage { cell: rng.below(CELLS), section: "soma".into(), loc: 0.5 };
+            let mut nc = NetCon::new(source, Some(NetTarget::PointProcess { cell: target, index: 0 }));
+            nc.weight = 0.002;
+            nc.delay = 1.0 + rng.below(5) as f64;
+            nc.threshold = 0.0;
+            sim.add_netcon(nc);
+        }
+    }
+    sim.threads = threads;
+    sim.set_engine(engine);
+    sim.tstop = TSTOP;
+    sim
+}
+
+/// Wall time (s) and spike count of a run
+fn run(threads: usize, engine: Engine) -> (f64, usize) {
+    let mut sim = network(threads, engine);
+    sim.finitialize(-65.0).expect("finitialize");
+    let start = Instant::now();
+    sim.run().expect("run");
+    let spikes = sim.netcons.iter().step_by(INPUTS).map(|nc| nc.spikes.len()).sum();
+    (start.elapsed().as_secs_f64(), spikes)
+}
+
+fn main() {
+    // At least two threads, to compare with one even on a single core, where
+    // the extra threads only share it and cannot show scaling
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} cells, {} connections each, {} ms, available cores: {}", CELLS, INPUTS, TSTOP, cores);
+    println!("{:<8} {:>10} {:>8} {:>9}", "threads", "seconds", "spikes", "speedup");
+    let (serial, spikes) = run(1, Engine::Cells);
+    println!("{:<8} {:>10.3} {:>8} {:>8.2}x", 1, serial, spikes, 1.0);
+    let mut threads = 2;
+    while threads <= cores.max(2) {
+        let (time, spikes) = run(threads, Engine::Cells);
+        let note = if threads > cores { "  (more threads than cores)" } else { "" };
+        println!("{:<8} {:>10.3} {:>8} {:>8.2}x{}", threads, time, spikes, serial / time, note);
+        threads *= 2;
+    }
+    let (time, spikes) = run(1, Engine::Soa);
+    println!("{:<8} {:>10.3} {:>8} {:>8.2}x", "soa", time, spikes, serial / time);
+}
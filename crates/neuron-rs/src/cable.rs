@@ -0,0 +1,1370 @@
+//! Cable equation solver
+//!
+//! Every segment of a cell is a node of a tree. Nodes are numbered so that
+//! each parent comes before its children (Hines ordering): sections are
+//! visited depth first from the roots, and a child section hangs off the
+//! segment of its parent that contains the connection point. The node
+//! voltages obey
+//!
+//! ```text
+//! C_i dv_i/dt = -I_mem,i(v_i) + sum_j g_ij (v_j - v_i) + I_inj,i
+//! ```
+//!
+//! which is advanced with Crank-Nicolson after linearizing the membrane
+//! currents about the current voltage (their slope is found numerically,
+//! as NEURON does). The matrix of such a tree is tridiagonal with branches
+//! and is solved exactly in linear time by eliminating leaves towards the
+//! root. Gating variables are then advanced with exponential Euler at the
+//! new voltage.
+//!
+//! Sections with the `extracellular` mechanism add a layer outside the
+//! membrane: `v` stays the membrane potential and the internal potential
+//! is `v + vext`. The layer has its own axial resistance (`xraxial`,
+//! MOhm/cm), a conductance (`xg`, S/cm^2) and capacitance (`xc`, uF/cm^2)
+//! to `e_extracellular`. Both layers are solved together, each node of
+//! the tree holding its internal and extracellular potentials.
+//!
+//! Ions used by NMODL mechanisms are shared within a section: the total
+//! current of each ion is summed over its writers, and concentrations
+//! written by accumulation mechanisms set the reversal potential through
+//! the Nernst equation every step.
+//!
+//! Internal units: mV, ms, nF, nA, uS. Densities follow NEURON: uF/cm^2
+//! for `cm`, S/cm^2 for conductances, ohm-cm for `Ra`.
+
+use crate::mechanism::{ion_default, MechanismLibrary};
+use crate::{InsertedMechanism, NeuronCell, PointProcess, Section};
+use oldies_core::{Current, OldiesError, Result, Rng, Time, Voltage};
+use std::collections::HashMap;
+
+/// Voltage step used to measure the slope of membrane currents (mV)
+const SLOPE_STEP: f64 = 0.001;
+
+/// Segments of a cell in Hines order
+#[derive(Debug, Clone)]
+pub struct CableTree {
+    /// (section, segment) of each node
+    pub nodes: Vec<(String, usize)>,
+    /// Parent of each node (`None` for roots)
+    pub parent: Vec<Option<usize>>,
+    /// Axial conductance to the parent (uS)
+    pub g_axial: Vec<f64>,
+    /// Membrane area (cm^2)
+    pub area: Vec<f64>,
+    /// Membrane capacitance (nF)
+    pub capacitance: Vec<f64>,
+    /// Node of each (section, segment)
+    index: HashMap<(String, usize), usize>,
+}
+
+impl CableTree {
+    /// Number the segments of `cell`
+    pub fn new(cell: &NeuronCell) -> Result<Self> {
+        let mut tree = Self {
+            nodes: vec![],
+            parent: vec![],
+            g_axial: vec![],
+            area: vec![],
+            capacitance: vec![],
+            index: HashMap::new(),
+        };
+
+        let mut roots: Vec<&Section> = cell.sections.values().filter(|s| s.parent.is_none()).collect();
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut stack: Vec<&Section> = roots.into_iter().rev().collect();
+        while let Some(sec) = stack.pop() {
+            if sec.nseg == 0 || !(sec.length > 0.0 && sec.diam > 0.0) {
+                return Err(OldiesError::SimulationError(format!(
+                    "Section {} needs nseg >= 1 and positive length and diam",
+                    sec.name
+                )));
+            }
+            if tree.index.contains_key(&(sec.name.clone(), 0)) {
+                return Err(OldiesError::SimulationError(format!(
+                    "Section {} is connected more than once",
+                    sec.name
+                )));
+            }
+            tree.add_section(cell, sec)?;
+            for child in sec.children.iter().rev() {
+                let child = cell.sections.get(child).ok_or_else(|| {
+                    OldiesError::ModelNotFound(format!("Section {} not found", child))
+                })?;
+                if child.parent.as_ref().is_some_and(|(p, _)| p == &sec.name) {
+                    stack.push(child);
+                }
+            }
+        }
+
+        if tree.index.len() != cell.total_segments() {
+            return Err(OldiesError::SimulationError(
+                "Sections form a loop or name a missing parent".into(),
+            ));
+        }
+        Ok(tree)
+    }
+
+    fn add_section(&mut self, cell: &NeuronCell, sec: &Section) -> Result<()> {
+        let n = sec.nseg as f64;
+        let center = |k: usize| (k as f64 + 0.5) / n;
+
+        // Link to the parent segment containing the connection point
+        let mut previous = match &sec.parent {
+            Some((parent, loc)) => {
+                let p = &cell.sections[parent];
+                let k = p.segment(*loc);
+                let end = sec.connection_end;
+                let r = sec.axial_resistance(end, (end - 0.5 / n).abs())
+                    + p.axial_resistance(*loc, center(k));
+                Some((self.index[&(parent.clone(), k)], 1.0 / r))
+            }
+            None => None,
+        };
+
+        // Segments are numbered starting at the end attached to the parent
+        let order: Vec<usize> = if sec.connection_end == 1.0 {
+            (0..sec.nseg).rev().collect()
+        } else {
+            (0..sec.nseg).collect()
+        };
+        let mut last = None;
+        for k in order {
+            if let Some(j) = last {
+                previous = Some((self.nodes.len() - 1, 1.0 / sec.axial_resistance(center(j), center(k))));
+            }
+            let node = self.nodes.len();
+            let area = sec.segment_area(k);
+            self.nodes.push((sec.name.clone(), k));
+            self.parent.push(previous.map(|(p, _)| p));
+            self.g_axial.push(previous.map_or(0.0, |(_, g)| g));
+            self.area.push(area);
+            self.capacitance.push(sec.cm * area * 1e3);
+            self.index.insert((sec.name.clone(), k), node);
+            last = Some(k);
+        }
+        Ok(())
+    }
+
+    /// Node of segment `k` of `section`
+    pub fn node(&self, section: &str, k: usize) -> Option<usize> {
+        self.index.get(&(section.to_string(), k)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Solve `A x = rhs` in place, where `A` has diagonal `d` and the
+    /// symmetric entry `a[i]` between each node and its parent
+    pub fn solve(&self, d: &mut [f64], a: &[f64], rhs: &mut [f64]) {
+        for i in (0..self.len()).rev() {
+            if let Some(p) = self.parent[i] {
+                let f = a[i] / d[i];
+                d[p] -= f * a[i];
+                rhs[p] -= f * rhs[i];
+            }
+        }
+        for i in 0..self.len() {
+            if let Some(p) = self.parent[i] {
+                rhs[i] -= a[i] * rhs[p];
+            }
+            rhs[i] /= d[i];
+        }
+    }
+}
+
+// =============================================================================
+// MEMBRANE MECHANISMS
+// =============================================================================
+
+/// `x / (exp(x / y) - 1)`, continuous at `x = 0`
+fn vtrap(x: f64, y: f64) -> f64 {
+    if (x / y).abs() < 1e-6 {
+        y * (1.0 - x / y / 2.0)
+    } else {
+        x / ((x / y).exp() - 1.0)
+    }
+}
+
+/// Hodgkin-Huxley gate: (steady state, time constant in ms) at `v`
+pub(crate) fn hh_gate(gate: &str, v: Voltage, celsius: f64) -> (f64, f64) {
+    let (alpha, beta) = match gate {
+        "m" => (0.1 * vtrap(-(v + 40.0), 10.0), 4.0 * (-(v + 65.0) / 18.0).exp()),
+        "h" => (0.07 * (-(v + 65.0) / 20.0).exp(), 1.0 / ((-(v + 35.0) / 10.0).exp() + 1.0)),
+        _ => (0.01 * vtrap(-(v + 55.0), 10.0), 0.125 * (-(v + 65.0) / 80.0).exp()),
+    };
+    let q10 = 3f64.powf((celsius - 6.3) / 10.0);
+    (alpha / (alpha + beta), 1.0 / (q10 * (alpha + beta)))
+}
+
+/// Gating variables of a built-in mechanism
+fn gates(mech: &InsertedMechanism) -> Result<&'static [&'static str]> {
+    match mech.name.as_str() {
+        "hh" => Ok(&["m", "h", "n"]),
+        "na" => Ok(&["m", "h"]),
+        "k" => Ok(&["n"]),
+        "pas" | "extracellular" | "rxd" => Ok(&[]),
+        name if name.ends_with("_ion") => Ok(&[]),
+        name => Err(OldiesError::SimulationError(format!("Unknown mechanism: {}", name))),
+    }
+}
+
+fn parameter(mech: &InsertedMechanism, name: &str, k: usize) -> Result<f64> {
+    mech.parameter(name, k).ok_or_else(|| {
+        OldiesError::SimulationError(format!("Mechanism {} lacks parameter {}", mech.name, name))
+    })
+}
+
+/// Range variables of a built-in mechanism in segment `k` at `v`:
+/// conductances (S/cm^2) and outward currents (mA/cm^2, named `i...`)
+fn assigned(mech: &InsertedMechanism, k: usize, v: Voltage) -> Result<Vec<(&'static str, f64)>> {
+    let state = |gate: &str| mech.state[gate][k];
+    Ok(match mech.name.as_str() {
+        "hh" => {
+            let gna = parameter(mech, "gnabar", k)? * state("m").powi(3) * state("h");
+            let gk = parameter(mech, "gkbar", k)? * state("n").powi(4);
+            vec![
+                ("gna", gna),
+                ("gk", gk),
+                ("ina", gna * (v - parameter(mech, "ena", k)?)),
+                ("ik", gk * (v - parameter(mech, "ek", k)?)),
+                ("il", parameter(mech, "gl", k)? * (v - parameter(mech, "el", k)?)),
+            ]
+        }
+        "na" => {
+            let gna = parameter(mech, "gnabar", k)? * state("m").powi(3) * state("h");
+            vec![("gna", gna), ("ina", gna * (v - parameter(mech, "ena", k)?))]
+        }
+        "k" => {
+            let gk = parameter(mech, "gkbar", k)? * state("n").powi(4);
+            vec![("gk", gk), ("ik", gk * (v - parameter(mech, "ek", k)?))]
+        }
+        "pas" => vec![("i", parameter(mech, "g", k)? * (v - parameter(mech, "e", k)?))],
+        "extracellular" | "rxd" => vec![],
+        name if name.ends_with("_ion") => vec![],
+        name => return Err(OldiesError::SimulationError(format!("Unknown mechanism: {}", name))),
+    })
+}
+
+/// Outward current density (mA/cm^2) of segment `k` at `v`
+pub(crate) fn current_density(mech: &InsertedMechanism, k: usize, v: Voltage, celsius: f64, library: &MechanismLibrary) -> Result<f64> {
+    if let Some(compiled) = library.get(&mech.name) {
+        return Ok(compiled.current(mech, k, v, celsius));
+    }
+    Ok(assigned(mech, k, v)?.iter().filter(|(name, _)| name.starts_with('i')).map(|(_, i)| i).sum())
+}
+
+/// Store the range variables of the built-in mechanisms of `cell` next to
+/// their gates, so that `gna`, `ina`, `i` and the like can be read, and
+/// the current `i` (nA, into the cell) and command `vc` of clamps at `t`
+pub(crate) fn update_assigned(cell: &mut NeuronCell, t: Time, library: &MechanismLibrary) -> Result<()> {
+    for pp in &mut cell.point_processes {
+        if !matches!(pp.name.as_str(), "IClamp" | "IRamp" | "ISine" | "INoise" | "IWave") {
+            continue;
+        }
+        if pp.name == "INoise" {
+            advance_noise(pp, t)?;
+        }
+        let i = stimulus_current(pp, t)?;
+        pp.state.insert("i".into(), i);
+    }
+    for pp in cell.point_processes.iter_mut().filter(|pp| matches!(pp.name.as_str(), "SEClamp" | "VClamp")) {
+        let sec = cell.sections.get(&pp.section).ok_or_else(|| {
+            OldiesError::ModelNotFound(format!("Section {} not found", pp.section))
+        })?;
+        let (_, g, e) = point_current(pp, t)?;
+        pp.state.insert("i".into(), g * (e - sec.v_at(pp.location)));
+        pp.state.insert("vc".into(), e);
+    }
+    for sec in cell.sections.values_mut() {
+        for mech in sec.mechanisms.iter_mut().filter(|m| !library.contains_key(&m.name)) {
+            let mut values: Vec<(&str, Vec<f64>)> = vec![];
+            for (k, &v) in sec.v.iter().enumerate() {
+                for (j, (name, x)) in assigned(mech, k, v)?.into_iter().enumerate() {
+                    if k == 0 {
+                        values.push((name, vec![]));
+                    }
+                    values[j].1.push(x);
+                }
+            }
+            for (name, x) in values {
+                mech.state.insert(name.to_string(), x);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set the gates of every segment to their steady state at `v`
+fn initialize_mechanism(mech: &mut InsertedMechanism, v: &[Voltage], celsius: f64, library: &MechanismLibrary) -> Result<()> {
+    if let Some(compiled) = library.get(&mech.name) {
+        compiled.initialize(mech, v, celsius);
+        return Ok(());
+    }
+    for gate in gates(mech)? {
+        let values = v.iter().map(|&v| hh_gate(gate, v, celsius).0).collect();
+        mech.state.insert(gate.to_string(), values);
+    }
+    Ok(())
+}
+
+/// State variables of a mechanism, in the order of [`state_rates`]
+pub(crate) fn state_names(mech: &InsertedMechanism, library: &MechanismLibrary) -> Result<Vec<String>> {
+    match library.get(&mech.name) {
+        Some(compiled) => Ok(compiled.states.clone()),
+        None => Ok(gates(mech)?.iter().map(|g| g.to_string()).collect()),
+    }
+}
+
+/// Time derivative of each state of segment `k` at `v`, with its partial
+/// derivative with respect to the state
+pub(crate) fn state_rates(
+    mech: &InsertedMechanism,
+    k: usize,
+    v: Voltage,
+    celsius: f64,
+    library: &MechanismLibrary,
+) -> Result<Vec<(f64, f64)>> {
+    if let Some(compiled) = library.get(&mech.name) {
+        return Ok(compiled.rates(mech, k, v, celsius));
+    }
+    Ok(gates(mech)?.iter().map(|gate| {
+        let (inf, tau) = hh_gate(gate, v, celsius);
+        ((inf - mech.state[*gate][k]) / tau, -1.0 / tau)
+    }).collect())
+}
+
+/// Relax the gates towards their steady state at `v` over `dt`
+fn advance_mechanism(
+    mech: &mut InsertedMechanism,
+    v: &[Voltage],
+    dt: Time,
+    celsius: f64,
+    library: &MechanismLibrary,
+) -> Result<()> {
+    if let Some(compiled) = library.get(&mech.name) {
+        compiled.advance(mech, v, dt, celsius);
+        return Ok(());
+    }
+    for gate in gates(mech)? {
+        let values = mech.state.get_mut(*gate).unwrap();
+        for (x, &v) in values.iter_mut().zip(v) {
+            let (inf, tau) = hh_gate(gate, v, celsius);
+            *x = inf + (*x - inf) * (-dt / tau).exp();
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// POINT PROCESSES
+// =============================================================================
+
+fn pp_parameter(pp: &PointProcess, name: &str) -> Result<f64> {
+    pp.parameters.get(name).copied().ok_or_else(|| {
+        OldiesError::SimulationError(format!("{} lacks parameter {}", pp.name, name))
+    })
+}
+
+/// First index of the `dur`/`amp` levels of a clamp: SEClamp counts
+/// from 1 and VClamp from 0, as in NEURON
+fn clamp_levels(pp: &PointProcess) -> std::ops::Range<usize> {
+    if pp.name == "SEClamp" { 1..4 } else { 0..3 }
+}
+
+/// Command potential of a clamp at `t`, or `None` after its last level
+fn clamp_command(pp: &PointProcess, t: Time) -> Result<Option<Voltage>> {
+    let mut end = 0.0;
+    for j in clamp_levels(pp) {
+        end += pp_parameter(pp, &format!("dur{}", j))?;
+        if t < end {
+            return Ok(Some(pp_parameter(pp, &format!("amp{}", j))?));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a stimulus with `delay` and `dur` is on at `t`
+fn stimulus_on(pp: &PointProcess, t: Time) -> Result<bool> {
+    let delay = pp_parameter(pp, "delay")?;
+    Ok(t >= delay && t < delay + pp_parameter(pp, "dur")?)
+}
+
+/// (time, current) points of an IWave
+fn wave_points(pp: &PointProcess) -> Result<Vec<(Time, Current)>> {
+    (0..pp_parameter(pp, "n")? as usize)
+        .map(|j| Ok((pp_parameter(pp, &format!("t{}", j))?, pp_parameter(pp, &format!("amp{}", j))?)))
+        .collect()
+}
+
+/// Current (nA) injected by a current clamp at `t`
+fn stimulus_current(pp: &PointProcess, t: Time) -> Result<Current> {
+    if pp.name == "IWave" {
+        let points = wave_points(pp)?;
+        let i = points.partition_point(|p| p.0 <= t);
+        return Ok(match (i.checked_sub(1).map(|i| points[i]), points.get(i)) {
+            (Some((t0, a0)), Some(&(t1, a1))) => {
+                if t1 > t0 { a0 + (a1 - a0) * (t - t0) / (t1 - t0) } else { a1 }
+            }
+            _ => 0.0,
+        });
+    }
+    if !stimulus_on(pp, t)? {
+        return Ok(0.0);
+    }
+    let since = t - pp_parameter(pp, "delay")?;
+    Ok(match pp.name.as_str() {
+        "IRamp" => {
+            let (amp0, amp1) = (pp_parameter(pp, "amp0")?, pp_parameter(pp, "amp1")?);
+            amp0 + (amp1 - amp0) * since / pp_parameter(pp, "dur")?
+        }
+        "ISine" => {
+            let phase = 2.0 * std::f64::consts::PI * pp_parameter(pp, "freq")? * since / 1000.0;
+            pp_parameter(pp, "offset")? + pp_parameter(pp, "amp")? * (phase + pp_parameter(pp, "phase")?).sin()
+        }
+        "INoise" => pp.state.get("x").copied().unwrap_or(0.0),
+        _ => pp_parameter(pp, "amp")?,
+    })
+}
+
+/// Generator seeded with `seed` about to draw its normal deviate `k`, so
+/// that any part of a sample path can be drawn again
+fn noise_stream(seed: f64, k: u64) -> Rng {
+    // Each pair of deviates takes two uniforms
+    let mut rng = Rng::new(seed as u64);
+    rng.advance(k / 2 * 2);
+    if k % 2 == 1 {
+        rng.normal();
+    }
+    rng
+}
+
+/// Interval of an INoise containing `t`, counting from 1
+fn noise_interval(pp: &PointProcess, t: Time) -> Result<u64> {
+    let interval = pp_parameter(pp, "interval")?;
+    if interval <= 0.0 {
+        return Err(OldiesError::SimulationError("INoise interval must be positive".into()));
+    }
+    Ok(((t - pp_parameter(pp, "delay")?) / interval + 1e-9).floor() as u64 + 1)
+}
+
+/// Advance the Ornstein-Uhlenbeck process of an INoise to the interval
+/// containing `t`. It starts from its stationary distribution and takes
+/// exact steps, `x = mean + (x - mean) a + std sqrt(1 - a^2) N(0, 1)`
+/// with `a = exp(-interval / tau)`.
+fn advance_noise(pp: &mut PointProcess, t: Time) -> Result<()> {
+    if !stimulus_on(pp, t)? {
+        return Ok(());
+    }
+    let target = noise_interval(pp, t)?;
+    let (mean, std, seed) = (pp_parameter(pp, "mean")?, pp_parameter(pp, "std")?, pp_parameter(pp, "seed")?);
+    let a = (-pp_parameter(pp, "interval")? / pp_parameter(pp, "tau")?).exp();
+    // Interval 0 is not started, as after initialization
+    let mut k = pp.state.get("k").copied().unwrap_or(0.0) as u64;
+    let mut x = pp.state.get("x").copied().unwrap_or(0.0);
+    if k == 0 || k > target {
+        k = 0;
+    }
+    let mut rng = noise_stream(seed, k);
+    if k == 0 {
+        k = 1;
+        x = mean + std * rng.normal();
+    }
+    while k < target {
+        x = mean + (x - mean) * a + std * (1.0 - a * a).sqrt() * rng.normal();
+        k += 1;
+    }
+    pp.state.insert("k".into(), k as f64);
+    pp.state.insert("x".into(), x);
+    Ok(())
+}
+
+/// Injected current (nA), conductance (uS) and reversal potential (mV) of
+/// a point process at `t`. The current through the conductance,
+/// `g * (v - e)`, is outward.
+fn point_current(pp: &PointProcess, t: Time) -> Result<(Current, f64, Voltage)> {
+    let state = |name: &str| pp.state.get(name).copied().unwrap_or(0.0);
+    Ok(match pp.name.as_str() {
+        "IClamp" | "IRamp" | "ISine" | "INoise" | "IWave" => (stimulus_current(pp, t)?, 0.0, 0.0),
+        "APCount" => (0.0, 0.0, 0.0),
+        "ExpSyn" => (0.0, state("g"), pp_parameter(pp, "e")?),
+        "Exp2Syn" => (0.0, state("B") - state("A"), pp_parameter(pp, "e")?),
+        // The electrode pulls v towards the command through the series
+        // resistance; VClamp's amplifier acts as rstim / gain
+        "SEClamp" | "VClamp" => match clamp_command(pp, t)? {
+            Some(vc) => {
+                let g = if pp.name == "SEClamp" {
+                    1.0 / pp_parameter(pp, "rs")?
+                } else {
+                    pp_parameter(pp, "gain")? / pp_parameter(pp, "rstim")?
+                };
+                (0.0, g, vc)
+            }
+            None => (0.0, 0.0, 0.0),
+        },
+        name => return Err(OldiesError::SimulationError(format!("Unknown point process: {}", name))),
+    })
+}
+
+/// Check the APCounts of `cell` at the end of a step to `t`, returning
+/// those that counted a spike. As in NEURON, a spike counts once the
+/// potential reaches `thresh`, and the next once it has fallen below.
+pub(crate) fn count_spikes(cell: &mut NeuronCell, t: Time) -> Result<Vec<usize>> {
+    let mut counted = vec![];
+    for (j, pp) in cell.point_processes.iter_mut().enumerate().filter(|(_, pp)| pp.name == "APCount") {
+        let v = cell.sections.get(&pp.section)
+            .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", pp.section)))?
+            .v_at(pp.location);
+        let thresh = pp_parameter(pp, "thresh")?;
+        let firing = pp.state.get("firing").is_some_and(|&f| f != 0.0);
+        if v >= thresh && !firing {
+            *pp.state.entry("n".into()).or_insert(0.0) += 1.0;
+            pp.state.insert("time".into(), t);
+            pp.state.insert("firing".into(), 1.0);
+            counted.push(j);
+        } else if v < thresh && firing {
+            pp.state.insert("firing".into(), 0.0);
+        }
+    }
+    Ok(counted)
+}
+
+/// Decaying states of a point process and their time constants (ms)
+pub(crate) fn point_decays(pp: &PointProcess) -> Result<Vec<(&'static str, f64)>> {
+    Ok(match pp.name.as_str() {
+        "ExpSyn" => vec![("g", pp_parameter(pp, "tau")?)],
+        "Exp2Syn" => vec![("A", pp_parameter(pp, "tau1")?), ("B", pp_parameter(pp, "tau2")?)],
+        _ => vec![],
+    })
+}
+
+/// Deliver a network event of `weight` (uS) to a synapse (NET_RECEIVE)
+pub(crate) fn net_receive(pp: &mut PointProcess, weight: f64) -> Result<()> {
+    match pp.name.as_str() {
+        "ExpSyn" => *pp.state.entry("g".into()).or_insert(0.0) += weight,
+        "Exp2Syn" => {
+            // Normalise so that a single event peaks at `weight`
+            let (tau1, tau2) = (pp_parameter(pp, "tau1")?, pp_parameter(pp, "tau2")?);
+            let tp = tau1 * tau2 / (tau2 - tau1) * (tau2 / tau1).ln();
+            let factor = 1.0 / ((-tp / tau2).exp() - (-tp / tau1).exp());
+            *pp.state.entry("A".into()).or_insert(0.0) += weight * factor;
+            *pp.state.entry("B".into()).or_insert(0.0) += weight * factor;
+        }
+        name => return Err(OldiesError::SimulationError(format!("{} cannot receive events", name))),
+    }
+    Ok(())
+}
+
+/// Times at which stimuli switch on or off or change course, of noise
+/// only the next after `t`
+pub(crate) fn discontinuities(cell: &NeuronCell, t: Time) -> Result<Vec<Time>> {
+    let mut times = vec![];
+    for pp in &cell.point_processes {
+        match pp.name.as_str() {
+            "IClamp" | "IRamp" | "ISine" | "INoise" => {
+                let delay = pp_parameter(pp, "delay")?;
+                times.push(delay);
+                times.push(delay + pp_parameter(pp, "dur")?);
+                if pp.name == "INoise" && stimulus_on(pp, t)? {
+                    times.push(delay + noise_interval(pp, t)? as f64 * pp_parameter(pp, "interval")?);
+                }
+            }
+            "IWave" => times.extend(wave_points(pp)?.iter().map(|p| p.0)),
+            "SEClamp" | "VClamp" => {
+                let mut end = 0.0;
+                for j in clamp_levels(pp) {
+                    end += pp_parameter(pp, &format!("dur{}", j))?;
+                    times.push(end);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(times)
+}
+
+/// Decay synaptic conductances over `dt`
+fn advance_point(pp: &mut PointProcess, dt: Time) -> Result<()> {
+    for (name, tau) in point_decays(pp)? {
+        if let Some(x) = pp.state.get_mut(name) {
+            *x *= (-dt / tau).exp();
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// IONS
+// =============================================================================
+
+/// Faraday constant (C/mol)
+const FARADAY: f64 = 96485.33212;
+
+/// Gas constant (J/K/mol)
+const GAS_CONSTANT: f64 = 8.314462618;
+
+/// Charge of an ion whose USEION declares no VALENCE
+fn default_valence(ion: &str) -> i32 {
+    match ion {
+        "ca" => 2,
+        "cl" => -1,
+        _ => 1,
+    }
+}
+
+/// Nernst potential (mV) of an ion of valence `z` between the
+/// concentrations `ci` inside and `co` outside (mM)
+pub fn nernst(ci: f64, co: f64, z: i32, celsius: f64) -> Voltage {
+    1e3 * GAS_CONSTANT * (celsius + 273.15) / (z as f64 * FARADAY) * (co / ci).ln()
+}
+
+/// Bring the ions used by compiled mechanisms up to date, as NEURON's
+/// `<ion>_ion` mechanisms do. In every section the total current of an
+/// ion `x` is summed over the mechanisms that write `ix`. Where some
+/// mechanism writes a concentration (`xi` or `xo`), the reversal
+/// potential `ex` follows the concentrations by the Nernst equation; so
+/// does it where the concentration is a reaction-diffusion species.
+/// These values, per segment, replace the parameters of the mechanisms
+/// that read them and are recorded in the states of a mechanism `x_ion`.
+pub(crate) fn update_ions(cell: &mut NeuronCell, celsius: f64, library: &MechanismLibrary) -> Result<()> {
+    for sec in cell.sections.values_mut() {
+        let mut ions: Vec<(String, i32)> = vec![];
+        for compiled in sec.mechanisms.iter().filter_map(|m| library.get(&m.name)) {
+            for use_ion in &compiled.ions {
+                if !ions.iter().any(|(ion, _)| *ion == use_ion.ion) {
+                    let z = use_ion.valence.unwrap_or_else(|| default_valence(&use_ion.ion));
+                    ions.push((use_ion.ion.clone(), z));
+                }
+            }
+        }
+
+        // Hand ion variables to the mechanisms that read them
+        let share = |mechanisms: &mut Vec<InsertedMechanism>, values: &[(String, Vec<f64>)]| {
+            for mech in mechanisms.iter_mut() {
+                let Some(compiled) = library.get(&mech.name) else { continue };
+                for (name, x) in values {
+                    if compiled.ions.iter().any(|u| u.read.contains(name) && !u.write.contains(name)) {
+                        mech.state.insert(name.clone(), x.clone());
+                    }
+                }
+            }
+        };
+        let nseg = sec.nseg;
+        for (ion, z) in ions {
+            let (ci, co, e, i) = (format!("{}i", ion), format!("{}o", ion), format!("e{}", ion), format!("i{}", ion));
+            let writes = |mech: &InsertedMechanism, var: &str| {
+                library.get(&mech.name).is_some_and(|c| c.ions.iter().any(|u| u.write.iter().any(|w| w == var)))
+            };
+            // Written by a mechanism, or held by reaction-diffusion
+            let concentration = |var: &str| {
+                sec.mechanisms.iter()
+                    .find(|m| writes(m, var) || (m.name == "rxd" && m.state.contains_key(var)))
+                    .map(|m| m.state.get(var).filter(|x| x.len() == nseg).cloned().unwrap_or_else(|| vec![ion_default(var); nseg]))
+            };
+
+            let mut values = vec![];
+            let (inside, outside) = (concentration(&ci), concentration(&co));
+            if inside.is_some() || outside.is_some() {
+                let inside = inside.unwrap_or_else(|| vec![ion_default(&ci); nseg]);
+                let outside = outside.unwrap_or_else(|| vec![ion_default(&co); nseg]);
+                let reversal = inside.iter().zip(&outside).map(|(&a, &b)| nernst(a, b, z, celsius)).collect();
+                values.push((ci.clone(), inside));
+                values.push((co.clone(), outside));
+                values.push((e.clone(), reversal));
+            }
+            share(&mut sec.mechanisms, &values);
+
+            // Currents follow the reversal potential just shared
+            let mut total = vec![0.0; nseg];
+            for mech in &sec.mechanisms {
+                if let Some(compiled) = library.get(&mech.name) {
+                    for (k, &v) in sec.v.iter().enumerate().take(nseg) {
+                        for (name, x) in compiled.ion_currents(mech, k, v, celsius) {
+                            if name == i {
+                                total[k] += x;
+                            }
+                        }
+                    }
+                }
+            }
+            let current = [(i.clone(), total)];
+            share(&mut sec.mechanisms, &current);
+            values.extend(current);
+
+            let record_name = format!("{}_ion", ion);
+            let record = match sec.mechanisms.iter().position(|m| m.name == record_name) {
+                Some(index) => &mut sec.mechanisms[index],
+                None => {
+                    sec.mechanisms.push(InsertedMechanism {
+                        name: record_name,
+                        parameters: HashMap::new(),
+                        state: HashMap::new(),
+                        segment_parameters: HashMap::new(),
+                    });
+                    sec.mechanisms.last_mut().unwrap()
+                }
+            };
+            record.state.extend(values);
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// INTEGRATION
+// =============================================================================
+
+/// The `extracellular` mechanism of a section, if inserted
+fn extracellular(sec: &Section) -> Option<&InsertedMechanism> {
+    sec.mechanisms.iter().find(|m| m.name == "extracellular")
+}
+
+/// Extracellular potential `vext` (mV) of every node, 0 where the layer
+/// is absent
+fn extracellular_potentials(cell: &NeuronCell, tree: &CableTree) -> Vec<Voltage> {
+    tree.nodes
+        .iter()
+        .map(|(name, k)| {
+            extracellular(&cell.sections[name])
+                .and_then(|m| m.state.get("vext"))
+                .and_then(|x| x.get(*k).copied())
+                .unwrap_or(0.0)
+        })
+        .collect()
+}
+
+/// Current (nA) that electrodes inject into each node at `t`, current
+/// clamps and voltage clamps, which does not cross the membrane, and the
+/// conductance (uS) of the voltage clamps
+fn electrode_currents(cell: &NeuronCell, tree: &CableTree, v: &[Voltage], t: Time) -> Result<(Vec<Current>, Vec<f64>)> {
+    let mut current = vec![0.0; tree.len()];
+    let mut conductance = vec![0.0; tree.len()];
+    for pp in &cell.point_processes {
+        let sec = cell.sections.get(&pp.section).ok_or_else(|| {
+            OldiesError::ModelNotFound(format!("Section {} not found", pp.section))
+        })?;
+        let i = tree.node(&pp.section, sec.segment(pp.location)).unwrap();
+        let (injected, g, e) = point_current(pp, t)?;
+        current[i] += injected;
+        if matches!(pp.name.as_str(), "SEClamp" | "VClamp") {
+            current[i] += g * (e - v[i]);
+            conductance[i] += g;
+        }
+    }
+    Ok((current, conductance))
+}
+
+/// Total outward current through the membrane (nA) of every node at `t`:
+/// ionic, synaptic and capacitive. By Kirchhoff's law it equals the axial
+/// current flowing into the node plus the electrode current.
+fn node_transmembrane_currents(cell: &NeuronCell, tree: &CableTree, t: Time) -> Result<Vec<Current>> {
+    let vext = extracellular_potentials(cell, tree);
+    let v: Vec<Voltage> = tree.nodes.iter().map(|(name, k)| cell.sections[name].v[*k]).collect();
+    let (mut current, _) = electrode_currents(cell, tree, &v, t)?;
+    for i in 0..tree.len() {
+        if let Some(p) = tree.parent[i] {
+            let flow = tree.g_axial[i] * (v[p] + vext[p] - v[i] - vext[i]);
+            current[i] += flow;
+            current[p] -= flow;
+        }
+    }
+    Ok(current)
+}
+
+/// Transmembrane current (nA, outward) of every segment of `cell` at `t`,
+/// as (section, segment, current)
+pub fn transmembrane_currents(cell: &NeuronCell, t: Time) -> Result<Vec<(String, usize, Current)>> {
+    let tree = CableTree::new(cell)?;
+    let current = node_transmembrane_currents(cell, &tree, t)?;
+    Ok(tree.nodes.into_iter().zip(current).map(|((name, k), i)| (name, k, i)).collect())
+}
+
+/// Extracellular layer of a node: capacitance (nF) and conductance (uS)
+/// to `e` (mV), and half the axial resistance of the segment (MOhm)
+struct Layer {
+    cx: f64,
+    gx: f64,
+    e: Voltage,
+    half_resistance: f64,
+}
+
+/// Extracellular layer of every node, `None` where it is absent
+fn extracellular_layers(cell: &NeuronCell, tree: &CableTree) -> Result<Vec<Option<Layer>>> {
+    tree.nodes
+        .iter()
+        .enumerate()
+        .map(|(i, (name, k))| {
+            let (sec, k) = (&cell.sections[name], *k);
+            extracellular(sec).map(|mech| -> Result<Layer> {
+                let dx = sec.length / sec.nseg as f64 * 1e-4;
+                Ok(Layer {
+                    cx: parameter(mech, "xc", k)? * tree.area[i] * 1e3,
+                    gx: parameter(mech, "xg", k)? * tree.area[i] * 1e6,
+                    e: parameter(mech, "e_extracellular", k)?,
+                    half_resistance: parameter(mech, "xraxial", k)? * dx / 2.0,
+                })
+            }).transpose()
+        })
+        .collect()
+}
+
+/// Solve a tree system whose nodes hold two unknowns: diagonal blocks `d`
+/// and diagonal blocks `a` between each node and its parent
+fn solve_blocks(tree: &CableTree, d: &mut [[[f64; 2]; 2]], a: &[[f64; 2]], rhs: &mut [[f64; 2]]) {
+    let inverse = |m: [[f64; 2]; 2]| {
+        let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+        [[m[1][1] / det, -m[0][1] / det], [-m[1][0] / det, m[0][0] / det]]
+    };
+    let apply = |m: [[f64; 2]; 2], x: [f64; 2]| [m[0][0] * x[0] + m[0][1] * x[1], m[1][0] * x[0] + m[1][1] * x[1]];
+    for i in (0..tree.len()).rev() {
+        if let Some(p) = tree.parent[i] {
+            let inv = inverse(d[i]);
+            let y = apply(inv, rhs[i]);
+            for r in 0..2 {
+                for c in 0..2 {
+                    d[p][r][c] -= a[i][r] * inv[r][c] * a[i][c];
+                }
+                rhs[p][r] -= a[i][r] * y[r];
+            }
+        }
+    }
+    for i in 0..tree.len() {
+        if let Some(p) = tree.parent[i] {
+            for r in 0..2 {
+                rhs[i][r] -= a[i][r] * rhs[p][r];
+            }
+        }
+        rhs[i] = apply(inverse(d[i]), rhs[i]);
+    }
+}
+
+/// Store `vext` and `i_membrane` (mA/cm^2) of the extracellular layers
+fn store_extracellular(cell: &mut NeuronCell, tree: &CableTree, vext: &[Voltage], t: Time) -> Result<()> {
+    for (i, (name, k)) in tree.nodes.iter().enumerate() {
+        if let Some(mech) = cell.sections.get_mut(name).unwrap().mechanisms.iter_mut().find(|m| m.name == "extracellular") {
+            mech.state.get_mut("vext").unwrap()[*k] = vext[i];
+        }
+    }
+    let i_m = node_transmembrane_currents(cell, tree, t)?;
+    for (i, (name, k)) in tree.nodes.iter().enumerate() {
+        if let Some(mech) = cell.sections.get_mut(name).unwrap().mechanisms.iter_mut().find(|m| m.name == "extracellular") {
+            mech.state.get_mut("i_membrane").unwrap()[*k] = i_m[i] / tree.area[i] * 1e-6;
+        }
+    }
+    Ok(())
+}
+
+/// Set every segment to `v_init` and every gate to its steady state.
+/// Mechanisms found in `library` take precedence over the built-in ones.
+pub fn initialize(cell: &mut NeuronCell, v_init: Voltage, celsius: f64, library: &MechanismLibrary) -> Result<()> {
+    for sec in cell.sections.values_mut() {
+        sec.v = vec![v_init; sec.nseg];
+    }
+    initialize_states(cell, celsius, library)
+}
+
+/// Run the INITIAL blocks of every mechanism at the present voltages and
+/// settle the ions
+pub(crate) fn initialize_states(cell: &mut NeuronCell, celsius: f64, library: &MechanismLibrary) -> Result<()> {
+    for sec in cell.sections.values_mut() {
+        // Concentrations restart from their defaults or INITIAL blocks
+        for mech in &mut sec.mechanisms {
+            if mech.name.ends_with("_ion") || library.contains_key(&mech.name) {
+                mech.state.clear();
+            }
+        }
+    }
+    update_ions(cell, celsius, library)?;
+    for sec in cell.sections.values_mut() {
+        for mech in &mut sec.mechanisms {
+            initialize_mechanism(mech, &sec.v, celsius, library)?;
+            if mech.name == "extracellular" {
+                mech.state.insert("vext".into(), vec![0.0; sec.nseg]);
+                mech.state.insert("i_membrane".into(), vec![0.0; sec.nseg]);
+            }
+        }
+    }
+    for pp in &mut cell.point_processes {
+        for value in pp.state.values_mut() {
+            *value = 0.0;
+        }
+    }
+    update_ions(cell, celsius, library)?;
+    update_assigned(cell, 0.0, library)
+}
+
+/// Fill in voltages and states missing after sections were resized or
+/// mechanisms inserted
+pub(crate) fn prepare(cell: &mut NeuronCell, celsius: f64, library: &MechanismLibrary) -> Result<()> {
+    // Sections resized without `set_nseg` keep their last voltage
+    for sec in cell.sections.values_mut() {
+        if sec.v.len() != sec.nseg {
+            let last = sec.v.last().copied().unwrap_or(-65.0);
+            sec.v.resize(sec.nseg, last);
+        }
+        for mech in &mut sec.mechanisms {
+            let stale = match library.get(&mech.name) {
+                Some(compiled) => compiled.is_stale(mech, sec.nseg),
+                None => gates(mech)?.iter().any(|g| mech.state.get(*g).is_none_or(|x| x.len() != sec.nseg)),
+            };
+            if stale {
+                initialize_mechanism(mech, &sec.v, celsius, library)?;
+            }
+            if mech.name == "extracellular" {
+                for name in ["vext", "i_membrane"] {
+                    mech.state.entry(name.into()).or_default().resize(sec.nseg, 0.0);
+                }
+            }
+        }
+    }
+    update_ions(cell, celsius, library)
+}
+
+/// Membrane current (nA, outward) and its slope (uS) at every node, with
+/// node voltages `v`
+pub(crate) fn membrane_currents(
+    cell: &NeuronCell,
+    tree: &CableTree,
+    v: &[Voltage],
+    t: Time,
+    celsius: f64,
+    library: &MechanismLibrary,
+) -> Result<(Vec<Current>, Vec<f64>)> {
+    let n = tree.len();
+    let mut current = vec![0.0; n];
+    let mut slope = vec![0.0; n];
+    for (i, (name, k)) in tree.nodes.iter().enumerate() {
+        let scale = tree.area[i] * 1e6;
+        for mech in &cell.sections[name].mechanisms {
+            let i0 = current_density(mech, *k, v[i], celsius, library)?;
+            let i1 = current_density(mech, *k, v[i] + SLOPE_STEP, celsius, library)?;
+            current[i] += i0 * scale;
+            slope[i] += (i1 - i0) / SLOPE_STEP * scale;
+        }
+    }
+    for pp in &cell.point_processes {
+        let sec = cell.sections.get(&pp.section).ok_or_else(|| {
+            OldiesError::ModelNotFound(format!("Section {} not found", pp.section))
+        })?;
+        let i = tree.node(&pp.section, sec.segment(pp.location)).unwrap();
+        let (injected, g, e) = point_current(pp, t)?;
+        current[i] += g * (v[i] - e) - injected;
+        slope[i] += g;
+    }
+    Ok((current, slope))
+}
+
+/// Advance `cell` from `t` to `t + dt`
+pub fn advance(cell: &mut NeuronCell, t: Time, dt: Time, celsius: f64, library: &MechanismLibrary) -> Result<()> {
+    prepare(cell, celsius, library)?;
+    let tree = CableTree::new(cell)?;
+    let n = tree.len();
+    let mut v = vec![0.0; n];
+    for (i, (name, k)) in tree.nodes.iter().enumerate() {
+        v[i] = cell.sections[name].v[*k];
+    }
+    let (current, slope) = membrane_currents(cell, &tree, &v, t, celsius, library)?;
+    let vext = extracellular_potentials(cell, &tree);
+
+    // C dv/dt = -I(v) - slope dv/2 + sum g (v_nb - v) + sum g (dv_nb - dv) / 2.
+    // Clamps are too stiff for Crank-Nicolson and would ring, so their
+    // nodes take the full slope (backward Euler) as in NEURON.
+    let mut theta = vec![0.5; n];
+    for pp in cell.point_processes.iter().filter(|pp| matches!(pp.name.as_str(), "SEClamp" | "VClamp")) {
+        if let Some(i) = cell.sections.get(&pp.section).and_then(|sec| tree.node(&pp.section, sec.segment(pp.location))) {
+            theta[i] = 1.0;
+        }
+    }
+    let mut d: Vec<f64> = (0..n).map(|i| tree.capacitance[i] / dt + theta[i] * slope[i]).collect();
+    let mut a = vec![0.0; n];
+    let mut rhs: Vec<f64> = current.iter().map(|c| -c).collect();
+    for i in 0..n {
+        if let Some(p) = tree.parent[i] {
+            let g = tree.g_axial[i];
+            let flow = g * (v[p] + vext[p] - v[i] - vext[i]);
+            rhs[i] += flow;
+            rhs[p] -= flow;
+            d[i] += 0.5 * g;
+            d[p] += 0.5 * g;
+            a[i] = -0.5 * g;
+        }
+    }
+    let layers = extracellular_layers(cell, &tree)?;
+    let (dv, vext) = if layers.iter().all(Option::is_none) {
+        tree.solve(&mut d, &a, &mut rhs);
+        (rhs, vext)
+    } else {
+        // Each node solves for the changes of its internal potential and
+        // of vext, whose difference is the change of v. The membrane
+        // current, electrodes aside, flows out through the layer:
+        // I_m + m dv = cx dvext/dt + gx (vext - e) - sum gx_ij (vext_j - vext_i)
+        let (electrode, g_electrode) = electrode_currents(cell, &tree, &v, t)?;
+        let mut db = vec![[[0.0; 2]; 2]; n];
+        let mut ab = vec![[0.0; 2]; n];
+        let mut rb = vec![[0.0; 2]; n];
+        for i in 0..n {
+            let m = tree.capacitance[i] / dt + theta[i] * slope[i];
+            db[i][0] = [d[i], -m];
+            ab[i][0] = a[i];
+            rb[i][0] = rhs[i];
+            match &layers[i] {
+                Some(layer) => {
+                    let m = tree.capacitance[i] / dt + theta[i] * (slope[i] - g_electrode[i]);
+                    db[i][1] = [-m, m + layer.cx / dt + layer.gx];
+                    rb[i][1] = current[i] + electrode[i] - layer.gx * (vext[i] - layer.e);
+                }
+                None => db[i][1] = [0.0, 1.0],
+            }
+        }
+        for i in 0..n {
+            if let (Some(p), Some(li)) = (tree.parent[i], &layers[i]) {
+                if let Some(lp) = &layers[p] {
+                    let g = 1.0 / (li.half_resistance + lp.half_resistance);
+                    let flow = g * (vext[p] - vext[i]);
+                    rb[i][1] += flow;
+                    rb[p][1] -= flow;
+                    db[i][1][1] += g;
+                    db[p][1][1] += g;
+                    ab[i][1] = -g;
+                }
+            }
+        }
+        solve_blocks(&tree, &mut db, &ab, &mut rb);
+        (rb.iter().map(|x| x[0] - x[1]).collect(), (0..n).map(|i| vext[i] + rb[i][1]).collect())
+    };
+
+    for (i, (name, k)) in tree.nodes.iter().enumerate() {
+        cell.sections.get_mut(name).unwrap().v[*k] = v[i] + dv[i];
+    }
+    for sec in cell.sections.values_mut() {
+        for mech in &mut sec.mechanisms {
+            advance_mechanism(mech, &sec.v, dt, celsius, library)?;
+        }
+    }
+    for pp in &mut cell.point_processes {
+        advance_point(pp, dt)?;
+    }
+    if layers.iter().any(Option::is_some) {
+        store_extracellular(cell, &tree, &vext, t + dt)?;
+    }
+    update_ions(cell, celsius, library)?;
+    update_assigned(cell, t + dt, library)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mechanisms;
+
+    #[test]
+    fn test_hines_solve_matches_dense() {
+        // Y-shaped tree: 0 - 1 - {2, 3}
+        let mut cell = NeuronCell::new("y");
+        cell.create("a").nseg = 2;
+        cell.create("b");
+        cell.create("c");
+        for sec in cell.sections.values_mut() {
+            sec.v = vec![-65.0; sec.nseg];
+        }
+        cell.connect("b", 0.0, "a", 1.0).unwrap();
+        cell.connect("c", 0.0, "a", 1.0).unwrap();
+        let tree = CableTree::new(&cell).unwrap();
+        assert_eq!(tree.parent, vec![None, Some(0), Some(1), Some(1)]);
+
+        let d0 = [4.0, 5.0, 3.0, 2.0];
+        let a = [0.0, -1.0, -0.5, -0.7];
+        let b = [1.0, 2.0, 3.0, 4.0];
+        let (mut d, mut x) = (d0.to_vec(), b.to_vec());
+        tree.solve(&mut d, &a, &mut x);
+        for i in 0..4 {
+            let mut ax = d0[i] * x[i];
+            for j in 0..4 {
+                if tree.parent[j] == Some(i) {
+                    ax += a[j] * x[j];
+                }
+                if tree.parent[i] == Some(j) {
+                    ax += a[i] * x[j];
+                }
+            }
+            assert!((ax - b[i]).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_passive_cable_attenuation() {
+        // Current injected into one end of a sealed passive cable decays
+        // along its length towards the steady-state profile
+        let mut cell = NeuronCell::new("cable");
+        let dend = cell.create("dend");
+        dend.length = 1000.0;
+        dend.diam = 2.0;
+        dend.set_nseg(51);
+        let mut pas = mechanisms::pas();
+        pas.parameters.insert("g".into(), 1e-4);
+        pas.parameters.insert("e".into(), -65.0);
+        dend.insert(pas);
+        cell.add_point_process(mechanisms::iclamp("dend", 0.0, 0.0, 1e9, 0.1));
+
+        initialize(&mut cell, -65.0, 6.3, &MechanismLibrary::new()).unwrap();
+        for step in 0..4000 {
+            advance(&mut cell, step as f64 * 0.025, 0.025, 6.3, &MechanismLibrary::new()).unwrap();
+        }
+
+        // lambda = sqrt(d / (4 Ra g)) = 1581 um; sealed end: cosh((L - x) / lambda)
+        let v = &cell.sections["dend"].v;
+        let lambda = (2e-4f64 / (4.0 * 100.0 * 1e-4)).sqrt() * 1e4;
+        let x = |k: usize| (k as f64 + 0.5) * 1000.0 / 51.0;
+        let expected = ((1000.0 - x(50)) / lambda).cosh() / ((1000.0 - x(0)) / lambda).cosh();
+        let ratio = (v[50] + 65.0) / (v[0] + 65.0);
+        assert!(v[0] > -65.0 && v.windows(2).all(|w| w[0] > w[1]));
+        assert!((ratio - expected).abs() < 0.01, "ratio = {}, expected {}", ratio, expected);
+    }
+
+    #[test]
+    fn test_hh_action_potential() {
+        // Single compartment of 1000 um^2 at 6.3 C driven by 10 uA/cm^2
+        let mut cell = NeuronCell::new("hh");
+        let soma = cell.create("soma");
+        soma.length = 17.841;
+        soma.diam = 17.841;
+        soma.insert(mechanisms::hh());
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 5.0, 1e9, 0.1));
+        let library = MechanismLibrary::new();
+        initialize(&mut cell, -65.0, 6.3, &library).unwrap();
+
+        let dt = 0.005;
+        let mut trace = vec![];
+        for step in 0..20000 {
+            advance(&mut cell, step as f64 * dt, dt, 6.3, &library).unwrap();
+            trace.push(((step + 1) as f64 * dt, cell.sections["soma"].v[0]));
+        }
+        let rest = trace[(4.9 / dt) as usize].1;
+        let peak = trace.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+        let trough = trace[(20.0 / dt) as usize..].iter().map(|p| p.1).fold(f64::MAX, f64::min);
+        let spikes: Vec<f64> = trace.windows(2).filter(|w| w[0].1 < 0.0 && w[1].1 >= 0.0).map(|w| w[1].0).collect();
+        let isi = spikes[spikes.len() - 1] - spikes[spikes.len() - 2];
+
+        // Hodgkin & Huxley (1952): 68 Hz repetitive firing, +40 mV peaks
+        // and an afterhyperpolarisation near -75 mV
+        assert!((rest + 65.0).abs() < 0.1, "rest {}", rest);
+        assert!((38.0..43.0).contains(&peak), "peak {}", peak);
+        assert!((-77.0..-73.0).contains(&trough), "trough {}", trough);
+        assert!((isi - 14.6).abs() < 0.2, "interspike interval {}", isi);
+
+        // Range variables sum to the membrane current
+        let hh = &cell.sections["soma"].mechanisms[0];
+        let v = cell.sections["soma"].v[0];
+        let total = hh.state["ina"][0] + hh.state["ik"][0] + hh.state["il"][0];
+        assert!((total - current_density(hh, 0, v, 6.3, &library).unwrap()).abs() < 1e-12);
+        assert_eq!(hh.state["gk"][0], 0.036 * hh.state["n"][0].powi(4));
+    }
+
+    #[test]
+    fn test_voltage_clamps() {
+        let library = MechanismLibrary::new();
+        for clamp in [
+            mechanisms::seclamp("soma", 0.5, &[(1.0, -65.0), (5.0, -40.0), (1.0, -65.0)]),
+            mechanisms::vclamp("soma", 0.5, &[(1.0, -65.0), (5.0, -40.0), (1.0, -65.0)]),
+        ] {
+            let mut cell = NeuronCell::new("pas");
+            let soma = cell.create("soma");
+            soma.length = 20.0;
+            soma.diam = 20.0;
+            soma.insert(mechanisms::pas());
+            cell.add_point_process(clamp);
+            if cell.point_processes[0].name == "SEClamp" {
+                cell.point_processes[0].parameters.insert("rs".into(), 0.01);
+            }
+            initialize(&mut cell, -65.0, 6.3, &library).unwrap();
+            for step in 0..200 {
+                advance(&mut cell, step as f64 * 0.025, 0.025, 6.3, &library).unwrap();
+            }
+
+            // Holding at -40 mV the clamp supplies the leak current
+            let v = cell.sections["soma"].v[0];
+            let pp = &cell.point_processes[0];
+            let leak = 0.001 * (v + 70.0) * cell.sections["soma"].area() * 1e6;
+            assert!((v + 40.0).abs() < 0.05, "{}: v = {}", pp.name, v);
+            assert_eq!(pp.state["vc"], -40.0);
+            assert!((pp.state["i"] - leak).abs() < 1e-3 * leak, "{}: i = {}, leak {}", pp.name, pp.state["i"], leak);
+        }
+    }
+
+    #[test]
+    fn test_hh_voltage_clamp_currents() {
+        // Stepping to 0 mV draws the early inward sodium current and the
+        // late outward potassium current
+        let mut cell = NeuronCell::new("hh");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::hh());
+        cell.add_point_process(mechanisms::seclamp("soma", 0.5, &[(1.0, -65.0), (10.0, 0.0)]));
+        cell.point_processes[0].parameters.insert("rs".into(), 0.001);
+        let library = MechanismLibrary::new();
+        initialize(&mut cell, -65.0, 6.3, &library).unwrap();
+        let mut i = vec![];
+        for step in 0..400 {
+            advance(&mut cell, step as f64 * 0.025, 0.025, 6.3, &library).unwrap();
+            i.push(cell.point_processes[0].state["i"]);
+        }
+        let early = i[44..120].iter().cloned().fold(f64::MAX, f64::min);
+        assert!(early < -1.0, "peak early current {}", early);
+        assert!(i[399] > 1.0, "late current {}", i[399]);
+    }
+
+    #[test]
+    fn test_current_stimuli() {
+        let ramp = mechanisms::iramp("soma", 0.5, 10.0, 20.0, 0.0, 1.0);
+        assert_eq!(stimulus_current(&ramp, 20.0).unwrap(), 0.5);
+        assert_eq!(stimulus_current(&ramp, 30.0).unwrap(), 0.0);
+        let mut sine = mechanisms::isine("soma", 0.5, 10.0, 100.0, 0.2, 50.0);
+        sine.parameters.insert("offset".into(), 0.1);
+        assert!((stimulus_current(&sine, 15.0).unwrap() - 0.3).abs() < 1e-12);
+        assert!((stimulus_current(&sine, 25.0).unwrap() + 0.1).abs() < 1e-12);
+        assert_eq!(stimulus_current(&sine, 5.0).unwrap(), 0.0);
+        let wave = mechanisms::iwave("soma", 0.5, &[(1.0, 0.0), (2.0, 1.0), (4.0, -1.0)]);
+        let i: Vec<f64> = [0.5, 1.5, 3.0, 5.0].iter().map(|&t| stimulus_current(&wave, t).unwrap()).collect();
+        assert_eq!(i, [0.0, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_noise_statistics() {
+        let mut cell = NeuronCell::new("cell");
+        cell.create("soma");
+        cell.add_point_process(mechanisms::inoise("soma", 0.5, 0.0, 1e9, 0.1, 0.05, 5.0));
+        let library = MechanismLibrary::new();
+        let mut x = vec![];
+        for k in 0..200_000 {
+            update_assigned(&mut cell, k as f64 * 0.025, &library).unwrap();
+            x.push(cell.point_processes[0].state["i"]);
+        }
+        let n = x.len() as f64;
+        let mean = x.iter().sum::<f64>() / n;
+        let var = x.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+        // Correlation after one time constant, 200 intervals
+        let lag = x.windows(201).map(|w| (w[0] - mean) * (w[200] - mean)).sum::<f64>() / (n - 200.0) / var;
+        assert!((mean - 0.1).abs() < 0.005, "mean {}", mean);
+        assert!((var.sqrt() - 0.05).abs() < 0.005, "std {}", var.sqrt());
+        assert!((lag - (-1.0f64).exp()).abs() < 0.05, "correlation {}", lag);
+
+        // The sample path depends only on the seed, however it is stepped
+        let last = x[x.len() - 1];
+        cell.point_processes[0].state.clear();
+        update_assigned(&mut cell, 199_999.0 * 0.025, &library).unwrap();
+        assert_eq!(cell.point_processes[0].state["i"], last);
+    }
+
+    #[test]
+    fn test_extracellular_layer() {
+        // The default layer is tied to ground and changes nothing. With a
+        // finite xg and no extracellular axial current, the membrane
+        // current of each segment raises its vext by I / (xg area).
+        let cable = |xg: Option<f64>| {
+            let mut cell = NeuronCell::new("cable");
+            let dend = cell.create("dend");
+            dend.length = 200.0;
+            dend.diam = 2.0;
+            dend.set_nseg(5);
+            let mut pas = mechanisms::pas();
+            pas.parameters.insert("g".into(), 1e-3);
+            dend.insert(pas);
+            if let Some(xg) = xg {
+                let mut ext = mechanisms::extracellular();
+                ext.parameters.insert("xg".into(), xg);
+                dend.insert(ext);
+            }
+            cell.add_point_process(mechanisms::iclamp("dend", 0.5, 0.0, 1e9, 0.05));
+            initialize(&mut cell, -70.0, 6.3, &MechanismLibrary::new()).unwrap();
+            for step in 0..2000 {
+                advance(&mut cell, step as f64 * 0.025, 0.025, 6.3, &MechanismLibrary::new()).unwrap();
+            }
+            cell
+        };
+        let plain = cable(None);
+        let grounded = cable(Some(1e9));
+        let leaky = cable(Some(0.01));
+        for k in 0..5 {
+            let v = plain.sections["dend"].v[k];
+            assert!((grounded.sections["dend"].v[k] - v).abs() < 1e-6);
+        }
+
+        let sec = &leaky.sections["dend"];
+        let ext = sec.mechanisms.iter().find(|m| m.name == "extracellular").unwrap();
+        for (_, k, i) in transmembrane_currents(&leaky, 50.0).unwrap() {
+            let expected = i / (0.01 * sec.segment_area(k) * 1e6);
+            assert!((ext.state["vext"][k] - expected).abs() < 1e-6, "{} vs {}", ext.state["vext"][k], expected);
+            assert!((ext.state["i_membrane"][k] - i / sec.segment_area(k) * 1e-6).abs() < 1e-9);
+        }
+        assert!(ext.state["vext"][2] > 0.1, "{}", ext.state["vext"][2]);
+    }
+
+    /// Calcium channel and calcium-activated potassium channel
+    const CAL_MOD: &str = "NEURON { SUFFIX cal USEION ca READ eca WRITE ica RANGE gcabar }
+PARAMETER { gcabar = 0.0005 (S/cm2) }
+ASSIGNED { v (mV) eca (mV) ica (mA/cm2) }
+BREAKPOINT { ica = gcabar*(v - eca) }
+";
+    const KCA_MOD: &str = "NEURON { SUFFIX kca USEION ca READ cai USEION k READ ek WRITE ik RANGE gkbar, kd }
+PARAMETER { gkbar = 0.001 (S/cm2) kd = 0.001 (mM) }
+ASSIGNED { v (mV) cai (mM) ek (mV) ik (mA/cm2) }
+BREAKPOINT { ik = gkbar*cai/(cai + kd)*(v - ek) }
+";
+
+    #[test]
+    fn test_nernst() {
+        // Potassium at 37 degrees
+        assert!((nernst(140.0, 5.0, 1, 37.0) + 89.06).abs() < 0.05);
+        assert!((nernst(1e-4, 2.0, 2, 37.0) - 132.3).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_calcium_accumulation() {
+        let run = |cvode: bool| {
+            let mut sim = crate::NeuronSimulation::new();
+            for mod_file in [CAL_MOD, KCA_MOD, crate::nmodl::CADECAY_MOD] {
+                sim.load_nmodl(mod_file).unwrap();
+            }
+            let mut cell = NeuronCell::new("cell");
+            let soma = cell.create("soma");
+            soma.length = 20.0;
+            soma.diam = 20.0;
+            for name in ["cal", "kca", "cad"] {
+                soma.insert(sim.mechanisms[name].instance());
+            }
+            cell.add_point_process(mechanisms::seclamp("soma", 0.5, &[(5.0, -65.0), (50.0, -10.0), (50.0, -65.0)]));
+            sim.add_cell(cell);
+            sim.cvode_active(cvode);
+            sim.cvode.atol_scale.insert("cai".into(), 1e-4);
+            sim.tstop = 55.0;
+            sim.finitialize(-65.0).unwrap();
+            sim.run().unwrap();
+            sim
+        };
+
+        let sim = run(false);
+        let soma = &sim.cells[0].sections["soma"];
+        let find = |name: &str| soma.mechanisms.iter().find(|m| m.name == name).unwrap();
+        let (ion, cal, kca) = (find("ca_ion"), find("cal"), find("kca"));
+        let cai = find("cad").state["cai"][0];
+        assert!(cai > 1e-3, "cai {}", cai);
+
+        // Everyone sees the accumulated calcium and its reversal potential
+        assert_eq!(ion.state["cai"][0], cai);
+        let eca = nernst(cai, 2.0, 2, sim.celsius);
+        assert_eq!(ion.state["eca"][0], eca);
+        assert_eq!(cal.state["eca"][0], eca);
+        let v = soma.v[0];
+        let ik = 0.001 * cai / (cai + 0.001) * (v + 77.0);
+        assert!((sim.mechanisms["kca"].current(kca, 0, v, sim.celsius) - ik).abs() < 1e-12);
+        assert!((ion.state["ica"][0] - 0.0005 * (v - eca)).abs() < 1e-12);
+
+        let variable = run(true);
+        let cai_cvode = variable.cells[0].sections["soma"].mechanisms.iter().find(|m| m.name == "cad").unwrap().state["cai"][0];
+        assert!((cai_cvode / cai - 1.0).abs() < 0.01, "{} vs {}", cai_cvode, cai);
+    }
+}
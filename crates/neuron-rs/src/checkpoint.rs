@@ -0,0 +1,281 @@
+//! Checkpointing a running simulation
+//!
+//! A [`Checkpoint`] is the dynamic state of a simulation at time `t`, as
+//! NEURON's BBSaveState writes it: segment voltages, the per-segment
+//! variables of every mechanism (gates, ion currents and concentrations,
+//! reaction-diffusion species), point process and artificial cell states
+//! with their random number generators, the events waiting for delivery,
+//! and what threshold detectors and recordings last saw. The setup itself
+//! is not part of it: it is restored into a simulation built the same way,
+//! for instance from the same [`Session`](crate::session::Session), and
+//! initialized.
+//!
+//! With fixed steps, running to `t`, saving, restoring into a fresh
+//! simulation and running on gives the same voltages, spikes and events as
+//! running on uninterrupted. Variable-step integrators start again from
+//! the restored state, so results agree to within the integration
+//! tolerance. Results (recordings, threshold events and the spike times of
+//! connections) are not saved: the restored simulation records from the
+//! checkpoint on.
+
+use crate::netcon::Delivery;
+use crate::NeuronSimulation;
+use oldies_core::{OldiesError, Result, Rng, Time, Voltage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Version of the checkpoint format written
+pub const VERSION: u32 = 1;
+
+/// Dynamic state of a simulation at `t`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Format version, at most [`VERSION`] to be loaded
+    pub version: u32,
+    pub t: Time,
+    pub cells: Vec<CellState>,
+    pub artificial_cells: Vec<ArtificialState>,
+    /// Events waiting for delivery, in delivery order
+    pub events: Vec<(Time, Delivery)>,
+    /// Last (t, v) seen by each connection with a voltage source
+    pub netcons: Vec<Option<(Time, Voltage)>>,
+    /// Last (t, v) seen by each threshold watch
+    pub thresholds: Vec<Option<(Time, Voltage)>>,
+    /// Next sampling time of each record
+    pub records: Vec<Time>,
+}
+
+/// State of a cell
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellState {
+    pub sections: HashMap<String, SectionState>,
+    /// State of each point process
+    pub point_processes: Vec<HashMap<String, f64>>,
+}
+
+/// State of a section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SectionState {
+    /// Membrane potential per segment
+    pub v: Vec<Voltage>,
+    /// Per-segment variables by mechanism
+    pub mechanisms: HashMap<String, HashMap<String, Vec<f64>>>,
+}
+
+/// State of an artificial cell
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArtificialState {
+    pub state: HashMap<String, f64>,
+    /// Random number generator state
+    pub rng: Rng,
+}
+
+fn mismatch(msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::SimulationError(format!("Checkpoint does not match the model: {}", msg))
+}
+
+impl Checkpoint {
+    /// Checkpoint as JSON
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self)
+            .map_err(|e| OldiesError::SimulationError(format!("Cannot write checkpoint: {}", e)))
+    }
+
+    /// Checkpoint from JSON
+    pub fn from_json(json: &str) -> Result<Self> {
+        let checkpoint: Self = serde_json::from_str(json)
+            .map_err(|e| OldiesError::ParseError(format!("Checkpoint: {}", e)))?;
+        if checkpoint.version > VERSION {
+            return Err(OldiesError::ParseError(format!(
+                "Checkpoint version {} is newer than {}", checkpoint.version, VERSION
+            )));
+        }
+        Ok(checkpoint)
+    }
+
+    /// Write the checkpoint to `path`
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    /// Read a checkpoint from `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl NeuronSimulation {
+    /// State of the simulation at the current time
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            version: VERSION,
+            t: self.t,
+            cells: self.cells.iter().map(|cell| CellState {
+                sections: cell.sections.iter().map(|(name, sec)| {
+                    let mechanisms = sec.mechanisms.iter().map(|m| (m.name.clone(), m.state.clone())).collect();
+                    (name.clone(), SectionState { v: sec.v.clone(), mechanisms })
+                }).collect(),
+                point_processes: cell.point_processes.iter().map(|pp| pp.state.clone()).collect(),
+            }).collect(),
+            artificial_cells: self.artificial_cells.iter()
+                .map(|a| ArtificialState { state: a.state.clone(), rng: a.rng.clone() })
+                .collect(),
+            events: self.events.pending(),
+            netcons: self.netcons.iter().map(|nc| nc.last).collect(),
+            thresholds: self.thresholds.iter().map(|th| th.last).collect(),
+            records: self.records.iter().map(|r| r.next).collect(),
+        }
+    }
+
+    /// Continue from `checkpoint`, taken from a simulation set up the same
+    /// way. Recordings, threshold events and spike times start afresh.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+        if checkpoint.cells.len() != self.cells.len()
+            || checkpoint.artificial_cells.len() != self.artificial_cells.len()
+            || checkpoint.netcons.len() != self.netcons.len()
+            || checkpoint.thresholds.len() != self.thresholds.len()
+            || checkpoint.records.len() != self.records.len()
+        {
+            return Err(mismatch("different numbers of cells, connections or recordings"));
+        }
+        for (i, (cell, state)) in self.cells.iter().zip(&checkpoint.cells).enumerate() {
+            if cell.point_processes.len() != state.point_processes.len() {
+                return Err(mismatch(format!("cell {} has {} point processes", i, cell.point_processes.len())));
+            }
+            for (name, sec) in &cell.sections {
+                let saved = state.sections.get(name)
+                    .ok_or_else(|| mismatch(format!("no section {} in cell {}", name, i)))?;
+                if saved.v.len() != sec.nseg {
+                    return Err(mismatch(format!("section {} has {} segments", name, sec.nseg)));
+                }
+                if let Some(m) = sec.mechanisms.iter().find(|m| !saved.mechanisms.contains_key(&m.name)) {
+                    return Err(mismatch(format!("no mechanism {} in section {}", m.name, name)));
+                }
+            }
+            if state.sections.len() != cell.sections.len() {
+                return Err(mismatch(format!("cell {} has {} sections", i, cell.sections.len())));
+            }
+        }
+
+        for (cell, state) in self.cells.iter_mut().zip(&checkpoint.cells) {
+            for (name, sec) in cell.sections.iter_mut() {
+                let saved = &state.sections[name];
+                sec.v.clone_from(&saved.v);
+                for mech in &mut sec.mechanisms {
+                    mech.state.clone_from(&saved.mechanisms[&mech.name]);
+                }
+            }
+            for (pp, saved) in cell.point_processes.iter_mut().zip(&state.point_processes) {
+                pp.state.clone_from(saved);
+            }
+        }
+        for (cell, saved) in self.artificial_cells.iter_mut().zip(&checkpoint.artificial_cells) {
+            cell.state.clone_from(&saved.state);
+            cell.rng = saved.rng.clone();
+        }
+        self.events.clear();
+        for &(t, delivery) in &checkpoint.events {
+            self.events.schedule(t, delivery);
+        }
+        for (nc, &last) in self.netcons.iter_mut().zip(&checkpoint.netcons) {
+            nc.last = last;
+            nc.spikes.clear();
+        }
+        for (th, &last) in self.thresholds.iter_mut().zip(&checkpoint.thresholds) {
+            th.last = last;
+        }
+        for (record, &next) in self.records.iter_mut().zip(&checkpoint.records) {
+            record.next = next;
+        }
+        self.t = checkpoint.t;
+        self.recordings.clear();
+        self.threshold_events.clear();
+        self.apcount_times.clear();
+        self.integrators.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netcon::{ArtificialCell, NetCon, NetSource, NetTarget};
+    use crate::{mechanisms, NeuronCell};
+
+    /// Two HH cells: a noisy NetStim drives the first through an ExpSyn,
+    /// whose spikes reach the second after a delay
+    fn network() -> NeuronSimulation {
+        let mut sim = NeuronSimulation::new();
+        for name in ["a", "b"] {
+            let mut cell = NeuronCell::new(name);
+            let soma = cell.create("soma");
+            soma.length = 20.0;
+            soma.diam = 20.0;
+            soma.insert(mechanisms::hh());
+            cell.add_point_process(mechanisms::exp_syn("soma", 0.5));
+            sim.add_cell(cell);
+        }
+        let stim = sim.add_artificial_cell(ArtificialCell::net_stim(2.0, 8.0, 100.0, 0.5));
+        let mut input = NetCon::new(NetSource::Artificial(stim), Some(NetTarget::PointProcess { cell: 0, index: 0 }));
+        input.weight = 0.05;
+        sim.add_netcon(input);
+        let source = NetSource::Voltage { cell: 0, section: "soma".into(), loc: 0.5 };
+        let mut link = NetCon::new(source, Some(NetTarget::PointProcess { cell: 1, index: 0 }));
+        link.weight = 0.05;
+        link.delay = 5.0;
+        sim.add_netcon(link);
+        sim.record_v("v", 1, "soma", 0.5);
+        sim.add_threshold("ap", 1, "soma", 0.5, 0.0);
+        sim
+    }
+
+    #[test]
+    fn test_split_run_matches_uninterrupted() {
+        let mut whole = network();
+        whole.tstop = 100.0;
+        whole.init().unwrap();
+        whole.run().unwrap();
+        assert!(whole.threshold_events["ap"].len() > 3);
+
+        // Run to the split, save, restore into a fresh simulation and run on:
+        // gating states, NetStim draws and spikes in flight must all carry over
+        for split in [20.0, 40.0, 60.0, 80.0] {
+            let mut first = network();
+            first.tstop = split;
+            first.init().unwrap();
+            first.run().unwrap();
+            let json = first.checkpoint().to_json().unwrap();
+            let checkpoint = Checkpoint::from_json(&json).unwrap();
+            assert_eq!(checkpoint, first.checkpoint());
+
+            let mut second = network();
+            second.init().unwrap();
+            second.restore(&checkpoint).unwrap();
+            second.continuerun(100.0).unwrap();
+
+            let mut v = first.recordings["v"].clone();
+            v.extend(&second.recordings["v"]);
+            assert_eq!(v, whole.recordings["v"], "split at {}", split);
+            let mut spikes = first.threshold_events["ap"].clone();
+            spikes.extend(&second.threshold_events["ap"]);
+            assert_eq!(spikes, whole.threshold_events["ap"], "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn test_restore_errors() {
+        let mut sim = network();
+        sim.init().unwrap();
+        let checkpoint = sim.checkpoint();
+        let mut other = network();
+        other.cells[1].sections.get_mut("soma").unwrap().nseg = 3;
+        other.init().unwrap();
+        let err = other.restore(&checkpoint).unwrap_err();
+        assert!(err.to_string().contains("segments"), "{}", err);
+        other.cells.pop();
+        assert!(other.restore(&checkpoint).is_err());
+        assert!(Checkpoint::from_json(r#"{"version": 99}"#).is_err());
+    }
+}
@@ -0,0 +1,554 @@
+//! Variable time-step integration
+//!
+//! The counterpart of NEURON's `cvode.active(1)`. The state of a group of
+//! cells (node voltages, mechanism states and synaptic conductances) is
+//! advanced with variable-step BDF formulas of order 1 (backward Euler)
+//! and 2. Each step predicts the new state by extrapolating the last
+//! accepted points and corrects it with Newton iterations whose Jacobian
+//! keeps the Hines matrix of the voltages and the diagonal of the states,
+//! as NEURON's preconditioner does. The distance between prediction and
+//! correction estimates the local error in the weighted RMS norm with
+//! weights `atol * atol_scale + rtol * |y|`. It sets the next step, and
+//! steps whose error exceeds 1 are retried shorter. The order rises after
+//! `order + 1` accepted steps and falls back to 1 after a rejection.
+//!
+//! Steps end exactly where stimuli switch on or off, and the history
+//! restarts there at order 1. With a global step all cells form one
+//! system; with `use_local_dt` every cell has its own integrator and the
+//! cells meet every `dt`. The `extracellular` layer is only advanced
+//! with fixed steps.
+
+use crate::cable::{self, CableTree};
+use crate::mechanism::MechanismLibrary;
+use crate::NeuronCell;
+use oldies_core::{OldiesError, Result, Time};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Smallest step before the integrator gives up (ms)
+const MIN_STEP: Time = 1e-9;
+
+/// Newton iterations per step
+const MAX_ITERATIONS: usize = 4;
+
+/// Variable time-step settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cvode {
+    /// Use variable steps instead of `dt`
+    pub active: bool,
+    /// Absolute tolerance
+    pub atol: f64,
+    /// Relative tolerance
+    pub rtol: f64,
+    /// Scale of `atol` per state name ("v", "m", "g", ...)
+    pub atol_scale: HashMap<String, f64>,
+    /// Largest step (ms); JSON has no infinity, so none is left out
+    #[serde(default = "unbounded", skip_serializing_if = "is_unbounded")]
+    pub max_step: Time,
+    /// Highest BDF order (1 or 2)
+    pub max_order: usize,
+    /// Give every cell its own step
+    pub use_local_dt: bool,
+}
+
+fn unbounded() -> Time {
+    f64::INFINITY
+}
+
+fn is_unbounded(step: &Time) -> bool {
+    step.is_infinite()
+}
+
+impl Default for Cvode {
+    fn default() -> Self {
+        Self {
+            active: false,
+            atol: 1e-3,     // NEURON default
+            rtol: 0.0,
+            atol_scale: HashMap::new(),
+            max_step: f64::INFINITY,
+            max_order: 2,
+            use_local_dt: false,
+        }
+    }
+}
+
+/// State vector layout of a group of cells
+struct System {
+    trees: Vec<CableTree>,
+    /// First entry of each cell
+    starts: Vec<usize>,
+    /// Tolerance scale of each entry
+    scale: Vec<f64>,
+}
+
+fn sorted_sections(cell: &NeuronCell) -> Vec<String> {
+    let mut names: Vec<String> = cell.sections.keys().cloned().collect();
+    names.sort();
+    names
+}
+
+impl System {
+    fn new(cells: &[NeuronCell], cvode: &Cvode, library: &MechanismLibrary) -> Result<Self> {
+        let mut system = Self { trees: vec![], starts: vec![], scale: vec![] };
+        let scale = |name: &str| cvode.atol_scale.get(name).copied().unwrap_or(1.0);
+        for cell in cells {
+            let tree = CableTree::new(cell)?;
+            system.starts.push(system.scale.len());
+            system.scale.extend(std::iter::repeat_n(scale("v"), tree.len()));
+            for name in sorted_sections(cell) {
+                let sec = &cell.sections[&name];
+                if sec.mechanisms.iter().any(|m| m.name == "extracellular") {
+                    return Err(OldiesError::SimulationError(format!(
+                        "extracellular in {} needs fixed steps", name
+                    )));
+                }
+                for mech in &sec.mechanisms {
+                    for state in cable::state_names(mech, library)? {
+                        system.scale.extend(std::iter::repeat_n(scale(&state), sec.nseg));
+                    }
+                }
+            }
+            for pp in &cell.point_processes {
+                for (state, _) in cable::point_decays(pp)? {
+                    system.scale.push(scale(state));
+                }
+            }
+            system.trees.push(tree);
+        }
+        Ok(system)
+    }
+
+    fn len(&self) -> usize {
+        self.scale.len()
+    }
+
+    fn pack(&self, cells: &[NeuronCell], library: &MechanismLibrary) -> Result<Vec<f64>> {
+        let mut y = Vec::with_capacity(self.len());
+        for (cell, tree) in cells.iter().zip(&self.trees) {
+            y.extend(tree.nodes.iter().map(|(name, k)| cell.sections[name].v[*k]));
+            for name in sorted_sections(cell) {
+                for mech in &cell.sections[&name].mechanisms {
+                    for state in cable::state_names(mech, library)? {
+                        y.extend_from_slice(&mech.state[&state]);
+                    }
+                }
+            }
+            for pp in &cell.point_processes {
+                for (state, _) in cable::point_decays(pp)? {
+                    y.push(pp.state.get(state).copied().unwrap_or(0.0));
+                }
+            }
+        }
+        Ok(y)
+    }
+
+    fn unpack(&self, cells: &mut [NeuronCell], y: &[f64], library: &MechanismLibrary) -> Result<()> {
+        let mut pos = 0;
+        for (cell, tree) in cells.iter_mut().zip(&self.trees) {
+            for (name, k) in &tree.nodes {
+                cell.sections.get_mut(name).unwrap().v[*k] = y[pos];
+                pos += 1;
+            }
+            for name in sorted_sections(cell) {
+                let sec = cell.sections.get_mut(&name).unwrap();
+                let nseg = sec.nseg;
+                for mech in &mut sec.mechanisms {
+                    for state in cable::state_names(mech, library)? {
+                        mech.state.insert(state, y[pos..pos + nseg].to_vec());
+                        pos += nseg;
+                    }
+                }
+            }
+            for pp in &mut cell.point_processes {
+                for (state, _) in cable::point_decays(pp)? {
+                    pp.state.insert(state.to_string(), y[pos]);
+                    pos += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Time derivative of `y` and the diagonal of its Jacobian. For
+    /// voltages the diagonal holds the slope of the membrane current (uS).
+    fn rhs(
+        &self,
+        cells: &mut [NeuronCell],
+        t: Time,
+        y: &[f64],
+        celsius: f64,
+        library: &MechanismLibrary,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        self.unpack(cells, y, library)?;
+        for cell in cells.iter_mut() {
+            cable::update_ions(cell, celsius, library)?;
+        }
+        let mut ydot = vec![0.0; y.len()];
+        let mut jac = vec![0.0; y.len()];
+        for ((cell, tree), &start) in cells.iter().zip(&self.trees).zip(&self.starts) {
+            let n = tree.len();
+            let v = &y[start..start + n];
+            let (current, slope) = cable::membrane_currents(cell, tree, v, t, celsius, library)?;
+            for i in 0..n {
+                ydot[start + i] -= current[i];
+                jac[start + i] = slope[i];
+                if let Some(p) = tree.parent[i] {
+                    let flow = tree.g_axial[i] * (v[p] - v[i]);
+                    ydot[start + i] += flow;
+                    ydot[start + p] -= flow;
+                }
+            }
+            for i in 0..n {
+                ydot[start + i] /= tree.capacitance[i];
+            }
+
+            let mut pos = start + n;
+            for name in sorted_sections(cell) {
+                let sec = &cell.sections[&name];
+                for mech in &sec.mechanisms {
+                    let count = cable::state_names(mech, library)?.len();
+                    for k in 0..sec.nseg {
+                        let v_k = v[tree.node(&name, k).unwrap()];
+                        for (s, (rate, d)) in cable::state_rates(mech, k, v_k, celsius, library)?.into_iter().enumerate() {
+                            ydot[pos + s * sec.nseg + k] = rate;
+                            jac[pos + s * sec.nseg + k] = d;
+                        }
+                    }
+                    pos += count * sec.nseg;
+                }
+            }
+            for pp in &cell.point_processes {
+                for (_, tau) in cable::point_decays(pp)? {
+                    ydot[pos] = -y[pos] / tau;
+                    jac[pos] = -1.0 / tau;
+                    pos += 1;
+                }
+            }
+        }
+        Ok((ydot, jac))
+    }
+
+    /// Newton correction `-(I - hg J)^-1 residual`
+    fn correct(&self, hg: f64, jac: &[f64], residual: &[f64]) -> Vec<f64> {
+        let mut delta = vec![0.0; residual.len()];
+        for (c, tree) in self.trees.iter().enumerate() {
+            let start = self.starts[c];
+            let end = self.starts.get(c + 1).copied().unwrap_or(self.len());
+            let n = tree.len();
+
+            // Voltage rows scaled by C / hg form a symmetric tree matrix
+            let mut d: Vec<f64> = (0..n).map(|i| tree.capacitance[i] / hg + jac[start + i]).collect();
+            let mut a = vec![0.0; n];
+            let mut rhs: Vec<f64> = (0..n).map(|i| -tree.capacitance[i] / hg * residual[start + i]).collect();
+            for i in 0..n {
+                if let Some(p) = tree.parent[i] {
+                    let g = tree.g_axial[i];
+                    d[i] += g;
+                    d[p] += g;
+                    a[i] = -g;
+                }
+            }
+            tree.solve(&mut d, &a, &mut rhs);
+            delta[start..start + n].copy_from_slice(&rhs);
+
+            for j in start + n..end {
+                delta[j] = -residual[j] / (1.0 - hg * jac[j]);
+            }
+        }
+        delta
+    }
+
+    /// Weighted RMS norm of `e`
+    fn norm(&self, e: &[f64], y: &[f64], cvode: &Cvode) -> f64 {
+        if e.is_empty() {
+            return 0.0;
+        }
+        let sum: f64 = e.iter().zip(y).zip(&self.scale)
+            .map(|((e, y), s)| (e / (cvode.atol * s + cvode.rtol * y.abs())).powi(2))
+            .sum();
+        (sum / e.len() as f64).sqrt()
+    }
+}
+
+/// Variable-step integration history of a group of cells
+#[derive(Debug, Clone, Default)]
+pub struct Integrator {
+    /// Accepted points, most recent first
+    history: Vec<(Time, Vec<f64>)>,
+    /// Next step, chosen from the first derivative after a restart
+    h: Option<Time>,
+    order: usize,
+    /// Steps accepted since the order last changed
+    accepted: usize,
+    /// Steps accepted since the integrator was created
+    pub steps: usize,
+}
+
+impl Integrator {
+    fn restart(&mut self, t: Time, y: Vec<f64>) {
+        self.history = vec![(t, y)];
+        self.h = None;
+        self.order = 1;
+        self.accepted = 0;
+    }
+
+    /// Take one step of `cells` from `t`, ending no later than `t_stop`,
+    /// and return the time reached
+    pub fn step(
+        &mut self,
+        cells: &mut [NeuronCell],
+        t: Time,
+        t_stop: Time,
+        cvode: &Cvode,
+        celsius: f64,
+        library: &MechanismLibrary,
+    ) -> Result<Time> {
+        for cell in cells.iter_mut() {
+            cable::prepare(cell, celsius, library)?;
+        }
+        let system = System::new(cells, cvode, library)?;
+        let y0 = system.pack(cells, library)?;
+        // Restart when the state was changed outside the integrator
+        if self.history.first().is_none_or(|(t0, y)| *t0 != t || *y != y0) {
+            self.restart(t, y0.clone());
+        }
+
+        let mut bound = t_stop;
+        let mut discontinuity = false;
+        for cell in cells.iter() {
+            for d in cable::discontinuities(cell, t)? {
+                if d > t + MIN_STEP && d <= bound {
+                    bound = d;
+                    discontinuity = true;
+                }
+            }
+        }
+
+        let mut h = match self.h {
+            Some(h) => h,
+            None => {
+                let (f, _) = system.rhs(cells, t, &y0, celsius, library)?;
+                0.1 / system.norm(&f, &y0, cvode).max(1e-12)
+            }
+        }
+        .min(cvode.max_step);
+
+        let mut rejected = false;
+        loop {
+            if h < MIN_STEP {
+                return Err(OldiesError::NumericalError(format!("CVODE step size below {} ms at t = {}", MIN_STEP, t)));
+            }
+            let landing = t + h >= bound - MIN_STEP;
+            if landing {
+                h = bound - t;
+            }
+            let t_new = if landing { bound } else { t + h };
+            // A step ending at a discontinuity sees the stimulus before it
+            let t_eval = if landing && discontinuity { t_new - MIN_STEP } else { t_new };
+
+            // Prediction, BDF base and coefficient, error constant
+            let order = if self.history.len() >= 3 { self.order } else { 1 };
+            let (t1, y1) = &self.history[0];
+            let (prediction, base, gamma, error_constant) = if order == 2 {
+                let (t2, y2) = &self.history[1];
+                let (t3, y3) = &self.history[2];
+                let w = h / (t1 - t2);
+                let (a1, a2) = ((1.0 + w).powi(2) / (1.0 + 2.0 * w), -w * w / (1.0 + 2.0 * w));
+                let l1 = (t_new - t2) * (t_new - t3) / ((t1 - t2) * (t1 - t3));
+                let l2 = (t_new - t1) * (t_new - t3) / ((t2 - t1) * (t2 - t3));
+                let l3 = (t_new - t1) * (t_new - t2) / ((t3 - t1) * (t3 - t2));
+                let prediction: Vec<f64> = (0..y1.len()).map(|i| l1 * y1[i] + l2 * y2[i] + l3 * y3[i]).collect();
+                let base = (0..y1.len()).map(|i| a1 * y1[i] + a2 * y2[i]).collect();
+                (prediction, base, (1.0 + w) / (1.0 + 2.0 * w), h / (t_new - t3))
+            } else if let Some((t2, y2)) = self.history.get(1) {
+                let prediction: Vec<f64> = y1.iter().zip(y2).map(|(a, b)| a + (a - b) * h / (t1 - t2)).collect();
+                (prediction, y1.clone(), 1.0, h / (t_new - t2))
+            } else {
+                let (f, _) = system.rhs(cells, *t1, y1, celsius, library)?;
+                let prediction: Vec<f64> = y1.iter().zip(&f).map(|(y, f)| y + h * f).collect();
+                (prediction, y1.clone(), 1.0, 0.5)
+            };
+
+            // Newton iterations on y - base - h gamma f(y) = 0
+            let hg = h * gamma;
+            let mut y: Vec<f64> = prediction.clone();
+            let mut converged = false;
+            for _ in 0..MAX_ITERATIONS {
+                let (f, jac) = system.rhs(cells, t_eval, &y, celsius, library)?;
+                let residual: Vec<f64> = (0..y.len()).map(|i| y[i] - base[i] - hg * f[i]).collect();
+                let delta = system.correct(hg, &jac, &residual);
+                for (y, d) in y.iter_mut().zip(&delta) {
+                    *y += d;
+                }
+                let size = system.norm(&delta, &y, cvode);
+                if !size.is_finite() {
+                    break;
+                }
+                if size < 0.1 {
+                    converged = true;
+                    break;
+                }
+            }
+            if !converged {
+                h *= 0.25;
+                self.order = 1;
+                self.accepted = 0;
+                rejected = true;
+                continue;
+            }
+
+            let difference: Vec<f64> = y.iter().zip(&prediction).map(|(a, b)| a - b).collect();
+            let error = system.norm(&difference, &y, cvode) * error_constant;
+            let exponent = -1.0 / (order as f64 + 1.0);
+            if error > 1.0 {
+                h *= (0.9 * error.powf(exponent)).clamp(0.1, 0.5);
+                self.order = 1;
+                self.accepted = 0;
+                rejected = true;
+                continue;
+            }
+
+            system.unpack(cells, &y, library)?;
+            for cell in cells.iter_mut() {
+                cable::update_ions(cell, celsius, library)?;
+                cable::update_assigned(cell, t_new, library)?;
+            }
+            self.steps += 1;
+            self.accepted += 1;
+            let growth = (0.9 * error.max(1e-10).powf(exponent)).clamp(0.2, 4.0);
+            self.h = Some(h * if rejected { growth.min(1.0) } else { growth });
+            self.history.insert(0, (t_new, y));
+            self.history.truncate(3);
+            if self.order < cvode.max_order.clamp(1, 2) && self.accepted > self.order && self.history.len() >= 3 {
+                self.order += 1;
+                self.accepted = 0;
+            }
+            if landing && discontinuity {
+                let y = self.history[0].1.clone();
+                self.restart(t_new, y);
+            }
+            return Ok(t_new);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mechanisms, NeuronCell, NeuronSimulation};
+
+    fn soma(name: &str, amp: f64) -> NeuronCell {
+        let mut cell = NeuronCell::new(name);
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::hh());
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 2.0, 100.0, amp));
+        cell
+    }
+
+    fn spikes(sim: &mut NeuronSimulation) -> Vec<f64> {
+        sim.add_threshold("ap", 0, "soma", 0.5, 0.0);
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+        sim.threshold_events.remove("ap").unwrap_or_default()
+    }
+
+    #[test]
+    fn test_passive_decay() {
+        // tau = cm / g = 1 ms
+        let mut cell = NeuronCell::new("pas");
+        let mut pas = mechanisms::pas();
+        pas.parameters.insert("g".into(), 1e-3);
+        pas.parameters.insert("e".into(), -70.0);
+        cell.create("soma").insert(pas);
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(cell);
+        sim.cvode_active(true);
+        sim.cvode.atol = 1e-4;
+        sim.tstop = 5.0;
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+
+        let v = sim.cells[0].sections["soma"].v[0];
+        let expected = -70.0 + 5.0 * (-5.0f64).exp();
+        assert_eq!(sim.t, 5.0);
+        assert!((v - expected).abs() < 2e-3, "v = {}, expected {}", v, expected);
+        assert!(sim.cvode_steps()[0] < 100);
+    }
+
+    #[test]
+    fn test_hh_spike_times_match_fixed_step() {
+        let mut fixed = NeuronSimulation::new();
+        fixed.add_cell(soma("fixed", 0.2));
+        fixed.dt = 0.002;
+        fixed.tstop = 20.0;
+        let reference = spikes(&mut fixed);
+
+        let mut variable = NeuronSimulation::new();
+        variable.add_cell(soma("variable", 0.2));
+        variable.cvode_active(true);
+        variable.cvode.atol = 1e-4;
+        variable.tstop = 20.0;
+        variable.record_v("v", 0, "soma", 0.5);
+        let times = spikes(&mut variable);
+
+        assert!(reference.len() >= 2);
+        assert_eq!(times.len(), reference.len());
+        for (a, b) in times.iter().zip(&reference) {
+            assert!((a - b).abs() < 0.02, "spike at {} vs {}", a, b);
+        }
+        // Steps land on the stimulus onset and are far fewer than fixed ones
+        assert!(variable.recordings["t"].contains(&2.0));
+        assert!(variable.cvode_steps()[0] < 2500, "{} steps", variable.cvode_steps()[0]);
+    }
+
+    #[test]
+    fn test_noise_current_matches_fixed_step() {
+        // The integrator stops wherever the noise changes
+        let run = |cvode: bool| {
+            let mut cell = NeuronCell::new("pas");
+            let soma = cell.create("soma");
+            soma.length = 20.0;
+            soma.diam = 20.0;
+            soma.insert(mechanisms::pas());
+            let mut noise = mechanisms::inoise("soma", 0.5, 1.0, 10.0, 0.02, 0.02, 2.0);
+            noise.parameters.insert("interval".into(), 0.5);
+            cell.add_point_process(noise);
+            let mut sim = NeuronSimulation::new();
+            sim.add_cell(cell);
+            sim.cvode_active(cvode);
+            sim.dt = 0.005;
+            sim.tstop = 15.0;
+            sim.record("v", crate::vector::Variable::voltage(0, "soma", 0.5), Some(0.5));
+            sim.finitialize(-70.0).unwrap();
+            sim.run().unwrap();
+            sim.recordings["v"].clone()
+        };
+        let (fixed, variable) = (run(false), run(true));
+        assert!(fixed.iter().any(|&v| v > -69.5), "{:?}", fixed);
+        let error = fixed.iter().zip(&variable).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+        assert!(error < 0.01, "max difference {} mV", error);
+    }
+
+    #[test]
+    fn test_local_steps() {
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(soma("quiet", 0.0));
+        sim.add_cell(soma("active", 0.2));
+        sim.cvode_active(true);
+        sim.cvode.use_local_dt = true;
+        sim.dt = 1.0;
+        sim.tstop = 20.0;
+        sim.add_threshold("quiet", 0, "soma", 0.5, 0.0);
+        sim.add_threshold("active", 1, "soma", 0.5, 0.0);
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+
+        let steps = sim.cvode_steps();
+        assert_eq!(steps.len(), 2);
+        assert!(steps[0] * 3 < steps[1], "steps {:?}", steps);
+        assert!(!sim.threshold_events.contains_key("quiet"));
+        assert!(sim.threshold_events["active"].len() >= 2);
+    }
+}
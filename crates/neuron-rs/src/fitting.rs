@@ -0,0 +1,287 @@
+//! Fitting channel kinetics to voltage-clamp data
+//!
+//! Many legacy channel models survive only as figures: current families
+//! recorded under voltage clamp, with the rate functions of the gates
+//! described in words. [`fit`] recovers the [`RateFunction`] coefficients
+//! of an [`IonChannel`] from such traces by Levenberg-Marquardt least
+//! squares, starting from a guess of the right form.
+//!
+//! Each [`ClampStep`] is the current density (uA/cm^2) after a step from
+//! `holding` to `step`, with the gates at steady state at `holding`. For a
+//! step the gates relax exponentially, so the current
+//! `g_max * prod(x^p) * (step - e_rev)` is computed exactly rather than
+//! integrated; traces digitized from plots or recorded from a simulated
+//! SEClamp can be fitted alike. The reversal potential and gate powers
+//! are kept; the maximal conductance is fitted on request.
+
+use crate::rxd::solve_dense;
+use oldies_core::{IonChannel, OldiesError, RateFunction, Result, Time, Voltage};
+
+/// Current after a voltage step
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClampStep {
+    /// Potential before the step (mV)
+    pub holding: Voltage,
+    /// Potential during the step (mV)
+    pub step: Voltage,
+    /// Times since the start of the step (ms)
+    pub times: Vec<Time>,
+    /// Current density at each time (uA/cm^2)
+    pub current: Vec<f64>,
+}
+
+impl ClampStep {
+    /// Step with measured `current` at `times`
+    pub fn new(holding: Voltage, step: Voltage, times: Vec<Time>, current: Vec<f64>) -> Result<Self> {
+        if times.len() != current.len() {
+            return Err(OldiesError::SimulationError(format!(
+                "clamp step needs as many times as currents ({} and {})", times.len(), current.len()
+            )));
+        }
+        Ok(Self { holding, step, times, current })
+    }
+
+    /// Step with the current `channel` passes at `times`
+    pub fn simulated(channel: &IonChannel, holding: Voltage, step: Voltage, times: Vec<Time>) -> Self {
+        let current = step_current(channel, holding, step, &times);
+        Self { holding, step, times, current }
+    }
+}
+
+/// Options of [`fit`]
+#[derive(Debug, Clone)]
+pub struct FitOptions {
+    /// Fit the maximal conductance as well as the rate functions
+    pub fit_g_max: bool,
+    pub max_iterations: usize,
+    /// Stop when a step lowers the sum of squares by less than this
+    /// fraction
+    pub tolerance: f64,
+}
+
+impl Default for FitOptions {
+    fn default() -> Self {
+        Self { fit_g_max: false, max_iterations: 200, tolerance: 1e-12 }
+    }
+}
+
+/// Result of [`fit`]
+#[derive(Debug, Clone)]
+pub struct Fit {
+    /// The channel with the fitted coefficients
+    pub channel: IonChannel,
+    /// Root mean square of the residual current (uA/cm^2)
+    pub rms: f64,
+    pub iterations: usize,
+}
+
+/// Current `channel` passes at `times` after a step from `holding` to
+/// `step`
+pub fn step_current(channel: &IonChannel, holding: Voltage, step: Voltage, times: &[Time]) -> Vec<f64> {
+    let gates: Vec<(f64, f64, f64, i32)> = channel.gates.iter().map(|gate| {
+        let (a0, b0) = (gate.alpha.eval(holding), gate.beta.eval(holding));
+        let (a, b) = (gate.alpha.eval(step), gate.beta.eval(step));
+        (a0 / (a0 + b0), a / (a + b), a + b, gate.power as i32)
+    }).collect();
+    times.iter().map(|&t| {
+        let open: f64 = gates.iter()
+            .map(|&(x0, inf, rate, power)| (inf + (x0 - inf) * (-rate * t).exp()).powi(power))
+            .product();
+        channel.g_max * open * (step - channel.e_rev)
+    }).collect()
+}
+
+fn coefficients(rate: &RateFunction) -> Vec<f64> {
+    match *rate {
+        RateFunction::HodgkinHuxley { a, b, c }
+        | RateFunction::Exponential { a, b, c }
+        | RateFunction::Sigmoid { a, b, c } => vec![a, b, c],
+        RateFunction::Linear { a, b } => vec![a, b],
+        RateFunction::Constant(c) => vec![c],
+    }
+}
+
+fn set_coefficients(rate: &mut RateFunction, p: &[f64]) {
+    match rate {
+        RateFunction::HodgkinHuxley { a, b, c }
+        | RateFunction::Exponential { a, b, c }
+        | RateFunction::Sigmoid { a, b, c } => (*a, *b, *c) = (p[0], p[1], p[2]),
+        RateFunction::Linear { a, b } => (*a, *b) = (p[0], p[1]),
+        RateFunction::Constant(c) => *c = p[0],
+    }
+}
+
+/// Fitted parameters of `channel`: the coefficients of alpha then beta of
+/// each gate, then `g_max` if fitted
+fn parameters(channel: &IonChannel, fit_g_max: bool) -> Vec<f64> {
+    let mut p: Vec<f64> = channel.gates.iter()
+        .flat_map(|gate| coefficients(&gate.alpha).into_iter().chain(coefficients(&gate.beta)))
+        .collect();
+    if fit_g_max {
+        p.push(channel.g_max);
+    }
+    p
+}
+
+fn with_parameters(channel: &IonChannel, p: &[f64], fit_g_max: bool) -> IonChannel {
+    let mut channel = channel.clone();
+    let mut i = 0;
+    for gate in &mut channel.gates {
+        for rate in [&mut gate.alpha, &mut gate.beta] {
+            let n = coefficients(rate).len();
+            set_coefficients(rate, &p[i..i + n]);
+            i += n;
+        }
+    }
+    if fit_g_max {
+        channel.g_max = p[i];
+    }
+    channel
+}
+
+/// Simulated minus measured current over all steps, or `None` where the
+/// rates leave the channel undefined
+fn residuals(channel: &IonChannel, steps: &[ClampStep]) -> Option<Vec<f64>> {
+    let r: Vec<f64> = steps.iter().flat_map(|s| {
+        step_current(channel, s.holding, s.step, &s.times).into_iter()
+            .zip(&s.current)
+            .map(|(model, data)| model - data)
+    }).collect();
+    r.iter().all(|x| x.is_finite()).then_some(r)
+}
+
+/// Fit the rate functions of `initial` to `steps`
+pub fn fit(initial: &IonChannel, steps: &[ClampStep], options: &FitOptions) -> Result<Fit> {
+    let mut p = parameters(initial, options.fit_g_max);
+    let n_points: usize = steps.iter().map(|s| s.times.len()).sum();
+    if p.is_empty() || n_points < p.len() {
+        return Err(OldiesError::SimulationError(format!(
+            "{} data points cannot determine {} parameters", n_points, p.len()
+        )));
+    }
+    let unfit = || OldiesError::NumericalError("initial guess gives undefined currents".into());
+    let mut r = residuals(initial, steps).ok_or_else(unfit)?;
+    let mut cost: f64 = r.iter().map(|x| x * x).sum();
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+
+    while iterations < options.max_iterations && cost > 0.0 {
+        iterations += 1;
+        // Forward-difference Jacobian, one column per parameter
+        let jacobian: Vec<Vec<f64>> = (0..p.len()).map(|k| {
+            let h = 1e-7 * p[k].abs().max(1e-3);
+            let mut q = p.clone();
+            q[k] += h;
+            match residuals(&with_parameters(initial, &q, options.fit_g_max), steps) {
+                Some(rq) => rq.iter().zip(&r).map(|(a, b)| (a - b) / h).collect(),
+                None => vec![0.0; r.len()],
+            }
+        }).collect();
+        let jtj: Vec<Vec<f64>> = jacobian.iter()
+            .map(|ci| jacobian.iter().map(|cj| ci.iter().zip(cj).map(|(a, b)| a * b).sum()).collect())
+            .collect();
+        let jtr: Vec<f64> = jacobian.iter().map(|c| c.iter().zip(&r).map(|(a, b)| a * b).sum()).collect();
+
+        // Raise the damping until a step lowers the sum of squares
+        let improved = loop {
+            let mut a = jtj.clone();
+            for (k, row) in a.iter_mut().enumerate() {
+                row[k] += lambda * jtj[k][k].max(1e-12);
+            }
+            let mut delta: Vec<f64> = jtr.iter().map(|x| -x).collect();
+            solve_dense(&mut a, &mut delta);
+            let q: Vec<f64> = p.iter().zip(&delta).map(|(x, d)| x + d).collect();
+            let trial = q.iter().all(|x| x.is_finite())
+                .then(|| residuals(&with_parameters(initial, &q, options.fit_g_max), steps))
+                .flatten();
+            if let Some(rq) = trial {
+                let c: f64 = rq.iter().map(|x| x * x).sum();
+                if c < cost {
+                    lambda = (lambda / 10.0).max(1e-12);
+                    let gain = (cost - c) / cost;
+                    (p, r, cost) = (q, rq, c);
+                    break Some(gain);
+                }
+            }
+            lambda *= 10.0;
+            if lambda > 1e12 {
+                break None;
+            }
+        };
+        match improved {
+            Some(gain) if gain >= options.tolerance => {}
+            _ => break,
+        }
+    }
+
+    Ok(Fit {
+        channel: with_parameters(initial, &p, options.fit_g_max),
+        rms: (cost / n_points as f64).sqrt(),
+        iterations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oldies_core::GateVariable;
+
+    /// Hodgkin-Huxley delayed rectifier, n^4
+    fn hh_k() -> IonChannel {
+        IonChannel {
+            name: "k".into(),
+            g_max: 36.0,
+            e_rev: -77.0,
+            gates: vec![GateVariable {
+                name: "n".into(),
+                power: 4,
+                alpha: RateFunction::HodgkinHuxley { a: -0.01, b: 55.0, c: -10.0 },
+                beta: RateFunction::Exponential { a: 0.125, b: 65.0, c: -80.0 },
+            }],
+        }
+    }
+
+    fn family(channel: &IonChannel) -> Vec<ClampStep> {
+        let times: Vec<Time> = (0..=60).map(|i| i as f64 * 0.25).collect();
+        (0..8).map(|i| ClampStep::simulated(channel, -80.0, -50.0 + 10.0 * i as f64, times.clone())).collect()
+    }
+
+    #[test]
+    fn test_step_current() {
+        let k = hh_k();
+        let steps = family(&k);
+        // At steady state at the holding potential the current is small and
+        // rises sigmoidally with depolarization
+        let i = &steps[7].current;
+        assert!(i[0] < 1.0 && i[60] > 2000.0, "{:?}", i);
+        assert!(i[2] - i[1] < i[8] - i[7]);
+        assert!(ClampStep::new(-80.0, 0.0, vec![0.0], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_fit_recovers_hh_kinetics() {
+        let k = hh_k();
+        let steps = family(&k);
+        let mut guess = hh_k();
+        guess.gates[0].alpha = RateFunction::HodgkinHuxley { a: -0.02, b: 50.0, c: -12.0 };
+        guess.gates[0].beta = RateFunction::Exponential { a: 0.1, b: 60.0, c: -70.0 };
+        guess.g_max = 30.0;
+        let fit = fit(&guess, &steps, &FitOptions { fit_g_max: true, ..Default::default() }).unwrap();
+
+        assert!(fit.rms < 1e-4, "rms {}", fit.rms);
+        assert!((fit.channel.g_max - 36.0).abs() < 1e-3, "{:?}", fit.channel);
+        for v in [-70.0, -40.0, 0.0, 30.0] {
+            let gate = &fit.channel.gates[0];
+            let expected = &k.gates[0];
+            assert!((gate.alpha.eval(v) / expected.alpha.eval(v) - 1.0).abs() < 1e-4);
+            assert!((gate.beta.eval(v) / expected.beta.eval(v) - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_fit_errors() {
+        let k = hh_k();
+        let step = ClampStep::simulated(&k, -80.0, 0.0, vec![0.0, 1.0]);
+        assert!(fit(&k, &[step], &FitOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,2736 @@
+//! HOC interpreter
+//!
+//! Executes NEURON's HOC scripting language against a [`NeuronSimulation`]
+//! whose first cell is the one the script builds, so ModelDB `.hoc` files
+//! can construct and run models directly. HOC decides from context whether
+//! a name is a section (`soma { nseg = 5 }`, `soma stim = new IClamp(0.5)`),
+//! so scripts are parsed by hand rather than with the [`crate::HocParser`]
+//! grammar.
+//!
+//! Supported:
+//!
+//! - numbers, strings (`strdef`), arrays (`double x[10]`), object references
+//!   (`objref`) and `local` and `localobj` variables
+//! - `proc`/`func`/`obfunc` definitions with `$1`, `$s1`, `$o1` arguments
+//!   and `return`
+//! - `begintemplate`/`endtemplate` with `public` and `external` names and
+//!   an `init` procedure run by `new`; sections created in a template are
+//!   named `Cell[0].soma` and reached from outside as `cell.soma`
+//! - `if`/`else`, `while`, `for (init; cond; step)`, `for i = a, b`,
+//!   `break`/`continue`
+//! - `create`, `access`, `insert`, `connect`, section statements
+//!   (`soma { ... }`, `soma.L`, `dend[2].diam`), `forall`, `forsec` and
+//!   `ifsec` over a pattern (see [`NeuronCell::sections_matching`]) or a
+//!   `SectionList` (`append()`, `remove()`, `wholetree()`), `issection`,
+//!   range variables (`gnabar_hh`, `v(0.5)`, `ena`,
+//!   `xg` and `vext(0.5)` of `extracellular`), set per segment with
+//!   `gnabar_hh(0.3) = x`, `for (x) { ... }` or `for (x, 0)` over the
+//!   segments and linear gradients `gnabar_hh(0.2:0.8) = 0.1:0.3`
+//! - `new IClamp/IRamp/ISine/INoise/ExpSyn/Exp2Syn/SEClamp/VClamp(x)` and
+//!   `new APCount(x)` in the current section (`vc.amp[1]` for array
+//!   parameters, `apc.n` for the count) and `new Vector()` with
+//!   `record(&var)` or `record(&var, Dt)`, `play(&var, tvec)`,
+//!   `play(&var, Dt)` or `play(&var, tvec, 1)` (interpolated), `size()`,
+//!   `append(x)` and `x[i]`, where `&var` is `&t`, `&sec.v(x)`, `&m_hh(x)`,
+//!   `&sec.gnabar_hh` or `&stim.amp`, and the Vector methods `resize`,
+//!   `indgen`, `fill`, `add`, `sub`, `mul`, `div`, `sum`, `mean`, `max`,
+//!   `min`, `contains`, `sort`, `reverse`, `c` and `copy`
+//! - 3D geometry of the current section: `pt3dadd`, `pt3dclear`, `n3d`,
+//!   `x3d`/`y3d`/`z3d`/`diam3d(i)` and `area(x)`
+//! - `print`, `printf`, `sprint`, `strcmp`, `execute`/`execute1`, math
+//!   functions, `finitialize`, `fadvance`, `init`, `run`, `continuerun`,
+//!   `cvode_active` and the variables `t`, `dt`, `tstop`, `celsius`,
+//!   `v_init`
+//! - `load_file` (once per file) and `xopen` of files relative to the
+//!   directory of the script (see [`Hoc::execute_file`])
+//!
+//! Printed text is collected in [`Hoc::output`]. GUI calls and loading the
+//! standard libraries (`nrngui.hoc`, `stdrun.hoc`), whose procedures are
+//! built in, are ignored. Other HOC keywords (`iterator`, `setpointer`, ...)
+//! are reported as not supported.
+
+use crate::vector::{self, Variable};
+use crate::{mechanisms, NeuronCell, NeuronSimulation, Point3d, Section, SectionList, SectionPattern};
+use oldies_core::{OldiesError, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Calls accepted and ignored (GUI and compiled mechanism libraries)
+const IGNORED_CALLS: &[&str] = &[
+    "nrn_load_dll", "nrnmainmenu", "nrncontrolmenu", "xpanel", "xbutton",
+    "xvalue", "xpvalue", "xlabel", "xmenu", "xstatebutton", "define_shape", "topology", "doNotify",
+];
+
+/// Standard libraries whose procedures (`run`, `init`, ...) are built in
+const BUILTIN_LIBRARIES: &[&str] = &["nrngui.hoc", "stdrun.hoc", "stdgui.hoc", "stdlib.hoc"];
+
+/// HOC keywords the interpreter does not run
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "iterator", "iterator_statement", "setpointer", "uninsert", "delete", "read", "debug", "depvar",
+    "eqn", "parallel", "help", "stop",
+];
+
+/// Numeric equality tolerance used by `==` (HOC's `float_epsilon`)
+const FLOAT_EPSILON: f64 = 1e-11;
+
+fn parse_error(line: usize, msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::ParseError(format!("line {}: {}", line, msg))
+}
+
+fn runtime_error(msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::SimulationError(format!("HOC: {}", msg))
+}
+
+// =============================================================================
+// LEXER
+// =============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    /// `$1`, `$s1`, `$o1`: arguments are typed by their values
+    Arg(usize),
+    Op(&'static str),
+    Newline,
+    Eof,
+}
+
+const OPS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+=", "-=", "*=", "/=", "+", "-", "*", "/", "%", "^", "<",
+    ">", "!", "=", "(", ")", "{", "}", "[", "]", ",", ".", "&", ";", ":",
+];
+
+/// Tokens with their line numbers. Newlines inside parentheses and
+/// brackets are dropped so expressions may span lines.
+fn lex(src: &str) -> Result<Vec<(Tok, usize)>> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut toks = vec![];
+    let mut line = 1;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            '\n' => {
+                if depth == 0 {
+                    toks.push((Tok::Newline, line));
+                }
+                line += 1;
+                i += 1;
+            }
+            ' ' | '\t' | '\r' => i += 1,
+            '/' if next == Some('/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if next == Some('*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                i += 2;
+            }
+            '"' => {
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None | Some('\n') => return Err(parse_error(line, "unterminated string")),
+                        Some('"') => break,
+                        Some('\\') => {
+                            s.push(match chars.get(i + 1) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some(&c) => c,
+                                None => '\\',
+                            });
+                            i += 2;
+                        }
+                        Some(&c) => {
+                            s.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                toks.push((Tok::Str(s), line));
+            }
+            '$' => {
+                i += 1;
+                if matches!(chars.get(i), Some('s' | 'o' | '&')) {
+                    i += 1;
+                }
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let index = chars[start..i].iter().collect::<String>().parse()
+                    .map_err(|_| parse_error(line, "expected argument number after '$'"))?;
+                toks.push((Tok::Arg(index), line));
+            }
+            c if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                if matches!(chars.get(i), Some('e' | 'E')) {
+                    let mut j = i + 1;
+                    if matches!(chars.get(j), Some('+' | '-')) {
+                        j += 1;
+                    }
+                    if chars.get(j).is_some_and(|c| c.is_ascii_digit()) {
+                        i = j;
+                        while i < chars.len() && chars[i].is_ascii_digit() {
+                            i += 1;
+                        }
+                    }
+                }
+                let text: String = chars[start..i].iter().collect();
+                let x = text.parse().map_err(|_| parse_error(line, format!("invalid number '{}'", text)))?;
+                toks.push((Tok::Num(x), line));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                toks.push((Tok::Ident(chars[start..i].iter().collect()), line));
+            }
+            _ => {
+                let op = OPS.iter()
+                    .find(|op| op.chars().enumerate().all(|(k, o)| chars.get(i + k) == Some(&o)))
+                    .ok_or_else(|| parse_error(line, format!("unexpected character '{}'", c)))?;
+                match *op {
+                    "(" | "[" => depth += 1,
+                    ")" | "]" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                i += op.len();
+                toks.push((Tok::Op(op), line));
+            }
+        }
+    }
+    toks.push((Tok::Eof, line));
+    Ok(toks)
+}
+
+// =============================================================================
+// SYNTAX TREE
+// =============================================================================
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(f64),
+    Str(String),
+    Arg(usize),
+    Name(String),
+    Index(Box<Expr>, Box<Expr>),
+    Member(Box<Expr>, String),
+    Call(Box<Expr>, Vec<Expr>),
+    New(String, Vec<Expr>),
+    /// `&sec.v(x)`
+    Ref(Box<Expr>),
+    /// `a:b`, only as the location and value of a range assignment
+    Span(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// `name` or `name[index]`, of the object `object` for `cell.soma`
+#[derive(Debug, Clone)]
+struct SecRef {
+    object: Option<Expr>,
+    name: String,
+    index: Option<Expr>,
+}
+
+/// `begintemplate Name ... endtemplate Name`
+#[derive(Debug)]
+struct Template {
+    name: String,
+    /// Names visible from outside an instance
+    public: Vec<String>,
+    /// Top-level names visible inside an instance
+    external: Vec<String>,
+    /// Declarations run for each new instance, before `init`
+    body: Vec<Stmt>,
+    procs: HashMap<String, Rc<Stmt>>,
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Expr(Expr),
+    Assign(Expr, &'static str, Expr),
+    Block(Vec<Stmt>),
+    Create(Vec<SecRef>),
+    Access(SecRef),
+    Insert(String),
+    /// `connect child(end), parent(loc)`; without a parent the current
+    /// section is used (`soma connect dend(0), 1`)
+    Connect { child: SecRef, end: Expr, parent: Option<SecRef>, loc: Expr },
+    /// A statement run with `section` pushed on the section stack
+    OnSection(SecRef, Box<Stmt>),
+    Forall(Box<Stmt>),
+    Forsec(Expr, Box<Stmt>),
+    Ifsec(Expr, Box<Stmt>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    While(Expr, Box<Stmt>),
+    For(Box<Stmt>, Expr, Box<Stmt>, Box<Stmt>),
+    ForRange(String, Expr, Expr, Box<Stmt>),
+    /// `for (x)` over 0, the segment centres and 1 of the current section,
+    /// or `for (x, 0)` over the centres only
+    ForSegments(String, bool, Box<Stmt>),
+    /// `var(x1:x2) = y1:y2`: `var` rising linearly from `y1` to `y2` over
+    /// the segments with centres from `x1` to `x2`
+    RangeAssign(Expr, [Expr; 4]),
+    Define(String, Rc<Stmt>),
+    Return(Option<Expr>),
+    Break,
+    Continue,
+    Local(Vec<String>),
+    Localobj(Vec<String>),
+    Template(Rc<Template>),
+    Objref(Vec<String>),
+    Strdef(Vec<String>),
+    Double(Vec<(String, Expr)>),
+    Print(Vec<Expr>),
+}
+
+// =============================================================================
+// PARSER
+// =============================================================================
+
+struct Parser {
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Tok {
+        &self.toks[self.pos].0
+    }
+
+    fn peek_at(&self, k: usize) -> &Tok {
+        &self.toks[(self.pos + k).min(self.toks.len() - 1)].0
+    }
+
+    fn line(&self) -> usize {
+        self.toks[self.pos].1
+    }
+
+    fn advance(&mut self) -> Tok {
+        let tok = self.toks[self.pos].0.clone();
+        if self.pos < self.toks.len() - 1 {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn error(&self, msg: impl std::fmt::Display) -> OldiesError {
+        parse_error(self.line(), msg)
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Tok::Op(o) if *o == op)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = self.is_op(op);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect(&mut self, op: &str) -> Result<()> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{}', found {:?}", op, self.peek())))
+        }
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Tok::Ident(w) if w == word)
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.advance() {
+            Tok::Ident(name) => Ok(name),
+            tok => Err(self.error(format!("expected a name, found {:?}", tok))),
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while *self.peek() == Tok::Newline {
+            self.advance();
+        }
+    }
+
+    fn skip_separators(&mut self) {
+        while *self.peek() == Tok::Newline || self.is_op(";") {
+            self.advance();
+        }
+    }
+
+    fn at_end_of_statement(&self) -> bool {
+        matches!(self.peek(), Tok::Newline | Tok::Eof) || self.is_op(";") || self.is_op("}")
+    }
+
+    fn program(&mut self) -> Result<Vec<Stmt>> {
+        let mut stmts = vec![];
+        loop {
+            self.skip_separators();
+            if *self.peek() == Tok::Eof {
+                return Ok(stmts);
+            }
+            stmts.push(self.statement()?);
+        }
+    }
+
+    fn block(&mut self) -> Result<Stmt> {
+        self.expect("{")?;
+        let mut stmts = vec![];
+        loop {
+            self.skip_separators();
+            if self.eat("}") {
+                return Ok(Stmt::Block(stmts));
+            }
+            if *self.peek() == Tok::Eof {
+                return Err(self.error("missing '}'"));
+            }
+            stmts.push(self.statement()?);
+        }
+    }
+
+    /// Body of a control statement, possibly on the next line
+    fn body(&mut self) -> Result<Box<Stmt>> {
+        self.skip_newlines();
+        Ok(Box::new(self.statement()?))
+    }
+
+    fn names(&mut self) -> Result<Vec<String>> {
+        let mut names = vec![self.ident()?];
+        while self.eat(",") {
+            names.push(self.ident()?);
+        }
+        Ok(names)
+    }
+
+    fn sec_ref(&mut self) -> Result<SecRef> {
+        let mut name = self.ident()?;
+        let mut object = None;
+        while self.is_op(".") && matches!(self.peek_at(1), Tok::Ident(_)) {
+            self.advance();
+            object = Some(match object.take() {
+                Some(base) => Expr::Member(Box::new(base), name),
+                None => Expr::Name(name),
+            });
+            name = self.ident()?;
+        }
+        let index = if self.eat("[") {
+            let index = self.expr()?;
+            self.expect("]")?;
+            Some(index)
+        } else {
+            None
+        };
+        Ok(SecRef { object, name, index })
+    }
+
+    /// `sec(x)` as used by `connect`
+    fn sec_location(&mut self) -> Result<(SecRef, Expr)> {
+        let sec = self.sec_ref()?;
+        self.expect("(")?;
+        let loc = self.expr()?;
+        self.expect(")")?;
+        Ok((sec, loc))
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        if self.is_op("{") {
+            return self.block();
+        }
+        let word = match self.peek() {
+            Tok::Ident(word) => word.clone(),
+            _ => return self.simple_statement(),
+        };
+        match word.as_str() {
+            "create" => {
+                self.advance();
+                let mut secs = vec![self.sec_ref()?];
+                while self.eat(",") {
+                    secs.push(self.sec_ref()?);
+                }
+                if secs.iter().any(|sec| sec.object.is_some()) {
+                    return Err(self.error("create takes section names"));
+                }
+                Ok(Stmt::Create(secs))
+            }
+            "access" => {
+                self.advance();
+                Ok(Stmt::Access(self.sec_ref()?))
+            }
+            "insert" => {
+                self.advance();
+                Ok(Stmt::Insert(self.ident()?))
+            }
+            "connect" => {
+                self.advance();
+                let (child, end) = self.sec_location()?;
+                self.expect(",")?;
+                let names_section = matches!(self.peek(), Tok::Ident(_))
+                    && matches!(self.peek_at(1), Tok::Op("(" | "[" | "."));
+                let (parent, loc) = if names_section {
+                    let (parent, loc) = self.sec_location()?;
+                    (Some(parent), loc)
+                } else {
+                    (None, self.expr()?)
+                };
+                Ok(Stmt::Connect { child, end, parent, loc })
+            }
+            "proc" | "func" | "obfunc" => {
+                self.advance();
+                let name = self.ident()?;
+                self.expect("(")?;
+                self.expect(")")?;
+                self.skip_newlines();
+                Ok(Stmt::Define(name, Rc::new(self.block()?)))
+            }
+            "objref" | "objectvar" => {
+                self.advance();
+                Ok(Stmt::Objref(self.names()?))
+            }
+            "strdef" => {
+                self.advance();
+                Ok(Stmt::Strdef(self.names()?))
+            }
+            "local" => {
+                self.advance();
+                Ok(Stmt::Local(self.names()?))
+            }
+            "localobj" => {
+                self.advance();
+                Ok(Stmt::Localobj(self.names()?))
+            }
+            "begintemplate" => self.template(),
+            "endtemplate" | "public" | "external" => Err(self.error(format!("{} outside a template", word))),
+            word if UNSUPPORTED_KEYWORDS.contains(&word) => Err(self.error(format!("'{}' is not supported", word))),
+            "double" => {
+                self.advance();
+                let mut arrays = vec![];
+                loop {
+                    let name = self.ident()?;
+                    self.expect("[")?;
+                    let size = self.expr()?;
+                    self.expect("]")?;
+                    arrays.push((name, size));
+                    if !self.eat(",") {
+                        return Ok(Stmt::Double(arrays));
+                    }
+                }
+            }
+            "if" => {
+                self.advance();
+                self.expect("(")?;
+                let cond = self.expr()?;
+                self.expect(")")?;
+                let then = self.body()?;
+                // `else` may follow on a later line
+                let save = self.pos;
+                self.skip_separators();
+                if self.is_word("else") {
+                    self.advance();
+                    Ok(Stmt::If(cond, then, Some(self.body()?)))
+                } else {
+                    self.pos = save;
+                    Ok(Stmt::If(cond, then, None))
+                }
+            }
+            "while" => {
+                self.advance();
+                self.expect("(")?;
+                let cond = self.expr()?;
+                self.expect(")")?;
+                Ok(Stmt::While(cond, self.body()?))
+            }
+            "for" => {
+                self.advance();
+                if self.is_op("(") && matches!(self.peek_at(1), Tok::Ident(_))
+                    && matches!(self.peek_at(2), Tok::Op(")" | ","))
+                {
+                    self.advance();
+                    let var = self.ident()?;
+                    let ends = if self.eat(",") { self.expr()? } else { Expr::Num(1.0) };
+                    self.expect(")")?;
+                    let ends = match ends {
+                        Expr::Num(x) => x != 0.0,
+                        _ => return Err(self.error("expected for (x) or for (x, 0)")),
+                    };
+                    Ok(Stmt::ForSegments(var, ends, self.body()?))
+                } else if self.eat("(") {
+                    let init = self.simple_statement()?;
+                    self.expect(";")?;
+                    let cond = self.expr()?;
+                    self.expect(";")?;
+                    let step = self.simple_statement()?;
+                    self.expect(")")?;
+                    Ok(Stmt::For(Box::new(init), cond, Box::new(step), self.body()?))
+                } else {
+                    let var = self.ident()?;
+                    self.expect("=")?;
+                    let from = self.expr()?;
+                    self.expect(",")?;
+                    let to = self.expr()?;
+                    Ok(Stmt::ForRange(var, from, to, self.body()?))
+                }
+            }
+            "forall" => {
+                self.advance();
+                Ok(Stmt::Forall(self.body()?))
+            }
+            "forsec" => {
+                self.advance();
+                let pattern = self.expr()?;
+                Ok(Stmt::Forsec(pattern, self.body()?))
+            }
+            "ifsec" => {
+                self.advance();
+                let pattern = self.expr()?;
+                Ok(Stmt::Ifsec(pattern, self.body()?))
+            }
+            "return" => {
+                self.advance();
+                if self.at_end_of_statement() {
+                    Ok(Stmt::Return(None))
+                } else {
+                    Ok(Stmt::Return(Some(self.expr()?)))
+                }
+            }
+            "break" => {
+                self.advance();
+                Ok(Stmt::Break)
+            }
+            "continue" => {
+                self.advance();
+                Ok(Stmt::Continue)
+            }
+            "print" => {
+                self.advance();
+                let mut items = vec![self.expr()?];
+                while self.eat(",") {
+                    items.push(self.expr()?);
+                }
+                Ok(Stmt::Print(items))
+            }
+            _ => self.simple_statement(),
+        }
+    }
+
+    /// `begintemplate Name`, its declarations and procedures, and
+    /// `endtemplate Name`
+    fn template(&mut self) -> Result<Stmt> {
+        self.advance();
+        let name = self.ident()?;
+        let mut template = Template { name, public: vec![], external: vec![], body: vec![], procs: HashMap::new() };
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                Tok::Eof => return Err(self.error(format!("missing endtemplate {}", template.name))),
+                Tok::Ident(word) if word == "endtemplate" => break,
+                Tok::Ident(word) if word == "begintemplate" => return Err(self.error("templates cannot be nested")),
+                Tok::Ident(word) if word == "public" || word == "external" => {
+                    let public = word == "public";
+                    self.advance();
+                    let names = self.names()?;
+                    match public {
+                        true => template.public.extend(names),
+                        false => template.external.extend(names),
+                    }
+                }
+                _ => match self.statement()? {
+                    Stmt::Define(name, body) => {
+                        template.procs.insert(name, body);
+                    }
+                    stmt => template.body.push(stmt),
+                },
+            }
+        }
+        self.advance();
+        if self.ident()? != template.name {
+            return Err(self.error(format!("expected endtemplate {}", template.name)));
+        }
+        Ok(Stmt::Template(Rc::new(template)))
+    }
+
+    /// Whether the tokens at the cursor are a section name prefixing a
+    /// statement: `soma {`, `soma insert hh`, `dend[1] nseg = 3`,
+    /// `cell.soma {`
+    fn is_section_prefix(&self) -> bool {
+        if !matches!(self.peek(), Tok::Ident(_)) {
+            return false;
+        }
+        let mut k = 1;
+        while matches!(self.peek_at(k), Tok::Op(".")) && matches!(self.peek_at(k + 1), Tok::Ident(_)) {
+            k += 2;
+        }
+        if matches!(self.peek_at(k), Tok::Op("[")) {
+            let mut depth = 0;
+            loop {
+                match self.peek_at(k) {
+                    Tok::Op("[") => depth += 1,
+                    Tok::Op("]") => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    Tok::Eof => return false,
+                    _ => {}
+                }
+                k += 1;
+            }
+            k += 1;
+        }
+        match self.peek_at(k) {
+            Tok::Op("{") => true,
+            Tok::Ident(word) => word != "else",
+            _ => false,
+        }
+    }
+
+    fn simple_statement(&mut self) -> Result<Stmt> {
+        if self.is_section_prefix() {
+            let sec = self.sec_ref()?;
+            return Ok(Stmt::OnSection(sec, Box::new(self.statement()?)));
+        }
+        let target = self.expr()?;
+        for op in ["=", "+=", "-=", "*=", "/="] {
+            if self.is_op(op) {
+                let op = match self.advance() {
+                    Tok::Op(op) => op,
+                    _ => unreachable!(),
+                };
+                if !matches!(target, Expr::Name(_) | Expr::Index(..) | Expr::Member(..) | Expr::Call(..)) {
+                    return Err(self.error("cannot assign to this expression"));
+                }
+                let value = self.expr()?;
+                return match (target, self.eat(":")) {
+                    (Expr::Call(callee, mut args), true) if op == "=" && matches!(args[..], [Expr::Span(..)]) => {
+                        let Some(Expr::Span(x1, x2)) = args.pop() else { unreachable!() };
+                        Ok(Stmt::RangeAssign(*callee, [*x1, *x2, value, self.expr()?]))
+                    }
+                    (_, true) => Err(self.error("a:b values need a range variable with a:b locations")),
+                    (target, false) => Ok(Stmt::Assign(target, op, value)),
+                };
+            }
+        }
+        Ok(Stmt::Expr(target))
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        self.binary(0)
+    }
+
+    /// Binary operators by increasing precedence
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        const LEVELS: &[&[&str]] = &[&["||"], &["&&"], &["==", "!="], &["<", "<=", ">", ">="], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Tok::Op(op) if LEVELS[level].contains(op) => *op,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            let base = self.postfix()?;
+            if self.eat("^") {
+                Ok(Expr::Binary("^", Box::new(base), Box::new(self.unary()?)))
+            } else {
+                Ok(base)
+            }
+        }
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>> {
+        let mut args = vec![];
+        if !self.eat(")") {
+            loop {
+                let arg = self.expr()?;
+                if self.eat(":") {
+                    args.push(Expr::Span(Box::new(arg), Box::new(self.expr()?)));
+                } else {
+                    args.push(arg);
+                }
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(args)
+    }
+
+    fn postfix(&mut self) -> Result<Expr> {
+        let mut e = self.primary()?;
+        loop {
+            if self.eat("[") {
+                let index = self.expr()?;
+                self.expect("]")?;
+                e = Expr::Index(Box::new(e), Box::new(index));
+            } else if self.eat("(") {
+                e = Expr::Call(Box::new(e), self.args()?);
+            } else if self.eat(".") {
+                e = Expr::Member(Box::new(e), self.ident()?);
+            } else {
+                return Ok(e);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.advance() {
+            Tok::Num(x) => Ok(Expr::Num(x)),
+            Tok::Str(s) => Ok(Expr::Str(s)),
+            Tok::Arg(index) => Ok(Expr::Arg(index)),
+            Tok::Ident(word) if word == "new" => {
+                let template = self.ident()?;
+                self.expect("(")?;
+                Ok(Expr::New(template, self.args()?))
+            }
+            Tok::Ident(name) => Ok(Expr::Name(name)),
+            Tok::Op("(") => {
+                let e = self.expr()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Tok::Op("&") => Ok(Expr::Ref(Box::new(self.postfix()?))),
+            tok => Err(self.error(format!("unexpected {:?}", tok))),
+        }
+    }
+}
+
+// =============================================================================
+// INTERPRETER
+// =============================================================================
+
+#[derive(Debug, Clone)]
+enum Value {
+    Num(f64),
+    Str(String),
+    /// Object reference (`None` for an unset `objref`)
+    Obj(Option<usize>),
+    /// Reference to a variable, from `&t`, `&sec.v(loc)` or `&stim.i`
+    Ref(Variable),
+}
+
+#[derive(Debug, Clone)]
+enum Object {
+    /// Index into the cell's point processes
+    Point(usize),
+    /// Values, or the recording named `key` once `record` was called
+    Vector { key: String, data: Vec<f64>, recording: bool },
+    SectionList(SectionList),
+    /// Instance `index` of a HOC template, with its own variables
+    Instance { template: Rc<Template>, index: usize, scope: Scope },
+}
+
+/// Variables and arrays of the top level or of a template instance
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    vars: HashMap<String, Value>,
+    arrays: HashMap<String, Vec<f64>>,
+}
+
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+#[derive(Default)]
+struct Frame {
+    args: Vec<Value>,
+    locals: HashMap<String, Value>,
+    /// Template instance whose procedure is running
+    this: Option<usize>,
+}
+
+/// HOC interpreter state
+pub struct Hoc {
+    /// Simulation driven by the script; cell 0 is the one it builds
+    pub sim: NeuronSimulation,
+    /// Text printed by `print` and `printf`
+    pub output: String,
+    globals: Scope,
+    procs: HashMap<String, Rc<Stmt>>,
+    templates: HashMap<String, Rc<Template>>,
+    objects: Vec<Object>,
+    /// Section arrays and their sizes
+    section_arrays: HashMap<String, usize>,
+    /// Sections in creation order
+    created: Vec<String>,
+    /// Sections pushed by section statements
+    stack: Vec<String>,
+    frames: Vec<Frame>,
+    /// Directory that `load_file` and `xopen` paths are relative to
+    directory: PathBuf,
+    /// Files run by `load_file`
+    loaded: HashSet<PathBuf>,
+}
+
+impl Default for Hoc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hoc {
+    pub fn new() -> Self {
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(NeuronCell::new("cell"));
+        Self {
+            sim,
+            output: String::new(),
+            globals: Scope::default(),
+            procs: HashMap::new(),
+            templates: HashMap::new(),
+            objects: vec![],
+            section_arrays: HashMap::new(),
+            created: vec![],
+            stack: vec![],
+            frames: vec![],
+            directory: PathBuf::new(),
+            loaded: HashSet::new(),
+        }
+    }
+
+    /// Parse and run a script
+    pub fn execute(&mut self, src: &str) -> Result<()> {
+        self.run_source(src, None)
+    }
+
+    /// Run a script file; the files it loads are found relative to its
+    /// directory
+    pub fn execute_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| runtime_error(format!("cannot open {}: {}", path.display(), e)))?;
+        self.loaded.insert(path.to_path_buf());
+        self.execute(&src)
+    }
+
+    /// Run `src` at the top level, or in the instance `this` as
+    /// `execute(cmd, obj)` does
+    fn run_source(&mut self, src: &str, this: Option<usize>) -> Result<()> {
+        let program = Parser { toks: lex(src)?, pos: 0 }.program()?;
+        let frame = this.map(|id| Frame { this: Some(id), ..Frame::default() });
+        let frames = std::mem::replace(&mut self.frames, frame.into_iter().collect());
+        let result = self.exec_program(&program);
+        self.frames = frames;
+        result
+    }
+
+    fn exec_program(&mut self, program: &[Stmt]) -> Result<()> {
+        for stmt in program {
+            match self.exec(stmt)? {
+                Flow::Next => {}
+                _ => return Err(runtime_error("return, break or continue outside a procedure or loop")),
+            }
+        }
+        Ok(())
+    }
+
+    /// `load_file` and `xopen`: run a file relative to the script's
+    /// directory, at most once with `once`
+    fn load(&mut self, file: &str, once: bool) -> Result<()> {
+        if BUILTIN_LIBRARIES.iter().any(|lib| Path::new(file).file_name() == Some(lib.as_ref())) {
+            return Ok(());
+        }
+        let path = self.directory.join(file);
+        if once && self.loaded.contains(&path) {
+            return Ok(());
+        }
+        let src = std::fs::read_to_string(&path)
+            .map_err(|e| runtime_error(format!("cannot open {}: {}", path.display(), e)))?;
+        self.loaded.insert(path.clone());
+        self.run_source(&src, None)
+            .map_err(|e| runtime_error(format!("in {}: {}", path.display(), e)))
+    }
+
+    /// The cell built by the script
+    pub fn cell(&self) -> &NeuronCell {
+        &self.sim.cells[0]
+    }
+
+    fn cell_mut(&mut self) -> &mut NeuronCell {
+        &mut self.sim.cells[0]
+    }
+
+    /// Value of a numeric global variable
+    pub fn get(&self, name: &str) -> Option<f64> {
+        match self.globals.vars.get(name) {
+            Some(Value::Num(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Statements
+    // -------------------------------------------------------------------------
+
+    fn exec_all(&mut self, stmts: &[Stmt]) -> Result<Flow> {
+        for stmt in stmts {
+            let flow = self.exec(stmt)?;
+            if !matches!(flow, Flow::Next) {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Run `stmt` with `section` as the current section
+    fn exec_on(&mut self, section: String, stmt: &Stmt) -> Result<Flow> {
+        self.stack.push(section);
+        let flow = self.exec(stmt);
+        self.stack.pop();
+        flow
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow> {
+        match stmt {
+            Stmt::Expr(e) => {
+                self.eval(e)?;
+            }
+            Stmt::Assign(target, op, value) => {
+                let value = self.eval(value)?;
+                let value = if *op == "=" {
+                    value
+                } else {
+                    let old = self.eval_num(target)?;
+                    let x = self.num(&value)?;
+                    Value::Num(match *op {
+                        "+=" => old + x,
+                        "-=" => old - x,
+                        "*=" => old * x,
+                        _ => old / x,
+                    })
+                };
+                self.assign(target, value)?;
+            }
+            Stmt::Block(stmts) => return self.exec_all(stmts),
+            Stmt::Create(secs) => {
+                // Sections of a template instance are named `Cell[0].soma`
+                let prefix = self.this().and_then(|id| self.instance_name(id));
+                for sec in secs {
+                    let base = match &prefix {
+                        Some(prefix) => format!("{}.{}", prefix, sec.name),
+                        None => sec.name.clone(),
+                    };
+                    let names = match &sec.index {
+                        Some(size) => {
+                            let n = self.eval_num(size)?.max(0.0) as usize;
+                            self.section_arrays.insert(base.clone(), n);
+                            (0..n).map(|k| format!("{}[{}]", base, k)).collect()
+                        }
+                        None => vec![base],
+                    };
+                    for name in names {
+                        self.cell_mut().sections.insert(name.clone(), Section::new(&name));
+                        self.created.retain(|c| c != &name);
+                        self.created.push(name);
+                    }
+                }
+            }
+            Stmt::Access(sec) => {
+                let name = self.section_name(sec)?;
+                self.cell_mut().access(&name)?;
+            }
+            Stmt::Insert(name) => {
+                let mechanism = match name.as_str() {
+                    "hh" => mechanisms::hh(),
+                    "pas" => mechanisms::pas(),
+                    "na" => mechanisms::hh_na(),
+                    "k" => mechanisms::hh_k(),
+                    "extracellular" => mechanisms::extracellular(),
+                    _ => match self.sim.mechanisms.get(name) {
+                        Some(compiled) => compiled.instance(),
+                        None => return Err(runtime_error(format!("unknown mechanism '{}'", name))),
+                    },
+                };
+                let section = self.current_section()?;
+                let sec = self.cell_mut().sections.get_mut(&section).unwrap();
+                if !sec.mechanisms.iter().any(|m| m.name == mechanism.name) {
+                    sec.insert(mechanism);
+                }
+            }
+            Stmt::Connect { child, end, parent, loc } => {
+                let child = self.section_name(child)?;
+                let end = self.eval_num(end)?;
+                let parent = match parent {
+                    Some(parent) => self.section_name(parent)?,
+                    None => self.current_section()?,
+                };
+                let loc = self.eval_num(loc)?;
+                self.cell_mut().connect(&child, end, &parent, loc)?;
+            }
+            Stmt::OnSection(sec, stmt) => {
+                let name = self.section_name(sec)?;
+                return self.exec_on(name, stmt);
+            }
+            Stmt::Forall(body) => {
+                for name in self.created.clone() {
+                    match self.exec_on(name, body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        _ => {}
+                    }
+                }
+            }
+            Stmt::Forsec(sections, body) => {
+                for name in self.sections_of(sections)? {
+                    match self.exec_on(name, body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        _ => {}
+                    }
+                }
+            }
+            Stmt::Ifsec(sections, body) => {
+                let section = self.current_section()?;
+                if self.sections_of(sections)?.contains(&section) {
+                    return self.exec(body);
+                }
+            }
+            Stmt::If(cond, then, otherwise) => {
+                if self.eval_num(cond)? != 0.0 {
+                    return self.exec(then);
+                } else if let Some(otherwise) = otherwise {
+                    return self.exec(otherwise);
+                }
+            }
+            Stmt::While(cond, body) => {
+                while self.eval_num(cond)? != 0.0 {
+                    match self.exec(body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        _ => {}
+                    }
+                }
+            }
+            Stmt::For(init, cond, step, body) => {
+                self.exec(init)?;
+                while self.eval_num(cond)? != 0.0 {
+                    match self.exec(body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        _ => {}
+                    }
+                    self.exec(step)?;
+                }
+            }
+            Stmt::ForRange(var, from, to, body) => {
+                let target = Expr::Name(var.clone());
+                let mut i = self.eval_num(from)?;
+                loop {
+                    let to = self.eval_num(to)?;
+                    if i > to {
+                        break;
+                    }
+                    self.assign(&target, Value::Num(i))?;
+                    match self.exec(body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        _ => {}
+                    }
+                    i = self.eval_num(&target)? + 1.0;
+                }
+            }
+            Stmt::ForSegments(var, ends, body) => {
+                let nseg = self.cell().sections[&self.current_section()?].nseg;
+                let centres = (0..nseg).map(|k| (k as f64 + 0.5) / nseg as f64);
+                let locations: Vec<f64> = match ends {
+                    true => std::iter::once(0.0).chain(centres).chain([1.0]).collect(),
+                    false => centres.collect(),
+                };
+                let target = Expr::Name(var.clone());
+                for x in locations {
+                    self.assign(&target, Value::Num(x))?;
+                    match self.exec(body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        _ => {}
+                    }
+                }
+            }
+            Stmt::RangeAssign(callee, [x1, x2, y1, y2]) => {
+                let (section, name) = self.range_target(callee)?;
+                let (x1, x2) = (self.eval_num(x1)?, self.eval_num(x2)?);
+                let (y1, y2) = (self.eval_num(y1)?, self.eval_num(y2)?);
+                let nseg = self.cell().sections[&section].nseg;
+                for k in 0..nseg {
+                    let x = (k as f64 + 0.5) / nseg as f64;
+                    if x < x1.min(x2) || x > x1.max(x2) {
+                        continue;
+                    }
+                    let y = if x2 == x1 { y1 } else { y1 + (y2 - y1) * (x - x1) / (x2 - x1) };
+                    if !self.section_set(&section, &name, Some(x), y)? {
+                        return Err(runtime_error(format!("{} has no range variable {}", section, name)));
+                    }
+                }
+            }
+            Stmt::Define(name, body) => {
+                self.procs.insert(name.clone(), body.clone());
+            }
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(e) => self.eval(e)?,
+                    None => Value::Num(0.0),
+                };
+                return Ok(Flow::Return(value));
+            }
+            Stmt::Break => return Ok(Flow::Break),
+            Stmt::Continue => return Ok(Flow::Continue),
+            Stmt::Local(names) | Stmt::Localobj(names) => {
+                let initial = match stmt {
+                    Stmt::Local(_) => Value::Num(0.0),
+                    _ => Value::Obj(None),
+                };
+                let frame = self.frames.last_mut()
+                    .ok_or_else(|| runtime_error("local outside a procedure"))?;
+                for name in names {
+                    frame.locals.insert(name.clone(), initial.clone());
+                }
+            }
+            Stmt::Template(template) => {
+                self.templates.insert(template.name.clone(), template.clone());
+            }
+            Stmt::Objref(names) => {
+                for name in names {
+                    self.scope_mut(name).vars.insert(name.clone(), Value::Obj(None));
+                }
+            }
+            Stmt::Strdef(names) => {
+                for name in names {
+                    self.scope_mut(name).vars.insert(name.clone(), Value::Str(String::new()));
+                }
+            }
+            Stmt::Double(arrays) => {
+                for (name, size) in arrays {
+                    let n = self.eval_num(size)?.max(0.0) as usize;
+                    self.scope_mut(name).arrays.insert(name.clone(), vec![0.0; n]);
+                }
+            }
+            Stmt::Print(items) => {
+                let mut parts = vec![];
+                for item in items {
+                    parts.push(match self.eval(item)? {
+                        Value::Num(x) => format_g(x, 8),
+                        Value::Str(s) => s,
+                        Value::Obj(Some(id)) => self.object_name(id),
+                        Value::Obj(None) => "NULLobject".to_string(),
+                        Value::Ref(Variable::Range { section, loc, name, .. }) => format!("{}.{}({})", section, name, loc),
+                        Value::Ref(Variable::Point { index, name, .. }) => format!("{}.{}", self.cell().point_processes[index].name, name),
+                        Value::Ref(variable) => format!("{:?}", variable),
+                    });
+                }
+                self.output.push_str(&parts.join(" "));
+                self.output.push('\n');
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    // -------------------------------------------------------------------------
+    // Sections
+    // -------------------------------------------------------------------------
+
+    fn section_name(&mut self, sec: &SecRef) -> Result<String> {
+        let base = self.section_base(sec.object.as_ref(), &sec.name)?.unwrap_or_else(|| sec.name.clone());
+        let name = match &sec.index {
+            Some(index) => format!("{}[{}]", base, self.eval_num(index)? as usize),
+            None => base,
+        };
+        if self.cell().sections.contains_key(&name) {
+            Ok(name)
+        } else {
+            Err(runtime_error(format!("{} is not a section", name)))
+        }
+    }
+
+    /// Full name of the section or section array `name`, of `object` or,
+    /// in a template, of the running instance if it has one
+    fn section_base(&mut self, object: Option<&Expr>, name: &str) -> Result<Option<String>> {
+        let id = match object {
+            // Only plain references are evaluated here, as they are again
+            // when they turn out not to name a section
+            Some(e @ (Expr::Name(_) | Expr::Member(..) | Expr::Index(..))) => match self.eval(e)? {
+                Value::Obj(Some(id)) => Some(id),
+                _ => return Ok(None),
+            },
+            Some(_) => return Ok(None),
+            None => self.this(),
+        };
+        let cell = &self.sim.cells[0];
+        let exists = |base: &String| cell.sections.contains_key(base) || self.section_arrays.contains_key(base);
+        let local = id.and_then(|id| self.instance_name(id))
+            .map(|prefix| format!("{}.{}", prefix, name))
+            .filter(|base| exists(base));
+        match object {
+            Some(_) => Ok(local),
+            None => Ok(local.or_else(|| Some(name.to_string()).filter(|name| exists(name)))),
+        }
+    }
+
+    /// Section named by `e`, if it is `name`, `name[i]`, `obj.name` or
+    /// `obj.name[i]` of an existing section
+    fn section_of(&mut self, e: &Expr) -> Result<Option<String>> {
+        let (object, name, index) = match e {
+            Expr::Name(name) => (None, name, None),
+            Expr::Member(object, name) => (Some(object.as_ref()), name, None),
+            Expr::Index(base, index) => match base.as_ref() {
+                Expr::Name(name) => (None, name, Some(index)),
+                Expr::Member(object, name) => (Some(object.as_ref()), name, Some(index)),
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        let Some(base) = self.section_base(object, name)? else {
+            return Ok(None);
+        };
+        match index {
+            None if self.cell().sections.contains_key(&base) => Ok(Some(base)),
+            Some(index) if self.section_arrays.contains_key(&base) => {
+                let sec = SecRef { object: object.cloned(), name: name.clone(), index: Some(index.as_ref().clone()) };
+                self.section_name(&sec).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Sections named by a `forsec`/`ifsec` pattern or SectionList, in
+    /// creation or list order
+    fn sections_of(&mut self, e: &Expr) -> Result<Vec<String>> {
+        match self.eval(e)? {
+            Value::Str(pattern) => {
+                let pattern = SectionPattern::new(&pattern)?;
+                Ok(self.created.iter().filter(|n| pattern.matches(n)).cloned().collect())
+            }
+            Value::Obj(Some(id)) => match &self.objects[id] {
+                Object::SectionList(list) => Ok(list.names.clone()),
+                _ => Err(runtime_error(format!("{} is not a SectionList", self.template(id)))),
+            },
+            _ => Err(runtime_error("forsec and ifsec expect a string or a SectionList")),
+        }
+    }
+
+    /// Section and name of the range variable in `var(x)` or `sec.var(x)`
+    fn range_target(&mut self, callee: &Expr) -> Result<(String, String)> {
+        match callee {
+            Expr::Member(base, member) => Ok((
+                self.section_of(base)?.ok_or_else(|| runtime_error("expected a section"))?,
+                member.clone(),
+            )),
+            Expr::Name(name) => Ok((self.current_section()?, name.clone())),
+            _ => Err(runtime_error("cannot assign to this expression")),
+        }
+    }
+
+    fn current_section(&self) -> Result<String> {
+        self.stack.last().cloned()
+            .or_else(|| self.cell().current().map(|s| s.name.clone()))
+            .or_else(|| self.created.first().cloned())
+            .ok_or_else(|| runtime_error("no section is accessed"))
+    }
+
+    /// Section variable `name` of `section`, at `loc` for range variables
+    fn section_get(&self, section: &str, name: &str, loc: Option<f64>) -> Option<f64> {
+        let sec = self.cell().sections.get(section)?;
+        match name {
+            "L" => Some(sec.length),
+            "diam" => Some(sec.diam),
+            "nseg" => Some(sec.nseg as f64),
+            "Ra" => Some(sec.ra),
+            "cm" => Some(sec.cm),
+            _ => sec.range(name, loc.unwrap_or(0.5)),
+        }
+    }
+
+    /// Set a section variable; returns false if `name` is not one
+    fn section_set(&mut self, section: &str, name: &str, loc: Option<f64>, x: f64) -> Result<bool> {
+        let sec = self.cell_mut().sections.get_mut(section)
+            .ok_or_else(|| runtime_error(format!("{} is not a section", section)))?;
+        match name {
+            "L" => sec.length = x,
+            "diam" => {
+                // As in NEURON, this overrides the diameters of the 3D points
+                sec.diam = x;
+                for p in &mut sec.pt3d {
+                    p.diam = x;
+                }
+            }
+            "nseg" => {
+                if x < 1.0 {
+                    return Err(runtime_error("nseg must be at least 1"));
+                }
+                sec.set_nseg(x as usize);
+            }
+            "Ra" => sec.ra = x,
+            "cm" => sec.cm = x,
+            _ => return Ok(sec.set_range(name, loc, x)),
+        }
+        Ok(true)
+    }
+
+    // -------------------------------------------------------------------------
+    // Expressions
+    // -------------------------------------------------------------------------
+
+    fn num(&self, value: &Value) -> Result<f64> {
+        match value {
+            Value::Num(x) => Ok(*x),
+            other => Err(runtime_error(format!("expected a number, got {:?}", other))),
+        }
+    }
+
+    fn eval_num(&mut self, e: &Expr) -> Result<f64> {
+        let value = self.eval(e)?;
+        self.num(&value)
+    }
+
+    fn template(&self, id: usize) -> String {
+        match &self.objects[id] {
+            Object::Point(k) => self.cell().point_processes[*k].name.clone(),
+            Object::Vector { .. } => "Vector".to_string(),
+            Object::SectionList(_) => "SectionList".to_string(),
+            Object::Instance { template, .. } => template.name.clone(),
+        }
+    }
+
+    /// `Vector[3]`, or `Cell[0]` for the first instance of a template
+    fn object_name(&self, id: usize) -> String {
+        self.instance_name(id).unwrap_or_else(|| format!("{}[{}]", self.template(id), id))
+    }
+
+    fn instance_name(&self, id: usize) -> Option<String> {
+        match &self.objects[id] {
+            Object::Instance { template, index, .. } => Some(format!("{}[{}]", template.name, index)),
+            _ => None,
+        }
+    }
+
+    /// Template instance whose procedure is running
+    fn this(&self) -> Option<usize> {
+        self.frames.last().and_then(|f| f.this)
+    }
+
+    fn is_external(template: &Template, name: &str) -> bool {
+        template.external.iter().any(|e| e == name)
+    }
+
+    /// Scope holding `name`: the running instance's, unless the template
+    /// declares it `external`, or the top level
+    fn scope(&self, name: &str) -> &Scope {
+        match self.this().map(|id| &self.objects[id]) {
+            Some(Object::Instance { template, scope, .. }) if !Self::is_external(template, name) => scope,
+            _ => &self.globals,
+        }
+    }
+
+    fn scope_mut(&mut self, name: &str) -> &mut Scope {
+        match self.this() {
+            Some(id) => match &mut self.objects[id] {
+                Object::Instance { template, scope, .. } if !Self::is_external(template, name) => scope,
+                _ => &mut self.globals,
+            },
+            None => &mut self.globals,
+        }
+    }
+
+    /// Members of an instance are only reached from outside if `public`
+    fn check_public(&self, id: usize, member: &str) -> Result<()> {
+        match &self.objects[id] {
+            Object::Instance { template, .. }
+                if self.this() != Some(id) && !template.public.iter().any(|p| p == member) =>
+            {
+                Err(runtime_error(format!("{} is not a public member of {}", member, template.name)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Procedure `name` and the instance it runs in: the running
+    /// instance's own, or a top-level one outside templates or if
+    /// declared `external`
+    fn proc_named(&self, name: &str) -> Option<(Rc<Stmt>, Option<usize>)> {
+        if let Some(id) = self.this() {
+            if let Object::Instance { template, .. } = &self.objects[id] {
+                if let Some(body) = template.procs.get(name) {
+                    return Some((body.clone(), Some(id)));
+                }
+                if !Self::is_external(template, name) {
+                    return None;
+                }
+            }
+        }
+        self.procs.get(name).map(|body| (body.clone(), None))
+    }
+
+    fn simulation_var(&self, name: &str) -> Option<f64> {
+        match name {
+            "t" => Some(self.sim.t),
+            "dt" => Some(self.sim.dt),
+            "tstop" => Some(self.sim.tstop),
+            "celsius" => Some(self.sim.celsius),
+            "v_init" => Some(self.sim.v_init),
+            "PI" => Some(std::f64::consts::PI),
+            "E" => Some(std::f64::consts::E),
+            _ => None,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Value> {
+        if let Some(value) = self.frames.last().and_then(|f| f.locals.get(name)) {
+            return Ok(value.clone());
+        }
+        if let (Some(id), "this") = (self.this(), name) {
+            return Ok(Value::Obj(Some(id)));
+        }
+        if let Some(x) = self.simulation_var(name) {
+            return Ok(Value::Num(x));
+        }
+        if let Ok(section) = self.current_section() {
+            if let Some(x) = self.section_get(&section, name, None) {
+                return Ok(Value::Num(x));
+            }
+        }
+        self.scope(name).vars.get(name).cloned()
+            .ok_or_else(|| runtime_error(format!("undefined variable '{}'", name)))
+    }
+
+    fn eval(&mut self, e: &Expr) -> Result<Value> {
+        Ok(match e {
+            Expr::Num(x) => Value::Num(*x),
+            Expr::Str(s) => Value::Str(s.clone()),
+            Expr::Arg(index) => self.frames.last()
+                .and_then(|f| f.args.get(index.wrapping_sub(1)))
+                .cloned()
+                .ok_or_else(|| runtime_error(format!("argument ${} not passed", index)))?,
+            Expr::Name(name) => self.lookup(name)?,
+            Expr::Neg(a) => Value::Num(-self.eval_num(a)?),
+            Expr::Span(..) => return Err(runtime_error("a:b is only allowed in range assignments")),
+            Expr::Not(a) => Value::Num(if self.eval_num(a)? == 0.0 { 1.0 } else { 0.0 }),
+            Expr::Binary(op, a, b) => {
+                // && and || short-circuit
+                let a = self.eval_num(a)?;
+                match *op {
+                    "&&" if a == 0.0 => return Ok(Value::Num(0.0)),
+                    "||" if a != 0.0 => return Ok(Value::Num(1.0)),
+                    _ => {}
+                }
+                let b = self.eval_num(b)?;
+                let truth = |c: bool| if c { 1.0 } else { 0.0 };
+                Value::Num(match *op {
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    "/" => {
+                        if b == 0.0 {
+                            return Err(runtime_error("division by zero"));
+                        }
+                        a / b
+                    }
+                    "%" => a - b * (a / b).floor(),
+                    "^" => a.powf(b),
+                    "<" => truth(a < b),
+                    "<=" => truth(a <= b + FLOAT_EPSILON),
+                    ">" => truth(a > b),
+                    ">=" => truth(a + FLOAT_EPSILON >= b),
+                    "==" => truth((a - b).abs() <= FLOAT_EPSILON),
+                    "!=" => truth((a - b).abs() > FLOAT_EPSILON),
+                    _ => truth(b != 0.0),
+                })
+            }
+            Expr::Index(base, index) => {
+                let i = self.eval_num(index)?;
+                match base.as_ref() {
+                    Expr::Name(name) if self.scope(name).arrays.contains_key(name) => {
+                        let array = &self.scope(name).arrays[name];
+                        Value::Num(*array.get(i as usize)
+                            .ok_or_else(|| runtime_error(format!("index {} out of range for {}", i, name)))?)
+                    }
+                    Expr::Member(obj, member) => {
+                        let id = self.object(obj)?;
+                        match &self.objects[id] {
+                            Object::Instance { scope, .. } => {
+                                self.check_public(id, member)?;
+                                let array = scope.arrays.get(member)
+                                    .ok_or_else(|| runtime_error(format!("{} has no array {}", self.template(id), member)))?;
+                                Value::Num(*array.get(i as usize)
+                                    .ok_or_else(|| runtime_error(format!("index {} out of range for {}", i, member)))?)
+                            }
+                            Object::Vector { .. } if member == "x" => {
+                                let data = self.vector_data(id)?;
+                                Value::Num(*data.get(i as usize)
+                                    .ok_or_else(|| runtime_error(format!("index {} out of range", i)))?)
+                            }
+                            // Array parameters of point processes: `vc.amp[1]`
+                            _ => self.object_get(id, &format!("{}{}", member, i))?,
+                        }
+                    }
+                    _ => return Err(runtime_error("only arrays and Vector.x can be indexed")),
+                }
+            }
+            Expr::Member(base, member) => {
+                if let Some(section) = self.section_of(base)? {
+                    Value::Num(self.section_get(&section, member, None)
+                        .ok_or_else(|| runtime_error(format!("{} has no variable {}", section, member)))?)
+                } else {
+                    let id = self.object(base)?;
+                    self.object_get(id, member)?
+                }
+            }
+            Expr::Call(callee, args) => self.call(callee, args)?,
+            Expr::New(template, args) => {
+                let args = args.iter().map(|a| self.eval(a)).collect::<Result<Vec<_>>>()?;
+                self.new_object(template, &args)?
+            }
+            Expr::Ref(target) => {
+                let range = |section: String, loc: f64, name: &str| {
+                    Value::Ref(Variable::Range { cell: 0, section, loc, name: name.to_string() })
+                };
+                match target.as_ref() {
+                    Expr::Name(name) if name == "t" => Value::Ref(Variable::Time),
+                    Expr::Call(callee, args) if args.len() == 1 => {
+                        let loc = self.eval_num(&args[0])?;
+                        match callee.as_ref() {
+                            Expr::Member(base, var) => {
+                                let section = self.section_of(base)?
+                                    .ok_or_else(|| runtime_error("expected &section.var(x)"))?;
+                                range(section, loc, var)
+                            }
+                            Expr::Name(var) => range(self.current_section()?, loc, var),
+                            _ => return Err(runtime_error("expected &var(x)")),
+                        }
+                    }
+                    Expr::Member(base, var) => match self.section_of(base)? {
+                        Some(section) => range(section, 0.5, var),
+                        None => {
+                            let id = self.object(base)?;
+                            match &self.objects[id] {
+                                Object::Point(k) => Value::Ref(Variable::Point { cell: 0, index: *k, name: var.clone() }),
+                                _ => return Err(runtime_error("only point process variables can be referenced")),
+                            }
+                        }
+                    },
+                    _ => return Err(runtime_error("only &t, &var(x) and &point.var references are supported")),
+                }
+            }
+        })
+    }
+
+    fn object(&mut self, e: &Expr) -> Result<usize> {
+        match self.eval(e)? {
+            Value::Obj(Some(id)) => Ok(id),
+            Value::Obj(None) => Err(runtime_error("object reference is NULL")),
+            other => Err(runtime_error(format!("expected an object, got {:?}", other))),
+        }
+    }
+
+    fn vector_data(&self, id: usize) -> Result<&[f64]> {
+        match &self.objects[id] {
+            Object::Vector { key, recording: true, .. } => {
+                Ok(self.sim.recordings.get(key).map_or(&[][..], Vec::as_slice))
+            }
+            Object::Vector { data, .. } => Ok(data),
+            _ => Err(runtime_error("not a Vector")),
+        }
+    }
+
+    fn object_get(&self, id: usize, member: &str) -> Result<Value> {
+        match &self.objects[id] {
+            Object::Point(k) => {
+                let pp = &self.cell().point_processes[*k];
+                let name = if member == "del" { "delay" } else { member };
+                pp.parameters.get(name).or_else(|| pp.state.get(name))
+                    .map(|&x| Value::Num(x))
+                    .ok_or_else(|| runtime_error(format!("{} has no variable {}", pp.name, member)))
+            }
+            Object::Instance { template, scope, .. } => {
+                self.check_public(id, member)?;
+                scope.vars.get(member).cloned()
+                    .ok_or_else(|| runtime_error(format!("{} has no variable {}", template.name, member)))
+            }
+            _ => Err(runtime_error(format!("{} has no variable {}", self.template(id), member))),
+        }
+    }
+
+    /// Set parameter `member` of point process `k`
+    fn point_set(&mut self, k: usize, member: &str, x: f64) -> Result<()> {
+        let name = if member == "del" { "delay" } else { member };
+        let pp = &mut self.cell_mut().point_processes[k];
+        if !pp.parameters.contains_key(name) {
+            return Err(runtime_error(format!("{} has no parameter {}", pp.name, member)));
+        }
+        pp.parameters.insert(name.to_string(), x);
+        Ok(())
+    }
+
+    fn new_object(&mut self, template: &str, args: &[Value]) -> Result<Value> {
+        let object = match template {
+            "Vector" => {
+                let n = match args.first() {
+                    Some(n) => self.num(n)? as usize,
+                    None => 0,
+                };
+                Object::Vector { key: format!("Vector[{}]", self.objects.len()), data: vec![0.0; n], recording: false }
+            }
+            "SectionList" => Object::SectionList(SectionList::new()),
+            "IClamp" | "IRamp" | "ISine" | "INoise" | "ExpSyn" | "Exp2Syn" | "SEClamp" | "VClamp" | "APCount" => {
+                let loc = match args.first() {
+                    Some(loc) => self.num(loc)?,
+                    None => 0.5,
+                };
+                let section = self.current_section()?;
+                let pp = match template {
+                    "IClamp" => mechanisms::iclamp(&section, loc, 0.0, 0.0, 0.0),
+                    "IRamp" => mechanisms::iramp(&section, loc, 0.0, 0.0, 0.0, 0.0),
+                    "ISine" => mechanisms::isine(&section, loc, 0.0, 0.0, 0.0, 0.0),
+                    "INoise" => mechanisms::inoise(&section, loc, 0.0, 0.0, 0.0, 0.0, 1.0),
+                    "ExpSyn" => mechanisms::exp_syn(&section, loc),
+                    "Exp2Syn" => mechanisms::exp2_syn(&section, loc),
+                    "SEClamp" => mechanisms::seclamp(&section, loc, &[]),
+                    "APCount" => mechanisms::apcount(&section, loc, -20.0),
+                    _ => mechanisms::vclamp(&section, loc, &[]),
+                };
+                self.cell_mut().add_point_process(pp);
+                Object::Point(self.cell().point_processes.len() - 1)
+            }
+            _ => match self.templates.get(template) {
+                Some(template) => return self.instantiate(template.clone(), args),
+                None => return Err(runtime_error(format!("unknown template '{}'", template))),
+            },
+        };
+        self.objects.push(object);
+        Ok(Value::Obj(Some(self.objects.len() - 1)))
+    }
+
+    /// New instance of a HOC template: its declarations, then `init`
+    fn instantiate(&mut self, template: Rc<Template>, args: &[Value]) -> Result<Value> {
+        let index = self.objects.iter()
+            .filter(|o| matches!(o, Object::Instance { template: t, .. } if t.name == template.name))
+            .count();
+        let id = self.objects.len();
+        self.objects.push(Object::Instance { template: template.clone(), index, scope: Scope::default() });
+        self.frames.push(Frame { this: Some(id), ..Frame::default() });
+        let flow = self.exec_all(&template.body);
+        self.frames.pop();
+        flow?;
+        if let Some(init) = template.procs.get("init") {
+            self.call_proc(init.clone(), args.to_vec(), Some(id))?;
+        }
+        Ok(Value::Obj(Some(id)))
+    }
+
+    fn assign(&mut self, target: &Expr, value: Value) -> Result<()> {
+        match target {
+            Expr::Name(name) => {
+                if let Some(frame) = self.frames.last_mut() {
+                    if let Some(local) = frame.locals.get_mut(name) {
+                        *local = value;
+                        return Ok(());
+                    }
+                }
+                if self.simulation_var(name).is_some() {
+                    let x = self.num(&value)?;
+                    match name.as_str() {
+                        "t" => self.sim.t = x,
+                        "dt" => self.sim.dt = x,
+                        "tstop" => self.sim.tstop = x,
+                        "celsius" => self.sim.celsius = x,
+                        "v_init" => self.sim.v_init = x,
+                        _ => return Err(runtime_error(format!("{} is read-only", name))),
+                    }
+                    return Ok(());
+                }
+                if let (Value::Num(x), Ok(section)) = (&value, self.current_section()) {
+                    if !self.scope(name).vars.contains_key(name) && self.section_set(&section, name, None, *x)? {
+                        return Ok(());
+                    }
+                }
+                self.scope_mut(name).vars.insert(name.clone(), value);
+            }
+            Expr::Index(base, index) => {
+                let i = self.eval_num(index)? as usize;
+                let x = self.num(&value)?;
+                match base.as_ref() {
+                    Expr::Name(name) if self.scope(name).arrays.contains_key(name) => {
+                        let array = self.scope_mut(name).arrays.get_mut(name).unwrap();
+                        *array.get_mut(i).ok_or_else(|| runtime_error(format!("index {} out of range for {}", i, name)))? = x;
+                    }
+                    Expr::Member(obj, member) => {
+                        let id = self.object(obj)?;
+                        self.check_public(id, member)?;
+                        match &mut self.objects[id] {
+                            Object::Instance { scope, .. } if scope.arrays.contains_key(member) => {
+                                let array = scope.arrays.get_mut(member).unwrap();
+                                *array.get_mut(i).ok_or_else(|| runtime_error(format!("index {} out of range for {}", i, member)))? = x;
+                            }
+                            Object::Vector { data, recording: false, .. } if member == "x" => {
+                                *data.get_mut(i).ok_or_else(|| runtime_error(format!("index {} out of range", i)))? = x;
+                            }
+                            Object::Vector { .. } if member == "x" => return Err(runtime_error("cannot assign to a recording")),
+                            Object::Point(k) => {
+                                let k = *k;
+                                self.point_set(k, &format!("{}{}", member, i), x)?
+                            }
+                            _ => return Err(runtime_error(format!("{} has no array {}", self.template(id), member))),
+                        }
+                    }
+                    _ => return Err(runtime_error("only arrays and Vector.x can be indexed")),
+                }
+            }
+            Expr::Member(base, member) => {
+                if let Some(section) = self.section_of(base)? {
+                    let x = self.num(&value)?;
+                    if !self.section_set(&section, member, None, x)? {
+                        return Err(runtime_error(format!("{} has no variable {}", section, member)));
+                    }
+                } else {
+                    let id = self.object(base)?;
+                    self.check_public(id, member)?;
+                    match &mut self.objects[id] {
+                        Object::Instance { scope, .. } => {
+                            scope.vars.insert(member.clone(), value);
+                        }
+                        Object::Point(k) => {
+                            let k = *k;
+                            let x = self.num(&value)?;
+                            self.point_set(k, member, x)?
+                        }
+                        _ => return Err(runtime_error(format!("{} has no variable {}", self.template(id), member))),
+                    }
+                }
+            }
+            // Range variable at a location: `v(0.5) = -70`, `soma.gnabar_hh(0.5) = 0.1`
+            Expr::Call(callee, args) if args.len() == 1 => {
+                let loc = self.eval_num(&args[0])?;
+                let x = self.num(&value)?;
+                let (section, name) = self.range_target(callee)?;
+                // The ends of a section carry no membrane, so `for (x)`
+                // loops setting densities leave the segments alone there
+                if (loc <= 0.0 || loc >= 1.0) && name != "v" {
+                    if self.section_get(&section, &name, Some(loc)).is_none() {
+                        return Err(runtime_error(format!("{} has no variable {}", section, name)));
+                    }
+                } else if !self.section_set(&section, &name, Some(loc), x)? {
+                    return Err(runtime_error(format!("{} has no variable {}", section, name)));
+                }
+            }
+            _ => return Err(runtime_error("cannot assign to this expression")),
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Calls
+    // -------------------------------------------------------------------------
+
+    fn call(&mut self, callee: &Expr, args: &[Expr]) -> Result<Value> {
+        match callee {
+            Expr::Member(base, member) => {
+                if let Some(section) = self.section_of(base)? {
+                    // Range variable at a location: soma.v(0.5)
+                    let loc = match args {
+                        [loc] => Some(self.eval_num(loc)?),
+                        _ => return Err(runtime_error(format!("{}.{} takes one location", section, member))),
+                    };
+                    return self.section_get(&section, member, loc).map(Value::Num)
+                        .ok_or_else(|| runtime_error(format!("{} has no variable {}", section, member)));
+                }
+                let id = self.object(base)?;
+                let args = args.iter().map(|a| self.eval(a)).collect::<Result<Vec<_>>>()?;
+                self.method(id, member, &args)
+            }
+            Expr::Name(name) => {
+                // sprint assigns its first argument, so it is not evaluated
+                if name == "sprint" {
+                    let (target, rest) = args.split_first().ok_or_else(|| runtime_error("sprint needs a string"))?;
+                    let rest = rest.iter().map(|a| self.eval(a)).collect::<Result<Vec<_>>>()?;
+                    let text = self.format(&rest)?;
+                    self.assign(target, Value::Str(text))?;
+                    return Ok(Value::Num(0.0));
+                }
+                let args = args.iter().map(|a| self.eval(a)).collect::<Result<Vec<_>>>()?;
+                if let Some((body, this)) = self.proc_named(name) {
+                    return self.call_proc(body, args, this);
+                }
+                // Range variable of the current section: v(0.5)
+                if let [Value::Num(loc)] = args.as_slice() {
+                    if let Ok(section) = self.current_section() {
+                        if let Some(x) = self.section_get(&section, name, Some(*loc)) {
+                            return Ok(Value::Num(x));
+                        }
+                    }
+                }
+                self.builtin(name, &args)
+            }
+            _ => Err(runtime_error("expression is not callable")),
+        }
+    }
+
+    /// Run a `proc`, `func` or `obfunc`, in the instance `this` for
+    /// template procedures
+    fn call_proc(&mut self, body: Rc<Stmt>, args: Vec<Value>, this: Option<usize>) -> Result<Value> {
+        self.frames.push(Frame { args, locals: HashMap::new(), this });
+        let flow = self.exec(&body);
+        self.frames.pop();
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Num(0.0)),
+        }
+    }
+
+    /// Values of a Vector that may be changed
+    fn vector_mut(&mut self, id: usize) -> Result<&mut Vec<f64>> {
+        match &mut self.objects[id] {
+            Object::Vector { data, recording: false, .. } => Ok(data),
+            Object::Vector { .. } => Err(runtime_error("cannot change a recording")),
+            _ => Err(runtime_error("not a Vector")),
+        }
+    }
+
+    fn method(&mut self, id: usize, method: &str, args: &[Value]) -> Result<Value> {
+        if let Object::Instance { template, .. } = &self.objects[id] {
+            let body = template.procs.get(method).cloned()
+                .ok_or_else(|| runtime_error(format!("{} has no method {}", template.name, method)))?;
+            self.check_public(id, method)?;
+            return self.call_proc(body, args.to_vec(), Some(id));
+        }
+        let nums = || args.iter().map(|a| self.num(a)).collect::<Result<Vec<_>>>();
+        match (&self.objects[id], method) {
+            (Object::Vector { key, .. }, "record") => {
+                let key = key.clone();
+                let (variable, interval) = match args {
+                    [Value::Ref(variable)] => (variable.clone(), None),
+                    [Value::Ref(variable), dt] => (variable.clone(), Some(self.num(dt)?)),
+                    _ => return Err(runtime_error("Vector.record expects &var and an optional Dt")),
+                };
+                if interval.is_some_and(|dt| dt <= 0.0) {
+                    return Err(runtime_error("Vector.record interval must be positive"));
+                }
+                self.sim.record(&key, variable, interval);
+                if let Object::Vector { recording, .. } = &mut self.objects[id] {
+                    *recording = true;
+                }
+                Ok(Value::Num(1.0))
+            }
+            (Object::Vector { .. }, "play") => {
+                let values = self.vector_data(id)?.to_vec();
+                let Some(Value::Ref(variable)) = args.first() else {
+                    return Err(runtime_error("Vector.play expects &var"));
+                };
+                let mut play = match &args[1..] {
+                    [Value::Obj(Some(times)), ..] => {
+                        let times = self.vector_data(*times)?.to_vec();
+                        vector::Play::new(variable.clone(), times, values)?
+                    }
+                    [dt, ..] => vector::Play::with_interval(variable.clone(), self.num(dt)?, values)?,
+                    [] => return Err(runtime_error("Vector.play expects a time Vector or Dt")),
+                };
+                if let Some(continuous) = args.get(2) {
+                    play.continuous = self.num(continuous)? != 0.0;
+                }
+                self.sim.play(play);
+                Ok(Value::Num(1.0))
+            }
+            (Object::Vector { .. }, "size") => Ok(Value::Num(self.vector_data(id)?.len() as f64)),
+            (Object::Vector { .. }, "append") => {
+                let xs = args.iter().map(|a| self.num(a)).collect::<Result<Vec<_>>>()?;
+                match &mut self.objects[id] {
+                    Object::Vector { data, recording: false, .. } => data.extend(xs),
+                    _ => return Err(runtime_error("cannot append to a recording")),
+                }
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::Vector { .. }, "resize") => {
+                let [n] = nums()?[..] else {
+                    return Err(runtime_error("Vector.resize takes the new size"));
+                };
+                self.vector_mut(id)?.resize(n.max(0.0) as usize, 0.0);
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::Vector { .. }, "indgen") => {
+                // indgen(), indgen(step), indgen(start, step) over the
+                // current size, or indgen(start, stop, step) resizing
+                let (start, stop, step) = match nums()?[..] {
+                    [] => (0.0, None, 1.0),
+                    [step] => (0.0, None, step),
+                    [start, step] => (start, None, step),
+                    [start, stop, step] => (start, Some(stop), step),
+                    _ => return Err(runtime_error("Vector.indgen takes at most start, stop and step")),
+                };
+                let data = self.vector_mut(id)?;
+                if let Some(stop) = stop {
+                    if step <= 0.0 || stop < start {
+                        return Err(runtime_error("Vector.indgen needs start <= stop and a positive step"));
+                    }
+                    data.resize(((stop - start) / step + FLOAT_EPSILON).floor() as usize + 1, 0.0);
+                }
+                for (k, x) in data.iter_mut().enumerate() {
+                    *x = start + step * k as f64;
+                }
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::Vector { .. }, "fill") => {
+                let (x, range) = match nums()?[..] {
+                    [x] => (x, None),
+                    [x, from, to] => (x, Some((from as usize, to as usize))),
+                    _ => return Err(runtime_error("Vector.fill takes a value and an optional index range")),
+                };
+                let data = self.vector_mut(id)?;
+                let (from, to) = range.unwrap_or((0, data.len().saturating_sub(1)));
+                for y in data.iter_mut().take(to + 1).skip(from) {
+                    *y = x;
+                }
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::Vector { .. }, "add" | "sub" | "mul" | "div") => {
+                // By a number or element by element by a Vector of the same size
+                let n = self.vector_data(id)?.len();
+                let operands = match args {
+                    [Value::Obj(Some(other))] => self.vector_data(*other)?.to_vec(),
+                    [x] => vec![self.num(x)?; n],
+                    _ => return Err(runtime_error(format!("Vector.{} takes a number or a Vector", method))),
+                };
+                if operands.len() != n {
+                    return Err(runtime_error(format!("Vector.{} needs Vectors of the same size", method)));
+                }
+                for (a, b) in self.vector_mut(id)?.iter_mut().zip(operands) {
+                    *a = match method {
+                        "add" => *a + b,
+                        "sub" => *a - b,
+                        "mul" => *a * b,
+                        _ => *a / b,
+                    };
+                }
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::Vector { .. }, "sum" | "mean" | "max" | "min") => {
+                let data = self.vector_data(id)?;
+                if data.is_empty() && method != "sum" {
+                    return Err(runtime_error(format!("Vector.{} of an empty Vector", method)));
+                }
+                let sum: f64 = data.iter().sum();
+                Ok(Value::Num(match method {
+                    "sum" => sum,
+                    "mean" => sum / data.len() as f64,
+                    "max" => data.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                    _ => data.iter().copied().fold(f64::INFINITY, f64::min),
+                }))
+            }
+            (Object::Vector { .. }, "contains") => {
+                let [x] = nums()?[..] else {
+                    return Err(runtime_error("Vector.contains takes one value"));
+                };
+                let found = self.vector_data(id)?.iter().any(|y| (x - y).abs() <= FLOAT_EPSILON);
+                Ok(Value::Num(found as u8 as f64))
+            }
+            (Object::Vector { .. }, "sort" | "reverse") => {
+                let data = self.vector_mut(id)?;
+                match method {
+                    "sort" => data.sort_by(f64::total_cmp),
+                    _ => data.reverse(),
+                }
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::Vector { .. }, "c") => {
+                let data = self.vector_data(id)?.to_vec();
+                self.objects.push(Object::Vector { key: format!("Vector[{}]", self.objects.len()), data, recording: false });
+                Ok(Value::Obj(Some(self.objects.len() - 1)))
+            }
+            (Object::Vector { .. }, "copy") => {
+                let source = match args {
+                    [Value::Obj(Some(source))] => self.vector_data(*source)?.to_vec(),
+                    _ => return Err(runtime_error("Vector.copy takes a Vector")),
+                };
+                *self.vector_mut(id)? = source;
+                Ok(Value::Obj(Some(id)))
+            }
+            (Object::SectionList(_), "append" | "remove" | "wholetree") => {
+                let section = self.current_section()?;
+                let names = match method {
+                    "wholetree" => self.cell().wholetree(&section)?.names,
+                    _ => vec![section],
+                };
+                let Object::SectionList(list) = &mut self.objects[id] else { unreachable!() };
+                let mut changed = 0;
+                for name in &names {
+                    changed += match method {
+                        "remove" => list.remove(name),
+                        _ => list.append(name),
+                    } as usize;
+                }
+                Ok(Value::Num(changed as f64))
+            }
+            _ => Err(runtime_error(format!("{} has no method {}", self.template(id), method))),
+        }
+    }
+
+    fn builtin(&mut self, name: &str, args: &[Value]) -> Result<Value> {
+        if IGNORED_CALLS.contains(&name) {
+            return Ok(Value::Num(1.0));
+        }
+        let nums = || args.iter().map(|a| self.num(a)).collect::<Result<Vec<_>>>();
+        let unary = |f: fn(f64) -> f64| -> Result<Value> {
+            match nums()?.as_slice() {
+                [x] => Ok(Value::Num(f(*x))),
+                _ => Err(runtime_error(format!("{} takes one argument", name))),
+            }
+        };
+        match name {
+            "sin" => unary(f64::sin),
+            "cos" => unary(f64::cos),
+            "tan" => unary(f64::tan),
+            "atan" => unary(f64::atan),
+            "exp" => unary(f64::exp),
+            "log" => unary(f64::ln),
+            "log10" => unary(f64::log10),
+            "sqrt" => unary(f64::sqrt),
+            "abs" | "fabs" => unary(f64::abs),
+            "int" => unary(f64::trunc),
+            "floor" => unary(f64::floor),
+            "ceil" => unary(f64::ceil),
+            "atan2" => match nums()?.as_slice() {
+                [y, x] => Ok(Value::Num(y.atan2(*x))),
+                _ => Err(runtime_error("atan2 takes two arguments")),
+            },
+            "printf" => {
+                let text = self.format(args)?;
+                self.output.push_str(&text);
+                Ok(Value::Num(text.len() as f64))
+            }
+            "strcmp" => match args {
+                [Value::Str(a), Value::Str(b)] => Ok(Value::Num(a.cmp(b) as i8 as f64)),
+                _ => Err(runtime_error("strcmp expects two strings")),
+            },
+            // Statements in a string, at the top level or in an instance;
+            // execute1 returns 0 instead of failing
+            "execute" | "execute1" => {
+                let (src, this) = match args {
+                    [Value::Str(src)] => (src, None),
+                    [Value::Str(src), Value::Obj(Some(id))] if self.instance_name(*id).is_some() => (src, Some(*id)),
+                    _ => return Err(runtime_error(format!("{} expects a string and an optional template object", name))),
+                };
+                let result = self.run_source(src, this);
+                match name {
+                    "execute" => result.map(|()| Value::Num(1.0)),
+                    _ => Ok(Value::Num(result.is_ok() as u8 as f64)),
+                }
+            }
+            // load_file("f") runs a file once, load_file(1, "f") again and
+            // load_file("f", "name") unless `name` is defined
+            "load_file" | "xopen" => {
+                let (file, once) = match (name, args) {
+                    ("xopen", [Value::Str(file)]) => (file, false),
+                    ("load_file", [Value::Str(file)]) => (file, true),
+                    ("load_file", [Value::Num(force), Value::Str(file)]) => (file, *force == 0.0),
+                    ("load_file", [Value::Str(file), Value::Str(symbol)]) => {
+                        let defined = self.procs.contains_key(symbol) || self.templates.contains_key(symbol)
+                            || self.globals.vars.contains_key(symbol);
+                        if defined {
+                            return Ok(Value::Num(1.0));
+                        }
+                        (file, true)
+                    }
+                    _ => return Err(runtime_error(format!("{} expects a file name", name))),
+                };
+                self.load(file, once)?;
+                Ok(Value::Num(1.0))
+            }
+            "secname" => Ok(Value::Str(self.current_section()?)),
+            "issection" => match args {
+                [Value::Str(pattern)] => {
+                    let matches = SectionPattern::new(pattern)?.matches(&self.current_section()?);
+                    Ok(Value::Num(matches as u8 as f64))
+                }
+                _ => Err(runtime_error("issection expects a string")),
+            },
+            "pt3dadd" => {
+                let section = self.current_section()?;
+                let [x, y, z, diam] = nums()?[..] else {
+                    return Err(runtime_error("pt3dadd takes x, y, z and diam"));
+                };
+                let sec = self.cell_mut().sections.get_mut(&section).unwrap();
+                sec.pt3dadd(Point3d { x, y, z, diam });
+                Ok(Value::Num(sec.pt3d.len() as f64))
+            }
+            "pt3dclear" => {
+                let section = self.current_section()?;
+                self.cell_mut().sections.get_mut(&section).unwrap().pt3dclear();
+                Ok(Value::Num(0.0))
+            }
+            "n3d" | "x3d" | "y3d" | "z3d" | "diam3d" | "area" => {
+                let section = self.current_section()?;
+                let sec = &self.cell().sections[&section];
+                let x = match (name, nums()?.as_slice()) {
+                    ("n3d", []) => sec.pt3d.len() as f64,
+                    ("area", [loc]) => sec.segment_area(sec.segment(*loc)) * 1e8,
+                    (_, [i]) => {
+                        let p = sec.pt3d.get(*i as usize)
+                            .ok_or_else(|| runtime_error(format!("{} has no 3D point {}", section, i)))?;
+                        match name {
+                            "x3d" => p.x,
+                            "y3d" => p.y,
+                            "z3d" => p.z,
+                            _ => p.diam,
+                        }
+                    }
+                    _ => return Err(runtime_error(format!("wrong number of arguments to {}", name))),
+                };
+                Ok(Value::Num(x))
+            }
+            "finitialize" | "init" | "stdinit" => {
+                let v = match nums()?.as_slice() {
+                    [v] => *v,
+                    _ => self.sim.v_init,
+                };
+                self.sim.finitialize(v)?;
+                Ok(Value::Num(0.0))
+            }
+            "fadvance" => {
+                self.sim.fadvance()?;
+                Ok(Value::Num(0.0))
+            }
+            "run" => {
+                self.sim.finitialize(self.sim.v_init)?;
+                self.sim.run()?;
+                Ok(Value::Num(0.0))
+            }
+            "continuerun" => match nums()?.as_slice() {
+                [tstop] => {
+                    self.sim.continuerun(*tstop)?;
+                    Ok(Value::Num(0.0))
+                }
+                _ => Err(runtime_error("continuerun takes one argument")),
+            },
+            "cvode_active" => {
+                if let [on] = nums()?.as_slice() {
+                    self.sim.cvode_active(*on != 0.0);
+                }
+                Ok(Value::Num(if self.sim.cvode.active { 1.0 } else { 0.0 }))
+            }
+            _ => Err(runtime_error(format!("undefined function '{}'", name))),
+        }
+    }
+
+    /// C-style formatting for `printf` and `sprint`
+    fn format(&self, args: &[Value]) -> Result<String> {
+        let (fmt, args) = match args.split_first() {
+            Some((Value::Str(fmt), rest)) => (fmt, rest),
+            _ => return Err(runtime_error("expected a format string")),
+        };
+        let mut out = String::new();
+        let mut args = args.iter();
+        let mut chars = fmt.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            if chars.peek() == Some(&'%') {
+                chars.next();
+                out.push('%');
+                continue;
+            }
+            let mut spec = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | ' ' | '#') {
+                    spec.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let conversion = chars.next().ok_or_else(|| runtime_error("incomplete format"))?;
+            let left = spec.starts_with('-');
+            let spec = spec.trim_start_matches(['-', '+', ' ', '#']);
+            let (width, precision) = match spec.split_once('.') {
+                Some((w, p)) => (w.parse().unwrap_or(0), Some(p.parse().unwrap_or(0))),
+                None => (spec.parse().unwrap_or(0), None),
+            };
+            let arg = args.next().ok_or_else(|| runtime_error("too few arguments for format"))?;
+            let text = match (conversion, arg) {
+                ('s', Value::Str(s)) => s.clone(),
+                ('s', Value::Num(x)) => format_g(*x, 8),
+                ('d' | 'i', value) => format!("{}", self.num(value)?.trunc() as i64),
+                ('f', value) => format!("{:.*}", precision.unwrap_or(6), self.num(value)?),
+                ('e', value) => format_e(self.num(value)?, precision.unwrap_or(6)),
+                ('g', value) => format_g(self.num(value)?, precision.unwrap_or(6)),
+                ('c', value) => char::from_u32(self.num(value)? as u32).unwrap_or('?').to_string(),
+                (c, _) => return Err(runtime_error(format!("unsupported format %{}", c))),
+            };
+            if left {
+                out.push_str(&format!("{:<width$}", text, width = width));
+            } else {
+                out.push_str(&format!("{:>width$}", text, width = width));
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// `%.*e`: exponent with a sign and at least two digits
+fn format_e(x: f64, precision: usize) -> String {
+    let s = format!("{:.*e}", precision, x);
+    match s.split_once('e') {
+        Some((mantissa, exp)) => {
+            let (sign, digits) = match exp.strip_prefix('-') {
+                Some(d) => ('-', d),
+                None => ('+', exp),
+            };
+            format!("{}e{}{:0>2}", mantissa, sign, digits)
+        }
+        None => s,
+    }
+}
+
+/// `%.*g`: shortest of fixed and exponent notation, trailing zeros removed
+fn format_g(x: f64, precision: usize) -> String {
+    if x == 0.0 || !x.is_finite() {
+        return format!("{}", x);
+    }
+    let p = precision.max(1);
+    let exp = x.abs().log10().floor() as i32;
+    let trim = |s: String| -> String {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s
+        }
+    };
+    if exp < -4 || exp >= p as i32 {
+        let s = format_e(x, p - 1);
+        match s.split_once('e') {
+            Some((mantissa, exp)) => format!("{}e{}", trim(mantissa.to_string()), exp),
+            None => s,
+        }
+    } else {
+        trim(format!("{:.*}", (p as i32 - 1 - exp).max(0) as usize, x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_flow_and_procs() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            func fact() {
+                if ($1 <= 1) { return 1 }
+                return $1 * fact($1 - 1)
+            }
+            proc count() { local i
+                total = 0
+                for i = 1, $1 {
+                    if (i % 2 == 0) continue
+                    total += i
+                }
+            }
+            double squares[5]
+            for (i = 0; i < 5; i += 1) squares[i] = i^2
+            count(9)
+            n = 0
+            while (1) {
+                n = n + 1
+                if (n >= 3) break
+            }
+            strdef name
+            sprint(name, "%s-%d", "cell", fact(4))
+            print fact(5), name, squares[4], 1/3
+            printf("%5.2f|%g|%e\n", PI, 1e-5, 250)
+        "#).unwrap();
+        assert_eq!(hoc.get("total"), Some(25.0));
+        assert_eq!(hoc.get("n"), Some(3.0));
+        assert_eq!(hoc.output, "120 cell-24 16 0.33333333\n 3.14|1e-05|2.500000e+02\n");
+    }
+
+    #[test]
+    fn test_build_and_run_cell() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            load_file("nrngui.hoc")
+            create soma, dend[2]
+            access soma
+            soma {
+                nseg = 1  L = 20  diam = 20
+                insert hh
+            }
+            for i = 0, 1 dend[i] {
+                L = 100  diam = 1  nseg = 3
+                insert pas
+                g_pas = 0.0002
+            }
+            connect dend[0](0), soma(1)
+            soma connect dend[1](0), 0
+            forsec "dend" e_pas = -65
+            gnabar_hh = 0.15
+
+            objref stim, vs
+            soma stim = new IClamp(0.5)
+            stim.del = 2
+            stim.dur = 20
+            stim.amp = 0.2
+            vs = new Vector()
+            vs.record(&soma.v(0.5))
+            tstop = 25
+            run()
+            printf("%d sections, %d samples\n", 3, vs.size())
+        "#).unwrap();
+
+        let cell = hoc.cell();
+        assert_eq!(cell.sections["dend[1]"].parent, Some(("soma".to_string(), 0.0)));
+        assert_eq!(cell.sections["dend[0]"].nseg, 3);
+        assert_eq!(cell.sections["dend[1]"].mechanisms[0].parameters["e"], -65.0);
+        assert_eq!(cell.sections["soma"].mechanisms[0].parameters["gnabar"], 0.15);
+        assert_eq!(cell.point_processes[0].parameters["delay"], 2.0);
+        let v = &hoc.sim.recordings["Vector[1]"];
+        assert!(v.iter().any(|&v| v > 0.0));
+        assert_eq!(hoc.output, format!("3 sections, {} samples\n", v.len()));
+    }
+
+    #[test]
+    fn test_run_stops_at_tstop() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            create soma
+            access soma
+            insert hh
+            objref vs
+            vs = new Vector()
+            vs.record(&soma.v(0.5))
+            tstop = 20
+            run()
+        "#).unwrap();
+        // 800 steps of 0.025 ms after the sample at t = 0, as in NEURON
+        assert!((hoc.sim.t - 20.0).abs() < 1e-9, "t = {}", hoc.sim.t);
+        assert_eq!(hoc.sim.recordings["Vector[0]"].len(), 801);
+    }
+
+    #[test]
+    fn test_pt3d() {
+        let mut hoc = Hoc::new();
+        hoc.execute("create dend\ndend { pt3dclear() pt3dadd(0, 0, 0, 2) pt3dadd(30, 40, 0, 2) }\n\
+                     access dend\nn = n3d()\nx = x3d(1)\na = area(0.5)\ndiam = 4\nd = diam3d(0)").unwrap();
+        assert_eq!(hoc.get("n"), Some(2.0));
+        assert_eq!(hoc.get("x"), Some(30.0));
+        assert!((hoc.get("a").unwrap() - std::f64::consts::PI * 2.0 * 50.0).abs() < 1e-9);
+        assert_eq!(hoc.get("d"), Some(4.0));
+        assert_eq!(hoc.cell().sections["dend"].length, 50.0);
+    }
+
+    #[test]
+    fn test_seclamp() {
+        let mut hoc = Hoc::new();
+        hoc.execute(
+            "create soma\naccess soma\nL = 20\ndiam = 20\ninsert pas\n\
+             objref vc, iv\nvc = new SEClamp(0.5)\nvc.dur1 = 1\nvc.amp1 = -65\nvc.dur2 = 3\nvc.amp2 = -30\nvc.rs = 0.01\n\
+             iv = new Vector()\niv.record(&vc.i)\ntstop = 4\nrun()\nn = iv.size()\nlast = iv.x[n - 2]\nvend = v(0.5)\n\
+             objref tv\ntv = new VClamp(0.5)\ntv.amp[1] = 10\na = tv.amp[1]",
+        ).unwrap();
+        assert!(hoc.get("n").unwrap() >= 161.0);
+        assert!(hoc.get("last").unwrap() > 0.0);
+        assert!((hoc.get("vend").unwrap() + 30.0).abs() < 0.1);
+        assert_eq!(hoc.get("a"), Some(10.0));
+    }
+
+    #[test]
+    fn test_extracellular() {
+        let mut hoc = Hoc::new();
+        hoc.execute(
+            "create soma\naccess soma\nL = 20\ndiam = 20\ninsert pas\ninsert extracellular\n\
+             xg = 0.001\ne_extracellular = 0\nobjref stim\nstim = new IClamp(0.5)\nstim.dur = 10\nstim.amp = 0.1\n\
+             tstop = 5\nrun()\nvx = vext(0.5)\nconductance = xg",
+        ).unwrap();
+        // All of the stimulus leaves through xg: vext = I / (xg area)
+        let area = std::f64::consts::PI * 20.0 * 20.0 * 1e-8;
+        assert!((hoc.get("vx").unwrap() - 0.1 / (0.001 * area * 1e6)).abs() < 1e-6);
+        assert_eq!(hoc.get("conductance"), Some(0.001));
+    }
+
+    #[test]
+    fn test_vector_play() {
+        let mut hoc = Hoc::new();
+        hoc.execute(
+            "create soma\naccess soma\nL = 20\ndiam = 20\ninsert pas\ne_pas = -65\n\
+             objref stim, amp, times, tv, vv, gv\nstim = new IClamp(0.5)\nstim.dur = 1e9\n\
+             amp = new Vector()\namp.append(0.1, 0)\ntimes = new Vector()\ntimes.append(1, 3)\n\
+             amp.play(&stim.amp, times)\ntv = new Vector()\ntv.record(&t, 0.5)\n\
+             vv = new Vector()\nvv.record(&soma.v(0.5), 0.5)\ngv = new Vector()\ngv.record(&g_pas(0.5))\n\
+             tstop = 5\nrun()\nn = tv.size()\nt2 = tv.x[4]\nm = gv.size()\nv1 = vv.x[2]\nv3 = vv.x[6]\nv5 = vv.x[10]",
+        ).unwrap();
+        assert_eq!(hoc.get("n"), Some(11.0));
+        assert!((hoc.get("t2").unwrap() - 2.0).abs() < 1e-9);
+        assert_eq!(hoc.get("m"), Some(201.0));
+        // Depolarised only while the played amplitude is on
+        let (v1, v3, v5) = (hoc.get("v1").unwrap(), hoc.get("v3").unwrap(), hoc.get("v5").unwrap());
+        assert!((v1 + 65.0).abs() < 1e-9, "{}", v1);
+        assert!(v3 > v1 + 5.0 && v5 < v3, "{} {}", v3, v5);
+        assert!(Hoc::new().execute("objref x\nx = new Vector()\nx.play(&t, 1)").is_err());
+    }
+
+    #[test]
+    fn test_section_lists() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            create soma, dend[3], axon
+            for i = 0, 2 connect dend[i](0), soma(1)
+            objref dendrites, tree
+            dendrites = new SectionList()
+            forsec "^dend" dendrites.append()
+            dend[1] dendrites.remove()
+            forsec dendrites insert pas
+            tree = new SectionList()
+            soma tree.wholetree()
+            n = 0
+            forsec tree n += 1
+            forall ifsec "\\[2\\]" diam = 3
+            axon is_axon = issection("ax.n")
+        "#).unwrap();
+        let cell = hoc.cell();
+        let has_pas = |name: &str| cell.sections[name].range("g_pas", 0.5).is_some();
+        assert!(has_pas("dend[0]") && has_pas("dend[2]"));
+        assert!(!has_pas("dend[1]") && !has_pas("soma"));
+        assert_eq!(hoc.get("n"), Some(4.0));
+        assert_eq!(cell.sections["dend[2]"].diam, 3.0);
+        assert_ne!(cell.sections["dend[0]"].diam, 3.0);
+        assert_eq!(hoc.get("is_axon"), Some(1.0));
+        assert!(Hoc::new().execute("create soma\nforsec 1 insert pas").is_err());
+    }
+
+    #[test]
+    fn test_segment_gradients() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            create soma, dend
+            access soma
+            insert hh
+            dend {
+                nseg = 5  L = 500
+                insert hh
+                for (x) gnabar_hh(x) = 0.1 + 0.1 * x
+                n = 0
+                for (x) n += 1
+                m = 0
+                for (x, 0) m += 1
+            }
+            dend.gkbar_hh(0.2:0.8) = 0.01:0.04
+            soma gl_hh(0:1) = 0.001:0.001
+            tstop = 1
+            run()
+        "#).unwrap();
+        assert_eq!((hoc.get("n"), hoc.get("m")), (Some(7.0), Some(5.0)));
+        let hh = &hoc.cell().sections["dend"].mechanisms[0];
+        let close = |a: &[f64], b: &[f64]| a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-12);
+        assert!(close(&hh.segment_parameters["gnabar"], &[0.11, 0.13, 0.15, 0.17, 0.19]), "{:?}", hh.segment_parameters);
+        // Only the centres within 0.2..0.8 take the gradient
+        assert!(close(&hh.segment_parameters["gkbar"], &[0.036, 0.015, 0.025, 0.035, 0.036]), "{:?}", hh.segment_parameters);
+        assert_eq!(hoc.cell().sections["soma"].range("gl_hh", 0.5), Some(0.001));
+
+        assert!(Hoc::new().execute("create soma\naccess soma\ninsert hh\ngnabar_hh(0:1) = 0.1").is_err());
+        assert!(Hoc::new().execute("x = 1:2").is_err());
+        assert!(Hoc::new().execute("create soma\naccess soma\nfor (x) foo_hh(x) = 1").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = Hoc::new().execute("x = 1\ny = (2 +\n").unwrap_err();
+        assert!(err.to_string().contains("line"), "{}", err);
+        assert!(Hoc::new().execute("print z").is_err());
+        assert!(Hoc::new().execute("create soma\nsoma insert foo").is_err());
+        assert!(Hoc::new().execute("objref s\ns.amp = 1").is_err());
+
+        let err = Hoc::new().execute("iterator it() { iterator_statement }").unwrap_err();
+        assert!(err.to_string().contains("'iterator' is not supported"), "{}", err);
+        assert!(Hoc::new().execute("public x").is_err());
+        assert!(Hoc::new().execute("begintemplate A\nx = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_templates() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            n_made = 0
+            begintemplate Cell
+                public soma, dend, syn, diameter, rates, scale, make_vector
+                external n_made
+                objref syn
+                double rates[3]
+                create soma, dend[2]
+                proc init() {
+                    diameter = $1
+                    soma { L = 20  diam = diameter  insert hh }
+                    for i = 0, 1 connect dend[i](0), soma(1)
+                    soma syn = new ExpSyn(0.5)
+                    rates[1] = 2 * $1
+                    n_made += 1
+                }
+                func scale() { return diameter * $1 }
+                obfunc make_vector() { localobj v
+                    v = new Vector()
+                    v.indgen(1, $1, 1)
+                    return v
+                }
+            endtemplate Cell
+
+            objref a, b, vec
+            a = new Cell(10)
+            b = new Cell(15)
+            a.dend[1].L = 300
+            access b.soma
+            b.soma { nseg = 3 }
+            x = a.scale(2)
+            vec = b.make_vector(4)
+            total = vec.sum()
+            r = b.rates[1]
+            d = b.diameter
+            print a, b, a.syn
+        "#).unwrap();
+        let cell = hoc.cell();
+        assert_eq!(cell.sections["Cell[0].soma"].diam, 10.0);
+        assert_eq!(cell.sections["Cell[1].soma"].diam, 15.0);
+        assert_eq!(cell.sections["Cell[1].soma"].nseg, 3);
+        assert_eq!(cell.sections["Cell[0].dend[1]"].length, 300.0);
+        assert_eq!(cell.sections["Cell[1].dend[0]"].parent, Some(("Cell[1].soma".to_string(), 1.0)));
+        assert_eq!(cell.point_processes.len(), 2);
+        for (name, value) in [("n_made", 2.0), ("x", 20.0), ("total", 10.0), ("r", 30.0), ("d", 15.0)] {
+            assert_eq!(hoc.get(name), Some(value), "{}", name);
+        }
+        assert_eq!(hoc.output, "Cell[0] Cell[1] ExpSyn[1]\n");
+
+        // Only public members are visible outside, and only external
+        // names inside
+        let hidden = "begintemplate A\nk = 1\nendtemplate A\nobjref a\na = new A()\n";
+        assert!(Hoc::new().execute(&format!("{}y = a.k", hidden)).is_err());
+        let global = "g = 1\nbegintemplate A\nproc init() { y = g }\nendtemplate A\nobjref a\na = new A()";
+        assert!(Hoc::new().execute(global).is_err());
+    }
+
+    #[test]
+    fn test_strings_and_execute() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            strdef s
+            s = "soma"
+            same = strcmp(s, "soma")
+            before = strcmp("axon", s)
+            execute("c = 3")
+            proc bump() { execute("c = c + 1") }
+            bump()
+            ok = execute1("undefined_function()")
+            begintemplate T
+                public k
+                k = 1
+            endtemplate T
+            objref obj
+            obj = new T()
+            execute("k = 5", obj)
+            k = obj.k
+        "#).unwrap();
+        for (name, value) in [("same", 0.0), ("before", -1.0), ("c", 4.0), ("ok", 0.0), ("k", 5.0)] {
+            assert_eq!(hoc.get(name), Some(value), "{}", name);
+        }
+        assert!(Hoc::new().execute("execute(\"x = \")").is_err());
+    }
+
+    #[test]
+    fn test_vector_methods() {
+        let mut hoc = Hoc::new();
+        hoc.execute(r#"
+            objref v, w, u
+            v = new Vector()
+            v.indgen(0, 8, 2)
+            n = v.size()
+            v.add(1)
+            w = v.c()
+            w.mul(w)
+            w.reverse()
+            top = w.x[0]
+            w.sort()
+            low = w.x[0]
+            s = v.sum()
+            m = v.mean()
+            hi = v.max()
+            lo = v.min()
+            has = v.contains(7)
+            u = new Vector(3)
+            u.fill(2)
+            u.fill(5, 1, 2)
+            u.resize(4)
+            us = u.sum()
+            u.indgen(3)
+            u3 = u.x[3]
+            v.copy(u)
+            vn = v.size()
+        "#).unwrap();
+        let expected = [
+            ("n", 5.0), ("top", 81.0), ("low", 1.0), ("s", 25.0), ("m", 5.0), ("hi", 9.0), ("lo", 1.0),
+            ("has", 1.0), ("us", 12.0), ("u3", 9.0), ("vn", 4.0),
+        ];
+        for (name, value) in expected {
+            assert_eq!(hoc.get(name), Some(value), "{}", name);
+        }
+        assert!(Hoc::new().execute("objref v\nv = new Vector()\nx = v.max()").is_err());
+        assert!(Hoc::new().execute("objref v, w\nv = new Vector(2)\nw = new Vector(3)\nv.add(w)").is_err());
+        assert!(Hoc::new().execute("objref v\nv = new Vector()\nv.record(&t)\nv.fill(1)").is_err());
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = std::env::temp_dir().join(format!("oldies-hoc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.hoc"), "loads += 1\nfunc twice() { return 2 * $1 }\n").unwrap();
+        std::fs::write(
+            dir.join("main.hoc"),
+            "loads = 0\nload_file(\"nrngui.hoc\")\nload_file(\"lib.hoc\")\nload_file(\"lib.hoc\")\n\
+             load_file(\"lib.hoc\", \"twice\")\nxopen(\"lib.hoc\")\nload_file(1, \"lib.hoc\")\ny = twice(4)\n",
+        ).unwrap();
+        let mut hoc = Hoc::new();
+        let result = hoc.execute_file(dir.join("main.hoc"));
+        let missing = hoc.execute("load_file(\"missing.hoc\")");
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(hoc.get("loads"), Some(3.0));
+        assert_eq!(hoc.get("y"), Some(8.0));
+        let err = missing.unwrap_err();
+        assert!(err.to_string().contains("missing.hoc"), "{}", err);
+    }
+}
@@ -0,0 +1,318 @@
+//! Impedance analysis
+//!
+//! As NEURON's `Impedance` class, the cell is linearized about its present
+//! state and driven by a sinusoidal current of frequency `f` at one
+//! location. Each node then has the admittance (uS)
+//!
+//! ```text
+//! y = g + j w C
+//! ```
+//!
+//! where `g` is the slope of its membrane current with the gates held and
+//! `C` its capacitance, and is coupled to its neighbours by the axial
+//! conductances. The extended analysis also lets the gates follow the
+//! voltage, which is what gives active membranes their resonance: a state
+//! `x' = F(x, v)` adds
+//!
+//! ```text
+//! dI/dx (dF/dv) / (j w - dF/dx)
+//! ```
+//!
+//! per unit area. This holds about a resting state. The tree matrix is
+//! solved once for the transfer impedances from the injection site, and
+//! the input impedance of every node comes from the diagonal of its
+//! inverse, found with one sweep towards the root and one back.
+//!
+//! Impedances are in MOhm, phases in radians and frequencies in Hz.
+//! Extracellular layers are taken as grounded.
+
+use crate::cable::{self, CableTree};
+use crate::mechanism::MechanismLibrary;
+use crate::NeuronCell;
+use num_complex::Complex64;
+use oldies_core::{OldiesError, Result};
+
+/// Voltage step used to measure the voltage dependence of state rates (mV)
+const RATE_STEP: f64 = 0.001;
+
+/// Relative step used to measure the dependence of currents on states
+const STATE_STEP: f64 = 1e-6;
+
+/// Impedances of a cell at one frequency for current injected at one
+/// location
+#[derive(Debug, Clone)]
+pub struct Impedance {
+    /// Frequency (Hz)
+    pub frequency: f64,
+    tree: CableTree,
+    /// Voltage at each node per unit current at the injection site
+    transfer: Vec<Complex64>,
+    /// Voltage at each node per unit current injected there
+    input: Vec<Complex64>,
+}
+
+/// Admittance (uS) of every node to ground at angular frequency `w`
+/// (rad/ms)
+fn admittances(
+    cell: &NeuronCell,
+    tree: &CableTree,
+    w: f64,
+    extended: bool,
+    celsius: f64,
+    library: &MechanismLibrary,
+) -> Result<Vec<Complex64>> {
+    let v: Vec<f64> = tree.nodes.iter().map(|(name, k)| cell.sections[name].v[*k]).collect();
+    let (_, slope) = cable::membrane_currents(cell, tree, &v, 0.0, celsius, library)?;
+    let mut y: Vec<Complex64> = (0..tree.len())
+        .map(|i| Complex64::new(slope[i], w * tree.capacitance[i]))
+        .collect();
+    if !extended {
+        return Ok(y);
+    }
+    for (i, (name, k)) in tree.nodes.iter().enumerate() {
+        let scale = tree.area[i] * 1e6;
+        for mech in &cell.sections[name].mechanisms {
+            let names = cable::state_names(mech, library)?;
+            let rates = cable::state_rates(mech, *k, v[i], celsius, library)?;
+            let shifted = cable::state_rates(mech, *k, v[i] + RATE_STEP, celsius, library)?;
+            let i0 = cable::current_density(mech, *k, v[i], celsius, library)?;
+            for (j, state) in names.iter().enumerate() {
+                let mut perturbed = mech.clone();
+                let x = &mut perturbed.state.get_mut(state).ok_or_else(|| {
+                    OldiesError::SimulationError(format!("Mechanism {} has no state {}", mech.name, state))
+                })?[*k];
+                let dx = STATE_STEP * x.abs().max(1e-3);
+                *x += dx;
+                let di_dx = (cable::current_density(&perturbed, *k, v[i], celsius, library)? - i0) / dx;
+                let df_dv = (shifted[j].0 - rates[j].0) / RATE_STEP;
+                let df_dx = rates[j].1;
+                y[i] += di_dx * df_dv / Complex64::new(-df_dx, w) * scale;
+            }
+        }
+    }
+    Ok(y)
+}
+
+impl Impedance {
+    /// Linearize `cell` about its present state at `frequency` (Hz), for
+    /// current injected at `section(loc)`, letting the gates follow the
+    /// voltage if `extended`
+    pub fn compute(
+        cell: &NeuronCell,
+        section: &str,
+        loc: f64,
+        frequency: f64,
+        extended: bool,
+        celsius: f64,
+        library: &MechanismLibrary,
+    ) -> Result<Self> {
+        let mut cell = cell.clone();
+        cable::prepare(&mut cell, celsius, library)?;
+        let tree = CableTree::new(&cell)?;
+        let w = 2.0 * std::f64::consts::PI * frequency * 1e-3;
+        let y = admittances(&cell, &tree, w, extended, celsius, library)?;
+
+        let n = tree.len();
+        let mut d = y;
+        let mut a = vec![Complex64::new(0.0, 0.0); n];
+        for i in 0..n {
+            if let Some(p) = tree.parent[i] {
+                d[i] += tree.g_axial[i];
+                d[p] += tree.g_axial[i];
+                a[i] = Complex64::new(-tree.g_axial[i], 0.0);
+            }
+        }
+
+        // Diagonal of the inverse: eliminating each subtree towards the
+        // root leaves `s`, and the sweep back adds what lies beyond the
+        // parent of each node
+        let mut s = d.clone();
+        for i in (0..n).rev() {
+            if let Some(p) = tree.parent[i] {
+                let e = a[i] * a[i] / s[i];
+                s[p] -= e;
+            }
+        }
+        let mut total = s.clone();
+        for i in 0..n {
+            if let Some(p) = tree.parent[i] {
+                let beyond = total[p] + a[i] * a[i] / s[i];
+                total[i] = s[i] - a[i] * a[i] / beyond;
+            }
+        }
+        let input = total.iter().map(|t| t.inv()).collect();
+
+        let node = node(&tree, section, loc)?;
+        let mut transfer = vec![Complex64::new(0.0, 0.0); n];
+        transfer[node] = Complex64::new(1.0, 0.0);
+        for i in (0..n).rev() {
+            if let Some(p) = tree.parent[i] {
+                let f = a[i] / s[i];
+                let r = transfer[i];
+                transfer[p] -= f * r;
+            }
+        }
+        for i in 0..n {
+            if let Some(p) = tree.parent[i] {
+                let r = transfer[p];
+                transfer[i] -= a[i] * r;
+            }
+            transfer[i] /= s[i];
+        }
+
+        Ok(Self { frequency, tree, transfer, input })
+    }
+
+    /// [`Impedance::compute`] at each of `frequencies` (Hz)
+    pub fn sweep(
+        cell: &NeuronCell,
+        section: &str,
+        loc: f64,
+        frequencies: &[f64],
+        extended: bool,
+        celsius: f64,
+        library: &MechanismLibrary,
+    ) -> Result<Vec<Self>> {
+        frequencies.iter()
+            .map(|&f| Self::compute(cell, section, loc, f, extended, celsius, library))
+            .collect()
+    }
+
+    /// Input impedance at `section(loc)` (MOhm)
+    pub fn input(&self, section: &str, loc: f64) -> Result<f64> {
+        Ok(self.input[node(&self.tree, section, loc)?].norm())
+    }
+
+    /// Phase of the voltage at `section(loc)` relative to the current
+    /// injected there (rad)
+    pub fn input_phase(&self, section: &str, loc: f64) -> Result<f64> {
+        Ok(self.input[node(&self.tree, section, loc)?].arg())
+    }
+
+    /// Voltage at `section(loc)` per unit current at the injection site,
+    /// equal to the voltage there per unit current at `section(loc)`
+    /// (MOhm)
+    pub fn transfer(&self, section: &str, loc: f64) -> Result<f64> {
+        Ok(self.transfer[node(&self.tree, section, loc)?].norm())
+    }
+
+    /// Phase of [`Impedance::transfer`] (rad)
+    pub fn transfer_phase(&self, section: &str, loc: f64) -> Result<f64> {
+        Ok(self.transfer[node(&self.tree, section, loc)?].arg())
+    }
+
+    /// Attenuation of voltage from `section(loc)`, where current is
+    /// injected, to the injection site of [`Impedance::compute`]: the
+    /// ratio of the voltages there and at `section(loc)`
+    pub fn ratio(&self, section: &str, loc: f64) -> Result<f64> {
+        let i = node(&self.tree, section, loc)?;
+        Ok(self.transfer[i].norm() / self.input[i].norm())
+    }
+}
+
+/// Node of the segment holding `section(loc)`
+fn node(tree: &CableTree, section: &str, loc: f64) -> Result<usize> {
+    let nseg = tree.nodes.iter().filter(|(name, _)| name == section).count();
+    if nseg == 0 {
+        return Err(OldiesError::ModelNotFound(format!("Section {} not found", section)));
+    }
+    Ok(tree.node(section, ((loc * nseg as f64) as usize).min(nseg - 1)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mechanisms, NeuronSimulation};
+
+    fn soma(mechanism: crate::InsertedMechanism) -> NeuronCell {
+        let mut cell = NeuronCell::new("cell");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanism);
+        cell
+    }
+
+    #[test]
+    fn test_passive_compartment() {
+        let cell = soma(mechanisms::pas());
+        let area = cell.sections["soma"].segment_area(0);
+        let (g, c) = (1e-3 * area * 1e6, area * 1e3);
+        for f in [0.0, 10.0, 100.0] {
+            let z = Impedance::compute(&cell, "soma", 0.5, f, false, 6.3, &MechanismLibrary::new()).unwrap();
+            let w = 2.0 * std::f64::consts::PI * f * 1e-3;
+            let expected = 1.0 / Complex64::new(g, w * c);
+            assert!((z.input("soma", 0.5).unwrap() / expected.norm() - 1.0).abs() < 1e-9);
+            assert!((z.input_phase("soma", 0.5).unwrap() - expected.arg()).abs() < 1e-9);
+            assert!((z.transfer("soma", 0.5).unwrap() / expected.norm() - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_sealed_cable() {
+        // Input and transfer impedances of a finite sealed cable at DC
+        let mut cell = NeuronCell::new("cell");
+        let dend = cell.create("dend");
+        dend.length = 1000.0;
+        dend.diam = 2.0;
+        dend.set_nseg(201);
+        let mut pas = mechanisms::pas();
+        pas.parameters.insert("g".into(), 1e-4);
+        dend.insert(pas);
+        let library = MechanismLibrary::new();
+        let z = Impedance::compute(&cell, "dend", 0.0, 0.0, false, 6.3, &library).unwrap();
+
+        let d = 2.0e-4; // cm
+        let ra = 4.0 * 100.0 / (std::f64::consts::PI * d * d); // ohm/cm
+        let rm = 1.0 / (1e-4 * std::f64::consts::PI * d); // ohm cm
+        let (lambda, r_inf) = ((rm / ra).sqrt(), (rm * ra).sqrt() * 1e-6);
+        let l = 0.1 / lambda;
+        let input = z.input("dend", 0.0).unwrap();
+        assert!((input / (r_inf / l.tanh()) - 1.0).abs() < 0.01, "{}", input);
+        let transfer = z.transfer("dend", 1.0).unwrap();
+        assert!((transfer / (r_inf / l.sinh()) - 1.0).abs() < 0.01, "{}", transfer);
+        // Reciprocity, and attenuation from the far end
+        let back = Impedance::compute(&cell, "dend", 1.0, 0.0, false, 6.3, &library).unwrap();
+        assert!((back.transfer("dend", 0.0).unwrap() / transfer - 1.0).abs() < 1e-9);
+        let ratio = z.ratio("dend", 1.0).unwrap();
+        assert!((ratio - 1.0 / l.cosh()).abs() < 0.01, "{}", ratio);
+
+        // Dendrites filter: transfer falls faster with frequency than input
+        let fast = Impedance::compute(&cell, "dend", 0.0, 100.0, false, 6.3, &library).unwrap();
+        assert!(fast.transfer("dend", 1.0).unwrap() / transfer < fast.input("dend", 0.0).unwrap() / input);
+    }
+
+    #[test]
+    fn test_hh_resonance() {
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(soma(mechanisms::hh()));
+        sim.finitialize(-65.0).unwrap();
+        // Let the membrane settle to rest
+        sim.continuerun(200.0).unwrap();
+        let cell = &sim.cells[0];
+        let z = |f: f64, extended: bool| {
+            Impedance::compute(cell, "soma", 0.5, f, extended, sim.celsius, &sim.mechanisms)
+                .unwrap().input("soma", 0.5).unwrap()
+        };
+
+        // At DC the gates follow: the impedance is that of the steady-state
+        // current-voltage relation
+        let steady = |v: f64| {
+            let mut cell = soma(mechanisms::hh());
+            cable::initialize(&mut cell, v, sim.celsius, &sim.mechanisms).unwrap();
+            let tree = CableTree::new(&cell).unwrap();
+            cable::membrane_currents(&cell, &tree, &[v], 0.0, sim.celsius, &sim.mechanisms).unwrap().0[0]
+        };
+        let v = cell.sections["soma"].v[0];
+        let slope = (steady(v + 1e-3) - steady(v - 1e-3)) / 2e-3;
+        assert!((z(0.0, true) * slope - 1.0).abs() < 1e-3, "{} vs {}", z(0.0, true), 1.0 / slope);
+
+        // Holding the gates, impedance only falls with frequency; letting
+        // them follow gives a resonance
+        assert!(z(0.0, false) > z(50.0, false));
+        let frequencies: Vec<f64> = (1..200).map(f64::from).collect();
+        let sweep = Impedance::sweep(cell, "soma", 0.5, &frequencies, true, sim.celsius, &sim.mechanisms).unwrap();
+        let peak = sweep.iter().map(|z| z.input("soma", 0.5).unwrap()).fold(0.0, f64::max);
+        assert!(peak > 1.1 * z(0.0, true), "{} vs {}", peak, z(0.0, true));
+    }
+}
@@ -0,0 +1,150 @@
+//! Extracellular field potentials
+//!
+//! The potential at an electrode in an infinite homogeneous medium of
+//! conductivity `sigma` is the sum over segments of their transmembrane
+//! current weighted by distance. A point source puts the current of a
+//! segment at its centre:
+//!
+//! ```text
+//! phi = I / (4 pi sigma r)
+//! ```
+//!
+//! and a line source spreads it evenly along the segment (Holt & Koch,
+//! 1999), which stays accurate for electrodes close to long segments:
+//!
+//! ```text
+//! phi = I / (4 pi sigma L) (asinh((L - s) / r) + asinh(s / r))
+//! ```
+//!
+//! where `s` is the position of the electrode along the segment axis and
+//! `r` its distance from the axis. Distances are clamped to the segment
+//! radius so that electrodes inside a segment stay finite. Segment
+//! positions come from the 3D points of the sections.
+//!
+//! Units: positions in um, `sigma` in S/m, currents in nA, potentials in
+//! mV.
+
+use crate::{cable, NeuronCell};
+use oldies_core::{OldiesError, Result, Time, Voltage};
+use serde::{Deserialize, Serialize};
+
+/// Conductivity of cortical tissue (S/m)
+pub const SIGMA: f64 = 0.3;
+
+/// How the current of a segment is spread in space
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LfpMethod {
+    /// All of it at the segment centre
+    PointSource,
+    /// Evenly along the segment
+    LineSource,
+}
+
+/// Recording site in the extracellular medium
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Electrode {
+    /// Position (um)
+    pub position: [f64; 3],
+    /// Conductivity of the medium (S/m)
+    pub sigma: f64,
+    pub method: LfpMethod,
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+impl Electrode {
+    /// Electrode at `position` in tissue of conductivity [`SIGMA`]
+    pub fn new(position: [f64; 3], method: LfpMethod) -> Self {
+        Self { position, sigma: SIGMA, method }
+    }
+
+    /// Potential (mV) of a current `i` (nA) spread from `a` to `b` (um),
+    /// with distances no shorter than `radius`
+    fn source_potential(&self, i: f64, a: [f64; 3], b: [f64; 3], radius: f64) -> Voltage {
+        let k = i / (4.0 * std::f64::consts::PI * self.sigma);
+        let length = distance(a, b);
+        if self.method == LfpMethod::PointSource || length <= 0.0 {
+            let centre = [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0, (a[2] + b[2]) / 2.0];
+            return k / distance(self.position, centre).max(radius);
+        }
+        let axis = [(b[0] - a[0]) / length, (b[1] - a[1]) / length, (b[2] - a[2]) / length];
+        let rel = [self.position[0] - a[0], self.position[1] - a[1], self.position[2] - a[2]];
+        let s = rel[0] * axis[0] + rel[1] * axis[1] + rel[2] * axis[2];
+        let r = (distance(self.position, a).powi(2) - s * s).max(0.0).sqrt().max(radius);
+        k / length * (((length - s) / r).asinh() + (s / r).asinh())
+    }
+
+    /// Extracellular potential (mV) produced by `cells` at `t`
+    pub fn potential(&self, cells: &[NeuronCell], t: Time) -> Result<Voltage> {
+        let mut phi = 0.0;
+        for cell in cells {
+            for (name, k, i) in cable::transmembrane_currents(cell, t)? {
+                let sec = &cell.sections[&name];
+                let n = sec.nseg as f64;
+                let ends = sec.point_at(k as f64 / n).zip(sec.point_at((k + 1) as f64 / n));
+                let (a, b) = ends.ok_or_else(|| {
+                    OldiesError::SimulationError(format!("Section {} has no 3D points", name))
+                })?;
+                phi += self.source_potential(i, a, b, sec.diam / 2.0);
+            }
+        }
+        Ok(phi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mechanisms, NeuronSimulation, Point3d};
+
+    /// Passive dendrite along x from 0 to 500 um, stimulated at its 0 end
+    fn dendrite() -> NeuronCell {
+        let mut cell = NeuronCell::new("cell");
+        let dend = cell.create("dend");
+        for x in [0.0, 500.0] {
+            dend.pt3dadd(Point3d { x, y: 0.0, z: 0.0, diam: 2.0 });
+        }
+        dend.set_nseg(25);
+        let mut pas = mechanisms::pas();
+        pas.parameters.insert("g".into(), 1e-4);
+        dend.insert(pas);
+        cell.add_point_process(mechanisms::iclamp("dend", 0.0, 0.0, 1e9, 0.1));
+        cell
+    }
+
+    #[test]
+    fn test_lfp_far_field_monopole() {
+        // Far away, the potential of a cell is that of the net current
+        // crossing its membrane, the stimulus
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(dendrite());
+        let far = [250.0, 1e5, 0.0];
+        sim.record_lfp("point", Electrode::new(far, LfpMethod::PointSource));
+        sim.record_lfp("line", Electrode::new(far, LfpMethod::LineSource));
+        sim.record_lfp("near", Electrode::new([0.0, 20.0, 0.0], LfpMethod::LineSource));
+        sim.tstop = 100.0;
+        sim.finitialize(-70.0).unwrap();
+        sim.run().unwrap();
+
+        let total: f64 = cable::transmembrane_currents(&sim.cells[0], sim.t).unwrap().iter().map(|c| c.2).sum();
+        assert!((total - 0.1).abs() < 1e-9, "{}", total);
+        let expected = 0.1 / (4.0 * std::f64::consts::PI * SIGMA * 1e5);
+        for name in ["point", "line"] {
+            let phi = *sim.recordings[name].last().unwrap();
+            assert!((phi / expected - 1.0).abs() < 0.01, "{}: {} vs {}", name, phi, expected);
+        }
+        // Close to the stimulated end the outward current dominates
+        assert!(*sim.recordings["near"].last().unwrap() > 10.0 * expected);
+    }
+
+    #[test]
+    fn test_lfp_needs_3d_points() {
+        let mut cell = NeuronCell::new("cell");
+        cell.create("soma");
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 0.0, 1.0, 0.1));
+        let electrode = Electrode::new([0.0, 0.0, 0.0], LfpMethod::PointSource);
+        assert!(electrode.potential(&[cell], 0.5).is_err());
+    }
+}
@@ -20,8 +20,32 @@
 //! - **Point Processes**: Synapses, electrodes at specific locations
 //! - **Connections**: Section-to-section connectivity
 //! - **cvode**: Variable time-step integration
-
-use oldies_core::{OldiesError, Result, Time, Voltage, Current};
+//! - **Impedance**: Input and transfer impedance of the linearized cell
+//! - **rxd**: Reaction-diffusion of intracellular species
+//! - **Vector**: Recording and playing of simulation variables
+//! - **Session**: Saving and restoring the setup of a simulation
+//! - **Checkpoint**: Saving and restoring the state of a running simulation
+//! - **SoA engine**: Flattened, CoreNEURON-style layout for large networks
+//! - **Fitting**: Channel kinetics fitted to voltage-clamp traces
+
+pub mod cable;
+pub mod checkpoint;
+pub mod cvode;
+pub mod fitting;
+pub mod hoc;
+pub mod impedance;
+pub mod lfp;
+pub mod mechanism;
+pub mod morphology;
+pub mod netcon;
+pub mod nmodl;
+pub mod parallel;
+pub mod rxd;
+pub mod session;
+pub mod soa;
+pub mod vector;
+
+use oldies_core::{OldiesError, Result, Time, Voltage};
 use pest_derive::Parser;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -258,12 +282,31 @@ pub struct Section {
     pub mechanisms: Vec<InsertedMechanism>,
     /// Parent section and location
     pub parent: Option<(String, f64)>,
+    /// End of this section attached to the parent (0 or 1)
+    #[serde(default)]
+    pub connection_end: f64,
     /// Children sections
     pub children: Vec<String>,
+    /// 3D points along the centreline, from the 0 to the 1 end
+    #[serde(default)]
+    pub pt3d: Vec<Point3d>,
     /// State: membrane potential per segment
     pub v: Vec<Voltage>,
 }
 
+fn distance3d(a: &Point3d, b: &Point3d) -> f64 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+/// A point of a section's centreline (um)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Point3d {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub diam: f64,
+}
+
 impl Section {
     /// Create a new section with default properties
     pub fn new(name: &str) -> Self {
@@ -276,13 +319,26 @@ impl Section {
             cm: 1.0,           // uF/cm^2
             mechanisms: Vec::new(),
             parent: None,
+            connection_end: 0.0,
             children: Vec::new(),
+            pt3d: Vec::new(),
             v: vec![-65.0],    // mV, resting potential
         }
     }
 
-    /// Set number of segments
+    /// Set number of segments. Parameters that vary along the section
+    /// take, in each new segment, their value in the old segment holding
+    /// its centre.
     pub fn set_nseg(&mut self, nseg: usize) {
+        for values in self.mechanisms.iter_mut().flat_map(|m| m.segment_parameters.values_mut()) {
+            if values.is_empty() {
+                continue;
+            }
+            let old = std::mem::take(values);
+            *values = (0..nseg)
+                .map(|k| old[((k as f64 + 0.5) / nseg as f64 * old.len() as f64) as usize])
+                .collect();
+        }
         self.nseg = nseg;
         self.v = vec![-65.0; nseg];
     }
@@ -292,11 +348,237 @@ impl Section {
         self.mechanisms.push(mechanism);
     }
 
-    /// Surface area per segment (cm^2)
+    /// Segment containing the location `loc` (0-1)
+    pub fn segment(&self, loc: f64) -> usize {
+        ((loc * self.nseg as f64) as usize).min(self.nseg.saturating_sub(1))
+    }
+
+    /// Membrane potential at location `loc` (0-1)
+    pub fn v_at(&self, loc: f64) -> Voltage {
+        self.v[self.segment(loc)]
+    }
+
+    /// Range variable `name` at location `loc` (0-1): `v`, or a state or
+    /// parameter of a mechanism, named plainly (`xg`, `cai`) or with the
+    /// mechanism suffix (`gnabar_hh`)
+    pub fn range(&self, name: &str, loc: f64) -> Option<f64> {
+        let k = self.segment(loc);
+        if name == "v" {
+            return self.v.get(k).copied();
+        }
+        let get = |m: &InsertedMechanism, var: &str| {
+            m.state.get(var).and_then(|x| x.get(k).copied())
+                .or_else(|| m.parameter(var, k))
+        };
+        if let Some(x) = self.mechanisms.iter().find_map(|m| get(m, name)) {
+            return Some(x);
+        }
+        let (var, suffix) = name.rsplit_once('_')?;
+        get(self.mechanisms.iter().find(|m| m.name == suffix)?, var)
+    }
+
+    /// Set range variable `name` at `loc`, or in every segment if `None`.
+    /// A parameter set at one location varies along the section from then
+    /// on, until it is set everywhere again. Returns false if `name` is
+    /// not a range variable.
+    pub fn set_range(&mut self, name: &str, loc: Option<f64>, x: f64) -> bool {
+        let nseg = self.nseg;
+        let k = loc.map(|loc| self.segment(loc));
+        let set = |values: &mut Vec<f64>| match k {
+            Some(k) => {
+                if let Some(value) = values.get_mut(k) {
+                    *value = x;
+                }
+            }
+            None => values.fill(x),
+        };
+        let set_parameter = |mech: &mut InsertedMechanism, var: &str| match k {
+            Some(_) => {
+                let value = mech.parameters.get(var).copied().unwrap_or(x);
+                let values = mech.segment_parameters.entry(var.to_string()).or_insert_with(|| vec![value; nseg]);
+                values.resize(nseg, value);
+                set(values);
+            }
+            None => {
+                mech.parameters.insert(var.to_string(), x);
+                mech.segment_parameters.remove(var);
+            }
+        };
+        if name == "v" {
+            set(&mut self.v);
+            return true;
+        }
+        let mut found = false;
+        for mech in &mut self.mechanisms {
+            if mech.parameters.contains_key(name) {
+                set_parameter(mech, name);
+                found = true;
+            } else if let Some(values) = mech.state.get_mut(name) {
+                set(values);
+                found = true;
+            }
+        }
+        if found {
+            return true;
+        }
+        let Some((var, suffix)) = name.rsplit_once('_') else { return false };
+        let Some(mech) = self.mechanisms.iter_mut().find(|m| m.name == suffix) else { return false };
+        match mech.state.get_mut(var) {
+            Some(values) if !values.is_empty() => set(values),
+            _ => set_parameter(mech, var),
+        }
+        true
+    }
+
+    /// Segment containing the location `x` (0-1), to read and set its
+    /// range variables
+    pub fn at(&mut self, x: f64) -> Segment<'_> {
+        let k = self.segment(x);
+        Segment { section: self, k }
+    }
+
+    /// Surface area per segment of the equivalent cylinder (cm^2)
     pub fn area(&self) -> f64 {
         let seg_length = self.length / self.nseg as f64;
         std::f64::consts::PI * self.diam * seg_length * 1e-8  // um^2 to cm^2
     }
+
+    /// Append a 3D point, making `length` the path length of the points
+    /// and `diam` their length-weighted mean diameter
+    pub fn pt3dadd(&mut self, point: Point3d) {
+        self.pt3d.push(point);
+        let length = self.arc3d();
+        if length > 0.0 {
+            let area: f64 = self.pt3d.windows(2)
+                .map(|w| distance3d(&w[0], &w[1]) * (w[0].diam + w[1].diam) / 2.0)
+                .sum();
+            self.length = length;
+            self.diam = area / length;
+        }
+    }
+
+    /// Remove the 3D points, keeping `length` and `diam`
+    pub fn pt3dclear(&mut self) {
+        self.pt3d.clear();
+    }
+
+    /// Path length of the 3D points (um)
+    pub fn arc3d(&self) -> f64 {
+        self.pt3d.windows(2).map(|w| distance3d(&w[0], &w[1])).sum()
+    }
+
+    /// Position (um) of location `x` (0-1) along the 3D points, or `None`
+    /// without them
+    pub fn point_at(&self, x: f64) -> Option<[f64; 3]> {
+        let target = x.clamp(0.0, 1.0) * self.arc3d();
+        let mut s = 0.0;
+        for w in self.pt3d.windows(2) {
+            let l = distance3d(&w[0], &w[1]);
+            if l > 0.0 && s + l >= target {
+                let f = (target - s) / l;
+                let lerp = |a: f64, b: f64| a + f * (b - a);
+                return Some([lerp(w[0].x, w[1].x), lerp(w[0].y, w[1].y), lerp(w[0].z, w[1].z)]);
+            }
+            s += l;
+        }
+        self.pt3d.last().map(|p| [p.x, p.y, p.z])
+    }
+
+    /// Diameter along the section as (distance from the 0 end, diameter)
+    /// in um: the 3D points stretched to `length`, or a cylinder of `diam`
+    fn profile(&self) -> Vec<(f64, f64)> {
+        let arc = self.arc3d();
+        if arc <= 0.0 {
+            return vec![(0.0, self.diam), (self.length, self.diam)];
+        }
+        let mut x = 0.0;
+        let mut profile = vec![(0.0, self.pt3d[0].diam)];
+        for w in self.pt3d.windows(2) {
+            x += distance3d(&w[0], &w[1]) * self.length / arc;
+            profile.push((x, w[1].diam));
+        }
+        profile
+    }
+
+    /// Sum of `f(length, d0, d1)` over the linearly tapering pieces of the
+    /// section between locations `from` and `to` (0-1)
+    fn integrate(&self, from: f64, to: f64, f: impl Fn(f64, f64, f64) -> f64) -> f64 {
+        let (a, b) = (from.min(to) * self.length, from.max(to) * self.length);
+        self.profile().windows(2).map(|w| {
+            let ((x0, d0), (x1, d1)) = (w[0], w[1]);
+            let (lo, hi) = (x0.max(a), x1.min(b));
+            if hi <= lo {
+                return 0.0;
+            }
+            let d = |x: f64| d0 + (d1 - d0) * (x - x0) / (x1 - x0);
+            f(hi - lo, d(lo), d(hi))
+        }).sum()
+    }
+
+    /// Membrane area of segment `k` (cm^2): the lateral area of the
+    /// frustums between 3D points, as NEURON computes it
+    pub fn segment_area(&self, k: usize) -> f64 {
+        let n = self.nseg as f64;
+        self.integrate(k as f64 / n, (k + 1) as f64 / n, |l, d0, d1| {
+            std::f64::consts::PI * (d0 + d1) / 2.0 * (l * l + (d1 - d0).powi(2) / 4.0).sqrt()
+        }) * 1e-8
+    }
+
+    /// Axial resistance between locations `from` and `to` (MOhm)
+    pub fn axial_resistance(&self, from: f64, to: f64) -> f64 {
+        // Integral of 4 Ra / (pi d^2) over a linear taper is 4 Ra l / (pi d0 d1)
+        self.integrate(from, to, |l, d0, d1| {
+            4.0 * self.ra * l / (std::f64::consts::PI * d0 * d1)
+        }) * 1e-2
+    }
+}
+
+/// A segment of a section, from [`Section::at`]
+pub struct Segment<'a> {
+    section: &'a mut Section,
+    k: usize,
+}
+
+impl Segment<'_> {
+    /// Index in the section, from the 0 end
+    pub fn index(&self) -> usize {
+        self.k
+    }
+
+    /// Location of the segment centre (0-1)
+    pub fn x(&self) -> f64 {
+        (self.k as f64 + 0.5) / self.section.nseg as f64
+    }
+
+    /// Membrane potential (mV)
+    pub fn v(&self) -> Voltage {
+        self.section.v[self.k]
+    }
+
+    pub fn set_v(&mut self, v: Voltage) {
+        self.section.v[self.k] = v;
+    }
+
+    /// Range variable `name`, as [`Section::range`] reads it
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.section.range(name, self.x())
+    }
+
+    /// Set range variable `name` in this segment only
+    pub fn set(&mut self, name: &str, x: f64) -> Result<()> {
+        let loc = self.x();
+        if !self.section.set_range(name, Some(loc), x) {
+            return Err(OldiesError::ModelNotFound(format!(
+                "Range variable {} in {}", name, self.section.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Membrane area (cm^2)
+    pub fn area(&self) -> f64 {
+        self.section.segment_area(self.k)
+    }
 }
 
 /// An inserted mechanism instance
@@ -305,6 +587,19 @@ pub struct InsertedMechanism {
     pub name: String,
     pub parameters: HashMap<String, f64>,
     pub state: HashMap<String, Vec<f64>>,
+    /// Parameters that vary along the section, per segment, in place of
+    /// their value in `parameters`
+    #[serde(default)]
+    pub segment_parameters: HashMap<String, Vec<f64>>,
+}
+
+impl InsertedMechanism {
+    /// Parameter `name` in segment `k`
+    pub fn parameter(&self, name: &str, k: usize) -> Option<f64> {
+        self.segment_parameters.get(name).and_then(|x| x.get(k))
+            .or_else(|| self.parameters.get(name))
+            .copied()
+    }
 }
 
 /// Point process (synapse, electrode, etc.)
@@ -341,6 +636,17 @@ impl NeuronCell {
         }
     }
 
+    /// Build a cell from an SWC reconstruction. See [`morphology::from_swc`].
+    pub fn from_swc(content: &str) -> Result<Self> {
+        morphology::from_swc(content)
+    }
+
+    /// Build a cell from a Neurolucida ASC reconstruction. See
+    /// [`morphology::from_asc`].
+    pub fn from_asc(content: &str) -> Result<Self> {
+        morphology::from_asc(content)
+    }
+
     /// Create sections
     pub fn create(&mut self, name: &str) -> &mut Section {
         let section = Section::new(name);
@@ -381,10 +687,21 @@ impl NeuronCell {
         if !self.sections.contains_key(parent) {
             return Err(OldiesError::ModelNotFound(format!("Section {} not found", parent)));
         }
+        if child_end != 0.0 && child_end != 1.0 {
+            return Err(OldiesError::SimulationError(format!(
+                "Section {} must connect by its 0 or 1 end", child
+            )));
+        }
 
-        // Set parent
+        // Set parent, detaching from a previous one
+        if let Some((old, _)) = self.sections[child].parent.clone() {
+            if let Some(sec) = self.sections.get_mut(&old) {
+                sec.children.retain(|c| c != child);
+            }
+        }
         if let Some(sec) = self.sections.get_mut(child) {
             sec.parent = Some((parent.to_string(), parent_loc));
+            sec.connection_end = child_end;
         }
 
         // Add child
@@ -406,6 +723,256 @@ impl NeuronCell {
     pub fn total_segments(&self) -> usize {
         self.sections.values().map(|s| s.nseg).sum()
     }
+
+    /// Sections from `section` to the root of its tree, each with the
+    /// location where the path enters it and the path length (um) to there
+    fn path_to_root(&self, section: &str, loc: f64) -> Result<Vec<(&str, f64, f64)>> {
+        let mut path = vec![];
+        let (mut name, mut loc, mut length) = (section, loc, 0.0);
+        loop {
+            let sec = self.sections.get(name)
+                .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", name)))?;
+            path.push((sec.name.as_str(), loc, length));
+            let Some((parent, parent_loc)) = &sec.parent else { return Ok(path) };
+            if path.len() > self.sections.len() {
+                return Err(OldiesError::SimulationError(format!("Section {} is in a loop", section)));
+            }
+            length += (loc - sec.connection_end).abs() * sec.length;
+            (name, loc) = (parent, *parent_loc);
+        }
+    }
+
+    /// Path length (um) along the sections between two locations, each a
+    /// section and a position in it (0-1), as NEURON's `distance`
+    pub fn path_distance(&self, from: (&str, f64), to: (&str, f64)) -> Result<f64> {
+        let up = self.path_to_root(from.0, from.1)?;
+        let down = self.path_to_root(to.0, to.1)?;
+        for &(name, a, up_length) in &up {
+            if let Some(&(_, b, down_length)) = down.iter().find(|d| d.0 == name) {
+                return Ok(up_length + down_length + (a - b).abs() * self.sections[name].length);
+            }
+        }
+        Err(OldiesError::SimulationError(format!("Sections {} and {} are not connected", from.0, to.0)))
+    }
+
+    /// Set range variable `name` in every segment of every section that
+    /// has it to `f(d)`, where `d` is the path distance (um) of the segment
+    /// centre from `origin`, such as the middle of the soma. Returns the
+    /// number of segments set.
+    pub fn distribute(&mut self, name: &str, origin: (&str, f64), f: impl Fn(f64) -> f64) -> Result<usize> {
+        let mut values = vec![];
+        for sec in self.sections.values().filter(|s| s.range(name, 0.5).is_some()) {
+            for k in 0..sec.nseg {
+                let x = (k as f64 + 0.5) / sec.nseg as f64;
+                values.push((sec.name.clone(), x, f(self.path_distance(origin, (&sec.name, x))?)));
+            }
+        }
+        for (section, x, value) in &values {
+            self.sections.get_mut(section).unwrap().at(*x).set(name, *value)?;
+        }
+        Ok(values.len())
+    }
+
+    /// Section names in order: alphabetically, with the sections of an
+    /// array (`dend[0]`, `dend[1]`, ...) by index
+    pub fn section_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.sections.keys().cloned().collect();
+        names.sort_by_cached_key(|name| match name.strip_suffix(']').and_then(|n| n.split_once('[')) {
+            Some((array, index)) => (array.to_string(), index.parse::<usize>().ok()),
+            None => (name.clone(), None),
+        });
+        names
+    }
+
+    /// Names of the sections matching `pattern`, as HOC's `forsec` and
+    /// `ifsec`. The pattern is a regular expression searched for anywhere
+    /// in the name, with `.`, `*`, `+`, `?`, `[...]`, `^`, `$` and `\`
+    /// escapes, so `"dend"` matches every dendrite and `"^dend\\[2\\]$"`
+    /// only `dend[2]`.
+    pub fn sections_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = SectionPattern::new(pattern)?;
+        Ok(self.section_names().into_iter().filter(|n| pattern.matches(n)).collect())
+    }
+
+    /// Apply `f` to every section, as HOC's `forall`
+    pub fn forall(&mut self, mut f: impl FnMut(&mut Section)) {
+        for name in self.section_names() {
+            f(self.sections.get_mut(&name).unwrap());
+        }
+    }
+
+    /// Apply `f` to every section whose name matches `pattern` (see
+    /// [`sections_matching`](Self::sections_matching)), returning how many
+    /// there were: `cell.forsec("dend", |sec| sec.insert(mechanisms::pas()))`
+    pub fn forsec(&mut self, pattern: &str, mut f: impl FnMut(&mut Section)) -> Result<usize> {
+        let names = self.sections_matching(pattern)?;
+        for name in &names {
+            f(self.sections.get_mut(name).unwrap());
+        }
+        Ok(names.len())
+    }
+
+    /// Apply `f` to every section of `list`, as HOC's `forsec` over a
+    /// SectionList
+    pub fn forsec_list(&mut self, list: &SectionList, mut f: impl FnMut(&mut Section)) -> Result<()> {
+        if let Some(name) = list.names.iter().find(|n| !self.sections.contains_key(*n)) {
+            return Err(OldiesError::ModelNotFound(format!("Section {} not found", name)));
+        }
+        for name in &list.names {
+            f(self.sections.get_mut(name).unwrap());
+        }
+        Ok(())
+    }
+
+    /// Sections of the tree containing `section`, from its root down
+    pub fn wholetree(&self, section: &str) -> Result<SectionList> {
+        let (root, _, _) = *self.path_to_root(section, 0.0)?.last().unwrap();
+        let mut list = SectionList::new();
+        let mut stack = vec![root.to_string()];
+        while let Some(name) = stack.pop() {
+            stack.extend(self.sections[&name].children.iter().rev().cloned());
+            list.append(&name);
+        }
+        Ok(list)
+    }
+}
+
+/// Ordered set of section names, as NEURON's SectionList
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SectionList {
+    pub names: Vec<String>,
+}
+
+impl SectionList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `section` unless already present, returning whether it was
+    /// added
+    pub fn append(&mut self, section: &str) -> bool {
+        let added = !self.contains(section);
+        if added {
+            self.names.push(section.to_string());
+        }
+        added
+    }
+
+    /// Remove `section`, returning whether it was present
+    pub fn remove(&mut self, section: &str) -> bool {
+        let n = self.names.len();
+        self.names.retain(|s| s != section);
+        self.names.len() < n
+    }
+
+    pub fn contains(&self, section: &str) -> bool {
+        self.names.iter().any(|s| s == section)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// Element of a [`SectionPattern`]
+#[derive(Debug, Clone)]
+enum PatternItem {
+    Char(char),
+    Any,
+    /// Character ranges, negated with `[^...]`
+    Class(Vec<(char, char)>, bool),
+}
+
+impl PatternItem {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            PatternItem::Char(p) => *p == c,
+            PatternItem::Any => true,
+            PatternItem::Class(ranges, negated) => ranges.iter().any(|&(a, b)| (a..=b).contains(&c)) != *negated,
+        }
+    }
+}
+
+/// Regular expression over section names, as used by `forsec`
+#[derive(Debug, Clone)]
+pub struct SectionPattern {
+    /// Items with the least and most times each may repeat
+    items: Vec<(PatternItem, usize, usize)>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+impl SectionPattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let invalid = |msg: &str| OldiesError::ParseError(format!("Section pattern {:?}: {}", pattern, msg));
+        let mut chars = pattern.chars().peekable();
+        let anchored_start = chars.next_if_eq(&'^').is_some();
+        let mut items: Vec<(PatternItem, usize, usize)> = vec![];
+        let mut anchored_end = false;
+        while let Some(c) = chars.next() {
+            let item = match c {
+                '$' if chars.peek().is_none() => {
+                    anchored_end = true;
+                    break;
+                }
+                '.' => PatternItem::Any,
+                '\\' => PatternItem::Char(chars.next().ok_or_else(|| invalid("trailing \\"))?),
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut ranges = vec![];
+                    loop {
+                        let a = match chars.next() {
+                            Some(']') if !ranges.is_empty() => break,
+                            Some('\\') => chars.next().ok_or_else(|| invalid("trailing \\"))?,
+                            Some(a) => a,
+                            None => return Err(invalid("unclosed [")),
+                        };
+                        let b = if chars.peek() == Some(&'-') && chars.clone().nth(1).is_some_and(|b| b != ']') {
+                            chars.next();
+                            chars.next().unwrap()
+                        } else {
+                            a
+                        };
+                        ranges.push((a, b));
+                    }
+                    PatternItem::Class(ranges, negated)
+                }
+                '*' | '+' | '?' => {
+                    let Some(last) = items.last_mut().filter(|(_, min, max)| (*min, *max) == (1, 1)) else {
+                        return Err(invalid(&format!("{} repeats nothing", c)));
+                    };
+                    (last.1, last.2) = match c {
+                        '*' => (0, usize::MAX),
+                        '+' => (1, usize::MAX),
+                        _ => (0, 1),
+                    };
+                    continue;
+                }
+                c => PatternItem::Char(c),
+            };
+            items.push((item, 1, 1));
+        }
+        Ok(Self { items, anchored_start, anchored_end })
+    }
+
+    /// Whether the pattern occurs in `name`
+    pub fn matches(&self, name: &str) -> bool {
+        let chars: Vec<char> = name.chars().collect();
+        let starts = if self.anchored_start { 0..=0 } else { 0..=chars.len() };
+        starts.into_iter().any(|i| self.match_here(&self.items, &chars[i..]))
+    }
+
+    fn match_here(&self, items: &[(PatternItem, usize, usize)], s: &[char]) -> bool {
+        let Some(((item, min, max), rest)) = items.split_first() else {
+            return !self.anchored_end || s.is_empty();
+        };
+        let n = s.iter().take(*max).take_while(|&&c| item.matches(c)).count();
+        (*min..=n).rev().any(|k| self.match_here(rest, &s[k..]))
+    }
 }
 
 // =============================================================================
@@ -416,6 +983,27 @@ impl NeuronCell {
 pub mod mechanisms {
     use super::*;
 
+    /// Hodgkin-Huxley squid axon channels with leak (hh). The solver
+    /// keeps the gates `m`, `h`, `n` in `state` along with the range
+    /// variables `gna`, `gk`, `ina`, `ik` and `il`; rates scale with
+    /// q10 = 3 from 6.3 C.
+    pub fn hh() -> InsertedMechanism {
+        let mut params = HashMap::new();
+        params.insert("gnabar".to_string(), 0.12);  // S/cm^2
+        params.insert("gkbar".to_string(), 0.036);  // S/cm^2
+        params.insert("gl".to_string(), 0.0003);    // S/cm^2
+        params.insert("el".to_string(), -54.3);     // mV
+        params.insert("ena".to_string(), 50.0);     // mV
+        params.insert("ek".to_string(), -77.0);     // mV
+
+        InsertedMechanism {
+            name: "hh".to_string(),
+            parameters: params,
+            state: HashMap::new(),
+            segment_parameters: HashMap::new(),
+        }
+    }
+
     /// Hodgkin-Huxley sodium channel (hh)
     pub fn hh_na() -> InsertedMechanism {
         let mut params = HashMap::new();
@@ -426,6 +1014,7 @@ pub mod mechanisms {
             name: "na".to_string(),
             parameters: params,
             state: HashMap::new(),
+            segment_parameters: HashMap::new(),
         }
     }
 
@@ -439,6 +1028,7 @@ pub mod mechanisms {
             name: "k".to_string(),
             parameters: params,
             state: HashMap::new(),
+            segment_parameters: HashMap::new(),
         }
     }
 
@@ -452,6 +1042,25 @@ pub mod mechanisms {
             name: "pas".to_string(),
             parameters: params,
             state: HashMap::new(),
+            segment_parameters: HashMap::new(),
+        }
+    }
+
+    /// Extracellular layer: axial resistance `xraxial` (MOhm/cm) and
+    /// conductance `xg` (S/cm^2) and capacitance `xc` (uF/cm^2) to
+    /// `e_extracellular` (mV). The defaults tie it to ground.
+    pub fn extracellular() -> InsertedMechanism {
+        let mut params = HashMap::new();
+        params.insert("xraxial".to_string(), 1e9);
+        params.insert("xg".to_string(), 1e9);
+        params.insert("xc".to_string(), 0.0);
+        params.insert("e_extracellular".to_string(), 0.0);
+
+        InsertedMechanism {
+            name: "extracellular".to_string(),
+            parameters: params,
+            state: HashMap::new(),
+            segment_parameters: HashMap::new(),
         }
     }
 
@@ -501,6 +1110,151 @@ pub mod mechanisms {
             state: HashMap::new(),
         }
     }
+
+    /// Single-electrode voltage clamp (SEClamp) stepping through up to
+    /// three (duration ms, command mV) levels, with series resistance
+    /// `rs` of 1 MOhm
+    pub fn seclamp(section: &str, loc: f64, levels: &[(f64, f64)]) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("rs".to_string(), 1.0);       // MOhm
+        for j in 0..3 {
+            let (dur, amp) = levels.get(j).copied().unwrap_or((0.0, 0.0));
+            params.insert(format!("dur{}", j + 1), dur);  // ms
+            params.insert(format!("amp{}", j + 1), amp);  // mV
+        }
+
+        PointProcess {
+            name: "SEClamp".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state: HashMap::new(),
+        }
+    }
+
+    /// Two-electrode voltage clamp (VClamp) stepping through up to three
+    /// (duration ms, command mV) levels. The amplifier of gain `gain`
+    /// injects through `rstim` (MOhm).
+    pub fn vclamp(section: &str, loc: f64, levels: &[(f64, f64)]) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("gain".to_string(), 1e5);
+        params.insert("rstim".to_string(), 1.0);    // MOhm
+        for j in 0..3 {
+            let (dur, amp) = levels.get(j).copied().unwrap_or((0.0, 0.0));
+            params.insert(format!("dur{}", j), dur);      // ms
+            params.insert(format!("amp{}", j), amp);      // mV
+        }
+
+        PointProcess {
+            name: "VClamp".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state: HashMap::new(),
+        }
+    }
+
+    /// Current clamp ramping linearly from `amp0` to `amp1` (nA) over
+    /// `dur` ms from `delay` (IRamp)
+    pub fn iramp(section: &str, loc: f64, delay: f64, dur: f64, amp0: f64, amp1: f64) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("delay".to_string(), delay);  // ms
+        params.insert("dur".to_string(), dur);      // ms
+        params.insert("amp0".to_string(), amp0);    // nA
+        params.insert("amp1".to_string(), amp1);    // nA
+
+        PointProcess {
+            name: "IRamp".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state: HashMap::new(),
+        }
+    }
+
+    /// Sinusoidal current clamp `offset + amp * sin(2 pi freq (t - delay)
+    /// + phase)` for `dur` ms from `delay` (ISine), with `offset` 0 nA and
+    /// `phase` 0 rad
+    pub fn isine(section: &str, loc: f64, delay: f64, dur: f64, amp: f64, freq: f64) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("delay".to_string(), delay);  // ms
+        params.insert("dur".to_string(), dur);      // ms
+        params.insert("amp".to_string(), amp);      // nA
+        params.insert("freq".to_string(), freq);    // Hz
+        params.insert("phase".to_string(), 0.0);    // rad
+        params.insert("offset".to_string(), 0.0);   // nA
+
+        PointProcess {
+            name: "ISine".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state: HashMap::new(),
+        }
+    }
+
+    /// Noisy current clamp (INoise): an Ornstein-Uhlenbeck process of
+    /// `mean` and standard deviation `std` (nA) with correlation time
+    /// `tau` (ms) for `dur` ms from `delay`. The current holds for each
+    /// `interval` of 0.025 ms; `seed` picks the sample path.
+    pub fn inoise(section: &str, loc: f64, delay: f64, dur: f64, mean: f64, std: f64, tau: f64) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("delay".to_string(), delay);      // ms
+        params.insert("dur".to_string(), dur);          // ms
+        params.insert("mean".to_string(), mean);        // nA
+        params.insert("std".to_string(), std);          // nA
+        params.insert("tau".to_string(), tau);          // ms
+        params.insert("interval".to_string(), 0.025);   // ms
+        params.insert("seed".to_string(), 1.0);
+
+        PointProcess {
+            name: "INoise".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state: HashMap::new(),
+        }
+    }
+
+    /// Spike counter (APCount) counting upward crossings of `thresh` (mV)
+    /// by the membrane potential at its location. It passes no current;
+    /// `n` holds the count, `time` the last crossing and `firing` whether
+    /// the potential is still above threshold. Crossing times are kept in
+    /// [`NeuronSimulation::apcount_times`], and a connection with a
+    /// [`NetSource::Point`](netcon::NetSource::Point) source fires with it.
+    pub fn apcount(section: &str, loc: f64, thresh: f64) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("thresh".to_string(), thresh);  // mV
+        let state = ["n", "time", "firing"].iter().map(|s| (s.to_string(), 0.0)).collect();
+
+        PointProcess {
+            name: "APCount".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state,
+        }
+    }
+
+    /// Current clamp following an arbitrary waveform (IWave) through the
+    /// (time ms, current nA) `points`, interpolated linearly and 0 outside
+    /// them. They are stored as the parameters `n`, `t0`, `amp0`, `t1`, ...
+    pub fn iwave(section: &str, loc: f64, points: &[(f64, f64)]) -> PointProcess {
+        let mut params = HashMap::new();
+        params.insert("n".to_string(), points.len() as f64);
+        for (j, &(t, amp)) in points.iter().enumerate() {
+            params.insert(format!("t{}", j), t);      // ms
+            params.insert(format!("amp{}", j), amp);  // nA
+        }
+
+        PointProcess {
+            name: "IWave".to_string(),
+            section: section.to_string(),
+            location: loc,
+            parameters: params,
+            state: HashMap::new(),
+        }
+    }
 }
 
 // =============================================================================
@@ -519,8 +1273,92 @@ pub struct NeuronSimulation {
     pub tstop: Time,
     /// Temperature (celsius)
     pub celsius: f64,
+    /// Initial membrane potential of `init` (mV)
+    pub v_init: Voltage,
     /// Recorded variables
     pub recordings: HashMap<String, Vec<f64>>,
+    /// Mechanisms compiled from NMODL, by name
+    pub mechanisms: mechanism::MechanismLibrary,
+    /// Variable time-step settings
+    pub cvode: cvode::Cvode,
+    /// Upward threshold crossing times, by watch name
+    pub threshold_events: HashMap<String, Vec<Time>>,
+    /// Times counted by APCount point processes, by cell and point process
+    /// index
+    pub apcount_times: HashMap<(usize, usize), Vec<Time>>,
+    /// Network connections
+    pub netcons: Vec<netcon::NetCon>,
+    /// Artificial cells, targets and sources of connections
+    pub artificial_cells: Vec<netcon::ArtificialCell>,
+    /// Reaction-diffusion species, advanced with fixed steps
+    pub rxd: rxd::Rxd,
+    /// Threads sharing the cells in fixed-step runs
+    pub threads: usize,
+    /// Network events waiting for delivery
+    events: netcon::EventQueue,
+    /// Recorded variables
+    records: Vec<vector::Record>,
+    /// Waveforms played into variables
+    plays: Vec<vector::Play>,
+    /// Threshold watches
+    thresholds: Vec<Threshold>,
+    /// One integrator for all cells, or one per cell with `use_local_dt`
+    integrators: Vec<cvode::Integrator>,
+    /// Callbacks run by `finitialize`, in the order they were added
+    init_handlers: Vec<(InitStage, Box<InitHandler>)>,
+    /// Engine of fixed-step runs
+    engine: soa::Engine,
+    /// Flattened model while the SoA engine runs
+    flat: Option<soa::FlatModel>,
+}
+
+type InitHandler = dyn FnMut(&mut NeuronSimulation) -> Result<()> + Send;
+
+/// Point of `finitialize` at which a handler runs, as the type of NEURON's
+/// `FInitializeHandler`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStage {
+    /// Before anything is reset, while the model may still change
+    /// structure (type 3)
+    Start,
+    /// After the membrane potential is set, before the INITIAL blocks
+    /// (type 0)
+    BeforeInitial,
+    /// After the INITIAL blocks, before recording starts (type 1, the
+    /// default). Steady-state pre-runs go here and set `t` back to 0.
+    AfterInitial,
+    /// At the end, once recording started and the events due at the
+    /// start were delivered (type 2)
+    End,
+}
+
+/// Voltage at `section(loc)` of cell `cell` watched for upward crossings
+/// of `threshold`, recorded under `name`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Threshold {
+    pub name: String,
+    pub cell: usize,
+    pub section: String,
+    pub loc: f64,
+    pub threshold: Voltage,
+    /// Last (t, v) seen
+    #[serde(skip)]
+    last: Option<(Time, Voltage)>,
+}
+
+impl Threshold {
+    /// Time at which `v` rose through the threshold since the last check,
+    /// located by linear interpolation
+    fn crossing(&mut self, t: Time, v: Voltage) -> Option<Time> {
+        let crossing = match self.last {
+            Some((t0, v0)) if v0 < self.threshold && v >= self.threshold => {
+                Some(t0 + (self.threshold - v0) / (v - v0) * (t - t0))
+            }
+            _ => None,
+        };
+        self.last = Some((t, v));
+        crossing
+    }
 }
 
 impl NeuronSimulation {
@@ -531,8 +1369,25 @@ impl NeuronSimulation {
             t: 0.0,
             dt: 0.025,      // Default NEURON dt
             tstop: 100.0,
-            celsius: 37.0,  // Default temperature
+            celsius: 6.3,   // Default NEURON temperature
+            v_init: -65.0,
             recordings: HashMap::new(),
+            mechanisms: HashMap::new(),
+            cvode: cvode::Cvode::default(),
+            threshold_events: HashMap::new(),
+            apcount_times: HashMap::new(),
+            netcons: Vec::new(),
+            artificial_cells: Vec::new(),
+            rxd: rxd::Rxd::default(),
+            threads: 1,
+            events: netcon::EventQueue::default(),
+            records: Vec::new(),
+            plays: Vec::new(),
+            thresholds: Vec::new(),
+            integrators: Vec::new(),
+            init_handlers: Vec::new(),
+            engine: soa::Engine::default(),
+            flat: None,
         }
     }
 
@@ -541,38 +1396,418 @@ impl NeuronSimulation {
         self.cells.push(cell);
     }
 
-    /// Initialize simulation
-    pub fn finitialize(&mut self, v_init: Voltage) {
+    /// Compile an NMODL density mechanism so sections can `insert` it,
+    /// returning its name
+    pub fn load_nmodl(&mut self, content: &str) -> Result<String> {
+        let compiled = mechanism::compile(&parse_nmodl(content)?)?;
+        let name = compiled.name.clone();
+        self.mechanisms.insert(name.clone(), compiled);
+        Ok(name)
+    }
+
+    /// Record `variable` under `name` every `interval` (ms) starting at 0,
+    /// or at every step alongside `t` if `None`
+    pub fn record(&mut self, name: &str, variable: vector::Variable, interval: Option<Time>) {
+        self.records.push(vector::Record::new(name, variable, interval));
+    }
+
+    /// Record the membrane potential of `section(loc)` of cell `cell`
+    /// under `name` at every step, alongside `t`
+    pub fn record_v(&mut self, name: &str, cell: usize, section: &str, loc: f64) {
+        self.record(name, vector::Variable::voltage(cell, section, loc), None);
+    }
+
+    /// Record variable `var` of point process `index` of cell `cell`, such
+    /// as the current `i` of a clamp, under `name` at every step
+    pub fn record_point(&mut self, name: &str, cell: usize, index: usize, var: &str) {
+        let variable = vector::Variable::Point { cell, index, name: var.to_string() };
+        self.record(name, variable, None);
+    }
+
+    /// Record the extracellular potential (mV) that all cells produce at
+    /// `electrode` under `name` at every step
+    pub fn record_lfp(&mut self, name: &str, electrode: lfp::Electrode) {
+        self.record(name, vector::Variable::Lfp(electrode), None);
+    }
+
+    /// Drive a variable from a waveform from now on
+    pub fn play(&mut self, play: vector::Play) {
+        self.plays.push(play);
+    }
+
+    /// Set the played variables to their values at the current time, ahead
+    /// of sampling and the next step
+    fn apply_plays(&mut self) -> Result<()> {
+        // Fixed steps need not land exactly on the waveform times
+        let t = self.t + if self.cvode.active { 1e-9 } else { self.dt / 2.0 };
+        for play in &self.plays {
+            if let Some(x) = play.value_at(t) {
+                play.variable.set(&mut self.cells, x)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record in `threshold_events[name]` the times at which the membrane
+    /// potential of `section(loc)` of cell `cell` rises through `threshold`
+    pub fn add_threshold(&mut self, name: &str, cell: usize, section: &str, loc: f64, threshold: Voltage) {
+        self.thresholds.push(Threshold {
+            name: name.to_string(),
+            cell,
+            section: section.to_string(),
+            loc,
+            threshold,
+            last: None,
+        });
+    }
+
+    /// Add a network connection, returning its index
+    pub fn add_netcon(&mut self, netcon: netcon::NetCon) -> usize {
+        self.netcons.push(netcon);
+        self.netcons.len() - 1
+    }
+
+    /// Add an artificial cell, returning its index
+    pub fn add_artificial_cell(&mut self, cell: netcon::ArtificialCell) -> usize {
+        self.artificial_cells.push(cell);
+        self.artificial_cells.len() - 1
+    }
+
+    /// Queue the events of the connections from artificial cell `cell`,
+    /// which fired at `t`
+    fn fire(&mut self, cell: usize, t: Time) {
+        let source = netcon::NetSource::Artificial(cell);
+        for (i, nc) in self.netcons.iter_mut().enumerate().filter(|(_, nc)| nc.source == source) {
+            nc.spikes.push(t);
+            if nc.target.is_some() {
+                self.events.push(t + nc.delay, i);
+            }
+        }
+    }
+
+    /// Deliver an event to artificial cell `cell` at `t`: of `weight` from
+    /// a connection, or one the cell sent itself if `None`
+    pub(crate) fn receive(&mut self, cell: usize, t: Time, weight: Option<f64>) -> Result<()> {
+        let artificial = self.artificial_cells.get_mut(cell)
+            .ok_or_else(|| OldiesError::ModelNotFound(format!("Artificial cell {}", cell)))?;
+        let response = match weight {
+            Some(weight) => artificial.net_receive(t, weight)?,
+            None => artificial.self_event(t)?,
+        };
+        if let Some(ts) = response.send {
+            self.events.send(ts, cell);
+        }
+        if response.fire {
+            self.fire(cell, t);
+        }
+        Ok(())
+    }
+
+    /// Deliver the events due by `t`
+    fn deliver(&mut self, t: Time) -> Result<()> {
+        while let Some((te, delivery)) = self.events.pop_until(t) {
+            let i = match delivery {
+                netcon::Delivery::NetCon(i) => i,
+                netcon::Delivery::SelfEvent(a) => {
+                    self.receive(a, te, None)?;
+                    continue;
+                }
+            };
+            let nc = &self.netcons[i];
+            match nc.target.clone() {
+                Some(netcon::NetTarget::PointProcess { cell, index }) if self.flat.is_some() => {
+                    self.flat.as_mut().unwrap().net_receive(cell, index, nc.weight)?;
+                }
+                Some(netcon::NetTarget::PointProcess { cell, index }) => {
+                    let pp = self.cells.get_mut(cell)
+                        .and_then(|c| c.point_processes.get_mut(index))
+                        .ok_or_else(|| OldiesError::ModelNotFound(format!("Point process {} of cell {}", index, cell)))?;
+                    cable::net_receive(pp, nc.weight)?;
+                }
+                Some(netcon::NetTarget::Artificial(a)) => self.receive(a, te, Some(nc.weight))?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch variable time-step integration on or off
+    pub fn cvode_active(&mut self, on: bool) {
+        self.cvode.active = on;
+        self.integrators.clear();
+    }
+
+    /// Steps taken by each variable-step integrator since `finitialize`
+    pub fn cvode_steps(&self) -> Vec<usize> {
+        self.integrators.iter().map(|i| i.steps).collect()
+    }
+
+    /// Check the thresholds of cell `cell` (all cells if `None`) at `t`,
+    /// locating crossings by linear interpolation
+    fn watch(&mut self, cell: Option<usize>, t: Time) -> Result<()> {
+        for (j, th) in self.thresholds.iter_mut().enumerate().filter(|(_, th)| cell.is_none_or(|c| c == th.cell)) {
+            let v = match &self.flat {
+                Some(flat) => flat.v(flat.threshold_nodes[j]),
+                None => self.cells.get(th.cell)
+                    .and_then(|c| c.sections.get(&th.section))
+                    .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", th.section)))?
+                    .v_at(th.loc),
+            };
+            if let Some(crossing) = th.crossing(t, v) {
+                self.threshold_events.entry(th.name.clone()).or_default().push(crossing);
+            }
+        }
+        if self.flat.is_none() {
+            for c in (0..self.cells.len()).filter(|&c| cell.is_none_or(|cell| cell == c)) {
+                for index in cable::count_spikes(&mut self.cells[c], t)? {
+                    self.ap_counted(c, index, t);
+                }
+            }
+        }
+        for (i, nc) in self.netcons.iter_mut().enumerate() {
+            let netcon::NetSource::Voltage { cell: c, section, loc } = &nc.source else { continue };
+            if cell.is_some_and(|cell| cell != *c) {
+                continue;
+            }
+            let v = match &self.flat {
+                Some(flat) => flat.v(flat.netcon_nodes[i].unwrap()),
+                None => self.cells.get(*c)
+                    .and_then(|c| c.sections.get(section))
+                    .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", section)))?
+                    .v_at(*loc),
+            };
+            if let Some(crossing) = nc.crossing(t, v) {
+                nc.spikes.push(crossing);
+                if nc.target.is_some() {
+                    self.events.push(crossing + nc.delay, i);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the time `t` counted by APCount `index` of cell `cell` and fire
+    /// the connections it is the source of
+    pub(crate) fn ap_counted(&mut self, cell: usize, index: usize, t: Time) {
+        self.apcount_times.entry((cell, index)).or_default().push(t);
+        let source = netcon::NetSource::Point { cell, index };
+        for (i, nc) in self.netcons.iter_mut().enumerate().filter(|(_, nc)| nc.source == source) {
+            nc.spikes.push(t);
+            if nc.target.is_some() {
+                self.events.push(t + nc.delay, i);
+            }
+        }
+    }
+
+    /// Spikes counted by every APCount as (time, cell), in time order, for
+    /// raster plots
+    pub fn raster(&self) -> Vec<(Time, usize)> {
+        let mut spikes: Vec<(Time, usize)> = self.apcount_times.iter()
+            .flat_map(|(&(cell, _), times)| times.iter().map(move |&t| (t, cell)))
+            .collect();
+        spikes.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        spikes
+    }
+
+    fn sample(&mut self) -> Result<()> {
+        if self.records.iter().any(|r| r.interval.is_none()) {
+            self.recordings.entry("t".to_string()).or_default().push(self.t);
+        }
+        let tolerance = if self.cvode.active && !self.cvode.use_local_dt { 1e-9 } else { self.dt / 2.0 };
+        for (j, record) in self.records.iter_mut().enumerate() {
+            if record.due(self.t, tolerance) {
+                let x = match &self.flat {
+                    Some(flat) => flat.recorded(j, self.t),
+                    None => record.variable.get(&self.cells, self.t)?,
+                };
+                self.recordings.entry(record.name.clone()).or_default().push(x);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `handler` at `stage` of every `finitialize`
+    pub fn finitialize_handler<F>(&mut self, stage: InitStage, handler: F)
+    where
+        F: FnMut(&mut NeuronSimulation) -> Result<()> + Send + 'static,
+    {
+        self.init_handlers.push((stage, Box::new(handler)));
+    }
+
+    fn run_init_handlers(&mut self, stage: InitStage) -> Result<()> {
+        // Handlers get the whole simulation, so they are taken out while
+        // they run; any they add themselves run from the next time on
+        let mut handlers = std::mem::take(&mut self.init_handlers);
+        let result = handlers.iter_mut()
+            .filter(|(s, _)| *s == stage)
+            .try_for_each(|(_, handler)| handler(self));
+        handlers.append(&mut self.init_handlers);
+        self.init_handlers = handlers;
+        result
+    }
+
+    /// Initialize simulation in NEURON's order: played values at 0, every
+    /// segment to `v_init`, the INITIAL blocks of mechanisms and artificial
+    /// cells with ions settled, recording, then delivery of the events due
+    /// at 0, with the handlers of each [`InitStage`] in between
+    pub fn finitialize(&mut self, v_init: Voltage) -> Result<()> {
+        self.run_init_handlers(InitStage::Start)?;
         self.t = 0.0;
-        self.recordings.clear();
+        self.events.clear();
+        for nc in &mut self.netcons {
+            nc.spikes.clear();
+            nc.last = None;
+        }
 
+        self.apply_plays()?;
+        for sec in self.cells.iter_mut().flat_map(|c| c.sections.values_mut()) {
+            sec.v = vec![v_init; sec.nseg];
+        }
+        self.run_init_handlers(InitStage::BeforeInitial)?;
+        self.rxd.initialize(&mut self.cells)?;
         for cell in &mut self.cells {
-            for section in cell.sections.values_mut() {
-                for v in &mut section.v {
-                    *v = v_init;
+            cable::initialize_states(cell, self.celsius, &self.mechanisms)?;
+        }
+        for (i, cell) in self.artificial_cells.iter_mut().enumerate() {
+            if let Some(ts) = cell.initialize()? {
+                self.events.send(ts, i);
+            }
+        }
+        self.run_init_handlers(InitStage::AfterInitial)?;
+
+        // Recording starts afresh after any pre-run
+        self.recordings.clear();
+        self.threshold_events.clear();
+        self.apcount_times.clear();
+        self.integrators.clear();
+        for record in &mut self.records {
+            record.next = self.t;
+        }
+        for th in &mut self.thresholds {
+            th.last = None;
+        }
+        for nc in &mut self.netcons {
+            nc.last = None;
+        }
+        self.watch(None, self.t)?;
+        self.sample()?;
+        self.deliver(self.t)?;
+        self.run_init_handlers(InitStage::End)
+    }
+
+    /// Initialize at `v_init`, as NEURON's `init()`
+    pub fn init(&mut self) -> Result<()> {
+        self.finitialize(self.v_init)
+    }
+
+    /// Advance one time step, solving the cable equation of every cell.
+    /// With CVODE active this is one variable step of all cells, ending no
+    /// later than `tstop`, the next network event, sampling time or step of
+    /// a played waveform, or with `use_local_dt` a stretch of `dt` that each
+    /// cell covers in its own steps. Played variables then take their values
+    /// at the new time.
+    pub fn fadvance(&mut self) -> Result<()> {
+        if self.engine == soa::Engine::Soa && !self.cvode.active {
+            return self.advance_flat(true);
+        }
+        if !self.cvode.active || self.cvode.use_local_dt {
+            self.deliver(self.t + self.dt / 2.0)?;
+        } else {
+            self.deliver(self.t)?;
+        }
+        if !self.cvode.active {
+            for cell in &mut self.cells {
+                cable::advance(cell, self.t, self.dt, self.celsius, &self.mechanisms)?;
+            }
+            if !self.rxd.is_empty() {
+                self.rxd.advance(&mut self.cells, self.dt)?;
+                for cell in &mut self.cells {
+                    cable::update_ions(cell, self.celsius, &self.mechanisms)?;
+                }
+            }
+            self.t += self.dt;
+            self.watch(None, self.t)?;
+            self.apply_plays()?;
+            return self.sample();
+        }
+
+        if !self.rxd.is_empty() {
+            return Err(OldiesError::SimulationError("rxd needs fixed steps".into()));
+        }
+        let count = if self.cvode.use_local_dt { self.cells.len() } else { 1 };
+        if self.integrators.len() != count {
+            self.integrators = vec![cvode::Integrator::default(); count];
+        }
+        if self.cvode.use_local_dt {
+            let target = self.t + self.dt;
+            for i in 0..self.cells.len() {
+                let mut t = self.t;
+                while t < target {
+                    t = self.integrators[i].step(
+                        std::slice::from_mut(&mut self.cells[i]),
+                        t,
+                        target,
+                        &self.cvode,
+                        self.celsius,
+                        &self.mechanisms,
+                    )?;
+                    self.watch(Some(i), t)?;
+                }
+            }
+            self.t = target;
+        } else {
+            let mut stop = if self.t < self.tstop { self.tstop } else { f64::INFINITY };
+            if let Some(te) = self.events.next_time() {
+                stop = stop.min(te);
+            }
+            // Land on sampling times and on the steps of played waveforms
+            for record in self.records.iter().filter(|r| r.interval.is_some()) {
+                if record.next > self.t {
+                    stop = stop.min(record.next);
+                }
+            }
+            for play in &self.plays {
+                if let Some(tp) = play.next_time(self.t + 1e-9) {
+                    stop = stop.min(tp);
                 }
             }
+            self.t = self.integrators[0].step(&mut self.cells, self.t, stop, &self.cvode, self.celsius, &self.mechanisms)?;
+            self.watch(None, self.t)?;
         }
+        self.apply_plays()?;
+        self.sample()
     }
 
-    /// Advance one time step
-    pub fn fadvance(&mut self) {
-        // Simplified cable equation integration
-        // In full NEURON, this uses Crank-Nicolson with Gaussian elimination
-        self.t += self.dt;
+    /// Whether a run at time `t` takes another step. Fixed steps stop
+    /// within half a step of `tstop`, as NEURON's stdrun does, so rounding
+    /// in `t` cannot add a step past it
+    pub(crate) fn before_tstop(&self, t: Time) -> bool {
+        if self.cvode.active {
+            t < self.tstop
+        } else {
+            t < self.tstop - self.dt / 2.0
+        }
     }
 
-    /// Run simulation
-    pub fn run(&mut self) {
-        while self.t < self.tstop {
-            self.fadvance();
+    /// Run simulation, on `threads` threads between network exchanges
+    /// where possible, or on the flattened model of the SoA engine
+    pub fn run(&mut self) -> Result<()> {
+        if self.engine == soa::Engine::Soa && !self.cvode.active && self.before_tstop(self.t) {
+            return self.advance_flat(false);
+        }
+        while self.before_tstop(self.t) {
+            match self.thread_steps() {
+                Some(steps) => self.advance_threads(steps)?,
+                None => self.fadvance()?,
+            }
         }
+        Ok(())
     }
 
     /// Continue running
-    pub fn continuerun(&mut self, tstop: Time) {
+    pub fn continuerun(&mut self, tstop: Time) -> Result<()> {
         self.tstop = tstop;
-        self.run();
+        self.run()
     }
 }
 
@@ -586,20 +1821,16 @@ impl Default for NeuronSimulation {
 // HOC FILE LOADER
 // =============================================================================
 
-/// Load and parse a HOC file
-pub fn load_hoc(_content: &str) -> Result<NeuronCell> {
-    // TODO: Implement full HOC parser
-    // For now, return a basic cell
-    Ok(NeuronCell::new("cell"))
+/// Run a HOC script and return the cell it builds
+pub fn load_hoc(content: &str) -> Result<NeuronCell> {
+    let mut interpreter = hoc::Hoc::new();
+    interpreter.execute(content)?;
+    Ok(interpreter.sim.cells.swap_remove(0))
 }
 
 /// Parse NMODL content
-pub fn parse_nmodl(_content: &str) -> Result<NmodlMechanism> {
-    // TODO: Implement full NMODL parser
-    Ok(NmodlMechanism {
-        title: None,
-        blocks: Vec::new(),
-    })
+pub fn parse_nmodl(content: &str) -> Result<NmodlMechanism> {
+    nmodl::parse(content)
 }
 
 // =============================================================================
@@ -670,10 +1901,43 @@ mod tests {
         cell.create("soma");
         sim.add_cell(cell);
 
-        sim.finitialize(-65.0);
+        sim.finitialize(-65.0).unwrap();
         assert_eq!(sim.t, 0.0);
     }
 
+    #[test]
+    fn test_soma_dendrite_spikes() {
+        let mut cell = NeuronCell::new("ball_and_stick");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::hh());
+        let dend = cell.create("dend");
+        dend.length = 200.0;
+        dend.diam = 1.0;
+        dend.set_nseg(5);
+        dend.insert(mechanisms::pas());
+        cell.connect("dend", 0.0, "soma", 1.0).unwrap();
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 5.0, 40.0, 0.3));
+
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(cell);
+        sim.tstop = 50.0;
+        sim.record_v("soma", 0, "soma", 0.5);
+        sim.record_v("dend", 0, "dend", 1.0);
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+
+        let soma = &sim.recordings["soma"];
+        assert_eq!(soma.len(), sim.recordings["t"].len());
+        let spikes = soma.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!(spikes >= 3, "spikes = {}", spikes);
+        // The dendrite follows the soma, attenuated
+        let peak = |v: &[f64]| v.iter().cloned().fold(f64::MIN, f64::max);
+        let dend = &sim.recordings["dend"];
+        assert!(peak(dend) > -40.0 && peak(dend) < peak(soma));
+    }
+
     #[test]
     fn test_section_area() {
         let mut sec = Section::new("test");
@@ -684,4 +1948,228 @@ mod tests {
         // pi * 10 * 100 * 1e-8 = ~3.14e-5 cm^2
         assert!((area - 3.14159e-5).abs() < 1e-6);
     }
+
+    #[test]
+    fn test_pt3d_geometry() {
+        // Cone tapering from 2 to 1 um over 100 um
+        let mut sec = Section::new("cone");
+        sec.pt3dadd(Point3d { x: 0.0, y: 0.0, z: 0.0, diam: 2.0 });
+        sec.pt3dadd(Point3d { x: 100.0, y: 0.0, z: 0.0, diam: 1.0 });
+        sec.set_nseg(2);
+        assert_eq!((sec.length, sec.diam), (100.0, 1.5));
+
+        let frustum = |d0: f64, d1: f64| std::f64::consts::PI * (d0 + d1) / 2.0 * (2500.0 + (d1 - d0).powi(2) / 4.0).sqrt() * 1e-8;
+        assert!((sec.segment_area(0) - frustum(2.0, 1.5)).abs() < 1e-15);
+        assert!((sec.segment_area(1) - frustum(1.5, 1.0)).abs() < 1e-15);
+
+        let ri = 4.0 * 100.0 * 100.0 / (std::f64::consts::PI * 2.0 * 1.0) * 1e-2;
+        assert!((sec.axial_resistance(0.0, 1.0) - ri).abs() < 1e-12);
+        assert!((sec.axial_resistance(1.0, 0.5) + sec.axial_resistance(0.0, 0.5) - ri).abs() < 1e-12);
+
+        // Changing L stretches the 3D points
+        sec.length = 200.0;
+        assert!((sec.axial_resistance(0.0, 1.0) - 2.0 * ri).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_segment_parameters() {
+        let mut sec = Section::new("dend");
+        sec.set_nseg(5);
+        sec.insert(mechanisms::pas());
+        sec.at(0.9).set("g_pas", 2e-3).unwrap();
+        sec.at(0.1).set_v(-80.0);
+        assert_eq!(sec.at(0.1).get("g_pas"), Some(1e-3));
+        assert_eq!(sec.range("g", 0.9), Some(2e-3));
+        assert_eq!((sec.at(0.1).v(), sec.at(0.5).x()), (-80.0, 0.5));
+        assert!(sec.at(0.5).set("gnabar_hh", 0.1).is_err());
+
+        // Finer segments keep the gradient
+        sec.set_nseg(10);
+        assert_eq!(sec.range("g_pas", 0.75), Some(1e-3));
+        assert_eq!(sec.range("g_pas", 0.85), Some(2e-3));
+        // Setting the whole section makes it uniform again
+        assert!(sec.set_range("g_pas", None, 5e-4));
+        assert_eq!(sec.range("g_pas", 0.85), Some(5e-4));
+
+        // The solver uses each segment's value
+        let mut cell = NeuronCell::new("cell");
+        let dend = cell.create("dend");
+        dend.length = 2000.0;
+        dend.set_nseg(2);
+        dend.insert(mechanisms::pas());
+        dend.at(0.75).set("e_pas", -50.0).unwrap();
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(cell);
+        sim.tstop = 50.0;
+        sim.finitialize(-70.0).unwrap();
+        sim.run().unwrap();
+        let dend = &sim.cells[0].sections["dend"];
+        assert!(dend.v_at(0.75) > dend.v_at(0.25) + 10.0, "{:?}", dend.v);
+    }
+
+    #[test]
+    fn test_distance_gradient() {
+        let mut cell = NeuronCell::new("cell");
+        cell.create("soma").length = 20.0;
+        let dend = cell.create("dend");
+        dend.length = 200.0;
+        dend.set_nseg(4);
+        dend.insert(mechanisms::pas());
+        cell.create("apic").length = 100.0;
+        cell.connect("dend", 0.0, "soma", 1.0).unwrap();
+        cell.connect("apic", 0.0, "soma", 0.0).unwrap();
+
+        assert_eq!(cell.path_distance(("soma", 0.5), ("dend", 0.5)).unwrap(), 110.0);
+        assert_eq!(cell.path_distance(("dend", 0.5), ("apic", 0.5)).unwrap(), 170.0);
+        assert_eq!(cell.path_distance(("dend", 0.25), ("dend", 0.75)).unwrap(), 100.0);
+        cell.create("orphan");
+        assert!(cell.path_distance(("soma", 0.5), ("orphan", 0.5)).is_err());
+
+        // Leak rising linearly with distance, only where pas is inserted
+        let set = cell.distribute("g_pas", ("soma", 0.5), |d| 1e-4 * (1.0 + d / 100.0)).unwrap();
+        assert_eq!(set, 4);
+        let dend = &cell.sections["dend"];
+        for (x, d) in [(0.125, 35.0), (0.875, 185.0)] {
+            assert!((dend.range("g_pas", x).unwrap() - 1e-4 * (1.0 + d / 100.0)).abs() < 1e-15);
+        }
+    }
+
+    #[test]
+    fn test_section_iteration() {
+        let mut cell = NeuronCell::new("cell");
+        for name in ["soma", "dend[10]", "dend[2]", "apic", "axon"] {
+            cell.create(name);
+        }
+        cell.connect("dend[2]", 0.0, "soma", 1.0).unwrap();
+        cell.connect("dend[10]", 0.0, "dend[2]", 1.0).unwrap();
+        assert_eq!(cell.section_names(), ["apic", "axon", "dend[2]", "dend[10]", "soma"]);
+
+        // Insert pas in all dendrites
+        let n = cell.forsec("dend", |sec| sec.insert(mechanisms::pas())).unwrap();
+        assert_eq!(n, 2);
+        assert!(cell.sections["dend[10]"].range("g_pas", 0.5).is_some());
+        assert!(cell.sections["soma"].mechanisms.is_empty());
+        assert_eq!(cell.sections_matching("^a").unwrap(), ["apic", "axon"]);
+        assert_eq!(cell.sections_matching(r"dend\[1[0-9]*\]$").unwrap(), ["dend[10]"]);
+        assert_eq!(cell.sections_matching("^s.m+a$").unwrap(), ["soma"]);
+        assert_eq!(cell.sections_matching("x?o[^x]").unwrap(), ["axon", "soma"]);
+        assert!(cell.sections_matching("dend[").is_err());
+        assert!(cell.sections_matching("*").is_err());
+
+        let mut n = 0;
+        cell.forall(|sec| {
+            sec.ra = 100.0;
+            n += 1;
+        });
+        assert_eq!(n, 5);
+
+        let mut list = cell.wholetree("dend[10]").unwrap();
+        assert_eq!(list.names, ["soma", "dend[2]", "dend[10]"]);
+        assert!(list.remove("soma") && !list.remove("soma"));
+        assert!(!list.append("dend[2]"));
+        cell.forsec_list(&list, |sec| sec.cm = 2.0).unwrap();
+        assert_eq!((cell.sections["dend[2]"].cm, cell.sections["soma"].cm), (2.0, 1.0));
+        list.append("gone");
+        assert!(cell.forsec_list(&list, |_| {}).is_err());
+    }
+
+    fn hh_soma() -> NeuronSimulation {
+        let mut cell = NeuronCell::new("cell");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::hh());
+        soma.insert(mechanisms::pas());
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(cell);
+        sim
+    }
+
+    #[test]
+    fn test_apcount_fi_curve() {
+        let mut counts = vec![];
+        for (cvode, amp) in [(false, 0.0), (false, 0.2), (false, 0.5), (true, 0.5)] {
+            let mut sim = hh_soma();
+            sim.cells[0].add_point_process(mechanisms::iclamp("soma", 0.5, 10.0, 100.0, amp));
+            sim.cells[0].add_point_process(mechanisms::apcount("soma", 0.5, -20.0));
+            sim.add_threshold("ap", 0, "soma", 0.5, -20.0);
+            sim.cvode_active(cvode);
+            sim.tstop = 120.0;
+            sim.init().unwrap();
+            sim.run().unwrap();
+            let n = sim.cells[0].point_processes[1].state["n"];
+            let times = sim.apcount_times.get(&(0, 1)).cloned().unwrap_or_default();
+            assert_eq!(times.len(), n as usize);
+            // Counted at the end of the step that crossed
+            let crossings = sim.threshold_events.get("ap").cloned().unwrap_or_default();
+            assert_eq!(crossings.len(), times.len());
+            for (t, c) in times.iter().zip(&crossings) {
+                assert!(t >= c && *t - c < 0.5, "{} {}", t, c);
+            }
+            counts.push(n);
+        }
+        // Firing rises with the injected current
+        assert_eq!(counts[0], 0.0);
+        assert!(counts[2] > counts[1] && counts[1] > 0.0, "{:?}", counts);
+        assert_eq!(counts[3], counts[2]);
+    }
+
+    #[test]
+    fn test_finitialize_handlers() {
+        use std::sync::{Arc, Mutex};
+        let mut sim = hh_soma();
+        let stim = sim.add_artificial_cell(netcon::ArtificialCell::net_stim(0.0, 10.0, 1.0, 0.0));
+        sim.add_netcon(netcon::NetCon::new(netcon::NetSource::Artificial(stim), None));
+        let seen = Arc::new(Mutex::new(vec![]));
+        let stages = [InitStage::End, InitStage::AfterInitial, InitStage::BeforeInitial, InitStage::Start];
+        for stage in stages {
+            let seen = seen.clone();
+            sim.finitialize_handler(stage, move |sim| {
+                let soma = &sim.cells[0].sections["soma"];
+                seen.lock().unwrap().push((stage, soma.v.first().copied(), sim.netcons[0].spikes.len()));
+                if stage == InitStage::BeforeInitial {
+                    sim.cells[0].sections.get_mut("soma").unwrap().v = vec![-60.0];
+                }
+                Ok(())
+            });
+        }
+        sim.finitialize(-65.0).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![
+            (InitStage::Start, Some(-65.0), 0),
+            (InitStage::BeforeInitial, Some(-65.0), 0),
+            (InitStage::AfterInitial, Some(-60.0), 0),
+            // The NetStim event at 0 is delivered before the end
+            (InitStage::End, Some(-60.0), 1),
+        ]);
+        // The gates start from their steady state at the handler's voltage
+        let mut reference = hh_soma();
+        reference.finitialize(-60.0).unwrap();
+        let m = |sim: &NeuronSimulation| sim.cells[0].sections["soma"].range("m_hh", 0.5).unwrap();
+        assert_eq!(m(&sim), m(&reference));
+    }
+
+    #[test]
+    fn test_steady_state_prerun() {
+        let mut sim = hh_soma();
+        sim.record_v("v", 0, "soma", 0.5);
+        sim.tstop = 20.0;
+        sim.finitialize_handler(InitStage::AfterInitial, |sim| {
+            // Long steps from far in the past, then back to 0
+            let dt = sim.dt;
+            sim.t = -500.0;
+            sim.dt = 1.0;
+            while sim.t < 0.0 {
+                sim.fadvance()?;
+            }
+            sim.t = 0.0;
+            sim.dt = dt;
+            Ok(())
+        });
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+        let v = &sim.recordings["v"];
+        assert_eq!(sim.recordings["t"][0], 0.0);
+        assert!((v[0] + 65.0).abs() > 0.1, "{}", v[0]);
+        assert!((v[v.len() - 1] - v[0]).abs() < 1e-3, "{} {}", v[0], v[v.len() - 1]);
+    }
 }
@@ -0,0 +1,1454 @@
+//! Runtime for NMODL mechanisms
+//!
+//! [`compile`] turns a parsed density mechanism (`SUFFIX`) into a
+//! [`CompiledMechanism`] that the cable solver evaluates like the built-in
+//! channels: BREAKPOINT gives the membrane current at a voltage, INITIAL
+//! sets the states and the DERIVATIVE block named by `SOLVE` advances them.
+//!
+//! Statements are compiled to a small tree over numbered variable slots.
+//! PARAMETERs and the ion variables read through USEION live in the
+//! section-wide [`InsertedMechanism::parameters`]; STATE and ASSIGNED
+//! variables are stored per segment in [`InsertedMechanism::state`];
+//! parameters set segment by segment, as for gradients along dendrites,
+//! in [`InsertedMechanism::segment_parameters`]. The
+//! membrane current is the sum of the ion currents written and the
+//! NONSPECIFIC_CURRENTs, in mA/cm^2. Ion variables that the cable solver
+//! keeps per segment (total currents, accumulated concentrations and the
+//! reversal potentials that follow them) are stored in `state` too and
+//! take precedence over the parameters. `v`, `celsius` and `dt` are the
+//! simulator's, even where a mod file declares them as PARAMETERs, so that
+//! rates scaled by a q10 follow `NeuronSimulation::celsius`.
+//!
+//! Each state equation is integrated as linear in its own state over the
+//! step, `x += (exp(b dt) - 1) / b * x'` with `b = dx'/dx` found
+//! numerically: exact for `cnexp` gates and stable for `derivimplicit`.
+//! `euler` takes a forward Euler step.
+//!
+//! KINETIC blocks (`METHOD sparse`) take a backward Euler step of their
+//! mass-action reactions, `~ a + 2 b <-> c (kf, kb)`, and fluxes,
+//! `~ x << (f)`, solved by Newton iteration. The ordinary statements of the
+//! block run first and the rate expressions are evaluated once per step.
+//! Each CONSERVE statement replaces the equation of the last state it
+//! names, so Markov schemes keep their occupancies summing to one, and
+//! COMPARTMENT divides the rates of change of its states by a volume.
+//! `SOLVE ... STEADYSTATE sparse` in INITIAL takes a step of 1e9 ms, as
+//! NEURON does. Functions cannot recurse; loops, arrays and point
+//! processes are not supported.
+
+use crate::rxd::solve_dense;
+use crate::{InsertedMechanism, MechanismType, NmodlBlock, NmodlMechanism, UseIon};
+use oldies_core::{OldiesError, Result, Time, Voltage};
+use std::collections::HashMap;
+
+/// Compiled mechanisms by name
+pub type MechanismLibrary = HashMap<String, CompiledMechanism>;
+
+/// Perturbation of the states used to measure `dx'/dx`
+const STATE_STEP: f64 = 1e-6;
+
+/// Time step (ms) of a KINETIC block solved to steady state
+const STEADY_STATE_DT: f64 = 1e9;
+
+/// Newton iterations of a KINETIC step, and their relative tolerance
+const NEWTON_ITERATIONS: usize = 20;
+const NEWTON_TOLERANCE: f64 = 1e-12;
+
+/// Ion variables and their NEURON defaults
+const ION_DEFAULTS: &[(&str, f64)] = &[
+    ("ena", 50.0), ("ek", -77.0), ("eca", 132.4579), ("nai", 10.0), ("nao", 140.0),
+    ("ki", 54.4), ("ko", 2.5), ("cai", 5e-5), ("cao", 2.0),
+];
+
+/// NEURON's default for ion variable `name`, 0 for other variables
+pub(crate) fn ion_default(name: &str) -> f64 {
+    ION_DEFAULTS.iter().find(|(n, _)| *n == name).map_or(0.0, |(_, x)| *x)
+}
+
+/// Variables set by the simulator, in slot order
+const SIMULATOR_VARS: [&str; 3] = ["v", "celsius", "dt"];
+
+fn error(mechanism: &str, msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::ParseError(format!("NMODL {}: {}", mechanism, msg))
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(f64),
+    Var(usize),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Builtin(fn(f64) -> f64, Box<Expr>),
+    Call(usize, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Exponential,
+    Euler,
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Assign(usize, Expr),
+    Call(usize, Vec<Expr>),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    /// Integrate a DERIVATIVE block; skipped when computing currents
+    Solve(usize, Method),
+    /// Step a KINETIC block, or bring it to steady state when set; the
+    /// step is skipped when computing currents
+    Kinetic(usize, bool),
+}
+
+#[derive(Debug, Clone)]
+struct Function {
+    params: Vec<usize>,
+    /// Slot holding the return value (the function's own name)
+    result: usize,
+    body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+struct Derivative {
+    /// (state slot, derivative slot)
+    states: Vec<(usize, usize)>,
+    body: Vec<Stmt>,
+}
+
+/// `~` statement of a KINETIC block, over the indices of its states
+#[derive(Debug, Clone)]
+struct Reaction {
+    /// (state, stoichiometry) on the left and right sides
+    reactants: Vec<(usize, i32)>,
+    products: Vec<(usize, i32)>,
+    forward: Expr,
+    /// None for a flux `~ x << (f)`
+    backward: Option<Expr>,
+}
+
+#[derive(Debug, Clone)]
+struct Kinetic {
+    /// Slots of the states taking part in the scheme
+    states: Vec<usize>,
+    /// Statements other than reactions, run before the rates are evaluated
+    body: Vec<Stmt>,
+    reactions: Vec<Reaction>,
+    /// CONSERVE: (state, weight) terms and their total
+    conserve: Vec<(Vec<(usize, f64)>, Expr)>,
+    /// COMPARTMENT volume of each state
+    volumes: Vec<Option<Expr>>,
+}
+
+/// Rate expressions of a KINETIC block evaluated for one step
+struct KineticRates {
+    forward: Vec<f64>,
+    backward: Vec<f64>,
+    volumes: Vec<f64>,
+    totals: Vec<f64>,
+}
+
+/// Density mechanism ready to be evaluated by the cable solver
+#[derive(Debug, Clone)]
+pub struct CompiledMechanism {
+    /// SUFFIX
+    pub name: String,
+    /// Parsed NMODL it was compiled from
+    pub model: NmodlMechanism,
+    /// USEION declarations
+    pub ions: Vec<UseIon>,
+    /// Section-wide parameters and their defaults
+    pub parameters: Vec<(String, f64)>,
+    /// STATE variables
+    pub states: Vec<String>,
+    /// Slot of each state
+    state_slots: Vec<usize>,
+    /// Initial value of every slot
+    defaults: Vec<f64>,
+    param_slots: Vec<(usize, String)>,
+    /// STATE and ASSIGNED variables kept per segment
+    segment_slots: Vec<(usize, String)>,
+    current_slots: Vec<usize>,
+    /// Ion currents written, such as `ica`
+    ion_current_slots: Vec<(String, usize)>,
+    initial: Vec<Stmt>,
+    breakpoint: Vec<Stmt>,
+    functions: Vec<Function>,
+    derivatives: Vec<Derivative>,
+    kinetics: Vec<Kinetic>,
+}
+
+// =============================================================================
+// COMPILER
+// =============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Num(f64),
+    Ident(String),
+    Op(&'static str),
+    Newline,
+    Eof,
+}
+
+const OPS: &[&str] = &[
+    "<->", "<<", "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "^", "<", ">", "!", "=", "(",
+    ")", "{", "}", ",", "'", "~",
+];
+
+fn lex(src: &str) -> std::result::Result<Vec<Tok>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut toks = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            toks.push(Tok::Newline);
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if matches!(chars.get(i), Some('e' | 'E')) {
+                let mut j = i + 1;
+                if matches!(chars.get(j), Some('+' | '-')) {
+                    j += 1;
+                }
+                if chars.get(j).is_some_and(|c| c.is_ascii_digit()) {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            toks.push(Tok::Num(text.parse().map_err(|_| format!("invalid number '{}'", text))?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            toks.push(Tok::Ident(chars[start..i].iter().collect()));
+        } else {
+            let op = OPS.iter()
+                .find(|op| op.chars().enumerate().all(|(k, o)| chars.get(i + k) == Some(&o)))
+                .ok_or_else(|| format!("unexpected character '{}'", c))?;
+            i += op.len();
+            toks.push(Tok::Op(op));
+        }
+    }
+    toks.push(Tok::Eof);
+    Ok(toks)
+}
+
+fn builtin(name: &str) -> Option<fn(f64) -> f64> {
+    Some(match name {
+        "exp" => f64::exp,
+        "log" => f64::ln,
+        "log10" => f64::log10,
+        "sqrt" => f64::sqrt,
+        "fabs" => f64::abs,
+        "sin" => f64::sin,
+        "cos" => f64::cos,
+        "tan" => f64::tan,
+        "atan" => f64::atan,
+        "tanh" => f64::tanh,
+        "floor" => f64::floor,
+        "ceil" => f64::ceil,
+        "exprelr" => |x: f64| if x.abs() < 1e-6 { 1.0 - x / 2.0 } else { x / x.exp_m1() },
+        _ => return None,
+    })
+}
+
+#[derive(Default)]
+struct Compiler {
+    defaults: Vec<f64>,
+    globals: HashMap<String, usize>,
+    locals: HashMap<String, usize>,
+    /// Function index and number of parameters
+    functions: HashMap<String, (usize, usize)>,
+    derivatives: HashMap<String, usize>,
+    kinetics: HashMap<String, usize>,
+    /// State -> derivative slot of the DERIVATIVE block being compiled
+    primes: Option<HashMap<String, usize>>,
+    toks: Vec<Tok>,
+    pos: usize,
+}
+
+impl Compiler {
+    fn slot(&mut self, default: f64) -> usize {
+        self.defaults.push(default);
+        self.defaults.len() - 1
+    }
+
+    fn global(&mut self, name: &str, default: f64) -> usize {
+        match self.globals.get(name) {
+            Some(&slot) => slot,
+            None => {
+                let slot = self.slot(default);
+                self.globals.insert(name.to_string(), slot);
+                slot
+            }
+        }
+    }
+
+    fn resolve(&self, name: &str) -> std::result::Result<usize, String> {
+        self.locals.get(name).or_else(|| self.globals.get(name)).copied()
+            .ok_or_else(|| format!("undefined variable '{}'", name))
+    }
+
+    /// Compile the statements of a block with the given local variables
+    fn body(&mut self, lines: &[String], locals: HashMap<String, usize>) -> std::result::Result<Vec<Stmt>, String> {
+        self.toks = lex(&lines.join("\n"))?;
+        self.pos = 0;
+        self.locals = locals;
+        self.statements(false)
+    }
+
+    fn peek(&self) -> &Tok {
+        &self.toks[self.pos]
+    }
+
+    fn advance(&mut self) -> Tok {
+        let tok = self.toks[self.pos].clone();
+        if self.pos < self.toks.len() - 1 {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = matches!(self.peek(), Tok::Op(o) if *o == op);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect(&mut self, op: &str) -> std::result::Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}', found {:?}", op, self.peek()))
+        }
+    }
+
+    fn ident(&mut self) -> std::result::Result<String, String> {
+        match self.advance() {
+            Tok::Ident(name) => Ok(name),
+            tok => Err(format!("expected a name, found {:?}", tok)),
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while *self.peek() == Tok::Newline {
+            self.advance();
+        }
+    }
+
+    fn statements(&mut self, braced: bool) -> std::result::Result<Vec<Stmt>, String> {
+        let mut stmts = vec![];
+        loop {
+            self.skip_newlines();
+            if braced && self.eat("}") {
+                return Ok(stmts);
+            }
+            if *self.peek() == Tok::Eof {
+                return if braced { Err("missing '}'".into()) } else { Ok(stmts) };
+            }
+            if let Some(stmt) = self.statement()? {
+                stmts.push(stmt);
+            }
+            if !matches!(self.peek(), Tok::Newline | Tok::Eof | Tok::Op("}")) {
+                return Err(format!("unexpected {:?} after statement", self.peek()));
+            }
+        }
+    }
+
+    fn braced(&mut self) -> std::result::Result<Vec<Stmt>, String> {
+        self.skip_newlines();
+        self.expect("{")?;
+        self.statements(true)
+    }
+
+    fn statement(&mut self) -> std::result::Result<Option<Stmt>, String> {
+        let word = self.ident()?;
+        match word.as_str() {
+            "LOCAL" => {
+                loop {
+                    let name = self.ident()?;
+                    let slot = self.slot(0.0);
+                    self.locals.insert(name, slot);
+                    if !self.eat(",") {
+                        return Ok(None);
+                    }
+                }
+            }
+            "TABLE" => {
+                while !matches!(self.peek(), Tok::Newline | Tok::Eof) {
+                    self.advance();
+                }
+                Ok(None)
+            }
+            "SOLVE" => {
+                let name = self.ident()?;
+                let kind = self.ident()?;
+                let method = self.ident()?;
+                if let Some(&index) = self.kinetics.get(&name) {
+                    return match (kind.as_str(), method.as_str()) {
+                        ("METHOD", "sparse") => Ok(Some(Stmt::Kinetic(index, false))),
+                        ("STEADYSTATE", "sparse") => Ok(Some(Stmt::Kinetic(index, true))),
+                        _ => Err(format!("KINETIC {} needs METHOD sparse or STEADYSTATE sparse", name)),
+                    };
+                }
+                if kind != "METHOD" {
+                    return Err(format!("SOLVE {} {} is not supported", kind, method));
+                }
+                let method = match method.as_str() {
+                    "cnexp" | "derivimplicit" => Method::Exponential,
+                    "euler" => Method::Euler,
+                    _ => return Err(format!("METHOD {} is not supported", method)),
+                };
+                let index = *self.derivatives.get(&name)
+                    .ok_or_else(|| format!("SOLVE {}: no DERIVATIVE or KINETIC block of that name", name))?;
+                Ok(Some(Stmt::Solve(index, method)))
+            }
+            "if" => {
+                self.expect("(")?;
+                let cond = self.expr()?;
+                self.expect(")")?;
+                let then = self.braced()?;
+                let save = self.pos;
+                self.skip_newlines();
+                let otherwise = if matches!(self.peek(), Tok::Ident(w) if w == "else") {
+                    self.advance();
+                    self.skip_newlines();
+                    if matches!(self.peek(), Tok::Ident(w) if w == "if") {
+                        self.statement()?.into_iter().collect()
+                    } else {
+                        self.braced()?
+                    }
+                } else {
+                    self.pos = save;
+                    vec![]
+                };
+                Ok(Some(Stmt::If(cond, then, otherwise)))
+            }
+            _ => {
+                if self.eat("'") {
+                    let slot = self.primes.as_ref().and_then(|p| p.get(&word)).copied()
+                        .ok_or_else(|| format!("{}' outside a DERIVATIVE block or not a STATE", word))?;
+                    self.expect("=")?;
+                    Ok(Some(Stmt::Assign(slot, self.expr()?)))
+                } else if self.eat("=") {
+                    let slot = self.resolve(&word)?;
+                    Ok(Some(Stmt::Assign(slot, self.expr()?)))
+                } else if self.eat("(") {
+                    let (index, args) = self.call(&word)?;
+                    Ok(Some(Stmt::Call(index, args)))
+                } else {
+                    Err(format!("unsupported statement '{}'", word))
+                }
+            }
+        }
+    }
+
+    /// Arguments of a call to a user function, after its `(`
+    fn call(&mut self, name: &str) -> std::result::Result<(usize, Vec<Expr>), String> {
+        let (index, arity) = *self.functions.get(name)
+            .ok_or_else(|| format!("undefined function '{}'", name))?;
+        let args = self.args()?;
+        if args.len() != arity {
+            return Err(format!("{} takes {} arguments, got {}", name, arity, args.len()));
+        }
+        Ok((index, args))
+    }
+
+    fn args(&mut self) -> std::result::Result<Vec<Expr>, String> {
+        let mut args = vec![];
+        if !self.eat(")") {
+            loop {
+                args.push(self.expr()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(args)
+    }
+
+    fn expr(&mut self) -> std::result::Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> std::result::Result<Expr, String> {
+        const LEVELS: &[&[&str]] = &[&["||"], &["&&"], &["==", "!="], &["<", "<=", ">", ">="], &["+", "-"], &["*", "/"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Tok::Op(op) if LEVELS[level].contains(op) => *op,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> std::result::Result<Expr, String> {
+        if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            let base = self.primary()?;
+            if self.eat("^") {
+                Ok(Expr::Binary("^", Box::new(base), Box::new(self.unary()?)))
+            } else {
+                Ok(base)
+            }
+        }
+    }
+
+    fn primary(&mut self) -> std::result::Result<Expr, String> {
+        match self.advance() {
+            Tok::Num(x) => Ok(Expr::Num(x)),
+            Tok::Op("(") => {
+                let e = self.expr()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Tok::Ident(name) if self.eat("(") => {
+                if let Some(f) = builtin(&name) {
+                    let mut args = self.args()?;
+                    match args.len() {
+                        1 => Ok(Expr::Builtin(f, Box::new(args.remove(0)))),
+                        n => Err(format!("{} takes 1 argument, got {}", name, n)),
+                    }
+                } else if name == "pow" {
+                    let mut args = self.args()?;
+                    if args.len() != 2 {
+                        return Err("pow takes 2 arguments".into());
+                    }
+                    let exponent = args.pop().unwrap();
+                    Ok(Expr::Binary("^", Box::new(args.pop().unwrap()), Box::new(exponent)))
+                } else {
+                    let (index, args) = self.call(&name)?;
+                    Ok(Expr::Call(index, args))
+                }
+            }
+            Tok::Ident(name) => Ok(Expr::Var(self.resolve(&name)?)),
+            tok => Err(format!("unexpected {:?}", tok)),
+        }
+    }
+
+    /// Compile a KINETIC block over the mechanism's `states`: `~`,
+    /// CONSERVE and COMPARTMENT lines, and ordinary statements
+    fn kinetic(&mut self, lines: &[String], states: &[String]) -> std::result::Result<Kinetic, String> {
+        let special = |l: &&String| l.starts_with('~') || l.starts_with("CONSERVE") || l.starts_with("COMPARTMENT");
+        let plain: Vec<String> = lines.iter().filter(|l| !special(l)).cloned().collect();
+        // Compiled first so that its LOCALs are visible to the rates
+        let body = self.body(&plain, HashMap::new())?;
+
+        let mut names: Vec<String> = vec![];
+        let mut index = |name: String| -> std::result::Result<usize, String> {
+            if !states.contains(&name) {
+                return Err(format!("'{}' in a reaction is not a STATE", name));
+            }
+            Ok(names.iter().position(|n| *n == name).unwrap_or_else(|| {
+                names.push(name);
+                names.len() - 1
+            }))
+        };
+        let mut reactions = vec![];
+        let mut conserve = vec![];
+        let mut compartments = vec![];
+        for line in lines.iter().filter(special) {
+            self.toks = lex(line)?;
+            self.pos = 0;
+            if self.eat("~") {
+                let reactants = self.side(&mut index)?;
+                if self.eat("<<") {
+                    let [(x, 1)] = reactants[..] else {
+                        return Err("a flux '<<' needs a single state on its left".into());
+                    };
+                    self.expect("(")?;
+                    let flux = self.expr()?;
+                    self.expect(")")?;
+                    reactions.push(Reaction { reactants: vec![], products: vec![(x, 1)], forward: flux, backward: None });
+                } else {
+                    self.expect("<->")?;
+                    let products = self.side(&mut index)?;
+                    self.expect("(")?;
+                    let forward = self.expr()?;
+                    self.expect(",")?;
+                    let backward = self.expr()?;
+                    self.expect(")")?;
+                    reactions.push(Reaction { reactants, products, forward, backward: Some(backward) });
+                }
+            } else {
+                match self.ident()?.as_str() {
+                    "CONSERVE" => {
+                        let mut terms = vec![];
+                        loop {
+                            let weight = match *self.peek() {
+                                Tok::Num(x) => {
+                                    self.advance();
+                                    self.eat("*");
+                                    x
+                                }
+                                _ => 1.0,
+                            };
+                            terms.push((index(self.ident()?)?, weight));
+                            if !self.eat("+") {
+                                break;
+                            }
+                        }
+                        self.expect("=")?;
+                        conserve.push((terms, self.expr()?));
+                    }
+                    "COMPARTMENT" => {
+                        let volume = self.expr()?;
+                        self.expect("{")?;
+                        let mut members = vec![];
+                        while !self.eat("}") {
+                            members.push(index(self.ident()?)?);
+                        }
+                        compartments.push((volume, members));
+                    }
+                    word => return Err(format!("unsupported statement '{}'", word)),
+                }
+            }
+            if *self.peek() != Tok::Eof {
+                return Err(format!("unexpected {:?} after statement", self.peek()));
+            }
+        }
+
+        let mut volumes = vec![None; names.len()];
+        for (volume, members) in compartments {
+            for i in members {
+                volumes[i] = Some(volume.clone());
+            }
+        }
+        Ok(Kinetic {
+            states: names.iter().map(|n| self.globals[n.as_str()]).collect(),
+            body,
+            reactions,
+            conserve,
+            volumes,
+        })
+    }
+
+    /// One side of a reaction: `a + 2 b`
+    fn side(
+        &mut self,
+        index: &mut impl FnMut(String) -> std::result::Result<usize, String>,
+    ) -> std::result::Result<Vec<(usize, i32)>, String> {
+        let mut terms = vec![];
+        loop {
+            let count = match *self.peek() {
+                Tok::Num(x) if x >= 1.0 && x.fract() == 0.0 => {
+                    self.advance();
+                    x as i32
+                }
+                Tok::Num(x) => return Err(format!("invalid stoichiometry {}", x)),
+                _ => 1,
+            };
+            terms.push((index(self.ident()?)?, count));
+            if !self.eat("+") {
+                return Ok(terms);
+            }
+        }
+    }
+}
+
+/// Names assigned anywhere in `lines` (`x = ...`), used to declare the
+/// variables of top-level LOCAL lines, which the parser skips
+fn assigned_names(lines: &[String]) -> Vec<String> {
+    let toks = lex(&lines.join("\n")).unwrap_or_default();
+    toks.windows(2)
+        .filter_map(|w| match w {
+            [Tok::Ident(name), Tok::Op("=")] => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compile a parsed density mechanism
+pub fn compile(mechanism: &NmodlMechanism) -> Result<CompiledMechanism> {
+    let (suffix, useion, nonspecific) = mechanism.blocks.iter()
+        .find_map(|b| match b {
+            NmodlBlock::Neuron { mechanism_type, suffix, useion, nonspecific_current, .. } => {
+                Some((mechanism_type, suffix, useion, nonspecific_current))
+            }
+            _ => None,
+        })
+        .map(|(kind, suffix, useion, nonspecific)| {
+            if *kind == MechanismType::Suffix {
+                Ok((suffix.clone(), useion, nonspecific))
+            } else {
+                Err(error(suffix, "only density mechanisms (SUFFIX) can be compiled"))
+            }
+        })
+        .ok_or_else(|| error("?", "missing NEURON block"))??;
+    let fail = |msg: String| error(&suffix, msg);
+
+    let mut c = Compiler::default();
+    for name in SIMULATOR_VARS {
+        c.global(name, 0.0);
+    }
+
+    // Section-wide: PARAMETERs and ion variables read
+    let mut param_slots = vec![];
+    let mut parameters = vec![];
+    let mut add_parameter = |c: &mut Compiler, name: &str, default: f64| {
+        if !c.globals.contains_key(name) {
+            param_slots.push((c.global(name, default), name.to_string()));
+            parameters.push((name.to_string(), default));
+        }
+    };
+    for block in &mechanism.blocks {
+        if let NmodlBlock::Parameter(vars) = block {
+            for var in vars {
+                add_parameter(&mut c, &var.name, var.default.unwrap_or(0.0));
+            }
+        }
+    }
+    let written: Vec<&String> = useion.iter().flat_map(|ion| &ion.write).collect();
+    for ion in useion {
+        for name in ion.read.iter().filter(|r| !written.contains(r)) {
+            add_parameter(&mut c, name, ion_default(name));
+        }
+    }
+
+    // Per segment: STATEs, ASSIGNED and ion variables written
+    let mut segment_slots = vec![];
+    let mut states = vec![];
+    for block in &mechanism.blocks {
+        let names: Vec<&String> = match block {
+            NmodlBlock::State(names) => {
+                states.extend(names.iter().cloned());
+                names.iter().collect()
+            }
+            NmodlBlock::Assigned(vars) => vars.iter().map(|v| &v.name).collect(),
+            _ => continue,
+        };
+        for name in names {
+            if !c.globals.contains_key(name.as_str()) {
+                segment_slots.push((c.global(name, ion_default(name)), name.clone()));
+            }
+        }
+    }
+    for name in written.iter().copied().chain(nonspecific) {
+        if !c.globals.contains_key(name.as_str()) {
+            segment_slots.push((c.global(name, 0.0), name.clone()));
+        }
+    }
+    let ion_current_slots: Vec<(String, usize)> = useion.iter()
+        .flat_map(|ion| ion.write.iter().filter(|w| **w == format!("i{}", ion.ion)))
+        .map(|name| (name.clone(), c.globals[name.as_str()]))
+        .collect();
+    let current_slots = ion_current_slots.iter()
+        .map(|(_, slot)| *slot)
+        .chain(nonspecific.iter().map(|name| c.globals[name.as_str()]))
+        .collect();
+
+    // Declare functions and derivative blocks before compiling any body
+    let mut function_blocks = vec![];
+    let mut derivative_blocks = vec![];
+    let mut kinetic_blocks = vec![];
+    let mut bodies: Vec<&Vec<String>> = vec![];
+    for block in &mechanism.blocks {
+        match block {
+            NmodlBlock::Procedure { name, params, body } | NmodlBlock::Function { name, params, body } => {
+                c.functions.insert(name.clone(), (function_blocks.len(), params.len()));
+                function_blocks.push((name, params, body, matches!(block, NmodlBlock::Function { .. })));
+                bodies.push(body);
+            }
+            NmodlBlock::Derivative { name, equations } => {
+                c.derivatives.insert(name.clone(), derivative_blocks.len());
+                derivative_blocks.push(equations);
+                bodies.push(equations);
+            }
+            NmodlBlock::Kinetic { name, reactions } => {
+                c.kinetics.insert(name.clone(), kinetic_blocks.len());
+                kinetic_blocks.push((name, reactions));
+                bodies.push(reactions);
+            }
+            NmodlBlock::Initial(body) | NmodlBlock::Breakpoint(body) => bodies.push(body),
+            _ => {}
+        }
+    }
+    for body in bodies {
+        for name in assigned_names(body) {
+            if !c.functions.contains_key(&name) {
+                c.global(&name, 0.0);
+            }
+        }
+    }
+
+    let mut functions = vec![];
+    for (name, params, body, is_function) in function_blocks {
+        let mut locals = HashMap::new();
+        let param_slots: Vec<usize> = params.iter().map(|p| {
+            let slot = c.slot(0.0);
+            locals.insert(p.clone(), slot);
+            slot
+        }).collect();
+        let result = c.slot(0.0);
+        if is_function {
+            locals.insert(name.clone(), result);
+        }
+        functions.push(Function { params: param_slots, result, body: vec![] });
+        let compiled = c.body(body, locals).map_err(|e| fail(format!("{}: {}", name, e)))?;
+        functions.last_mut().unwrap().body = compiled;
+    }
+
+    let mut derivatives = vec![];
+    for equations in derivative_blocks {
+        let primes: HashMap<String, usize> = states.iter()
+            .map(|s| (s.clone(), c.slot(0.0)))
+            .collect();
+        c.primes = Some(primes.clone());
+        let body = c.body(equations, HashMap::new()).map_err(|e| fail(format!("DERIVATIVE: {}", e)))?;
+        c.primes = None;
+        // Only the states with an equation are integrated
+        let assigned: Vec<usize> = assigned_slots(&body);
+        let states = states.iter()
+            .map(|s| (c.globals[s.as_str()], primes[s]))
+            .filter(|(_, d)| assigned.contains(d))
+            .collect();
+        derivatives.push(Derivative { states, body });
+    }
+
+    let mut kinetics = vec![];
+    for (name, lines) in kinetic_blocks {
+        kinetics.push(c.kinetic(lines, &states).map_err(|e| fail(format!("KINETIC {}: {}", name, e)))?);
+    }
+
+    let mut initial = vec![];
+    let mut breakpoint = vec![];
+    for block in &mechanism.blocks {
+        match block {
+            NmodlBlock::Initial(body) => {
+                initial = c.body(body, HashMap::new()).map_err(|e| fail(format!("INITIAL: {}", e)))?;
+            }
+            NmodlBlock::Breakpoint(body) => {
+                breakpoint = c.body(body, HashMap::new()).map_err(|e| fail(format!("BREAKPOINT: {}", e)))?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(CompiledMechanism {
+        model: mechanism.clone(),
+        name: suffix,
+        ions: useion.clone(),
+        parameters,
+        state_slots: states.iter().map(|s| c.globals[s.as_str()]).collect(),
+        states,
+        defaults: c.defaults,
+        param_slots,
+        segment_slots,
+        current_slots,
+        ion_current_slots,
+        initial,
+        breakpoint,
+        functions,
+        derivatives,
+        kinetics,
+    })
+}
+
+/// Slots assigned by `stmts`, including inside conditionals
+fn assigned_slots(stmts: &[Stmt]) -> Vec<usize> {
+    let mut slots = vec![];
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(slot, _) => slots.push(*slot),
+            Stmt::If(_, then, otherwise) => {
+                slots.extend(assigned_slots(then));
+                slots.extend(assigned_slots(otherwise));
+            }
+            _ => {}
+        }
+    }
+    slots
+}
+
+/// Mass-action rate `k * prod x^s` of one side of a reaction; adds its
+/// gradient, times `sign`, to `grad`
+fn mass_action(side: &[(usize, i32)], x: &[f64], k: f64, sign: f64, grad: &mut [f64]) -> f64 {
+    for (j, &(i, s)) in side.iter().enumerate() {
+        let others: f64 = side.iter().enumerate()
+            .filter(|(m, _)| *m != j)
+            .map(|(_, &(i, s))| x[i].powi(s))
+            .product();
+        grad[i] += sign * k * s as f64 * x[i].powi(s - 1) * others;
+    }
+    k * side.iter().map(|&(i, s)| x[i].powi(s)).product::<f64>()
+}
+
+/// Rate of change of the states of a KINETIC block at `x`, with its Jacobian
+fn kinetic_derivatives(block: &Kinetic, rates: &KineticRates, x: &[f64]) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = x.len();
+    let mut f = vec![0.0; n];
+    let mut jac = vec![vec![0.0; n]; n];
+    for (r, reaction) in block.reactions.iter().enumerate() {
+        let mut grad = vec![0.0; n];
+        let flux = mass_action(&reaction.reactants, x, rates.forward[r], 1.0, &mut grad)
+            - mass_action(&reaction.products, x, rates.backward[r], -1.0, &mut grad);
+        let sides = [(&reaction.reactants, -1.0), (&reaction.products, 1.0)];
+        for (side, sign) in sides {
+            for &(i, s) in side {
+                let scale = sign * s as f64 / rates.volumes[i];
+                f[i] += scale * flux;
+                for (d, g) in jac[i].iter_mut().zip(&grad) {
+                    *d += scale * g;
+                }
+            }
+        }
+    }
+    (f, jac)
+}
+
+// =============================================================================
+// EVALUATION
+// =============================================================================
+
+impl CompiledMechanism {
+    /// A new instance with default parameters, ready to insert in a section
+    pub fn instance(&self) -> InsertedMechanism {
+        InsertedMechanism {
+            name: self.name.clone(),
+            parameters: self.parameters.iter().cloned().collect(),
+            state: HashMap::new(),
+            segment_parameters: HashMap::new(),
+        }
+    }
+
+    /// Whether the per-segment variables of `mech` need initializing
+    pub fn is_stale(&self, mech: &InsertedMechanism, nseg: usize) -> bool {
+        self.segment_slots.iter().any(|(_, name)| mech.state.get(name).is_none_or(|x| x.len() != nseg))
+    }
+
+    /// Variables of segment `k`
+    fn frame(&self, mech: &InsertedMechanism, k: usize, v: Voltage, celsius: f64, dt: Time) -> Vec<f64> {
+        let mut frame = self.defaults.clone();
+        frame[0] = v;
+        frame[1] = celsius;
+        frame[2] = dt;
+        for (slot, name) in &self.param_slots {
+            let segment = mech.state.get(name).and_then(|x| x.get(k)).copied();
+            if let Some(x) = segment.or_else(|| mech.parameter(name, k)) {
+                frame[*slot] = x;
+            }
+        }
+        for (slot, name) in &self.segment_slots {
+            if let Some(&x) = mech.state.get(name).and_then(|x| x.get(k)) {
+                frame[*slot] = x;
+            }
+        }
+        frame
+    }
+
+    fn store(&self, mech: &mut InsertedMechanism, k: usize, nseg: usize, frame: &[f64]) {
+        for (slot, name) in &self.segment_slots {
+            let values = mech.state.entry(name.clone()).or_default();
+            values.resize(nseg, 0.0);
+            values[k] = frame[*slot];
+        }
+    }
+
+    fn eval(&self, e: &Expr, frame: &mut [f64]) -> f64 {
+        let truth = |c: bool| if c { 1.0 } else { 0.0 };
+        match e {
+            Expr::Num(x) => *x,
+            Expr::Var(slot) => frame[*slot],
+            Expr::Neg(a) => -self.eval(a, frame),
+            Expr::Not(a) => truth(self.eval(a, frame) == 0.0),
+            Expr::Builtin(f, a) => f(self.eval(a, frame)),
+            Expr::Call(index, args) => self.call(*index, args, frame),
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(a, frame);
+                match *op {
+                    "&&" if a == 0.0 => return 0.0,
+                    "||" if a != 0.0 => return 1.0,
+                    _ => {}
+                }
+                let b = self.eval(b, frame);
+                match *op {
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    "/" => a / b,
+                    "^" => a.powf(b),
+                    "<" => truth(a < b),
+                    "<=" => truth(a <= b),
+                    ">" => truth(a > b),
+                    ">=" => truth(a >= b),
+                    "==" => truth(a == b),
+                    "!=" => truth(a != b),
+                    _ => truth(b != 0.0),
+                }
+            }
+        }
+    }
+
+    fn call(&self, index: usize, args: &[Expr], frame: &mut [f64]) -> f64 {
+        let f = &self.functions[index];
+        let values: Vec<f64> = args.iter().map(|a| self.eval(a, frame)).collect();
+        for (slot, x) in f.params.iter().zip(values) {
+            frame[*slot] = x;
+        }
+        self.exec(&f.body, frame, false);
+        frame[f.result]
+    }
+
+    /// Run statements; SOLVE is performed only when `solve` is set
+    fn exec(&self, stmts: &[Stmt], frame: &mut [f64], solve: bool) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Assign(slot, e) => frame[*slot] = self.eval(e, frame),
+                Stmt::Call(index, args) => {
+                    self.call(*index, args, frame);
+                }
+                Stmt::If(cond, then, otherwise) => {
+                    let branch = if self.eval(cond, frame) != 0.0 { then } else { otherwise };
+                    self.exec(branch, frame, solve);
+                }
+                Stmt::Solve(index, method) => {
+                    if solve {
+                        self.integrate(&self.derivatives[*index], *method, frame);
+                    }
+                }
+                Stmt::Kinetic(index, steady) => {
+                    if solve || *steady {
+                        self.solve_kinetic(&self.kinetics[*index], frame, *steady);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run the statements of a KINETIC block and evaluate its rates
+    fn kinetic_rates(&self, block: &Kinetic, frame: &mut [f64]) -> KineticRates {
+        self.exec(&block.body, frame, false);
+        let mut eval = |e: &Expr| self.eval(e, frame);
+        KineticRates {
+            forward: block.reactions.iter().map(|r| eval(&r.forward)).collect(),
+            backward: block.reactions.iter().map(|r| r.backward.as_ref().map_or(0.0, &mut eval)).collect(),
+            volumes: block.volumes.iter().map(|v| v.as_ref().map_or(1.0, &mut eval)).collect(),
+            totals: block.conserve.iter().map(|(_, total)| eval(total)).collect(),
+        }
+    }
+
+    /// Backward Euler step of a KINETIC block, by Newton iteration
+    fn solve_kinetic(&self, block: &Kinetic, frame: &mut [f64], steady: bool) {
+        let dt = if steady { STEADY_STATE_DT } else { frame[2] };
+        let rates = self.kinetic_rates(block, frame);
+        let old: Vec<f64> = block.states.iter().map(|&s| frame[s]).collect();
+        let n = old.len();
+        let mut x = old.clone();
+        for _ in 0..NEWTON_ITERATIONS {
+            // (I - dt J) dx = dt f(x) - (x - old)
+            let (f, jac) = kinetic_derivatives(block, &rates, &x);
+            let mut b: Vec<f64> = (0..n).map(|i| dt * f[i] - (x[i] - old[i])).collect();
+            let mut a: Vec<Vec<f64>> = jac.iter().enumerate()
+                .map(|(i, row)| row.iter().enumerate()
+                    .map(|(j, d)| if i == j { 1.0 } else { 0.0 } - dt * d)
+                    .collect())
+                .collect();
+            for ((terms, _), total) in block.conserve.iter().zip(&rates.totals) {
+                let row = terms[terms.len() - 1].0;
+                a[row] = vec![0.0; n];
+                b[row] = *total;
+                for &(i, w) in terms {
+                    a[row][i] += w;
+                    b[row] -= w * x[i];
+                }
+            }
+            solve_dense(&mut a, &mut b);
+            let mut converged = true;
+            for (x, dx) in x.iter_mut().zip(&b) {
+                *x += dx;
+                converged &= dx.abs() <= NEWTON_TOLERANCE * (1.0 + x.abs());
+            }
+            if converged {
+                break;
+            }
+        }
+        for (&slot, x) in block.states.iter().zip(x) {
+            frame[slot] = x;
+        }
+    }
+
+    fn integrate(&self, block: &Derivative, method: Method, frame: &mut [f64]) {
+        let dt = frame[2];
+        let mut perturbed = frame.to_vec();
+        self.exec(&block.body, frame, false);
+        if method == Method::Euler {
+            for &(x, dx) in &block.states {
+                frame[x] += dt * frame[dx];
+            }
+            return;
+        }
+        for &(x, _) in &block.states {
+            perturbed[x] += STATE_STEP;
+        }
+        self.exec(&block.body, &mut perturbed, false);
+        for &(x, dx) in &block.states {
+            let slope = (perturbed[dx] - frame[dx]) / STATE_STEP;
+            frame[x] += if (slope * dt).abs() < 1e-9 {
+                dt * frame[dx]
+            } else {
+                (slope * dt).exp_m1() / slope * frame[dx]
+            };
+        }
+    }
+
+    /// Outward current density (mA/cm^2) of segment `k` at `v`
+    pub fn current(&self, mech: &InsertedMechanism, k: usize, v: Voltage, celsius: f64) -> f64 {
+        let mut frame = self.frame(mech, k, v, celsius, 0.0);
+        self.exec(&self.breakpoint, &mut frame, false);
+        self.current_slots.iter().map(|&s| frame[s]).sum()
+    }
+
+    /// Ion currents written (mA/cm^2, outward) in segment `k` at `v`
+    pub fn ion_currents(&self, mech: &InsertedMechanism, k: usize, v: Voltage, celsius: f64) -> Vec<(&str, f64)> {
+        if self.ion_current_slots.is_empty() {
+            return vec![];
+        }
+        let mut frame = self.frame(mech, k, v, celsius, 0.0);
+        self.exec(&self.breakpoint, &mut frame, false);
+        self.ion_current_slots.iter().map(|(name, slot)| (name.as_str(), frame[*slot])).collect()
+    }
+
+    /// Time derivative of each state of segment `k` at `v`, from the
+    /// DERIVATIVE and KINETIC blocks solved in BREAKPOINT, with its partial
+    /// derivative with respect to the state
+    pub fn rates(&self, mech: &InsertedMechanism, k: usize, v: Voltage, celsius: f64) -> Vec<(f64, f64)> {
+        let frame = self.frame(mech, k, v, celsius, 0.0);
+        let mut rates = vec![(0.0, 0.0); self.states.len()];
+        let position = |x: usize| self.state_slots.iter().position(|&slot| slot == x).unwrap();
+        for stmt in &self.breakpoint {
+            match stmt {
+                Stmt::Solve(index, _) => {
+                    let block = &self.derivatives[*index];
+                    let (mut f0, mut f1) = (frame.clone(), frame.clone());
+                    for &(x, _) in &block.states {
+                        f1[x] += STATE_STEP;
+                    }
+                    self.exec(&block.body, &mut f0, false);
+                    self.exec(&block.body, &mut f1, false);
+                    for &(x, dx) in &block.states {
+                        rates[position(x)] = (f0[dx], (f1[dx] - f0[dx]) / STATE_STEP);
+                    }
+                }
+                Stmt::Kinetic(index, _) => {
+                    let block = &self.kinetics[*index];
+                    let coefficients = self.kinetic_rates(block, &mut frame.clone());
+                    let x: Vec<f64> = block.states.iter().map(|&s| frame[s]).collect();
+                    let (f, jac) = kinetic_derivatives(block, &coefficients, &x);
+                    for (i, &slot) in block.states.iter().enumerate() {
+                        rates[position(slot)] = (f[i], jac[i][i]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        rates
+    }
+
+    /// Run INITIAL in every segment
+    pub fn initialize(&self, mech: &mut InsertedMechanism, v: &[Voltage], celsius: f64) {
+        // Ion variables supplied by the cable solver stay
+        mech.state.retain(|name, _| self.param_slots.iter().any(|(_, p)| p == name));
+        for (k, &v_k) in v.iter().enumerate() {
+            let mut frame = self.frame(mech, k, v_k, celsius, 0.0);
+            self.exec(&self.initial, &mut frame, false);
+            self.exec(&self.breakpoint, &mut frame, false);
+            self.store(mech, k, v.len(), &frame);
+        }
+    }
+
+    /// Integrate the states of every segment over `dt` at `v`
+    pub fn advance(&self, mech: &mut InsertedMechanism, v: &[Voltage], dt: Time, celsius: f64) {
+        for (k, &v_k) in v.iter().enumerate() {
+            let mut frame = self.frame(mech, k, v_k, celsius, dt);
+            self.exec(&self.breakpoint, &mut frame, true);
+            self.store(mech, k, v.len(), &frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mechanisms, nmodl, NeuronCell, NeuronSimulation};
+
+    /// Leak current with an inactivating potassium conductance
+    const KLEAK_MOD: &str = r#"
+NEURON {
+    SUFFIX kleak
+    USEION k READ ek WRITE ik
+    NONSPECIFIC_CURRENT il
+    RANGE gk, gl, el
+}
+PARAMETER {
+    gk = 0.001 (S/cm2)
+    gl = 0.0001 (S/cm2)
+    el = -70 (mV)
+}
+STATE { h }
+ASSIGNED { v (mV) ek (mV) ik (mA/cm2) il (mA/cm2) hinf tau (ms) }
+LOCAL q
+BREAKPOINT {
+    SOLVE states METHOD euler
+    ik = gk*h*(v - ek)
+    il = gl*(v - el)
+}
+INITIAL {
+    h = 1
+}
+DERIVATIVE states {
+    q = boltz(v, -50, 5)
+    if (q > 0.5) { tau = 10 } else { tau = 100 }
+    h' = (q - h)/tau
+}
+FUNCTION boltz(x, mid, k) {
+    boltz = 1/(1 + exp((x - mid)/k))
+}
+"#;
+
+    /// Potassium channel as a five-state Markov scheme equivalent to n^4
+    const KMARKOV_MOD: &str = r#"
+NEURON {
+    SUFFIX kmarkov
+    USEION k READ ek WRITE ik
+    RANGE gbar
+}
+PARAMETER { gbar = 0.036 (S/cm2) }
+STATE { c0 c1 c2 c3 o }
+ASSIGNED { v (mV) ek (mV) ik (mA/cm2) a b }
+BREAKPOINT {
+    SOLVE kin METHOD sparse
+    ik = gbar*o*(v - ek)
+}
+INITIAL {
+    SOLVE kin STEADYSTATE sparse
+}
+KINETIC kin {
+    a = 0.01*vtrap(-(v + 55), 10)
+    b = 0.125*exp(-(v + 65)/80)
+    ~ c0 <-> c1 (4*a, b)
+    ~ c1 <-> c2 (3*a, 2*b)
+    ~ c2 <-> c3 (2*a, 3*b)
+    ~ c3 <-> o (a, 4*b)
+    CONSERVE c0 + c1 + c2 + c3 + o = 1
+}
+FUNCTION vtrap(x, y) {
+    vtrap = x/(exp(x/y) - 1)
+}
+"#;
+
+    /// Calcium buffering: `ca + buf <-> cabuf` with a constant influx
+    const BUFFER_MOD: &str = r#"
+NEURON {
+    SUFFIX buffer
+    RANGE kon, koff, influx, total
+}
+PARAMETER { kon = 100 koff = 0.1 influx = 1e-4 total = 0.05 vol = 2 }
+STATE { ca buf cabuf }
+INITIAL {
+    ca = 5e-5
+    buf = total
+    cabuf = 0
+}
+BREAKPOINT { SOLVE kin METHOD sparse }
+KINETIC kin {
+    COMPARTMENT vol { ca buf cabuf }
+    ~ ca + buf <-> cabuf (kon, koff)
+    ~ ca << (influx)
+}
+"#;
+
+    fn soma(mechanism: crate::InsertedMechanism) -> NeuronCell {
+        let mut cell = NeuronCell::new("cell");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanism);
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 2.0, 20.0, 0.2));
+        cell
+    }
+
+    #[test]
+    fn test_compiled_hh_matches_builtin() {
+        let hh = compile(&nmodl::parse(nmodl::HH_MOD).unwrap()).unwrap();
+        assert_eq!(hh.states, ["m", "h", "n"]);
+        let instance = hh.instance();
+        assert_eq!(instance.parameters["ena"], 50.0);
+
+        let mut compiled = NeuronSimulation::new();
+        compiled.mechanisms.insert("hh".into(), hh);
+        compiled.add_cell(soma(instance));
+        let mut builtin = NeuronSimulation::new();
+        builtin.add_cell(soma(mechanisms::hh()));
+        for sim in [&mut compiled, &mut builtin] {
+            sim.tstop = 20.0;
+            sim.record_v("v", 0, "soma", 0.5);
+            sim.finitialize(-65.0).unwrap();
+            sim.run().unwrap();
+        }
+        let (a, b) = (&compiled.recordings["v"], &builtin.recordings["v"]);
+        assert!(a.iter().any(|&v| v > 0.0));
+        let error = a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f64::max);
+        assert!(error < 1e-4, "max difference {} mV", error);
+        assert!(compiled.cells[0].sections["soma"].mechanisms[0].state["gna"][0] > 0.0);
+    }
+
+    #[test]
+    fn test_temperature_dependence() {
+        // A PARAMETER celsius, as many mod files declare, still reads the
+        // simulation's temperature
+        let text = nmodl::HH_MOD
+            .replace("    celsius (degC)\n", "")
+            .replace("    el = -54.3 (mV)", "    el = -54.3 (mV)\n    celsius = 22 (degC)");
+        let hh = compile(&nmodl::parse(&text).unwrap()).unwrap();
+        let mech = hh.instance();
+        assert!(!mech.parameters.contains_key("celsius"));
+        let (cold, warm) = (hh.rates(&mech, 0, -50.0, 6.3), hh.rates(&mech, 0, -50.0, 36.3));
+        // Three times faster every 10 C
+        for ((rate0, jac0), (rate1, jac1)) in cold.iter().zip(&warm) {
+            assert!((rate1 / rate0 - 27.0).abs() < 1e-9, "q10 ratio {}", rate1 / rate0);
+            assert!((jac1 / jac0 - 27.0).abs() < 1e-6, "q10 ratio {}", jac1 / jac0);
+        }
+
+        // Compiled and built-in HH agree away from 6.3 C, and both run faster
+        let mut compiled = NeuronSimulation::new();
+        compiled.mechanisms.insert("hh".into(), hh);
+        compiled.add_cell(soma(mech));
+        let mut builtin = NeuronSimulation::new();
+        builtin.add_cell(soma(mechanisms::hh()));
+        let mut cold = NeuronSimulation::new();
+        cold.add_cell(soma(mechanisms::hh()));
+        for sim in [&mut compiled, &mut builtin, &mut cold] {
+            sim.tstop = 20.0;
+            sim.record_v("v", 0, "soma", 0.5);
+        }
+        compiled.celsius = 16.3;
+        builtin.celsius = 16.3;
+        for sim in [&mut compiled, &mut builtin, &mut cold] {
+            sim.finitialize(-65.0).unwrap();
+            sim.run().unwrap();
+        }
+        let (a, b) = (&compiled.recordings["v"], &builtin.recordings["v"]);
+        let error = a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f64::max);
+        assert!(error < 1e-4, "max difference {} mV", error);
+        let peak_time = |v: &[f64]| v.iter().enumerate().max_by(|x, y| x.1.total_cmp(y.1)).unwrap().0;
+        assert!(peak_time(b) < peak_time(&cold.recordings["v"]));
+    }
+
+    #[test]
+    fn test_custom_mechanism() {
+        let kleak = compile(&nmodl::parse(KLEAK_MOD).unwrap()).unwrap();
+        let mut mech = kleak.instance();
+        assert_eq!(mech.parameters.len(), 4);
+        kleak.initialize(&mut mech, &[-65.0], 6.3);
+        assert_eq!(mech.state["h"], [1.0]);
+        let expected = 0.001 * (-65.0 + 77.0) + 0.0001 * (-65.0 + 70.0);
+        assert!((kleak.current(&mech, 0, -65.0, 6.3) - expected).abs() < 1e-12);
+
+        // q = 0.95 at -65 mV: h relaxes with tau = 10 ms
+        kleak.advance(&mut mech, &[-65.0], 0.1, 6.3);
+        let q = 1.0 / (1.0 + (-15.0f64 / 5.0).exp());
+        assert!((mech.state["h"][0] - (1.0 + 0.1 * (q - 1.0) / 10.0)).abs() < 1e-12);
+        assert_eq!(mech.state["tau"], [10.0]);
+    }
+
+    #[test]
+    fn test_kinetic_markov_scheme() {
+        let k = compile(&nmodl::parse(KMARKOV_MOD).unwrap()).unwrap();
+        let mut mech = k.instance();
+        // STEADYSTATE gives the binomial occupancies of four n gates
+        k.initialize(&mut mech, &[-65.0], 6.3);
+        let a = 0.01 * 10.0 / ((1.0f64).exp() - 1.0);
+        let n = a / (a + 0.125);
+        assert!((mech.state["o"][0] - n.powi(4)).abs() < 1e-8);
+        assert!((mech.state["c0"][0] - (1.0 - n).powi(4)).abs() < 1e-8);
+
+        // The occupancies keep summing to one through a depolarization, and
+        // the rates seen by CVODE vanish at steady state
+        let total = |mech: &InsertedMechanism| ["c0", "c1", "c2", "c3", "o"].iter().map(|s| mech.state[*s][0]).sum::<f64>();
+        assert!(k.rates(&mech, 0, -65.0, 6.3).iter().all(|(rate, _)| rate.abs() < 1e-9));
+        for _ in 0..2000 {
+            k.advance(&mut mech, &[0.0], 0.025, 6.3);
+        }
+        assert!((total(&mech) - 1.0).abs() < 1e-12);
+        let a = 0.01 * 55.0 / (1.0 - (-5.5f64).exp());
+        let n = a / (a + 0.125 * (-65.0f64 / 80.0).exp());
+        assert!((mech.state["o"][0] - n.powi(4)).abs() < 1e-3, "o = {}", mech.state["o"][0]);
+
+        // Runs in the cable solver next to the built-in sodium channel
+        let mut sim = NeuronSimulation::new();
+        sim.mechanisms.insert("kmarkov".into(), k);
+        let mut hh = mechanisms::hh();
+        hh.parameters.insert("gkbar".into(), 0.0);
+        let mut cell = soma(sim.mechanisms["kmarkov"].instance());
+        cell.sections.get_mut("soma").unwrap().insert(hh);
+        sim.add_cell(cell);
+        sim.tstop = 20.0;
+        sim.record_v("v", 0, "soma", 0.5);
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+        let v = &sim.recordings["v"];
+        assert!(v.iter().any(|&v| v > 0.0));
+        assert!(v.last().unwrap() < &-50.0);
+    }
+
+    #[test]
+    fn test_kinetic_binding() {
+        let buffer = compile(&nmodl::parse(BUFFER_MOD).unwrap()).unwrap();
+        let mut mech = buffer.instance();
+        buffer.initialize(&mut mech, &[-65.0], 6.3);
+        let state = |mech: &InsertedMechanism, s: &str| mech.state[s][0];
+        for _ in 0..1000 {
+            buffer.advance(&mut mech, &[-65.0], 0.1, 6.3);
+        }
+        // Buffer is conserved; calcium gains the influx over the volume
+        let (ca, buf, cabuf) = (state(&mech, "ca"), state(&mech, "buf"), state(&mech, "cabuf"));
+        assert!((buf + cabuf - 0.05).abs() < 1e-12);
+        assert!((ca + cabuf - (5e-5 + 1e-4 * 100.0 / 2.0)).abs() < 1e-10);
+        assert!(cabuf > 0.0 && buf < 0.05);
+
+        // A large step lands on the binding equilibrium
+        mech.parameters.insert("influx".into(), 0.0);
+        buffer.advance(&mut mech, &[-65.0], 1e6, 6.3);
+        let (ca, buf, cabuf) = (state(&mech, "ca"), state(&mech, "buf"), state(&mech, "cabuf"));
+        assert!((100.0 * ca * buf - 0.1 * cabuf).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert!(compile(&nmodl::parse(nmodl::EXPSYN_MOD).unwrap()).is_err());
+        let undefined = KLEAK_MOD.replace("il = gl*(v - el)", "il = gx*(v - el)");
+        let err = compile(&nmodl::parse(&undefined).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("gx"), "{}", err);
+        let solve = KLEAK_MOD.replace("SOLVE states", "SOLVE other");
+        assert!(compile(&nmodl::parse(&solve).unwrap()).is_err());
+        let method = KMARKOV_MOD.replace("METHOD sparse", "METHOD cnexp");
+        assert!(compile(&nmodl::parse(&method).unwrap()).is_err());
+        let species = KMARKOV_MOD.replace("c3 <-> o (a", "c3 <-> x (a");
+        let err = compile(&nmodl::parse(&species).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("'x'"), "{}", err);
+    }
+}
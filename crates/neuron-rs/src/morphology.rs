@@ -0,0 +1,710 @@
+//! Morphology import
+//!
+//! Reconstructions, read from SWC or Neurolucida ASC files, are trees of
+//! points with a position, diameter and type. They are cut into
+//! unbranched sections as NEURON's Import3D does: a section runs from a
+//! root or branch point to the next branch point, tip or change of type,
+//! and is named after its type (`soma`, `axon`, `dend`, `apic`) with an
+//! index. Each section keeps its points in `pt3d`, its path length as
+//! `length` and its length-weighted mean diameter as `diam`, and gets an
+//! odd `nseg` from the d_lambda rule.
+//!
+//! A soma given as a single point, as NeuroMorpho.Org's three-point
+//! cylinder or as ASC contours becomes one cylinder as long as it is wide,
+//! whose area is that of the sphere. Its children attach to its middle.
+//!
+//! Reconstructions often need cleaning up before they are simulated, as
+//! Import3D reports: [`validate`] lists points without diameter, repeated
+//! points, sections of no length, trees apart from the cell and `nseg`
+//! far from the d_lambda rule, and [`repair`] fixes what it can.
+//! [`geom_nseg`] applies the d_lambda rule to a whole cell.
+
+use crate::{distance3d, NeuronCell, Point3d, Section};
+use oldies_core::{OldiesError, Result};
+
+/// Largest segment length, as a fraction of the AC length constant
+pub const D_LAMBDA: f64 = 0.1;
+
+/// Frequency of the AC length constant (Hz)
+pub const D_LAMBDA_FREQ: f64 = 100.0;
+
+/// Odd number of segments no longer than `d_lambda` AC length constants
+/// of `sec` at `freq`
+pub fn d_lambda_nseg(sec: &Section, d_lambda: f64, freq: f64) -> usize {
+    let lambda = 1e5 * (sec.diam / (4.0 * std::f64::consts::PI * freq * sec.ra * sec.cm)).sqrt();
+    ((sec.length / (d_lambda * lambda) + 0.9) / 2.0) as usize * 2 + 1
+}
+
+/// A reconstructed point
+#[derive(Debug, Clone)]
+struct Sample {
+    kind: &'static str,
+    point: Point3d,
+    /// Index of the parent sample
+    parent: Option<usize>,
+}
+
+/// Section name prefix of an SWC type
+fn swc_kind(kind: f64) -> &'static str {
+    match kind as i64 {
+        1 => "soma",
+        2 => "axon",
+        4 => "apic",
+        _ => "dend",
+    }
+}
+
+fn parse_swc(content: &str) -> Result<Vec<Sample>> {
+    let mut ids = std::collections::HashMap::new();
+    let mut rows = vec![];
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<f64> = line.split_whitespace()
+            .map(|f| f.parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| OldiesError::ParseError(format!("SWC line {}: expected numbers", n + 1)))?;
+        if fields.len() < 7 {
+            return Err(OldiesError::ParseError(format!(
+                "SWC line {}: expected id, type, x, y, z, radius and parent", n + 1
+            )));
+        }
+        ids.insert(fields[0] as i64, rows.len());
+        rows.push(fields);
+    }
+
+    rows.iter().map(|f| {
+        let parent = match f[6] as i64 {
+            id if id < 0 => None,
+            id => Some(*ids.get(&id).ok_or_else(|| {
+                OldiesError::ParseError(format!("SWC point {} has unknown parent {}", f[0], id))
+            })?),
+        };
+        Ok(Sample {
+            kind: swc_kind(f[1]),
+            point: Point3d { x: f[2], y: f[3], z: f[4], diam: 2.0 * f[5] },
+            parent,
+        })
+    }).collect()
+}
+
+/// Neurolucida S-expression
+#[derive(Debug, Clone, PartialEq)]
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+    /// `<( ... )>`
+    Spine,
+    /// `|` between sibling branches
+    Bar,
+}
+
+fn parse_sexps(chars: &mut std::iter::Peekable<std::str::Chars>, close: Option<char>) -> Result<Vec<Sexp>> {
+    let mut items = vec![];
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() || c == ',' => {}
+            ';' => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '(' => items.push(Sexp::List(parse_sexps(chars, Some(')'))?)),
+            '<' => {
+                parse_sexps(chars, Some('>'))?;
+                items.push(Sexp::Spine);
+            }
+            '|' => items.push(Sexp::Bar),
+            ')' | '>' if close == Some(c) => return Ok(items),
+            ')' | '>' => return Err(OldiesError::ParseError(format!("ASC: unexpected '{}'", c))),
+            '"' => {
+                let mut text = String::new();
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    text.push(c);
+                }
+                items.push(Sexp::Atom(text));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !"()<>|;,\"".contains(c)) {
+                    word.push(c);
+                }
+                items.push(Sexp::Atom(word));
+            }
+        }
+    }
+    match close {
+        Some(c) => Err(OldiesError::ParseError(format!("ASC: missing '{}'", c))),
+        None => Ok(items),
+    }
+}
+
+/// `(x y z d)`, with the diameter optional
+fn asc_point(items: &[Sexp]) -> Option<Point3d> {
+    let numbers: Vec<f64> = items.iter()
+        .map_while(|item| match item {
+            Sexp::Atom(a) => a.parse().ok(),
+            _ => None,
+        })
+        .collect();
+    match numbers[..] {
+        [x, y, z] => Some(Point3d { x, y, z, diam: 0.0 }),
+        [x, y, z, diam, ..] => Some(Point3d { x, y, z, diam }),
+        _ => None,
+    }
+}
+
+/// Type of an ASC object from its `(Dendrite)`-style property or
+/// `"CellBody"` name
+fn asc_kind(items: &[Sexp]) -> Option<&'static str> {
+    items.iter().find_map(|item| {
+        let name = match item {
+            Sexp::Atom(a) => a.as_str(),
+            Sexp::List(list) => match &list[..] {
+                [Sexp::Atom(a)] => a.as_str(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        match name {
+            "CellBody" => Some("soma"),
+            "Dendrite" => Some("dend"),
+            "Apical" => Some("apic"),
+            "Axon" => Some("axon"),
+            _ => None,
+        }
+    })
+}
+
+/// Append the points and branches of a tree growing from `parent`
+fn asc_tree(items: &[Sexp], kind: &'static str, parent: Option<usize>, samples: &mut Vec<Sample>) {
+    let mut last = parent;
+    for item in items {
+        let Sexp::List(list) = item else { continue };
+        if let Some(point) = asc_point(list) {
+            samples.push(Sample { kind, point, parent: last });
+            last = Some(samples.len() - 1);
+        } else if matches!(list.first(), Some(Sexp::List(_))) {
+            // Sibling branches separated by '|'
+            for branch in list.split(|item| *item == Sexp::Bar) {
+                asc_tree(branch, kind, last, samples);
+            }
+        }
+    }
+}
+
+fn parse_asc(content: &str) -> Result<Vec<Sample>> {
+    let objects = parse_sexps(&mut content.chars().peekable(), None)?;
+    let objects: Vec<(&'static str, &[Sexp])> = objects.iter()
+        .filter_map(|object| match object {
+            Sexp::List(items) => asc_kind(items).map(|kind| (kind, &items[..])),
+            _ => None,
+        })
+        .collect();
+
+    // All cell body contours make one soma at their centroid
+    let contour: Vec<Point3d> = objects.iter()
+        .filter(|(kind, _)| *kind == "soma")
+        .flat_map(|(_, items)| items.iter().filter_map(|item| match item {
+            Sexp::List(list) => asc_point(list),
+            _ => None,
+        }))
+        .collect();
+    let mut samples = vec![];
+    if !contour.is_empty() {
+        let n = contour.len() as f64;
+        let c = Point3d {
+            x: contour.iter().map(|p| p.x).sum::<f64>() / n,
+            y: contour.iter().map(|p| p.y).sum::<f64>() / n,
+            z: contour.iter().map(|p| p.z).sum::<f64>() / n,
+            diam: 0.0,
+        };
+        let diam = 2.0 * contour.iter().map(|p| distance3d(p, &c)).sum::<f64>() / n;
+        samples.push(Sample { kind: "soma", point: Point3d { diam, ..c }, parent: None });
+    }
+    let soma = if samples.is_empty() { None } else { Some(0) };
+    for (kind, items) in objects.iter().filter(|(kind, _)| *kind != "soma") {
+        asc_tree(items, kind, soma, &mut samples);
+    }
+    Ok(samples)
+}
+
+/// Where a section grows from
+struct Attachment {
+    section: String,
+    /// Sample the section grows from
+    sample: usize,
+    /// Location on the parent section
+    loc: f64,
+    /// The parent is a spherical soma
+    sphere: bool,
+}
+
+/// Build a cell from an SWC reconstruction
+/// (`id type x y z radius parent` per line, `#` comments)
+pub fn from_swc(content: &str) -> Result<NeuronCell> {
+    build(&parse_swc(content)?)
+}
+
+/// Build a cell from a Neurolucida ASC reconstruction. Cell body
+/// contours make the soma; dendrite, apical and axon trees make the rest.
+/// Markers, spines and other objects are ignored.
+pub fn from_asc(content: &str) -> Result<NeuronCell> {
+    build(&parse_asc(content)?)
+}
+
+/// Cut samples into sections
+fn build(samples: &[Sample]) -> Result<NeuronCell> {
+    if samples.is_empty() {
+        return Err(OldiesError::ParseError("Morphology has no points".into()));
+    }
+    let mut children = vec![vec![]; samples.len()];
+    for (i, s) in samples.iter().enumerate() {
+        if let Some(p) = s.parent {
+            children[p].push(i);
+        }
+    }
+    let soma: Vec<usize> = (0..samples.len()).filter(|&i| samples[i].kind == "soma").collect();
+    let spherical = soma.len() == 1
+        || (soma.len() == 3 && soma[1..].iter().all(|&i| samples[i].parent == Some(soma[0])));
+
+    let mut cell = NeuronCell::new("swc");
+    let mut counts = std::collections::HashMap::new();
+    let mut visited = 0;
+    let mut pending: Vec<(usize, Option<Attachment>)> = (0..samples.len()).rev()
+        .filter(|&i| samples[i].parent.is_none())
+        .map(|i| (i, None))
+        .collect();
+    while let Some((first, attachment)) = pending.pop() {
+        let kind = samples[first].kind;
+        let count = counts.entry(kind).or_insert(0);
+        let name = format!("{}[{}]", kind, count);
+        *count += 1;
+
+        let sphere = kind == "soma" && spherical;
+        let (members, branches) = if sphere {
+            let branches: Vec<usize> = soma.iter()
+                .flat_map(|&m| children[m].iter().copied())
+                .filter(|&c| samples[c].kind != "soma")
+                .collect();
+            (soma.clone(), branches)
+        } else {
+            let mut members = vec![first];
+            let mut last = first;
+            while let [c] = children[last][..] {
+                if samples[c].kind != kind {
+                    break;
+                }
+                members.push(c);
+                last = c;
+            }
+            (members, children[last].clone())
+        };
+        visited += members.len();
+
+        let sec = cell.create(&name);
+        if sphere {
+            let c = samples[soma[0]].point;
+            let r = c.diam / 2.0;
+            for point in [Point3d { y: c.y - r, ..c }, c, Point3d { y: c.y + r, ..c }] {
+                sec.pt3dadd(point);
+            }
+        } else {
+            let mut points = vec![];
+            if let Some(a) = &attachment {
+                if !a.sphere || members.len() == 1 {
+                    let diam = if a.sphere { samples[first].point.diam } else { samples[a.sample].point.diam };
+                    points.push(Point3d { diam, ..samples[a.sample].point });
+                }
+            }
+            points.extend(members.iter().map(|&m| samples[m].point));
+            for point in points {
+                sec.pt3dadd(point);
+            }
+            if sec.arc3d() <= 0.0 {
+                return Err(OldiesError::ParseError(format!("Morphology section {} has zero length", name)));
+            }
+        }
+        let nseg = d_lambda_nseg(sec, D_LAMBDA, D_LAMBDA_FREQ);
+        sec.set_nseg(nseg);
+        if let Some(a) = &attachment {
+            cell.connect(&name, 0.0, &a.section, a.loc)?;
+        }
+
+        let last = *members.last().unwrap();
+        for &c in branches.iter().rev() {
+            let sample = if sphere { soma[0] } else { last };
+            let loc = if sphere { 0.5 } else { 1.0 };
+            pending.push((c, Some(Attachment { section: name.clone(), sample, loc, sphere })));
+        }
+    }
+    if visited != samples.len() {
+        return Err(OldiesError::ParseError("Morphology points form a cycle".into()));
+    }
+
+    let root = if cell.sections.contains_key("soma[0]") {
+        "soma[0]".to_string()
+    } else {
+        cell.sections.keys().min().cloned().unwrap()
+    };
+    cell.access(&root)?;
+    Ok(cell)
+}
+
+// =============================================================================
+// VALIDATION AND REPAIR
+// =============================================================================
+
+/// Diameter (um) given to points and sections with none to take from
+/// their neighbours
+pub const MIN_DIAMETER: f64 = 0.1;
+
+/// Problem in the morphology of a cell
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// Diameter of zero or less at 3D point `point`, or of the whole
+    /// section without 3D points
+    ZeroDiameter { section: String, point: Option<usize> },
+    /// 3D point `point` at the same position as the one before
+    DuplicatePoint { section: String, point: usize },
+    /// Section of no length. Not repaired.
+    ZeroLength { section: String },
+    /// Tree rooted at `section`, apart from the main one
+    Disconnected { section: String },
+    /// `nseg` more than five times too coarse or ten times too fine for
+    /// the d_lambda rule, which gives `expected`
+    Nseg { section: String, nseg: usize, expected: usize },
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Issue::ZeroDiameter { section, point: Some(i) } => write!(f, "{}: 3D point {} has no diameter", section, i),
+            Issue::ZeroDiameter { section, point: None } => write!(f, "{}: no diameter", section),
+            Issue::DuplicatePoint { section, point } => write!(f, "{}: 3D point {} repeats the one before", section, point),
+            Issue::ZeroLength { section } => write!(f, "{}: no length", section),
+            Issue::Disconnected { section } => write!(f, "{}: tree not connected to the cell", section),
+            Issue::Nseg { section, nseg, expected } => {
+                write!(f, "{}: nseg = {} where d_lambda gives {}", section, nseg, expected)
+            }
+        }
+    }
+}
+
+/// Root section of the main tree: that of the accessed section, or else
+/// of the largest tree
+fn main_root(cell: &NeuronCell) -> Option<String> {
+    let root_of = |name: &str| cell.wholetree(name).ok().and_then(|t| t.names.first().cloned());
+    if let Some(current) = cell.current() {
+        return root_of(&current.name);
+    }
+    cell.section_names().iter()
+        .filter(|n| cell.sections[*n].parent.is_none())
+        .max_by_key(|n| cell.wholetree(n).map_or(0, |t| t.len()))
+        .cloned()
+}
+
+/// Problems in the morphology of `cell`, section by section
+pub fn validate(cell: &NeuronCell) -> Vec<Issue> {
+    let main = main_root(cell);
+    let mut issues = vec![];
+    for name in cell.section_names() {
+        let sec = &cell.sections[&name];
+        let section = || name.clone();
+        for (i, p) in sec.pt3d.iter().enumerate() {
+            if p.diam <= 0.0 {
+                issues.push(Issue::ZeroDiameter { section: section(), point: Some(i) });
+            }
+            if i > 0 && distance3d(&sec.pt3d[i - 1], p) == 0.0 {
+                issues.push(Issue::DuplicatePoint { section: section(), point: i });
+            }
+        }
+        if sec.pt3d.is_empty() && sec.diam <= 0.0 {
+            issues.push(Issue::ZeroDiameter { section: section(), point: None });
+        }
+        if sec.length <= 0.0 {
+            issues.push(Issue::ZeroLength { section: section() });
+        } else if sec.diam > 0.0 {
+            let expected = d_lambda_nseg(sec, D_LAMBDA, D_LAMBDA_FREQ);
+            if 5 * sec.nseg < expected || sec.nseg > 10 * expected {
+                issues.push(Issue::Nseg { section: section(), nseg: sec.nseg, expected });
+            }
+        }
+        if sec.parent.is_none() && main.as_ref() != Some(&name) {
+            issues.push(Issue::Disconnected { section: section() });
+        }
+    }
+    issues
+}
+
+/// Fix the problems [`validate`] finds, returning those fixed:
+///
+/// - points without diameter take that of the nearest point with one,
+///   sections without any that of their parent, else [`MIN_DIAMETER`]
+/// - repeated points are dropped
+/// - disconnected trees are attached by their root's 0 end to the nearest
+///   3D point of the main tree, or to the middle of its root without 3D
+///   points
+/// - `nseg` is set by the d_lambda rule
+///
+/// Sections of no length are reported by [`validate`] but left alone.
+pub fn repair(cell: &mut NeuronCell) -> Result<Vec<Issue>> {
+    let issues = validate(cell);
+    let mut fixed = vec![];
+    for issue in &issues {
+        match issue {
+            Issue::ZeroDiameter { section, point: None } => {
+                let parent = cell.sections[section].parent.as_ref().map(|(p, _)| cell.sections[p].diam);
+                cell.sections.get_mut(section).unwrap().diam = parent.filter(|&d| d > 0.0).unwrap_or(MIN_DIAMETER);
+            }
+            Issue::ZeroDiameter { .. } | Issue::DuplicatePoint { .. } | Issue::Nseg { .. } => {}
+            Issue::ZeroLength { .. } => continue,
+            Issue::Disconnected { section } => {
+                let Some(main) = main_root(cell) else { continue };
+                let (parent, loc) = nearest_location(cell, &main, section)?;
+                cell.connect(section, 0.0, &parent, loc)?;
+            }
+        }
+        fixed.push(issue.clone());
+    }
+
+    let points: Vec<String> = issues.iter().filter_map(|issue| match issue {
+        Issue::ZeroDiameter { section, point: Some(_) } | Issue::DuplicatePoint { section, .. } => Some(section.clone()),
+        _ => None,
+    }).collect();
+    for name in points {
+        let sec = cell.sections.get_mut(&name).unwrap();
+        let mut pt3d = std::mem::take(&mut sec.pt3d);
+        pt3d.dedup_by(|b, a| distance3d(a, b) == 0.0);
+        let diams: Vec<f64> = (0..pt3d.len()).map(|i| {
+            (0..pt3d.len()).filter(|&j| pt3d[j].diam > 0.0)
+                .min_by_key(|&j| j.abs_diff(i))
+                .map_or(MIN_DIAMETER, |j| pt3d[j].diam)
+        }).collect();
+        for (mut point, diam) in pt3d.into_iter().zip(diams) {
+            point.diam = diam;
+            sec.pt3dadd(point);
+        }
+    }
+
+    for issue in &fixed {
+        if let Issue::Nseg { section, .. } | Issue::ZeroDiameter { section, .. } = issue {
+            let sec = cell.sections.get_mut(section).unwrap();
+            if sec.length > 0.0 {
+                sec.set_nseg(d_lambda_nseg(sec, D_LAMBDA, D_LAMBDA_FREQ));
+            }
+        }
+    }
+    Ok(fixed)
+}
+
+/// Location on the tree rooted at `main` nearest the start of `section`
+fn nearest_location(cell: &NeuronCell, main: &str, section: &str) -> Result<(String, f64)> {
+    let Some(start) = cell.sections[section].pt3d.first() else {
+        return Ok((main.to_string(), 0.5));
+    };
+    let mut nearest = (main.to_string(), 0.5, f64::INFINITY);
+    for name in cell.wholetree(main)?.names {
+        let sec = &cell.sections[&name];
+        let (length, mut s) = (sec.arc3d(), 0.0);
+        for (i, p) in sec.pt3d.iter().enumerate() {
+            if i > 0 {
+                s += distance3d(&sec.pt3d[i - 1], p);
+            }
+            let d = distance3d(p, start);
+            if d < nearest.2 {
+                let loc = if length > 0.0 { s / length } else { 0.5 };
+                nearest = (name.clone(), loc, d);
+            }
+        }
+    }
+    Ok((nearest.0, nearest.1))
+}
+
+/// Set `nseg` of every section of `cell` by the d_lambda rule, as
+/// NEURON's `geom_nseg()`, returning the total number of segments
+pub fn geom_nseg(cell: &mut NeuronCell, d_lambda: f64, freq: f64) -> usize {
+    cell.forall(|sec| {
+        if sec.length > 0.0 && sec.diam > 0.0 {
+            let nseg = d_lambda_nseg(sec, d_lambda, freq);
+            sec.set_nseg(nseg);
+        }
+    });
+    cell.total_segments()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::CableTree;
+
+    const SWC: &str = "\
+# three-point soma, a forked dendrite and an axon
+1 1 0 0 0 10 -1
+2 1 0 -10 0 10 1
+3 1 0 10 0 10 1
+4 3 10 0 0 1 1
+5 3 60 0 0 1 4
+6 3 60 50 0 0.5 5
+7 3 60 -50 0 0.5 5
+8 2 -10 0 0 0.5 1
+9 2 -210 0 0 0.5 8
+";
+
+    #[test]
+    fn test_swc_sections() {
+        let cell = NeuronCell::from_swc(SWC).unwrap();
+        let mut names: Vec<&String> = cell.sections.keys().collect();
+        names.sort();
+        assert_eq!(names, ["axon[0]", "dend[0]", "dend[1]", "dend[2]", "soma[0]"]);
+        assert_eq!(cell.current().unwrap().name, "soma[0]");
+
+        let soma = &cell.sections["soma[0]"];
+        assert_eq!((soma.length, soma.diam, soma.pt3d.len()), (20.0, 20.0, 3));
+
+        let trunk = &cell.sections["dend[0]"];
+        assert_eq!((trunk.length, trunk.diam), (50.0, 2.0));
+        assert_eq!(trunk.parent, Some(("soma[0]".to_string(), 0.5)));
+        assert_eq!(trunk.nseg, 3);
+
+        // Branches start from the fork point
+        let branch = &cell.sections["dend[1]"];
+        assert_eq!(branch.pt3d[0], Point3d { x: 60.0, y: 0.0, z: 0.0, diam: 2.0 });
+        assert_eq!((branch.length, branch.diam), (50.0, 1.5));
+        assert_eq!(branch.parent, Some(("dend[0]".to_string(), 1.0)));
+
+        let axon = &cell.sections["axon[0]"];
+        assert_eq!((axon.length, axon.diam, axon.nseg), (200.0, 1.0, 7));
+
+        let tree = CableTree::new(&cell).unwrap();
+        assert_eq!(tree.len(), cell.total_segments());
+    }
+
+    #[test]
+    fn test_asc_sections() {
+        let asc = r#"
+; Neurolucida export
+(ImageCoords Filename "cell.jpg")
+("CellBody"
+  (Color Red)
+  (CellBody)
+  (  10    0   0  0.1)  ; 1, 1
+  (   0   10   0  0.1)  ; 1, 2
+  ( -10    0   0  0.1)
+  (   0  -10   0  0.1)
+)
+(Dot (Color Cyan) (Name "Marker") (0 0 0 1))
+( (Color Yellow)
+  (Dendrite)
+  (  10    0   0  2)  ; Root
+  (  60    0   0  2)
+  <(  40    2   0  0.5)>  ; Spine
+  (
+    (  60   50   0  1)
+    (  60  100   0  1)
+    Normal
+  |
+    (  60  -50   0  1)
+    High
+  )
+)
+( (Axon)
+  ( -10    0   0  1)
+  (-110    0   0  1)
+  Incomplete
+)
+"#;
+        let cell = NeuronCell::from_asc(asc).unwrap();
+        let mut names: Vec<&String> = cell.sections.keys().collect();
+        names.sort();
+        assert_eq!(names, ["axon[0]", "dend[0]", "dend[1]", "dend[2]", "soma[0]"]);
+
+        let soma = &cell.sections["soma[0]"];
+        assert_eq!((soma.length, soma.diam), (20.0, 20.0));
+        assert_eq!(cell.sections["dend[0]"].length, 50.0);
+        assert_eq!(cell.sections["dend[1]"].length, 100.0);
+        assert_eq!(cell.sections["dend[2]"].parent, Some(("dend[0]".to_string(), 1.0)));
+        assert_eq!(cell.sections["axon[0]"].parent, Some(("soma[0]".to_string(), 0.5)));
+
+        assert!(from_asc("((Dendrite) (0 0 0 1)").is_err());
+    }
+
+    #[test]
+    fn test_validate_and_repair() {
+        // Dendrite with a repeated point and a point without diameter, an
+        // axon stub of 500 segments and a dendrite off on its own
+        let swc = "\
+1 1 0 0 0 10 -1
+2 3 10 0 0 1 1
+3 3 60 0 0 1 2
+4 3 60 0 0 1 3
+5 3 110 0 0 0 4
+6 3 210 0 0 1 5
+7 2 -10 0 0 0.5 1
+8 2 -30 0 0 0.5 7
+9 3 60 5 0 1 -1
+10 3 60 60 0 1 9
+";
+        let mut cell = NeuronCell::from_swc(swc).unwrap();
+        cell.sections.get_mut("axon[0]").unwrap().set_nseg(500);
+        let issues = validate(&cell);
+        let expected = [
+            Issue::Nseg { section: "axon[0]".into(), nseg: 500, expected: 1 },
+            Issue::DuplicatePoint { section: "dend[0]".into(), point: 2 },
+            Issue::ZeroDiameter { section: "dend[0]".into(), point: Some(3) },
+            Issue::Disconnected { section: "dend[1]".into() },
+        ];
+        assert_eq!(issues, expected);
+        assert!(issues[3].to_string().contains("not connected"));
+        assert!(CableTree::new(&cell).is_ok());
+
+        let fixed = repair(&mut cell).unwrap();
+        assert_eq!(fixed, expected);
+        assert!(validate(&cell).is_empty(), "{:?}", validate(&cell));
+        let dend = &cell.sections["dend[0]"];
+        assert_eq!(dend.pt3d.len(), 4);
+        assert!(dend.pt3d.iter().all(|p| p.diam == 2.0));
+        assert_eq!((dend.length, dend.diam), (200.0, 2.0));
+        assert_eq!(cell.sections["axon[0]"].nseg, 1);
+        // Attached at the point of dend[0] nearest its start
+        assert_eq!(cell.sections["dend[1]"].parent, Some(("dend[0]".to_string(), 0.25)));
+        assert!(cell.wholetree("soma[0]").unwrap().contains("dend[1]"));
+
+        // Sections without 3D points
+        let mut cell = NeuronCell::new("cell");
+        cell.create("soma");
+        cell.create("dend").diam = 0.0;
+        cell.connect("dend", 0.0, "soma", 1.0).unwrap();
+        cell.create("axon").length = 0.0;
+        cell.access("soma").unwrap();
+        let fixed = repair(&mut cell).unwrap();
+        assert_eq!(fixed, [
+            Issue::Disconnected { section: "axon".into() },
+            Issue::ZeroDiameter { section: "dend".into(), point: None },
+        ]);
+        assert_eq!(cell.sections["dend"].diam, 1.0);
+        assert_eq!(cell.sections["axon"].parent, Some(("soma".to_string(), 0.5)));
+        assert_eq!(validate(&cell), [Issue::ZeroLength { section: "axon".into() }]);
+    }
+
+    #[test]
+    fn test_geom_nseg() {
+        let mut cell = NeuronCell::from_swc(SWC).unwrap();
+        cell.forall(|sec| sec.set_nseg(1));
+        assert_eq!(geom_nseg(&mut cell, D_LAMBDA, D_LAMBDA_FREQ), 1 + 3 + 3 + 3 + 7);
+        // Finer with a smaller fraction of the length constant
+        assert!(geom_nseg(&mut cell, 0.01, D_LAMBDA_FREQ) > 40);
+        assert!(cell.sections.values().all(|s| s.nseg % 2 == 1));
+    }
+
+    #[test]
+    fn test_swc_errors() {
+        assert!(from_swc("").is_err());
+        assert!(from_swc("1 1 0 0 0 x -1").is_err());
+        assert!(from_swc("1 1 0 0 0 5 7").is_err());
+        assert!(from_swc("1 3 0 0 0 1 2\n2 3 10 0 0 1 1").is_err());
+    }
+}
@@ -0,0 +1,521 @@
+//! Network connections
+//!
+//! A [`NetCon`] watches a source, either the membrane potential at a point
+//! of a cell or an artificial cell, and whenever the source fires delivers
+//! an event of its `weight` to its target `delay` ms later. Events wait in
+//! a global queue ordered by delivery time. With fixed steps they are
+//! delivered at the step boundary nearest their time, as NEURON does; the
+//! variable-step integrator stops exactly at each of them.
+//!
+//! Delivery runs the target's NET_RECEIVE block: synapses step their
+//! conductance, artificial cells update their state and may fire in turn.
+//! Artificial cells are integrated by events alone, without a cable solve:
+//! besides the events of their connections they receive the self-events
+//! they send with `net_send`, which is how a [`ArtificialCell::net_stim`]
+//! paces its spike train.
+
+use oldies_core::{OldiesError, Result, Rng, Time, Voltage};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+/// What a connection watches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NetSource {
+    /// Upward crossings of the connection's threshold by the membrane
+    /// potential of `section(loc)` of cell `cell`
+    Voltage { cell: usize, section: String, loc: f64 },
+    /// Firing of an artificial cell
+    Artificial(usize),
+    /// Counts of APCount point process `index` of cell `cell`
+    Point { cell: usize, index: usize },
+}
+
+/// Where a connection delivers its events
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NetTarget {
+    /// Point process `index` of cell `cell`
+    PointProcess { cell: usize, index: usize },
+    /// An artificial cell
+    Artificial(usize),
+}
+
+/// Network connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetCon {
+    pub source: NetSource,
+    /// Target, or `None` to only record the source
+    pub target: Option<NetTarget>,
+    /// Event weight (uS for synapses)
+    pub weight: f64,
+    /// Delivery delay (ms)
+    pub delay: Time,
+    /// Threshold of voltage sources (mV)
+    pub threshold: Voltage,
+    /// Times the source fired since `finitialize`
+    pub spikes: Vec<Time>,
+    /// Last (t, v) of a voltage source
+    #[serde(skip)]
+    pub(crate) last: Option<(Time, Voltage)>,
+}
+
+impl NetCon {
+    /// Connection with NEURON's defaults: weight 0, delay 1 ms, threshold
+    /// 10 mV
+    pub fn new(source: NetSource, target: Option<NetTarget>) -> Self {
+        Self {
+            source,
+            target,
+            weight: 0.0,
+            delay: 1.0,
+            threshold: 10.0,
+            spikes: Vec::new(),
+            last: None,
+        }
+    }
+
+    /// Time at which `v` crossed the threshold upwards since the last
+    /// check, located by linear interpolation
+    pub(crate) fn crossing(&mut self, t: Time, v: Voltage) -> Option<Time> {
+        let crossing = match self.last {
+            Some((t0, v0)) if v0 < self.threshold && v >= self.threshold => {
+                Some(t0 + (self.threshold - v0) / (v - v0) * (t - t0))
+            }
+            _ => None,
+        };
+        self.last = Some((t, v));
+        crossing
+    }
+}
+
+/// Cell without membrane, driven by events (`IntFire1`, `NetStim`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtificialCell {
+    pub name: String,
+    pub parameters: HashMap<String, f64>,
+    pub state: HashMap<String, f64>,
+    /// Random number generator, reseeded from `seed` at initialization
+    #[serde(skip)]
+    pub(crate) rng: Rng,
+}
+
+/// What an artificial cell does on receiving an event
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Response {
+    /// Whether the cell fires at the event time
+    pub fire: bool,
+    /// Time of a self-event to send (`net_send`)
+    pub send: Option<Time>,
+}
+
+impl ArtificialCell {
+    fn new(name: &str, parameters: &[(&str, f64)]) -> Self {
+        Self {
+            name: name.to_string(),
+            parameters: parameters.iter().map(|&(k, v)| (k.to_string(), v)).collect(),
+            state: HashMap::new(),
+            rng: Rng::default(),
+        }
+    }
+
+    /// Leaky integrator that fires when `m` exceeds 1 (`tau`, `refrac` in ms)
+    pub fn int_fire1() -> Self {
+        Self::new("IntFire1", &[("tau", 10.0), ("refrac", 5.0)])
+    }
+
+    /// Spike train of `number` events from `start` every `interval` ms, as
+    /// NEURON's `NetStim`
+    ///
+    /// With `noise` between 0 and 1 the intervals are a fixed part
+    /// `(1 - noise) * interval` plus an exponentially distributed part of
+    /// mean `noise * interval`, so that `noise = 1` gives a Poisson train.
+    /// Each stimulus draws from its own generator, seeded by its `seed`
+    /// parameter. An event of positive weight starts a new train at once
+    /// if the stimulus is off, one of negative weight turns it off.
+    pub fn net_stim(start: Time, interval: Time, number: f64, noise: f64) -> Self {
+        Self::new("NetStim", &[
+            ("start", start),
+            ("interval", interval),
+            ("number", number),
+            ("noise", noise),
+            ("seed", 1.0),
+        ])
+    }
+
+    fn parameter(&self, name: &str) -> Result<f64> {
+        self.parameters.get(name).copied().ok_or_else(|| {
+            OldiesError::SimulationError(format!("{} lacks parameter {}", self.name, name))
+        })
+    }
+
+    fn state(&self, name: &str) -> f64 {
+        self.state.get(name).copied().unwrap_or(0.0)
+    }
+
+    /// Next NetStim interval
+    fn stim_interval(&mut self) -> Result<Time> {
+        let interval = self.parameter("interval")?.max(0.01);
+        let noise = self.parameter("noise")?.clamp(0.0, 1.0);
+        if noise == 0.0 {
+            return Ok(interval);
+        }
+        Ok((1.0 - noise) * interval + self.rng.exponential(1.0 / (noise * interval)))
+    }
+
+    /// Start a NetStim train at `t`, returning the time of its first spike
+    fn stim_start(&mut self, t: Time) -> Result<Time> {
+        self.state.insert("on".into(), 1.0);
+        self.state.insert("count".into(), 0.0);
+        // On average the first spike comes `noise * interval` after `t`
+        let first = self.stim_interval()? - self.parameter("interval")? * (1.0 - self.parameter("noise")?.clamp(0.0, 1.0));
+        let first = t + first.max(0.0);
+        self.state.insert("next".into(), first);
+        Ok(first)
+    }
+
+    /// Reset the state at the start of a run, returning the time of the
+    /// first self-event to send
+    pub(crate) fn initialize(&mut self) -> Result<Option<Time>> {
+        match self.name.as_str() {
+            "IntFire1" => {
+                self.state.insert("m".into(), 0.0);
+                self.state.insert("t0".into(), 0.0);
+                self.state.insert("refractory".into(), 0.0);
+                Ok(None)
+            }
+            "NetStim" => {
+                self.rng = Rng::new(self.parameter("seed")? as u64);
+                self.state.insert("on".into(), 0.0);
+                self.state.insert("count".into(), 0.0);
+                let start = self.parameter("start")?;
+                if start < 0.0 || self.parameter("number")? <= 0.0 {
+                    return Ok(None);
+                }
+                self.stim_start(start).map(Some)
+            }
+            name => Err(OldiesError::SimulationError(format!("Unknown artificial cell: {}", name))),
+        }
+    }
+
+    /// Receive an event of `weight` at `t`
+    pub(crate) fn net_receive(&mut self, t: Time, weight: f64) -> Result<Response> {
+        match self.name.as_str() {
+            "IntFire1" => {
+                if t < self.state("refractory") {
+                    return Ok(Response::default());
+                }
+                let m = self.state("m") * (-(t - self.state("t0")) / self.parameter("tau")?).exp() + weight;
+                let fire = m > 1.0;
+                let refrac = self.parameter("refrac")?;
+                self.state.insert("t0".into(), t);
+                self.state.insert("m".into(), if fire { 0.0 } else { m });
+                if fire {
+                    self.state.insert("refractory".into(), t + refrac);
+                }
+                Ok(Response { fire, send: None })
+            }
+            "NetStim" => {
+                if weight > 0.0 && self.state("on") == 0.0 && self.parameter("number")? > 0.0 {
+                    return Ok(Response { fire: false, send: Some(self.stim_start(t)?) });
+                }
+                if weight < 0.0 {
+                    self.state.insert("on".into(), 0.0);
+                }
+                Ok(Response::default())
+            }
+            name => Err(OldiesError::SimulationError(format!("Unknown artificial cell: {}", name))),
+        }
+    }
+
+    /// Receive at `t` a self-event sent earlier
+    pub(crate) fn self_event(&mut self, t: Time) -> Result<Response> {
+        match self.name.as_str() {
+            "NetStim" => {
+                // Events of a train since turned off or restarted are stale
+                if self.state("on") == 0.0 || t != self.state("next") {
+                    return Ok(Response::default());
+                }
+                let count = self.state("count") + 1.0;
+                self.state.insert("count".into(), count);
+                if count >= self.parameter("number")? {
+                    self.state.insert("on".into(), 0.0);
+                    return Ok(Response { fire: true, send: None });
+                }
+                let next = t + self.stim_interval()?;
+                self.state.insert("next".into(), next);
+                Ok(Response { fire: true, send: Some(next) })
+            }
+            _ => Ok(Response::default()),
+        }
+    }
+}
+
+/// What an event is delivered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Delivery {
+    /// The target of connection `netcon`
+    NetCon(usize),
+    /// Artificial cell `cell`, which sent the event to itself
+    SelfEvent(usize),
+}
+
+/// Pending delivery at `t`
+#[derive(Debug, Clone)]
+struct Event {
+    t: Time,
+    /// Order of insertion, keeping simultaneous events first in, first out
+    seq: usize,
+    delivery: Delivery,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Event {
+    // Reversed so that the heap pops the earliest event
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.t.total_cmp(&self.t).then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// Events waiting for delivery
+#[derive(Debug, Clone, Default)]
+pub struct EventQueue {
+    heap: BinaryHeap<Event>,
+    seq: usize,
+}
+
+impl EventQueue {
+    /// Schedule connection `netcon` for delivery at `t`
+    pub fn push(&mut self, t: Time, netcon: usize) {
+        self.schedule(t, Delivery::NetCon(netcon));
+    }
+
+    /// Schedule a self-event of artificial cell `cell` at `t`
+    pub fn send(&mut self, t: Time, cell: usize) {
+        self.schedule(t, Delivery::SelfEvent(cell));
+    }
+
+    /// Schedule `delivery` at `t`, after the events already due then
+    pub(crate) fn schedule(&mut self, t: Time, delivery: Delivery) {
+        self.heap.push(Event { t, seq: self.seq, delivery });
+        self.seq += 1;
+    }
+
+    /// Time of the earliest event
+    pub fn next_time(&self) -> Option<Time> {
+        self.heap.peek().map(|e| e.t)
+    }
+
+    /// Remove the earliest event if it is due by `t`
+    pub fn pop_until(&mut self, t: Time) -> Option<(Time, Delivery)> {
+        if self.next_time()? <= t {
+            self.heap.pop().map(|e| (e.t, e.delivery))
+        } else {
+            None
+        }
+    }
+
+    /// Events waiting, in delivery order
+    pub fn pending(&self) -> Vec<(Time, Delivery)> {
+        let mut events: Vec<&Event> = self.heap.iter().collect();
+        events.sort_by(|a, b| b.cmp(a));
+        events.into_iter().map(|e| (e.t, e.delivery)).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mechanisms, NeuronCell, NeuronSimulation};
+
+    fn hh_cell(name: &str) -> NeuronCell {
+        let mut cell = NeuronCell::new(name);
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::hh());
+        cell
+    }
+
+    fn soma(cell: usize) -> NetSource {
+        NetSource::Voltage { cell, section: "soma".into(), loc: 0.5 }
+    }
+
+    /// A pulse-driven cell exciting a second one through an ExpSyn
+    fn pair() -> NeuronSimulation {
+        let mut sim = NeuronSimulation::new();
+        let mut pre = hh_cell("pre");
+        pre.add_point_process(mechanisms::iclamp("soma", 0.5, 1.0, 1.0, 1.0));
+        let mut post = hh_cell("post");
+        post.add_point_process(mechanisms::exp_syn("soma", 0.5));
+        sim.add_cell(pre);
+        sim.add_cell(post);
+        let mut nc = NetCon::new(soma(0), Some(NetTarget::PointProcess { cell: 1, index: 0 }));
+        nc.weight = 0.05;
+        nc.delay = 2.0;
+        nc.threshold = 0.0;
+        sim.add_netcon(nc);
+        sim.add_threshold("post", 1, "soma", 0.5, 0.0);
+        sim.tstop = 15.0;
+        sim
+    }
+
+    #[test]
+    fn test_event_queue_order() {
+        let mut queue = EventQueue::default();
+        queue.push(3.0, 0);
+        queue.push(1.0, 1);
+        queue.push(1.0, 2);
+        assert_eq!(queue.next_time(), Some(1.0));
+        queue.send(1.0, 0);
+        assert_eq!(queue.pop_until(2.0), Some((1.0, Delivery::NetCon(1))));
+        assert_eq!(queue.pop_until(2.0), Some((1.0, Delivery::NetCon(2))));
+        assert_eq!(queue.pop_until(2.0), Some((1.0, Delivery::SelfEvent(0))));
+        assert_eq!(queue.pop_until(2.0), None);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_synaptic_transmission() {
+        let mut fixed = pair();
+        fixed.finitialize(-65.0).unwrap();
+        fixed.run().unwrap();
+        let pre = fixed.netcons[0].spikes.clone();
+        let post = fixed.threshold_events["post"].clone();
+        assert_eq!((pre.len(), post.len()), (1, 1));
+        assert!(post[0] > pre[0] + 2.0 && post[0] < pre[0] + 6.0, "{:?} {:?}", pre, post);
+
+        // The variable-step integrator stops at the delivery time
+        let mut variable = pair();
+        variable.cvode_active(true);
+        variable.record_v("v", 1, "soma", 0.5);
+        variable.finitialize(-65.0).unwrap();
+        variable.run().unwrap();
+        let delivery = variable.netcons[0].spikes[0] + 2.0;
+        assert!(variable.recordings["t"].contains(&delivery));
+        assert!((variable.threshold_events["post"][0] - post[0]).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_apcount_source() {
+        // The presynaptic spike reaches the synapse through an APCount
+        let mut sim = pair();
+        sim.cells[0].add_point_process(mechanisms::apcount("soma", 0.5, 0.0));
+        sim.netcons[0].source = NetSource::Point { cell: 0, index: 1 };
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+        let pre = &sim.apcount_times[&(0, 1)];
+        assert_eq!(&sim.netcons[0].spikes, pre);
+        assert_eq!(sim.threshold_events["post"].len(), 1);
+        assert_eq!(sim.raster(), [(pre[0], 0)]);
+    }
+
+    #[test]
+    fn test_int_fire1() {
+        // Two connections from one source arrive 1 ms apart and together
+        // push an IntFire1 over threshold
+        let mut sim = NeuronSimulation::new();
+        let mut pre = hh_cell("pre");
+        pre.add_point_process(mechanisms::iclamp("soma", 0.5, 2.0, 100.0, 0.2));
+        sim.add_cell(pre);
+        let cell = sim.add_artificial_cell(ArtificialCell::int_fire1());
+        for delay in [1.0, 2.0] {
+            let mut nc = NetCon::new(soma(0), Some(NetTarget::Artificial(cell)));
+            nc.weight = 0.6;
+            nc.delay = delay;
+            nc.threshold = 0.0;
+            sim.add_netcon(nc);
+        }
+        let out = sim.add_netcon(NetCon::new(NetSource::Artificial(cell), None));
+        sim.tstop = 20.0;
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+
+        let pre = &sim.netcons[0].spikes;
+        let fired = &sim.netcons[out].spikes;
+        assert!(pre.len() >= 2);
+        assert_eq!(fired.len(), pre.len());
+        for (a, b) in fired.iter().zip(pre) {
+            assert!((a - (b + 2.0)).abs() < 0.025, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_net_stim_trains() {
+        let mut sim = NeuronSimulation::new();
+        let regular = sim.add_artificial_cell(ArtificialCell::net_stim(5.0, 10.0, 4.0, 0.0));
+        let mut poisson = ArtificialCell::net_stim(0.0, 5.0, 1e9, 1.0);
+        poisson.parameters.insert("seed".into(), 7.0);
+        let poisson = sim.add_artificial_cell(poisson);
+        let a = sim.add_netcon(NetCon::new(NetSource::Artificial(regular), None));
+        let b = sim.add_netcon(NetCon::new(NetSource::Artificial(poisson), None));
+        // Turn the regular train back on once it has ended
+        let mut on = NetCon::new(NetSource::Artificial(poisson), Some(NetTarget::Artificial(regular)));
+        on.weight = 1.0;
+        let on = sim.add_netcon(on);
+        sim.tstop = 5000.0;
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+
+        // Later Poisson events cannot restart a train that is running
+        let spikes = &sim.netcons[a].spikes;
+        assert_eq!(spikes[..4].to_vec(), vec![5.0, 15.0, 25.0, 35.0]);
+        let restart = sim.netcons[on].spikes.iter().find(|&&t| t + 1.0 > 35.0).unwrap() + 1.0;
+        assert_eq!(spikes[4], restart);
+        assert!((spikes[7] - spikes[4] - 30.0).abs() < 1e-9);
+
+        let isi: Vec<f64> = sim.netcons[b].spikes.windows(2).map(|w| w[1] - w[0]).collect();
+        let mean = isi.iter().sum::<f64>() / isi.len() as f64;
+        let cv = (isi.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / isi.len() as f64).sqrt() / mean;
+        assert!((mean - 5.0).abs() < 0.3, "mean ISI {}", mean);
+        assert!((cv - 1.0).abs() < 0.1, "CV {}", cv);
+    }
+
+    #[test]
+    fn test_net_stim_drives_synapse() {
+        for cvode in [false, true] {
+            let mut sim = NeuronSimulation::new();
+            let mut cell = hh_cell("cell");
+            cell.add_point_process(mechanisms::exp_syn("soma", 0.5));
+            sim.add_cell(cell);
+            let stim = sim.add_artificial_cell(ArtificialCell::net_stim(10.0, 20.0, 3.0, 0.0));
+            let mut nc = NetCon::new(NetSource::Artificial(stim), Some(NetTarget::PointProcess { cell: 0, index: 0 }));
+            nc.weight = 0.05;
+            sim.add_netcon(nc);
+            sim.add_threshold("cell", 0, "soma", 0.5, 0.0);
+            sim.cvode_active(cvode);
+            sim.tstop = 100.0;
+            sim.finitialize(-65.0).unwrap();
+            sim.run().unwrap();
+            let spikes = &sim.threshold_events["cell"];
+            assert_eq!(spikes.len(), 3, "cvode {}: {:?}", cvode, spikes);
+            for (spike, stim) in spikes.iter().zip([11.0, 31.0, 51.0]) {
+                assert!(*spike > stim && *spike < stim + 4.0, "cvode {}: {:?}", cvode, spikes);
+            }
+        }
+    }
+}
@@ -0,0 +1,676 @@
+//! NMODL parser
+//!
+//! Reads a mechanism description into [`NmodlBlock`]s. Declaration blocks
+//! (NEURON, UNITS, PARAMETER, STATE, ASSIGNED) are broken down into names,
+//! defaults, units and limits; code blocks (INITIAL, BREAKPOINT, DERIVATIVE,
+//! KINETIC, PROCEDURE, FUNCTION, NET_RECEIVE) keep their statements one per
+//! line for the mechanism compiler.
+//!
+//! Comments (`:`, `?`, `COMMENT ... ENDCOMMENT`), `VERBATIM` C code and
+//! `UNITSON`/`UNITSOFF` are dropped. INDEPENDENT, CONSTANT, LINEAR,
+//! NONLINEAR, CONSTRUCTOR, DESTRUCTOR, BEFORE and AFTER blocks, top-level
+//! `DEFINE` and `LOCAL` lines, and `ELECTRODE_CURRENT`, `EXTERNAL` and
+//! `THREADSAFE` declarations are skipped.
+
+use crate::{MechanismType, NmodlBlock, NmodlMechanism, NmodlVariable, UseIon};
+use oldies_core::{OldiesError, Result};
+
+/// Blocks accepted and ignored
+const SKIPPED_BLOCKS: &[&str] = &[
+    "INDEPENDENT", "CONSTANT", "LINEAR", "NONLINEAR", "CONSTRUCTOR", "DESTRUCTOR", "BEFORE", "AFTER",
+];
+
+/// Statements of the NEURON block
+const NEURON_KEYWORDS: &[&str] = &[
+    "SUFFIX", "POINT_PROCESS", "ARTIFICIAL_CELL", "USEION", "NONSPECIFIC_CURRENT",
+    "ELECTRODE_CURRENT", "RANGE", "GLOBAL", "POINTER", "BBCOREPOINTER", "EXTERNAL", "THREADSAFE",
+];
+
+fn error(line: usize, msg: impl std::fmt::Display) -> OldiesError {
+    OldiesError::ParseError(format!("NMODL line {}: {}", line, msg))
+}
+
+/// Parse a `.mod` file
+pub fn parse(src: &str) -> Result<NmodlMechanism> {
+    let (title, text) = strip(src);
+    let mut cursor = Cursor { src: &text, pos: 0 };
+    let mut blocks = vec![];
+    let mut has_neuron = false;
+    loop {
+        cursor.skip_ws();
+        if cursor.eof() {
+            break;
+        }
+        let line = cursor.line();
+        let keyword = cursor.word().ok_or_else(|| error(line, "expected a block keyword"))?;
+        let block = match keyword {
+            "NEURON" => {
+                has_neuron = true;
+                neuron(cursor.braced()?, line)?
+            }
+            "UNITS" => NmodlBlock::Units(units(cursor.braced()?)),
+            "PARAMETER" => NmodlBlock::Parameter(declarations(cursor.braced()?, line)?),
+            "ASSIGNED" => NmodlBlock::Assigned(declarations(cursor.braced()?, line)?),
+            "STATE" => NmodlBlock::State(
+                declarations(cursor.braced()?, line)?.into_iter().map(|v| v.name).collect(),
+            ),
+            "INITIAL" => NmodlBlock::Initial(statements(cursor.braced()?)),
+            "BREAKPOINT" => NmodlBlock::Breakpoint(statements(cursor.braced()?)),
+            "DERIVATIVE" | "KINETIC" => {
+                let name = cursor.word().ok_or_else(|| error(line, format!("{} needs a name", keyword)))?
+                    .to_string();
+                let body = statements(cursor.braced()?);
+                if keyword == "DERIVATIVE" {
+                    NmodlBlock::Derivative { name, equations: body }
+                } else {
+                    NmodlBlock::Kinetic { name, reactions: body }
+                }
+            }
+            "PROCEDURE" | "FUNCTION" => {
+                let name = cursor.word().ok_or_else(|| error(line, format!("{} needs a name", keyword)))?
+                    .to_string();
+                let params = params(cursor.parenthesized()?);
+                // FUNCTION f(x) (units) { ... }
+                cursor.skip_ws();
+                if cursor.peek() == Some('(') {
+                    cursor.parenthesized()?;
+                }
+                let body = statements(cursor.braced()?);
+                if keyword == "PROCEDURE" {
+                    NmodlBlock::Procedure { name, params, body }
+                } else {
+                    NmodlBlock::Function { name, params, body }
+                }
+            }
+            "NET_RECEIVE" => {
+                let params = params(cursor.parenthesized()?);
+                NmodlBlock::NetReceive { params, body: statements(cursor.braced()?) }
+            }
+            "DEFINE" | "LOCAL" => {
+                cursor.skip_line();
+                continue;
+            }
+            _ if SKIPPED_BLOCKS.contains(&keyword) => {
+                cursor.skip_to('{');
+                cursor.braced()?;
+                continue;
+            }
+            _ => return Err(error(line, format!("unknown block '{}'", keyword))),
+        };
+        blocks.push(block);
+    }
+    if !has_neuron {
+        return Err(error(cursor.line(), "missing NEURON block"));
+    }
+    Ok(NmodlMechanism { title, blocks })
+}
+
+/// Remove comments, VERBATIM code and unit switches, keeping line breaks so
+/// errors report source lines. Returns the TITLE and the remaining text.
+fn strip(src: &str) -> (Option<String>, String) {
+    let mut title = None;
+    let mut out = String::new();
+    let mut skipping: Option<&str> = None;
+    for line in src.lines() {
+        let trimmed = line.trim();
+        if let Some(end) = skipping {
+            if trimmed.starts_with(end) {
+                skipping = None;
+            }
+        } else if trimmed.starts_with("COMMENT") {
+            skipping = Some("ENDCOMMENT");
+        } else if trimmed.starts_with("VERBATIM") {
+            skipping = Some("ENDVERBATIM");
+        } else if let Some(rest) = trimmed.strip_prefix("TITLE") {
+            title = Some(rest.trim().to_string());
+        } else {
+            let code = line.split([':', '?']).next().unwrap_or("");
+            if !matches!(code.trim(), "UNITSON" | "UNITSOFF") {
+                out.push_str(code);
+            }
+        }
+        out.push('\n');
+    }
+    (title, out)
+}
+
+struct Cursor<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn eof(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn line(&self) -> usize {
+        self.src[..self.pos].matches('\n').count() + 1
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_line(&mut self) {
+        self.pos = self.src[self.pos..].find('\n').map_or(self.src.len(), |k| self.pos + k);
+    }
+
+    fn skip_to(&mut self, c: char) {
+        self.pos = self.src[self.pos..].find(c).map_or(self.src.len(), |k| self.pos + k);
+    }
+
+    fn word(&mut self) -> Option<&'a str> {
+        self.skip_ws();
+        let rest = &self.src[self.pos..];
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        if len == 0 {
+            return None;
+        }
+        self.pos += len;
+        Some(&rest[..len])
+    }
+
+    /// Text between `open` and its matching `close`
+    fn delimited(&mut self, open: char, close: char) -> Result<&'a str> {
+        self.skip_ws();
+        let line = self.line();
+        if self.peek() != Some(open) {
+            return Err(error(line, format!("expected '{}'", open)));
+        }
+        let start = self.pos + 1;
+        let mut depth = 0;
+        for (k, c) in self.src[self.pos..].char_indices() {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    let end = self.pos + k;
+                    self.pos = end + 1;
+                    return Ok(&self.src[start..end]);
+                }
+            }
+        }
+        Err(error(line, format!("missing '{}'", close)))
+    }
+
+    fn braced(&mut self) -> Result<&'a str> {
+        self.delimited('{', '}')
+    }
+
+    fn parenthesized(&mut self) -> Result<&'a str> {
+        self.delimited('(', ')')
+    }
+}
+
+/// Non-empty trimmed lines of a code block
+fn statements(body: &str) -> Vec<String> {
+    body.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect()
+}
+
+/// Argument names, without their units: `v(mV), weight (uS)`
+fn params(list: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in list.chars().chain(std::iter::once(',')) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                let name: String = current.trim()
+                    .chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+                if !name.is_empty() {
+                    names.push(name);
+                }
+                current.clear();
+            }
+            _ if depth == 0 => current.push(c),
+            _ => {}
+        }
+    }
+    names
+}
+
+/// `(mA) = (milliamp)` and `FARADAY = (faraday) (coulomb)` pairs
+fn units(body: &str) -> Vec<(String, String)> {
+    let unwrap = |s: &str| -> String {
+        let s = s.trim();
+        match s.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+            Some(inner) if !inner.contains(['(', ')']) => inner.trim().to_string(),
+            _ => s.to_string(),
+        }
+    };
+    body.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(name, def)| (unwrap(name), unwrap(def)))
+        .collect()
+}
+
+/// Variable declarations: `name [= value] [(units)] [<lo, hi>]`, several per
+/// line allowed. Array sizes and `FROM a TO b` bounds are skipped.
+fn declarations(body: &str, first_line: usize) -> Result<Vec<NmodlVariable>> {
+    let mut cursor = Cursor { src: body, pos: 0 };
+    let mut vars: Vec<NmodlVariable> = vec![];
+    let number = |cursor: &mut Cursor| -> Result<f64> {
+        cursor.skip_ws();
+        let rest = &cursor.src[cursor.pos..];
+        let mut len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+')))
+            .unwrap_or(rest.len());
+        // Stop a sign that follows the mantissa unless it is an exponent
+        if let Some(k) = rest[1.min(len)..len].find(['-', '+']) {
+            let k = k + 1.min(len);
+            if !matches!(rest.as_bytes()[k - 1], b'e' | b'E') {
+                len = k;
+            }
+        }
+        let line = first_line + cursor.line() - 1;
+        let x = rest[..len].parse()
+            .map_err(|_| error(line, format!("invalid number '{}'", &rest[..len])))?;
+        cursor.pos += len;
+        Ok(x)
+    };
+    loop {
+        cursor.skip_ws();
+        let line = first_line + cursor.line() - 1;
+        match cursor.peek() {
+            None => return Ok(vars),
+            Some('=') => {
+                cursor.pos += 1;
+                let var = vars.last_mut().ok_or_else(|| error(line, "value without a name"))?;
+                var.default = Some(number(&mut cursor)?);
+            }
+            Some('(') => {
+                let units = cursor.parenthesized()?.trim().to_string();
+                let var = vars.last_mut().ok_or_else(|| error(line, "units without a name"))?;
+                var.units = Some(units);
+            }
+            Some('<') => {
+                let limits = cursor.delimited('<', '>')?;
+                let (lo, hi) = limits.split_once(',').ok_or_else(|| error(line, "limits need '<lo, hi>'"))?;
+                let parse = |s: &str| s.trim().parse::<f64>()
+                    .map_err(|_| error(line, format!("invalid limit '{}'", s.trim())));
+                let var = vars.last_mut().ok_or_else(|| error(line, "limits without a name"))?;
+                var.range = Some((parse(lo)?, parse(hi)?));
+            }
+            Some('[') => {
+                cursor.delimited('[', ']')?;
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = cursor.word().unwrap_or_default();
+                if matches!(name, "FROM" | "TO" | "WITH") {
+                    number(&mut cursor)?;
+                } else {
+                    vars.push(NmodlVariable { name: name.to_string(), default: None, units: None, range: None });
+                }
+            }
+            Some(c) => return Err(error(line, format!("unexpected '{}' in declarations", c))),
+        }
+    }
+}
+
+/// NEURON block: interface declarations
+fn neuron(body: &str, line: usize) -> Result<NmodlBlock> {
+    let words: Vec<&str> = body.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mut kind = None;
+    let mut useion = vec![];
+    let (mut range, mut global, mut pointer, mut nonspecific) = (vec![], vec![], vec![], vec![]);
+    let mut i = 0;
+    while i < words.len() {
+        let keyword = words[i];
+        if !NEURON_KEYWORDS.contains(&keyword) {
+            return Err(error(line, format!("unexpected '{}' in NEURON block", keyword)));
+        }
+        let start = i + 1;
+        i = start;
+        while i < words.len() && !NEURON_KEYWORDS.contains(&words[i]) {
+            i += 1;
+        }
+        let args = &words[start..i];
+        let names = || args.iter().map(|s| s.to_string());
+        match keyword {
+            "SUFFIX" | "POINT_PROCESS" | "ARTIFICIAL_CELL" => {
+                let name = args.first().ok_or_else(|| error(line, format!("{} needs a name", keyword)))?;
+                let mechanism_type = match keyword {
+                    "SUFFIX" => MechanismType::Suffix,
+                    "POINT_PROCESS" => MechanismType::PointProcess,
+                    _ => MechanismType::ArtificialCell,
+                };
+                kind = Some((mechanism_type, name.to_string()));
+            }
+            "USEION" => {
+                let (ion, rest) = args.split_first().ok_or_else(|| error(line, "USEION needs an ion"))?;
+                let mut ion = UseIon { ion: ion.to_string(), read: vec![], write: vec![], valence: None };
+                let mut mode = "";
+                let mut k = 0;
+                while k < rest.len() {
+                    match rest[k] {
+                        "READ" | "WRITE" => mode = rest[k],
+                        "VALENCE" => {
+                            k += 1;
+                            let valence = rest.get(k).and_then(|v| v.parse().ok())
+                                .ok_or_else(|| error(line, "VALENCE needs an integer"))?;
+                            ion.valence = Some(valence);
+                        }
+                        name if mode == "READ" => ion.read.push(name.to_string()),
+                        name if mode == "WRITE" => ion.write.push(name.to_string()),
+                        name => return Err(error(line, format!("unexpected '{}' in USEION", name))),
+                    }
+                    k += 1;
+                }
+                useion.push(ion);
+            }
+            "RANGE" => range.extend(names()),
+            "GLOBAL" => global.extend(names()),
+            "POINTER" => pointer.extend(names()),
+            "NONSPECIFIC_CURRENT" => nonspecific.extend(names()),
+            _ => {}
+        }
+    }
+    let (mechanism_type, suffix) = kind
+        .ok_or_else(|| error(line, "NEURON block needs SUFFIX, POINT_PROCESS or ARTIFICIAL_CELL"))?;
+    Ok(NmodlBlock::Neuron {
+        mechanism_type,
+        suffix,
+        useion,
+        range,
+        global,
+        pointer,
+        nonspecific_current: nonspecific,
+    })
+}
+
+/// Calcium decay (cad.mod, Destexhe et al. 1994): calcium entering
+/// through `ica` fills a shell of `depth` under the membrane and is pumped
+/// back to `cainf` with time constant `taur`. Load it with
+/// [`NeuronSimulation::load_nmodl`](crate::NeuronSimulation::load_nmodl)
+/// and `insert cad`.
+pub const CADECAY_MOD: &str = r#"TITLE cad.mod   calcium decay in a submembrane shell
+
+NEURON {
+    SUFFIX cad
+    USEION ca READ ica, cai WRITE cai
+    RANGE depth, taur, cainf, drive
+}
+
+PARAMETER {
+    depth = 0.1 (um)
+    taur = 200 (ms)
+    cainf = 1e-4 (mM)
+}
+
+STATE { cai (mM) }
+
+ASSIGNED {
+    ica (mA/cm2)
+    drive (mM/ms)
+}
+
+BREAKPOINT {
+    SOLVE state METHOD derivimplicit
+}
+
+INITIAL {
+    cai = cainf
+}
+
+DERIVATIVE state {
+    : 1e4 converts ica / depth to mM/ms; 96485.309 is Faraday's constant
+    drive = -1e4 * ica / (2 * 96485.309 * depth)
+    if (drive <= 0) { drive = 0 }
+    cai' = drive + (cainf - cai) / taur
+}
+"#;
+
+/// hh.mod from the NEURON distribution
+#[cfg(test)]
+pub(crate) const HH_MOD: &str = r#"TITLE hh.mod   squid sodium, potassium, and leak channels
+
+COMMENT
+ This is the original Hodgkin-Huxley treatment for the set of sodium,
+  potassium, and leakage channels found in the squid giant axon membrane.
+  ("A quantitative description of membrane current and its application
+  conduction and excitation in nerve" J.Physiol. (Lond.) 117:500-544 (1952).)
+ Membrane voltage is in absolute mV and has been reversed in polarity
+  from the original HH convention and shifted to reflect a resting potential
+  of -65 mV.
+ENDCOMMENT
+
+UNITS {
+    (mA) = (milliamp)
+    (mV) = (millivolt)
+	(S) = (siemens)
+}
+
+? interface
+NEURON {
+    SUFFIX hh
+    USEION na READ ena WRITE ina
+    USEION k READ ek WRITE ik
+    NONSPECIFIC_CURRENT il
+    RANGE gnabar, gkbar, gl, el, gna, gk
+    :GLOBAL minf, hinf, ninf, mtau, htau, ntau
+    RANGE minf, hinf, ninf, mtau, htau, ntau
+	THREADSAFE : assigned GLOBALs will be per thread
+}
+
+PARAMETER {
+    gnabar = .12 (S/cm2)	<0,1e9>
+    gkbar = .036 (S/cm2)	<0,1e9>
+    gl = .0003 (S/cm2)	<0,1e9>
+    el = -54.3 (mV)
+}
+
+STATE {
+    m h n
+}
+
+ASSIGNED {
+    v (mV)
+    celsius (degC)
+    ena (mV)
+    ek (mV)
+
+	gna (S/cm2)
+	gk (S/cm2)
+    ina (mA/cm2)
+    ik (mA/cm2)
+    il (mA/cm2)
+    minf hinf ninf
+	mtau (ms) htau (ms) ntau (ms)
+}
+
+? currents
+BREAKPOINT {
+    SOLVE states METHOD cnexp
+    gna = gnabar*m*m*m*h
+	ina = gna*(v - ena)
+    gk = gkbar*n*n*n*n
+	ik = gk*(v - ek)
+    il = gl*(v - el)
+}
+
+
+INITIAL {
+	rates(v)
+	m = minf
+	h = hinf
+	n = ninf
+}
+
+? states
+DERIVATIVE states {
+    rates(v)
+    m' =  (minf-m)/mtau
+    h' = (hinf-h)/htau
+    n' = (ninf-n)/ntau
+}
+
+:LOCAL q10
+
+
+? rates
+PROCEDURE rates(v(mV)) {  :Computes rate and other constants at current v.
+                  :Call once from HOC to initialize inf at resting v.
+    LOCAL  alpha, beta, sum, q10
+    TABLE minf, mtau, hinf, htau, ninf, ntau DEPEND celsius FROM -100 TO 100 WITH 200
+
+UNITSOFF
+    q10 = 3^((celsius - 6.3)/10)
+            :"m" sodium activation system
+    alpha = .1 * vtrap(-(v+40),10)
+    beta =  4 * exp(-(v+65)/18)
+    sum = alpha + beta
+	mtau = 1/(q10*sum)
+    minf = alpha/sum
+            :"h" sodium inactivation system
+    alpha = .07 * exp(-(v+65)/20)
+    beta = 1 / (exp(-(v+35)/10) + 1)
+    sum = alpha + beta
+	htau = 1/(q10*sum)
+    hinf = alpha/sum
+            :"n" potassium activation system
+    alpha = .01*vtrap(-(v+55),10)
+    beta = .125*exp(-(v+65)/80)
+	sum = alpha + beta
+    ntau = 1/(q10*sum)
+    ninf = alpha/sum
+}
+
+FUNCTION vtrap(x,y) {  :Traps for 0 in denominator of rate eqns.
+    if (fabs(x/y) < 1e-6) {
+            vtrap = y*(1 - x/y/2)
+    }else{
+            vtrap = x/(exp(x/y) - 1)
+    }
+}
+
+UNITSON
+"#;
+
+/// expsyn.mod from the NEURON distribution
+#[cfg(test)]
+pub(crate) const EXPSYN_MOD: &str = r#"NEURON {
+	POINT_PROCESS ExpSyn
+	RANGE tau, e, i
+	NONSPECIFIC_CURRENT i
+}
+
+UNITS {
+	(nA) = (nanoamp)
+	(mV) = (millivolt)
+	(uS) = (microsiemens)
+}
+
+PARAMETER {
+	tau = 0.1 (ms) <1e-9,1e9>
+	e = 0	(mV)
+}
+
+ASSIGNED {
+	v (mV)
+	i (nA)
+}
+
+STATE {
+	g (uS)
+}
+
+INITIAL {
+	g=0
+}
+
+BREAKPOINT {
+	SOLVE state METHOD cnexp
+	i = g*(v - e)
+}
+
+DERIVATIVE state {
+	g' = -g/tau
+}
+
+NET_RECEIVE(weight (uS)) {
+	g = g + weight
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hh_mod() {
+        let mech = parse(HH_MOD).unwrap();
+        assert_eq!(mech.title.as_deref(), Some("hh.mod   squid sodium, potassium, and leak channels"));
+        assert_eq!(mech.blocks.len(), 10);
+
+        let NmodlBlock::Neuron { mechanism_type, suffix, useion, range, nonspecific_current, .. } = &mech.blocks[1]
+        else { panic!("expected NEURON block, got {:?}", mech.blocks[1]) };
+        assert_eq!(*mechanism_type, MechanismType::Suffix);
+        assert_eq!(suffix, "hh");
+        assert_eq!(useion.len(), 2);
+        assert_eq!((useion[0].ion.as_str(), &useion[0].read[..], &useion[0].write[..]),
+            ("na", &["ena".to_string()][..], &["ina".to_string()][..]));
+        assert_eq!(range.len(), 12);
+        assert_eq!(nonspecific_current, &["il"]);
+
+        let NmodlBlock::Units(units) = &mech.blocks[0] else { panic!() };
+        assert_eq!(units[2], ("S".to_string(), "siemens".to_string()));
+
+        let NmodlBlock::Parameter(params) = &mech.blocks[2] else { panic!() };
+        assert_eq!(params[0].name, "gnabar");
+        assert_eq!(params[0].default, Some(0.12));
+        assert_eq!(params[0].units.as_deref(), Some("S/cm2"));
+        assert_eq!(params[0].range, Some((0.0, 1e9)));
+        assert_eq!(params[3].default, Some(-54.3));
+
+        let NmodlBlock::State(states) = &mech.blocks[3] else { panic!() };
+        assert_eq!(states, &["m", "h", "n"]);
+        let NmodlBlock::Assigned(assigned) = &mech.blocks[4] else { panic!() };
+        assert_eq!(assigned.len(), 15);
+        assert_eq!(assigned[14].name, "ntau");
+        assert_eq!(assigned[14].units.as_deref(), Some("ms"));
+
+        let NmodlBlock::Derivative { name, equations } = &mech.blocks[7] else { panic!() };
+        assert_eq!(name, "states");
+        assert_eq!(equations[1], "m' =  (minf-m)/mtau");
+        let NmodlBlock::Procedure { name, params, body } = &mech.blocks[8] else { panic!() };
+        assert_eq!((name.as_str(), &params[..]), ("rates", &["v".to_string()][..]));
+        assert_eq!(body[2], "q10 = 3^((celsius - 6.3)/10)");
+        assert!(body.iter().all(|l| l != "UNITSOFF"));
+        let NmodlBlock::Function { name, params, body } = &mech.blocks[9] else { panic!() };
+        assert_eq!((name.as_str(), params.len(), body.len()), ("vtrap", 2, 5));
+    }
+
+    #[test]
+    fn test_parse_expsyn_mod() {
+        let mech = parse(EXPSYN_MOD).unwrap();
+        assert_eq!(mech.title, None);
+        let NmodlBlock::Neuron { mechanism_type, suffix, range, .. } = &mech.blocks[0] else { panic!() };
+        assert_eq!((*mechanism_type, suffix.as_str()), (MechanismType::PointProcess, "ExpSyn"));
+        assert_eq!(range, &["tau", "e", "i"]);
+        let NmodlBlock::Parameter(params) = &mech.blocks[2] else { panic!() };
+        assert_eq!((params[1].name.as_str(), params[1].default), ("e", Some(0.0)));
+        let NmodlBlock::NetReceive { params, body } = &mech.blocks[8] else { panic!() };
+        assert_eq!(params, &["weight"]);
+        assert_eq!(body, &["g = g + weight"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("PARAMETER { a = 1 }").is_err());
+        let err = parse("NEURON { SUFFIX x }\n\nSTATE {\n m\n").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+        assert!(parse("NEURON { SUFFIX x }\nFOO { }").is_err());
+        assert!(parse("NEURON { SUFFIX x USEION ca READ cai VALENCE two }").is_err());
+    }
+}
@@ -0,0 +1,404 @@
+//! Multithreaded fixed-step integration
+//!
+//! Cells interact only through network events, and an event arrives at
+//! least the shortest connection delay after the spike that sends it. As
+//! with NEURON's `ParallelContext.nthread`, the cells are dealt into
+//! contiguous blocks of about equal numbers of segments, one per thread,
+//! and each thread integrates its block on its own for that delay: it
+//! delivers the events of its cells, solves their Hines matrices, watches
+//! their thresholds and plays and records their variables. Spikes are
+//! exchanged at the end of each interval. Every event delivered within an
+//! interval is queued before it starts, so the results are those of
+//! single-threaded steps. Events for artificial cells are delivered on the
+//! main thread as each interval starts.
+//!
+//! Runs with CVODE, reaction-diffusion or LFP recordings, which couple the
+//! cells at every step, and connections shorter than `dt` stay on one
+//! thread. Cells are not split between threads.
+
+use crate::netcon::{Delivery, NetCon, NetSource, NetTarget};
+use crate::vector::{Play, Record, Variable};
+use crate::{cable, mechanism, NeuronCell, NeuronSimulation, Threshold};
+use oldies_core::{OldiesError, Result, Time};
+
+/// A block of cells and what belongs to them, integrated by one thread
+struct Block<'a> {
+    /// Index of the first cell
+    offset: usize,
+    cells: &'a mut [NeuronCell],
+    /// Events to deliver as (step, cell in block, point process, weight)
+    events: Vec<(usize, usize, usize, f64)>,
+    /// Connections and thresholds watching the cells, by index
+    netcons: Vec<(usize, NetCon)>,
+    thresholds: Vec<(usize, Threshold)>,
+    /// Waveforms played into the cells, indexed within the block
+    plays: Vec<Play>,
+    /// Records of the cells by index, indexed within the block
+    records: Vec<(usize, Record)>,
+    /// Crossings found as (step, 0 for thresholds, 1 for APCounts or 2 for
+    /// connections, index, point process index of APCounts, time)
+    crossings: Vec<(usize, u8, usize, usize, Time)>,
+    /// Samples by record index
+    samples: Vec<(usize, f64)>,
+}
+
+impl Block<'_> {
+    /// Integrate the block over the steps starting at `starts`
+    fn integrate(&mut self, starts: &[Time], dt: Time, celsius: f64, library: &mechanism::MechanismLibrary) -> Result<()> {
+        let mut events = self.events.iter().peekable();
+        for (step, &t) in starts.iter().enumerate() {
+            while let Some(&(_, cell, index, weight)) = events.next_if(|e| e.0 == step) {
+                let pp = self.cells[cell].point_processes.get_mut(index)
+                    .ok_or_else(|| OldiesError::ModelNotFound(format!(
+                        "Point process {} of cell {}", index, cell + self.offset
+                    )))?;
+                cable::net_receive(pp, weight)?;
+            }
+            for cell in self.cells.iter_mut() {
+                cable::advance(cell, t, dt, celsius, library)?;
+            }
+            let t = t + dt;
+
+            for (i, th) in &mut self.thresholds {
+                let v = voltage(self.cells, th.cell - self.offset, &th.section, th.loc)?;
+                if let Some(crossing) = th.crossing(t, v) {
+                    self.crossings.push((step, 0, *i, 0, crossing));
+                }
+            }
+            for (c, cell) in self.cells.iter_mut().enumerate() {
+                for index in cable::count_spikes(cell, t)? {
+                    self.crossings.push((step, 1, c + self.offset, index, t));
+                }
+            }
+            for (i, nc) in &mut self.netcons {
+                let NetSource::Voltage { cell, section, loc } = &nc.source else { continue };
+                let v = voltage(self.cells, cell - self.offset, section, *loc)?;
+                if let Some(crossing) = nc.crossing(t, v) {
+                    self.crossings.push((step, 2, *i, 0, crossing));
+                }
+            }
+            for play in &self.plays {
+                if let Some(x) = play.value_at(t + dt / 2.0) {
+                    play.variable.set(self.cells, x)?;
+                }
+            }
+            for (i, record) in &mut self.records {
+                if record.due(t, dt / 2.0) {
+                    self.samples.push((*i, record.variable.get(self.cells, t)?));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn voltage(cells: &[NeuronCell], cell: usize, section: &str, loc: f64) -> Result<f64> {
+    cells.get(cell)
+        .and_then(|c| c.sections.get(section))
+        .map(|s| s.v_at(loc))
+        .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", section)))
+}
+
+/// Number of cells in each of at most `threads` contiguous blocks holding
+/// about equal numbers of segments
+fn partition(cells: &[NeuronCell], threads: usize) -> Vec<usize> {
+    let weights: Vec<usize> = cells.iter().map(|c| c.sections.values().map(|s| s.nseg).sum::<usize>().max(1)).collect();
+    let total: usize = weights.iter().sum();
+    let mut sizes = vec![];
+    let (mut size, mut filled) = (0, 0);
+    for w in weights {
+        size += 1;
+        filled += w;
+        if filled * threads >= total * (sizes.len() + 1) {
+            sizes.push(size);
+            size = 0;
+        }
+    }
+    if size > 0 {
+        sizes.push(size);
+    }
+    sizes
+}
+
+impl NeuronSimulation {
+    /// Steps that the next interval of a multithreaded run covers, or
+    /// `None` if it must run on one thread
+    pub(crate) fn thread_steps(&self) -> Option<usize> {
+        if self.threads < 2
+            || self.cells.len() < 2
+            || self.cvode.active
+            || !self.rxd.is_empty()
+            || self.records.iter().any(|r| matches!(r.variable, Variable::Lfp(_)))
+            || self.plays.iter().any(|p| p.variable.cell().is_none())
+        {
+            return None;
+        }
+        let delay = self.netcons.iter()
+            .filter(|nc| nc.target.is_some())
+            .map(|nc| nc.delay)
+            .fold(f64::INFINITY, f64::min);
+        let most = (delay / self.dt + 1e-9).floor();
+        if most < 1.0 {
+            return None;
+        }
+        let mut t = self.t;
+        let mut steps = 0;
+        while self.before_tstop(t) && (steps as f64) < most {
+            t += self.dt;
+            steps += 1;
+        }
+        Some(steps)
+    }
+
+    /// Integrate `steps` fixed steps with the cells shared between threads
+    pub(crate) fn advance_threads(&mut self, steps: usize) -> Result<()> {
+        let dt = self.dt;
+        let mut starts = Vec::with_capacity(steps);
+        let mut t = self.t;
+        for _ in 0..steps {
+            starts.push(t);
+            t += dt;
+        }
+
+        let sizes = partition(&self.cells, self.threads);
+        let mut block_of = Vec::with_capacity(self.cells.len());
+        let mut offsets = Vec::with_capacity(sizes.len());
+        for (b, &size) in sizes.iter().enumerate() {
+            offsets.push(block_of.len());
+            block_of.extend(std::iter::repeat_n(b, size));
+        }
+
+        // Everything due within the interval is already queued, or sent by
+        // artificial cells firing now
+        let mut events = vec![vec![]; sizes.len()];
+        let last = starts[steps - 1] + dt / 2.0;
+        while let Some((te, delivery)) = self.events.pop_until(last) {
+            let i = match delivery {
+                Delivery::NetCon(i) => i,
+                Delivery::SelfEvent(a) => {
+                    self.receive(a, te, None)?;
+                    continue;
+                }
+            };
+            let weight = self.netcons[i].weight;
+            match self.netcons[i].target.clone() {
+                Some(NetTarget::PointProcess { cell, index }) => {
+                    let b = *block_of.get(cell)
+                        .ok_or_else(|| OldiesError::ModelNotFound(format!("Point process {} of cell {}", index, cell)))?;
+                    let step = starts.partition_point(|&s| s + dt / 2.0 < te);
+                    events[b].push((step, cell - offsets[b], index, weight));
+                }
+                Some(NetTarget::Artificial(a)) => self.receive(a, te, Some(weight))?,
+                None => {}
+            }
+        }
+        for block in &mut events {
+            block.sort_by_key(|e| e.0);
+        }
+
+        let mut rest = self.cells.as_mut_slice();
+        let mut blocks = vec![];
+        for ((b, &size), events) in sizes.iter().enumerate().zip(events) {
+            let (cells, tail) = rest.split_at_mut(size);
+            rest = tail;
+            let offset = offsets[b];
+            let mine = |cell: usize| block_of[cell] == b;
+            blocks.push(Block {
+                offset,
+                cells,
+                events,
+                netcons: self.netcons.iter().enumerate()
+                    .filter(|(_, nc)| matches!(nc.source, NetSource::Voltage { cell, .. } if mine(cell)))
+                    .map(|(i, nc)| (i, nc.clone()))
+                    .collect(),
+                thresholds: self.thresholds.iter().enumerate()
+                    .filter(|(_, th)| mine(th.cell))
+                    .map(|(i, th)| (i, th.clone()))
+                    .collect(),
+                plays: self.plays.iter()
+                    .filter(|p| p.variable.cell().is_some_and(mine))
+                    .map(|p| Play { variable: p.variable.shifted(offset), ..p.clone() })
+                    .collect(),
+                records: self.records.iter().enumerate()
+                    .filter(|(_, r)| r.variable.cell().is_some_and(mine))
+                    .map(|(i, r)| (i, Record { variable: r.variable.shifted(offset), ..r.clone() }))
+                    .collect(),
+                crossings: vec![],
+                samples: vec![],
+            });
+        }
+
+        let (celsius, library) = (self.celsius, &self.mechanisms);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = blocks.iter_mut()
+                .map(|block| scope.spawn(|| block.integrate(&starts, dt, celsius, library)))
+                .collect();
+            handles.into_iter().try_for_each(|h| {
+                h.join().map_err(|_| OldiesError::SimulationError("simulation thread panicked".into()))?
+            })
+        })?;
+
+        // Exchange spikes in the order single-threaded steps find them
+        let mut crossings = vec![];
+        let mut samples = vec![vec![]; self.records.len()];
+        for block in blocks {
+            for (i, nc) in block.netcons {
+                self.netcons[i].last = nc.last;
+            }
+            for (i, th) in block.thresholds {
+                self.thresholds[i].last = th.last;
+            }
+            for (i, record) in block.records {
+                self.records[i].next = record.next;
+            }
+            for (i, x) in block.samples {
+                samples[i].push(x);
+            }
+            crossings.extend(block.crossings);
+        }
+        crossings.sort_by_key(|&(step, kind, i, j, _)| (step, kind, i, j));
+        for (_, kind, i, j, crossing) in crossings {
+            match kind {
+                0 => {
+                    let name = self.thresholds[i].name.clone();
+                    self.threshold_events.entry(name).or_default().push(crossing);
+                }
+                1 => self.ap_counted(i, j, crossing),
+                _ => {
+                    let nc = &mut self.netcons[i];
+                    nc.spikes.push(crossing);
+                    if nc.target.is_some() {
+                        self.events.push(crossing + nc.delay, i);
+                    }
+                }
+            }
+        }
+
+        // Time is the same on every thread
+        let every_step = self.records.iter().any(|r| r.interval.is_none());
+        for &start in &starts {
+            let t = start + dt;
+            if every_step {
+                self.recordings.entry("t".to_string()).or_default().push(t);
+            }
+            for (i, record) in self.records.iter_mut().enumerate() {
+                if record.variable == Variable::Time && record.due(t, dt / 2.0) {
+                    samples[i].push(t);
+                }
+            }
+        }
+        for (record, samples) in self.records.iter().zip(samples) {
+            if !samples.is_empty() {
+                self.recordings.entry(record.name.clone()).or_default().extend(samples);
+            }
+        }
+        self.t = t;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netcon::ArtificialCell;
+    use crate::{mechanisms, vector};
+
+    /// Ring of HH cells, each exciting the next through an ExpSyn, started
+    /// by a pulse into the first and relayed once through an IntFire1
+    fn ring(n: usize, threads: usize) -> NeuronSimulation {
+        let mut sim = NeuronSimulation::new();
+        for i in 0..n {
+            let mut cell = NeuronCell::new(&format!("cell{}", i));
+            let soma = cell.create("soma");
+            soma.length = 20.0;
+            soma.diam = 20.0;
+            soma.insert(mechanisms::hh());
+            // Cells of different sizes balance differently
+            let dend = cell.create("dend");
+            dend.set_nseg(1 + i % 4);
+            dend.insert(mechanisms::pas());
+            cell.connect("dend", 0.0, "soma", 1.0).unwrap();
+            cell.add_point_process(mechanisms::exp_syn("soma", 0.5));
+            cell.add_point_process(mechanisms::apcount("soma", 0.5, 0.0));
+            sim.add_cell(cell);
+        }
+        sim.cells[0].add_point_process(mechanisms::iclamp("soma", 0.5, 1.0, 1.0, 1.0));
+        let relay = sim.add_artificial_cell(ArtificialCell::int_fire1());
+        let soma = |cell| NetSource::Voltage { cell, section: "soma".into(), loc: 0.5 };
+        for i in 0..n {
+            let mut nc = NetCon::new(soma(i), Some(NetTarget::PointProcess { cell: (i + 1) % n, index: 0 }));
+            nc.weight = 0.05;
+            nc.delay = 1.5 + 0.25 * i as f64;
+            nc.threshold = 0.0;
+            sim.add_netcon(nc);
+        }
+        let mut nc = NetCon::new(soma(n - 1), Some(NetTarget::Artificial(relay)));
+        nc.weight = 2.0;
+        sim.add_netcon(nc);
+        let mut nc = NetCon::new(NetSource::Artificial(relay), Some(NetTarget::PointProcess { cell: n / 2, index: 0 }));
+        nc.weight = 0.05;
+        sim.add_netcon(nc);
+
+        sim.add_threshold("last", n - 1, "soma", 0.5, 0.0);
+        sim.record_v("v", n / 2, "soma", 0.5);
+        sim.record("m", vector::Variable::Range { cell: 1, section: "soma".into(), loc: 0.5, name: "m_hh".into() }, Some(0.5));
+        sim.record("time", vector::Variable::Time, Some(0.5));
+        let amp = vector::Variable::Point { cell: n - 1, index: 0, name: "tau".into() };
+        sim.play(Play::new(amp, vec![20.0], vec![5.0]).unwrap());
+        sim.threads = threads;
+        sim.tstop = 40.0;
+        sim
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut cells = vec![];
+        for nseg in [4, 1, 1, 1, 1] {
+            let mut cell = NeuronCell::new("cell");
+            cell.create("soma").set_nseg(nseg);
+            cells.push(cell);
+        }
+        assert_eq!(partition(&cells, 2), vec![1, 4]);
+        assert_eq!(partition(&cells, 1), vec![5]);
+        assert_eq!(partition(&cells, 8).iter().sum::<usize>(), 5);
+    }
+
+    #[test]
+    fn test_threads_match_one_thread() {
+        let mut serial = ring(6, 1);
+        serial.finitialize(-65.0).unwrap();
+        serial.run().unwrap();
+        assert!(serial.threshold_events["last"].len() >= 2);
+
+        for threads in [2, 4] {
+            let mut parallel = ring(6, threads);
+            parallel.finitialize(-65.0).unwrap();
+            // The shortest delay, 1 ms, sets the interval
+            assert_eq!(parallel.thread_steps(), Some(40));
+            parallel.run().unwrap();
+            assert_eq!(parallel.t, serial.t);
+            assert_eq!(parallel.recordings, serial.recordings, "{} threads", threads);
+            assert_eq!(parallel.threshold_events, serial.threshold_events);
+            assert_eq!(parallel.raster(), serial.raster());
+            for (a, b) in parallel.netcons.iter().zip(&serial.netcons) {
+                assert_eq!(a.spikes, b.spikes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_threads_stop_with_one_thread() {
+        // tstop falls between steps: both stop at 10 ms after 400 steps
+        let mut serial = ring(6, 1);
+        let mut parallel = ring(6, 2);
+        for sim in [&mut serial, &mut parallel] {
+            sim.tstop = 10.01;
+            sim.finitialize(-65.0).unwrap();
+            sim.run().unwrap();
+        }
+        assert!((serial.t - 10.0).abs() < 1e-9, "t = {}", serial.t);
+        assert_eq!(parallel.t, serial.t);
+        assert_eq!(serial.recordings["v"].len(), 401);
+        assert_eq!(parallel.recordings, serial.recordings);
+    }
+}
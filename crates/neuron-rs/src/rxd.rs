@@ -0,0 +1,493 @@
+//! Reaction-diffusion
+//!
+//! The counterpart of NEURON's `rxd` module for intracellular signalling.
+//! A [`Species`] lives in a [`Region`], a set of sections, and has a
+//! concentration (mM) in every segment of them. Each step it diffuses
+//! along the cable between neighbouring segments, through the cross
+//! section of the neurite, and takes part in mass-action [`Reaction`]s:
+//!
+//! ```text
+//! rate = kf prod [reactant]^n - kb prod [product]^n      (mM/ms)
+//! ```
+//!
+//! Diffusion is advanced with backward Euler on the cable tree and the
+//! reactions of every segment with backward Euler and Newton iterations,
+//! so fast buffers stay stable.
+//!
+//! In a region inside the membrane (`NrnRegion::Inside`) species `x` is
+//! NEURON's `xi`: when `x` is an ion used by the mechanisms of a section,
+//! its current `ix` flows into the segment and the concentration sets the
+//! reversal potential `ex`, as an accumulation mechanism would. The
+//! concentrations are kept in the states of an `rxd` mechanism of each
+//! section, under the species name with the region's suffix.
+//!
+//! Units: um, ms, mM; diffusion constants in um^2/ms.
+
+use crate::cable::CableTree;
+use crate::{InsertedMechanism, NeuronCell, Section};
+use oldies_core::{OldiesError, Result, Time};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Faraday constant (C/mol)
+const FARADAY: f64 = 96485.33212;
+
+/// Newton iterations of the reaction step
+const NEWTON_ITERATIONS: usize = 4;
+
+/// Side of the membrane a region lies on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NrnRegion {
+    /// Cytosol: species `x` is the concentration `xi`
+    Inside,
+    /// Outside: species `x` is the concentration `xo`
+    Outside,
+}
+
+/// Sections a species lives in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Region {
+    /// Section names; empty for every section
+    pub sections: Vec<String>,
+    pub nrn_region: Option<NrnRegion>,
+}
+
+impl Region {
+    /// Region over `sections` (all sections if empty)
+    pub fn new(sections: &[&str], nrn_region: Option<NrnRegion>) -> Self {
+        Self { sections: sections.iter().map(|s| s.to_string()).collect(), nrn_region }
+    }
+
+    fn contains(&self, section: &str) -> bool {
+        self.sections.is_empty() || self.sections.iter().any(|s| s == section)
+    }
+}
+
+/// Diffusing chemical species
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Species {
+    pub name: String,
+    /// Index of its region
+    pub region: usize,
+    /// Diffusion constant (um^2/ms)
+    pub d: f64,
+    /// Concentration at `finitialize` (mM)
+    pub initial: f64,
+    /// Charge, for the membrane current of ions
+    pub charge: i32,
+}
+
+/// Mass-action reaction between species, with stoichiometries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub reactants: Vec<(usize, u32)>,
+    pub products: Vec<(usize, u32)>,
+    /// Forward rate constant (mM^(1 - order) / ms)
+    pub kf: f64,
+    /// Backward rate constant (mM^(1 - order) / ms)
+    pub kb: f64,
+}
+
+/// Species, their regions and reactions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rxd {
+    pub regions: Vec<Region>,
+    pub species: Vec<Species>,
+    pub reactions: Vec<Reaction>,
+}
+
+/// Cable geometry of diffusion in one cell
+struct Geometry {
+    tree: CableTree,
+    /// Volume of every node (um^3)
+    volume: Vec<f64>,
+    /// Cross section over length between each node and its parent (um)
+    coupling: Vec<f64>,
+}
+
+/// Integral of 4 / (pi d^2) along `sec` between `from` and `to` (1/um)
+fn inverse_cross_section(sec: &Section, from: f64, to: f64) -> f64 {
+    sec.integrate(from, to, |l, d0, d1| 4.0 * l / (std::f64::consts::PI * d0 * d1))
+}
+
+impl Geometry {
+    fn new(cell: &NeuronCell) -> Result<Self> {
+        let tree = CableTree::new(cell)?;
+        let mut volume = vec![0.0; tree.len()];
+        let mut coupling = vec![0.0; tree.len()];
+        for (i, (name, k)) in tree.nodes.iter().enumerate() {
+            let sec = &cell.sections[name];
+            let n = sec.nseg as f64;
+            let center = |k: usize| (k as f64 + 0.5) / n;
+            volume[i] = sec.integrate(*k as f64 / n, (*k + 1) as f64 / n, |l, d0, d1| {
+                std::f64::consts::PI * l * (d0 * d0 + d0 * d1 + d1 * d1) / 12.0
+            });
+            let Some(p) = tree.parent[i] else { continue };
+            let (parent, kp) = &tree.nodes[p];
+            let resistance = if parent == name {
+                inverse_cross_section(sec, center(*kp), center(*k))
+            } else {
+                let (_, loc) = sec.parent.as_ref().unwrap();
+                let end = sec.connection_end;
+                inverse_cross_section(sec, end, (end - 0.5 / n).abs())
+                    + inverse_cross_section(&cell.sections[parent], *loc, center(*kp))
+            };
+            coupling[i] = 1.0 / resistance;
+        }
+        Ok(Self { tree, volume, coupling })
+    }
+}
+
+/// Solve `a x = b` in place by Gaussian elimination with partial pivoting
+pub(crate) fn solve_dense(a: &mut [Vec<f64>], b: &mut [f64]) {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs())).unwrap();
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (top, bottom) = a.split_at_mut(col + 1);
+        let pivot_row = &top[col];
+        for (offset, row) in bottom.iter_mut().enumerate() {
+            let f = row[col] / pivot_row[col];
+            for (x, p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= f * p;
+            }
+            b[col + 1 + offset] -= f * b[col];
+        }
+    }
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|c| a[row][c] * b[c]).sum();
+        b[row] = (b[row] - s) / a[row][row];
+    }
+}
+
+impl Rxd {
+    /// Add a region, returning its index
+    pub fn add_region(&mut self, region: Region) -> usize {
+        self.regions.push(region);
+        self.regions.len() - 1
+    }
+
+    /// Add a species of charge `charge` diffusing with `d` (um^2/ms) in
+    /// region `region` from concentration `initial` (mM), returning its
+    /// index
+    pub fn add_species(&mut self, name: &str, region: usize, d: f64, initial: f64, charge: i32) -> usize {
+        self.species.push(Species { name: name.to_string(), region, d, initial, charge });
+        self.species.len() - 1
+    }
+
+    /// Add the reaction `reactants <-> products` with rate constants `kf`
+    /// and `kb`, species given as (index, stoichiometry)
+    pub fn add_reaction(&mut self, reactants: &[(usize, u32)], products: &[(usize, u32)], kf: f64, kb: f64) {
+        self.reactions.push(Reaction { reactants: reactants.to_vec(), products: products.to_vec(), kf, kb });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.species.is_empty()
+    }
+
+    fn region(&self, species: &Species) -> Result<&Region> {
+        self.regions.get(species.region).ok_or_else(|| {
+            OldiesError::ModelNotFound(format!("Region {} of species {}", species.region, species.name))
+        })
+    }
+
+    /// Name of the concentration of a species: `cai` for `ca` inside
+    pub fn state_name(&self, species: usize) -> Result<String> {
+        let s = &self.species[species];
+        Ok(match self.region(s)?.nrn_region {
+            Some(NrnRegion::Inside) => format!("{}i", s.name),
+            Some(NrnRegion::Outside) => format!("{}o", s.name),
+            None => s.name.clone(),
+        })
+    }
+
+    /// Concentration (mM) of species `species` at `section(loc)`
+    pub fn concentration(&self, cell: &NeuronCell, species: usize, section: &str, loc: f64) -> Option<f64> {
+        let sec = cell.sections.get(section)?;
+        let name = self.state_name(species).ok()?;
+        let mech = sec.mechanisms.iter().find(|m| m.name == "rxd")?;
+        mech.state.get(&name)?.get(sec.segment(loc)).copied()
+    }
+
+    /// Set every species to its initial concentration
+    pub(crate) fn initialize(&self, cells: &mut [NeuronCell]) -> Result<()> {
+        for cell in cells {
+            for sec in cell.sections.values_mut() {
+                sec.mechanisms.retain(|m| m.name != "rxd");
+                let mut state = HashMap::new();
+                for (j, species) in self.species.iter().enumerate() {
+                    if self.region(species)?.contains(&sec.name) {
+                        state.insert(self.state_name(j)?, vec![species.initial; sec.nseg]);
+                    }
+                }
+                if !state.is_empty() {
+                    sec.insert(InsertedMechanism {
+                        name: "rxd".to_string(),
+                        parameters: HashMap::new(),
+                        state,
+                        segment_parameters: HashMap::new(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Concentration of every species at every node of `geometry`, NaN
+    /// outside its region
+    fn gather(&self, cell: &NeuronCell, geometry: &Geometry) -> Result<Vec<Vec<f64>>> {
+        let names: Vec<String> = (0..self.species.len()).map(|j| self.state_name(j)).collect::<Result<_>>()?;
+        Ok(names
+            .iter()
+            .map(|name| {
+                geometry.tree.nodes.iter().map(|(section, k)| {
+                    cell.sections[section].mechanisms.iter()
+                        .find(|m| m.name == "rxd")
+                        .and_then(|m| m.state.get(name))
+                        .and_then(|x| x.get(*k).copied())
+                        .unwrap_or(f64::NAN)
+                }).collect()
+            })
+            .collect())
+    }
+
+    /// Rate of change (mM/ms) of every species from the reactions at
+    /// concentrations `c`, of those reactions whose species are all present
+    fn reaction_rates(&self, c: &[f64]) -> Vec<f64> {
+        let mut rates = vec![0.0; c.len()];
+        for r in &self.reactions {
+            if r.reactants.iter().chain(&r.products).any(|&(s, _)| c[s].is_nan()) {
+                continue;
+            }
+            let product = |side: &[(usize, u32)]| side.iter().map(|&(s, n)| c[s].powi(n as i32)).product::<f64>();
+            let rate = r.kf * product(&r.reactants) - r.kb * product(&r.products);
+            for &(s, n) in &r.reactants {
+                rates[s] -= n as f64 * rate;
+            }
+            for &(s, n) in &r.products {
+                rates[s] += n as f64 * rate;
+            }
+        }
+        rates
+    }
+
+    /// Advance the reactions of one segment over `dt` with backward Euler.
+    /// Species absent from the segment (NaN) take no part.
+    fn react(&self, c: &mut [f64], dt: Time) {
+        let local: Vec<usize> = (0..c.len()).filter(|&j| !c[j].is_nan()).collect();
+        if local.is_empty() {
+            return;
+        }
+        let old = c.to_vec();
+        let mut x = old.clone();
+        for _ in 0..NEWTON_ITERATIONS {
+            // Newton on x - old - dt R(x) = 0
+            let f0 = self.reaction_rates(&x);
+            let mut jac = vec![vec![0.0; local.len()]; local.len()];
+            for (col, &s) in local.iter().enumerate() {
+                let h = 1e-8 * x[s].abs().max(1e-9);
+                let mut y = x.clone();
+                y[s] += h;
+                let f1 = self.reaction_rates(&y);
+                for (row, &r) in local.iter().enumerate() {
+                    jac[row][col] = if r == s { 1.0 } else { 0.0 } - dt * (f1[r] - f0[r]) / h;
+                }
+            }
+            let mut residual: Vec<f64> = local.iter().map(|&s| old[s] + dt * f0[s] - x[s]).collect();
+            solve_dense(&mut jac, &mut residual);
+            for (row, &s) in local.iter().enumerate() {
+                x[s] += residual[row];
+            }
+        }
+        for &s in &local {
+            c[s] = x[s];
+        }
+    }
+
+    /// Advance concentrations over `dt`: membrane influx and diffusion,
+    /// then reactions
+    pub(crate) fn advance(&self, cells: &mut [NeuronCell], dt: Time) -> Result<()> {
+        for cell in cells {
+            let geometry = Geometry::new(cell)?;
+            let tree = &geometry.tree;
+            let n = tree.len();
+            let mut c = self.gather(cell, &geometry)?;
+
+            for (j, species) in self.species.iter().enumerate() {
+                let inside = self.region(species)?.nrn_region == Some(NrnRegion::Inside);
+                let present: Vec<bool> = c[j].iter().map(|x| !x.is_nan()).collect();
+                let mut d = vec![1.0; n];
+                let mut a = vec![0.0; n];
+                let mut rhs = vec![0.0; n];
+                for (i, (section, k)) in tree.nodes.iter().enumerate() {
+                    if !present[i] {
+                        continue;
+                    }
+                    let volume = geometry.volume[i];
+                    d[i] = volume / dt;
+                    rhs[i] = volume / dt * c[j][i];
+                    // Outward current (mA/cm^2) of the ion leaves the cytosol
+                    let ion = format!("{}_ion", species.name);
+                    let current = cell.sections[section].mechanisms.iter()
+                        .find(|m| m.name == ion)
+                        .and_then(|m| m.state.get(&format!("i{}", species.name)))
+                        .and_then(|x| x.get(*k).copied());
+                    if let (true, Some(current)) = (inside, current) {
+                        // mA/cm^2 * cm^2 / (z F) is mmol/s, i.e. 1e12 um^3 mM/ms
+                        let flux = -current * tree.area[i] / (species.charge as f64 * FARADAY) * 1e12;
+                        rhs[i] += flux;
+                    }
+                }
+                for i in 0..n {
+                    if let Some(p) = tree.parent[i] {
+                        if present[i] && present[p] {
+                            let g = species.d * geometry.coupling[i];
+                            d[i] += g;
+                            d[p] += g;
+                            a[i] = -g;
+                        }
+                    }
+                }
+                tree.solve(&mut d, &a, &mut rhs);
+                for i in (0..n).filter(|&i| present[i]) {
+                    c[j][i] = rhs[i];
+                }
+            }
+
+            if !self.reactions.is_empty() {
+                for i in 0..n {
+                    let mut local: Vec<f64> = c.iter().map(|cj| cj[i]).collect();
+                    self.react(&mut local, dt);
+                    for (cj, x) in c.iter_mut().zip(local) {
+                        cj[i] = x;
+                    }
+                }
+            }
+
+            for (j, values) in c.iter().enumerate() {
+                let name = self.state_name(j)?;
+                for (i, (section, k)) in tree.nodes.iter().enumerate() {
+                    if values[i].is_nan() {
+                        continue;
+                    }
+                    let sec = cell.sections.get_mut(section).unwrap();
+                    if let Some(mech) = sec.mechanisms.iter_mut().find(|m| m.name == "rxd") {
+                        if let Some(x) = mech.state.get_mut(&name).and_then(|x| x.get_mut(*k)) {
+                            *x = values[i];
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cable, mechanisms, NeuronSimulation};
+
+    /// Calcium leak through a fixed conductance
+    const CALEAK_MOD: &str = "NEURON { SUFFIX caleak USEION ca READ eca WRITE ica RANGE g }
+PARAMETER { g = 0.0005 (S/cm2) }
+ASSIGNED { v (mV) eca (mV) ica (mA/cm2) }
+BREAKPOINT { ica = g*(v - eca) }
+";
+
+    fn state(sim: &NeuronSimulation, section: &str, name: &str) -> Vec<f64> {
+        let sec = &sim.cells[0].sections[section];
+        sec.mechanisms.iter().find(|m| m.name == "rxd").unwrap().state[name].clone()
+    }
+
+    #[test]
+    fn test_rxd_diffusion_spread() {
+        // A pulse in the middle of a long dendrite spreads with variance
+        // 2 D t and keeps its amount
+        let mut sim = NeuronSimulation::new();
+        let mut cell = NeuronCell::new("cell");
+        let dend = cell.create("dend");
+        dend.length = 1005.0;
+        dend.set_nseg(201);
+        sim.add_cell(cell);
+        let all = sim.rxd.add_region(Region::new(&[], None));
+        let x = sim.rxd.add_species("x", all, 1.0, 0.0, 0);
+        sim.finitialize(-65.0).unwrap();
+        let mech = sim.cells[0].sections.get_mut("dend").unwrap().mechanisms.iter_mut().find(|m| m.name == "rxd").unwrap();
+        mech.state.get_mut("x").unwrap()[100] = 1.0;
+        sim.tstop = 100.0;
+        sim.run().unwrap();
+
+        let c = state(&sim, "dend", "x");
+        let mass: f64 = c.iter().sum();
+        assert!((mass - 1.0).abs() < 1e-9, "{}", mass);
+        let variance: f64 = c.iter().enumerate().map(|(k, c)| c * ((k as f64 - 100.0) * 5.0).powi(2)).sum::<f64>() / mass;
+        assert!((variance / 200.0 - 1.0).abs() < 0.01, "variance {}", variance);
+        assert!((c[90] - c[110]).abs() < 1e-12);
+        assert_eq!(sim.rxd.concentration(&sim.cells[0], x, "dend", 0.5), Some(c[100]));
+    }
+
+    #[test]
+    fn test_rxd_buffering() {
+        let mut sim = NeuronSimulation::new();
+        let mut cell = NeuronCell::new("cell");
+        cell.create("soma");
+        sim.add_cell(cell);
+        let cyt = sim.rxd.add_region(Region::new(&["soma"], Some(NrnRegion::Inside)));
+        let ca = sim.rxd.add_species("ca", cyt, 0.6, 1e-3, 2);
+        let buf = sim.rxd.add_species("buf", cyt, 0.0, 0.01, 0);
+        let cabuf = sim.rxd.add_species("cabuf", cyt, 0.0, 0.0, 0);
+        sim.rxd.add_reaction(&[(ca, 1), (buf, 1)], &[(cabuf, 1)], 100.0, 0.1);
+        sim.tstop = 10.0;
+        sim.finitialize(-65.0).unwrap();
+        sim.run().unwrap();
+
+        let (c, b, cb) = (state(&sim, "soma", "cai")[0], state(&sim, "soma", "bufi")[0], state(&sim, "soma", "cabufi")[0]);
+        assert!((c + cb - 1e-3).abs() < 1e-12);
+        assert!((b + cb - 0.01).abs() < 1e-12);
+        assert!((cb / (c * b) / 1000.0 - 1.0).abs() < 1e-3, "{} {} {}", c, b, cb);
+    }
+
+    #[test]
+    fn test_rxd_calcium_influx() {
+        // Calcium entering through the membrane raises cai in the
+        // cytosol, which sets eca for the channel
+        let mut sim = NeuronSimulation::new();
+        sim.load_nmodl(CALEAK_MOD).unwrap();
+        let mut cell = NeuronCell::new("cell");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(sim.mechanisms["caleak"].instance());
+        cell.add_point_process(mechanisms::seclamp("soma", 0.5, &[(100.0, -10.0)]));
+        sim.add_cell(cell);
+        let cyt = sim.rxd.add_region(Region::new(&[], Some(NrnRegion::Inside)));
+        sim.rxd.add_species("ca", cyt, 0.6, 1e-4, 2);
+        sim.finitialize(-10.0).unwrap();
+
+        let sec = &sim.cells[0].sections["soma"];
+        let ion = |sim: &NeuronSimulation, name: &str| {
+            let sec = &sim.cells[0].sections["soma"];
+            sec.mechanisms.iter().find(|m| m.name == "ca_ion").unwrap().state[name][0]
+        };
+        let ica = ion(&sim, "ica");
+        assert!(ica < 0.0);
+        let volume = std::f64::consts::PI * 100.0 * 20.0;
+        let expected = 1e-4 - ica * sec.segment_area(0) * 1e12 / (2.0 * FARADAY * volume) * sim.dt;
+        sim.fadvance().unwrap();
+        let cai = state(&sim, "soma", "cai")[0];
+        assert!((cai / expected - 1.0).abs() < 1e-3, "{} vs {}", cai, expected);
+
+        sim.continuerun(20.0).unwrap();
+        let cai = state(&sim, "soma", "cai")[0];
+        assert!(cai > 1e-3);
+        let eca = cable::nernst(cai, 2.0, 2, sim.celsius);
+        assert_eq!(ion(&sim, "eca"), eca);
+        let caleak = sim.cells[0].sections["soma"].mechanisms.iter().find(|m| m.name == "caleak").unwrap();
+        assert_eq!(caleak.state["eca"][0], eca);
+
+        sim.cvode_active(true);
+        assert!(sim.fadvance().is_err());
+    }
+}
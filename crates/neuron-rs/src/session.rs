@@ -0,0 +1,195 @@
+//! Saving and restoring the setup of a simulation
+//!
+//! A [`Session`] holds everything needed to run a simulation again: the
+//! cells with their mechanisms and point processes, the NMODL mechanisms
+//! they use, connections and artificial cells, reaction-diffusion species,
+//! recorded and played variables, threshold watches and the integration
+//! settings, engine included. Results, pending events and `finitialize`
+//! handlers are not part of it. Written as JSON, conventionally with the
+//! extension `.ses`, it takes the place of NEURON's GUI session files as
+//! the record of how a result was obtained; the `oldies` command line and
+//! GUI both open it.
+//!
+//! NEURON's own `.ses` files are HOC scripts driving its GUI and cannot be
+//! loaded as sessions.
+
+use crate::cvode::Cvode;
+use crate::netcon::{ArtificialCell, NetCon};
+use crate::rxd::Rxd;
+use crate::soa::Engine;
+use crate::vector::{Play, Record};
+use crate::{mechanism, NeuronCell, NeuronSimulation, NmodlMechanism, Threshold};
+use oldies_core::{OldiesError, Result, Time, Voltage};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Version of the session format written
+pub const VERSION: u32 = 1;
+
+/// Setup of a simulation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Format version, at most [`VERSION`] to be loaded
+    pub version: u32,
+    pub cells: Vec<NeuronCell>,
+    /// NMODL mechanisms, compiled again on loading
+    pub mechanisms: Vec<NmodlMechanism>,
+    pub netcons: Vec<NetCon>,
+    pub artificial_cells: Vec<ArtificialCell>,
+    pub rxd: Rxd,
+    pub records: Vec<Record>,
+    pub plays: Vec<Play>,
+    pub thresholds: Vec<Threshold>,
+    pub dt: Time,
+    pub tstop: Time,
+    pub celsius: f64,
+    pub v_init: Voltage,
+    pub cvode: Cvode,
+    pub threads: usize,
+    #[serde(default)]
+    pub engine: Engine,
+}
+
+impl Session {
+    /// Session as JSON
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| OldiesError::SimulationError(format!("Cannot write session: {}", e)))
+    }
+
+    /// Session from JSON
+    pub fn from_json(json: &str) -> Result<Self> {
+        let session: Self = serde_json::from_str(json)
+            .map_err(|e| OldiesError::ParseError(format!("Session: {}", e)))?;
+        if session.version > VERSION {
+            return Err(OldiesError::ParseError(format!(
+                "Session version {} is newer than {}", session.version, VERSION
+            )));
+        }
+        Ok(session)
+    }
+
+    /// Write the session to `path`
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    /// Read a session from `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl NeuronSimulation {
+    /// Setup of the simulation
+    pub fn session(&self) -> Session {
+        let mut mechanisms: Vec<&mechanism::CompiledMechanism> = self.mechanisms.values().collect();
+        mechanisms.sort_by(|a, b| a.name.cmp(&b.name));
+        Session {
+            version: VERSION,
+            cells: self.cells.clone(),
+            mechanisms: mechanisms.into_iter().map(|m| m.model.clone()).collect(),
+            netcons: self.netcons.iter().cloned().map(|mut nc| {
+                nc.spikes.clear();
+                nc
+            }).collect(),
+            artificial_cells: self.artificial_cells.clone(),
+            rxd: self.rxd.clone(),
+            records: self.records.clone(),
+            plays: self.plays.clone(),
+            thresholds: self.thresholds.clone(),
+            dt: self.dt,
+            tstop: self.tstop,
+            celsius: self.celsius,
+            v_init: self.v_init,
+            cvode: self.cvode.clone(),
+            threads: self.threads,
+            engine: self.engine,
+        }
+    }
+
+    /// Simulation set up from `session`, ready for `init`
+    pub fn from_session(session: Session) -> Result<Self> {
+        let mut sim = NeuronSimulation::new();
+        for model in &session.mechanisms {
+            let compiled = mechanism::compile(model)?;
+            sim.mechanisms.insert(compiled.name.clone(), compiled);
+        }
+        sim.cells = session.cells;
+        sim.netcons = session.netcons;
+        sim.artificial_cells = session.artificial_cells;
+        sim.rxd = session.rxd;
+        sim.records = session.records;
+        sim.plays = session.plays;
+        sim.thresholds = session.thresholds;
+        sim.dt = session.dt;
+        sim.tstop = session.tstop;
+        sim.celsius = session.celsius;
+        sim.v_init = session.v_init;
+        sim.cvode = session.cvode;
+        sim.threads = session.threads;
+        sim.engine = session.engine;
+        Ok(sim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netcon::{NetSource, NetTarget};
+    use crate::vector::Variable;
+    use crate::{mechanisms, nmodl};
+
+    /// HH soma compiled from NMODL, driven by a NetStim through an ExpSyn,
+    /// with a played clamp amplitude
+    fn setup() -> NeuronSimulation {
+        let mut sim = NeuronSimulation::new();
+        let hh = sim.load_nmodl(nmodl::HH_MOD).unwrap();
+        let mut cell = NeuronCell::new("cell");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(sim.mechanisms[&hh].instance());
+        cell.add_point_process(mechanisms::exp_syn("soma", 0.5));
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 0.0, 1e9, 0.0));
+        sim.add_cell(cell);
+        let stim = sim.add_artificial_cell(ArtificialCell::net_stim(5.0, 15.0, 3.0, 0.0));
+        let mut nc = NetCon::new(NetSource::Artificial(stim), Some(NetTarget::PointProcess { cell: 0, index: 0 }));
+        nc.weight = 0.02;
+        sim.add_netcon(nc);
+        let amp = Variable::Point { cell: 0, index: 1, name: "amp".into() };
+        sim.play(Play::new(amp, vec![30.0, 35.0], vec![0.2, 0.0]).unwrap());
+        sim.record_v("v", 0, "soma", 0.5);
+        sim.add_threshold("ap", 0, "soma", 0.5, 0.0);
+        sim.tstop = 50.0;
+        sim.celsius = 10.0;
+        sim.v_init = -70.0;
+        sim.cvode.atol = 1e-4;
+        sim
+    }
+
+    #[test]
+    fn test_session_restores_setup() {
+        let mut original = setup();
+        original.init().unwrap();
+        original.run().unwrap();
+        let session = original.session();
+        assert_eq!(session.mechanisms.len(), 1);
+        assert!(session.netcons[0].spikes.is_empty());
+
+        let mut restored = NeuronSimulation::from_session(session).unwrap();
+        assert_eq!((restored.celsius, restored.v_init, restored.cvode.atol), (10.0, -70.0, 1e-4));
+        restored.init().unwrap();
+        restored.run().unwrap();
+        assert!(original.threshold_events["ap"].len() >= 2);
+        assert_eq!(restored.threshold_events, original.threshold_events);
+        assert_eq!(restored.recordings["v"], original.recordings["v"]);
+    }
+
+    #[test]
+    fn test_session_errors() {
+        assert!(Session::from_json("not json").is_err());
+        assert!(matches!(Session::load("/nonexistent/model.ses"), Err(OldiesError::IoError(_))));
+    }
+}
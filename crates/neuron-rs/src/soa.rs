@@ -0,0 +1,628 @@
+//! Structure-of-arrays engine for large networks
+//!
+//! With [`Engine::Soa`] fixed-step runs use a flattened copy of the model,
+//! laid out as CoreNEURON lays it out. The segments of all cells become one
+//! array of nodes in Hines order, cell after cell, whose tree, areas and
+//! axial conductances are computed once per run rather than every step.
+//! Each mechanism type keeps the parameters and states of all its
+//! instances in parallel arrays and is updated in one loop per type: the
+//! currents of every type, the matrix solve of all cells together, then
+//! the states of every type. Currents are linear in `v` for all supported
+//! types, so their slope is the conductance itself rather than a
+//! numerical derivative. Results match the default engine to rounding.
+//!
+//! The engine trades flexibility for speed. It takes the built-in `hh`,
+//! `na`, `k` and `pas` mechanisms, `ExpSyn`, `Exp2Syn` and `IClamp` point
+//! processes, connections, artificial cells, threshold watches and
+//! recordings of time and membrane potential. Anything else (NMODL
+//! mechanisms, clamps, extracellular layers, reaction-diffusion, played
+//! waveforms, other recorded variables) is refused when a run starts, and
+//! such models need [`Engine::Cells`]. Runs with CVODE use the default
+//! engine whatever the setting, and the flattened model runs on one
+//! thread.
+//!
+//! The cells stay the model of record: they are flattened when a run
+//! starts and receive the voltages and states back, with their assigned
+//! variables brought up to date, when it ends. `fadvance` makes that round
+//! trip every step and is correspondingly slow.
+
+use crate::cable::{self, CableTree};
+use crate::netcon::NetSource;
+use crate::vector::Variable;
+use crate::NeuronSimulation;
+use oldies_core::{OldiesError, Result, Time, Voltage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How fixed-step runs are carried out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Engine {
+    /// Cell by cell on the model itself, for every feature
+    #[default]
+    Cells,
+    /// On a flattened structure-of-arrays copy of the model
+    Soa,
+}
+
+fn unsupported(what: impl std::fmt::Display) -> OldiesError {
+    OldiesError::SimulationError(format!("The SoA engine does not support {}; use Engine::Cells", what))
+}
+
+/// Where an instance is stored in the cells: (cell, section, mechanism or
+/// point process index, segment)
+type Owner = (usize, String, usize, usize);
+
+/// Instances of `hh`, `na` or `k`, which differ in the channels present
+#[derive(Debug, Default)]
+struct Gated {
+    sodium: bool,
+    potassium: bool,
+    leak: bool,
+    owner: Vec<Owner>,
+    node: Vec<usize>,
+    gnabar: Vec<f64>,
+    gkbar: Vec<f64>,
+    gl: Vec<f64>,
+    el: Vec<Voltage>,
+    ena: Vec<Voltage>,
+    ek: Vec<Voltage>,
+    m: Vec<f64>,
+    h: Vec<f64>,
+    n: Vec<f64>,
+}
+
+#[derive(Debug, Default)]
+struct Passive {
+    node: Vec<usize>,
+    g: Vec<f64>,
+    e: Vec<Voltage>,
+}
+
+/// ExpSyn and Exp2Syn instances; ExpSyn has its conductance in `b` alone
+#[derive(Debug, Default)]
+struct Synapses {
+    owner: Vec<Owner>,
+    node: Vec<usize>,
+    e: Vec<Voltage>,
+    /// Per-step decay of `a` and `b`
+    decay_a: Vec<f64>,
+    decay_b: Vec<f64>,
+    /// Scale of the weight of an event
+    factor: Vec<f64>,
+    a: Vec<f64>,
+    b: Vec<f64>,
+}
+
+#[derive(Debug, Default)]
+struct Stimuli {
+    node: Vec<usize>,
+    delay: Vec<Time>,
+    dur: Vec<Time>,
+    amp: Vec<f64>,
+}
+
+/// The whole network as flat arrays
+#[derive(Debug)]
+pub(crate) struct FlatModel {
+    v: Vec<Voltage>,
+    /// Parent of each node, across cells
+    parent: Vec<Option<usize>>,
+    g_axial: Vec<f64>,
+    capacitance: Vec<f64>,
+    /// Membrane area (cm^2) times 1e6, from mA/cm^2 to nA
+    scale: Vec<f64>,
+    /// Node of each (section, segment) of each cell
+    index: Vec<HashMap<(String, usize), usize>>,
+    gated: Vec<Gated>,
+    passive: Passive,
+    synapses: Synapses,
+    stimuli: Stimuli,
+    /// Synapse of each point process, by cell
+    points: Vec<Vec<Option<usize>>>,
+    /// Node watched by each connection with a voltage source
+    pub(crate) netcon_nodes: Vec<Option<usize>>,
+    /// Node watched by each threshold
+    pub(crate) threshold_nodes: Vec<usize>,
+    /// Node of each record of a membrane potential
+    record_nodes: Vec<Option<usize>>,
+    celsius: f64,
+    dt: Time,
+    // Work arrays of a step
+    current: Vec<f64>,
+    slope: Vec<f64>,
+    d: Vec<f64>,
+    rhs: Vec<f64>,
+}
+
+impl FlatModel {
+    /// Flatten the model of `sim`
+    fn new(sim: &mut NeuronSimulation) -> Result<Self> {
+        if !sim.rxd.is_empty() {
+            return Err(unsupported("reaction-diffusion"));
+        }
+        if !sim.plays.is_empty() {
+            return Err(unsupported("played waveforms"));
+        }
+        let mut model = FlatModel {
+            v: vec![],
+            parent: vec![],
+            g_axial: vec![],
+            capacitance: vec![],
+            scale: vec![],
+            index: vec![],
+            gated: vec![
+                Gated { sodium: true, potassium: true, leak: true, ..Gated::default() },
+                Gated { sodium: true, ..Gated::default() },
+                Gated { potassium: true, ..Gated::default() },
+            ],
+            passive: Passive::default(),
+            synapses: Synapses::default(),
+            stimuli: Stimuli::default(),
+            points: vec![],
+            netcon_nodes: vec![],
+            threshold_nodes: vec![],
+            record_nodes: vec![],
+            celsius: sim.celsius,
+            dt: sim.dt,
+            current: vec![],
+            slope: vec![],
+            d: vec![],
+            rhs: vec![],
+        };
+
+        for (c, cell) in sim.cells.iter_mut().enumerate() {
+            cable::prepare(cell, sim.celsius, &sim.mechanisms)?;
+            let tree = CableTree::new(cell)?;
+            let offset = model.v.len();
+            let mut index = HashMap::new();
+            for (i, (name, k)) in tree.nodes.iter().enumerate() {
+                model.v.push(cell.sections[name].v[*k]);
+                model.parent.push(tree.parent[i].map(|p| p + offset));
+                model.g_axial.push(tree.g_axial[i]);
+                model.capacitance.push(tree.capacitance[i]);
+                model.scale.push(tree.area[i] * 1e6);
+                index.insert((name.clone(), *k), offset + i);
+            }
+
+            let mut sections: Vec<&String> = cell.sections.keys().collect();
+            sections.sort();
+            for name in sections {
+                let sec = &cell.sections[name];
+                for (j, mech) in sec.mechanisms.iter().enumerate() {
+                    let is_compiled = sim.mechanisms.contains_key(&mech.name);
+                    let type_index = match mech.name.as_str() {
+                        _ if is_compiled => return Err(unsupported(format!("NMODL mechanism {}", mech.name))),
+                        "hh" => 0,
+                        "na" => 1,
+                        "k" => 2,
+                        "pas" => {
+                            for k in 0..sec.nseg {
+                                model.passive.node.push(index[&(name.clone(), k)]);
+                                model.passive.g.push(parameter(mech, "g", k)?);
+                                model.passive.e.push(parameter(mech, "e", k)?);
+                            }
+                            continue;
+                        }
+                        ion if ion.ends_with("_ion") => continue,
+                        other => return Err(unsupported(format!("mechanism {}", other))),
+                    };
+                    let gated = &mut model.gated[type_index];
+                    for k in 0..sec.nseg {
+                        let optional = |present: bool, param: &str| -> Result<f64> {
+                            if present { parameter(mech, param, k) } else { Ok(0.0) }
+                        };
+                        let gate = |present: bool, g: &str| if present { mech.state[g][k] } else { 0.0 };
+                        gated.owner.push((c, name.clone(), j, k));
+                        gated.node.push(index[&(name.clone(), k)]);
+                        gated.gnabar.push(optional(gated.sodium, "gnabar")?);
+                        gated.ena.push(optional(gated.sodium, "ena")?);
+                        gated.gkbar.push(optional(gated.potassium, "gkbar")?);
+                        gated.ek.push(optional(gated.potassium, "ek")?);
+                        gated.gl.push(optional(gated.leak, "gl")?);
+                        gated.el.push(optional(gated.leak, "el")?);
+                        gated.m.push(gate(gated.sodium, "m"));
+                        gated.h.push(gate(gated.sodium, "h"));
+                        gated.n.push(gate(gated.potassium, "n"));
+                    }
+                }
+            }
+
+            let mut points = vec![];
+            for (j, pp) in cell.point_processes.iter().enumerate() {
+                let sec = cell.sections.get(&pp.section).ok_or_else(|| {
+                    OldiesError::ModelNotFound(format!("Section {} not found", pp.section))
+                })?;
+                let node = index[&(pp.section.clone(), sec.segment(pp.location))];
+                let param = |name: &str| pp.parameters.get(name).copied().ok_or_else(|| {
+                    OldiesError::SimulationError(format!("{} lacks parameter {}", pp.name, name))
+                });
+                let state = |name: &str| pp.state.get(name).copied().unwrap_or(0.0);
+                let synapses = &mut model.synapses;
+                match pp.name.as_str() {
+                    "IClamp" => {
+                        model.stimuli.node.push(node);
+                        model.stimuli.delay.push(param("delay")?);
+                        model.stimuli.dur.push(param("dur")?);
+                        model.stimuli.amp.push(param("amp")?);
+                        points.push(None);
+                        continue;
+                    }
+                    "ExpSyn" => {
+                        synapses.decay_a.push(0.0);
+                        synapses.decay_b.push((-sim.dt / param("tau")?).exp());
+                        synapses.factor.push(1.0);
+                        synapses.a.push(0.0);
+                        synapses.b.push(state("g"));
+                    }
+                    "Exp2Syn" => {
+                        let (tau1, tau2) = (param("tau1")?, param("tau2")?);
+                        let tp = tau1 * tau2 / (tau2 - tau1) * (tau2 / tau1).ln();
+                        synapses.decay_a.push((-sim.dt / tau1).exp());
+                        synapses.decay_b.push((-sim.dt / tau2).exp());
+                        synapses.factor.push(1.0 / ((-tp / tau2).exp() - (-tp / tau1).exp()));
+                        synapses.a.push(state("A"));
+                        synapses.b.push(state("B"));
+                    }
+                    other => return Err(unsupported(format!("point process {}", other))),
+                }
+                points.push(Some(synapses.node.len()));
+                synapses.owner.push((c, pp.section.clone(), j, 0));
+                synapses.node.push(node);
+                synapses.e.push(param("e")?);
+            }
+            model.points.push(points);
+            model.index.push(index);
+        }
+
+        let node = |model: &FlatModel, cell: usize, section: &str, loc: f64| -> Result<usize> {
+            sim.cells.get(cell)
+                .and_then(|c| c.sections.get(section))
+                .and_then(|sec| model.index[cell].get(&(section.to_string(), sec.segment(loc))))
+                .copied()
+                .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", section)))
+        };
+        for nc in &sim.netcons {
+            let watched = match &nc.source {
+                NetSource::Voltage { cell, section, loc } => Some(node(&model, *cell, section, *loc)?),
+                NetSource::Artificial(_) | NetSource::Point { .. } => None,
+            };
+            model.netcon_nodes.push(watched);
+        }
+        for th in &sim.thresholds {
+            let watched = node(&model, th.cell, &th.section, th.loc)?;
+            model.threshold_nodes.push(watched);
+        }
+        for record in &sim.records {
+            let recorded = match &record.variable {
+                Variable::Time => None,
+                Variable::Range { cell, section, loc, name } if name == "v" => Some(node(&model, *cell, section, *loc)?),
+                other => return Err(unsupported(format!("recording {:?}", other))),
+            };
+            model.record_nodes.push(recorded);
+        }
+
+        let n = model.v.len();
+        model.current = vec![0.0; n];
+        model.slope = vec![0.0; n];
+        model.d = vec![0.0; n];
+        model.rhs = vec![0.0; n];
+        Ok(model)
+    }
+
+    /// Membrane potential of node `i`
+    pub(crate) fn v(&self, i: usize) -> Voltage {
+        self.v[i]
+    }
+
+    /// Value of record `r` at `t`
+    pub(crate) fn recorded(&self, r: usize, t: Time) -> f64 {
+        self.record_nodes[r].map_or(t, |i| self.v[i])
+    }
+
+    /// Deliver an event of `weight` to point process `index` of cell `cell`
+    pub(crate) fn net_receive(&mut self, cell: usize, index: usize, weight: f64) -> Result<()> {
+        let s = self.points.get(cell).and_then(|p| p.get(index)).copied()
+            .ok_or_else(|| OldiesError::ModelNotFound(format!("Point process {} of cell {}", index, cell)))?
+            .ok_or_else(|| OldiesError::SimulationError("IClamp cannot receive events".into()))?;
+        let w = weight * self.synapses.factor[s];
+        if self.synapses.decay_a[s] != 0.0 {
+            self.synapses.a[s] += w;
+        }
+        self.synapses.b[s] += w;
+        Ok(())
+    }
+
+    /// Advance every cell from `t` to `t + dt`, as `cable::advance` does
+    fn advance(&mut self, t: Time) {
+        let dt = self.dt;
+        let n = self.v.len();
+        self.current.iter_mut().for_each(|x| *x = 0.0);
+        self.slope.iter_mut().for_each(|x| *x = 0.0);
+
+        // Membrane currents by mechanism type
+        for g in &self.gated {
+            for j in 0..g.node.len() {
+                let i = g.node[j];
+                let v = self.v[i];
+                let gna = g.gnabar[j] * g.m[j].powi(3) * g.h[j];
+                let gk = g.gkbar[j] * g.n[j].powi(4);
+                let density = gna * (v - g.ena[j]) + gk * (v - g.ek[j]) + g.gl[j] * (v - g.el[j]);
+                self.current[i] += density * self.scale[i];
+                self.slope[i] += (gna + gk + g.gl[j]) * self.scale[i];
+            }
+        }
+        let p = &self.passive;
+        for j in 0..p.node.len() {
+            let i = p.node[j];
+            self.current[i] += p.g[j] * (self.v[i] - p.e[j]) * self.scale[i];
+            self.slope[i] += p.g[j] * self.scale[i];
+        }
+        let s = &self.synapses;
+        for j in 0..s.node.len() {
+            let i = s.node[j];
+            let g = s.b[j] - s.a[j];
+            self.current[i] += g * (self.v[i] - s.e[j]);
+            self.slope[i] += g;
+        }
+        let st = &self.stimuli;
+        for j in 0..st.node.len() {
+            if t >= st.delay[j] && t < st.delay[j] + st.dur[j] {
+                self.current[st.node[j]] -= st.amp[j];
+            }
+        }
+
+        // Crank-Nicolson over all cells, solved leaves to roots
+        for i in 0..n {
+            self.d[i] = self.capacitance[i] / dt + 0.5 * self.slope[i];
+            self.rhs[i] = -self.current[i];
+        }
+        for i in 0..n {
+            if let Some(p) = self.parent[i] {
+                let g = self.g_axial[i];
+                let flow = g * (self.v[p] - self.v[i]);
+                self.rhs[i] += flow;
+                self.rhs[p] -= flow;
+                self.d[i] += 0.5 * g;
+                self.d[p] += 0.5 * g;
+            }
+        }
+        for i in (0..n).rev() {
+            if let Some(p) = self.parent[i] {
+                let a = -0.5 * self.g_axial[i];
+                let f = a / self.d[i];
+                self.d[p] -= f * a;
+                self.rhs[p] -= f * self.rhs[i];
+            }
+        }
+        for i in 0..n {
+            if let Some(p) = self.parent[i] {
+                self.rhs[i] -= -0.5 * self.g_axial[i] * self.rhs[p];
+            }
+            self.rhs[i] /= self.d[i];
+            self.v[i] += self.rhs[i];
+        }
+
+        // States by mechanism type, at the new voltages
+        let relax = |x: &mut f64, gate: &str, v: Voltage, celsius: f64| {
+            let (inf, tau) = cable::hh_gate(gate, v, celsius);
+            *x = inf + (*x - inf) * (-dt / tau).exp();
+        };
+        for g in &mut self.gated {
+            for j in 0..g.node.len() {
+                let v = self.v[g.node[j]];
+                if g.sodium {
+                    relax(&mut g.m[j], "m", v, self.celsius);
+                    relax(&mut g.h[j], "h", v, self.celsius);
+                }
+                if g.potassium {
+                    relax(&mut g.n[j], "n", v, self.celsius);
+                }
+            }
+        }
+        let s = &mut self.synapses;
+        for j in 0..s.node.len() {
+            s.a[j] *= s.decay_a[j];
+            s.b[j] *= s.decay_b[j];
+        }
+    }
+
+    /// Write the voltages and states back into the cells of `sim` at `t`
+    fn store(&self, sim: &mut NeuronSimulation) -> Result<()> {
+        for (cell, index) in sim.cells.iter_mut().zip(&self.index) {
+            for ((name, k), &i) in index {
+                cell.sections.get_mut(name).unwrap().v[*k] = self.v[i];
+            }
+        }
+        for g in &self.gated {
+            let gates = [(g.sodium, "m", &g.m), (g.sodium, "h", &g.h), (g.potassium, "n", &g.n)];
+            for (j, (c, section, mech, k)) in g.owner.iter().enumerate() {
+                let mech = &mut sim.cells[*c].sections.get_mut(section).unwrap().mechanisms[*mech];
+                for (present, gate, values) in gates {
+                    if present {
+                        mech.state.get_mut(gate).unwrap()[*k] = values[j];
+                    }
+                }
+            }
+        }
+        let s = &self.synapses;
+        for (j, (c, _, index, _)) in s.owner.iter().enumerate() {
+            let pp = &mut sim.cells[*c].point_processes[*index];
+            if pp.name == "ExpSyn" {
+                pp.state.insert("g".into(), s.b[j]);
+            } else {
+                pp.state.insert("A".into(), s.a[j]);
+                pp.state.insert("B".into(), s.b[j]);
+            }
+        }
+        for cell in &mut sim.cells {
+            cable::update_assigned(cell, sim.t, &sim.mechanisms)?;
+        }
+        Ok(())
+    }
+}
+
+fn parameter(mech: &crate::InsertedMechanism, name: &str, k: usize) -> Result<f64> {
+    mech.parameter(name, k).ok_or_else(|| {
+        OldiesError::SimulationError(format!("Mechanism {} lacks parameter {}", mech.name, name))
+    })
+}
+
+impl NeuronSimulation {
+    /// Select the engine of fixed-step runs
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    /// Engine of fixed-step runs
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Run on the flattened model until `tstop`, or for one step if `once`
+    pub(crate) fn advance_flat(&mut self, once: bool) -> Result<()> {
+        self.flat = Some(FlatModel::new(self)?);
+        let result = self.flat_steps(once);
+        let model = self.flat.take().unwrap();
+        model.store(self)?;
+        result
+    }
+
+    fn flat_steps(&mut self, once: bool) -> Result<()> {
+        loop {
+            self.deliver(self.t + self.dt / 2.0)?;
+            self.flat.as_mut().unwrap().advance(self.t);
+            self.t += self.dt;
+            self.watch(None, self.t)?;
+            self.sample()?;
+            if once || !self.before_tstop(self.t) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netcon::{ArtificialCell, NetCon, NetTarget};
+    use crate::{mechanisms, nmodl, NeuronCell};
+
+    /// Ball-and-stick cells in a ring, the first driven by a NetStim
+    fn ring(engine: Engine) -> NeuronSimulation {
+        let mut sim = NeuronSimulation::new();
+        for i in 0..4 {
+            let mut cell = NeuronCell::new(&format!("cell{}", i));
+            let soma = cell.create("soma");
+            soma.length = 20.0;
+            soma.diam = 20.0;
+            soma.insert(mechanisms::hh());
+            let dend = cell.create("dend");
+            dend.length = 200.0;
+            dend.set_nseg(5);
+            dend.insert(mechanisms::pas());
+            dend.insert(mechanisms::hh_k());
+            cell.connect("dend", 0.0, "soma", 1.0).unwrap();
+            cell.add_point_process(mechanisms::exp2_syn("dend", 0.5));
+            if i == 0 {
+                cell.add_point_process(mechanisms::iclamp("soma", 0.5, 1.0, 0.5, 0.5));
+            }
+            sim.add_cell(cell);
+        }
+        for i in 0..4 {
+            let source = NetSource::Voltage { cell: i, section: "soma".into(), loc: 0.5 };
+            let mut nc = NetCon::new(source, Some(NetTarget::PointProcess { cell: (i + 1) % 4, index: 0 }));
+            nc.weight = 0.05;
+            nc.delay = 3.0;
+            nc.threshold = 0.0;
+            sim.add_netcon(nc);
+        }
+        let stim = sim.add_artificial_cell(ArtificialCell::net_stim(20.0, 10.0, 3.0, 0.0));
+        let mut nc = NetCon::new(NetSource::Artificial(stim), Some(NetTarget::PointProcess { cell: 2, index: 0 }));
+        nc.weight = 0.05;
+        sim.add_netcon(nc);
+        sim.record_v("v", 3, "dend", 0.9);
+        sim.add_threshold("ap", 1, "soma", 0.5, 0.0);
+        sim.set_engine(engine);
+        sim.tstop = 60.0;
+        sim
+    }
+
+    #[test]
+    fn test_soa_matches_cells() {
+        let mut cells = ring(Engine::Cells);
+        let mut soa = ring(Engine::Soa);
+        for sim in [&mut cells, &mut soa] {
+            sim.init().unwrap();
+            sim.run().unwrap();
+        }
+        let (a, b) = (&cells.recordings["v"], &soa.recordings["v"]);
+        assert_eq!(a.len(), b.len());
+        let error = a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f64::max);
+        assert!(error < 1e-6, "max difference {} mV", error);
+        let (a, b) = (&cells.threshold_events["ap"], &soa.threshold_events["ap"]);
+        assert!(a.len() >= 3);
+        assert_eq!(a.len(), b.len());
+        assert!(a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-6));
+        for (x, y) in cells.netcons.iter().zip(&soa.netcons) {
+            assert_eq!(x.spikes.len(), y.spikes.len());
+        }
+
+        // States and assigned variables are back in the cells
+        let (x, y) = (&cells.cells[1].sections["soma"], &soa.cells[1].sections["soma"]);
+        for name in ["m", "h", "n", "gna", "ik"] {
+            assert!((x.mechanisms[0].state[name][0] - y.mechanisms[0].state[name][0]).abs() < 1e-9, "{}", name);
+        }
+        let g = |sim: &NeuronSimulation| sim.cells[2].point_processes[0].state["B"];
+        assert!((g(&cells) - g(&soa)).abs() < 1e-12);
+
+        // fadvance and continuerun go on from there
+        soa.fadvance().unwrap();
+        cells.fadvance().unwrap();
+        assert!((soa.cells[3].sections["dend"].v[4] - cells.cells[3].sections["dend"].v[4]).abs() < 1e-6);
+        soa.continuerun(80.0).unwrap();
+        cells.continuerun(80.0).unwrap();
+        assert_eq!(soa.t, cells.t);
+        assert_eq!(soa.recordings["v"].len(), cells.recordings["v"].len());
+    }
+
+    #[test]
+    fn test_soa_stops_with_cells() {
+        // tstop is not a whole number of steps: both engines take 400 steps
+        for tstop in [10.0, 10.01, 9.99] {
+            let mut cells = ring(Engine::Cells);
+            let mut soa = ring(Engine::Soa);
+            for sim in [&mut cells, &mut soa] {
+                sim.tstop = tstop;
+                sim.init().unwrap();
+                sim.run().unwrap();
+            }
+            assert!((cells.t - 10.0).abs() < 1e-9, "t = {} at tstop {}", cells.t, tstop);
+            assert_eq!(soa.t, cells.t);
+            assert_eq!(cells.recordings["v"].len(), 401);
+            assert_eq!(soa.recordings["v"].len(), cells.recordings["v"].len());
+        }
+    }
+
+    #[test]
+    fn test_soa_unsupported() {
+        let mut sim = ring(Engine::Soa);
+        sim.record_point("g", 0, 0, "B");
+        sim.init().unwrap();
+        let err = sim.run().unwrap_err();
+        assert!(err.to_string().contains("Engine::Cells"), "{}", err);
+
+        let mut sim = ring(Engine::Soa);
+        let hh = sim.load_nmodl(nmodl::HH_MOD).unwrap();
+        let instance = sim.mechanisms[&hh].instance();
+        sim.cells[0].sections.get_mut("soma").unwrap().mechanisms[0] = instance;
+        sim.init().unwrap();
+        assert!(sim.run().is_err());
+
+        // CVODE runs on the cells whatever the engine
+        let mut sim = ring(Engine::Soa);
+        sim.cvode_active(true);
+        sim.tstop = 5.0;
+        sim.init().unwrap();
+        sim.run().unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+//! Recording and playing simulation variables
+//!
+//! As with NEURON's `Vector.record` and `Vector.play`, a [`Variable`]
+//! names any quantity of the simulation: time, a range variable of a
+//! section (`v`, a mechanism state such as `m_hh` or `cai`, or a
+//! parameter such as `gnabar_hh`), a variable of a point process or the
+//! potential at an extracellular electrode. A [`Record`] samples it at
+//! every step or every `interval`, and a [`Play`] sets it from a waveform
+//! at initialization and at the end of every step:
+//!
+//! - step-wise: `values[i]` holds from `times[i]` until `times[i + 1]`,
+//!   and the variable is untouched before `times[0]`. CVODE steps end at
+//!   each `times[i]` so that the change is not smeared over a step.
+//! - continuous: linear interpolation between the points, holding the end
+//!   values outside them.
+
+use crate::{lfp, NeuronCell};
+use oldies_core::{OldiesError, Result, Time};
+use serde::{Deserialize, Serialize};
+
+/// A simulation variable that can be recorded or played into
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Variable {
+    /// Simulation time
+    Time,
+    /// Range variable `name` at `section(loc)` of cell `cell`
+    Range { cell: usize, section: String, loc: f64, name: String },
+    /// Parameter or state `name` of point process `index` of cell `cell`
+    Point { cell: usize, index: usize, name: String },
+    /// Extracellular potential at an electrode
+    Lfp(lfp::Electrode),
+}
+
+fn point_not_found(cell: usize, index: usize) -> OldiesError {
+    OldiesError::ModelNotFound(format!("Point process {} of cell {}", index, cell))
+}
+
+impl Variable {
+    /// Membrane potential of `section(loc)` of cell `cell`
+    pub fn voltage(cell: usize, section: &str, loc: f64) -> Self {
+        Variable::Range { cell, section: section.to_string(), loc, name: "v".into() }
+    }
+
+    /// Cell the variable belongs to, if only one
+    pub fn cell(&self) -> Option<usize> {
+        match self {
+            Variable::Range { cell, .. } | Variable::Point { cell, .. } => Some(*cell),
+            Variable::Time | Variable::Lfp(_) => None,
+        }
+    }
+
+    /// The same variable with its cell index lowered by `offset`, for a
+    /// block of cells starting at `offset`
+    pub(crate) fn shifted(&self, offset: usize) -> Self {
+        let mut variable = self.clone();
+        if let Variable::Range { cell, .. } | Variable::Point { cell, .. } = &mut variable {
+            *cell -= offset;
+        }
+        variable
+    }
+
+    /// Current value in `cells` at `t`
+    pub fn get(&self, cells: &[NeuronCell], t: Time) -> Result<f64> {
+        match self {
+            Variable::Time => Ok(t),
+            Variable::Range { cell, section, loc, name } => {
+                let sec = cells.get(*cell)
+                    .and_then(|c| c.sections.get(section))
+                    .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", section)))?;
+                sec.range(name, *loc).ok_or_else(|| {
+                    OldiesError::ModelNotFound(format!("Range variable {} in {}", name, section))
+                })
+            }
+            Variable::Point { cell, index, name } => {
+                let pp = cells.get(*cell)
+                    .and_then(|c| c.point_processes.get(*index))
+                    .ok_or_else(|| point_not_found(*cell, *index))?;
+                // Assigned variables such as `i` exist once computed
+                Ok(pp.parameters.get(name).or_else(|| pp.state.get(name)).copied().unwrap_or(0.0))
+            }
+            Variable::Lfp(electrode) => electrode.potential(cells, t),
+        }
+    }
+
+    /// Set the variable in `cells`
+    pub fn set(&self, cells: &mut [NeuronCell], x: f64) -> Result<()> {
+        match self {
+            Variable::Range { cell, section, loc, name } => {
+                let sec = cells.get_mut(*cell)
+                    .and_then(|c| c.sections.get_mut(section))
+                    .ok_or_else(|| OldiesError::ModelNotFound(format!("Section {} not found", section)))?;
+                if !sec.set_range(name, Some(*loc), x) {
+                    return Err(OldiesError::ModelNotFound(format!("Range variable {} in {}", name, section)));
+                }
+                Ok(())
+            }
+            Variable::Point { cell, index, name } => {
+                let pp = cells.get_mut(*cell)
+                    .and_then(|c| c.point_processes.get_mut(*index))
+                    .ok_or_else(|| point_not_found(*cell, *index))?;
+                let slot = match pp.parameters.get_mut(name) {
+                    Some(p) => Some(p),
+                    None => pp.state.get_mut(name),
+                };
+                *slot.ok_or_else(|| {
+                    OldiesError::ModelNotFound(format!("Variable {} of {}", name, pp.name))
+                })? = x;
+                Ok(())
+            }
+            Variable::Time | Variable::Lfp(_) => {
+                Err(OldiesError::SimulationError(format!("{:?} cannot be played into", self)))
+            }
+        }
+    }
+}
+
+/// Variable recorded under `name`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub name: String,
+    pub variable: Variable,
+    /// Sampling interval (ms), or `None` for every step
+    pub interval: Option<Time>,
+    /// Next sampling time
+    #[serde(skip)]
+    pub(crate) next: Time,
+}
+
+impl Record {
+    pub fn new(name: &str, variable: Variable, interval: Option<Time>) -> Self {
+        Self { name: name.to_string(), variable, interval, next: 0.0 }
+    }
+
+    /// Whether to sample at `t`, give or take `tolerance`, moving on to the
+    /// next sampling time if so
+    pub(crate) fn due(&mut self, t: Time, tolerance: Time) -> bool {
+        let Some(interval) = self.interval else { return true };
+        if t < self.next - tolerance {
+            return false;
+        }
+        while self.next <= t + tolerance {
+            self.next += interval;
+        }
+        true
+    }
+}
+
+/// Waveform played into a variable
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Play {
+    pub variable: Variable,
+    /// Increasing times (ms)
+    pub times: Vec<Time>,
+    pub values: Vec<f64>,
+    /// Interpolate linearly instead of stepping
+    pub continuous: bool,
+}
+
+impl Play {
+    /// Step-wise waveform taking `values[i]` at `times[i]`
+    pub fn new(variable: Variable, times: Vec<Time>, values: Vec<f64>) -> Result<Self> {
+        if matches!(variable, Variable::Time | Variable::Lfp(_)) {
+            return Err(OldiesError::SimulationError(format!("{:?} cannot be played into", variable)));
+        }
+        if times.len() != values.len() {
+            return Err(OldiesError::SimulationError(format!(
+                "play needs as many times as values ({} and {})", times.len(), values.len()
+            )));
+        }
+        if times.windows(2).any(|w| w[1] < w[0]) {
+            return Err(OldiesError::SimulationError("play times must not decrease".into()));
+        }
+        Ok(Self { variable, times, values, continuous: false })
+    }
+
+    /// Step-wise waveform taking `values[i]` at `i * interval`
+    pub fn with_interval(variable: Variable, interval: Time, values: Vec<f64>) -> Result<Self> {
+        if interval <= 0.0 {
+            return Err(OldiesError::SimulationError("play interval must be positive".into()));
+        }
+        let times = (0..values.len()).map(|i| i as f64 * interval).collect();
+        Self::new(variable, times, values)
+    }
+
+    /// Value at `t`, or `None` before a step-wise waveform starts
+    pub fn value_at(&self, t: Time) -> Option<f64> {
+        let i = self.times.partition_point(|&ti| ti <= t);
+        if !self.continuous {
+            return i.checked_sub(1).map(|i| self.values[i]);
+        }
+        if i == 0 {
+            return self.values.first().copied();
+        }
+        if i == self.times.len() {
+            return self.values.last().copied();
+        }
+        let (t0, t1) = (self.times[i - 1], self.times[i]);
+        let f = if t1 > t0 { (t - t0) / (t1 - t0) } else { 1.0 };
+        Some(self.values[i - 1] + f * (self.values[i] - self.values[i - 1]))
+    }
+
+    /// First step of a step-wise waveform after `t`
+    pub(crate) fn next_time(&self, t: Time) -> Option<Time> {
+        if self.continuous {
+            return None;
+        }
+        self.times.get(self.times.partition_point(|&ti| ti <= t)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mechanisms, NeuronSimulation};
+
+    #[test]
+    fn test_play_value_at() {
+        let amp = Variable::Point { cell: 0, index: 0, name: "amp".into() };
+        let mut play = Play::new(amp.clone(), vec![1.0, 2.0, 4.0], vec![10.0, 20.0, 0.0]).unwrap();
+        assert_eq!(play.value_at(0.5), None);
+        assert_eq!(play.value_at(1.0), Some(10.0));
+        assert_eq!(play.value_at(3.0), Some(20.0));
+        assert_eq!(play.value_at(9.0), Some(0.0));
+        assert_eq!(play.next_time(1.0), Some(2.0));
+        play.continuous = true;
+        assert_eq!(play.value_at(0.5), Some(10.0));
+        assert_eq!(play.value_at(3.0), Some(10.0));
+        assert_eq!(play.next_time(1.0), None);
+        assert!(Play::new(amp, vec![1.0], vec![]).is_err());
+        assert!(Play::new(Variable::Time, vec![0.0], vec![1.0]).is_err());
+    }
+
+    /// Passive soma with an IClamp whose amplitude is played
+    fn sim_with_ramp(cvode: bool) -> NeuronSimulation {
+        let mut cell = NeuronCell::new("cell");
+        let soma = cell.create("soma");
+        soma.length = 20.0;
+        soma.diam = 20.0;
+        soma.insert(mechanisms::pas());
+        cell.add_point_process(mechanisms::iclamp("soma", 0.5, 0.0, 1e9, 0.0));
+        let mut sim = NeuronSimulation::new();
+        sim.add_cell(cell);
+        sim.cvode_active(cvode);
+        let amp = Variable::Point { cell: 0, index: 0, name: "amp".into() };
+        sim.play(Play::new(amp, vec![5.0, 10.0], vec![0.1, 0.0]).unwrap());
+        sim.record("v", Variable::voltage(0, "soma", 0.5), Some(1.0));
+        sim.record("gpas", Variable::Range { cell: 0, section: "soma".into(), loc: 0.5, name: "g_pas".into() }, Some(1.0));
+        sim.tstop = 20.0;
+        sim
+    }
+
+    #[test]
+    fn test_play_into_point_process() {
+        for cvode in [false, true] {
+            let mut sim = sim_with_ramp(cvode);
+            sim.finitialize(-70.0).unwrap();
+            sim.run().unwrap();
+            let v = &sim.recordings["v"];
+            // Sampled at 0, 1, ..., 20 ms whatever the steps
+            assert_eq!(v.len(), 21, "cvode {}", cvode);
+            assert_eq!(sim.recordings["gpas"][3], 0.001);
+            assert!((v[5] + 70.0).abs() < 1e-6, "cvode {}: {}", cvode, v[5]);
+            assert!(v[10] > v[6] && v[6] > -69.0, "cvode {}: {:?}", cvode, v);
+            assert!(v[20] < v[10]);
+        }
+    }
+
+    #[test]
+    fn test_play_into_range_variable() {
+        let mut sim = sim_with_ramp(false);
+        let g = Variable::Range { cell: 0, section: "soma".into(), loc: 0.5, name: "g_pas".into() };
+        sim.play(Play::with_interval(g, 10.0, vec![1e-3, 1e-4]).unwrap());
+        sim.finitialize(-70.0).unwrap();
+        sim.run().unwrap();
+        let g = &sim.recordings["gpas"];
+        assert_eq!((g[9], g[10], g[20]), (1e-3, 1e-4, 1e-4));
+        // Missing variables are reported
+        let missing = Variable::Range { cell: 0, section: "soma".into(), loc: 0.5, name: "gbar_foo".into() };
+        sim.play(Play::new(missing, vec![0.0], vec![1.0]).unwrap());
+        assert!(sim.finitialize(-70.0).is_err());
+    }
+}
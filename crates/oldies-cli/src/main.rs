@@ -24,7 +24,7 @@ use colored::Colorize;
 use console::{style, Emoji};
 use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 // Emoji for visual feedback
@@ -66,6 +66,7 @@ EXAMPLES:
     oldies                          Interactive mode
     oldies genesis script.g         Run GENESIS simulation
     oldies neuron model.hoc         Run NEURON simulation
+    oldies neuron model.ses         Rerun a saved NEURON session
     oldies brian network.py         Run Brian spiking network
     oldies xpp model.ode -p I       Bifurcation analysis
     oldies list                     List all simulators
@@ -98,7 +99,7 @@ enum Commands {
 
     /// Run a NEURON simulation
     Neuron {
-        /// HOC script file
+        /// HOC script or saved session (.ses) file
         script: PathBuf,
 
         /// NMODL mechanism files
@@ -152,12 +153,16 @@ enum Commands {
 
     /// Run COPASI/SBML biochemical simulation
     Copasi {
-        /// SBML or COPASI file
+        /// SBML, COPASI or SED-ML file
         model: PathBuf,
 
         /// Simulation time
         #[arg(short, long, default_value = "100")]
         time: f64,
+
+        /// Output directory of SED-ML reports and plots
+        #[arg(short, long)]
+        output: Option<PathBuf>,
     },
 
     /// List all supported simulators
@@ -265,7 +270,7 @@ fn main() -> Result<()> {
         Commands::Nest { script } => run_nest(&script)?,
         Commands::Xpp { ode, parameter, points } => run_xppaut(&ode, parameter, points)?,
         Commands::Auto { problem, start, end } => run_auto(&problem, start, end)?,
-        Commands::Copasi { model, time } => run_copasi(&model, time)?,
+        Commands::Copasi { model, time, output } => run_copasi(&model, time, output)?,
         Commands::List { detailed } => show_list(detailed)?,
         Commands::Import { id, output } => run_import(id, output)?,
     }
@@ -350,7 +355,7 @@ fn interactive_neuron(theme: &ColorfulTheme) -> Result<()> {
     println!("\n{}", style("── NEURON Simulation ──").bold());
 
     let script: String = Input::with_theme(theme)
-        .with_prompt("HOC script file")
+        .with_prompt("HOC script or session file")
         .interact_text()?;
 
     run_neuron(&PathBuf::from(script), &[])
@@ -418,7 +423,7 @@ fn interactive_copasi(theme: &ColorfulTheme) -> Result<()> {
         .default(100.0)
         .interact_text()?;
 
-    run_copasi(&PathBuf::from(model), time)
+    run_copasi(&PathBuf::from(model), time, None)
 }
 
 fn interactive_import(theme: &ColorfulTheme) -> Result<()> {
@@ -456,6 +461,9 @@ fn run_genesis(script: &PathBuf, duration: f64, dt: f64) -> Result<()> {
 }
 
 fn run_neuron(script: &PathBuf, mod_files: &[PathBuf]) -> Result<()> {
+    if script.extension().is_some_and(|ext| ext == "ses") {
+        return run_neuron_session(script);
+    }
     println!("\n{}NEURON Simulation", style("⚡").cyan());
     println!("  Script: {}", style(script.display()).cyan());
     if !mod_files.is_empty() {
@@ -469,6 +477,39 @@ fn run_neuron(script: &PathBuf, mod_files: &[PathBuf]) -> Result<()> {
     Ok(())
 }
 
+fn run_neuron_session(path: &PathBuf) -> Result<()> {
+    use oldies_neuron::{session::Session, NeuronSimulation};
+
+    println!("\n{}NEURON Session", style("⚡").cyan());
+    println!("  Session: {}", style(path.display()).cyan());
+    let session = Session::load(path)?;
+    println!("  Cells: {}", style(session.cells.len()).yellow());
+    println!("  Duration: {} ms, dt = {} ms", session.tstop, session.dt);
+
+    let mut sim = NeuronSimulation::from_session(session)?;
+    let tstop = sim.tstop;
+    let pb = create_progress_bar(tstop.ceil() as u64);
+    pb.set_message("Running NEURON...");
+    sim.init()?;
+    while sim.t < tstop {
+        sim.continuerun((sim.t + 1.0).min(tstop))?;
+        pb.set_position(sim.t as u64);
+    }
+    pb.finish_with_message("Complete!");
+
+    let mut names: Vec<_> = sim.recordings.keys().collect();
+    names.sort();
+    for name in names {
+        println!("  {}Recorded {}: {} samples", CHART, style(name).cyan(), sim.recordings[name].len());
+    }
+    for (name, times) in &sim.threshold_events {
+        println!("  {}: {} spikes", style(name).cyan(), times.len());
+    }
+
+    println!("\n{}Simulation complete!", CHECK);
+    Ok(())
+}
+
 fn run_brian(script: &PathBuf, neurons: usize) -> Result<()> {
     println!("\n{}Brian Spiking Network", style("🔮").magenta());
     println!("  Script: {}", style(script.display()).cyan());
@@ -529,7 +570,10 @@ fn run_auto(problem: &PathBuf, start: Option<f64>, end: Option<f64>) -> Result<(
     Ok(())
 }
 
-fn run_copasi(model: &PathBuf, time: f64) -> Result<()> {
+fn run_copasi(model: &PathBuf, time: f64, output: Option<PathBuf>) -> Result<()> {
+    if model.extension().is_some_and(|ext| ext == "sedml") {
+        return run_sedml(model, output);
+    }
     println!("\n{}COPASI Simulation", DNA);
     println!("  Model: {}", style(model.display()).cyan());
     println!("  Time: {} s", time);
@@ -541,6 +585,34 @@ fn run_copasi(model: &PathBuf, time: f64) -> Result<()> {
     Ok(())
 }
 
+fn run_sedml(path: &PathBuf, output: Option<PathBuf>) -> Result<()> {
+    use oldies_copasi::sedml::SedDocument;
+
+    println!("\n{}SED-ML Experiment", DNA);
+    println!("  Experiment: {}", style(path.display()).cyan());
+    let document = SedDocument::read_sedml(path)?;
+    println!(
+        "  Models: {}, tasks: {}, outputs: {}",
+        style(document.models.len()).yellow(),
+        style(document.tasks.len()).yellow(),
+        style(document.outputs.len()).yellow()
+    );
+
+    let pb = create_progress_bar(1);
+    pb.set_message("Running tasks...");
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let data = document.execute(directory)?;
+    pb.finish_with_message("Complete!");
+
+    let output = output.unwrap_or_else(|| directory.to_path_buf());
+    for file in document.write_outputs(&data, &output)? {
+        println!("  {}Wrote {}", CHART, style(file.display()).cyan());
+    }
+
+    println!("\n{}Experiment complete!", CHECK);
+    Ok(())
+}
+
 fn run_import(id: u32, output: Option<PathBuf>) -> Result<()> {
     println!("\n{}ModelDB Import", style("📥").blue());
     println!("  Accession: {}", style(id).cyan());
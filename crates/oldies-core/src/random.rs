@@ -0,0 +1,208 @@
+//! Seedable random number generation
+//!
+//! One SplitMix64 generator, shared by all the simulators, keeps stochastic
+//! runs reproducible from a single seed (Brian's `seed`, GENESIS's
+//! `randseed`, COPASI's `set_seed`, ...) without pulling in an external
+//! RNG crate.
+
+use serde::{Deserialize, Serialize};
+
+/// SplitMix64 increment between successive states
+const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// SplitMix64 pseudo-random number generator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rng {
+    state: u64,
+    /// Second normal deviate from the last Box-Muller draw
+    cached_normal: Option<f64>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            cached_normal: None,
+        }
+    }
+
+    /// Generator continuing from a state returned by [`Rng::state`]
+    pub fn from_state(state: u64, cached_normal: Option<f64>) -> Self {
+        Self { state, cached_normal }
+    }
+
+    /// Internal state, for carrying the sequence over to generated code
+    pub fn state(&self) -> (u64, Option<f64>) {
+        (self.state, self.cached_normal)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GAMMA);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Skip `n` draws of [`Rng::next_u64`] at once: SplitMix64 counts
+    /// through its states, so a stream can be picked up anywhere
+    pub fn advance(&mut self, n: u64) {
+        self.state = self.state.wrapping_add(n.wrapping_mul(GAMMA));
+        self.cached_normal = None;
+    }
+
+    /// Uniform sample in [0, 1)
+    pub fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal sample (Box-Muller)
+    pub fn normal(&mut self) -> f64 {
+        if let Some(z) = self.cached_normal.take() {
+            return z;
+        }
+        let u1 = 1.0 - self.uniform();
+        let u2 = self.uniform();
+        let r = (-2.0 * u1.ln()).sqrt();
+        let theta = 2.0 * std::f64::consts::PI * u2;
+        self.cached_normal = Some(r * theta.sin());
+        r * theta.cos()
+    }
+
+    /// Exponential sample of rate `rate` (infinite for a zero rate)
+    pub fn exponential(&mut self, rate: f64) -> f64 {
+        if rate <= 0.0 {
+            return f64::INFINITY;
+        }
+        -(1.0 - self.uniform()).ln() / rate
+    }
+
+    /// Poisson sample of mean `mean`: by multiplying uniforms for small
+    /// means, by Hörmann's transformed rejection (PTRS) for large ones
+    pub fn poisson(&mut self, mean: f64) -> f64 {
+        if mean <= 0.0 {
+            return 0.0;
+        }
+        if mean < 10.0 {
+            let limit = (-mean).exp();
+            let mut k = 0.0;
+            let mut p = self.uniform();
+            while p > limit {
+                k += 1.0;
+                p *= self.uniform();
+            }
+            return k;
+        }
+        let b = 0.931 + 2.53 * mean.sqrt();
+        let a = -0.059 + 0.02483 * b;
+        let inv_alpha = 1.1239 + 1.1328 / (b - 3.4);
+        let vr = 0.9277 - 3.6224 / (b - 2.0);
+        loop {
+            let u = self.uniform() - 0.5;
+            let v = self.uniform();
+            let us = 0.5 - u.abs();
+            let k = ((2.0 * a / us + b) * u + mean + 0.43).floor();
+            if us >= 0.07 && v <= vr {
+                return k;
+            }
+            if k < 0.0 || (us < 0.013 && v > us) {
+                continue;
+            }
+            let log_accept = (v * inv_alpha / (a / (us * us) + b)).ln();
+            if log_accept <= -mean + k * mean.ln() - ln_factorial(k) {
+                return k;
+            }
+        }
+    }
+
+    /// Binomial sample: successes in `n` trials of probability `p`.
+    ///
+    /// Uses a normal approximation when both the expected successes and
+    /// failures exceed 5, and exact inversion otherwise.
+    pub fn binomial(&mut self, n: usize, p: f64) -> usize {
+        if n == 0 || p <= 0.0 {
+            return 0;
+        }
+        if p >= 1.0 {
+            return n;
+        }
+        let mean = n as f64 * p;
+        if mean > 5.0 && n as f64 - mean > 5.0 {
+            let x = (mean + self.normal() * (mean * (1.0 - p)).sqrt()).round();
+            return x.clamp(0.0, n as f64) as usize;
+        }
+
+        // Walk the cumulative distribution from k = 0
+        let q = 1.0 - p;
+        let s = p / q;
+        let a = (n as f64 + 1.0) * s;
+        let mut r = q.powi(n as i32);
+        let mut u = self.uniform();
+        let mut k = 0;
+        while u > r && k < n {
+            u -= r;
+            k += 1;
+            r *= a / k as f64 - s;
+        }
+        k
+    }
+
+    /// Uniform integer in [0, n)
+    pub fn below(&mut self, n: usize) -> usize {
+        ((self.uniform() * n as f64) as usize).min(n.saturating_sub(1))
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0x5EED)
+    }
+}
+
+/// ln(k!), by Stirling's series beyond small k
+fn ln_factorial(k: f64) -> f64 {
+    if k < 10.0 {
+        return (2..=k as u64).map(|i| (i as f64).ln()).sum();
+    }
+    let n = k + 1.0;
+    (n - 0.5) * n.ln() - n + 0.5 * (2.0 * std::f64::consts::PI).ln() + 1.0 / (12.0 * n)
+        - 1.0 / (360.0 * n.powi(3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_moments() {
+        let (mut a, mut b) = (Rng::new(3), Rng::new(3));
+        assert!((0..100).all(|_| a.next_u64() == b.next_u64()));
+        (0..10).for_each(|_| {
+            a.next_u64();
+        });
+        b.advance(10);
+        assert_eq!(a.next_u64(), b.next_u64());
+
+        let n = 20000;
+        let mut rng = Rng::new(5);
+        let mean = |xs: Vec<f64>| xs.iter().sum::<f64>() / xs.len() as f64;
+        assert!((mean((0..n).map(|_| rng.uniform()).collect()) - 0.5).abs() < 0.01);
+        assert!(mean((0..n).map(|_| rng.normal()).collect()).abs() < 0.03);
+        assert!((mean((0..n).map(|_| rng.exponential(4.0)).collect()) - 0.25).abs() < 0.01);
+        assert!((mean((0..n).map(|_| rng.binomial(100, 0.3) as f64).collect()) - 30.0).abs() < 0.2);
+        assert_eq!(rng.exponential(0.0), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_poisson_moments() {
+        let mut rng = Rng::new(11);
+        for mean in [0.5, 4.0, 30.0, 1e4] {
+            let samples: Vec<f64> = (0..20000).map(|_| rng.poisson(mean)).collect();
+            let m = samples.iter().sum::<f64>() / samples.len() as f64;
+            let var = samples.iter().map(|x| (x - m).powi(2)).sum::<f64>() / samples.len() as f64;
+            assert!((m - mean).abs() < 4.0 * (mean / 20000.0).sqrt(), "{} {}", mean, m);
+            assert!((var / mean - 1.0).abs() < 0.05, "{} {}", mean, var);
+            assert!(samples.iter().all(|x| x.fract() == 0.0 && *x >= 0.0));
+        }
+    }
+}
@@ -90,7 +90,7 @@ impl Simulator {
     fn file_extensions(&self) -> &'static [&'static str] {
         match self {
             Self::Genesis => &["g", "genesis", "sli"],
-            Self::Neuron => &["hoc", "nmodl", "mod"],
+            Self::Neuron => &["hoc", "nmodl", "mod", "ses"],
             Self::Brian => &["py", "brian"],
             Self::Nest => &["sli", "nest", "py"],
             Self::Xppaut => &["ode", "xpp"],
@@ -185,7 +185,7 @@ impl OldiesApp {
         if let Some(path) = rfd::FileDialog::new()
             .add_filter("All Supported", &extensions)
             .add_filter("GENESIS", &["g", "genesis", "sli"])
-            .add_filter("NEURON", &["hoc", "mod"])
+            .add_filter("NEURON", &["hoc", "mod", "ses"])
             .add_filter("Brian", &["py", "brian"])
             .add_filter("XPPAUT", &["ode", "xpp"])
             .add_filter("COPASI", &["cps", "sbml", "xml"])
@@ -206,6 +206,10 @@ impl OldiesApp {
                         }
                     }
 
+                    if path.extension().is_some_and(|ext| ext == "ses") {
+                        self.load_neuron_session();
+                    }
+
                     self.status_message = format!("Loaded: {}", path.display());
                     self.log(&format!("Loaded file: {}", path.display()));
                 }
@@ -217,6 +221,24 @@ impl OldiesApp {
         }
     }
 
+    /// Take the run parameters of the NEURON session just loaded
+    fn load_neuron_session(&mut self) {
+        match oldies_neuron::session::Session::from_json(&self.script_content) {
+            Ok(session) => {
+                self.sim_duration = session.tstop;
+                self.sim_dt = session.dt;
+                self.log(&format!(
+                    "NEURON session: {} cells, {} connections, tstop={:.1}ms, dt={:.3}ms",
+                    session.cells.len(),
+                    session.netcons.len(),
+                    session.tstop,
+                    session.dt
+                ));
+            }
+            Err(e) => self.log(&format!("Invalid NEURON session: {}", e)),
+        }
+    }
+
     fn save_file(&mut self) {
         let ext = match self.selected_simulator {
             Simulator::Genesis => "g",